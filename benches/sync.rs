@@ -0,0 +1,135 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::SinkExt;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::network::{SyncMessage, SyncServer};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Read;
+use std::net::TcpListener as StdTcpListener;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+#[path = "../tests/common/fixtures.rs"]
+#[allow(dead_code)]
+mod fixtures;
+
+use fixtures::{FixtureBuilder, FixtureRng, WorldSpec};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// World-like tree of `worlds` generated worlds with `ldb_files` tables each.
+fn synthetic_worlds(worlds: usize, ldb_files: usize, ldb_size: usize) -> TempDir {
+    let dir = TempDir::new().unwrap();
+    let mut builder = FixtureBuilder::new(0x2545_f491_4f6c_dd1d);
+    for w in 0..worlds {
+        builder = builder.world(WorldSpec::new(&format!("World {}", w)).ldb(ldb_files, ldb_size));
+    }
+    builder.build(dir.path());
+    dir
+}
+
+fn first_ldb(root: &Path) -> PathBuf {
+    let world = fs::read_dir(root).unwrap().next().unwrap().unwrap().path();
+    world.join("db").join("000005.ldb")
+}
+
+fn bench_scan(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scan");
+    for worlds in [1, 8] {
+        let tree = synthetic_worlds(worlds, 16, 256 * 1024);
+        group.bench_with_input(BenchmarkId::from_parameter(worlds), &tree, |b, tree| {
+            b.iter(|| FileManager::new(tree.path().to_path_buf()).scan_directory().unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_hash(c: &mut Criterion) {
+    let tree = synthetic_worlds(1, 1, 8 * 1024 * 1024);
+    let file = first_ldb(tree.path());
+    let manager = FileManager::new(tree.path().to_path_buf());
+
+    let mut group = c.benchmark_group("hash");
+    group.throughput(Throughput::Bytes(8 * 1024 * 1024));
+    group.bench_function("streaming", |b| b.iter(|| manager.calculate_file_hash(&file).unwrap()));
+    group.bench_function("read_whole", |b| {
+        b.iter(|| format!("{:x}", Sha256::digest(fs::read(&file).unwrap())))
+    });
+    group.bench_function("buffered_1mib", |b| {
+        b.iter(|| {
+            let mut f = fs::File::open(&file).unwrap();
+            let mut hasher = Sha256::new();
+            let mut buf = vec![0u8; 1024 * 1024];
+            loop {
+                let n = f.read(&mut buf).unwrap();
+                if n == 0 {
+                    break;
+                }
+                hasher.update(&buf[..n]);
+            }
+            format!("{:x}", hasher.finalize())
+        })
+    });
+    group.finish();
+}
+
+fn bench_chunking(c: &mut Criterion) {
+    let data = FixtureRng::new(7).block_bytes(8 * 1024 * 1024);
+
+    let mut group = c.benchmark_group("chunking");
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_function("fixed_64kib", |b| {
+        b.iter(|| data.chunks(CHUNK_SIZE).map(Sha256::digest).collect::<Vec<_>>())
+    });
+    group.finish();
+}
+
+fn bench_loopback_transfer(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let port = {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    runtime.spawn(async move {
+        let _ = SyncServer::new(port).start().await;
+    });
+
+    let tree = synthetic_worlds(1, 4, 1024 * 1024);
+    let db = first_ldb(tree.path()).parent().unwrap().to_path_buf();
+    let files: Vec<(PathBuf, Vec<u8>)> = fs::read_dir(db)
+        .unwrap()
+        .map(|e| {
+            let path = e.unwrap().path();
+            let content = fs::read(&path).unwrap();
+            (path.strip_prefix(tree.path()).unwrap().to_path_buf(), content)
+        })
+        .collect();
+    let total: usize = files.iter().map(|(_, c)| c.len()).sum();
+
+    let mut group = c.benchmark_group("transfer");
+    group.throughput(Throughput::Bytes(total as u64));
+    group.bench_function("loopback_file_content", |b| {
+        b.iter(|| {
+            runtime.block_on(async {
+                let socket = loop {
+                    match TcpStream::connect(("127.0.0.1", port)).await {
+                        Ok(socket) => break socket,
+                        Err(_) => tokio::task::yield_now().await,
+                    }
+                };
+                let mut framed = Framed::new(socket, LengthDelimitedCodec::builder().max_frame_length(usize::MAX).new_codec());
+                for (path, content) in &files {
+                    let message = SyncMessage::FileContent { path: path.clone(), content: content.clone(), group: None, modified: None, version: None, correlation_id: None };
+                    framed.send(Bytes::from(serde_json::to_vec(&message).unwrap())).await.unwrap();
+                }
+            })
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_scan, bench_hash, bench_chunking, bench_loopback_transfer);
+criterion_main!(benches);
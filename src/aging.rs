@@ -0,0 +1,126 @@
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+use crate::manifest::ManifestCache;
+use crate::reconnect::{PeerState, Reachability};
+use crate::transfer_queue::{FailedFile, TransferQueue};
+
+/// One configured device as reported by `/status`.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceStatus {
+    pub name: String,
+    /// Unix time of the last completed manifest exchange.
+    pub last_synced: Option<u64>,
+    pub stale: bool,
+    /// Changes are not queued for it until it is back.
+    pub paused: bool,
+    pub needs_reconcile: bool,
+    /// Changes the device has not acknowledged yet, including `in_flight`.
+    pub queued: usize,
+    /// Changes sent to the device that it has not acknowledged yet.
+    pub in_flight: usize,
+    /// Unix time since which the device could not be reached, if it cannot.
+    pub offline_since: Option<u64>,
+    /// Unix time anything last arrived from the device.
+    pub last_seen: Option<u64>,
+    pub state: PeerState,
+    /// Files the device received but could not apply, retried until it does.
+    pub failed: Vec<FailedFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgingChange {
+    /// Not synced for longer than the stale threshold.
+    Stale { device: String, offline: Duration },
+    /// Synced again after it was stale.
+    Returned { device: String, was_paused: bool },
+}
+
+/// Tracks how long each configured device has not synced. Devices that
+/// never synced are not considered stale, there is nothing to age from.
+#[derive(Clone)]
+pub struct DeviceAging {
+    devices: Vec<String>,
+    cursors: ManifestCache,
+    stale_after: Duration,
+    pause: bool,
+    stale: Arc<Mutex<HashSet<String>>>,
+    reachability: Reachability,
+}
+
+impl DeviceAging {
+    pub fn new(devices: Vec<String>, cursors: ManifestCache, stale_after_days: u64) -> Self {
+        Self {
+            devices,
+            cursors,
+            stale_after: Duration::from_secs(stale_after_days * 24 * 60 * 60),
+            pause: false,
+            stale: Arc::new(Mutex::new(HashSet::new())),
+            reachability: Reachability::default(),
+        }
+    }
+
+    /// Stop queuing changes for stale devices.
+    pub fn with_pause(mut self, pause: bool) -> Self {
+        self.pause = pause;
+        self
+    }
+
+    /// Where the transfer workers record which devices are offline.
+    pub fn with_reachability(mut self, reachability: Reachability) -> Self {
+        self.reachability = reachability;
+        self
+    }
+
+    pub async fn is_paused(&self, device: &str) -> bool {
+        self.pause && self.stale.lock().await.contains(device)
+    }
+
+    /// Re-evaluates every device and returns the ones that became stale or
+    /// came back since the last call.
+    pub async fn refresh(&self, now: SystemTime) -> Vec<AgingChange> {
+        let last_synced = self.cursors.last_synced().await;
+        let mut stale = self.stale.lock().await;
+        let mut changes = Vec::new();
+        for device in &self.devices {
+            let offline = last_synced.get(device).and_then(|at| now.duration_since(*at).ok());
+            let is_stale = offline.is_some_and(|offline| offline > self.stale_after);
+            match (is_stale, stale.contains(device)) {
+                (true, false) => {
+                    stale.insert(device.clone());
+                    changes.push(AgingChange::Stale { device: device.clone(), offline: offline.unwrap_or_default() });
+                }
+                (false, true) => {
+                    stale.remove(device);
+                    changes.push(AgingChange::Returned { device: device.clone(), was_paused: self.pause });
+                }
+                _ => {}
+            }
+        }
+        changes
+    }
+
+    pub async fn status(&self, queue: &TransferQueue) -> Vec<DeviceStatus> {
+        let last_synced = self.cursors.last_synced().await;
+        let stale = self.stale.lock().await.clone();
+        let mut statuses = Vec::new();
+        for device in &self.devices {
+            statuses.push(DeviceStatus {
+                name: device.clone(),
+                last_synced: last_synced.get(device).and_then(|at| at.duration_since(UNIX_EPOCH).ok()).map(|since| since.as_secs()),
+                stale: stale.contains(device),
+                paused: self.pause && stale.contains(device),
+                needs_reconcile: self.cursors.needs_reconcile(device).await,
+                queued: queue.len_for(device).await,
+                in_flight: queue.in_flight_for(device).await,
+                offline_since: self.reachability.offline_since(device).and_then(|at| at.duration_since(UNIX_EPOCH).ok()).map(|since| since.as_secs()),
+                last_seen: self.reachability.last_seen(device).and_then(|at| at.duration_since(UNIX_EPOCH).ok()).map(|seen| seen.as_secs()),
+                state: self.reachability.state(device, SystemTime::now()),
+                failed: queue.failures_for(device).await,
+            });
+        }
+        statuses
+    }
+}
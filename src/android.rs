@@ -0,0 +1,328 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, UNIX_EPOCH};
+use log::debug;
+use tokio::sync::Mutex;
+use crate::exclusions::Exclusions;
+use crate::conflicts::Resolution;
+use crate::file_manager::{hash_bytes, root_of, FileIndex, FileInfo, FileManager};
+use crate::transfer_queue;
+
+/// Where Minecraft keeps its worlds on Android.
+pub const DEFAULT_WORLDS: &str = "/sdcard/Android/data/com.mojang.minecraftpe/files/games/com.mojang/minecraftWorlds";
+
+/// A file in the Android worlds directory, as `stat` reports it.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteFile {
+    pub size: u64,
+    /// Seconds since the Unix epoch.
+    pub modified: i64,
+}
+
+/// The `adb` command line, talking to one device.
+#[derive(Debug, Clone)]
+pub struct Adb {
+    program: String,
+    serial: Option<String>,
+    root: String,
+}
+
+impl Adb {
+    /// `root` is the worlds directory on the device. Without a `serial` the
+    /// only connected device is used.
+    pub fn new(program: String, serial: Option<String>, root: String) -> Self {
+        Self { program, serial, root: root.trim_end_matches('/').to_string() }
+    }
+
+    fn run(&self, args: &[&str]) -> Result<Vec<u8>> {
+        let mut command = Command::new(&self.program);
+        if let Some(serial) = &self.serial {
+            command.args(["-s", serial]);
+        }
+        let output = command.args(args).output().map_err(|e| anyhow!("Cannot run {}: {}", self.program, e))?;
+        if !output.status.success() {
+            let message = String::from_utf8_lossy(if output.stderr.is_empty() { &output.stdout } else { &output.stderr }).trim().to_string();
+            bail!("adb {} failed: {}", args.first().unwrap_or(&""), message);
+        }
+        Ok(output.stdout)
+    }
+
+    fn remote(&self, path: &Path) -> String {
+        let mut remote = self.root.clone();
+        for component in path.components() {
+            remote.push('/');
+            remote.push_str(&component.as_os_str().to_string_lossy());
+        }
+        remote
+    }
+
+    /// Connects to a device reached over the network, `host:port` as its
+    /// serial. USB devices need nothing.
+    pub fn connect(&self) -> Result<()> {
+        let Some(serial) = self.serial.as_ref().filter(|serial| serial.contains(':')) else {
+            return Ok(());
+        };
+        // adb reports a failed connect on stdout, with a successful exit
+        let output = String::from_utf8_lossy(&Command::new(&self.program).args(["connect", serial]).output()?.stdout).trim().to_string();
+        if !output.contains("connected to") {
+            bail!("Cannot connect to {}: {}", serial, output);
+        }
+        Ok(())
+    }
+
+    /// Every file below the worlds directory, relative to it. Fails if the
+    /// directory does not exist.
+    pub fn list(&self) -> Result<BTreeMap<PathBuf, RemoteFile>> {
+        let root = quote(&self.root);
+        let script = format!("cd {root} && find . -type f -exec stat -c '%s %Y %n' {{}} +");
+        let output = self.run(&["shell", &script])?;
+        let mut files = BTreeMap::new();
+        for line in String::from_utf8_lossy(&output).lines() {
+            let mut fields = line.splitn(3, ' ');
+            let (Some(size), Some(modified), Some(path)) = (fields.next(), fields.next(), fields.next()) else {
+                bail!("Unexpected file listing from adb: {}", line);
+            };
+            let path: PathBuf = Path::new(path).components().filter(|c| matches!(c, Component::Normal(_))).collect();
+            files.insert(path, RemoteFile { size: size.parse()?, modified: modified.parse()? });
+        }
+        Ok(files)
+    }
+
+    pub fn pull(&self, path: &Path, to: &Path) -> Result<()> {
+        self.run(&["pull", &self.remote(path), &to.to_string_lossy()])?;
+        Ok(())
+    }
+
+    /// Copies `from` to `path` on the device, returning what it became there.
+    pub fn push(&self, from: &Path, path: &Path) -> Result<RemoteFile> {
+        let remote = self.remote(path);
+        self.run(&["push", &from.to_string_lossy(), &remote])?;
+        let output = self.run(&["shell", &format!("stat -c '%s %Y' {}", quote(&remote))])?;
+        let output = String::from_utf8_lossy(&output);
+        let (size, modified) = output.trim().split_once(' ').ok_or_else(|| anyhow!("Unexpected stat output from adb: {}", output.trim()))?;
+        Ok(RemoteFile { size: size.parse()?, modified: modified.parse()? })
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        self.run(&["shell", &format!("rm -f {}", quote(&self.remote(path)))])?;
+        Ok(())
+    }
+}
+
+/// Quotes `s` for the device's shell, which `adb shell` hands its arguments to.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// A file as both sides last had it.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+struct Synced {
+    #[serde(flatten)]
+    remote: RemoteFile,
+    hash: String,
+}
+
+/// What one sync with an Android device changed on either side.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AndroidSync {
+    /// Files received from the device, for the other peers.
+    pub received: Vec<PathBuf>,
+    /// Files deleted here because the device no longer has them.
+    pub removed: Vec<PathBuf>,
+    pub pushed: usize,
+    pub deleted_there: usize,
+}
+
+/// An Android device whose worlds directory is synced over ADB. It runs no
+/// daemon of its own: each sync lists its files, and compares both sides
+/// with how they were at the last sync to tell which one changed. Files
+/// changed on the device are received like from any peer, so they are
+/// snapshotted, journaled and indexed, and local changes are pushed. When
+/// both sides changed a file, the file manager settles it with the conflict
+/// resolution of its world.
+#[derive(Debug, Clone)]
+pub struct AndroidDevice {
+    pub name: String,
+    adb: Adb,
+    /// How both sides were at the last sync.
+    state_file: PathBuf,
+    exclusions: Exclusions,
+}
+
+impl AndroidDevice {
+    pub fn new(name: String, adb: Adb, state_dir: &Path) -> Self {
+        let state_file = state_dir.join(format!("{}.json", name));
+        Self { name, adb, state_file, exclusions: Exclusions::new(&[]) }
+    }
+
+    pub fn with_exclusions(mut self, exclusions: Exclusions) -> Self {
+        self.exclusions = exclusions;
+        self
+    }
+
+    fn load(&self) -> Result<BTreeMap<PathBuf, Synced>> {
+        match fs::read(&self.state_file) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, synced: &BTreeMap<PathBuf, Synced>) -> Result<()> {
+        let tmp = self.state_file.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec(synced)?)?;
+        fs::rename(&tmp, &self.state_file)?;
+        Ok(())
+    }
+
+    /// Syncs both worlds directories once. Blocks on `adb`, so call it from
+    /// a blocking thread; `files` is only locked to apply what was pulled.
+    pub fn sync(&self, index: &FileIndex, files: &Mutex<FileManager>) -> Result<AndroidSync> {
+        self.adb.connect()?;
+        let remote: BTreeMap<PathBuf, RemoteFile> = self.adb.list()?
+            .into_iter()
+            .filter(|(path, _)| !self.exclusions.is_excluded(Path::new(""), path))
+            .collect();
+        if !index.is_available() {
+            bail!("The worlds directory {} is unavailable", index.base_path().display());
+        }
+        let mut synced = self.load()?;
+        // Like an unmounted share, an emptied directory is more likely gone than deleted
+        if remote.is_empty() && !synced.is_empty() {
+            bail!("The worlds directory on {} is empty", self.name);
+        }
+
+        // Android only has the worlds, not the other roots
+        let local: BTreeMap<PathBuf, _> = index.entries().into_iter().filter(|entry| root_of(&entry.path).is_none()).map(|entry| (entry.path.clone(), entry)).collect();
+        let paths: BTreeSet<PathBuf> = remote.keys().chain(local.keys()).chain(synced.keys()).cloned().collect();
+        let mut result = AndroidSync::default();
+        if let Some(parent) = self.state_file.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let staging = self.state_file.with_extension("pull");
+        for path in paths {
+            // Left as they are on both sides, and as they were last synced
+            if self.exclusions.is_archived(&path) {
+                continue;
+            }
+            let (there, here, last) = (remote.get(&path), local.get(&path), synced.get(&path));
+            // Hashed in the background, compared once it is
+            if here.is_some_and(|entry| entry.hash_pending()) {
+                continue;
+            }
+            let changed_there = match (there, last) {
+                (Some(there), Some(last)) => *there != last.remote,
+                (None, None) => false,
+                _ => true,
+            };
+            let changed_here = match (here, last) {
+                (Some(here), Some(last)) => here.hash != last.hash,
+                (None, None) => false,
+                _ => true,
+            };
+            if !changed_there && !changed_here {
+                continue;
+            }
+
+            let content = match there {
+                Some(there) if changed_there => {
+                    self.adb.pull(&path, &staging)?;
+                    let content = fs::read(&staging);
+                    let _ = fs::remove_file(&staging);
+                    Some((*there, content?))
+                }
+                _ => None,
+            };
+            let hash = content.as_ref().map(|(_, content)| hash_bytes(content));
+            if let (Some(here), Some(hash)) = (here, &hash) {
+                if here.hash == *hash {
+                    synced.insert(path, Synced { remote: *there.unwrap(), hash: hash.clone() });
+                    continue;
+                }
+            }
+            let mut merged = None;
+            let there_wins = match (changed_there, changed_here) {
+                (true, false) => true,
+                (false, true) => false,
+                _ => {
+                    let theirs = content.as_ref().zip(hash.as_ref()).map(|((there, content), hash)| FileInfo {
+                        path: path.clone(),
+                        last_modified: UNIX_EPOCH + Duration::from_secs(there.modified.max(0) as u64),
+                        size: content.len() as u64,
+                        hash: hash.clone(),
+                    });
+                    let mut files = files.blocking_lock();
+                    match files.resolve_conflict(&path, &self.name, theirs.as_ref(), true)? {
+                        Resolution::Take => true,
+                        Resolution::Keep => false,
+                        // This device's version goes to the device, its own to the copy
+                        Resolution::Divert(copy) => {
+                            if let Some((_, content)) = &content {
+                                files.receive_file(&copy, content)?;
+                                debug!("Received {} from {} as {}", path.display(), self.name, copy.display());
+                                result.received.push(copy);
+                            }
+                            false
+                        }
+                        // Merged here, then pushed back to the device like a local change
+                        Resolution::Merge { theirs_wins } => {
+                            if let Some((_, content)) = &content {
+                                if files.receive_merged(&path, content, theirs_wins)? {
+                                    merged = files.get_file_info(&path).map(|info| info.hash);
+                                    result.received.push(path.clone());
+                                }
+                            }
+                            false
+                        }
+                    }
+                }
+            };
+
+            if there_wins {
+                match content {
+                    Some((there, content)) => {
+                        files.blocking_lock().receive_file(&path, &content)?;
+                        debug!("Received {} from {}", path.display(), self.name);
+                        synced.insert(path.clone(), Synced { remote: there, hash: hash.unwrap() });
+                        result.received.push(path);
+                    }
+                    None => {
+                        files.blocking_lock().remove_received(&path)?;
+                        debug!("Removed {}, {} no longer has it", path.display(), self.name);
+                        synced.remove(&path);
+                        result.removed.push(path);
+                    }
+                }
+            } else {
+                match here {
+                    Some(here) => {
+                        let pushed = self.adb.push(&index.full_path(&path), &path)?;
+                        debug!("Pushed {} to {}", path.display(), self.name);
+                        synced.insert(path, Synced { remote: pushed, hash: merged.take().unwrap_or_else(|| here.hash.clone()) });
+                        result.pushed += 1;
+                    }
+                    None => {
+                        self.adb.remove(&path)?;
+                        debug!("Deleted {} on {}", path.display(), self.name);
+                        synced.remove(&path);
+                        result.deleted_there += 1;
+                    }
+                }
+            }
+            // Saved as it goes, so an interrupted sync does not count as a change on both sides
+            if (result.received.len() + result.removed.len() + result.pushed + result.deleted_there) % 64 == 0 {
+                self.save(&synced)?;
+            }
+        }
+        self.save(&synced)?;
+        let worlds: BTreeSet<&str> = result.received.iter().chain(&result.removed).filter_map(|path| transfer_queue::world_of(path)).collect();
+        for world in worlds {
+            files.blocking_lock().commit_staged(world)?;
+        }
+        Ok(result)
+    }
+}
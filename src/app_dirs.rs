@@ -0,0 +1,135 @@
+use anyhow::Result;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::config::PathConfig;
+use crate::platform;
+
+const APP_NAME: &str = "mcbd-world-sync";
+
+/// Where the tool keeps its own state. This lives outside the worlds
+/// directory so Minecraft never lists our folders as broken worlds.
+#[derive(Debug, Clone)]
+pub struct AppDirs {
+    root: PathBuf,
+}
+
+impl AppDirs {
+    pub fn new(config: &PathConfig) -> Self {
+        let root = match &config.state_dir {
+            Some(dir) => platform::expand(dir),
+            None => default_state_dir(),
+        };
+        Self { root }
+    }
+
+    pub fn at(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn staging(&self) -> PathBuf {
+        self.root.join("staging")
+    }
+
+    pub fn trash(&self) -> PathBuf {
+        self.root.join("trash")
+    }
+
+    pub fn snapshots(&self) -> PathBuf {
+        self.root.join("snapshots")
+    }
+
+    /// Change sets applied to each world.
+    pub fn journal(&self) -> PathBuf {
+        self.root.join("journal")
+    }
+
+    /// Read-only copies of snapshots.
+    pub fn views(&self) -> PathBuf {
+        self.root.join("views")
+    }
+
+    /// How the files of each Android device were at its last sync.
+    pub fn android(&self) -> PathBuf {
+        self.root.join("android")
+    }
+
+    pub fn quarantine(&self) -> PathBuf {
+        self.root.join("quarantine")
+    }
+
+    /// Chunks of received files, by hash.
+    pub fn chunks(&self) -> PathBuf {
+        self.root.join("chunks")
+    }
+
+    pub fn downloads_file(&self) -> PathBuf {
+        self.root.join("downloads.json")
+    }
+
+    pub fn uploads_file(&self) -> PathBuf {
+        self.root.join("uploads.json")
+    }
+
+    /// Worlds archived on this device.
+    pub fn archived_file(&self) -> PathBuf {
+        self.root.join("archived.json")
+    }
+
+    /// Worlds kept when they were proposed for archiving.
+    pub fn kept_file(&self) -> PathBuf {
+        self.root.join("kept.json")
+    }
+
+    /// Deletions received from peers and not applied yet.
+    pub fn deletions_file(&self) -> PathBuf {
+        self.root.join("deletions.json")
+    }
+
+    /// Bytes sent to peers per day.
+    pub fn usage_file(&self) -> PathBuf {
+        self.root.join("usage.json")
+    }
+
+    /// When the last summary report was emailed, see `report`.
+    pub fn report_file(&self) -> PathBuf {
+        self.root.join("report.json")
+    }
+
+    /// The version of each file, see `versions`.
+    pub fn versions_file(&self) -> PathBuf {
+        self.root.join("versions.jsonl")
+    }
+
+    pub fn index_file(&self) -> PathBuf {
+        self.root.join("index.json")
+    }
+
+    pub fn cursors_file(&self) -> PathBuf {
+        self.root.join("cursors.json")
+    }
+
+    pub fn ensure(&self) -> Result<()> {
+        for dir in [self.root.clone(), self.staging(), self.trash(), self.snapshots(), self.journal(), self.views(), self.quarantine(), self.chunks()] {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn default_state_dir() -> PathBuf {
+    if let Ok(local) = env::var("LOCALAPPDATA") {
+        return PathBuf::from(local).join(APP_NAME);
+    }
+    if let Ok(data) = env::var("XDG_DATA_HOME") {
+        return PathBuf::from(data).join(APP_NAME);
+    }
+    if let Ok(home) = env::var("HOME") {
+        return PathBuf::from(home).join(".local").join("share").join(APP_NAME);
+    }
+    PathBuf::from(APP_NAME)
+}
@@ -0,0 +1,167 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::file_manager::{root_of, FileInfo};
+use crate::snapshots::{Snapshot, Snapshots};
+
+/// Reason of the snapshot taken of a world as it is archived.
+pub const ARCHIVE_REASON: &str = "archived";
+
+/// Worlds archived on this device. They stay in the worlds directory, with
+/// a final snapshot, but are not indexed, watched or synced until they are
+/// restored, so the index and bandwidth go to the worlds still played.
+/// Kept in `archived.json` in the state directory, which the daemon reads
+/// again when told to.
+#[derive(Debug, Clone, Default)]
+pub struct ArchivedWorlds {
+    file: Option<PathBuf>,
+    worlds: Arc<RwLock<BTreeSet<String>>>,
+}
+
+impl ArchivedWorlds {
+    /// Archived worlds that are only kept in memory.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(file: PathBuf) -> Result<Self> {
+        let worlds = read(&file)?;
+        Ok(Self { file: Some(file), worlds: Arc::new(RwLock::new(worlds)) })
+    }
+
+    pub fn contains(&self, world: &str) -> bool {
+        self.worlds.read().unwrap().contains(world)
+    }
+
+    /// Whether `path`, relative to the worlds directory, is in an archived
+    /// world.
+    pub fn contains_path(&self, path: &Path) -> bool {
+        match path.components().next() {
+            Some(Component::Normal(world)) => world.to_str().is_some_and(|world| self.contains(world)),
+            _ => false,
+        }
+    }
+
+    pub fn worlds(&self) -> Vec<String> {
+        self.worlds.read().unwrap().iter().cloned().collect()
+    }
+
+    /// Takes the final snapshot of `world` in `snapshots`, then archives it.
+    pub fn archive(&self, worlds_root: &Path, world: &str, snapshots: &Snapshots, now: SystemTime) -> Result<Snapshot> {
+        if self.contains(world) {
+            bail!("{} is archived already", world);
+        }
+        let snapshot = snapshots.take(worlds_root, world, ARCHIVE_REASON, now)?;
+        self.update(|worlds| worlds.insert(world.to_string()))?;
+        Ok(snapshot)
+    }
+
+    /// Makes `world` an active world again.
+    pub fn restore(&self, world: &str) -> Result<()> {
+        if !self.update(|worlds| worlds.remove(world))? {
+            bail!("{} is not archived", world);
+        }
+        Ok(())
+    }
+
+    /// Reads `archived.json` again, returning the worlds archived and the
+    /// worlds restored since it was last read.
+    pub fn reload(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let Some(file) = &self.file else {
+            return Ok((Vec::new(), Vec::new()));
+        };
+        let current = read(file)?;
+        let mut worlds = self.worlds.write().unwrap();
+        let archived = current.difference(&worlds).cloned().collect();
+        let restored = worlds.difference(&current).cloned().collect();
+        *worlds = current;
+        Ok((archived, restored))
+    }
+
+    /// Applies `change` and saves the worlds if it changed them.
+    fn update(&self, change: impl FnOnce(&mut BTreeSet<String>) -> bool) -> Result<bool> {
+        let mut worlds = self.worlds.write().unwrap();
+        if !change(&mut worlds) {
+            return Ok(false);
+        }
+        if let Some(file) = &self.file {
+            let tmp = file.with_extension("json.tmp");
+            fs::write(&tmp, serde_json::to_vec_pretty(&*worlds)?)?;
+            fs::rename(&tmp, file)?;
+        }
+        Ok(true)
+    }
+}
+
+/// When each world last changed, from the files of the index.
+pub fn last_changes(entries: &[FileInfo]) -> BTreeMap<String, SystemTime> {
+    let mut changes = BTreeMap::new();
+    for entry in entries.iter().filter(|entry| root_of(&entry.path).is_none()) {
+        let Some(Component::Normal(world)) = entry.path.components().next() else {
+            continue;
+        };
+        let last = changes.entry(world.to_string_lossy().into_owned()).or_insert(entry.last_modified);
+        *last = (*last).max(entry.last_modified);
+    }
+    changes
+}
+
+/// Worlds proposed for archiving, as nothing in them changed for a while.
+/// Worlds the user chose to keep are remembered in `kept.json` in the state
+/// directory, and proposed again only once they changed and then went
+/// untouched again.
+#[derive(Debug, Clone)]
+pub struct ArchiveProposals {
+    kept_file: PathBuf,
+}
+
+impl ArchiveProposals {
+    pub fn new(kept_file: PathBuf) -> Self {
+        Self { kept_file }
+    }
+
+    /// The worlds of `last_changes` unchanged for `untouched_for`, with
+    /// when they last changed, leaving out the ones kept since.
+    pub fn propose(&self, last_changes: &BTreeMap<String, SystemTime>, untouched_for: Duration, now: SystemTime) -> Result<Vec<(String, SystemTime)>> {
+        let kept = self.kept()?;
+        Ok(last_changes.iter()
+            .filter(|(_, last)| now.duration_since(**last).is_ok_and(|since| since >= untouched_for))
+            .filter(|(world, last)| kept.get(*world).is_none_or(|kept| *kept < unix_secs(**last)))
+            .map(|(world, last)| (world.clone(), *last))
+            .collect())
+    }
+
+    /// Stops proposing `world` until it changes after `last_change`.
+    pub fn keep(&self, world: &str, last_change: SystemTime) -> Result<()> {
+        let mut kept = self.kept()?;
+        kept.insert(world.to_string(), unix_secs(last_change));
+        let tmp = self.kept_file.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&kept)?)?;
+        fs::rename(&tmp, &self.kept_file)?;
+        Ok(())
+    }
+
+    /// Unix seconds of the last change of each world kept.
+    fn kept(&self) -> Result<BTreeMap<String, u64>> {
+        match fs::read(&self.kept_file) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn read(file: &Path) -> Result<BTreeSet<String>> {
+    match fs::read(file) {
+        Ok(data) => Ok(serde_json::from_slice(&data)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeSet::new()),
+        Err(e) => Err(e.into()),
+    }
+}
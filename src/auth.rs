@@ -0,0 +1,83 @@
+use anyhow::{bail, Result};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::config::Device;
+use crate::shares;
+
+/// How far the time of an authentication may be from the receiver's clock.
+/// Nonces are remembered for as long, so a captured one cannot be replayed.
+pub const AUTH_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// First message of a connection to a daemon that requires authentication.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeviceAuth {
+    /// Name of the connecting device, as the receiver has it configured.
+    pub device: String,
+    pub nonce: String,
+    /// Unix seconds.
+    pub timestamp: u64,
+    /// Hash over the shared key and the fields above, so the key is never sent.
+    pub proof: String,
+}
+
+impl DeviceAuth {
+    pub fn new(device: &str, key: &str, now: SystemTime) -> Result<Self> {
+        let nonce = shares::random_key()?;
+        let timestamp = now.duration_since(UNIX_EPOCH)?.as_secs();
+        Ok(Self { proof: proof(key, device, &nonce, timestamp), device: device.to_string(), nonce, timestamp })
+    }
+}
+
+fn proof(key: &str, device: &str, nonce: &str, timestamp: u64) -> String {
+    let mut hasher = Sha256::new();
+    for part in [key.as_bytes(), device.as_bytes(), nonce.as_bytes(), timestamp.to_string().as_bytes()] {
+        hasher.update(part);
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Keys of the configured devices that have one. Once any device has a key,
+/// every connection has to authenticate as one of them.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceKeys {
+    keys: Arc<HashMap<String, String>>,
+    /// Nonces seen within `AUTH_WINDOW`, with their timestamps.
+    seen: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl DeviceKeys {
+    pub fn new(devices: &[Device]) -> Self {
+        let keys = devices.iter().filter_map(|d| Some((d.name.clone(), d.key.clone()?))).collect();
+        Self { keys: Arc::new(keys), seen: Arc::default() }
+    }
+
+    pub fn required(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// Checks that `auth` was made with the key of the device it names,
+    /// recently, and not seen before.
+    pub fn verify(&self, auth: &DeviceAuth, now: SystemTime) -> Result<()> {
+        let Some(key) = self.keys.get(&auth.device) else {
+            bail!("Device {} has no key configured", auth.device);
+        };
+        if proof(key, &auth.device, &auth.nonce, auth.timestamp) != auth.proof {
+            bail!("Wrong key for device {}", auth.device);
+        }
+        let now = now.duration_since(UNIX_EPOCH)?.as_secs();
+        let window = AUTH_WINDOW.as_secs();
+        if now.abs_diff(auth.timestamp) > window {
+            bail!("Authentication of {} is {} seconds off, check the clocks", auth.device, now.abs_diff(auth.timestamp));
+        }
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, timestamp| now.abs_diff(*timestamp) <= window);
+        if seen.insert(auth.nonce.clone(), auth.timestamp).is_some() {
+            bail!("Authentication of {} was replayed", auth.device);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{File, TryLockError};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+/// Worlds Minecraft has open, on this device and as devices reported
+/// theirs. LevelDB files copied while the game writes them make a corrupt
+/// world, so the files of a world open here are held back until it closes.
+#[derive(Debug, Clone, Default)]
+pub struct BusyWorlds {
+    local: Arc<RwLock<BTreeSet<String>>>,
+    peers: Arc<RwLock<BTreeMap<String, BTreeSet<String>>>>,
+}
+
+impl BusyWorlds {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// World folders open on this device.
+    pub fn worlds(&self) -> Vec<String> {
+        self.local.read().unwrap().iter().cloned().collect()
+    }
+
+    pub fn is_busy(&self, world: &str) -> bool {
+        self.local.read().unwrap().contains(world)
+    }
+
+    /// Replaces the worlds open on this device, returning whether that
+    /// changed them.
+    pub fn set(&self, worlds: BTreeSet<String>) -> bool {
+        let mut local = self.local.write().unwrap();
+        let changed = *local != worlds;
+        *local = worlds;
+        changed
+    }
+
+    /// Replaces the worlds `device` reported open there.
+    pub fn set_peer(&self, device: &str, worlds: Vec<String>) {
+        let mut peers = self.peers.write().unwrap();
+        if worlds.is_empty() {
+            peers.remove(device);
+        } else {
+            peers.insert(device.to_string(), worlds.into_iter().collect());
+        }
+    }
+
+    /// Worlds open on other devices, by device, as they last reported.
+    pub fn peers(&self) -> BTreeMap<String, Vec<String>> {
+        self.peers.read().unwrap().iter().map(|(device, worlds)| (device.clone(), worlds.iter().cloned().collect())).collect()
+    }
+}
+
+/// Whether a program holds the lock on the database of `world`, a world
+/// folder, as Minecraft does while the world is open.
+pub fn lock_held(world: &Path) -> bool {
+    match File::open(world.join("db").join("LOCK")) {
+        Ok(file) => matches!(file.try_lock(), Err(TryLockError::WouldBlock)),
+        // Windows does not even let the file be opened, ERROR_SHARING_VIOLATION
+        Err(e) => cfg!(windows) && e.raw_os_error() == Some(32),
+    }
+}
+
+/// The world folders in `worlds_dir` Minecraft may have open: every world
+/// while `game_running`, otherwise those whose database lock is held.
+pub fn open_worlds(worlds_dir: &Path, game_running: bool) -> BTreeSet<String> {
+    let Ok(entries) = std::fs::read_dir(worlds_dir) else {
+        return BTreeSet::new();
+    };
+    entries.flatten()
+        .filter(|entry| entry.path().join("db").is_dir())
+        .filter(|entry| game_running || lock_held(&entry.path()))
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect()
+}
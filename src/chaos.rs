@@ -0,0 +1,119 @@
+use anyhow::{Result, anyhow, bail};
+use futures::{Sink, SinkExt};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use log::warn;
+use tokio_util::bytes::Bytes;
+
+/// Fault injection for outgoing frames, used to check that retries, resumption
+/// and corruption detection really work. Enabled with the hidden `--chaos`
+/// flag, e.g. `--chaos drop=0.1,delay=200,duplicate=0.05,truncate=0.01,kill=0.01,seed=7`.
+#[derive(Debug, Clone, Default)]
+pub struct Chaos {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub truncate_rate: f64,
+    pub kill_rate: f64,
+    /// Upper bound for a random delay added before every frame.
+    pub max_delay: Duration,
+    rng: Arc<Mutex<u64>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameFault {
+    Pass,
+    Drop,
+    Duplicate,
+    Truncate(usize),
+    Kill,
+}
+
+impl Chaos {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Arc::new(Mutex::new(seed.max(1))),
+            ..Self::default()
+        }
+    }
+
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut chaos = Self::new(0x9e37_79b9_7f4a_7c15);
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part.split_once('=')
+                .ok_or_else(|| anyhow!("Invalid chaos setting '{}', expected key=value", part))?;
+            match key {
+                "drop" => chaos.drop_rate = value.parse()?,
+                "duplicate" => chaos.duplicate_rate = value.parse()?,
+                "truncate" => chaos.truncate_rate = value.parse()?,
+                "kill" => chaos.kill_rate = value.parse()?,
+                "delay" => chaos.max_delay = Duration::from_millis(value.parse()?),
+                "seed" => chaos.rng = Arc::new(Mutex::new(value.parse::<u64>()?.max(1))),
+                _ => bail!("Unknown chaos setting '{}'", key),
+            }
+        }
+        Ok(chaos)
+    }
+
+    fn next_f64(&self) -> f64 {
+        let mut state = self.rng.lock().unwrap();
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        (*state >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Picks what happens to the next frame of `len` bytes.
+    pub fn decide(&self, len: usize) -> FrameFault {
+        if self.next_f64() < self.kill_rate {
+            FrameFault::Kill
+        } else if self.next_f64() < self.drop_rate {
+            FrameFault::Drop
+        } else if self.next_f64() < self.duplicate_rate {
+            FrameFault::Duplicate
+        } else if len > 0 && self.next_f64() < self.truncate_rate {
+            FrameFault::Truncate((self.next_f64() * len as f64) as usize)
+        } else {
+            FrameFault::Pass
+        }
+    }
+
+    fn delay(&self) -> Duration {
+        self.max_delay.mul_f64(self.next_f64())
+    }
+}
+
+/// Sends a frame, passing it through the fault layer when chaos is enabled.
+pub async fn send_frame<S>(sink: &mut S, frame: Bytes, chaos: Option<&Chaos>) -> Result<()>
+where
+    S: Sink<Bytes> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let Some(chaos) = chaos else {
+        sink.send(frame).await?;
+        return Ok(());
+    };
+
+    let delay = chaos.delay();
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+
+    match chaos.decide(frame.len()) {
+        FrameFault::Pass => sink.send(frame).await?,
+        FrameFault::Drop => warn!("chaos: dropped frame of {} bytes", frame.len()),
+        FrameFault::Duplicate => {
+            warn!("chaos: duplicated frame of {} bytes", frame.len());
+            sink.send(frame.clone()).await?;
+            sink.send(frame).await?;
+        }
+        FrameFault::Truncate(len) => {
+            warn!("chaos: truncated frame from {} to {} bytes", frame.len(), len);
+            sink.send(frame.slice(..len)).await?;
+        }
+        FrameFault::Kill => {
+            warn!("chaos: killing connection");
+            bail!("Connection killed by chaos layer");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Files at least this large are sent as chunks, so a peer that already has
+/// some of them from other files only receives the rest.
+pub const MIN_CHUNKED_SIZE: usize = 64 * 1024;
+
+const MIN_CHUNK: usize = 2 * 1024;
+const MAX_CHUNK: usize = 64 * 1024;
+/// Cuts about every 8 KiB past the minimum.
+const CUT_MASK: u64 = (1 << 13) - 1;
+
+/// Random values per byte for the gear hash, fixed so every peer cuts the
+/// same data at the same places.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A chunk sent to a peer that lacks it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chunk {
+    pub hash: String,
+    #[serde(with = "serde_bytes")]
+    pub bytes: Vec<u8>,
+}
+
+/// Splits `data` where its content says so, so an insertion only changes
+/// the chunks around it instead of shifting every later one.
+pub fn boundaries(data: &[u8]) -> Vec<Range<usize>> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < data.len() {
+        let end = start + cut(&data[start..]);
+        chunks.push(start..end);
+        start = end;
+    }
+    chunks
+}
+
+fn cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let max = data.len().min(MAX_CHUNK);
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(max).skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if hash & CUT_MASK == 0 {
+            return i + 1;
+        }
+    }
+    max
+}
+
+fn hash(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Hashes of the chunks of `data`, in order.
+pub fn chunk_hashes(data: &[u8]) -> Vec<String> {
+    boundaries(data).into_iter().map(|range| hash(&data[range])).collect()
+}
+
+/// The chunks of `data` with the given hashes.
+pub fn select(data: &[u8], wanted: &[String]) -> Vec<Chunk> {
+    let wanted: HashSet<&String> = wanted.iter().collect();
+    let mut seen = HashSet::new();
+    boundaries(data)
+        .into_iter()
+        .map(|range| Chunk { hash: hash(&data[range.clone()]), bytes: data[range].to_vec() })
+        .filter(|chunk| wanted.contains(&chunk.hash) && seen.insert(chunk.hash.clone()))
+        .collect()
+}
+
+/// Rebuilds a file from its chunk list, taking each chunk from `provided` or
+/// else from `store`.
+pub fn assemble(chunks: &[String], provided: &[Chunk], store: Option<&ChunkStore>) -> Result<Vec<u8>> {
+    let provided: HashMap<&str, &[u8]> = provided.iter().map(|c| (c.hash.as_str(), c.bytes.as_slice())).collect();
+    let mut out = Vec::new();
+    for chunk in chunks {
+        match (provided.get(chunk.as_str()), store) {
+            (Some(bytes), _) => out.extend_from_slice(bytes),
+            (None, Some(store)) => out.extend_from_slice(&store.get(chunk)?),
+            (None, None) => bail!("Chunk {} was neither sent nor stored", chunk),
+        }
+    }
+    Ok(out)
+}
+
+/// Chunks of received files by hash, so the same content is never
+/// transferred twice. Chunks not used for `prune`'s age are removed.
+#[derive(Debug, Clone)]
+pub struct ChunkStore {
+    dir: PathBuf,
+}
+
+impl ChunkStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    fn path(&self, hash: &str) -> Result<PathBuf> {
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Invalid chunk hash {}", hash);
+        }
+        Ok(self.dir.join(&hash[..2]).join(hash))
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.path(hash).is_ok_and(|path| path.is_file())
+    }
+
+    /// The hashes of `chunks` this store lacks, each once.
+    pub fn missing(&self, chunks: &[String]) -> Vec<String> {
+        let mut seen = HashSet::new();
+        chunks.iter().filter(|c| !self.contains(c) && seen.insert(*c)).cloned().collect()
+    }
+
+    /// Every hash of `chunks` once, for a receiver without a store.
+    pub fn all_unique(chunks: &[String]) -> Vec<String> {
+        let mut seen = HashSet::new();
+        chunks.iter().filter(|c| seen.insert(*c)).cloned().collect()
+    }
+
+    /// Stores `bytes` under their hash. Storing a chunk again marks it as used.
+    pub fn put(&self, bytes: &[u8]) -> Result<String> {
+        let hash = hash(bytes);
+        let path = self.path(&hash)?;
+        if path.is_file() {
+            touch(&path)?;
+            return Ok(hash);
+        }
+        fs::create_dir_all(path.parent().unwrap_or(&self.dir))?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &path)?;
+        Ok(hash)
+    }
+
+    /// Stores a received chunk the sender says hashes to `hash`, failing if
+    /// it does not. A chunk delivered again is recognised by its hash alone
+    /// and only marked as used. Returns whether it was new.
+    pub fn put_received(&self, hash: &str, bytes: &[u8]) -> Result<bool> {
+        if self.contains(hash) {
+            touch(&self.path(hash)?)?;
+            return Ok(false);
+        }
+        if self.put(bytes)? != hash {
+            bail!("Chunk {} does not match its hash", hash);
+        }
+        Ok(true)
+    }
+
+    /// A stored chunk, checked against its hash.
+    pub fn get(&self, hash: &str) -> Result<Vec<u8>> {
+        let bytes = fs::read(self.path(hash)?).map_err(|e| anyhow!("Chunk {} is not stored: {}", hash, e))?;
+        if self::hash(&bytes) != hash {
+            bail!("Stored chunk {} is corrupt", hash);
+        }
+        Ok(bytes)
+    }
+
+    /// Stores every chunk of `data`.
+    pub fn insert_file(&self, data: &[u8]) -> Result<()> {
+        for range in boundaries(data) {
+            self.put(&data[range])?;
+        }
+        Ok(())
+    }
+
+    /// Removes chunks not stored or used for `max_age`. Returns how many.
+    pub fn prune(&self, max_age: Duration, now: SystemTime) -> Result<usize> {
+        let Ok(fanout) = fs::read_dir(&self.dir) else {
+            return Ok(0);
+        };
+        let mut removed = 0;
+        for dir in fanout {
+            for entry in fs::read_dir(dir?.path())? {
+                let entry = entry?;
+                let modified = entry.metadata()?.modified()?;
+                if now.duration_since(modified).unwrap_or_default() >= max_age {
+                    fs::remove_file(entry.path())?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Marks a stored chunk as used, so `prune` keeps it.
+fn touch(path: &Path) -> Result<()> {
+    fs::File::options().write(true).open(path)?.set_modified(SystemTime::now())?;
+    Ok(())
+}
@@ -0,0 +1,54 @@
+use anyhow::Result;
+use globset::GlobSet;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use crate::exclusions;
+use crate::transfer_queue;
+
+/// Files Minecraft rewrites on every launch or save whether or not anything
+/// in the world changed: the copy of `level.dat` it keeps and the world's
+/// thumbnail.
+pub const DEFAULT_LOW_VALUE: &[&str] = &["*/level.dat_old", "*/world_icon.jpeg"];
+
+/// Holds back changes to low value files, see `WatchConfig::low_value`,
+/// until another file of their world changes, so they go along with that
+/// sync instead of starting one of their own.
+#[derive(Debug, Default)]
+pub struct Churn {
+    low_value: GlobSet,
+    /// Paths changed, by world.
+    held: BTreeMap<String, BTreeSet<PathBuf>>,
+}
+
+impl Churn {
+    /// Takes glob rules in the syntax of `watch.ignore`.
+    pub fn new(low_value: &[String]) -> Result<Self> {
+        Ok(Self { low_value: exclusions::glob_set(low_value, "low value")?, held: BTreeMap::new() })
+    }
+
+    pub fn is_low_value(&self, path: &Path) -> bool {
+        exclusions::matches(&self.low_value, path)
+    }
+
+    /// Holds the change of `path` if it is a low value file, returning
+    /// whether it did.
+    pub fn hold(&mut self, path: &Path) -> bool {
+        if !self.is_low_value(path) {
+            return false;
+        }
+        let world = transfer_queue::world_of(path).unwrap_or_default().to_string();
+        self.held.entry(world).or_default().insert(path.to_path_buf());
+        true
+    }
+
+    /// The changes held for the world of `path`, which changed for real.
+    pub fn release(&mut self, path: &Path) -> Vec<PathBuf> {
+        let world = transfer_queue::world_of(path).unwrap_or_default();
+        self.held.remove(world).map(|held| held.into_iter().collect()).unwrap_or_default()
+    }
+
+    /// How many changes are held.
+    pub fn held(&self) -> usize {
+        self.held.values().map(BTreeSet::len).sum()
+    }
+}
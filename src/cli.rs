@@ -0,0 +1,264 @@
+use clap::{Parser, Subcommand};
+use log::LevelFilter;
+use std::env;
+use std::path::PathBuf;
+
+/// Keeps Minecraft Bedrock worlds in sync between devices. Without a command
+/// it runs the sync daemon.
+#[derive(Debug, Parser)]
+#[command(name = "mcbd-world-sync", version)]
+pub struct Cli {
+    /// Configuration file, not read in headless mode
+    #[arg(long, global = true, default_value = "config.json")]
+    pub config: PathBuf,
+    /// Most detailed log messages shown: error, warn, info, debug or trace.
+    /// Debug by default, info or `RUST_LOG` in headless mode
+    #[arg(long, global = true)]
+    pub log_level: Option<LevelFilter>,
+    /// Container mode, also `MCBD_HEADLESS=1`: configuration from `MCBD_*`
+    /// environment variables and JSON logs on stdout
+    #[arg(long, global = true)]
+    headless: bool,
+    /// Transport fault injection for testing
+    #[arg(long, global = true, hide = true)]
+    pub chaos: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    pub fn headless(&self) -> bool {
+        self.headless || env::var("MCBD_HEADLESS").is_ok_and(|v| v == "1" || v == "true")
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Runs the sync daemon
+    Run,
+    /// Has the running daemon send every file of a world right away
+    SyncNow {
+        #[arg(required_unless_present = "full", conflicts_with = "full")]
+        world: Option<String>,
+        /// Every world instead of one
+        #[arg(long)]
+        full: bool,
+        /// Only to this device or mirror
+        #[arg(long)]
+        device: Option<String>,
+        /// Send outside the gaming mode limits and performance.max_transfers
+        #[arg(long)]
+        ignore_limits: bool,
+    },
+    /// Shows the running daemon's devices and worlds
+    Status,
+    /// Lists the world folders with the names Minecraft shows
+    ListWorlds,
+    /// Compares the worlds directory with the stored index
+    Verify { world: Option<String> },
+    /// Writes a world as a .mcworld file
+    Export { world: String, file: PathBuf },
+    /// Unpacks a .mcworld file into a new world folder, named after the
+    /// file unless given
+    Import { file: PathBuf, world: Option<String> },
+    /// Shows a code for another device to enter, or enters the code another
+    /// device shows
+    Pair {
+        #[arg(requires = "code")]
+        address: Option<String>,
+        code: Option<String>,
+        /// Port to wait on for the other device, the sync port by default
+        #[arg(long, conflicts_with = "address")]
+        port: Option<u16>,
+    },
+    /// Lists devices on the local network, or adds one
+    Discover {
+        #[command(subcommand)]
+        command: Option<DiscoverCommand>,
+    },
+    /// Removes or renames a configured device
+    Device {
+        #[command(subcommand)]
+        command: DeviceCommand,
+    },
+    /// Moves this device's name and sync state to a new install
+    Identity {
+        #[command(subcommand)]
+        command: IdentityCommand,
+    },
+    /// Shares a single world with a friend
+    Share {
+        #[command(subcommand)]
+        command: ShareCommand,
+    },
+    /// Pulls a shared world without running the daemon
+    Guest {
+        token: String,
+        /// Address of the device that shared the world
+        address: String,
+        /// Pull again every sync interval
+        #[arg(long)]
+        keep_updated: bool,
+    },
+    /// Creates a one-time download link for a world
+    DownloadLink {
+        world: String,
+        #[arg(long, default_value_t = 24)]
+        hours: u64,
+    },
+    /// Creates a one-time upload link for a new world
+    UploadLink {
+        world: String,
+        /// Group to add the new world to
+        #[arg(long)]
+        group: Option<String>,
+        #[arg(long, default_value_t = 24)]
+        hours: u64,
+    },
+    /// Shows this device's certificate fingerprint
+    Tls {
+        #[command(subcommand)]
+        command: TlsCommand,
+    },
+    /// Lists, restores, opens and compares snapshots of a world
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Summarizes a world or one of its snapshots
+    Inspect { world: String, snapshot: Option<String> },
+    /// Reports the space worlds and snapshots take and what syncing saved
+    Stats {
+        /// Days of transfers to report on
+        #[arg(long, default_value_t = 30)]
+        days: u64,
+    },
+    /// Shows the summary the `report` config section emails
+    Report {
+        /// Email it now instead of printing it
+        #[arg(long)]
+        send: bool,
+    },
+    /// Undoes the last burst of received changes to a world
+    Undo { world: String },
+    /// Snapshots a world one last time and stops syncing it on this device
+    Archive {
+        #[arg(required_unless_present = "proposed", conflicts_with_all = ["proposed", "yes"])]
+        world: Option<String>,
+        /// Asks about each world unchanged for archive.propose_after_days
+        #[arg(long)]
+        proposed: bool,
+        /// Archives every proposed world without asking
+        #[arg(long, requires = "proposed")]
+        yes: bool,
+    },
+    /// Syncs an archived world again
+    Unarchive { world: String },
+    /// Lists the deletions other devices sent that wait to be applied here
+    Deletions {
+        /// A file or folder, such as a world, to apply or keep the deletions of
+        #[arg(requires = "action")]
+        path: Option<String>,
+        /// Deletes the files at the daemon's next check
+        #[arg(long, group = "action")]
+        apply: bool,
+        /// Keeps the files and forgets the deletions
+        #[arg(long, group = "action")]
+        keep: bool,
+    },
+    /// Lists, shows and replays the received changes to a world
+    Journal {
+        #[command(subcommand)]
+        command: JournalCommand,
+    },
+    /// Takes the chunks another copy of a world changed since a snapshot
+    Merge {
+        world: String,
+        /// Snapshot both copies started from
+        base: String,
+        /// The other copy, a snapshot or a folder
+        theirs: String,
+        #[arg(long)]
+        yes: bool,
+    },
+    /// Relays connections between devices that cannot reach each other
+    Relay {
+        #[arg(long)]
+        port: Option<u16>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DiscoverCommand {
+    /// Adds a device found on the local network
+    Add {
+        name: String,
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DeviceCommand {
+    Remove {
+        name: String,
+        /// Send the device what it has not received yet first
+        #[arg(long)]
+        final_sync: bool,
+    },
+    Rename { old: String, new: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IdentityCommand {
+    Export { file: PathBuf },
+    Import { file: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum ShareCommand {
+    Create {
+        world: String,
+        /// The friend's device name
+        friend: String,
+        /// The friend's address; without one the friend can only pull as a guest
+        address: Option<String>,
+        #[arg(long, default_value_t = 7)]
+        days: u64,
+        #[arg(long)]
+        read_write: bool,
+    },
+    Join {
+        token: String,
+        /// The host's device name
+        host: String,
+        /// The host's address
+        address: String,
+    },
+    /// Revokes a share by its name or the friend's device name
+    Revoke { name: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TlsCommand {
+    Fingerprint,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SnapshotCommand {
+    List { world: String },
+    /// Restores the latest snapshot, or the one named
+    Restore { world: String, name: Option<String> },
+    /// Opens the latest snapshot, or the one named, read-only
+    View { world: String, name: Option<String> },
+    Close { world: String, name: String },
+    Diff { world: String, a: String, b: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum JournalCommand {
+    List { world: String },
+    Show { world: String, set: u64 },
+    /// Rebuilds a world as of a change set in another folder
+    Replay { world: String, set: u64, target: PathBuf },
+}
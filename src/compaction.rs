@@ -0,0 +1,72 @@
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use crate::config::IndexConfig;
+use crate::file_manager::{FileManager, Tombstone};
+use crate::manifest::SyncCursor;
+
+/// What a compaction dropped.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    /// Past the retention and acknowledged by every peer.
+    pub expired: usize,
+    /// Over the size limit, oldest first.
+    pub evicted: usize,
+}
+
+/// Whether `cursor` shows the peer acknowledged a manifest without the
+/// deleted file, built after it was deleted.
+fn acknowledged(cursor: Option<&SyncCursor>, tombstone: &Tombstone) -> bool {
+    cursor.is_some_and(|cursor| cursor.acknowledged_at >= tombstone.deleted_at && !cursor.manifest.entries.contains_key(&tombstone.path))
+}
+
+/// The longer of the local retention and the one the peer announced.
+pub fn negotiated_retention(cursor: Option<&SyncCursor>, config: &IndexConfig) -> Duration {
+    let days = cursor.and_then(|cursor| cursor.peer_retention_days).unwrap_or(0).max(config.tombstone_retention_days);
+    Duration::from_secs(days * 24 * 60 * 60)
+}
+
+/// A peer last seen longer ago than the negotiated retention. Tombstones it
+/// has not seen may already be gone, so it needs a full reconcile.
+fn lapsed(cursor: &SyncCursor, config: &IndexConfig, now: SystemTime) -> bool {
+    now.duration_since(cursor.acknowledged_at).is_ok_and(|offline| offline > negotiated_retention(Some(cursor), config))
+}
+
+/// Known peers that lapsed and are not marked for a reconcile yet.
+pub fn lapsed_peers(cursors: &HashMap<String, SyncCursor>, peers: &[String], config: &IndexConfig, now: SystemTime) -> Vec<String> {
+    peers.iter()
+        .filter(|peer| cursors.get(*peer).is_some_and(|cursor| !cursor.needs_reconcile && lapsed(cursor, config, now)))
+        .cloned()
+        .collect()
+}
+
+/// Drops tombstones older than the retention negotiated with every peer once
+/// each peer acknowledged the deletion, then the oldest ones beyond
+/// `max_tombstones`. Lapsed peers are not waited for, peers never seen are.
+pub fn compact(file_manager: &mut FileManager, cursors: &HashMap<String, SyncCursor>, peers: &[String], config: &IndexConfig, now: SystemTime) -> Compaction {
+    let retention = peers.iter()
+        .map(|peer| negotiated_retention(cursors.get(peer), config))
+        .fold(negotiated_retention(None, config), Duration::max);
+    let waiting: Vec<Option<&SyncCursor>> = peers.iter()
+        .map(|peer| cursors.get(peer))
+        .filter(|cursor| cursor.is_none_or(|cursor| !cursor.needs_reconcile && !lapsed(cursor, config, now)))
+        .collect();
+    let expired = file_manager.retain_tombstones(|tombstone| {
+        let old = now.duration_since(tombstone.deleted_at).is_ok_and(|age| age >= retention);
+        !(old && waiting.iter().all(|cursor| acknowledged(*cursor, tombstone)))
+    });
+
+    let mut tombstones = file_manager.tombstones();
+    let over = tombstones.len().saturating_sub(config.max_tombstones);
+    let evicted = if over > 0 {
+        tombstones.sort_by_key(|tombstone| tombstone.deleted_at);
+        let oldest: HashSet<PathBuf> = tombstones.into_iter().take(over).map(|tombstone| tombstone.path).collect();
+        let evicted = file_manager.retain_tombstones(|tombstone| !oldest.contains(&tombstone.path));
+        warn!("Dropped {} tombstones over the limit of {}, peers that missed those deletions may restore the files", evicted, config.max_tombstones);
+        evicted
+    } else {
+        0
+    };
+    Compaction { expired, evicted }
+}
@@ -0,0 +1,126 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Serialize, Deserialize};
+use std::io::Read;
+use std::path::Path;
+use tokio_util::bytes::Bytes;
+
+/// Files that are stored compressed already. Bedrock compresses every
+/// leveldb block in `.ldb` tables, and packs are zip archives.
+const COMPRESSED_EXTENSIONS: &[&str] = &["ldb", "zip", "mcworld", "mcpack", "mcaddon", "mctemplate", "png", "jpg", "jpeg"];
+
+/// Bytes looked at to estimate how compressible a file is.
+const SAMPLE_LEN: usize = 4096;
+
+/// Above this many bits per byte the sample looks like compressed or random
+/// data, and compressing the file would only cost CPU.
+const MAX_ENTROPY: f64 = 7.5;
+
+/// Smaller payloads are sent as they are, the saving would not be noticeable.
+const MIN_LEN: usize = 512;
+
+/// zstd level for bulk messages, fast enough to keep up with a home uplink.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Largest message a compressed frame may unpack to, so a tiny frame cannot
+/// exhaust memory.
+const MAX_DECOMPRESSED: u64 = 1024 * 1024 * 1024;
+
+/// How compressible bulk messages are packed on the wire. Peers agree on one
+/// when a multiplexed connection opens; lz4 is what every peer understands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Codec {
+    Lz4,
+    /// Packs world files tighter than lz4, for slow links between houses.
+    #[default]
+    Zstd,
+}
+
+impl Codec {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::Lz4 => "lz4",
+            Codec::Zstd => "zstd",
+        }
+    }
+
+    /// Codecs a peer preferring `self` can decompress, best first.
+    pub fn accepted(&self) -> Vec<String> {
+        match self {
+            Codec::Zstd => vec![Codec::Zstd.name().to_string(), Codec::Lz4.name().to_string()],
+            Codec::Lz4 => vec![Codec::Lz4.name().to_string()],
+        }
+    }
+
+    /// The codec to send with, given what the peer accepts.
+    pub fn negotiate(&self, peer_accepts: &[String]) -> Codec {
+        match self {
+            Codec::Zstd if peer_accepts.iter().any(|c| c == Codec::Zstd.name()) => Codec::Zstd,
+            _ => Codec::Lz4,
+        }
+    }
+}
+
+/// Whether `content` of the file at `path` is worth compressing for transfer.
+pub fn should_compress(path: &Path, content: &[u8]) -> bool {
+    if content.len() < MIN_LEN {
+        return false;
+    }
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+    if extension.is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.as_str())) {
+        return false;
+    }
+    entropy(&content[..content.len().min(SAMPLE_LEN)]) <= MAX_ENTROPY
+}
+
+/// Shannon entropy of `sample` in bits per byte, from 0 (constant) to 8 (random).
+pub fn entropy(sample: &[u8]) -> f64 {
+    if sample.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0usize; 256];
+    for byte in sample {
+        counts[*byte as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    counts
+        .iter()
+        .filter(|c| **c > 0)
+        .map(|c| {
+            let p = *c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+pub fn compress_zstd(data: &[u8]) -> Result<Bytes> {
+    Ok(Bytes::from(zstd::bulk::compress(data, ZSTD_LEVEL)?))
+}
+
+pub fn decompress_zstd(data: &[u8]) -> Result<Bytes> {
+    let mut out = Vec::new();
+    zstd::stream::read::Decoder::new(data)?
+        .take(MAX_DECOMPRESSED + 1)
+        .read_to_end(&mut out)
+        .map_err(|e| anyhow!("Invalid zstd payload: {}", e))?;
+    if out.len() as u64 > MAX_DECOMPRESSED {
+        bail!("zstd payload unpacks to more than {} bytes", MAX_DECOMPRESSED);
+    }
+    Ok(Bytes::from(out))
+}
+
+pub fn compress(data: &[u8]) -> Bytes {
+    Bytes::from(lz4_flex::compress_prepend_size(data))
+}
+
+/// Unpacks an lz4 payload, refusing one whose size header claims more than
+/// `MAX_DECOMPRESSED` before anything is allocated for it.
+pub fn decompress(data: &[u8]) -> Result<Bytes> {
+    let (size, _) = lz4_flex::block::uncompressed_size(data).map_err(|e| anyhow!("Invalid compressed payload: {}", e))?;
+    if size as u64 > MAX_DECOMPRESSED {
+        bail!("lz4 payload claims to unpack to {} bytes, more than {}", size, MAX_DECOMPRESSED);
+    }
+    lz4_flex::decompress_size_prepended(data)
+        .map(Bytes::from)
+        .map_err(|e| anyhow!("Invalid compressed payload: {}", e))
+}
@@ -1,49 +1,1137 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use serde::{Serialize, Deserialize};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use crate::android;
+use crate::churn;
+use crate::compression::Codec;
+use crate::exclusions;
+use crate::platform::{self, Edition};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     pub server: ServerConfig,
     pub sync: SyncConfig,
     pub paths: PathConfig,
+    #[serde(default)]
+    pub watch: WatchConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    #[serde(default)]
+    pub index: IndexConfig,
+    #[serde(default)]
+    pub performance: PerformanceConfig,
+    #[serde(default)]
+    pub gaming_mode: GamingModeConfig,
+    #[serde(default)]
+    pub network_change: NetworkChangeConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub deletions: DeletionConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub telemetry: Option<TelemetryConfig>,
+    /// Emails a summary of syncing. Without this section none is sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<ReportConfig>,
+    /// Publishes sync state to an MQTT broker, for Home Assistant and the
+    /// like. Without this section nothing is published.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttConfig>,
+    /// Encrypts sync connections. Without this section they are plain TCP.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    /// Local folders, such as on a second drive, kept a copy of the worlds
+    /// directory as a continuous backup.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirrors: Vec<MirrorConfig>,
+    /// Android devices synced over ADB.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub android: Vec<AndroidConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub port: u16,
     pub host: String,
+    /// Transports to accept peers on. Without this section the server only
+    /// listens for TCP on `port`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listeners: Option<ListenersConfig>,
+    /// Forward the listener ports on the router, see `port_mapping`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port_mapping: Option<PortMappingConfig>,
+    /// `host:port` of a relay to also accept peers through, for when this
+    /// device cannot accept connections. Peers then use the address
+    /// `relay://<host:port>/<this device's name>`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+    /// Key the relay has configured for this device, proving its name there.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_key: Option<String>,
+}
+
+impl ServerConfig {
+    pub fn listeners(&self) -> ListenersConfig {
+        self.listeners.clone().unwrap_or_else(|| ListenersConfig::tcp(self.port))
+    }
+}
+
+/// Transports the sync server listens on at the same time, each on its own port.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ListenersConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<PortConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quic: Option<QuicConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub websocket: Option<PortConfig>,
+}
+
+impl ListenersConfig {
+    pub fn tcp(port: u16) -> Self {
+        Self { tcp: Some(PortConfig { port }), ..Self::default() }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortConfig {
+    pub port: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct QuicConfig {
+    pub port: u16,
+    /// PEM certificate chain and private key. A self-signed certificate is
+    /// generated on startup when they are not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+/// Port forwarding requested from the router with UPnP IGD, or NAT-PMP
+/// where UPnP is not available.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PortMappingConfig {
+    #[serde(default = "default_true")]
+    pub upnp: bool,
+    #[serde(default = "default_true")]
+    pub nat_pmp: bool,
+    /// Router address for NAT-PMP, with an optional port. Guessed as the
+    /// `.1` address of this device's network when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<String>,
+    /// Seconds the router keeps a mapping. They are renewed halfway.
+    #[serde(default = "default_lease")]
+    pub lease: u64,
+}
+
+impl Default for PortMappingConfig {
+    fn default() -> Self {
+        Self { upnp: true, nat_pmp: true, gateway: None, lease: default_lease() }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_lease() -> u64 {
+    3600
+}
+
+/// TLS for sync connections. Every device needs it, since a device with TLS
+/// only accepts TLS connections.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TlsConfig {
+    /// PEM certificate chain and private key. A self-signed certificate is
+    /// generated into the state directory when they are not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// SHA-256 fingerprints of the certificates of the devices to connect to.
+    #[serde(default)]
+    pub trusted: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SyncConfig {
+    /// Name this device reports to peers. Defaults to the rendezvous ID or
+    /// the computer name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
     pub devices: Vec<Device>,
     pub conflict_resolution: String,
+    /// `conflict_resolution` of single worlds, by world folder.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub conflict_overrides: BTreeMap<String, String>,
     pub sync_interval: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rendezvous: Option<RendezvousConfig>,
+    /// Further groups of devices, each syncing its own selection of worlds.
+    /// `devices` forms the default group next to them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<GroupConfig>,
+    /// Days without a completed sync after which a device is reported stale.
+    #[serde(default = "default_stale_after_days")]
+    pub stale_after_days: u64,
+    /// Stop queuing changes for stale devices, so the queue does not grow
+    /// while they are away. They get every file again when they return.
+    #[serde(default)]
+    pub pause_stale_devices: bool,
+    /// Codec for compressible file contents sent to peers that accept it.
+    /// Others get lz4.
+    #[serde(default)]
+    pub compression: Codec,
+    /// Announce this device on the local network and connect to devices
+    /// where they announce themselves, see `discovery`.
+    #[serde(default)]
+    pub discovery: bool,
+    /// Receive changes into a copy of their world and swap it in once all
+    /// of them arrived, instead of writing into the world as they come.
+    #[serde(default = "default_true")]
+    pub atomic_apply: bool,
+}
+
+fn default_stale_after_days() -> u64 {
+    7
+}
+
+impl SyncConfig {
+    pub fn local_name(&self) -> String {
+        self.name.clone()
+            .or_else(|| self.rendezvous.as_ref().map(|r| r.id.clone()))
+            .or_else(|| env::var("COMPUTERNAME").ok())
+            .or_else(|| env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /// The default group made of `devices`, if it has any, and every
+    /// configured group.
+    pub fn groups(&self) -> Vec<GroupConfig> {
+        let default = (!self.devices.is_empty()).then(|| GroupConfig {
+            name: DEFAULT_GROUP.to_string(),
+            devices: self.devices.clone(),
+            worlds: Vec::new(),
+            key: None,
+            direction: Direction::Both,
+            expires_at: None,
+        });
+        default.into_iter().chain(self.groups.iter().cloned()).collect()
+    }
+
+    pub fn all_devices(&self) -> Vec<Device> {
+        self.groups().into_iter().flat_map(|g| g.devices).collect()
+    }
+
+    /// Device lists of the default group and every other group.
+    pub fn device_lists_mut(&mut self) -> impl Iterator<Item = &mut Vec<Device>> {
+        std::iter::once(&mut self.devices).chain(self.groups.iter_mut().map(|g| &mut g.devices))
+    }
+}
+
+/// Name of the group formed by `sync.devices`.
+pub const DEFAULT_GROUP: &str = "default";
+
+/// Devices that sync a selection of worlds, isolated from the other groups
+/// on the same daemon.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GroupConfig {
+    pub name: String,
+    pub devices: Vec<Device>,
+    /// World folder names shared with this group. Empty shares every world.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub worlds: Vec<String>,
+    /// Secret every member of the group configures. File contents for the
+    /// group are refused unless the sender proves it knows it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Whether this daemon sends to the group, receives from it, or both.
+    #[serde(default, skip_serializing_if = "Direction::is_both")]
+    pub direction: Direction,
+    /// Unix time after which the group no longer syncs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    #[default]
+    Both,
+    /// Changes go out, but file contents from the group are refused.
+    SendOnly,
+    /// Nothing is sent to the group.
+    ReceiveOnly,
+}
+
+impl Direction {
+    pub fn is_both(&self) -> bool {
+        *self == Direction::Both
+    }
+
+    pub fn sends(&self) -> bool {
+        *self != Direction::ReceiveOnly
+    }
+
+    pub fn receives(&self) -> bool {
+        *self != Direction::SendOnly
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Device {
     pub name: String,
+    /// `host:port` of the peer. The host may be a dynamic-DNS name, it is
+    /// resolved again on every connection attempt.
+    #[serde(default)]
     pub address: String,
+    /// Rendezvous ID looked up on the relay when `address` is empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rendezvous: Option<String>,
+    /// Secret shared with the device. Connections to it prove this device's
+    /// name with it, and once any device has a key, only devices that prove
+    /// theirs may connect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl Device {
+    /// Whether changes can be pushed to the device.
+    pub fn is_reachable(&self) -> bool {
+        !self.address.is_empty() || self.rendezvous.is_some()
+    }
+
+    /// A device from `host:port`, or `@id` for a rendezvous ID.
+    pub fn from_target(name: &str, target: &str) -> Self {
+        match target.strip_prefix('@') {
+            Some(id) => Device { name: name.to_string(), address: String::new(), rendezvous: Some(id.to_string()), key: None },
+            None => Device { name: name.to_string(), address: target.to_string(), rendezvous: None, key: None },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RendezvousConfig {
+    /// Address of the relay that keeps the rendezvous registry.
+    pub relay: String,
+    /// Name this device registers under, so peers can find it without a static IP.
+    pub id: String,
+    #[serde(default = "default_register_interval")]
+    pub register_interval: u64,
+}
+
+fn default_register_interval() -> u64 {
+    300
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PathConfig {
     pub minecraft_worlds: String,
+    /// Directory for the index, staging, trash and snapshots. Defaults to the
+    /// platform app-data directory; must not be inside `minecraft_worlds`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state_dir: Option<String>,
+    /// When the files of `minecraft_worlds` are hashed.
+    #[serde(default, skip_serializing_if = "HashPolicy::is_eager")]
+    pub hashing: HashPolicy,
+    /// `minecraft_worlds` is a plain directory on a device without Minecraft,
+    /// such as an always-on server keeping every world for the others: the
+    /// Minecraft install paths are not tried and the directory is created if
+    /// missing.
+    #[serde(default)]
+    pub seed: bool,
+    /// `minecraft_worlds` is on a network share or an external drive that
+    /// may go away. The daemon waits for it at startup instead of giving up.
+    #[serde(default)]
+    pub removable: bool,
+    /// Which Minecraft `minecraft_worlds` belongs to, for paths that do not
+    /// tell, such as a seed's. Worlds are only synced with devices of the
+    /// same edition, and only its folders are tried if `minecraft_worlds`
+    /// does not exist.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edition: Option<Edition>,
+    /// Further directories synced along with `minecraft_worlds`, by name,
+    /// such as `development_behavior_packs`. A root's files go into the root
+    /// of the same name on other devices.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub roots: BTreeMap<String, String>,
+}
+
+impl PathConfig {
+    /// `minecraft_worlds` with `~` and environment variables expanded.
+    pub fn worlds_dir(&self) -> PathBuf {
+        platform::expand(&self.minecraft_worlds)
+    }
+
+    /// `roots` with `~` and environment variables expanded.
+    pub fn roots(&self) -> BTreeMap<String, PathBuf> {
+        self.roots.iter().map(|(name, path)| (name.clone(), platform::expand(path))).collect()
+    }
+
+    /// The configured `edition`, or the one the worlds directory's path
+    /// belongs to.
+    pub fn edition(&self) -> Option<Edition> {
+        self.edition.or_else(|| platform::edition_of(&self.worlds_dir()))
+    }
+}
+
+/// When files are hashed. Hashes are what manifests compare, so a file is
+/// hashed at the latest before it is compared with a peer's copy or sent.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HashPolicy {
+    /// In the background after startup, and whenever a file changes.
+    #[default]
+    Eager,
+    /// Only on demand, for worlds that rarely change: a save that rewrites a
+    /// file many times costs one hash, and worlds no group sends are never
+    /// hashed.
+    Lazy,
+    /// Files up to `HYBRID_LIMIT` eagerly, such as `level.dat`, larger ones
+    /// on demand.
+    Hybrid,
+}
+
+/// Largest file `HashPolicy::Hybrid` hashes eagerly.
+pub const HYBRID_LIMIT: u64 = 1024 * 1024;
+
+impl HashPolicy {
+    pub fn is_eager(&self) -> bool {
+        *self == HashPolicy::Eager
+    }
+
+    /// Whether a file of `size` bytes is hashed as soon as it is found or
+    /// changed, rather than when it is needed.
+    pub fn hashes_eagerly(&self, size: u64) -> bool {
+        match self {
+            HashPolicy::Eager => true,
+            HashPolicy::Lazy => false,
+            HashPolicy::Hybrid => size <= HYBRID_LIMIT,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WatchConfig {
+    /// Hash and sync files even when only their attributes changed. Off by
+    /// default because antivirus scans touch attributes constantly.
+    #[serde(default)]
+    pub process_metadata_changes: bool,
+    /// Extra directory names or root-relative paths to leave out of scanning,
+    /// watching and transfer, on top of the tool's own data directories.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Glob rules for files left out the same way, such as those Minecraft
+    /// touches constantly while a world is open. Replaces `DEFAULT_IGNORE`.
+    #[serde(default = "default_ignore")]
+    pub ignore: Vec<String>,
+    /// Glob rules, as `ignore`, for files whose changes are held until
+    /// another file of their world changes. Replaces `DEFAULT_LOW_VALUE`.
+    #[serde(default = "default_low_value")]
+    pub low_value: Vec<String>,
+    /// Watcher events waiting to be processed before further ones are
+    /// coalesced per path.
+    #[serde(default = "default_event_queue")]
+    pub event_queue: usize,
+    /// How changes below the worlds directory are noticed.
+    #[serde(default)]
+    pub mode: WatchMode,
+    /// Seconds between scans of a polled worlds directory.
+    #[serde(default = "default_poll_interval")]
+    pub poll_interval: u64,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        Self {
+            process_metadata_changes: false,
+            exclude: Vec::new(),
+            ignore: default_ignore(),
+            low_value: default_low_value(),
+            event_queue: default_event_queue(),
+            mode: WatchMode::default(),
+            poll_interval: default_poll_interval(),
+        }
+    }
+}
+
+fn default_ignore() -> Vec<String> {
+    exclusions::DEFAULT_IGNORE.iter().map(|pattern| pattern.to_string()).collect()
+}
+
+fn default_low_value() -> Vec<String> {
+    churn::DEFAULT_LOW_VALUE.iter().map(|pattern| pattern.to_string()).collect()
+}
+
+fn default_event_queue() -> usize {
+    1024
+}
+
+fn default_poll_interval() -> u64 {
+    10
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchMode {
+    /// Events from the operating system, falling back to polling for a
+    /// directory that cannot be watched.
+    #[default]
+    Events,
+    /// Scans every `poll_interval` seconds and compares each file's size and
+    /// modification time with the index, for network shares and other
+    /// filesystems whose events are missing or unreliable.
+    Polling,
+}
+
+/// Threads and parallelism, to turn the daemon down on a weak always-on
+/// device or up on a fast desktop.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PerformanceConfig {
+    /// Threads running the daemon's tasks. One per CPU core when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worker_threads: Option<usize>,
+    /// Most threads at once for blocking work such as world imports and
+    /// exports.
+    #[serde(default = "default_blocking_threads")]
+    pub blocking_threads: usize,
+    /// Threads hashing files while the worlds directory is scanned.
+    #[serde(default = "default_hash_workers")]
+    pub hash_workers: usize,
+    /// Transfers to each device at the same time, each over its own
+    /// connection.
+    #[serde(default = "default_transfer_streams")]
+    pub transfer_streams: usize,
+    /// Most files sent at the same time to all devices together. No limit
+    /// but `transfer_streams` per device when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_transfers: Option<usize>,
+    /// MiB of chunks all devices together may send ahead of what was
+    /// written to disk. Lower it for a slow disk such as an SD card.
+    #[serde(default = "default_receive_window")]
+    pub receive_window: usize,
+    /// Percent of all CPU cores together that hashing and compression may
+    /// use on average. No limit when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_budget: Option<u8>,
+}
+
+impl Default for PerformanceConfig {
+    fn default() -> Self {
+        Self {
+            worker_threads: None,
+            blocking_threads: default_blocking_threads(),
+            hash_workers: default_hash_workers(),
+            transfer_streams: default_transfer_streams(),
+            max_transfers: None,
+            receive_window: default_receive_window(),
+            cpu_budget: None,
+        }
+    }
+}
+
+fn default_blocking_threads() -> usize {
+    512
+}
+
+fn default_hash_workers() -> usize {
+    1
+}
+
+fn default_transfer_streams() -> usize {
+    1
+}
+
+fn default_receive_window() -> usize {
+    16
+}
+
+/// What sync may use while Minecraft is in the foreground on this device,
+/// to leave the CPU, disk and network to the game.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct GamingModeConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Threads hashing files at the same time.
+    #[serde(default = "default_hash_workers")]
+    pub hash_workers: usize,
+    /// MiB hashed per second. No limit when not set.
+    #[serde(default = "default_hash_mib_per_sec")]
+    pub hash_mib_per_sec: Option<u64>,
+    /// KiB sent per second to all devices together. No limit when not set.
+    #[serde(default = "default_send_kib_per_sec")]
+    pub send_kib_per_sec: Option<u64>,
+    /// Seconds between checks whether Minecraft is in the foreground.
+    #[serde(default = "default_gaming_check_interval")]
+    pub check_interval: u64,
+    /// Hold back the changes of worlds while Minecraft has them open, and
+    /// send them once it closes them. Checked every `check_interval` too.
+    #[serde(default = "default_true")]
+    pub pause_open_worlds: bool,
+    /// Send a world asked to be synced now while it is open from a Volume
+    /// Shadow Copy. Windows only, and the daemon must run as administrator.
+    #[serde(default)]
+    pub shadow_copies: bool,
+}
+
+impl Default for GamingModeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            hash_workers: default_hash_workers(),
+            hash_mib_per_sec: default_hash_mib_per_sec(),
+            send_kib_per_sec: default_send_kib_per_sec(),
+            check_interval: default_gaming_check_interval(),
+            pause_open_worlds: true,
+            shadow_copies: false,
+        }
+    }
+}
+
+fn default_hash_mib_per_sec() -> Option<u64> {
+    Some(16)
+}
+
+fn default_send_kib_per_sec() -> Option<u64> {
+    Some(1024)
+}
+
+fn default_gaming_check_interval() -> u64 {
+    5
+}
+
+/// What happens when this device's network changes, such as Wi-Fi joining
+/// another network or a VPN coming up.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct NetworkChangeConfig {
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Seconds between checks of the network interfaces.
+    #[serde(default = "default_network_check_interval")]
+    pub check_interval: u64,
+    /// Try devices that could not be reached again right away, instead of
+    /// after their backoff.
+    #[serde(default = "default_true")]
+    pub retry_peers: bool,
+    /// Exchange manifests right away, instead of at the next sync interval.
+    #[serde(default = "default_true")]
+    pub reconcile: bool,
+}
+
+impl Default for NetworkChangeConfig {
+    fn default() -> Self {
+        Self { enabled: true, check_interval: default_network_check_interval(), retry_peers: true, reconcile: true }
+    }
+}
+
+fn default_network_check_interval() -> u64 {
+    5
+}
+
+/// When worlds are proposed for archiving.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ArchiveConfig {
+    /// Days without a change after which a world is proposed for archiving;
+    /// 0 proposes none.
+    #[serde(default)]
+    pub propose_after_days: u64,
+}
+
+/// When files other devices deleted are deleted here.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DeletionConfig {
+    /// Seconds a received deletion waits, during which a new version of the
+    /// file cancels it; 0 deletes right away.
+    #[serde(default)]
+    pub grace_period: u64,
+    /// Waits for `mcbd-world-sync deletions --apply` instead.
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+impl DeletionConfig {
+    /// Whether received deletions wait rather than being applied right away.
+    pub fn deferred(&self) -> bool {
+        self.grace_period > 0 || self.confirm
+    }
+}
+
+/// How long the index remembers deleted files, and how often it is compacted.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IndexConfig {
+    /// Seconds between compactions.
+    #[serde(default = "default_compact_interval")]
+    pub compact_interval: u64,
+    /// Days a tombstone is kept at least, and longer until every peer
+    /// acknowledged the deletion.
+    #[serde(default = "default_tombstone_retention_days")]
+    pub tombstone_retention_days: u64,
+    /// Hard limit; the oldest tombstones beyond it are dropped even if a peer
+    /// has not seen them yet.
+    #[serde(default = "default_max_tombstones")]
+    pub max_tombstones: usize,
+    /// Days a chunk of a received file is kept after it was last used.
+    #[serde(default = "default_chunk_retention_days")]
+    pub chunk_retention_days: u64,
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        Self {
+            compact_interval: default_compact_interval(),
+            tombstone_retention_days: default_tombstone_retention_days(),
+            max_tombstones: default_max_tombstones(),
+            chunk_retention_days: default_chunk_retention_days(),
+        }
+    }
+}
+
+fn default_compact_interval() -> u64 {
+    3600
+}
+
+fn default_tombstone_retention_days() -> u64 {
+    30
+}
+
+fn default_chunk_retention_days() -> u64 {
+    14
+}
+
+fn default_max_tombstones() -> usize {
+    10_000
+}
+
+/// Local HTTP server for metrics and status.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct HttpConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_http_bind")]
+    pub bind: String,
+    /// Serve the one-time .mcworld download links made with `download-link`.
+    #[serde(default)]
+    pub downloads: bool,
+    /// Accept .mcworld uploads through the links made with `upload-link`.
+    #[serde(default)]
+    pub uploads: bool,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: default_http_bind(),
+            downloads: false,
+            uploads: false,
+        }
+    }
+}
+
+fn default_http_bind() -> String {
+    "127.0.0.1:8081".to_string()
+}
+
+/// A local backup folder, see `mirror::Mirror`. Its name is what the log and
+/// the transfer queue call it, and must not be a device's.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MirrorConfig {
+    pub name: String,
+    pub path: String,
+    /// Hard-link files when the mirror is on the same volume as the worlds
+    /// directory, instead of copying them.
+    #[serde(default = "default_true")]
+    pub hard_links: bool,
+}
+
+/// An Android device synced over ADB, see `android::AndroidDevice`. Its name
+/// must not be another device's or mirror's.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AndroidConfig {
+    pub name: String,
+    /// `adb` serial of the device, or `host:port` for one reached over the
+    /// network. May be left out while only one device is connected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// The worlds directory on the device.
+    #[serde(default = "default_android_worlds")]
+    pub worlds: String,
+    /// The `adb` program, if it is not on the `PATH`.
+    #[serde(default = "default_adb")]
+    pub adb: String,
+}
+
+fn default_android_worlds() -> String {
+    android::DEFAULT_WORLDS.to_string()
+}
+
+fn default_adb() -> String {
+    "adb".to_string()
+}
+
+/// OTLP export of spans and metrics to an OpenTelemetry collector.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TelemetryConfig {
+    /// Collector base URL for OTLP over HTTP, e.g. `http://localhost:4318`.
+    pub otlp_endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    #[serde(default = "default_export_interval")]
+    pub export_interval: u64,
+}
+
+fn default_service_name() -> String {
+    "mcbd-world-sync".to_string()
+}
+
+fn default_export_interval() -> u64 {
+    30
+}
+
+/// The broker sync state is published to, see `home_assistant`.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MqttConfig {
+    /// `host` or `host:port`, port 1883 if left out.
+    pub broker: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Topics are published below `<topic>/<device name>`.
+    #[serde(default = "default_mqtt_topic")]
+    pub topic: String,
+    /// Where Home Assistant looks for discovery payloads.
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Seconds between updates.
+    #[serde(default = "default_mqtt_interval")]
+    pub interval: u64,
+}
+
+fn default_mqtt_topic() -> String {
+    "mcbd-world-sync".to_string()
+}
+
+fn default_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_mqtt_interval() -> u64 {
+    30
+}
+
+/// A summary of syncing emailed every day or week.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReportConfig {
+    #[serde(default)]
+    pub schedule: ReportSchedule,
+    pub from: String,
+    pub to: Vec<String>,
+    pub smtp: SmtpConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportSchedule {
+    Daily,
+    #[default]
+    Weekly,
+}
+
+impl ReportSchedule {
+    /// Days a report covers.
+    pub fn days(&self) -> u64 {
+        match self {
+            ReportSchedule::Daily => 1,
+            ReportSchedule::Weekly => 7,
+        }
+    }
+}
+
+/// The mail server reports are sent through.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: SmtpSecurity,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpSecurity {
+    /// Plain connection upgraded with STARTTLS, usually on port 587.
+    #[default]
+    Starttls,
+    /// TLS from the start, usually on port 465.
+    Tls,
+    /// No encryption, only for a relay on the same machine or network.
+    None,
 }
 
 impl Config {
-    pub fn load() -> Result<Self> {
-        let config_str = fs::read_to_string("config.json")?;
+    pub fn load(path: &Path) -> Result<Self> {
+        let config_str = fs::read_to_string(path).map_err(|e| anyhow!("Cannot read {}: {}", path.display(), e))?;
         let config: Config = serde_json::from_str(&config_str)?;
         Ok(config)
     }
 
-    pub fn save(&self) -> Result<()> {
+    /// Builds the whole configuration from `MCBD_*` environment variables for
+    /// headless (container) mode. Worlds and state default to paths under
+    /// `/data` so they can be mounted as volumes.
+    pub fn from_env() -> Result<Self> {
+        let var = |key: &str| env::var(key).ok().filter(|v| !v.is_empty());
+        let list = |key: &str| -> Vec<String> {
+            var(key).map(|v| v.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()).unwrap_or_default()
+        };
+        let number = |key: &str, default: u64| -> Result<u64> {
+            match var(key) {
+                Some(v) => v.parse().map_err(|_| anyhow!("{} must be a number, got '{}'", key, v)),
+                None => Ok(default),
+            }
+        };
+
+        // MCBD_DEVICE_KEYS=name=secret, for devices of any group
+        let device_keys = list("MCBD_DEVICE_KEYS")
+            .into_iter()
+            .map(|entry| entry.split_once('=').map(|(name, key)| (name.to_string(), key.to_string()))
+                .ok_or_else(|| anyhow!("Invalid device key '{}' in MCBD_DEVICE_KEYS, expected name=key", entry)))
+            .collect::<Result<HashMap<String, String>>>()?;
+        // MCBD_DEVICES=name=host:port,other=@rendezvous-id
+        let devices_var = |key: &str| -> Result<Vec<Device>> {
+            list(key)
+                .into_iter()
+                .map(|entry| {
+                    let (name, target) = entry.split_once('=')
+                        .ok_or_else(|| anyhow!("Invalid device '{}' in {}, expected name=address", entry, key))?;
+                    Ok(Device { key: device_keys.get(name).cloned(), ..Device::from_target(name, target) })
+                })
+                .collect()
+        };
+        let devices = devices_var("MCBD_DEVICES")?;
+
+        // MCBD_GROUPS=friends, then MCBD_GROUP_FRIENDS_DEVICES, _WORLDS and _KEY
+        let groups = list("MCBD_GROUPS")
+            .into_iter()
+            .map(|name| {
+                let prefix = format!("MCBD_GROUP_{}", name.to_uppercase().replace('-', "_"));
+                Ok(GroupConfig {
+                    devices: devices_var(&format!("{}_DEVICES", prefix))?,
+                    worlds: list(&format!("{}_WORLDS", prefix)),
+                    key: var(&format!("{}_KEY", prefix)),
+                    direction: Direction::Both,
+                    expires_at: None,
+                    name,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let rendezvous = match (var("MCBD_RENDEZVOUS_RELAY"), var("MCBD_RENDEZVOUS_ID")) {
+            (Some(relay), Some(id)) => Some(RendezvousConfig {
+                relay,
+                id,
+                register_interval: number("MCBD_RENDEZVOUS_INTERVAL", default_register_interval())?,
+            }),
+            (None, None) => None,
+            _ => return Err(anyhow!("MCBD_RENDEZVOUS_RELAY and MCBD_RENDEZVOUS_ID must be set together")),
+        };
+
+        let port_var = |key: &str| -> Result<Option<u16>> {
+            match var(key) {
+                Some(v) => v.parse().map(Some).map_err(|_| anyhow!("{} must be a port number, got '{}'", key, v)),
+                None => Ok(None),
+            }
+        };
+        let port = port_var("MCBD_PORT")?.unwrap_or(8080);
+        let quic_port = port_var("MCBD_QUIC_PORT")?;
+        let websocket_port = port_var("MCBD_WEBSOCKET_PORT")?;
+        let listeners = (quic_port.is_some() || websocket_port.is_some()).then(|| ListenersConfig {
+            tcp: Some(PortConfig { port }),
+            quic: quic_port.map(|port| QuicConfig { port, cert: var("MCBD_QUIC_CERT"), key: var("MCBD_QUIC_KEY") }),
+            websocket: websocket_port.map(|port| PortConfig { port }),
+        });
+        Ok(Config {
+            server: ServerConfig {
+                port,
+                host: var("MCBD_HOST").unwrap_or_else(|| "0.0.0.0".to_string()),
+                listeners,
+                port_mapping: (var("MCBD_PORT_MAPPING").is_some_and(|v| v == "1" || v == "true") || var("MCBD_PORT_MAPPING_GATEWAY").is_some()).then(|| PortMappingConfig {
+                    gateway: var("MCBD_PORT_MAPPING_GATEWAY"),
+                    ..PortMappingConfig::default()
+                }),
+                relay: var("MCBD_RELAY"),
+                relay_key: var("MCBD_RELAY_KEY"),
+            },
+            sync: SyncConfig {
+                name: var("MCBD_NAME"),
+                devices,
+                conflict_resolution: var("MCBD_CONFLICT_RESOLUTION").unwrap_or_else(|| "newest".to_string()),
+                // MCBD_CONFLICT_OVERRIDES=Survival=manual,Creative=prefer:laptop
+                conflict_overrides: list("MCBD_CONFLICT_OVERRIDES")
+                    .into_iter()
+                    .map(|entry| entry.split_once('=').map(|(world, resolution)| (world.to_string(), resolution.to_string()))
+                        .ok_or_else(|| anyhow!("Invalid override '{}' in MCBD_CONFLICT_OVERRIDES, expected world=resolution", entry)))
+                    .collect::<Result<BTreeMap<_, _>>>()?,
+                sync_interval: number("MCBD_SYNC_INTERVAL", 60)?,
+                rendezvous,
+                groups,
+                stale_after_days: number("MCBD_STALE_AFTER_DAYS", default_stale_after_days())?,
+                pause_stale_devices: var("MCBD_PAUSE_STALE_DEVICES").is_some_and(|v| v == "1" || v == "true"),
+                compression: match var("MCBD_COMPRESSION").as_deref() {
+                    None | Some("zstd") => Codec::Zstd,
+                    Some("lz4") => Codec::Lz4,
+                    Some(other) => return Err(anyhow!("MCBD_COMPRESSION must be zstd or lz4, got '{}'", other)),
+                },
+                discovery: var("MCBD_DISCOVERY").is_some_and(|v| v == "1" || v == "true"),
+                atomic_apply: var("MCBD_ATOMIC_APPLY").is_none_or(|v| v == "1" || v == "true"),
+            },
+            paths: PathConfig {
+                minecraft_worlds: var("MCBD_WORLDS").unwrap_or_else(|| "/data/worlds".to_string()),
+                state_dir: Some(var("MCBD_STATE_DIR").unwrap_or_else(|| "/data/state".to_string())),
+                hashing: match var("MCBD_HASHING").as_deref() {
+                    None | Some("eager") => HashPolicy::Eager,
+                    Some("lazy") => HashPolicy::Lazy,
+                    Some("hybrid") => HashPolicy::Hybrid,
+                    Some(other) => return Err(anyhow!("MCBD_HASHING must be eager, lazy or hybrid, got '{}'", other)),
+                },
+                seed: var("MCBD_SEED").is_some_and(|v| v == "1" || v == "true"),
+                removable: var("MCBD_REMOVABLE").is_some_and(|v| v == "1" || v == "true"),
+                edition: match var("MCBD_EDITION").as_deref() {
+                    None => None,
+                    Some("release") => Some(Edition::Release),
+                    Some("preview") => Some(Edition::Preview),
+                    Some("education") => Some(Edition::Education),
+                    Some(other) => return Err(anyhow!("MCBD_EDITION must be release, preview or education, got '{}'", other)),
+                },
+                // MCBD_ROOTS=development_behavior_packs=/data/behavior_packs
+                roots: list("MCBD_ROOTS")
+                    .into_iter()
+                    .map(|entry| entry.split_once('=').map(|(name, path)| (name.to_string(), path.to_string()))
+                        .ok_or_else(|| anyhow!("Invalid root '{}' in MCBD_ROOTS, expected name=path", entry)))
+                    .collect::<Result<BTreeMap<_, _>>>()?,
+            },
+            watch: WatchConfig {
+                process_metadata_changes: var("MCBD_PROCESS_METADATA_CHANGES").is_some_and(|v| v == "1" || v == "true"),
+                exclude: list("MCBD_EXCLUDE"),
+                ignore: var("MCBD_IGNORE").map(|_| list("MCBD_IGNORE")).unwrap_or_else(default_ignore),
+                low_value: var("MCBD_LOW_VALUE").map(|_| list("MCBD_LOW_VALUE")).unwrap_or_else(default_low_value),
+                event_queue: number("MCBD_EVENT_QUEUE", default_event_queue() as u64)? as usize,
+                mode: match var("MCBD_WATCH_MODE").as_deref() {
+                    None | Some("events") => WatchMode::Events,
+                    Some("polling") => WatchMode::Polling,
+                    Some(other) => return Err(anyhow!("MCBD_WATCH_MODE must be events or polling, got '{}'", other)),
+                },
+                poll_interval: number("MCBD_POLL_INTERVAL", default_poll_interval())?,
+            },
+            index: IndexConfig {
+                compact_interval: number("MCBD_COMPACT_INTERVAL", default_compact_interval())?,
+                tombstone_retention_days: number("MCBD_TOMBSTONE_RETENTION_DAYS", default_tombstone_retention_days())?,
+                max_tombstones: number("MCBD_MAX_TOMBSTONES", default_max_tombstones() as u64)? as usize,
+                chunk_retention_days: number("MCBD_CHUNK_RETENTION_DAYS", default_chunk_retention_days())?,
+            },
+            performance: PerformanceConfig {
+                worker_threads: var("MCBD_WORKER_THREADS").map(|_| number("MCBD_WORKER_THREADS", 0)).transpose()?.map(|n| n as usize),
+                blocking_threads: number("MCBD_BLOCKING_THREADS", default_blocking_threads() as u64)? as usize,
+                hash_workers: number("MCBD_HASH_WORKERS", default_hash_workers() as u64)? as usize,
+                transfer_streams: number("MCBD_TRANSFER_STREAMS", default_transfer_streams() as u64)? as usize,
+                max_transfers: var("MCBD_MAX_TRANSFERS").map(|_| number("MCBD_MAX_TRANSFERS", 0)).transpose()?.map(|n| n as usize),
+                receive_window: number("MCBD_RECEIVE_WINDOW", default_receive_window() as u64)? as usize,
+                cpu_budget: var("MCBD_CPU_BUDGET").map(|_| number("MCBD_CPU_BUDGET", 0)).transpose()?.map(|n| n.min(100) as u8),
+            },
+            // Nobody plays on a headless device
+            gaming_mode: GamingModeConfig { enabled: false, ..GamingModeConfig::default() },
+            network_change: NetworkChangeConfig { enabled: var("MCBD_NETWORK_CHANGE").is_none_or(|v| v == "1" || v == "true"), ..NetworkChangeConfig::default() },
+            archive: ArchiveConfig { propose_after_days: number("MCBD_ARCHIVE_AFTER_DAYS", 0)? },
+            deletions: DeletionConfig {
+                grace_period: number("MCBD_DELETE_GRACE_PERIOD", 0)?,
+                confirm: var("MCBD_CONFIRM_DELETIONS").is_some_and(|v| v == "1" || v == "true"),
+            },
+            http: match var("MCBD_HTTP_BIND") {
+                Some(bind) => HttpConfig {
+                    enabled: true,
+                    bind,
+                    downloads: var("MCBD_HTTP_DOWNLOADS").is_some_and(|v| v == "1" || v == "true"),
+                    uploads: var("MCBD_HTTP_UPLOADS").is_some_and(|v| v == "1" || v == "true"),
+                },
+                None => HttpConfig::default(),
+            },
+            telemetry: match var("MCBD_OTLP_ENDPOINT") {
+                Some(otlp_endpoint) => Some(TelemetryConfig {
+                    otlp_endpoint,
+                    service_name: var("MCBD_SERVICE_NAME").unwrap_or_else(default_service_name),
+                    export_interval: number("MCBD_EXPORT_INTERVAL", default_export_interval())?,
+                }),
+                None => None,
+            },
+            report: match var("MCBD_SMTP_HOST") {
+                Some(host) => Some(ReportConfig {
+                    schedule: match var("MCBD_REPORT_SCHEDULE").as_deref() {
+                        None | Some("weekly") => ReportSchedule::Weekly,
+                        Some("daily") => ReportSchedule::Daily,
+                        Some(other) => return Err(anyhow!("MCBD_REPORT_SCHEDULE must be daily or weekly, got '{}'", other)),
+                    },
+                    from: var("MCBD_REPORT_FROM").ok_or_else(|| anyhow!("MCBD_REPORT_FROM is required with MCBD_SMTP_HOST"))?,
+                    to: list("MCBD_REPORT_TO"),
+                    smtp: SmtpConfig {
+                        host,
+                        port: number("MCBD_SMTP_PORT", default_smtp_port() as u64)? as u16,
+                        security: match var("MCBD_SMTP_SECURITY").as_deref() {
+                            None | Some("starttls") => SmtpSecurity::Starttls,
+                            Some("tls") => SmtpSecurity::Tls,
+                            Some("none") => SmtpSecurity::None,
+                            Some(other) => return Err(anyhow!("MCBD_SMTP_SECURITY must be starttls, tls or none, got '{}'", other)),
+                        },
+                        username: var("MCBD_SMTP_USERNAME"),
+                        password: var("MCBD_SMTP_PASSWORD"),
+                    },
+                }),
+                None => None,
+            },
+            mqtt: match var("MCBD_MQTT_BROKER") {
+                Some(broker) => Some(MqttConfig {
+                    broker,
+                    username: var("MCBD_MQTT_USERNAME"),
+                    password: var("MCBD_MQTT_PASSWORD"),
+                    topic: var("MCBD_MQTT_TOPIC").unwrap_or_else(default_mqtt_topic),
+                    discovery_prefix: var("MCBD_MQTT_DISCOVERY_PREFIX").unwrap_or_else(default_discovery_prefix),
+                    interval: number("MCBD_MQTT_INTERVAL", default_mqtt_interval())?,
+                }),
+                None => None,
+            },
+            tls: (var("MCBD_TLS").is_some_and(|v| v == "1" || v == "true") || var("MCBD_TLS_TRUSTED").is_some()).then(|| TlsConfig {
+                cert: var("MCBD_TLS_CERT"),
+                key: var("MCBD_TLS_KEY"),
+                trusted: list("MCBD_TLS_TRUSTED"),
+            }),
+            // MCBD_MIRRORS=backup=/backup/worlds
+            mirrors: list("MCBD_MIRRORS")
+                .into_iter()
+                .map(|entry| entry.split_once('=').map(|(name, path)| MirrorConfig { name: name.to_string(), path: path.to_string(), hard_links: true })
+                    .ok_or_else(|| anyhow!("Invalid mirror '{}' in MCBD_MIRRORS, expected name=path", entry)))
+                .collect::<Result<Vec<_>>>()?,
+            // Containers have no USB devices, nor adb
+            android: Vec::new(),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
         let config_str = serde_json::to_string_pretty(self)?;
-        fs::write("config.json", config_str)?;
+        fs::write(path, config_str)?;
         Ok(())
     }
 
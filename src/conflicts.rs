@@ -0,0 +1,152 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::file_manager::FileInfo;
+
+/// What happens when a device sends changes to a world that was changed
+/// here too, and those changes have not reached it yet. Set with
+/// `sync.conflict_resolution`, and per world with `sync.conflict_overrides`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum ConflictResolution {
+    /// Of a file changed on both devices, the version changed last wins.
+    #[default]
+    Newest,
+    /// Of a file changed on both devices, the larger version wins.
+    Largest,
+    /// Of a file changed on both devices, the version of this device wins,
+    /// as `prefer:<device>`.
+    PreferDevice(String),
+    /// The changes that arrive overwrite the world, but the world as it was
+    /// here is kept first as a copy next to it, see `copy_name`. Of a file
+    /// changed on both devices, the version changed last is the one kept
+    /// in the world.
+    KeepBoth,
+    /// The changes that arrive go to a copy of the world named after the
+    /// device that sent them, and the world stays as it is here until
+    /// someone picks the version to keep.
+    Manual,
+}
+
+impl ConflictResolution {
+    /// Whether it settles conflicts for the whole world, whether or not
+    /// the same files changed on both devices.
+    pub fn is_per_world(&self) -> bool {
+        matches!(self, Self::KeepBoth | Self::Manual)
+    }
+
+    /// Whether the version of a file `peer` has, `theirs`, wins over the
+    /// one `device` has, `ours`, when both changed it. `None` is a deleted
+    /// file, which loses to a changed one. Both devices come to the same
+    /// answer, so ties go to the larger file, then to the greater hash.
+    pub fn theirs_wins(&self, ours: Option<&FileInfo>, theirs: Option<&FileInfo>, peer: &str, device: &str) -> bool {
+        match self {
+            Self::PreferDevice(preferred) if preferred == peer => return true,
+            Self::PreferDevice(preferred) if preferred == device => return false,
+            Self::Manual => return false,
+            _ => {}
+        }
+        let (ours, theirs) = match (ours, theirs) {
+            (_, None) => return false,
+            (None, Some(_)) => return true,
+            (Some(ours), Some(theirs)) => (ours, theirs),
+        };
+        let newest = theirs.last_modified.cmp(&ours.last_modified);
+        let largest = theirs.size.cmp(&ours.size);
+        let order = match self {
+            Self::Largest => largest.then(newest),
+            _ => newest.then(largest),
+        };
+        order.then_with(|| theirs.hash.cmp(&ours.hash)).is_gt()
+    }
+}
+
+impl FromStr for ConflictResolution {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "newest" => Ok(Self::Newest),
+            "largest" => Ok(Self::Largest),
+            "keep_both" => Ok(Self::KeepBoth),
+            "manual" => Ok(Self::Manual),
+            _ => match value.strip_prefix("prefer:") {
+                Some(device) if !device.is_empty() => Ok(Self::PreferDevice(device.to_string())),
+                _ => bail!("Unknown conflict resolution {}, use newest, largest, prefer:<device>, keep_both or manual", value),
+            },
+        }
+    }
+}
+
+/// The conflict resolution of each world.
+#[derive(Debug, Clone, Default)]
+pub struct ConflictPolicy {
+    default: ConflictResolution,
+    worlds: BTreeMap<String, ConflictResolution>,
+}
+
+impl ConflictPolicy {
+    pub fn new(default: ConflictResolution) -> Self {
+        Self { default, worlds: BTreeMap::new() }
+    }
+
+    /// Parses `sync.conflict_resolution` and the overrides by world folder.
+    pub fn parse(default: &str, worlds: &BTreeMap<String, String>) -> Result<Self> {
+        let mut policy = Self::new(default.parse()?);
+        for (world, resolution) in worlds {
+            policy = policy.with_world(world.clone(), resolution.parse().map_err(|e| anyhow!("{}: {}", world, e))?);
+        }
+        Ok(policy)
+    }
+
+    pub fn with_world(mut self, world: String, resolution: ConflictResolution) -> Self {
+        self.worlds.insert(world, resolution);
+        self
+    }
+
+    pub fn for_world(&self, world: &str) -> &ConflictResolution {
+        self.worlds.get(world).unwrap_or(&self.default)
+    }
+}
+
+/// What becomes of a change a peer sent that conflicts with this device's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolution {
+    /// It is applied.
+    Take,
+    /// It is dropped, and this device's version goes to the peer instead.
+    Keep,
+    /// It is written to this path instead, in a copy of the world.
+    Divert(PathBuf),
+    /// It is merged with this device's version, see `level_dat::merge`,
+    /// with conflicting values going to the peer's if `theirs_wins`.
+    Merge { theirs_wins: bool },
+}
+
+/// The folder the version of `world` that `device` had is kept in, as in
+/// `MyWorld (conflict from LAPTOP 2024-06-01)`.
+pub fn copy_name(world: &str, device: &str, at: SystemTime) -> String {
+    format!("{} (conflict from {} {})", world, device, date(at))
+}
+
+/// Whether the world folder `folder` is a copy made by `copy_name`.
+pub fn is_copy(folder: &str) -> bool {
+    folder.ends_with(')') && folder.contains(" (conflict from ")
+}
+
+/// The UTC date of `at` as `YYYY-MM-DD`.
+pub fn date(at: SystemTime) -> String {
+    let days = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as i64 / 86400;
+    // Howard Hinnant's days_from_civil, backwards
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
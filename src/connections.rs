@@ -0,0 +1,72 @@
+use anyhow::Result;
+use log::debug;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, OwnedMappedMutexGuard, OwnedMutexGuard};
+use crate::network::{PeerSession, SyncClient};
+
+/// A session unused for this long is replaced instead of reused, since a
+/// router may have dropped the connection without either side noticing.
+pub const SESSION_IDLE: Duration = Duration::from_secs(2 * 60);
+
+type Slot = Arc<Mutex<Option<(PeerSession, Instant)>>>;
+
+/// Sessions to peers kept open between messages, up to `streams` per
+/// address, so a burst of changes during a world save goes over a few
+/// connections instead of opening one per file.
+#[derive(Clone)]
+pub struct Connections {
+    sessions: Arc<Mutex<HashMap<String, Vec<Slot>>>>,
+    streams: usize,
+}
+
+impl Default for Connections {
+    fn default() -> Self {
+        Self { sessions: Arc::default(), streams: 1 }
+    }
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sessions kept per address, one for each transfer sent at the same time.
+    pub fn with_streams(mut self, streams: usize) -> Self {
+        self.streams = streams.max(1);
+        self
+    }
+
+    /// A session to `client`'s peer that is not in use, opening one if there
+    /// is none or the last one closed or sat idle. When all are in use it
+    /// waits for the first. It is held until the guard is dropped, so
+    /// replies to one message are never taken by another.
+    pub async fn session(&self, client: &SyncClient) -> Result<OwnedMappedMutexGuard<Option<(PeerSession, Instant)>, PeerSession>> {
+        let slots = self.sessions.lock().await
+            .entry(client.address().to_string())
+            .or_insert_with(|| (0..self.streams).map(|_| Slot::default()).collect())
+            .clone();
+        let mut held: OwnedMutexGuard<_> = match slots.iter().find_map(|slot| slot.clone().try_lock_owned().ok()) {
+            Some(free) => free,
+            None => slots[0].clone().lock_owned().await,
+        };
+        let reusable = held.as_ref().is_some_and(|(session, used)| !session.is_closed() && used.elapsed() < SESSION_IDLE);
+        if !reusable {
+            debug!("Opening a session to {}", client.address());
+            *held = Some((client.session().await?, Instant::now()));
+        }
+        Ok(OwnedMutexGuard::map(held, |slot| {
+            let (session, used) = slot.as_mut().expect("session just checked or opened");
+            *used = Instant::now();
+            session
+        }))
+    }
+
+    /// Stops reusing the sessions kept so far, such as after the network
+    /// changed and they may lead nowhere. Those in use are dropped once
+    /// released.
+    pub async fn forget(&self) {
+        self.sessions.lock().await.clear();
+    }
+}
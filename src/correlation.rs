@@ -0,0 +1,94 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    static CURRENT: CorrelationId;
+}
+
+/// Short ID tying together the log lines of one sync session or transfer on
+/// both peers. It travels in protocol messages and is printed by the logger.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CorrelationId(String);
+
+impl CorrelationId {
+    pub fn new() -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(std::process::id().to_le_bytes());
+        hasher.update(COUNTER.fetch_add(1, Ordering::Relaxed).to_le_bytes());
+        if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            hasher.update(now.as_nanos().to_le_bytes());
+        }
+        let hash = hasher.finalize();
+        Self(hash[..4].iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for CorrelationId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The ID of the session the current task is working on, if any.
+pub fn current() -> Option<CorrelationId> {
+    CURRENT.try_with(|id| id.clone()).ok()
+}
+
+/// Runs `f` with `id` as the current correlation ID.
+pub async fn scope<F: Future>(id: CorrelationId, f: F) -> F::Output {
+    CURRENT.scope(id, f).await
+}
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines on stderr.
+    Text,
+    /// One JSON object per line on stdout, for container log collectors.
+    Json,
+}
+
+/// env_logger format that adds the current correlation ID to each line.
+pub fn init_logger(format: LogFormat) {
+    use std::io::Write;
+
+    let mut builder = env_logger::Builder::from_default_env();
+    match format {
+        LogFormat::Text => builder.format(|buf, record| {
+            let timestamp = buf.timestamp();
+            match current() {
+                Some(id) => writeln!(buf, "[{} {} {}] [{}] {}", timestamp, record.level(), record.target(), id, record.args()),
+                None => writeln!(buf, "[{} {} {}] {}", timestamp, record.level(), record.target(), record.args()),
+            }
+        }),
+        LogFormat::Json => builder.target(env_logger::Target::Stdout).format(|buf, record| {
+            let mut line = serde_json::json!({
+                "ts": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "msg": record.args().to_string(),
+            });
+            if let Some(id) = current() {
+                line["correlation_id"] = serde_json::Value::String(id.0);
+            }
+            writeln!(buf, "{}", line)
+        }),
+    };
+    builder.init();
+}
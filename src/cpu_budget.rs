@@ -0,0 +1,83 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A share of this device's CPU that hashing and compression may use on
+/// average, so a daemon on a shared PC never makes it stutter. Work is not
+/// interrupted: after each piece, whoever did it waits long enough to bring
+/// the average back within the budget. Shared by every thread doing such
+/// work, so together they stay within it.
+#[derive(Debug, Clone, Default)]
+pub struct CpuBudget {
+    /// Cores' worth of work allowed per second, no limit when not set.
+    cores: Option<f64>,
+    /// Until when the work done so far uses up the budget.
+    until: Arc<Mutex<Option<Instant>>>,
+}
+
+impl CpuBudget {
+    /// `percent` of all cores of this device together.
+    pub fn new(percent: Option<u8>) -> Self {
+        let cores = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        Self::for_cores(percent, cores)
+    }
+
+    /// `percent` of `cores` cores together.
+    pub fn for_cores(percent: Option<u8>, cores: usize) -> Self {
+        let cores = percent.filter(|percent| *percent < 100).map(|percent| f64::from(percent.max(1)) / 100.0 * cores.max(1) as f64);
+        Self { cores, until: Arc::new(Mutex::new(None)) }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.cores.is_some()
+    }
+
+    /// Books `busy`, work that just finished, and returns how long to wait
+    /// before the next piece.
+    pub fn charge(&self, busy: Duration) -> Duration {
+        let Some(cores) = self.cores else {
+            return Duration::ZERO;
+        };
+        let now = Instant::now();
+        let mut until = self.until.lock().unwrap();
+        // Time the budget was not used is not saved up
+        let started = now.checked_sub(busy).unwrap_or(now);
+        let from = until.filter(|until| *until > started).unwrap_or(started);
+        let next = from + busy.div_f64(cores);
+        *until = Some(next);
+        next.saturating_duration_since(now)
+    }
+
+    /// Runs `work`, then waits, blocking the thread, as long as the budget
+    /// asks for.
+    pub fn run<T>(&self, work: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = work();
+        let wait = self.charge(start.elapsed());
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+        result
+    }
+
+    /// Like `run`, for work that must not wait at all, such as a check on an
+    /// async task holding a lock. The work is booked all the same, so the
+    /// next piece of work waits for it instead.
+    pub fn run_booked<T>(&self, work: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = work();
+        self.charge(start.elapsed());
+        result
+    }
+
+    /// Like `run`, for work on an async task, which waits without blocking
+    /// its thread.
+    pub async fn run_async<T>(&self, work: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = work();
+        let wait = self.charge(start.elapsed());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        result
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// How often received deletions are checked for their grace period or a
+/// confirmation.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A file a peer deleted, not deleted here yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingDeletion {
+    pub path: PathBuf,
+    /// Hash of the version the peer deleted, see
+    /// `FileManager::receive_deletion`.
+    pub hash: String,
+    pub device: String,
+    pub received_at: SystemTime,
+    /// Confirmed with `mcbd-world-sync deletions --apply`.
+    #[serde(default)]
+    pub approved: bool,
+}
+
+/// Deletions received while `deletions.grace_period` or `deletions.confirm`
+/// holds them back, kept in `deletions.json` in the state directory.
+#[derive(Debug, Clone)]
+pub struct PendingDeletions {
+    file: PathBuf,
+    /// Connections add to the file while the daemon applies what is due.
+    lock: Arc<Mutex<()>>,
+}
+
+impl PendingDeletions {
+    pub fn new(file: PathBuf) -> Self {
+        Self { file, lock: Arc::new(Mutex::new(())) }
+    }
+
+    pub fn list(&self) -> Result<Vec<PendingDeletion>> {
+        match fs::read(&self.file) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Adds `deletions`, replacing what was pending for the same files.
+    pub fn add(&self, deletions: Vec<PendingDeletion>) -> Result<()> {
+        self.update(|pending| {
+            pending.retain(|old| !deletions.iter().any(|new| new.path == old.path));
+            pending.extend(deletions);
+            0
+        })?;
+        Ok(())
+    }
+
+    /// Drops what is pending for `path` or below it, once applied or kept,
+    /// or when a new version of the file arrived. Returns how many were
+    /// dropped.
+    pub fn remove(&self, path: &Path) -> Result<usize> {
+        if !self.file.exists() {
+            return Ok(0);
+        }
+        self.update(|pending| {
+            let before = pending.len();
+            pending.retain(|deletion| !deletion.path.starts_with(path));
+            before - pending.len()
+        })
+    }
+
+    /// Confirms what is pending for `path` or below it, everything without
+    /// a path. Returns how many were confirmed.
+    pub fn approve(&self, path: Option<&Path>) -> Result<usize> {
+        self.update(|pending| {
+            let mut approved = 0;
+            for deletion in pending.iter_mut().filter(|deletion| path.is_none_or(|path| deletion.path.starts_with(path))) {
+                deletion.approved = true;
+                approved += 1;
+            }
+            approved
+        })
+    }
+
+    /// The deletions to apply at `now`: the confirmed ones, and with
+    /// `confirm` off those older than `grace_period`.
+    pub fn due(&self, grace_period: Duration, confirm: bool, now: SystemTime) -> Result<Vec<PendingDeletion>> {
+        Ok(self.list()?.into_iter()
+            .filter(|deletion| deletion.approved || (!confirm && now.duration_since(deletion.received_at).is_ok_and(|age| age >= grace_period)))
+            .collect())
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Vec<PendingDeletion>) -> usize) -> Result<usize> {
+        let _guard = self.lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut pending = self.list()?;
+        let changed = change(&mut pending);
+        let tmp = self.file.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&pending)?)?;
+        fs::rename(&tmp, &self.file)?;
+        Ok(changed)
+    }
+}
@@ -0,0 +1,168 @@
+use anyhow::{bail, Result};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+
+/// Block size receivers cut their copy of a file into.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+/// Files at least this large are sent as a delta when the peer has an older
+/// copy. Smaller ones are cheaper to send whole than to negotiate.
+pub const MIN_DELTA_SIZE: usize = 1024 * 1024;
+
+/// Block sizes a receiver accepts, so a peer cannot ask for millions of tiny blocks.
+pub fn check_block_size(block_size: usize) -> Result<()> {
+    if !(1024..=1024 * 1024).contains(&block_size) {
+        bail!("Unsupported delta block size {}", block_size);
+    }
+    Ok(())
+}
+
+/// Checksums of one block of the receiver's copy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: String,
+}
+
+/// One step of rebuilding a file from the receiver's copy.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeltaOp {
+    /// A block the receiver already has, by index.
+    Copy { block: u32 },
+    /// Bytes the receiver does not have.
+    Data {
+        #[serde(with = "serde_bytes")]
+        bytes: Vec<u8>,
+    },
+}
+
+/// Adler-style checksum that slides along the data one byte at a time.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let len = window.len() as u32;
+        let (mut a, mut b) = (0u32, 0u32);
+        for (i, &byte) in window.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(byte as u32));
+        }
+        Self { a, b, len }
+    }
+
+    fn value(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+
+    fn roll(&mut self, out: u8, incoming: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(incoming as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+}
+
+fn strong(block: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(block))
+}
+
+/// Checksums of every `block_size` block of `data`; the last may be shorter.
+pub fn signatures(data: &[u8], block_size: usize) -> Vec<BlockSignature> {
+    data.chunks(block_size)
+        .map(|block| BlockSignature { weak: Rolling::new(block).value(), strong: strong(block) })
+        .collect()
+}
+
+/// Instructions that turn the receiver's copy, described by `signatures`,
+/// into `data`. Blocks found at any offset are copied, everything else is sent.
+pub fn diff(signatures: &[BlockSignature], block_size: usize, data: &[u8]) -> Vec<DeltaOp> {
+    let mut ops = Vec::new();
+    if block_size == 0 {
+        push_data(&mut ops, data);
+        return ops;
+    }
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    for (index, signature) in signatures.iter().enumerate() {
+        by_weak.entry(signature.weak).or_default().push(index);
+    }
+    let find = |weak: u32, window: &[u8]| -> Option<usize> {
+        let candidates = by_weak.get(&weak)?;
+        let hash = strong(window);
+        candidates.iter().copied().find(|&i| signatures[i].strong == hash)
+    };
+
+    let mut literal = 0;
+    let mut pos = 0;
+    let mut rolling: Option<Rolling> = None;
+    while pos + block_size <= data.len() {
+        let window = &data[pos..pos + block_size];
+        let checksum = rolling.get_or_insert_with(|| Rolling::new(window));
+        if let Some(block) = find(checksum.value(), window) {
+            push_data(&mut ops, &data[literal..pos]);
+            ops.push(DeltaOp::Copy { block: block as u32 });
+            pos += block_size;
+            literal = pos;
+            rolling = None;
+            continue;
+        }
+        if pos + block_size < data.len() {
+            checksum.roll(data[pos], data[pos + block_size]);
+        }
+        pos += 1;
+    }
+
+    // The receiver's last block is usually shorter, so it can only match the end
+    let tail_start = data.len() - data.len() % block_size;
+    let tail = &data[tail_start..];
+    match (tail_start >= literal && !tail.is_empty()).then(|| find(Rolling::new(tail).value(), tail)).flatten() {
+        Some(block) => {
+            push_data(&mut ops, &data[literal..tail_start]);
+            ops.push(DeltaOp::Copy { block: block as u32 });
+        }
+        None => push_data(&mut ops, &data[literal..]),
+    }
+    ops
+}
+
+fn push_data(ops: &mut Vec<DeltaOp>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        ops.push(DeltaOp::Data { bytes: bytes.to_vec() });
+    }
+}
+
+/// Rebuilds a file from the receiver's copy `base` and the sender's `ops`.
+pub fn apply(base: &[u8], block_size: usize, ops: &[DeltaOp]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(base.len());
+    for op in ops {
+        match op {
+            DeltaOp::Copy { block } => {
+                let start = *block as usize * block_size;
+                if block_size == 0 || start >= base.len() {
+                    bail!("Delta copies block {} past the end of the {} byte base", block, base.len());
+                }
+                out.extend_from_slice(&base[start..(start + block_size).min(base.len())]);
+            }
+            DeltaOp::Data { bytes } => out.extend_from_slice(bytes),
+        }
+    }
+    Ok(out)
+}
+
+/// Size of the file `ops` rebuild from a `base_len` byte copy.
+pub fn rebuilt_len(ops: &[DeltaOp], block_size: usize, base_len: u64) -> u64 {
+    ops.iter().map(|op| match op {
+        DeltaOp::Data { bytes } => bytes.len() as u64,
+        DeltaOp::Copy { block } => base_len.saturating_sub(*block as u64 * block_size as u64).min(block_size as u64),
+    }).sum()
+}
+
+/// Bytes of file content a delta carries.
+pub fn literal_len(ops: &[DeltaOp]) -> usize {
+    ops.iter().map(|op| match op {
+        DeltaOp::Data { bytes } => bytes.len(),
+        DeltaOp::Copy { .. } => 0,
+    }).sum()
+}
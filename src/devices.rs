@@ -0,0 +1,105 @@
+use anyhow::{bail, Result};
+use log::info;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use crate::app_dirs::AppDirs;
+use crate::config::Config;
+use crate::correlation::{self, CorrelationId};
+use crate::groups::Groups;
+use crate::index;
+use crate::manifest::{self, Manifest, ManifestCache, ManifestSent, SyncCursor};
+use crate::network::SyncClient;
+use crate::rendezvous::{self, Lookup};
+use crate::tls;
+
+/// What removing a device cleaned up.
+#[derive(Debug)]
+pub struct Removal {
+    /// Whether the device had a sync cursor.
+    pub cursor_removed: bool,
+    /// Outcome of the final manifest push, when one was requested.
+    pub final_sync: Option<ManifestSent>,
+}
+
+/// Removes `name` from the configured devices, whichever group it is in,
+/// and drops its sync cursor.
+/// Tombstones no longer wait for it once it is out of the configuration, and
+/// its transfer queue only lives in the running daemon, so a restart clears
+/// it. With `final_sync` the current manifest is pushed to the device first,
+/// and nothing is removed if that fails. Saving `config` is up to the caller.
+pub async fn remove(config: &mut Config, app_dirs: &AppDirs, name: &str, final_sync: bool) -> Result<Removal> {
+    let groups = Groups::new(config.sync.groups())?;
+    let (Some(group), Some(device)) = (groups.of_device(name), groups.device(name)) else {
+        bail!("No device named {} is configured", name);
+    };
+    let cache = ManifestCache::load(app_dirs.cursors_file());
+
+    let final_sync = if final_sync {
+        let files = index::load(&app_dirs.index_file())?.map(|stored| stored.files).unwrap_or_default();
+        let current = Groups::manifest_for(group, &Arc::new(Manifest::from_files(&files)));
+        let tls = config.tls.as_ref().map(|tls| tls::connector(&tls.trusted)).transpose()?;
+        let connect = |address| SyncClient::new(address).with_tls(tls.clone());
+        let address = rendezvous::resolve_device(device, &Lookup { rendezvous: config.sync.rendezvous.clone(), discovered: None }, connect).await?;
+        let mut session = connect(address).with_device_name(config.sync.local_name()).with_key(device.key.clone()).session().await?;
+        let sent = correlation::scope(CorrelationId::new(), manifest::exchange(&mut session, &cache, &config.sync.local_name(), name, current)).await?;
+        info!("Final manifest sent to {} ({:?})", name, sent);
+        Some(sent)
+    } else {
+        None
+    };
+
+    for devices in config.sync.device_lists_mut() {
+        devices.retain(|d| d.name != name);
+    }
+    let cursor_removed = cache.reconcile(name).await;
+    cache.flush().await;
+    Ok(Removal { cursor_removed, final_sync })
+}
+
+/// Renames a configured device and keeps its sync cursor, so the next
+/// exchange with it continues with a delta. Saving `config` is up to the caller.
+pub async fn rename(config: &mut Config, app_dirs: &AppDirs, old: &str, new: &str) -> Result<()> {
+    if config.sync.all_devices().iter().any(|d| d.name == new) {
+        bail!("A device named {} is already configured", new);
+    }
+    let Some(device) = config.sync.device_lists_mut().flatten().find(|d| d.name == old) else {
+        bail!("No device named {} is configured", old);
+    };
+    device.name = new.to_string();
+    let cache = ManifestCache::load(app_dirs.cursors_file());
+    cache.rename(old, new).await;
+    cache.flush().await;
+    Ok(())
+}
+
+/// Who this device is to its peers: the name it reports, and what each peer
+/// acknowledged from it. A reinstalled computer that imports it continues
+/// with deltas instead of being seeded like a new device.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Identity {
+    pub name: String,
+    pub cursors: HashMap<String, SyncCursor>,
+}
+
+pub async fn export_identity(config: &Config, app_dirs: &AppDirs, path: &Path) -> Result<Identity> {
+    let identity = Identity {
+        name: config.sync.local_name(),
+        cursors: ManifestCache::load(app_dirs.cursors_file()).cursors().await,
+    };
+    fs::write(path, serde_json::to_vec_pretty(&identity)?)?;
+    Ok(identity)
+}
+
+/// Adopts an exported identity: its name becomes `sync.name` and its cursors
+/// replace the local ones. Saving `config` is up to the caller.
+pub async fn import_identity(config: &mut Config, app_dirs: &AppDirs, path: &Path) -> Result<Identity> {
+    let identity: Identity = serde_json::from_slice(&fs::read(path)?)?;
+    config.sync.name = Some(identity.name.clone());
+    let cache = ManifestCache::load(app_dirs.cursors_file());
+    cache.restore(identity.cursors.clone()).await;
+    cache.flush().await;
+    Ok(identity)
+}
@@ -0,0 +1,175 @@
+use anyhow::{bail, Result};
+use log::{debug, info, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::config::{Config, Device};
+use crate::pairing::PairedDevice;
+
+/// DNS-SD service type daemons announce themselves with.
+pub const SERVICE_TYPE: &str = "_mcbd-sync._tcp.local.";
+
+/// How long `discover` listens for announcements.
+pub const SCAN_TIME: Duration = Duration::from_secs(3);
+
+/// A daemon announced on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Peer {
+    pub device: PairedDevice,
+    /// `host:port` of its sync server.
+    pub address: String,
+}
+
+impl Peer {
+    /// The peer a resolved announcement describes. IPv4 addresses are
+    /// preferred, link-local IPv6 ones would need a scope to connect.
+    pub fn from_service(info: &ServiceInfo) -> Option<Self> {
+        let name = info.get_property_val_str("name")?.to_string();
+        let ip = info.get_addresses().iter().copied().min_by_key(|ip| !ip.is_ipv4())?;
+        Some(Peer {
+            address: SocketAddr::new(ip, info.get_port()).to_string(),
+            device: PairedDevice {
+                name,
+                port: info.get_port(),
+                fingerprint: info.get_property_val_str("fingerprint").map(str::to_string),
+            },
+        })
+    }
+}
+
+/// Peers announced on the local network, by name. Clones share them.
+#[derive(Debug, Clone, Default)]
+pub struct Discovered {
+    peers: Arc<Mutex<BTreeMap<String, Peer>>>,
+}
+
+impl Discovered {
+    /// Records `peer`, returning whether it is new or moved.
+    pub fn insert(&self, peer: Peer) -> bool {
+        let mut peers = self.peers.lock().unwrap();
+        let changed = peers.get(&peer.device.name) != Some(&peer);
+        peers.insert(peer.device.name.clone(), peer);
+        changed
+    }
+
+    pub fn remove(&self, name: &str) -> Option<Peer> {
+        self.peers.lock().unwrap().remove(name)
+    }
+
+    /// Where the device called `name` was last announced.
+    pub fn address(&self, name: &str) -> Option<String> {
+        self.peers.lock().unwrap().get(name).map(|p| p.address.clone())
+    }
+
+    pub fn peers(&self) -> Vec<Peer> {
+        self.peers.lock().unwrap().values().cloned().collect()
+    }
+}
+
+/// This device's announcement, and the peers it has heard of.
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    discovered: Discovered,
+}
+
+impl Discovery {
+    /// Announces `local` on every interface and starts listening for others.
+    /// Its own announcement is never listed.
+    pub fn start(local: &PairedDevice) -> Result<Self> {
+        let daemon = ServiceDaemon::new()?;
+        let mut properties = HashMap::from([("name".to_string(), local.name.clone())]);
+        if let Some(fingerprint) = &local.fingerprint {
+            properties.insert("fingerprint".to_string(), fingerprint.clone());
+        }
+        let host: String = local.name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect();
+        let service = ServiceInfo::new(SERVICE_TYPE, &local.name, &format!("{}.local.", host), "", local.port, properties)?
+            .enable_addr_auto();
+        daemon.register(service)?;
+
+        let events = daemon.browse(SERVICE_TYPE)?;
+        let discovered = Discovered::default();
+        let (peers, local_name) = (discovered.clone(), local.name.clone());
+        std::thread::spawn(move || {
+            while let Ok(event) = events.recv() {
+                match event {
+                    ServiceEvent::ServiceResolved(info) => match Peer::from_service(&info) {
+                        Some(peer) if peer.device.name == local_name => {}
+                        Some(peer) => {
+                            let (name, address) = (peer.device.name.clone(), peer.address.clone());
+                            if peers.insert(peer) {
+                                debug!("{} is on the local network at {}", name, address);
+                            }
+                        }
+                        None => debug!("Ignoring announcement {} without a name or address", info.get_fullname()),
+                    },
+                    ServiceEvent::ServiceRemoved(_, fullname) => {
+                        if let Some(peer) = fullname.strip_suffix(&format!(".{}", SERVICE_TYPE)).and_then(|name| peers.remove(name)) {
+                            debug!("{} left the local network", peer.device.name);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        });
+        Ok(Self { daemon, discovered })
+    }
+
+    pub fn discovered(&self) -> Discovered {
+        self.discovered.clone()
+    }
+
+    /// Stops announcing this device.
+    pub fn stop(&self) {
+        if let Err(e) = self.daemon.shutdown() {
+            warn!("Failed to stop announcing on the local network: {}", e);
+        }
+    }
+}
+
+/// Logs peers that show up without being configured, once each, so they
+/// can be added with `discover add`.
+pub async fn report_new(discovered: Discovered, configured: Vec<String>, every: Duration) {
+    let mut reported = configured;
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        for peer in discovered.peers() {
+            if !reported.contains(&peer.device.name) {
+                info!("Found {} on the local network at {}, run `mcbd-world-sync discover add {}` to sync with it", peer.device.name, peer.address, peer.device.name);
+                reported.push(peer.device.name);
+            }
+        }
+    }
+}
+
+/// Announces `local` for `time` and returns the peers heard from meanwhile.
+pub async fn scan(local: &PairedDevice, time: Duration) -> Result<Vec<Peer>> {
+    let discovery = Discovery::start(local)?;
+    tokio::time::sleep(time).await;
+    let peers = discovery.discovered().peers();
+    discovery.stop();
+    Ok(peers)
+}
+
+/// Adds a discovered peer to `sync.devices`, or moves a device of that name
+/// to its address, and trusts its certificate when this device uses TLS.
+/// Announcements are not authenticated, so the caller confirms the peer and
+/// its fingerprint first. Saving `config` is up to the caller.
+pub fn add(config: &mut Config, peer: &Peer) -> Result<()> {
+    if peer.device.name == config.sync.local_name() {
+        bail!("{} is this device", peer.device.name);
+    }
+    let existing = config.sync.device_lists_mut().flatten().find(|d| d.name == peer.device.name);
+    match existing {
+        Some(existing) => existing.address = peer.address.clone(),
+        None => config.sync.devices.push(Device::from_target(&peer.device.name, &peer.address)),
+    }
+    if let (Some(tls), Some(fingerprint)) = (config.tls.as_mut(), &peer.device.fingerprint) {
+        if !tls.trusted.contains(fingerprint) {
+            tls.trusted.push(fingerprint.clone());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+use anyhow::{anyhow, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::{Component, Path, PathBuf};
+use crate::archive::ArchivedWorlds;
+
+pub const STAGING_DIR: &str = ".mcbd-staging";
+pub const TRASH_DIR: &str = ".mcbd-trash";
+pub const SNAPSHOT_DIR: &str = ".mcbd-snapshots";
+pub const QUARANTINE_DIR: &str = ".mcbd-quarantine";
+
+/// Directories the tool creates for itself. They are never scanned, watched
+/// or transferred, wherever they appear under a root.
+pub const BUILTIN_EXCLUDED_DIRS: &[&str] = &[STAGING_DIR, TRASH_DIR, SNAPSHOT_DIR, QUARANTINE_DIR];
+
+/// Files Minecraft touches constantly while a world is open, which are of
+/// no use on another device: LevelDB's lock and log, temporary files, and
+/// what LevelDB's recovery left in `db/lost`.
+pub const DEFAULT_IGNORE: &[&str] = &["*/db/LOCK", "*/db/LOG", "*/db/LOG.old", "*/db/lost", "*.dbtmp", "*.tmp"];
+
+#[derive(Debug, Clone, Default)]
+pub struct Exclusions {
+    dir_names: Vec<String>,
+    prefixes: Vec<PathBuf>,
+    ignore: GlobSet,
+    archived: ArchivedWorlds,
+}
+
+impl Exclusions {
+    /// Built-in rules plus user rules. A user rule without a path separator
+    /// matches a directory of that name at any depth, otherwise it is a path
+    /// relative to the root (or an absolute path inside it).
+    pub fn new(user_rules: &[String]) -> Self {
+        let mut exclusions = Self {
+            dir_names: BUILTIN_EXCLUDED_DIRS.iter().map(|d| d.to_string()).collect(),
+            prefixes: Vec::new(),
+            ignore: GlobSet::empty(),
+            archived: ArchivedWorlds::default(),
+        };
+        for rule in user_rules {
+            let rule = rule.trim_end_matches(['/', '\\']);
+            if rule.is_empty() {
+                continue;
+            }
+            if rule.contains(['/', '\\']) {
+                exclusions.prefixes.push(PathBuf::from(rule));
+            } else {
+                exclusions.dir_names.push(rule.to_string());
+            }
+        }
+        exclusions
+    }
+
+    /// Adds glob rules such as `*/db/LOCK` or `*.tmp`, matched against the
+    /// path relative to the root with `/` between its parts. `*` and `?` stay
+    /// within one part and `**` spans several; a rule without a `/` matches
+    /// a file or directory of that name at any depth.
+    pub fn with_ignore(mut self, patterns: &[String]) -> Result<Self> {
+        self.ignore = glob_set(patterns, "ignore")?;
+        Ok(self)
+    }
+
+    /// Also excludes the worlds in `archived`, as they are archived and
+    /// restored.
+    pub fn with_archived(mut self, archived: ArchivedWorlds) -> Self {
+        self.archived = archived;
+        self
+    }
+
+    /// Whether `relative` is in an archived world.
+    pub fn is_archived(&self, relative: &Path) -> bool {
+        self.archived.contains_path(relative)
+    }
+
+    /// Checks a path relative to `root`; absolute paths are made relative first.
+    pub fn is_excluded(&self, root: &Path, path: &Path) -> bool {
+        let relative = path.strip_prefix(root).unwrap_or(path);
+        let name_match = relative.components().any(|c| match c {
+            Component::Normal(name) => self.dir_names.iter().any(|d| name == d.as_str()),
+            _ => false,
+        });
+        name_match || self.prefixes.iter().any(|p| {
+            relative.starts_with(p) || (p.is_absolute() && path.starts_with(p))
+        }) || self.is_ignored(relative) || self.is_archived(relative)
+    }
+
+    /// Whether `relative` or a directory it is in matches an ignore rule.
+    fn is_ignored(&self, relative: &Path) -> bool {
+        matches(&self.ignore, relative)
+    }
+}
+
+/// Glob rules as `Exclusions::with_ignore` reads them. `kind` names them in
+/// the error for an invalid one.
+pub fn glob_set(patterns: &[String], kind: &str) -> Result<GlobSet> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        let pattern = pattern.trim_end_matches('/');
+        if pattern.is_empty() {
+            continue;
+        }
+        let pattern = if pattern.contains('/') { pattern.to_string() } else { format!("**/{}", pattern) };
+        let glob = GlobBuilder::new(&pattern).literal_separator(true).build()
+            .map_err(|e| anyhow!("Invalid {} pattern '{}': {}", kind, pattern, e))?;
+        set.add(glob);
+    }
+    Ok(set.build()?)
+}
+
+/// Whether `relative` or a directory it is in matches a rule of `set`.
+pub fn matches(set: &GlobSet, relative: &Path) -> bool {
+    if set.is_empty() {
+        return false;
+    }
+    let mut parts = String::new();
+    for component in relative.components() {
+        let Component::Normal(name) = component else {
+            continue;
+        };
+        if !parts.is_empty() {
+            parts.push('/');
+        }
+        parts.push_str(&name.to_string_lossy());
+        if set.is_match(&parts) {
+            return true;
+        }
+    }
+    false
+}
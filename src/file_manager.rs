@@ -1,98 +1,1397 @@
-use anyhow::Result;
-use std::path::{Path, PathBuf};
+use anyhow::{anyhow, bail, Result};
+use std::path::{Component, Path, PathBuf};
 use std::fs;
-use std::time::SystemTime;
-use std::collections::HashMap;
+use std::time::{Duration, Instant, SystemTime};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use dashmap::DashMap;
+use log::{info, warn};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use crate::config::HashPolicy;
+use crate::cpu_budget::CpuBudget;
+use crate::gaming::GamingMode;
+use crate::interference::WriteTracker;
+use crate::exclusions::{Exclusions, STAGING_DIR};
+use crate::journal::{Change, Journal};
+use crate::level_dat::{self, LEVEL_DAT};
+use crate::leveldb;
+use crate::busy;
+use crate::conflicts::{self, ConflictPolicy, ConflictResolution, Resolution};
+use crate::mcworld;
+use crate::shadow_copy::ShadowCopies;
+use crate::snapshots::{self, Snapshots};
+use crate::delta::{self, BlockSignature, DeltaOp};
 
-#[derive(Debug, Clone)]
+/// Received changes to a world closer together than this belong to one
+/// burst, which gets one snapshot.
+pub const RECEIVE_BURST_GAP: Duration = Duration::from_secs(60);
+
+/// How recently the files of a folder must have been deleted for a new
+/// folder holding the same files to count as that folder, moved.
+pub const RENAME_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long sending the deletion of a folder is held back, so the folder
+/// it was moved to can still be found.
+pub const RENAME_DELAY: Duration = Duration::from_secs(5);
+
+/// Numbers temporary files, so concurrent writes never share one.
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the first component of paths in a root other than the worlds
+/// directory, as in `@development_behavior_packs/MyPack/manifest.json`.
+/// Minecraft never names a world folder like that.
+pub const ROOT_MARKER: char = '@';
+
+/// The name of the root `path` is in, unless it is in the worlds directory.
+pub fn root_of(path: &Path) -> Option<&str> {
+    path.components().next()?.as_os_str().to_str()?.strip_prefix(ROOT_MARKER)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileInfo {
     pub path: PathBuf,
     pub last_modified: SystemTime,
     pub size: u64,
+    /// Empty while the file is listed but not hashed yet.
     pub hash: String,
 }
 
+impl FileInfo {
+    /// Whether the file was found by `FileManager::list_directory` and still
+    /// waits to be hashed. Such files are left out of manifests.
+    pub fn hash_pending(&self) -> bool {
+        self.hash.is_empty()
+    }
+}
+
+/// One world folder of the index, as reported by `/status`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WorldSummary {
+    pub name: String,
+    pub files: usize,
+    pub size: u64,
+    /// Files listed but not hashed yet.
+    pub hash_pending: usize,
+    /// LevelDB left files it could not read in `db/lost`, see
+    /// `leveldb::has_lost_files`.
+    pub unclean_shutdown: bool,
+}
+
+/// A deleted file, remembered so peers that still have it delete it too
+/// instead of sending it back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub path: PathBuf,
+    pub hash: String,
+    pub deleted_at: SystemTime,
+}
+
+/// A folder found moved, see `FileManager::detect_rename`.
+#[derive(Debug, Clone)]
+pub struct Rename {
+    pub to: PathBuf,
+    /// The files moved, as they were deleted from the old folder.
+    pub files: Vec<Tombstone>,
+}
+
+/// The entries and tombstones of a `FileManager`, shared with every task
+/// that only reads them. Reading never waits for the manager, so transfers,
+/// manifests and peers' requests go on while it scans or receives a file.
+#[derive(Clone)]
+pub struct FileIndex {
+    base_path: PathBuf,
+    /// Further synced directories by name, see `ROOT_MARKER`.
+    roots: Arc<BTreeMap<String, PathBuf>>,
+    files: Arc<DashMap<PathBuf, FileInfo>>,
+    tombstones: Arc<DashMap<PathBuf, Tombstone>>,
+    generation: Arc<AtomicU64>,
+    writes: Arc<WriteTracker>,
+    hashing: HashPolicy,
+    hash_workers: usize,
+    /// Slows hashing down while Minecraft is played.
+    gaming: GamingMode,
+    cpu: CpuBudget,
+    /// Copies worlds that are played are read from.
+    shadows: ShadowCopies,
+    /// Files received into the staging folder, by the path they are indexed
+    /// as, see `FileManager::with_staging`.
+    staged: Arc<DashMap<PathBuf, PathBuf>>,
+    /// Folders moved, by where they were, until something shows up there again.
+    renames: Arc<DashMap<PathBuf, Rename>>,
+    available: Arc<AtomicBool>,
+}
+
+impl FileIndex {
+    fn new(base_path: PathBuf) -> Self {
+        Self {
+            base_path,
+            roots: Arc::new(BTreeMap::new()),
+            files: Arc::new(DashMap::new()),
+            tombstones: Arc::new(DashMap::new()),
+            generation: Arc::new(AtomicU64::new(0)),
+            writes: Arc::new(WriteTracker::new()),
+            hashing: HashPolicy::Eager,
+            hash_workers: 1,
+            gaming: GamingMode::default(),
+            cpu: CpuBudget::default(),
+            shadows: ShadowCopies::default(),
+            staged: Arc::new(DashMap::new()),
+            renames: Arc::new(DashMap::new()),
+            available: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn base_path(&self) -> &Path {
+        &self.base_path
+    }
+
+    /// Where the file indexed as `path` is, below the base path or the root
+    /// it names. A file received but not swapped into its world yet is in
+    /// the staging folder.
+    pub fn full_path(&self, path: &Path) -> PathBuf {
+        match self.staged.get(path) {
+            Some(staged) => staged.clone(),
+            None => self.live_path(path),
+        }
+    }
+
+    /// Where the file indexed as `path` is once it is no longer staged.
+    fn live_path(&self, path: &Path) -> PathBuf {
+        match root_of(path).and_then(|name| self.roots.get(name)) {
+            Some(root) => root.join(path.components().skip(1).collect::<PathBuf>()),
+            None => self.base_path.join(path),
+        }
+    }
+
+    /// Whether `path` was received into the staging folder. Its copy in the
+    /// world is left alone by scans and the watcher until it is swapped in.
+    pub fn is_staged(&self, path: &Path) -> bool {
+        self.staged.contains_key(path)
+    }
+
+    /// Whether `full_path` was written by this device in the last few
+    /// seconds, such as a world folder swapped in.
+    pub fn recently_written(&self, full_path: &Path) -> bool {
+        self.writes.recently_written(full_path)
+    }
+
+    /// Where the content of `path` is read from: the shadow copy of its
+    /// world while there is one, otherwise `full_path`.
+    pub fn source_path(&self, path: &Path) -> PathBuf {
+        let full_path = self.full_path(path);
+        let world = path.components().next().and_then(|c| c.as_os_str().to_str());
+        world.and_then(|world| self.shadows.get(world))
+            .and_then(|copy| copy.path_of(&full_path))
+            .unwrap_or(full_path)
+    }
+
+    /// The path a file below the base path or a root is indexed as.
+    pub fn relative_path(&self, full_path: &Path) -> Option<PathBuf> {
+        if let Ok(relative) = full_path.strip_prefix(&self.base_path) {
+            return Some(relative.to_path_buf());
+        }
+        self.roots.iter().find_map(|(name, root)| {
+            Some(PathBuf::from(format!("{}{}", ROOT_MARKER, name)).join(full_path.strip_prefix(root).ok()?))
+        })
+    }
+
+    /// The base path and every root, each with the path its files are
+    /// indexed below.
+    pub fn dirs(&self) -> Vec<(PathBuf, PathBuf)> {
+        std::iter::once((self.base_path.clone(), PathBuf::new()))
+            .chain(self.roots.iter().map(|(name, root)| (root.clone(), PathBuf::from(format!("{}{}", ROOT_MARKER, name)))))
+            .collect()
+    }
+
+    /// The directory the file indexed as `path` is in, the base path or
+    /// one of the roots.
+    pub fn dir_of(&self, path: &Path) -> &Path {
+        root_of(path).and_then(|name| self.roots.get(name)).map_or(&self.base_path, |root| root.as_path())
+    }
+
+    /// When the files of the base path are hashed.
+    pub fn hash_policy(&self) -> HashPolicy {
+        self.hashing
+    }
+
+    /// Threads hashing files at the same time.
+    pub fn hash_workers(&self) -> usize {
+        self.hash_workers
+    }
+
+    /// Whether the base path could be read when `check_available` last looked.
+    pub fn is_available(&self) -> bool {
+        self.available.load(Ordering::Acquire)
+    }
+
+    /// Looks whether the base path can be read, and remembers it. An empty
+    /// base path while files are indexed counts as unavailable too: that is
+    /// what an unmounted share or a disconnected drive usually leaves behind,
+    /// rather than every world having been deleted.
+    pub fn check_available(&self) -> bool {
+        let available = match fs::read_dir(&self.base_path) {
+            Ok(mut entries) => self.files.is_empty() || entries.next().is_some(),
+            Err(_) => false,
+        };
+        self.available.store(available, Ordering::Release);
+        available
+    }
+
+    /// See `FileManager::generation`. Bumped after the change it stands for,
+    /// so entries read after it are at least that new.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn bump(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn get(&self, path: &Path) -> Option<FileInfo> {
+        self.files.get(path).map(|info| info.clone())
+    }
+
+    pub fn entries(&self) -> Vec<FileInfo> {
+        self.files.iter().map(|entry| entry.value().clone()).collect()
+    }
+
+    pub fn tombstones(&self) -> Vec<Tombstone> {
+        self.tombstones.iter().map(|entry| entry.value().clone()).collect()
+    }
+
+    /// The tombstone of `path`, or those of every file under it for a
+    /// deleted folder.
+    pub fn tombstones_under(&self, path: &Path) -> Vec<Tombstone> {
+        self.tombstones.iter().filter(|entry| entry.key().starts_with(path)).map(|entry| entry.value().clone()).collect()
+    }
+
+    /// Where the folder at `path` was moved to, if it was.
+    pub fn renamed(&self, path: &Path) -> Option<Rename> {
+        self.renames.get(path).map(|rename| rename.clone())
+    }
+
+    /// Whether `info` is a version of a file deleted here after it was last
+    /// changed, as a peer that missed the deletion sends it back.
+    pub fn deleted_version(&self, info: &FileInfo) -> bool {
+        self.tombstones.get(&info.path).is_some_and(|tombstone| tombstone.hash == info.hash && info.last_modified <= tombstone.deleted_at)
+    }
+
+    /// Reads a file, to send it. A pending hash is filled in from what was
+    /// read, as the file is hashed on demand under a lazy `HashPolicy`.
+    pub fn get_file_content(&self, path: &Path) -> Result<Vec<u8>> {
+        let full_path = self.source_path(path);
+        let listed = match self.get(path) {
+            Some(info) if info.hash_pending() && full_path == self.full_path(path) => fs::metadata(&full_path).ok().and_then(|m| Some((m.len(), m.modified().ok()?))),
+            _ => None,
+        };
+        let content = self.writes.retry(&full_path, || fs::read(&full_path))?;
+        if let Some((size, last_modified)) = listed {
+            self.set_hash(FileInfo { path: path.to_path_buf(), last_modified, size, hash: hash_bytes(&content) });
+        }
+        Ok(content)
+    }
+
+    /// The entry at `path`, hashing the file first if its hash is pending.
+    /// Used while answering peers, so the hashing does not wait for the budget.
+    pub fn hashed(&self, path: &Path) -> Result<Option<FileInfo>> {
+        match self.get(path) {
+            Some(info) if info.hash_pending() => {
+                let hashed = FileInfo { hash: self.hash_file_now(path)?, ..info };
+                self.set_hash(hashed.clone());
+                Ok(Some(hashed))
+            }
+            info => Ok(info),
+        }
+    }
+
+    /// Stores the hash of an entry left pending, unless the entry was
+    /// replaced, removed or found changed meanwhile. Returns whether it was
+    /// stored.
+    pub fn set_hash(&self, hashed: FileInfo) -> bool {
+        let Some(mut cached) = self.files.get_mut(&hashed.path) else {
+            return false;
+        };
+        if !cached.hash_pending() || cached.size != hashed.size || cached.last_modified != hashed.last_modified {
+            return false;
+        }
+        *cached = hashed;
+        drop(cached);
+        self.bump();
+        true
+    }
+
+    /// Entries still waiting to be hashed.
+    pub fn pending_hashes(&self) -> Vec<FileInfo> {
+        self.files.iter().filter(|entry| entry.hash_pending()).map(|entry| entry.value().clone()).collect()
+    }
+
+    /// The world folders with their files, by name.
+    pub fn worlds(&self) -> Vec<WorldSummary> {
+        let mut worlds: BTreeMap<String, WorldSummary> = BTreeMap::new();
+        for entry in self.files.iter() {
+            let Some(Component::Normal(name)) = entry.path.components().next() else {
+                continue;
+            };
+            let name = name.to_string_lossy().to_string();
+            let world = worlds.entry(name.clone()).or_insert_with(|| WorldSummary { name, files: 0, size: 0, hash_pending: 0, unclean_shutdown: false });
+            world.files += 1;
+            world.size += entry.size;
+            world.hash_pending += entry.hash_pending() as usize;
+        }
+        for world in worlds.values_mut() {
+            world.unclean_shutdown = leveldb::has_lost_files(&self.full_path(Path::new(&world.name)));
+        }
+        worlds.into_values().collect()
+    }
+
+    /// Hashes the file at `path`, below the base path or absolute. Paces
+    /// itself by blocking the thread, so it runs on hashing threads only.
+    pub fn hash_file(&self, path: &Path) -> Result<String> {
+        let full_path = self.full_path(path);
+        let mut file = self.writes.retry(&full_path, || fs::File::open(&full_path))?;
+        self.gaming.before_hashing(file.metadata()?.len());
+        self.cpu.run(|| hash_reader(&mut file))
+    }
+
+    /// Like `hash_file`, for async tasks, which must not block: the work
+    /// only counts against the budget, and the hashing threads wait for it.
+    pub fn hash_file_now(&self, path: &Path) -> Result<String> {
+        let full_path = self.full_path(path);
+        let mut file = self.writes.retry(&full_path, || fs::File::open(&full_path))?;
+        self.gaming.book_hashing(file.metadata()?.len());
+        self.cpu.run_booked(|| hash_reader(&mut file))
+    }
+}
+
+fn hash_reader(reader: &mut impl std::io::Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hashes `paths` with `FileIndex::hash_file` on up to `workers` threads,
+/// returning the results in the same order.
+pub fn hash_files(index: &FileIndex, paths: &[PathBuf], workers: usize) -> Vec<Result<String>> {
+    let workers = index.gaming.hash_workers(workers).min(paths.len());
+    if workers <= 1 {
+        return paths.iter().map(|path| index.hash_file(path)).collect();
+    }
+    std::thread::scope(|scope| {
+        let running: Vec<_> = paths.chunks(paths.len().div_ceil(workers))
+            .map(|chunk| scope.spawn(move || chunk.iter().map(|path| index.hash_file(path)).collect::<Vec<_>>()))
+            .collect();
+        running.into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            .collect()
+    })
+}
+
+/// Keeps the index of the worlds directory and makes every change to it.
+/// Only one task changes it at a time, behind its lock; readers use its
+/// `FileIndex` instead.
 pub struct FileManager {
     base_path: PathBuf,
-    file_cache: HashMap<PathBuf, FileInfo>,
+    index: FileIndex,
+    exclusions: Exclusions,
+    snapshots: Option<Snapshots>,
+    journal: Option<Journal>,
+    /// When each world last received a change.
+    received_at: HashMap<String, Instant>,
+    /// Change set each world is receiving into, and the journal size after
+    /// its last change.
+    open_sets: HashMap<String, (u64, u64)>,
+    staging: bool,
+    conflicts: ConflictPolicy,
+    /// Name of this device, which copies of its version of a world are
+    /// named after.
+    device: String,
+    /// Conflict copies made of each world, by world and device, with when
+    /// they last took a change, so a burst goes into a single copy.
+    conflict_copies: HashMap<(String, String), (String, Instant)>,
 }
 
 impl FileManager {
     pub fn new(base_path: PathBuf) -> Self {
         Self {
+            index: FileIndex::new(base_path.clone()),
             base_path,
-            file_cache: HashMap::new(),
+            exclusions: Exclusions::default(),
+            snapshots: None,
+            journal: None,
+            received_at: HashMap::new(),
+            open_sets: HashMap::new(),
+            staging: false,
+            conflicts: ConflictPolicy::default(),
+            device: "this device".to_string(),
+            conflict_copies: HashMap::new(),
         }
     }
 
+    /// Writes received changes to the staging folder rather than into their
+    /// world, and `commit_staged` swaps them in all at once, so a sync that
+    /// stops halfway never leaves a world made of both versions.
+    pub fn with_staging(mut self, staging: bool) -> Self {
+        self.staging = staging;
+        self
+    }
+
+    /// Settles changes from peers that conflict with this device's, named
+    /// `device`, with the conflict resolution of their world.
+    pub fn with_conflicts(mut self, conflicts: ConflictPolicy, device: String) -> Self {
+        self.conflicts = conflicts;
+        self.device = device;
+        self
+    }
+
+    pub fn with_exclusions(mut self, exclusions: Exclusions) -> Self {
+        self.exclusions = exclusions;
+        self
+    }
+
+    /// Snapshots a world before a burst of received changes overwrites it.
+    pub fn with_snapshots(mut self, snapshots: Snapshots) -> Self {
+        self.snapshots = Some(snapshots);
+        self
+    }
+
+    /// Paces hashing to stay within `cpu`.
+    pub fn with_cpu_budget(mut self, cpu: CpuBudget) -> Self {
+        self.index.cpu = cpu;
+        self
+    }
+
+    /// Reads the worlds `shadows` has a copy of from their copy.
+    pub fn with_shadow_copies(mut self, shadows: ShadowCopies) -> Self {
+        self.index.shadows = shadows;
+        self
+    }
+
+    /// Hashes within the limits of `gaming` while it is active.
+    pub fn with_gaming_mode(mut self, gaming: GamingMode) -> Self {
+        self.index.gaming = gaming;
+        self
+    }
+
+    /// Threads hashing files while a directory is scanned.
+    pub fn with_hash_workers(mut self, workers: usize) -> Self {
+        self.index.hash_workers = workers.max(1);
+        self
+    }
+
+    /// Also syncs each of `roots` by name, such as Minecraft's
+    /// `development_behavior_packs`. Peers put its files into the root of
+    /// that name of their own.
+    pub fn with_roots(mut self, roots: BTreeMap<String, PathBuf>) -> Self {
+        self.index.roots = Arc::new(roots);
+        self
+    }
+
+    /// Leaves hashing files until they are compared or sent, see `HashPolicy`.
+    pub fn with_hash_policy(mut self, hashing: HashPolicy) -> Self {
+        self.index.hashing = hashing;
+        self
+    }
+
+    /// Journals every burst of received changes, so the last one can be
+    /// undone and earlier states of a world rebuilt.
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    pub fn base_path(&self) -> &Path {
+        &self.base_path
+    }
+
+    /// A handle on the entries that reads them without this manager's lock.
+    pub fn index(&self) -> FileIndex {
+        self.index.clone()
+    }
+
+    /// Bumped on every change to the entries, so derived data such as the
+    /// manifest is only rebuilt when something changed. Persisted with the
+    /// index, so it keeps growing across restarts.
+    pub fn generation(&self) -> u64 {
+        self.index.generation()
+    }
+
+    pub fn restore_generation(&mut self, generation: u64) {
+        self.index.generation.fetch_max(generation, Ordering::AcqRel);
+    }
+
+    pub fn load_entries(&mut self, files: Vec<FileInfo>) {
+        for file in files {
+            self.index.files.insert(file.path.clone(), file);
+        }
+        self.index.bump();
+    }
+
+    pub fn entries(&self) -> Vec<FileInfo> {
+        self.index.entries()
+    }
+
+    pub fn load_tombstones(&mut self, tombstones: Vec<Tombstone>) {
+        for tombstone in tombstones {
+            if !self.index.files.contains_key(&tombstone.path) {
+                self.index.tombstones.insert(tombstone.path.clone(), tombstone);
+            }
+        }
+    }
+
+    pub fn tombstones(&self) -> Vec<Tombstone> {
+        self.index.tombstones()
+    }
+
+    /// Keeps the tombstones `keep` returns true for; returns how many were dropped.
+    pub fn retain_tombstones(&mut self, mut keep: impl FnMut(&Tombstone) -> bool) -> usize {
+        let before = self.index.tombstones.len();
+        self.index.tombstones.retain(|_, tombstone| keep(tombstone));
+        before - self.index.tombstones.len()
+    }
+
+    /// Checks a path below the base path or one of the roots.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let dirs = self.index.dirs();
+        let root = dirs.iter().map(|(dir, _)| dir).find(|dir| path.starts_with(dir)).unwrap_or(&self.base_path);
+        self.exclusions.is_excluded(root, path)
+    }
+
+    /// Whether the directory `path` is in can be scanned. A root that does
+    /// not exist is skipped, and its files are not taken for deleted.
+    fn scannable(&self, path: &Path) -> bool {
+        (root_of(path).is_none() || self.index.dir_of(path).is_dir()) && !self.index.is_staged(path)
+    }
+
     pub fn scan_directory(&mut self) -> Result<Vec<FileInfo>> {
         let mut files = Vec::new();
-        let base_path = self.base_path.clone();
-        self.scan_directory_recursive(&base_path, &mut files)?;
+        for (dir, _) in self.index.dirs() {
+            if dir == self.base_path || dir.is_dir() {
+                self.scan_directory_recursive(&dir, &mut files)?;
+            }
+        }
+        Ok(files)
+    }
+
+    /// Scans one folder below the base path, e.g. one that was just created.
+    /// Returns only files the index did not have like this, so files just
+    /// received into a new folder are not sent back.
+    pub fn scan_subtree(&mut self, dir: &Path) -> Result<Vec<FileInfo>> {
+        let prefix = self.index.relative_path(dir).unwrap_or_else(|| dir.to_path_buf());
+        let known: HashMap<PathBuf, String> = self.index.files.iter()
+            .filter(|entry| entry.key().starts_with(&prefix))
+            .map(|entry| (entry.key().clone(), entry.hash.clone()))
+            .collect();
+        let mut files = Vec::new();
+        self.scan_directory_recursive(dir, &mut files)?;
+        files.retain(|file| known.get(&file.path) != Some(&file.hash));
         Ok(files)
     }
 
+    /// Finds the folder `dir`, indexed just now, was moved from: one whose
+    /// files were deleted within `RENAME_WINDOW`, or are still indexed but
+    /// gone, with the same paths below it and the same hashes. A folder is
+    /// only taken for moved once, a copy of it made later is new. The move is
+    /// remembered for `FileIndex::renamed`, so peers move their copy rather
+    /// than get every file again.
+    pub fn detect_rename(&mut self, dir: &Path) -> Result<Option<PathBuf>> {
+        let mut moved = BTreeMap::new();
+        for path in self.index.files.iter().map(|entry| entry.key().clone()).filter(|path| path.starts_with(dir)).collect::<Vec<_>>() {
+            if let Some(info) = self.index.hashed(&path)? {
+                moved.insert(path.strip_prefix(dir)?.to_path_buf(), info.hash);
+            }
+        }
+        let Some((probe, hash)) = moved.iter().next() else {
+            return Ok(None);
+        };
+        let since = SystemTime::now() - RENAME_WINDOW;
+        let recent = |tombstone: &Tombstone| tombstone.deleted_at >= since;
+        // Folders that had a file like the first one, at the same place below them
+        let from_of = |path: &Path| path.ends_with(probe).then(|| path.ancestors().nth(probe.components().count()).map(Path::to_path_buf)).flatten();
+        let candidates: BTreeSet<PathBuf> = self.index.tombstones.iter()
+            .filter(|tombstone| recent(tombstone) && tombstone.hash == *hash)
+            .filter_map(|tombstone| from_of(&tombstone.path))
+            .chain(self.index.files.iter().filter(|entry| entry.hash == *hash).filter_map(|entry| from_of(entry.key())))
+            .filter(|from| {
+                !from.as_os_str().is_empty() && !from.starts_with(dir) && !dir.starts_with(from) && !self.index.renames.contains_key(from)
+                    && self.index.dir_of(from) == self.index.dir_of(dir) && !self.index.live_path(from).exists()
+            })
+            .collect();
+        for from in candidates {
+            // The watcher may not have reported the old folder gone yet
+            self.mark_deleted(&from);
+            let files: Vec<Tombstone> = self.index.tombstones_under(&from).into_iter().filter(recent).collect();
+            let left: BTreeMap<PathBuf, String> = files.iter()
+                .filter_map(|tombstone| Some((tombstone.path.strip_prefix(&from).ok()?.to_path_buf(), tombstone.hash.clone())))
+                .collect();
+            if left == moved {
+                info!("{} was moved to {}", from.display(), dir.display());
+                self.index.renames.insert(from.clone(), Rename { to: dir.to_path_buf(), files });
+                return Ok(Some(from));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Scans the whole base path again after watcher events were lost, and
+    /// marks files that are gone as deleted. Returns the paths of files that
+    /// are new, changed or gone.
+    pub fn rescan(&mut self) -> Result<Vec<PathBuf>> {
+        self.check_available()?;
+        let known: HashMap<PathBuf, String> = self.index.files.iter()
+            .map(|entry| (entry.key().clone(), entry.hash.clone()))
+            .collect();
+        let files = self.scan_directory()?;
+        let present: HashSet<&PathBuf> = files.iter().map(|file| &file.path).collect();
+        let mut changed: Vec<PathBuf> = known.keys().filter(|path| !present.contains(path) && self.scannable(path)).cloned().collect();
+        for path in &changed {
+            self.mark_deleted(path);
+        }
+        changed.extend(files.iter().filter(|file| known.get(&file.path) != Some(&file.hash)).map(|file| file.path.clone()));
+        Ok(changed)
+    }
+
+    /// One scan of a polled base path. Unlike `rescan` it only reads files
+    /// whose size or modification time differ from the index, and only those
+    /// the hash policy hashes eagerly; the others are indexed with a pending
+    /// hash. A file that cannot be read yet is left for the next poll. Marks
+    /// files that are gone as deleted and returns the paths of files that are
+    /// new, changed or gone.
+    pub fn poll(&mut self) -> Result<Vec<PathBuf>> {
+        self.check_available()?;
+        let found = self.find_all()?;
+        let mut present = HashSet::new();
+        let mut modified = Vec::new();
+        for (path, metadata) in found {
+            let relative_path = self.relative_path(&path)?;
+            let (size, last_modified) = (metadata.len(), metadata.modified()?);
+            let known = self.index.files.get(&relative_path).is_some_and(|cached| cached.size == size && cached.last_modified == last_modified);
+            present.insert(relative_path.clone());
+            if !known {
+                modified.push((path, FileInfo { path: relative_path, last_modified, size, hash: String::new() }));
+            }
+        }
+
+        let mut changed: Vec<PathBuf> = self.index.files.iter().map(|entry| entry.key().clone()).filter(|path| !present.contains(path) && self.scannable(path)).collect();
+        for path in &changed {
+            self.mark_deleted(path);
+        }
+        let hashing = self.index.hashing;
+        let eager: Vec<PathBuf> = modified.iter().filter(|(_, file)| hashing.hashes_eagerly(file.size)).map(|(path, _)| path.clone()).collect();
+        let mut hashes = hash_files(&self.index, &eager, self.index.hash_workers).into_iter();
+        for (_, mut file_info) in modified {
+            if hashing.hashes_eagerly(file_info.size) {
+                match hashes.next() {
+                    Some(Ok(hash)) => file_info.hash = hash,
+                    // Locked by the game or gone again, tried on the next poll
+                    _ => continue,
+                }
+            }
+            changed.push(file_info.path.clone());
+            self.insert_entry(file_info.path.clone(), file_info);
+        }
+        Ok(changed)
+    }
+
     fn scan_directory_recursive(&mut self, dir: &Path, files: &mut Vec<FileInfo>) -> Result<()> {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
+        let mut found = Vec::new();
+        self.find_files(dir, &mut found)?;
+        let paths: Vec<PathBuf> = found.iter().map(|(path, _)| path.clone()).collect();
+        let hashes = hash_files(&self.index, &paths, self.index.hash_workers).into_iter().collect::<Result<Vec<_>>>()?;
+        for ((path, metadata), hash) in found.into_iter().zip(hashes) {
+            let relative_path = self.relative_path(&path)?;
+            let file_info = FileInfo {
+                path: relative_path.clone(),
+                last_modified: metadata.modified()?,
+                size: metadata.len(),
+                hash,
+            };
+            files.push(file_info.clone());
+            self.insert_entry(relative_path, file_info);
+        }
+        Ok(())
+    }
+
+    fn relative_path(&self, full_path: &Path) -> Result<PathBuf> {
+        self.index.relative_path(full_path).ok_or_else(|| anyhow::anyhow!("{} is not in a synced directory", full_path.display()))
+    }
+
+    /// Files below the base path and every root that exists.
+    fn find_all(&self) -> Result<Vec<(PathBuf, fs::Metadata)>> {
+        let mut found = Vec::new();
+        for (dir, _) in self.index.dirs() {
+            if dir == self.base_path || dir.is_dir() {
+                self.find_files(&dir, &mut found)?;
+            }
+        }
+        Ok(found)
+    }
 
+    /// Files below `dir` that are not excluded, with their metadata. Files
+    /// with received changes staged are left out, the index has those.
+    fn find_files(&self, dir: &Path, found: &mut Vec<(PathBuf, fs::Metadata)>) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if self.is_excluded(&path) || self.index.relative_path(&path).is_some_and(|relative| self.index.is_staged(&relative)) {
+                continue;
+            }
             if path.is_dir() {
-                self.scan_directory_recursive(&path, files)?;
-            } else {
-                if let Ok(metadata) = fs::metadata(&path) {
-                    let relative_path = path.strip_prefix(&self.base_path)?;
-                    let file_info = FileInfo {
-                        path: relative_path.to_path_buf(),
-                        last_modified: metadata.modified()?,
-                        size: metadata.len(),
-                        hash: self.calculate_file_hash(&path)?,
-                    };
-                    files.push(file_info.clone());
-                    self.file_cache.insert(relative_path.to_path_buf(), file_info);
-                }
+                self.find_files(&path, found)?;
+            } else if let Ok(metadata) = fs::metadata(&path) {
+                found.push((path, metadata));
             }
         }
         Ok(())
     }
 
     pub fn calculate_file_hash(&self, path: &Path) -> Result<String> {
-        use sha2::{Sha256, Digest};
-        let mut file = fs::File::open(path)?;
-        let mut hasher = Sha256::new();
-        std::io::copy(&mut file, &mut hasher)?;
-        let hash = hasher.finalize();
-        Ok(format!("{:x}", hash))
+        self.index.hash_file(path)
+    }
+
+    /// First half of a scan that lists the base path without reading any
+    /// file, so the worlds are known right away. Files whose size and
+    /// modification time match the index keep their hash; the others are
+    /// indexed with a pending hash for `FileIndex::set_hash` to fill in. Returns how
+    /// many files were found and how many of them wait to be hashed.
+    pub fn list_directory(&mut self) -> Result<(usize, usize)> {
+        let found = self.find_all()?;
+        let mut pending = 0;
+        for (path, metadata) in &found {
+            let relative_path = self.relative_path(path)?;
+            let (size, last_modified) = (metadata.len(), metadata.modified()?);
+            let known = self.index.files.get(&relative_path).is_some_and(|cached| cached.size == size && cached.last_modified == last_modified);
+            if !known {
+                let file_info = FileInfo { path: relative_path.clone(), last_modified, size, hash: String::new() };
+                self.insert_entry(relative_path.clone(), file_info);
+            }
+            if self.index.files.get(&relative_path).is_some_and(|cached| cached.hash_pending()) {
+                pending += 1;
+            }
+        }
+        Ok((found.len(), pending))
     }
 
     pub fn get_file_content(&self, path: &Path) -> Result<Vec<u8>> {
-        let full_path = self.base_path.join(path);
-        Ok(fs::read(full_path)?)
+        self.index.get_file_content(path)
     }
 
+    /// Writes `content` to a temporary file in the excluded staging folder and
+    /// moves it over `path`. Replacing the file instead of writing into it
+    /// leaves snapshots that hard-link the old one untouched.
     pub fn save_file_content(&self, path: &Path, content: &[u8]) -> Result<()> {
-        let full_path = self.base_path.join(path);
+        let full_path = self.index.full_path(path);
         if let Some(parent) = full_path.parent() {
             fs::create_dir_all(parent)?;
         }
-        fs::write(full_path, content)?;
+        // On the same volume as the file, so it can be moved over it
+        let staging = self.index.dir_of(path).join(STAGING_DIR);
+        fs::create_dir_all(&staging)?;
+        let tmp = staging.join(format!("{}-{}.tmp", std::process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed)));
+        let written = fs::write(&tmp, content).and_then(|_| self.index.writes.retry(&full_path, || fs::rename(&tmp, &full_path)));
+        if written.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        // Only succeeds once no other write or import is using it
+        let _ = fs::remove_dir(&staging);
+        written?;
+        self.index.writes.record_write(&full_path);
+        Ok(())
+    }
+
+    /// Writes a file received from a peer and indexes it, so the watcher event
+    /// for this write is recognised as unchanged and not sent back. The file
+    /// is read back to check it was written as received. Returns false when
+    /// the file already has this content, such as when a retry delivers it
+    /// again, and leaves it alone, as well as for worlds archived here.
+    pub fn receive_file(&mut self, path: &Path, content: &[u8]) -> Result<bool> {
+        if self.exclusions.is_archived(path) {
+            return Ok(false);
+        }
+        let full_path = self.receivable_path(path)?;
+        // Staged files would only meet it when they are swapped in
+        if self.index.live_path(path).is_dir() {
+            bail!("{} is a folder here", path.display());
+        }
+        let hash = hash_bytes(content);
+        // A pending hash is only worth computing when the sizes match
+        let resized = self.index.get(path).is_some_and(|cached| cached.hash_pending() && cached.size != content.len() as u64);
+        if !resized && self.has_content(path, &hash) {
+            if self.index.get(path).is_none() {
+                let metadata = fs::metadata(&full_path)?;
+                self.insert_entry(path.to_path_buf(), FileInfo { path: path.to_path_buf(), last_modified: metadata.modified()?, size: metadata.len(), hash });
+            }
+            return Ok(false);
+        }
+
+        self.before_receiving(path, Some((&hash, content)))?;
+        self.save_file_content(path, content)?;
+        if self.index.hash_file_now(path)? != hash {
+            bail!("{} reads back differently than it was received", path.display());
+        }
+        let metadata = fs::metadata(self.index.full_path(path))?;
+        self.insert_entry(path.to_path_buf(), FileInfo {
+            path: path.to_path_buf(),
+            last_modified: metadata.modified()?,
+            size: metadata.len(),
+            hash,
+        });
+        Ok(true)
+    }
+
+    /// Whether the copy of `path` here already hashes to `hash`, so the same
+    /// change delivered twice is only applied once. The index is trusted
+    /// where it has the hash; other files are hashed.
+    pub fn has_content(&self, path: &Path, hash: &str) -> bool {
+        if !self.index.full_path(path).is_file() {
+            return false;
+        }
+        match self.index.get(path) {
+            Some(cached) if !cached.hash_pending() => cached.hash == hash,
+            Some(_) => self.index.hashed(path).ok().flatten().is_some_and(|local| local.hash == hash),
+            None => self.index.hash_file_now(path).is_ok_and(|local| local == hash),
+        }
+    }
+
+    /// Checksums of the local copy of `path`, for a peer that wants to send
+    /// only what changed. Empty when there is no copy to build on.
+    pub fn block_signatures(&self, path: &Path, block_size: usize) -> Result<Vec<BlockSignature>> {
+        delta::check_block_size(block_size)?;
+        if !self.receivable_path(path)?.is_file() {
+            return Ok(Vec::new());
+        }
+        Ok(delta::signatures(&self.get_file_content(path)?, block_size))
+    }
+
+    /// Rebuilds `path` from the local copy and a peer's delta, then stores it
+    /// like `receive_file`. Fails when the result does not hash to `hash`,
+    /// e.g. because the local copy changed after its signatures were sent.
+    /// Returns false without applying it when the copy already is the result.
+    pub fn receive_delta(&mut self, path: &Path, block_size: usize, ops: &[DeltaOp], hash: &str) -> Result<bool> {
+        delta::check_block_size(block_size)?;
+        if self.exclusions.is_archived(path) {
+            return Ok(false);
+        }
+        self.receivable_path(path)?;
+        // Delivered before, so the delta no longer fits the copy it was made for
+        if self.has_content(path, hash) {
+            return Ok(false);
+        }
+        let content = delta::apply(&self.get_file_content(path)?, block_size, ops)?;
+        if hash_bytes(&content) != hash {
+            bail!("Delta for {} does not reproduce the sender's file", path.display());
+        }
+        self.receive_file(path, &content)
+    }
+
+    /// Deletes a file the peer it came from no longer has, leaving a tombstone.
+    pub fn remove_received(&mut self, path: &Path) -> Result<()> {
+        if self.exclusions.is_archived(path) {
+            return Ok(());
+        }
+        if self.receivable_path(path)?.is_file() {
+            self.before_receiving(path, None)?;
+            // Only staged as deleted, unless received earlier in the burst
+            let full_path = self.index.full_path(path);
+            if full_path.is_file() {
+                fs::remove_file(&full_path)?;
+                self.index.writes.record_write(&full_path);
+            }
+        }
+        self.mark_deleted(path);
+        Ok(())
+    }
+
+    /// Deletes `path` for a peer that deleted the version hashing to `hash`,
+    /// any version if the hash is empty, along with the folders of its world
+    /// left empty. A copy changed here since is kept and false returned, so
+    /// a deletion never takes a change the peer has not seen.
+    pub fn receive_deletion(&mut self, path: &Path, hash: &str) -> Result<bool> {
+        if self.exclusions.is_archived(path) {
+            return Ok(false);
+        }
+        let existed = self.receivable_path(path)?.is_file();
+        if existed && !hash.is_empty() && !self.has_content(path, hash) {
+            return Ok(false);
+        }
+        self.remove_received(path)?;
+        if root_of(path).is_none() {
+            for folder in path.ancestors().skip(1).filter(|folder| !folder.as_os_str().is_empty()) {
+                if fs::remove_dir(self.index.live_path(folder)).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(existed)
+    }
+
+    /// Moves the folder `from` to `to` for a peer that moved it, if the files
+    /// below it here are those of `files`, by path and hash. Otherwise, such
+    /// as when one changed here since or `to` exists already, nothing is
+    /// moved and false returned, and the peer sends the files instead.
+    pub fn receive_rename(&mut self, from: &Path, to: &Path, files: &BTreeMap<PathBuf, String>) -> Result<bool> {
+        if self.exclusions.is_archived(from) || self.exclusions.is_archived(to) {
+            return Ok(false);
+        }
+        let (source, target) = (self.receivable_path(from)?, self.receivable_path(to)?);
+        if !source.is_dir() || target.exists() {
+            return Ok(false);
+        }
+        // Worlds Minecraft has open or with received changes waiting are left alone
+        for path in [from, to].into_iter().filter(|path| root_of(path).is_none()) {
+            let Some(world) = path.components().next() else {
+                continue;
+            };
+            if busy::lock_held(&self.base_path.join(world)) || self.index.staged.iter().any(|entry| entry.key().starts_with(world)) {
+                return Ok(false);
+            }
+        }
+        let mut here = BTreeMap::new();
+        for path in self.index.files.iter().map(|entry| entry.key().clone()).filter(|path| path.starts_with(from)).collect::<Vec<_>>() {
+            if let Some(info) = self.index.hashed(&path)? {
+                here.insert(path, info.hash);
+            }
+        }
+        if here != *files {
+            return Ok(false);
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(&source, &target)?;
+        self.index.writes.record_write(&source);
+        self.index.writes.record_write(&target);
+        let deleted_at = SystemTime::now();
+        for path in here.into_keys() {
+            if let Some((_, info)) = self.index.files.remove(&path) {
+                let moved = to.join(path.strip_prefix(from)?);
+                self.index.tombstones.insert(path.clone(), Tombstone { path, hash: info.hash.clone(), deleted_at });
+                self.insert_entry(moved.clone(), FileInfo { path: moved, ..info });
+            }
+        }
+        self.index.bump();
+        if root_of(from).is_none() {
+            for folder in from.ancestors().skip(1).filter(|folder| !folder.as_os_str().is_empty()) {
+                if fs::remove_dir(self.index.live_path(folder)).is_err() {
+                    break;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// Takes a snapshot of the world `path` is in when a burst of received
+    /// changes starts, so the whole burst can be undone, and journals the
+    /// change to `after`, a hash and the content. A new world has nothing to
+    /// lose and is not snapshotted, and files of other roots are not worlds.
+    /// With staging, the change goes to the staging folder, and what a
+    /// previous burst left there is swapped in first.
+    fn before_receiving(&mut self, path: &Path, after: Option<(&str, &[u8])>) -> Result<()> {
+        if root_of(path).is_some() {
+            return Ok(());
+        }
+        let mut components = path.components();
+        let (Some(Component::Normal(world)), relative) = (components.next(), components.as_path()) else {
+            return Ok(());
+        };
+        let world = world.to_string_lossy().into_owned();
+        let now = Instant::now();
+        let mut burst_started = self.received_at.get(&world).is_none_or(|last| now.duration_since(*last) >= RECEIVE_BURST_GAP);
+        let mut open = None;
+        if let Some(journal) = &self.journal {
+            // Also a new burst when something else wrote to the journal, such as an undo
+            let size = journal.size(&world)?;
+            open = self.open_sets.get(&world).filter(|(_, written)| !burst_started && *written == size).map(|(set, _)| *set);
+            burst_started |= open.is_none();
+        }
+        self.received_at.insert(world.clone(), now);
+        if self.staging && burst_started {
+            self.commit_staged(&world)?;
+        }
+
+        let mut snapshot = None;
+        if let Some(snapshots) = self.snapshots.as_ref().filter(|_| burst_started && self.base_path.join(&world).is_dir()) {
+            snapshot = Some(snapshots.take(&self.base_path, &world, "received", SystemTime::now())?.name);
+        }
+        if let Some(journal) = &self.journal {
+            let set = match open {
+                Some(set) => set,
+                None => journal.begin(&world, snapshot, None, SystemTime::now())?,
+            };
+            let full_path = self.index.full_path(path);
+            let before = match self.index.get(path) {
+                _ if !full_path.is_file() => None,
+                Some(info) => Some(info.hash.clone()),
+                None => Some(hash_bytes(&fs::read(&full_path)?)),
+            };
+            let change = Change { path: relative.to_path_buf(), before, after: after.map(|(hash, _)| hash.to_string()) };
+            let size = journal.record(&world, set, change, after.map(|(_, content)| content))?;
+            self.open_sets.insert(world.clone(), (set, size));
+        }
+        if self.staging && !self.index.staged.contains_key(path) {
+            let staged = self.base_path.join(STAGING_DIR).join(format!("{}.received", world)).join(relative);
+            self.index.staged.insert(path.to_path_buf(), staged);
+        }
         Ok(())
     }
 
-    pub fn get_file_info(&self, path: &Path) -> Option<&FileInfo> {
-        self.file_cache.get(path)
+    /// Swaps the changes `world` received into it, once all of them arrived:
+    /// the world's files are linked into a new folder, the staged files are
+    /// moved into it and it replaces the world. Files changed here since
+    /// the changes started arriving are kept, unless they were received
+    /// too, and a world left without files is removed. Returns false when
+    /// nothing is staged for the world, or while Minecraft has it open,
+    /// which keeps them staged until it closes it.
+    pub fn commit_staged(&mut self, world: &str) -> Result<bool> {
+        let staged: Vec<(PathBuf, PathBuf)> = self.index.staged.iter()
+            .filter(|entry| entry.key().components().next().is_some_and(|c| c.as_os_str() == world))
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect();
+        if staged.is_empty() {
+            return Ok(false);
+        }
+        let live = self.base_path.join(world);
+        if busy::lock_held(&live) {
+            return Ok(false);
+        }
+        let staging = self.base_path.join(STAGING_DIR);
+        let next = staging.join(format!("{}.next", world));
+        let built = (|| -> Result<()> {
+            if next.exists() {
+                fs::remove_dir_all(&next)?;
+            }
+            if live.is_dir() {
+                link_world(&live, &next)?;
+            } else {
+                fs::create_dir_all(&next)?;
+            }
+            for (path, from) in &staged {
+                let to = next.join(path.components().skip(1).collect::<PathBuf>());
+                if from.is_file() {
+                    fs::create_dir_all(to.parent().unwrap_or(&next))?;
+                    fs::rename(from, &to)?;
+                } else if to.is_file() {
+                    fs::remove_file(&to)?;
+                    // A world deleted file by file goes with its last file
+                    for folder in to.ancestors().skip(1).take_while(|folder| folder.starts_with(&next)) {
+                        if fs::remove_dir(folder).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })();
+        if let Err(e) = built {
+            let _ = fs::remove_dir_all(&next);
+            return Err(e);
+        }
+
+        let replaced = staging.join(format!("{}.replaced", world));
+        let had_live = live.is_dir();
+        if had_live {
+            self.index.writes.retry(&live, || fs::rename(&live, &replaced))?;
+        }
+        let swapped = if next.exists() { self.index.writes.retry(&next, || fs::rename(&next, &live)) } else { Ok(()) };
+        if let Err(e) = swapped {
+            if had_live {
+                fs::rename(&replaced, &live)?;
+            }
+            return Err(e.into());
+        }
+        for (path, _) in &staged {
+            self.index.staged.remove(path);
+        }
+        self.index.writes.record_write(&live);
+        if had_live {
+            fs::remove_dir_all(&replaced)?;
+        }
+        let _ = fs::remove_dir_all(staging.join(format!("{}.received", world)));
+        let _ = fs::remove_dir(&staging);
+        Ok(true)
+    }
+
+    /// Worlds with staged changes that received nothing for `quiet`, for
+    /// senders that never tell when they are done.
+    pub fn settled_stages(&self, quiet: Duration) -> Vec<String> {
+        let worlds: BTreeSet<String> = self.index.staged.iter()
+            .filter_map(|entry| Some(entry.key().components().next()?.as_os_str().to_string_lossy().into_owned()))
+            .collect();
+        worlds.into_iter().filter(|world| self.received_at.get(world).is_none_or(|at| at.elapsed() >= quiet)).collect()
+    }
+
+    /// Settles a change to `path` that `peer` sent while this device has
+    /// changes to the same world it did not send it yet. `theirs` is the
+    /// version sent, `None` for a deletion, and `same_file` tells whether
+    /// this device's changes include `path` itself; only then do strategies
+    /// that pick between versions of a file have a conflict to settle.
+    /// Copies of the world are made once per burst, and copies are not
+    /// copied again: of their files the newest version wins.
+    pub fn resolve_conflict(&mut self, path: &Path, peer: &str, theirs: Option<&FileInfo>, same_file: bool) -> Result<Resolution> {
+        let Some(world) = path.components().next().and_then(|c| c.as_os_str().to_str()).filter(|_| root_of(path).is_none()) else {
+            return Ok(Resolution::Take);
+        };
+        let resolution = match self.conflicts.for_world(world) {
+            resolution if resolution.is_per_world() && conflicts::is_copy(world) => ConflictResolution::Newest,
+            resolution => resolution.clone(),
+        };
+        let ours = self.index.get(path);
+        match resolution {
+            // Both devices keep the version that wins, and copy the other
+            ConflictResolution::KeepBoth if same_file && !resolution.theirs_wins(ours.as_ref(), theirs, peer, &self.device) => match self.conflict_copy(world, peer)? {
+                Some(copy) => Ok(Resolution::Divert(Path::new(&copy).join(path.strip_prefix(world)?))),
+                None => Ok(Resolution::Keep),
+            },
+            ConflictResolution::KeepBoth => {
+                let device = self.device.clone();
+                self.conflict_copy(world, &device)?;
+                Ok(Resolution::Take)
+            }
+            ConflictResolution::Manual => match self.conflict_copy(world, peer)? {
+                Some(copy) => Ok(Resolution::Divert(Path::new(&copy).join(path.strip_prefix(world)?))),
+                None => Ok(Resolution::Take),
+            },
+            _ if !same_file => Ok(Resolution::Take),
+            _ => {
+                let theirs_wins = resolution.theirs_wins(ours.as_ref(), theirs, peer, &self.device);
+                if ours.is_some() && theirs.is_some() && path == Path::new(world).join(LEVEL_DAT) {
+                    Ok(Resolution::Merge { theirs_wins })
+                } else if theirs_wins {
+                    Ok(Resolution::Take)
+                } else {
+                    Ok(Resolution::Keep)
+                }
+            }
+        }
+    }
+
+    /// Applies `content`, a change to `path` a peer sent, as `resolution`
+    /// settled it. Returns whether it changed the file here.
+    pub fn receive_resolved(&mut self, path: &Path, resolution: &Resolution, content: &[u8]) -> Result<bool> {
+        match resolution {
+            Resolution::Take => self.receive_file(path, content),
+            Resolution::Keep => Ok(false),
+            Resolution::Divert(copy) => self.receive_file(copy, content),
+            Resolution::Merge { theirs_wins } => self.receive_merged(path, content, *theirs_wins),
+        }
+    }
+
+    /// Merges `theirs`, a `level.dat` both devices changed, into the one
+    /// here, from the last version received as the base both started from.
+    /// Without one, or if a version cannot be read, the whole file goes to
+    /// the winner instead. Where the merge differs from `theirs`, the peer
+    /// still needs it. Returns whether it changed the file here.
+    pub fn receive_merged(&mut self, path: &Path, theirs: &[u8], theirs_wins: bool) -> Result<bool> {
+        let winner = |files: &mut Self| if theirs_wins { files.receive_file(path, theirs) } else { Ok(false) };
+        let (Some(journal), Some(Component::Normal(world))) = (&self.journal, path.components().next()) else {
+            return winner(self);
+        };
+        let world = world.to_string_lossy().into_owned();
+        let base = journal.last_content(&world, Path::new(LEVEL_DAT))?;
+        let ours = self.get_file_content(path)?;
+        let merged = match base.map(|base| level_dat::merge(&base, &ours, theirs, theirs_wins)) {
+            Some(Ok(merged)) => merged,
+            Some(Err(e)) => {
+                warn!("Could not merge {}, taking the newer version whole: {}", path.display(), e);
+                return winner(self);
+            }
+            None => return winner(self),
+        };
+        if !merged.conflicts.is_empty() {
+            let side = if theirs_wins { "the other device's" } else { "this device's" };
+            info!("{} changed {} on both devices, kept {} values", path.display(), merged.conflicts.join(", "), side);
+        }
+        if merged.content == ours {
+            return Ok(false);
+        }
+        self.receive_file(path, &merged.content)
+    }
+
+    /// The copy of `world` named after `device` that this burst of changes
+    /// goes with, made when there is none yet. `None` when the world is not
+    /// here.
+    fn conflict_copy(&mut self, world: &str, device: &str) -> Result<Option<String>> {
+        let key = (world.to_string(), device.to_string());
+        if let Some((copy, at)) = self.conflict_copies.get_mut(&key) {
+            if at.elapsed() < RECEIVE_BURST_GAP && self.base_path.join(&*copy).is_dir() {
+                *at = Instant::now();
+                return Ok(Some(copy.clone()));
+            }
+        }
+        let copy = self.keep_conflict_copy(world, device, SystemTime::now())
+            .map_err(|e| anyhow!("{} changed here too and could not be copied: {}", world, e))?;
+        match &copy {
+            Some(copy) => {
+                warn!("{} changed here and on another device, kept {}'s version as {}", world, device, copy);
+                self.conflict_copies.insert(key, (copy.clone(), Instant::now()));
+            }
+            None => {
+                self.conflict_copies.remove(&key);
+            }
+        }
+        Ok(copy)
+    }
+
+    /// Copies `world` as it is here to a new world folder named after
+    /// `device`, the device whose version it is, before changes that
+    /// conflict with it overwrite it. The copy is named the same way in
+    /// Minecraft, and picked up and synced like any new world. Returns the
+    /// folder name, or `None` when the world is not here.
+    pub fn keep_conflict_copy(&self, world: &str, device: &str, now: SystemTime) -> Result<Option<String>> {
+        let live = self.base_path.join(world);
+        if !live.is_dir() {
+            return Ok(None);
+        }
+        let base = conflicts::copy_name(world, device, now);
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.base_path.join(&name).exists() {
+            n += 1;
+            name = format!("{} {}", base, n);
+        }
+        let staging = self.base_path.join(STAGING_DIR).join(format!("{}.conflict", name));
+        let copied = (|| -> Result<()> {
+            snapshots::copy_world(&live, &staging)?;
+            let level_name = format!("{}{}", mcworld::display_name(&live), &name[world.len()..]);
+            fs::write(staging.join("levelname.txt"), level_name)?;
+            fs::rename(&staging, self.base_path.join(&name))?;
+            Ok(())
+        })();
+        if let Err(e) = copied {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        let _ = fs::remove_dir(self.base_path.join(STAGING_DIR));
+        Ok(Some(name))
+    }
+
+    /// Cleans up after a run that stopped while changes were staged: they
+    /// are dropped, as nothing tells whether all of them had arrived, and a
+    /// world caught halfway through its swap is put back. Returns how many
+    /// worlds had changes dropped.
+    pub fn recover_stages(&self) -> Result<usize> {
+        let staging = self.base_path.join(STAGING_DIR);
+        let Ok(entries) = fs::read_dir(&staging) else {
+            return Ok(0);
+        };
+        let mut dropped = 0;
+        for entry in entries {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            if let Some(world) = name.strip_suffix(".replaced") {
+                let live = self.base_path.join(world);
+                if live.exists() {
+                    fs::remove_dir_all(&path)?;
+                } else {
+                    fs::rename(&path, &live)?;
+                }
+            } else if name.ends_with(".next") || name.ends_with(".conflict") {
+                fs::remove_dir_all(&path)?;
+            } else if name.ends_with(".received") {
+                fs::remove_dir_all(&path)?;
+                dropped += 1;
+            }
+        }
+        let _ = fs::remove_dir(&staging);
+        Ok(dropped)
+    }
+
+    /// Fails while the base path is unavailable, so files that cannot be
+    /// seen are not taken for deleted and nothing is written in their place.
+    fn check_available(&self) -> Result<()> {
+        if !self.index.check_available() {
+            bail!("The worlds directory {} is unavailable", self.base_path.display());
+        }
+        Ok(())
+    }
+
+    /// Where a file received from a peer goes, refusing paths that leave the
+    /// worlds directory or are excluded, files of roots not synced here, and
+    /// anything while the worlds directory is unavailable.
+    fn receivable_path(&self, path: &Path) -> Result<PathBuf> {
+        if !self.index.is_available() {
+            bail!("The worlds directory {} is unavailable", self.base_path.display());
+        }
+        if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!("Refusing to write {} outside the worlds directory", path.display());
+        }
+        if let Some(name) = root_of(path).filter(|name| !self.index.roots.contains_key(*name)) {
+            bail!("Refusing {}, {} is not synced on this device", path.display(), name);
+        }
+        if root_of(path).is_some() && path.components().count() < 2 {
+            bail!("Refusing to write over the root {}", path.display());
+        }
+        // Where the file ends up, rather than where it is staged
+        if self.is_excluded(&self.index.live_path(path)) {
+            bail!("Refusing to write excluded path {}", path.display());
+        }
+        Ok(self.index.full_path(path))
+    }
+
+    pub fn get_file_info(&self, path: &Path) -> Option<FileInfo> {
+        self.index.get(path)
     }
 
     pub fn update_file_info(&mut self, path: PathBuf, info: FileInfo) {
-        self.file_cache.insert(path, info);
+        self.insert_entry(path, info);
+    }
+
+    /// Stores an entry, bumping the generation only if its content changed.
+    /// A file that comes back supersedes its tombstone.
+    fn insert_entry(&mut self, path: PathBuf, info: FileInfo) {
+        let (hash, size) = (info.hash.clone(), info.size);
+        let old = self.index.files.insert(path.clone(), info);
+        self.index.tombstones.remove(&path);
+        if !self.index.renames.is_empty() {
+            self.index.renames.retain(|from, _| !path.starts_with(from));
+        }
+        if old.is_none_or(|old| old.hash != hash || old.size != size) {
+            self.index.bump();
+        }
+    }
+
+    /// Replaces the entry at `path`, or every entry under it for a deleted
+    /// folder, with a tombstone. Returns how many entries were removed.
+    pub fn mark_deleted(&mut self, path: &Path) -> usize {
+        let deleted: Vec<PathBuf> = self.index.files.iter().map(|entry| entry.key().clone()).filter(|p| p.starts_with(path)).collect();
+        if deleted.is_empty() {
+            return 0;
+        }
+        let deleted_at = SystemTime::now();
+        for path in &deleted {
+            if let Some(info) = self.index.get(path) {
+                self.index.tombstones.insert(path.clone(), Tombstone { path: path.clone(), hash: info.hash, deleted_at });
+                self.index.files.remove(path);
+            }
+        }
+        self.index.bump();
+        deleted.len()
     }
 
-    pub fn handle_conflict(&self, local: &FileInfo, remote: &FileInfo) -> Result<FileInfo> {
-        // Simple conflict resolution: use the newest file
-        if local.last_modified > remote.last_modified {
-            Ok(local.clone())
-        } else {
-            Ok(remote.clone())
+    pub fn remove_entries_under(&mut self, prefix: &Path) {
+        self.index.files.retain(|path, _| !path.starts_with(prefix));
+        self.index.bump();
+    }
+} 
+/// Hard-links every file of `from` into `to`, or copies it with its
+/// modification time where links are not possible, so the index still
+/// matches the files.
+fn link_world(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            link_world(&entry.path(), &target)?;
+        } else if fs::hard_link(entry.path(), &target).is_err() {
+            fs::copy(entry.path(), &target)?;
+            fs::File::options().write(true).open(&target)?.set_modified(entry.metadata()?.modified()?)?;
         }
     }
-} 
\ No newline at end of file
+    Ok(())
+}
+
+pub fn hash_bytes(content: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(content))
+}
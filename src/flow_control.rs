@@ -0,0 +1,104 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Most parts one transfer may have sent ahead of what was stored.
+pub const MAX_CREDITS: usize = 8;
+
+/// How many parts of chunks all senders together may send ahead of what
+/// this device has written, so a fast sender cannot outrun a slow disk such
+/// as an SD card. Each granted part counts until it is stored: while writes
+/// fall behind, the queue stays deep and senders get fewer credits.
+#[derive(Debug, Clone)]
+pub struct WriteWindow {
+    limit: usize,
+    queued: Arc<AtomicUsize>,
+}
+
+impl WriteWindow {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, queued: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// Parts granted and not stored yet, the depth of the write queue.
+    pub fn depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Grants up to `wanted` parts, fewer the fuller the write queue is. A
+    /// transfer that `holds` no credits always gets one, so it can go on.
+    fn grant(&self, wanted: usize, holds: usize) -> usize {
+        let mut queued = self.queued.load(Ordering::Relaxed);
+        loop {
+            let mut granted = wanted.min(self.limit.saturating_sub(queued));
+            if granted == 0 && holds == 0 {
+                granted = 1;
+            }
+            match self.queued.compare_exchange_weak(queued, queued + granted, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => return granted,
+                Err(current) => queued = current,
+            }
+        }
+    }
+
+    fn release(&self, parts: usize) {
+        self.queued.fetch_sub(parts, Ordering::Relaxed);
+    }
+}
+
+impl Default for WriteWindow {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+/// The credits granted to the transfers of one connection, by path. Those
+/// not used are given back once the transfer ends or the connection closes.
+#[derive(Debug)]
+pub struct Credits {
+    window: WriteWindow,
+    held: Mutex<HashMap<PathBuf, usize>>,
+}
+
+impl Credits {
+    pub fn new(window: WriteWindow) -> Self {
+        Self { window, held: Mutex::new(HashMap::new()) }
+    }
+
+    /// Credits to start sending `path` with.
+    pub fn open(&self, path: &Path) -> usize {
+        self.close(path);
+        let granted = self.window.grant(MAX_CREDITS, 0);
+        self.held.lock().unwrap().insert(path.to_path_buf(), granted);
+        granted
+    }
+
+    /// Further credits once a part of `path` was stored.
+    pub fn stored(&self, path: &Path) -> usize {
+        let mut held = self.held.lock().unwrap();
+        let holds = held.entry(path.to_path_buf()).or_default();
+        // A part sent without credit, such as after a reconnect, was not counted
+        if *holds > 0 {
+            *holds -= 1;
+            self.window.release(1);
+        }
+        let granted = self.window.grant(MAX_CREDITS - *holds, *holds);
+        *holds += granted;
+        granted
+    }
+
+    /// Gives back what the transfer of `path` did not use.
+    pub fn close(&self, path: &Path) {
+        if let Some(holds) = self.held.lock().unwrap().remove(path) {
+            self.window.release(holds);
+        }
+    }
+}
+
+impl Drop for Credits {
+    fn drop(&mut self) {
+        let held = self.held.get_mut().unwrap();
+        self.window.release(held.values().sum());
+    }
+}
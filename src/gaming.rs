@@ -0,0 +1,233 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::info;
+use crate::config::GamingModeConfig;
+
+/// Programs that are Minecraft: Bedrock Edition on Windows, or
+/// mcpelauncher's client on Linux and macOS.
+pub const GAME_PROCESSES: &[&str] = &["Minecraft.Windows.exe", "mcpelauncher-client"];
+
+/// Whether `program`, a path or file name, is one of `GAME_PROCESSES`.
+pub fn is_game(program: &str) -> bool {
+    // Split by hand, a Windows path has no separators elsewhere
+    let name = program.trim().rsplit(['/', '\\']).next().unwrap_or_default();
+    GAME_PROCESSES.iter().any(|game| game.eq_ignore_ascii_case(name))
+}
+
+/// What sync may use while Minecraft is played on this device, so it does
+/// not take CPU, disk and bandwidth from the game. Outside gaming mode
+/// nothing is limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GamingLimits {
+    pub hash_workers: usize,
+    /// Bytes hashed per second, no limit when not set.
+    pub hash_rate: Option<u64>,
+    /// Bytes sent per second to all devices together, no limit when not set.
+    pub send_rate: Option<u64>,
+}
+
+impl From<&GamingModeConfig> for GamingLimits {
+    fn from(config: &GamingModeConfig) -> Self {
+        Self {
+            hash_workers: config.hash_workers.max(1),
+            hash_rate: config.hash_mib_per_sec.map(|mib| mib.max(1) * 1024 * 1024),
+            send_rate: config.send_kib_per_sec.map(|kib| kib.max(1) * 1024),
+        }
+    }
+}
+
+/// Switched on while Minecraft is in the foreground, and shared by
+/// everything that hashes or sends, which then stays within its limits.
+#[derive(Debug, Clone)]
+pub struct GamingMode {
+    active: Arc<AtomicBool>,
+    limits: Option<GamingLimits>,
+    hashing: Pace,
+    sending: Pace,
+}
+
+impl GamingMode {
+    /// Never active without `limits`.
+    pub fn new(limits: Option<GamingLimits>) -> Self {
+        Self { active: Arc::new(AtomicBool::new(false)), limits, hashing: Pace::default(), sending: Pace::default() }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Turns gaming mode on or off, returning whether that changed it.
+    pub fn set_active(&self, active: bool) -> bool {
+        let active = active && self.limits.is_some();
+        self.active.swap(active, Ordering::Relaxed) != active
+    }
+
+    fn limits(&self) -> Option<GamingLimits> {
+        self.limits.filter(|_| self.is_active())
+    }
+
+    /// How many of `workers` may hash at the same time.
+    pub fn hash_workers(&self, workers: usize) -> usize {
+        match self.limits() {
+            Some(limits) => workers.min(limits.hash_workers),
+            None => workers,
+        }
+    }
+
+    /// Waits, blocking the thread, until `bytes` more may be hashed.
+    pub fn before_hashing(&self, bytes: u64) {
+        if let Some(wait) = self.limits().and_then(|limits| limits.hash_rate).map(|rate| self.hashing.take(bytes, rate)) {
+            std::thread::sleep(wait);
+        }
+    }
+
+    /// Counts `bytes` hashed without waiting, for async tasks, which must
+    /// not block. The next hashing waits for them instead.
+    pub fn book_hashing(&self, bytes: u64) {
+        if let Some(rate) = self.limits().and_then(|limits| limits.hash_rate) {
+            self.hashing.take(bytes, rate);
+        }
+    }
+
+    /// Waits until `bytes` more may be sent.
+    pub async fn before_sending(&self, bytes: u64) {
+        if let Some(wait) = self.limits().and_then(|limits| limits.send_rate).map(|rate| self.sending.take(bytes, rate)) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Checks every `every` whether Minecraft is in the foreground, with
+    /// `in_foreground` such as `minecraft_in_foreground`, and switches
+    /// gaming mode along.
+    pub async fn follow(self, every: Duration, in_foreground: impl Fn() -> bool) {
+        loop {
+            let playing = in_foreground();
+            if self.set_active(playing) {
+                if playing {
+                    info!("Minecraft is in the foreground, syncing within the gaming mode limits");
+                } else {
+                    info!("Minecraft left the foreground, syncing at full speed");
+                }
+            }
+            tokio::time::sleep(every).await;
+        }
+    }
+}
+
+impl Default for GamingMode {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+/// When the next bytes may go at a rate, shared by all who take them.
+#[derive(Debug, Clone, Default)]
+struct Pace {
+    next: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Pace {
+    /// Books `bytes` at `rate` bytes per second, returning how long to wait
+    /// before using them. Time not used since the last booking is not saved up.
+    fn take(&self, bytes: u64, rate: u64) -> Duration {
+        let now = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        let start = next.filter(|next| *next > now).unwrap_or(now);
+        *next = Some(start + Duration::from_secs_f64(bytes as f64 / rate as f64));
+        start - now
+    }
+}
+
+/// Whether Minecraft is the program in the foreground. On Windows that is
+/// the program owning the active window; elsewhere, where launchers keep no
+/// window apart from the game, it is whether the game runs at all.
+pub fn minecraft_in_foreground() -> bool {
+    #[cfg(windows)]
+    {
+        windows::foreground_is_game()
+    }
+    #[cfg(not(windows))]
+    {
+        minecraft_running()
+    }
+}
+
+/// Whether one of `GAME_PROCESSES` runs on this device.
+pub fn minecraft_running() -> bool {
+    if cfg!(windows) {
+        GAME_PROCESSES.iter().any(|game| {
+            let filter = format!("IMAGENAME eq {}", game);
+            std::process::Command::new("tasklist").args(["/FI", &filter, "/FO", "CSV", "/NH"]).output()
+                .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains(game))
+        })
+    } else if cfg!(target_os = "linux") {
+        let Ok(processes) = std::fs::read_dir("/proc") else {
+            return false;
+        };
+        processes.flatten().any(|process| {
+            std::fs::read(process.path().join("cmdline")).is_ok_and(|cmdline| {
+                let program = cmdline.split(|b| *b == 0).next().unwrap_or_default();
+                is_game(&String::from_utf8_lossy(program))
+            })
+        })
+    } else {
+        GAME_PROCESSES.iter().any(|game| {
+            std::process::Command::new("pgrep").args(["-x", game]).output().is_ok_and(|output| output.status.success())
+        })
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use std::ffi::c_void;
+
+    type Handle = *mut c_void;
+    const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+
+    #[link(name = "user32")]
+    extern "system" {
+        fn GetForegroundWindow() -> Handle;
+        fn GetWindowThreadProcessId(window: Handle, process_id: *mut u32) -> u32;
+        fn GetWindowTextW(window: Handle, text: *mut u16, max: i32) -> i32;
+    }
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn OpenProcess(access: u32, inherit: i32, process_id: u32) -> Handle;
+        fn QueryFullProcessImageNameW(process: Handle, flags: u32, name: *mut u16, size: *mut u32) -> i32;
+        fn CloseHandle(handle: Handle) -> i32;
+    }
+
+    pub fn foreground_is_game() -> bool {
+        // SAFETY: every buffer outlives the call it is passed to, with its length
+        unsafe {
+            let window = GetForegroundWindow();
+            if window.is_null() {
+                return false;
+            }
+            let mut process_id = 0;
+            GetWindowThreadProcessId(window, &mut process_id);
+            let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id);
+            if process.is_null() {
+                return false;
+            }
+            let mut name = [0u16; 1024];
+            let mut size = name.len() as u32;
+            let queried = QueryFullProcessImageNameW(process, 0, name.as_mut_ptr(), &mut size);
+            CloseHandle(process);
+            if queried == 0 {
+                return false;
+            }
+            let program = String::from_utf16_lossy(&name[..size as usize]);
+            if super::is_game(&program) {
+                return true;
+            }
+            // Store apps in a window are framed by another program, named after the app
+            let mut title = [0u16; 256];
+            let len = GetWindowTextW(window, title.as_mut_ptr(), title.len() as i32);
+            program.to_ascii_lowercase().ends_with("applicationframehost.exe")
+                && String::from_utf16_lossy(&title[..len.max(0) as usize]).starts_with("Minecraft")
+        }
+    }
+}
@@ -0,0 +1,163 @@
+use anyhow::{bail, Result};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::HashSet;
+use std::path::{Component, Path};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::config::{Device, GroupConfig, DEFAULT_GROUP};
+use crate::manifest::Manifest;
+
+/// Sent with file contents so the receiver knows which group they belong to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupTag {
+    pub name: String,
+    /// Hash over the group key and the path, so the key is never sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proof: Option<String>,
+}
+
+impl GroupTag {
+    /// Tag for `path` in `group`, with a proof if the group has a key.
+    pub fn new(group: &str, key: Option<&str>, path: &Path) -> Self {
+        Self { name: group.to_string(), proof: key.map(|key| proof(key, group, path)) }
+    }
+}
+
+fn proof(key: &str, group: &str, path: &Path) -> String {
+    let mut hasher = Sha256::new();
+    for part in [key.as_bytes(), group.as_bytes(), path.to_string_lossy().as_bytes()] {
+        hasher.update(part);
+        hasher.update([0]);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Whether `group` has not expired yet.
+pub fn is_live(group: &GroupConfig) -> bool {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    group.expires_at.is_none_or(|expires_at| now < expires_at)
+}
+
+/// The sync groups of one daemon. Each device belongs to exactly one group
+/// and only ever sees that group's worlds.
+#[derive(Debug, Clone, Default)]
+pub struct Groups {
+    groups: Arc<Vec<GroupConfig>>,
+}
+
+impl Groups {
+    pub fn new(groups: Vec<GroupConfig>) -> Result<Self> {
+        let mut names = HashSet::new();
+        let mut devices = HashSet::new();
+        for group in &groups {
+            if !names.insert(group.name.as_str()) {
+                bail!("Sync group {} is configured twice", group.name);
+            }
+            for device in &group.devices {
+                if !devices.insert(device.name.as_str()) {
+                    bail!("Device {} is in more than one sync group", device.name);
+                }
+            }
+        }
+        Ok(Self { groups: Arc::new(groups) })
+    }
+
+    pub fn all(&self) -> &[GroupConfig] {
+        &self.groups
+    }
+
+    /// Groups this daemon currently sends changes and manifests to.
+    pub fn sending(&self) -> impl Iterator<Item = &GroupConfig> {
+        self.groups.iter().filter(|g| g.direction.sends() && is_live(g))
+    }
+
+    pub fn of_device(&self, device: &str) -> Option<&GroupConfig> {
+        self.groups.iter().find(|g| g.devices.iter().any(|d| d.name == device))
+    }
+
+    pub fn device(&self, name: &str) -> Option<&Device> {
+        self.groups.iter().flat_map(|g| &g.devices).find(|d| d.name == name)
+    }
+
+    /// Whether `path` (relative to the worlds root) is in a world `group` syncs.
+    pub fn shares(group: &GroupConfig, path: &Path) -> bool {
+        match path.components().next() {
+            Some(Component::Normal(world)) => group.worlds.is_empty() || group.worlds.iter().any(|w| world == w.as_str()),
+            _ => false,
+        }
+    }
+
+    /// Devices of every group `path` is currently sent to. Devices without an
+    /// address, such as guests of a share, only pull.
+    pub fn devices_for(&self, path: &Path) -> Vec<&Device> {
+        self.sending().filter(|g| Self::shares(g, path)).flat_map(|g| &g.devices).filter(|d| d.is_reachable()).collect()
+    }
+
+    /// Tag for sending `path` to `device`. The default group sends untagged,
+    /// like daemons that know no groups.
+    pub fn tag(&self, device: &str, path: &Path) -> Option<GroupTag> {
+        let group = self.of_device(device).filter(|g| g.name != DEFAULT_GROUP)?;
+        Some(GroupTag::new(&group.name, group.key.as_deref(), path))
+    }
+
+    /// The part of `manifest` a device of `group` may see.
+    pub fn manifest_for(group: &GroupConfig, manifest: &Arc<Manifest>) -> Arc<Manifest> {
+        if group.worlds.is_empty() {
+            return manifest.clone();
+        }
+        let entries = manifest.entries.iter().filter(|(path, _)| Self::shares(group, path)).map(|(p, e)| (p.clone(), e.clone())).collect();
+        Arc::new(Manifest::new(entries))
+    }
+
+    /// Checks that received content for `path` may be written: its group must
+    /// exist, accept contents and not have expired, the proof must match the
+    /// group key and the path must be in one of the group's worlds. Untagged content belongs to the default group.
+    /// A daemon without any groups accepts everything.
+    pub fn authorize(&self, tag: Option<&GroupTag>, path: &Path) -> Result<()> {
+        if self.groups.is_empty() {
+            return Ok(());
+        }
+        let group = self.check(tag, path)?;
+        if !group.direction.receives() {
+            bail!("Sync group {} may not send file contents", group.name);
+        }
+        Ok(())
+    }
+
+    /// Checks that a guest may pull `path`: like `authorize`, but the group
+    /// must have a key and this daemon must send to it. Nothing is readable
+    /// without a group.
+    pub fn authorize_read(&self, tag: &GroupTag, path: &Path) -> Result<()> {
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!("Refusing to read {} outside the worlds directory", path.display());
+        }
+        let group = self.check(Some(tag), path)?;
+        if group.key.is_none() {
+            bail!("Sync group {} has no key to pull with", group.name);
+        }
+        if !group.direction.sends() {
+            bail!("Sync group {} may not pull file contents", group.name);
+        }
+        Ok(())
+    }
+
+    fn check(&self, tag: Option<&GroupTag>, path: &Path) -> Result<&GroupConfig> {
+        let name = tag.map_or(DEFAULT_GROUP, |t| t.name.as_str());
+        let Some(group) = self.groups.iter().find(|g| g.name == name) else {
+            bail!("Unknown sync group {}", name);
+        };
+        if !is_live(group) {
+            bail!("Sync group {} has expired", name);
+        }
+        if let Some(key) = &group.key {
+            if tag.and_then(|t| t.proof.as_deref()) != Some(proof(key, name, path).as_str()) {
+                bail!("Wrong key for sync group {}", name);
+            }
+        }
+        if !Self::shares(group, path) {
+            bail!("{} is not in a world of sync group {}", path.display(), name);
+        }
+        Ok(group)
+    }
+}
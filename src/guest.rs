@@ -0,0 +1,72 @@
+use anyhow::{bail, Result};
+use log::{debug, info};
+use std::path::{Path, PathBuf};
+use crate::file_manager::{self, FileManager};
+use crate::groups::GroupTag;
+use crate::network::{SyncClient, WarmingUp};
+use crate::shares::ShareToken;
+
+/// How often a host that is still scanning its worlds is asked again
+/// before the pull gives up.
+const WARM_UP_RETRIES: u32 = 12;
+
+/// What one pull changed in the local copy of a shared world.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Pulled {
+    pub fetched: usize,
+    pub unchanged: usize,
+    pub removed: usize,
+}
+
+/// Brings the local copy of the world in `token` up to date with the host,
+/// without joining its group: files that differ are downloaded and files
+/// the host no longer has are deleted. A staged world is swapped in once
+/// the whole pull arrived.
+pub async fn pull(client: &SyncClient, token: &ShareToken, files: &mut FileManager) -> Result<Pulled> {
+    let tag = |path: &Path| GroupTag::new(&token.group, Some(&token.key), path);
+    let world_dir = files.base_path().join(&token.world);
+    if world_dir.is_dir() {
+        files.scan_subtree(&world_dir)?;
+    }
+
+    let mut session = client.session().await?;
+    let mut retries = 0;
+    let listing = loop {
+        let listed = session.list_world(&token.world, tag(Path::new(&token.world))).await;
+        match listed.as_ref().err().and_then(|e| e.downcast_ref::<WarmingUp>()) {
+            Some(warming_up) if retries < WARM_UP_RETRIES => {
+                info!("Host is still scanning its worlds, asking again in {}s", warming_up.retry_after.as_secs());
+                tokio::time::sleep(warming_up.retry_after).await;
+                retries += 1;
+            }
+            _ => break listed?,
+        }
+    };
+    let mut pulled = Pulled::default();
+    for (path, entry) in &listing {
+        if files.get_file_info(path).is_some_and(|local| local.hash == entry.hash) {
+            pulled.unchanged += 1;
+            continue;
+        }
+        let content = session.pull_file(path, tag(path)).await?;
+        if file_manager::hash_bytes(&content) != entry.hash {
+            bail!("{} from the host does not match its listing", path.display());
+        }
+        files.receive_file(path, &content)?;
+        debug!("Pulled {} ({} bytes)", path.display(), content.len());
+        pulled.fetched += 1;
+    }
+
+    let gone: Vec<PathBuf> = files.entries()
+        .into_iter()
+        .map(|f| f.path)
+        .filter(|p| p.starts_with(&token.world) && !listing.contains_key(p))
+        .collect();
+    for path in gone {
+        files.remove_received(&path)?;
+        pulled.removed += 1;
+    }
+    files.commit_staged(&token.world)?;
+    info!("Pulled {}: {} files updated, {} unchanged, {} removed", token.world, pulled.fetched, pulled.unchanged, pulled.removed);
+    Ok(pulled)
+}
@@ -0,0 +1,56 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Startup milestones the daemon must reach before it is ready to sync.
+/// Liveness needs none of them, only a responding HTTP server.
+#[derive(Debug, Default)]
+pub struct Health {
+    pub index_loaded: AtomicBool,
+    /// The initial scan listed the worlds, so the index holds them all.
+    pub index_scanned: AtomicBool,
+    pub roots_watched: AtomicBool,
+    pub listener_bound: AtomicBool,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(flag: &AtomicBool) {
+        flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn clear(flag: &AtomicBool) {
+        flag.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_set(flag: &AtomicBool) -> bool {
+        flag.load(Ordering::Relaxed)
+    }
+
+    /// Names of the readiness checks that have not passed yet.
+    pub fn pending(&self) -> Vec<&'static str> {
+        [
+            ("index_loaded", &self.index_loaded),
+            ("index_scanned", &self.index_scanned),
+            ("roots_watched", &self.roots_watched),
+            ("listener_bound", &self.listener_bound),
+        ]
+        .into_iter()
+        .filter(|(_, flag)| !flag.load(Ordering::Relaxed))
+        .map(|(name, _)| name)
+        .collect()
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.pending().is_empty()
+    }
+
+    /// Waits until `flag` is set, for what has to start after a milestone.
+    pub async fn until_set(flag: &AtomicBool) {
+        while !Self::is_set(flag) {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use serde_json::{json, Value};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::aging::{DeviceAging, DeviceStatus};
+use crate::busy::BusyWorlds;
+use crate::config::MqttConfig;
+use crate::conflicts;
+use crate::file_manager::FileIndex;
+use crate::gaming::GamingMode;
+use crate::mcworld;
+use crate::metrics::Metrics;
+use crate::mqtt::{ConnectOptions, Will};
+use crate::reconnect::PeerState;
+use crate::transfer_queue::TransferQueue;
+
+/// One world as published, see `WorldStatus::state`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorldStatus {
+    pub folder: String,
+    pub name: String,
+    pub files: usize,
+    pub size: u64,
+    /// Changes to it queued or in flight to any device.
+    pub queued: usize,
+    /// Open in Minecraft here.
+    pub open: bool,
+    /// Files listed but not hashed yet.
+    pub hash_pending: usize,
+}
+
+impl WorldStatus {
+    /// `open`, `scanning`, `syncing` or `synced`, in that order of precedence.
+    pub fn state(&self) -> &'static str {
+        if self.open {
+            "open"
+        } else if self.hash_pending > 0 {
+            "scanning"
+        } else if self.queued > 0 {
+            "syncing"
+        } else {
+            "synced"
+        }
+    }
+}
+
+/// The state of the daemon published on every update.
+#[derive(Debug, Clone)]
+pub struct DaemonStatus {
+    pub gaming_mode: bool,
+    pub transfers_sent: u64,
+    pub transfers_failed: u64,
+    pub devices: Vec<DeviceStatus>,
+    pub worlds: Vec<WorldStatus>,
+}
+
+/// What the status is read from.
+#[derive(Clone)]
+pub struct StatusSources {
+    pub index: FileIndex,
+    pub aging: DeviceAging,
+    pub queue: Arc<TransferQueue>,
+    pub busy: BusyWorlds,
+    pub gaming: GamingMode,
+    pub metrics: Arc<Metrics>,
+    pub worlds_root: PathBuf,
+}
+
+impl StatusSources {
+    pub async fn gather(&self) -> DaemonStatus {
+        let queued = self.queue.len_by_world().await;
+        let worlds = self.index.worlds().into_iter()
+            .map(|world| WorldStatus {
+                name: mcworld::display_name(&self.worlds_root.join(&world.name)),
+                files: world.files,
+                size: world.size,
+                queued: queued.get(&world.name).copied().unwrap_or(0),
+                open: self.busy.is_busy(&world.name),
+                hash_pending: world.hash_pending,
+                folder: world.name,
+            })
+            .collect();
+        DaemonStatus {
+            gaming_mode: self.gaming.is_active(),
+            transfers_sent: Metrics::get(&self.metrics.transfers_sent),
+            transfers_failed: Metrics::get(&self.metrics.transfers_failed),
+            devices: self.aging.status(&self.queue).await,
+            worlds,
+        }
+    }
+}
+
+/// A message to publish, retained so a dashboard that starts later shows
+/// the last state right away.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// Turns the daemon status into MQTT messages: states below
+/// `<topic>/<device>`, and Home Assistant discovery payloads that make each
+/// a sensor of one Home Assistant device per daemon.
+pub struct Publisher {
+    device: String,
+    base: String,
+    discovery_prefix: String,
+    node: String,
+    /// Discovery and state topics published so far, cleared again once what
+    /// they describe is gone.
+    published: BTreeSet<String>,
+}
+
+impl Publisher {
+    pub fn new(config: &MqttConfig, device: &str) -> Self {
+        Self {
+            device: device.to_string(),
+            base: format!("{}/{}", config.topic.trim_end_matches('/'), slug(device)),
+            discovery_prefix: config.discovery_prefix.trim_end_matches('/').to_string(),
+            node: format!("mcbd_world_sync_{}", slug(device)),
+            published: BTreeSet::new(),
+        }
+    }
+
+    pub fn availability_topic(&self) -> String {
+        format!("{}/availability", self.base)
+    }
+
+    /// Connects with a will that marks every sensor unavailable when the
+    /// daemon goes away.
+    pub fn connect_options(&self, config: &MqttConfig) -> ConnectOptions {
+        ConnectOptions {
+            client_id: self.node.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            keep_alive: (config.interval.max(1) * 3).min(u16::MAX as u64) as u16,
+            will: Some(Will { topic: self.availability_topic(), payload: "offline".to_string() }),
+        }
+    }
+
+    /// Messages for `status`: the discovery payloads and states of every
+    /// sensor, and empty ones for the worlds and devices that are gone,
+    /// which removes their sensors.
+    pub fn messages(&mut self, status: &DaemonStatus) -> Vec<Message> {
+        let mut messages = vec![Message { topic: self.availability_topic(), payload: "online".to_string() }];
+        let state_topic = format!("{}/state", self.base);
+        let online = status.devices.iter().filter(|device| device.state != PeerState::Offline).count();
+        let stale = status.devices.iter().filter(|device| device.stale).count();
+        let queued: usize = status.worlds.iter().map(|world| world.queued).sum();
+        let size: u64 = status.worlds.iter().map(|world| world.size).sum();
+        messages.push(Message {
+            topic: state_topic.clone(),
+            payload: json!({
+                "queued": queued,
+                "transfers_sent": status.transfers_sent,
+                "transfers_failed": status.transfers_failed,
+                "gaming_mode": if status.gaming_mode { "ON" } else { "OFF" },
+                "worlds": status.worlds.len(),
+                "size": size,
+                "devices_online": online,
+                "devices_stale": stale,
+            }).to_string(),
+        });
+        for (object, name, extra) in [
+            ("queued", "Queued changes", json!({ "icon": "mdi:tray-full" })),
+            ("transfers_failed", "Failed transfers", json!({ "state_class": "total_increasing" })),
+            ("size", "Worlds size", json!({ "device_class": "data_size", "unit_of_measurement": "B" })),
+            ("devices_online", "Devices online", json!({})),
+            ("devices_stale", "Stale devices", json!({})),
+        ] {
+            messages.push(self.discovery("sensor", object, name, &state_topic, &format!("{{{{ value_json.{} }}}}", object), extra));
+        }
+        messages.push(self.discovery("binary_sensor", "gaming_mode", "Gaming mode", &state_topic, "{{ value_json.gaming_mode }}", json!({})));
+
+        for device in &status.devices {
+            let topic = format!("{}/device/{}", self.base, slug(&device.name));
+            messages.push(Message {
+                topic: topic.clone(),
+                payload: json!({
+                    "state": match device.state {
+                        PeerState::Online => "online",
+                        PeerState::Idle => "idle",
+                        PeerState::Offline => "offline",
+                    },
+                    "last_synced": device.last_synced.map(|at| timestamp(UNIX_EPOCH + Duration::from_secs(at))),
+                    "stale": device.stale,
+                    "paused": device.paused,
+                    "queued": device.queued,
+                    "failed": device.failed.len(),
+                }).to_string(),
+            });
+            let object = format!("device_{}", slug(&device.name));
+            let connected = "{{ 'OFF' if value_json.state == 'offline' else 'ON' }}";
+            messages.push(self.discovery("binary_sensor", &format!("{}_connected", object), &format!("{} connected", device.name), &topic, connected, json!({ "device_class": "connectivity", "json_attributes_topic": topic })));
+            messages.push(self.discovery("sensor", &format!("{}_last_synced", object), &format!("{} last synced", device.name), &topic, "{{ value_json.last_synced }}", json!({ "device_class": "timestamp" })));
+        }
+
+        for world in &status.worlds {
+            let topic = format!("{}/world/{}", self.base, slug(&world.folder));
+            messages.push(Message {
+                topic: topic.clone(),
+                payload: json!({
+                    "state": world.state(),
+                    "name": world.name,
+                    "folder": world.folder,
+                    "files": world.files,
+                    "size": world.size,
+                    "queued": world.queued,
+                }).to_string(),
+            });
+            messages.push(self.discovery("sensor", &format!("world_{}", slug(&world.folder)), &world.name, &topic, "{{ value_json.state }}", json!({ "icon": "mdi:earth", "json_attributes_topic": topic })));
+        }
+
+        let current: BTreeSet<String> = messages.iter().map(|message| message.topic.clone()).collect();
+        for gone in self.published.difference(&current) {
+            messages.push(Message { topic: gone.clone(), payload: String::new() });
+        }
+        self.published = current;
+        messages
+    }
+
+    /// The discovery payload of one sensor, reading `template` from the
+    /// JSON on `state_topic`.
+    fn discovery(&self, component: &str, object: &str, name: &str, state_topic: &str, template: &str, extra: Value) -> Message {
+        let mut config = json!({
+            "name": name,
+            "unique_id": format!("{}_{}", self.node, object),
+            "object_id": format!("{}_{}", self.node, object),
+            "state_topic": state_topic,
+            "value_template": template,
+            "availability_topic": self.availability_topic(),
+            "device": {
+                "identifiers": [self.node],
+                "name": format!("World sync on {}", self.device),
+                "manufacturer": "mcbd-world-sync",
+                "sw_version": env!("CARGO_PKG_VERSION"),
+            },
+        });
+        if let (Some(config), Value::Object(extra)) = (config.as_object_mut(), extra) {
+            config.extend(extra);
+        }
+        Message { topic: format!("{}/{}/{}/{}/config", self.discovery_prefix, component, self.node, object), payload: config.to_string() }
+    }
+}
+
+/// `name` with everything but letters, digits, `-` and `_` replaced by `_`,
+/// as topics and Home Assistant IDs want it.
+pub fn slug(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' }).collect()
+}
+
+/// `at` in RFC 3339, as Home Assistant reads timestamps.
+fn timestamp(at: SystemTime) -> String {
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    format!("{}T{:02}:{:02}:{:02}+00:00", conflicts::date(at), secs / 3600 % 24, secs / 60 % 60, secs % 60)
+}
@@ -0,0 +1,335 @@
+use anyhow::{anyhow, Result};
+use axum::{Json, Router, body::{Body, Bytes}, routing::{get, post}, extract::{DefaultBodyLimit, Path, Query, State}, http::{header, StatusCode}, response::{Html, IntoResponse, Response}};
+use std::path::{Component, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::mpsc;
+use log::{info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use serde::Deserialize;
+use crate::aging::DeviceAging;
+use crate::busy::BusyWorlds;
+use crate::file_manager::FileIndex;
+use crate::gaming::GamingMode;
+use crate::links::Links;
+use crate::health::Health;
+use crate::manifest::ManifestCache;
+use crate::mcworld;
+use crate::metrics::Metrics;
+use crate::port_mapping::Mappings;
+use crate::snapshots::Snapshots;
+use crate::transfer_queue::TransferQueue;
+
+#[derive(Clone)]
+pub struct HttpState {
+    pub metrics: Arc<Metrics>,
+    pub health: Arc<Health>,
+    /// Receives the worlds to sync ahead of background transfers.
+    pub sync_now: mpsc::UnboundedSender<SyncNow>,
+    /// Told to read the archived worlds again.
+    pub archived: mpsc::UnboundedSender<()>,
+    /// Sync cursors per peer, cleared by a reconcile request.
+    pub cursors: ManifestCache,
+    pub aging: DeviceAging,
+    pub queue: Arc<TransferQueue>,
+    /// Set when one-time world downloads are enabled.
+    pub downloads: Option<WorldLinks>,
+    /// Set when one-time world uploads are enabled.
+    pub uploads: Option<WorldLinks>,
+    /// Set when worlds can be restored from their snapshots.
+    pub snapshots: Option<WorldSnapshots>,
+    /// Ports forwarded on the router, empty without port mapping.
+    pub port_mappings: Mappings,
+    /// Set when the worlds directory is indexed, to list its worlds.
+    pub index: Option<FileIndex>,
+    pub gaming: GamingMode,
+    /// Worlds open in Minecraft, here and on other devices.
+    pub busy: BusyWorlds,
+}
+
+/// A request to send worlds right away, from `POST /sync` or
+/// `POST /sync/<world>`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct SyncNow {
+    /// Every world when not set.
+    #[serde(skip)]
+    pub world: Option<String>,
+    /// Only to this device or mirror, instead of every one syncing the world.
+    pub device: Option<String>,
+    /// Sent outside the gaming mode limits and `performance.max_transfers`.
+    #[serde(default)]
+    pub ignore_limits: bool,
+}
+
+#[derive(Clone)]
+pub struct WorldLinks {
+    pub links: Links,
+    /// The worlds directory links point into, or upload to.
+    pub worlds: PathBuf,
+}
+
+#[derive(Clone)]
+pub struct WorldSnapshots {
+    pub snapshots: Snapshots,
+    pub worlds: PathBuf,
+}
+
+pub fn router(state: HttpState) -> Router {
+    Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/status", get(status))
+        .route("/sync", post(sync_all))
+        .route("/sync/{world}", post(sync_now))
+        .route("/reconcile/{peer}", post(reconcile))
+        .route("/restore/{world}", post(restore))
+        .route("/archived", post(reload_archived))
+        .route("/download/{token}", get(download))
+        .route("/upload/{token}", get(upload_form).post(upload).layer(DefaultBodyLimit::max(mcworld::MAX_UPLOAD_SIZE)))
+        .with_state(state)
+}
+
+pub async fn serve(bind: &str, state: HttpState) -> Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    info!("HTTP server listening on {}", listener.local_addr()?);
+    axum::serve(listener, router(state)).await?;
+    Ok(())
+}
+
+/// Sends a request without a body to the HTTP server of a running daemon
+/// at `bind`, for the commands that talk to it. Returns the status code and
+/// the body.
+pub async fn request(bind: &str, method: &str, path: &str) -> Result<(u16, String)> {
+    // A server listening on every interface is reached on this device
+    let address = match bind.rsplit_once(':') {
+        Some(("0.0.0.0" | "[::]", port)) => format!("127.0.0.1:{}", port),
+        _ => bind.to_string(),
+    };
+    let mut stream = tokio::net::TcpStream::connect(&address).await
+        .map_err(|e| anyhow!("Cannot reach the daemon at {} ({}), is it running with http.enabled?", address, e))?;
+    let target = match path.split_once('?') {
+        Some((path, query)) => format!("{}?{}", encode_path(path), encode_query(query)),
+        None => encode_path(path),
+    };
+    let request = format!("{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", method, target, address);
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or_else(|| anyhow!("Incomplete response from the daemon"))?;
+    let status = head.split(' ').nth(1).and_then(|s| s.parse().ok()).ok_or_else(|| anyhow!("Invalid response from the daemon"))?;
+    Ok((status, body.to_string()))
+}
+
+/// Percent-encodes what a URL path cannot hold as it is, such as the spaces
+/// in world names.
+fn encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'/' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Percent-encodes the keys and values of a query, keeping what separates
+/// them.
+fn encode_query(query: &str) -> String {
+    query.split('&').map(|pair| pair.split('=').map(encode_path).collect::<Vec<_>>().join("=")).collect::<Vec<_>>().join("&")
+}
+
+async fn metrics(State(state): State<HttpState>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], state.metrics.render_prometheus())
+}
+
+/// Answers as long as the runtime is alive.
+async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, "ok\n")
+}
+
+/// 503 with the unmet checks until the daemon is able to sync.
+async fn readyz(State(state): State<HttpState>) -> impl IntoResponse {
+    let pending = state.health.pending();
+    if pending.is_empty() {
+        (StatusCode::OK, "ready\n".to_string())
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, format!("not ready: {}\n", pending.join(", ")))
+    }
+}
+
+/// Every configured device with its last sync and queued transfers, the
+/// indexed worlds, which are listed before their files are hashed, and the
+/// worlds open in Minecraft.
+async fn status(State(state): State<HttpState>) -> impl IntoResponse {
+    let worlds = state.index.as_ref().map(FileIndex::worlds).unwrap_or_default();
+    Json(serde_json::json!({
+        "devices": state.aging.status(&state.queue).await,
+        "worlds": worlds,
+        "port_mappings": state.port_mappings.current(),
+        "gaming_mode": state.gaming.is_active(),
+        "busy_worlds": state.busy.worlds(),
+        "busy_on_devices": state.busy.peers(),
+    }))
+}
+
+/// Queues every file of one world with interactive priority.
+async fn sync_now(State(state): State<HttpState>, Path(world): Path<String>, Query(request): Query<SyncNow>) -> impl IntoResponse {
+    let mut components = std::path::Path::new(&world).components();
+    if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+        return (StatusCode::BAD_REQUEST, "expected a world folder name\n".to_string());
+    }
+    if state.sync_now.send(SyncNow { world: Some(world.clone()), ..request }).is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "sync is not running\n".to_string());
+    }
+    (StatusCode::ACCEPTED, format!("syncing {}\n", world))
+}
+
+/// Queues every file of every world with interactive priority.
+async fn sync_all(State(state): State<HttpState>, Query(request): Query<SyncNow>) -> impl IntoResponse {
+    if state.sync_now.send(SyncNow { world: None, ..request }).is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "sync is not running\n".to_string());
+    }
+    (StatusCode::ACCEPTED, "syncing every world\n".to_string())
+}
+
+/// Starts over with `peer`, which is needed after it was offline longer than
+/// the tombstone retention.
+async fn reconcile(State(state): State<HttpState>, Path(peer): Path<String>) -> impl IntoResponse {
+    if !state.cursors.reconcile(&peer).await {
+        return (StatusCode::NOT_FOUND, format!("no sync state for {}\n", peer));
+    }
+    (StatusCode::ACCEPTED, format!("reconciling {}\n", peer))
+}
+
+/// Applies worlds archived and restored with the command line.
+async fn reload_archived(State(state): State<HttpState>) -> impl IntoResponse {
+    if state.archived.send(()).is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "archiving is not running\n");
+    }
+    (StatusCode::ACCEPTED, "reading archived worlds\n")
+}
+
+/// Puts a world back the way its latest snapshot has it, which undoes the
+/// changes received since. The restore is itself snapshotted first, so a
+/// second request undoes it again.
+async fn restore(State(state): State<HttpState>, Path(world): Path<String>) -> Response {
+    let Some(WorldSnapshots { snapshots, worlds }) = state.snapshots else {
+        return (StatusCode::NOT_FOUND, "snapshots are not enabled\n").into_response();
+    };
+    let restored = tokio::task::spawn_blocking(move || {
+        let Some(latest) = snapshots.list(&world)?.pop() else {
+            return Ok(None);
+        };
+        snapshots.restore(&worlds, &latest, SystemTime::now())?;
+        Ok::<_, anyhow::Error>(Some(latest))
+    }).await;
+    match restored {
+        Ok(Ok(Some(snapshot))) => {
+            info!("Restored {} from snapshot {}", snapshot.world, snapshot.name);
+            (StatusCode::OK, format!("restored {} from {}\n", snapshot.world, snapshot.name)).into_response()
+        }
+        Ok(Ok(None)) => (StatusCode::NOT_FOUND, "no snapshot of this world\n").into_response(),
+        Ok(Err(e)) => (StatusCode::BAD_REQUEST, format!("not restored: {}\n", e)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e)).into_response(),
+    }
+}
+
+/// Streams a world as .mcworld for a one-time link. Unknown, used and expired
+/// links all look the same.
+async fn download(State(state): State<HttpState>, Path(token): Path<String>) -> Response {
+    let not_found = || (StatusCode::NOT_FOUND, "no such download\n").into_response();
+    let Some(downloads) = state.downloads else {
+        return not_found();
+    };
+    let link = match downloads.links.redeem(&token, SystemTime::now()) {
+        Ok(Some(link)) => link,
+        Ok(None) => return not_found(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e)).into_response(),
+    };
+    let world_dir = downloads.worlds.join(&link.world);
+    if !world_dir.is_dir() {
+        return not_found();
+    }
+    info!("Serving {} as a download", link.world);
+    // Header values must be plain ASCII
+    let name: String = mcworld::display_name(&world_dir)
+        .chars()
+        .map(|c| if (c.is_ascii_graphic() || c == ' ') && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let chunks = futures::stream::unfold(mcworld::stream(world_dir), |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    (
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.mcworld\"", name)),
+        ],
+        Body::from_stream(chunks),
+    ).into_response()
+}
+
+const UPLOAD_FORM: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Upload a world</title></head>
+<body>
+<h1>Upload a world</h1>
+<p>Choose a .mcworld file exported from Minecraft.</p>
+<input type="file" id="world" accept=".mcworld">
+<button onclick="upload()">Upload</button>
+<p id="result"></p>
+<script>
+async function upload() {
+  const file = document.getElementById("world").files[0];
+  if (!file) return;
+  const result = document.getElementById("result");
+  result.textContent = "Uploading...";
+  const response = await fetch(location.href, { method: "POST", body: file });
+  result.textContent = await response.text();
+}
+</script>
+</body>
+</html>
+"#;
+
+/// Page to pick a .mcworld for an upload link, which stays usable until a
+/// world was imported with it.
+async fn upload_form(State(state): State<HttpState>, Path(token): Path<String>) -> Response {
+    match state.uploads.map(|uploads| uploads.links.find(&token, SystemTime::now())) {
+        Some(Ok(Some(_))) => Html(UPLOAD_FORM).into_response(),
+        Some(Err(e)) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e)).into_response(),
+        _ => (StatusCode::NOT_FOUND, "no such upload\n").into_response(),
+    }
+}
+
+/// Imports an uploaded .mcworld as the link's world. The new folder is
+/// picked up by the watcher and synced like any other world.
+async fn upload(State(state): State<HttpState>, Path(token): Path<String>, body: Bytes) -> Response {
+    let not_found = || (StatusCode::NOT_FOUND, "no such upload\n").into_response();
+    let Some(uploads) = state.uploads else {
+        return not_found();
+    };
+    let link = match uploads.links.find(&token, SystemTime::now()) {
+        Ok(Some(link)) => link,
+        Ok(None) => return not_found(),
+        Err(e) => return (StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e)).into_response(),
+    };
+    let world_dir = uploads.worlds.join(&link.world);
+    if world_dir.exists() {
+        return (StatusCode::CONFLICT, format!("{} already exists\n", link.world)).into_response();
+    }
+    let imported = tokio::task::spawn_blocking(move || mcworld::import(&body, &world_dir)).await;
+    match imported {
+        Ok(Ok(files)) => {
+            // A failed import leaves the link usable, a successful one uses it up
+            if let Err(e) = uploads.links.redeem(&token, SystemTime::now()) {
+                warn!("Failed to use up upload link for {}: {}", link.world, e);
+            }
+            info!("Imported uploaded world {} ({} files)", link.world, files);
+            (StatusCode::CREATED, format!("imported {}\n", link.world)).into_response()
+        }
+        Ok(Err(e)) => (StatusCode::BAD_REQUEST, format!("not imported: {}\n", e)).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("{}\n", e)).into_response(),
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::{Result, bail};
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use log::{info, warn};
+use sha2::{Sha256, Digest};
+use crate::file_manager::{FileInfo, Tombstone};
+
+/// On-disk form of the file index, tied to the root it was built from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PersistedIndex {
+    pub root: PathBuf,
+    pub root_fingerprint: String,
+    /// File manager generation when the index was saved.
+    #[serde(default)]
+    pub generation: u64,
+    pub files: Vec<FileInfo>,
+    #[serde(default)]
+    pub tombstones: Vec<Tombstone>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum IndexCheck {
+    /// No index on disk yet.
+    Missing,
+    Matches,
+    /// The index was built for another root directory.
+    Mismatch { stored_root: PathBuf },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MismatchAction {
+    /// Throw the old entries away and scan from scratch.
+    Rebuild,
+    /// Keep the entries, the worlds were moved to the new root as they are.
+    Migrate,
+}
+
+/// A file whose entry in the stored index does not match the worlds
+/// directory, as found by `verify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// Indexed with other content.
+    Changed(PathBuf),
+    /// Indexed but gone.
+    Missing(PathBuf),
+    /// There but not indexed.
+    Unindexed(PathBuf),
+}
+
+/// Compares the `stored` entries with the files `scanned` from disk, only
+/// below `prefix`. Entries still waiting to be hashed are compared by size.
+pub fn verify(stored: &[FileInfo], scanned: &[FileInfo], prefix: &Path) -> Vec<Discrepancy> {
+    let stored: BTreeMap<&Path, &FileInfo> = stored.iter().filter(|f| f.path.starts_with(prefix)).map(|f| (f.path.as_path(), f)).collect();
+    let scanned: BTreeMap<&Path, &FileInfo> = scanned.iter().filter(|f| f.path.starts_with(prefix)).map(|f| (f.path.as_path(), f)).collect();
+    let mut found = Vec::new();
+    for (path, entry) in &stored {
+        match scanned.get(path) {
+            None => found.push(Discrepancy::Missing(path.to_path_buf())),
+            Some(file) if file.size != entry.size || (!entry.hash_pending() && file.hash != entry.hash) => found.push(Discrepancy::Changed(path.to_path_buf())),
+            Some(_) => {}
+        }
+    }
+    found.extend(scanned.keys().filter(|path| !stored.contains_key(*path)).map(|path| Discrepancy::Unindexed(path.to_path_buf())));
+    found
+}
+
+/// Identifies a root directory by its canonical path and, where the platform
+/// records it, its creation time, so a deleted and recreated folder differs too.
+pub fn root_fingerprint(root: &Path) -> String {
+    let canonical = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+    let mut hasher = Sha256::new();
+    hasher.update(canonical.to_string_lossy().as_bytes());
+    if let Ok(created) = fs::metadata(root).and_then(|m| m.created()) {
+        if let Ok(since_epoch) = created.duration_since(UNIX_EPOCH) {
+            hasher.update(since_epoch.as_nanos().to_le_bytes());
+        }
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+pub fn load(path: &Path) -> Result<Option<PersistedIndex>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let index_str = fs::read_to_string(path)?;
+    Ok(Some(serde_json::from_str(&index_str)?))
+}
+
+pub fn save(path: &Path, root: &Path, generation: u64, files: Vec<FileInfo>, tombstones: Vec<Tombstone>) -> Result<()> {
+    let index = PersistedIndex {
+        root: root.to_path_buf(),
+        root_fingerprint: root_fingerprint(root),
+        generation,
+        files,
+        tombstones,
+    };
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec(&index)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+pub fn check(index: Option<&PersistedIndex>, root: &Path) -> IndexCheck {
+    match index {
+        None => IndexCheck::Missing,
+        Some(index) if index.root_fingerprint == root_fingerprint(root) => IndexCheck::Matches,
+        Some(index) => IndexCheck::Mismatch { stored_root: index.root.clone() },
+    }
+}
+
+/// Asks the user how to deal with an index built for another root. Refuses to
+/// continue when nobody can answer (no terminal attached).
+pub fn resolve_mismatch(stored_root: &Path, root: &Path) -> Result<MismatchAction> {
+    warn!("The index was built for {}, but the configured worlds directory is {}", stored_root.display(), root.display());
+    if !io::stdin().is_terminal() {
+        bail!("Index does not match the configured worlds directory. Run interactively to rebuild or migrate it.");
+    }
+    ask_mismatch(root, io::stdin().lock())
+}
+
+/// Prompts until `answers` names an action; quitting or running out of input
+/// refuses to continue.
+pub fn ask_mismatch(root: &Path, mut answers: impl BufRead) -> Result<MismatchAction> {
+    loop {
+        print!("[r]ebuild the index, [m]igrate it to the new directory, or [q]uit? ");
+        io::stdout().flush()?;
+        let mut answer = String::new();
+        if answers.read_line(&mut answer)? == 0 {
+            bail!("Refusing to run with a mismatched index");
+        }
+        match answer.trim().to_lowercase().as_str() {
+            "r" | "rebuild" => {
+                info!("Rebuilding index for {}", root.display());
+                return Ok(MismatchAction::Rebuild);
+            }
+            "m" | "migrate" => {
+                info!("Migrating index to {}", root.display());
+                return Ok(MismatchAction::Migrate);
+            }
+            "q" | "quit" => bail!("Refusing to run with a mismatched index"),
+            _ => continue,
+        }
+    }
+}
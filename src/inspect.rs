@@ -0,0 +1,90 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use crate::leveldb::{self, Damaged};
+use crate::players;
+
+/// Chunk record tags, from the 3D data (43) to the checksums and generation
+/// data of newer versions, plus the legacy version tag (118).
+fn is_chunk_tag(tag: u8) -> bool {
+    matches!(tag, 43..=65 | 118)
+}
+
+/// Sub-chunks add their index after this tag.
+const SUB_CHUNK_TAG: u8 = 47;
+
+/// What a world's database holds, read by Bedrock's key layout, to judge
+/// whether it is worth restoring from a snapshot.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Inspection {
+    pub keys: usize,
+    /// Sum of the value sizes.
+    pub bytes: u64,
+    /// Chunks with any data, by dimension.
+    pub chunks: BTreeMap<String, usize>,
+    /// `~local_player` and the `player_…` keys.
+    pub players: Vec<String>,
+    /// Entities stored apart from their chunk by newer versions.
+    pub actors: usize,
+    pub maps: usize,
+    pub damaged: Vec<Damaged>,
+    /// Files every world needs that are not there.
+    pub missing: Vec<&'static str>,
+}
+
+pub fn dimension(id: i32) -> String {
+    match id {
+        0 => "overworld".to_string(),
+        1 => "nether".to_string(),
+        2 => "end".to_string(),
+        other => format!("dimension {}", other),
+    }
+}
+
+/// The chunk a key belongs to, as x, z and dimension.
+pub fn chunk(key: &[u8]) -> Option<(i32, i32, i32)> {
+    let (dimension, tag) = match key.len() {
+        9 | 10 => (0, key[8]),
+        13 | 14 => (i32::from_le_bytes(key[8..12].try_into().ok()?), key[12]),
+        _ => return None,
+    };
+    let sub_chunk = key.len() == 10 || key.len() == 14;
+    if !is_chunk_tag(tag) || sub_chunk != (tag == SUB_CHUNK_TAG) {
+        return None;
+    }
+    Some((i32::from_le_bytes(key[0..4].try_into().ok()?), i32::from_le_bytes(key[4..8].try_into().ok()?), dimension))
+}
+
+/// Reads the database of the world in `world_dir` without changing it.
+pub fn inspect(world_dir: &Path) -> Result<Inspection> {
+    let mut inspection = Inspection::default();
+    let db = world_dir.join("db");
+    for (required, path) in [("level.dat", world_dir.join("level.dat")), ("db/CURRENT", db.join("CURRENT"))] {
+        if !path.is_file() {
+            inspection.missing.push(required);
+        }
+    }
+    if !db.is_dir() {
+        return Ok(inspection);
+    }
+    let contents = leveldb::read(&db)?;
+    let mut chunks = BTreeSet::new();
+    for (key, entry) in &contents.keys {
+        inspection.keys += 1;
+        inspection.bytes += entry.size as u64;
+        if let Some(chunk) = chunk(key) {
+            chunks.insert(chunk);
+        } else if players::is_player_key(key) {
+            inspection.players.push(String::from_utf8_lossy(key).into_owned());
+        } else if key.starts_with(b"actorprefix") {
+            inspection.actors += 1;
+        } else if key.starts_with(b"map_") {
+            inspection.maps += 1;
+        }
+    }
+    for (_, _, id) in chunks {
+        *inspection.chunks.entry(dimension(id)).or_default() += 1;
+    }
+    inspection.damaged = contents.damaged;
+    Ok(inspection)
+}
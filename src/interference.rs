@@ -0,0 +1,91 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use log::debug;
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// How long after one of our own writes a lock is blamed on a scanner
+/// (Windows Defender, Search Indexer) rather than on a real problem.
+const INTERFERENCE_WINDOW: Duration = Duration::from_secs(10);
+const MAX_RETRIES: u32 = 5;
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Windows error codes for files opened by another process
+#[cfg(windows)]
+const ERROR_SHARING_VIOLATION: i32 = 32;
+#[cfg(windows)]
+const ERROR_LOCK_VIOLATION: i32 = 33;
+
+#[cfg(windows)]
+pub fn is_sharing_violation(e: &io::Error) -> bool {
+    matches!(e.raw_os_error(), Some(ERROR_SHARING_VIOLATION) | Some(ERROR_LOCK_VIOLATION))
+}
+
+/// Other systems do not lock files against other processes, and the same
+/// numbers mean something else there (`EPIPE`, `EDOM` on Linux).
+#[cfg(not(windows))]
+pub fn is_sharing_violation(_e: &io::Error) -> bool {
+    false
+}
+
+/// Waits `delay` before a retry, returning false when this thread must not
+/// wait. Async tasks on a multi-threaded runtime hand the thread's other
+/// tasks over first; on a single-threaded runtime waiting would stall every
+/// task, so there is no retry.
+fn pause(delay: Duration) -> bool {
+    match Handle::try_current() {
+        Err(_) => thread::sleep(delay),
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => tokio::task::block_in_place(|| thread::sleep(delay)),
+        Ok(_) => return false,
+    }
+    true
+}
+
+/// Remembers which files we wrote recently, so short-lived locks taken by
+/// scanners reacting to those writes can be retried quietly.
+#[derive(Default)]
+pub struct WriteTracker {
+    recent: Mutex<HashMap<PathBuf, Instant>>,
+}
+
+impl WriteTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_write(&self, path: &Path) {
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, at| at.elapsed() < INTERFERENCE_WINDOW);
+        recent.insert(path.to_path_buf(), Instant::now());
+    }
+
+    pub fn recently_written(&self, path: &Path) -> bool {
+        let recent = self.recent.lock().unwrap();
+        recent.get(path).is_some_and(|at| at.elapsed() < INTERFERENCE_WINDOW)
+    }
+
+    fn is_interference(&self, path: &Path, e: &io::Error) -> bool {
+        (is_sharing_violation(e) || e.kind() == io::ErrorKind::PermissionDenied) && self.recently_written(path)
+    }
+
+    /// Runs a file operation, retrying it a few times while it fails with what
+    /// looks like a scanner briefly holding a file we just wrote.
+    pub fn retry<T>(&self, path: &Path, mut op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut attempt = 0;
+        loop {
+            match op() {
+                Err(e) if attempt < MAX_RETRIES && self.is_interference(path, &e) => {
+                    attempt += 1;
+                    debug!("{} is locked by another process ({}), retry {}/{}", path.display(), e, attempt, MAX_RETRIES);
+                    if !pause(RETRY_DELAY * attempt) {
+                        return Err(e);
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
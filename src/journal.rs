@@ -0,0 +1,247 @@
+use anyhow::{anyhow, bail, Result};
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+use crate::chunk_store::ChunkStore;
+use crate::snapshots::{self, check_world, Snapshots};
+
+/// A file a change set touched. Hashes are `None` where the file did not
+/// exist.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// Relative to the world folder.
+    pub path: PathBuf,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+/// Changes received for a world in one burst, see `RECEIVE_BURST_GAP`, or
+/// made by undoing one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeSet {
+    pub world: String,
+    /// Numbers the change sets of a world, from 1.
+    pub seq: u64,
+    /// Snapshot of the world from before the change set, if it existed then.
+    pub snapshot: Option<String>,
+    pub started_at: SystemTime,
+    /// The change set this one undid.
+    pub undoes: Option<u64>,
+    pub changes: Vec<Change>,
+}
+
+impl ChangeSet {
+    /// Adds a change, keeping the first `before` of a file changed again.
+    pub fn record(&mut self, change: Change) {
+        match self.changes.iter_mut().find(|c| c.path == change.path) {
+            Some(existing) => existing.after = change.after,
+            None => self.changes.push(change),
+        }
+    }
+}
+
+/// One line of a world's journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "entry", rename_all = "snake_case")]
+enum Entry {
+    Begin { set: u64, snapshot: Option<String>, started_at: SystemTime, undoes: Option<u64> },
+    Change { set: u64, change: Change },
+}
+
+/// Every change set applied to each world, appended to one file per world as
+/// it happens, with the received contents kept by hash. Together with the
+/// snapshots, this rebuilds a world as it was after any change set.
+#[derive(Debug, Clone)]
+pub struct Journal {
+    dir: PathBuf,
+    contents: ChunkStore,
+}
+
+impl Journal {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { contents: ChunkStore::new(dir.join("contents")), dir }
+    }
+
+    fn file(&self, world: &str) -> Result<PathBuf> {
+        check_world(world)?;
+        Ok(self.dir.join(format!("{}.jsonl", world)))
+    }
+
+    /// Size of the journal of `world`, which changes with every entry.
+    pub fn size(&self, world: &str) -> Result<u64> {
+        match fs::metadata(self.file(world)?) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn append(&self, world: &str, entry: &Entry) -> Result<u64> {
+        let file = self.file(world)?;
+        fs::create_dir_all(&self.dir)?;
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut journal = fs::File::options().create(true).append(true).open(&file)?;
+        journal.write_all(&line)?;
+        Ok(journal.metadata()?.len())
+    }
+
+    /// Starts the next change set of `world` and returns its number.
+    pub fn begin(&self, world: &str, snapshot: Option<String>, undoes: Option<u64>, now: SystemTime) -> Result<u64> {
+        let set = self.change_sets(world)?.last().map_or(1, |last| last.seq + 1);
+        self.append(world, &Entry::Begin { set, snapshot, started_at: now, undoes })?;
+        Ok(set)
+    }
+
+    /// Adds a change to change set `set`, keeping `content` if the file has
+    /// one afterwards. Returns the new `size` of the journal.
+    pub fn record(&self, world: &str, set: u64, change: Change, content: Option<&[u8]>) -> Result<u64> {
+        if let Some(content) = content {
+            self.contents.put(content)?;
+        }
+        self.append(world, &Entry::Change { set, change })
+    }
+
+    /// The change sets of `world`, oldest first. A line cut short by a crash
+    /// ends the journal.
+    pub fn change_sets(&self, world: &str) -> Result<Vec<ChangeSet>> {
+        let data = match fs::read(self.file(world)?) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut sets: Vec<ChangeSet> = Vec::new();
+        for line in data.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+            let Ok(entry) = serde_json::from_slice::<Entry>(line) else {
+                break;
+            };
+            match entry {
+                Entry::Begin { set, snapshot, started_at, undoes } => {
+                    sets.push(ChangeSet { world: world.to_string(), seq: set, snapshot, started_at, undoes, changes: Vec::new() });
+                }
+                Entry::Change { set, change } => match sets.iter_mut().rev().find(|s| s.seq == set) {
+                    Some(changes) => changes.record(change),
+                    None => bail!("Journal of {} has a change for unknown change set {}", world, set),
+                },
+            }
+        }
+        Ok(sets)
+    }
+
+    pub fn last(&self, world: &str) -> Result<Option<ChangeSet>> {
+        Ok(self.change_sets(world)?.pop())
+    }
+
+    /// The content `path`, relative to the world folder, was last changed
+    /// to by a change set of `world`, if the journal still has it.
+    pub fn last_content(&self, world: &str, path: &Path) -> Result<Option<Vec<u8>>> {
+        let sets = self.change_sets(world)?;
+        let last = sets.iter().rev().flat_map(|set| &set.changes).find(|change| change.path == path);
+        match last.and_then(|change| change.after.as_ref()) {
+            Some(hash) => Ok(self.contents.get(hash).ok()),
+            None => Ok(None),
+        }
+    }
+
+    /// Puts the files of the last change set of `world` back the way they
+    /// were before it, from its snapshot. The files are written like any
+    /// local edit, so a running daemon syncs the undo to the other devices.
+    /// The current state is snapshotted first, and the undo is journaled as
+    /// a change set of its own, which cannot be undone again.
+    pub fn undo(&self, worlds_root: &Path, snapshots: &Snapshots, world: &str, now: SystemTime) -> Result<ChangeSet> {
+        let Some(changes) = self.last(world)?.filter(|last| last.undoes.is_none()) else {
+            bail!("Nothing to undo in {}", world);
+        };
+        let paths: Vec<PathBuf> = changes.changes.iter().map(|c| c.path.clone()).collect();
+        if let Some(path) = paths.iter().find(|p| !p.components().all(|c| matches!(c, Component::Normal(_)))) {
+            bail!("Refusing to undo {} outside the world", path.display());
+        }
+        let before = match &changes.snapshot {
+            Some(name) => {
+                let Some(snapshot) = snapshots.list(world)?.into_iter().find(|s| &s.name == name) else {
+                    bail!("Snapshot {} of {} is gone, the last change can no longer be undone", name, world);
+                };
+                snapshots.revert(worlds_root, &snapshot, &paths, now)?
+            }
+            // The world arrived with this change set, so undoing it removes the files it brought
+            None if changes.changes.iter().all(|c| c.before.is_none()) => {
+                let before = worlds_root.join(world).is_dir().then(|| snapshots.take(worlds_root, world, "undo", now)).transpose()?;
+                for path in &paths {
+                    let full_path = worlds_root.join(world).join(path);
+                    if full_path.is_file() {
+                        fs::remove_file(full_path)?;
+                    }
+                }
+                before
+            }
+            None => bail!("The last change to {} has no snapshot to undo it from", world),
+        };
+
+        let set = self.begin(world, before.map(|s| s.name), Some(changes.seq), now)?;
+        for change in &changes.changes {
+            let full_path = worlds_root.join(world).join(&change.path);
+            let content = change.before.as_ref().map(|_| fs::read(&full_path)).transpose()?;
+            let reverted = Change { path: change.path.clone(), before: change.after.clone(), after: change.before.clone() };
+            self.record(world, set, reverted, content.as_deref())?;
+        }
+        Ok(changes)
+    }
+
+    /// Rebuilds `world` as it was right after change set `until` in `target`,
+    /// which must not exist yet: starts from the newest snapshot taken at or
+    /// before it and applies the change sets from there. Changes made locally
+    /// after that snapshot are not in the journal and not replayed. Returns
+    /// the change sets applied.
+    pub fn replay(&self, snapshots: &Snapshots, world: &str, until: u64, target: &Path) -> Result<Vec<ChangeSet>> {
+        if target.exists() {
+            bail!("{} already exists, replay needs a fresh folder", target.display());
+        }
+        let sets: Vec<ChangeSet> = self.change_sets(world)?.into_iter().filter(|s| s.seq <= until).collect();
+        if sets.last().is_none_or(|last| last.seq != until) {
+            bail!("No change set {} of {}", until, world);
+        }
+        let kept = snapshots.list(world)?;
+        let start = sets.iter().rposition(|set| match &set.snapshot {
+            Some(name) => kept.iter().any(|s| &s.name == name),
+            // The world did not exist before it
+            None => set.changes.iter().all(|c| c.before.is_none()),
+        }).ok_or_else(|| anyhow!("No snapshot of {} left to replay change set {} from", world, until))?;
+
+        let staging = target.with_file_name(format!(".{}", target.file_name().map_or_else(|| "replay".into(), |n| n.to_string_lossy())));
+        let from = sets[start].snapshot.as_ref().and_then(|name| kept.iter().find(|s| &s.name == name));
+        let replayed = self.apply(world, &sets[start..], from.map(|s| s.path.as_path()), &staging).and_then(|_| Ok(fs::rename(&staging, target)?));
+        if let Err(e) = replayed {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        Ok(sets[start..].to_vec())
+    }
+
+    /// Copies `from`, or starts an empty folder, at `dir` and applies `sets`.
+    fn apply(&self, world: &str, sets: &[ChangeSet], from: Option<&Path>, dir: &Path) -> Result<()> {
+        match from {
+            Some(from) => snapshots::copy_world(from, dir)?,
+            None => fs::create_dir_all(dir)?,
+        }
+        for set in sets {
+            for change in &set.changes {
+                if !change.path.components().all(|c| matches!(c, Component::Normal(_))) {
+                    bail!("Refusing to replay {} outside the world", change.path.display());
+                }
+                let full_path = dir.join(&change.path);
+                match &change.after {
+                    Some(hash) => {
+                        let content = self.contents.get(hash).map_err(|e| anyhow!("Change set {} of {}: {}", set.seq, world, e))?;
+                        fs::create_dir_all(full_path.parent().unwrap_or(dir))?;
+                        fs::write(&full_path, content)?;
+                    }
+                    None if full_path.is_file() => fs::remove_file(&full_path)?,
+                    None => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+use anyhow::{bail, Result};
+use crate::nbt::{self, Tag};
+
+/// Name of the file that holds the settings of a world.
+pub const LEVEL_DAT: &str = "level.dat";
+
+/// `level.dat` starts with the storage version and the length of the NBT
+/// that follows, both little-endian.
+const HEADER_LEN: usize = 8;
+
+/// The settings of a world as `level.dat` stores them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelDat {
+    pub storage_version: i32,
+    pub name: String,
+    pub root: Tag,
+}
+
+impl LevelDat {
+    pub fn read(data: &[u8]) -> Result<Self> {
+        let Some((header, nbt)) = data.split_first_chunk::<HEADER_LEN>() else {
+            bail!("level.dat is too short");
+        };
+        let storage_version = i32::from_le_bytes(header[..4].try_into()?);
+        let len = u32::from_le_bytes(header[4..].try_into()?) as usize;
+        if len != nbt.len() {
+            bail!("level.dat says it has {} bytes of NBT but has {}", len, nbt.len());
+        }
+        let (name, root) = nbt::read(nbt)?;
+        if !matches!(root, Tag::Compound(_)) {
+            bail!("level.dat does not hold a compound");
+        }
+        Ok(Self { storage_version, name, root })
+    }
+
+    pub fn write(&self) -> Vec<u8> {
+        let nbt = nbt::write(&self.name, &self.root);
+        let mut out = Vec::with_capacity(HEADER_LEN + nbt.len());
+        out.extend_from_slice(&self.storage_version.to_le_bytes());
+        out.extend_from_slice(&(nbt.len() as u32).to_le_bytes());
+        out.extend_from_slice(&nbt);
+        out
+    }
+}
+
+/// Two versions of `level.dat` merged tag by tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelMerge {
+    pub content: Vec<u8>,
+    /// Tags both versions changed to different values, dotted from the
+    /// root, which went to the version that wins the conflict.
+    pub conflicts: Vec<String>,
+}
+
+/// Merges the settings `ours` and `theirs` changed since `base`, the
+/// version both started from: a tag changed on one side only keeps that
+/// change, so a game rule changed on one device and the spawn moved on the
+/// other both survive. Tags changed on both sides to different values go
+/// to `theirs` if `theirs_wins`. Compounds are merged field by field, in
+/// the winner's order with the other's new fields after, so both devices
+/// come to the same file.
+pub fn merge(base: &[u8], ours: &[u8], theirs: &[u8], theirs_wins: bool) -> Result<LevelMerge> {
+    let (base, ours, theirs) = (LevelDat::read(base)?, LevelDat::read(ours)?, LevelDat::read(theirs)?);
+    let mut conflicts = Vec::new();
+    let (winner, loser) = if theirs_wins { (&theirs, &ours) } else { (&ours, &theirs) };
+    let root = merge_tag(Some(&base.root), &ours.root, &theirs.root, theirs_wins, "", &mut conflicts);
+    let merged = LevelDat { storage_version: winner.storage_version.max(loser.storage_version), name: winner.name.clone(), root };
+    Ok(LevelMerge { content: merged.write(), conflicts })
+}
+
+fn merge_tag(base: Option<&Tag>, ours: &Tag, theirs: &Tag, theirs_wins: bool, path: &str, conflicts: &mut Vec<String>) -> Tag {
+    if ours == theirs || base == Some(theirs) {
+        return ours.clone();
+    }
+    if base == Some(ours) {
+        return theirs.clone();
+    }
+    let (Tag::Compound(ours_fields), Tag::Compound(theirs_fields)) = (ours, theirs) else {
+        conflicts.push(path.to_string());
+        return if theirs_wins { theirs.clone() } else { ours.clone() };
+    };
+    let base = base.filter(|base| matches!(base, Tag::Compound(_)));
+    let (first, second) = if theirs_wins { (theirs_fields, ours_fields) } else { (ours_fields, theirs_fields) };
+    let names = first.iter().chain(second.iter().filter(|(name, _)| !first.iter().any(|(n, _)| n == name))).map(|(name, _)| name);
+    let mut fields = Vec::new();
+    for name in names {
+        let (base, ours, theirs) = (base.and_then(|base| base.get(name)), ours.get(name), theirs.get(name));
+        let path = if path.is_empty() { name.clone() } else { format!("{}.{}", path, name) };
+        let merged = match (ours, theirs) {
+            (Some(ours), Some(theirs)) => Some(merge_tag(base, ours, theirs, theirs_wins, &path, conflicts)),
+            // Added on one side
+            (Some(tag), None) | (None, Some(tag)) if base.is_none() => Some(tag.clone()),
+            // Removed on one side and left alone on the other
+            (Some(kept), None) | (None, Some(kept)) if base == Some(kept) => None,
+            // Removed on one side and changed on the other
+            (ours, theirs) => {
+                conflicts.push(path);
+                if theirs_wins { theirs.cloned() } else { ours.cloned() }
+            }
+        };
+        fields.extend(merged.map(|tag| (name.clone(), tag)));
+    }
+    Tag::Compound(fields)
+}
@@ -0,0 +1,345 @@
+use anyhow::{anyhow, bail, Result};
+use flate2::read::{DeflateDecoder, ZlibDecoder};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Folder of a world's `db` that LevelDB moves files it could not read to
+/// while repairing the database, as after Minecraft was not shut down
+/// cleanly.
+pub const LOST_DIR: &str = "lost";
+
+/// Whether LevelDB moved files of the world in `world_dir` to `LOST_DIR`,
+/// the sign of an unclean shutdown. They are not synced, see
+/// `exclusions::DEFAULT_IGNORE`.
+pub fn has_lost_files(world_dir: &Path) -> bool {
+    world_dir.join("db").join(LOST_DIR).is_dir()
+}
+
+/// Whether `path` is the `LOST_DIR` of a world.
+pub fn is_lost_dir(path: &Path) -> bool {
+    path.ends_with(Path::new("db").join(LOST_DIR))
+}
+
+/// Last bytes of every table file.
+const TABLE_MAGIC: u64 = 0xdb47_7524_8b80_fb57;
+
+const FOOTER_SIZE: usize = 48;
+
+/// Log files are written in blocks of this size, each record with a header.
+const LOG_BLOCK_SIZE: usize = 32 * 1024;
+const LOG_HEADER_SIZE: usize = 7;
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0x82F6_3B78 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32C of `data`, masked the way LevelDB stores its checksums.
+pub fn checksum(data: &[u8]) -> u32 {
+    let crc = !data.iter().fold(!0u32, |crc, &byte| CRC32C_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8));
+    crc.rotate_right(15).wrapping_add(0xa282_ead8)
+}
+
+/// A file of the database that could not be read to the end. What was read
+/// before the damage still counts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Damaged {
+    pub file: PathBuf,
+    pub error: String,
+}
+
+/// The newest write of a live key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Sequence number of the write, higher for later writes.
+    pub seq: u64,
+    pub size: usize,
+    /// The value itself, kept only for the keys asked for.
+    pub value: Option<Vec<u8>>,
+}
+
+/// The live keys of a LevelDB database, from one read-only pass over its
+/// tables and logs. Tables left over from a compaction are read too, so a
+/// deleted key can rarely come back.
+#[derive(Debug, Default)]
+pub struct Contents {
+    pub keys: BTreeMap<Vec<u8>, Entry>,
+    pub damaged: Vec<Damaged>,
+    /// Highest sequence number used, deletions included.
+    pub last_seq: u64,
+}
+
+/// The newest write of each key, `None` for a deletion, and which values to
+/// keep while reading.
+struct Writes<'a> {
+    newest: BTreeMap<Vec<u8>, (u64, Option<Entry>)>,
+    keep: &'a dyn Fn(&[u8]) -> bool,
+    last_seq: u64,
+}
+
+impl Writes<'_> {
+    fn write(&mut self, key: &[u8], seq: u64, value: Option<&[u8]>) {
+        self.last_seq = self.last_seq.max(seq);
+        if self.newest.get(key).is_some_and(|(newest, _)| *newest >= seq) {
+            return;
+        }
+        let entry = value.map(|value| Entry { seq, size: value.len(), value: (self.keep)(key).then(|| value.to_vec()) });
+        self.newest.insert(key.to_vec(), (seq, entry));
+    }
+}
+
+/// Reads the database in `dir` without taking its lock or writing anything,
+/// so it works on a world Minecraft has open.
+pub fn read(dir: &Path) -> Result<Contents> {
+    read_keeping(dir, &|_| false)
+}
+
+/// Like `read`, also keeping the values of the keys `keep` accepts.
+pub fn read_keeping(dir: &Path, keep: &dyn Fn(&[u8]) -> bool) -> Result<Contents> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect::<std::io::Result<_>>()?;
+    files.sort();
+    let mut writes = Writes { newest: BTreeMap::new(), keep, last_seq: 0 };
+    let mut damaged = Vec::new();
+    for file in files {
+        let read = match file.extension().and_then(|e| e.to_str()) {
+            Some("ldb" | "sst") => read_table(&file, &mut writes),
+            Some("log") => read_log(&file, &mut writes),
+            _ => continue,
+        };
+        if let Err(e) = read {
+            damaged.push(Damaged { file, error: e.to_string() });
+        }
+    }
+    let keys = writes.newest.into_iter().filter_map(|(key, (_, entry))| Some((key, entry?))).collect();
+    Ok(Contents { keys, damaged, last_seq: writes.last_seq })
+}
+
+fn varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or_else(|| anyhow!("Varint runs past the end"))?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Varint too long")
+}
+
+/// `len` bytes at `pos`, advancing past them.
+fn take<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let bytes = pos.checked_add(len).and_then(|end| data.get(*pos..end)).ok_or_else(|| anyhow!("Entry runs past the end"))?;
+    *pos += len;
+    Ok(bytes)
+}
+
+fn block_handle(data: &[u8], pos: &mut usize) -> Result<(usize, usize)> {
+    Ok((varint(data, pos)? as usize, varint(data, pos)? as usize))
+}
+
+/// The contents of the block at `handle`, checked and decompressed.
+/// Minecraft compresses with zlib (2) or raw deflate (4).
+fn read_block(file: &[u8], (offset, size): (usize, usize)) -> Result<Vec<u8>> {
+    let Some(block) = offset.checked_add(size + 5).and_then(|end| file.get(offset..end)) else {
+        bail!("Block at {} runs past the end", offset);
+    };
+    let (contents, trailer) = block.split_at(size);
+    if checksum(&block[..size + 1]) != u32::from_le_bytes([trailer[1], trailer[2], trailer[3], trailer[4]]) {
+        bail!("Checksum mismatch in block at {}", offset);
+    }
+    let mut decompressed = Vec::new();
+    match trailer[0] {
+        0 => return Ok(contents.to_vec()),
+        2 => ZlibDecoder::new(contents).read_to_end(&mut decompressed)?,
+        4 => DeflateDecoder::new(contents).read_to_end(&mut decompressed)?,
+        other => bail!("Block at {} uses unsupported compression {}", offset, other),
+    };
+    Ok(decompressed)
+}
+
+/// Key and value of every entry of a block, undoing the key prefix sharing.
+fn block_entries(block: &[u8]) -> Result<Vec<(Vec<u8>, &[u8])>> {
+    let Some(count) = block.len().checked_sub(4).map(|at| u32::from_le_bytes([block[at], block[at + 1], block[at + 2], block[at + 3]]) as usize) else {
+        bail!("Block too short");
+    };
+    let end = count.checked_mul(4).and_then(|restarts| block.len().checked_sub(4 + restarts)).ok_or_else(|| anyhow!("Block has too many restart points"))?;
+    let mut entries = Vec::new();
+    let mut key: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    while pos < end {
+        let shared = varint(block, &mut pos)? as usize;
+        let unshared = varint(block, &mut pos)? as usize;
+        let value_len = varint(block, &mut pos)? as usize;
+        if shared > key.len() {
+            bail!("Entry shares more of the key than there is");
+        }
+        key.truncate(shared);
+        key.extend_from_slice(take(block, &mut pos, unshared)?);
+        entries.push((key.clone(), take(block, &mut pos, value_len)?));
+    }
+    Ok(entries)
+}
+
+fn read_table(path: &Path, writes: &mut Writes<'_>) -> Result<()> {
+    let file = fs::read(path)?;
+    let Some(footer) = file.len().checked_sub(FOOTER_SIZE).map(|at| &file[at..]) else {
+        bail!("Too short for a table");
+    };
+    if u64::from_le_bytes(footer[40..].try_into()?) != TABLE_MAGIC {
+        bail!("Not a table, the footer is missing");
+    }
+    let mut pos = 0;
+    block_handle(footer, &mut pos)?;
+    let index = read_block(&file, block_handle(footer, &mut pos)?)?;
+    for (_, handle) in block_entries(&index)? {
+        let block = read_block(&file, block_handle(handle, &mut 0)?)?;
+        for (key, value) in block_entries(&block)? {
+            // Keys end in the sequence number and whether they were written or deleted
+            let Some(split) = key.len().checked_sub(8) else {
+                bail!("Key too short");
+            };
+            let tag = u64::from_le_bytes(key[split..].try_into()?);
+            writes.write(&key[..split], tag >> 8, (tag & 0xff == 1).then_some(value));
+        }
+    }
+    Ok(())
+}
+
+/// Applies every complete record of a log. A record cut short at the end
+/// is one Minecraft was still writing, and is left out.
+fn read_log(path: &Path, writes: &mut Writes<'_>) -> Result<()> {
+    let file = fs::read(path)?;
+    let mut record = Vec::new();
+    let mut pos = 0;
+    while pos + LOG_HEADER_SIZE <= file.len() {
+        let block_left = LOG_BLOCK_SIZE - pos % LOG_BLOCK_SIZE;
+        if block_left < LOG_HEADER_SIZE {
+            pos += block_left;
+            continue;
+        }
+        let header = &file[pos..pos + LOG_HEADER_SIZE];
+        let len = u16::from_le_bytes([header[4], header[5]]) as usize;
+        let kind = header[6];
+        let Some(payload) = file.get(pos + LOG_HEADER_SIZE..pos + LOG_HEADER_SIZE + len) else {
+            return Ok(());
+        };
+        if kind == 0 && len == 0 {
+            // Preallocated space that was never written
+            pos += block_left;
+            continue;
+        }
+        if checksum(&file[pos + 6..pos + LOG_HEADER_SIZE + len]) != u32::from_le_bytes([header[0], header[1], header[2], header[3]]) {
+            bail!("Checksum mismatch in record at {}", pos);
+        }
+        pos += LOG_HEADER_SIZE + len;
+        match kind {
+            // Whole, first, middle and last fragment
+            1 => apply_batch(payload, writes)?,
+            2 => record = payload.to_vec(),
+            3 => record.extend_from_slice(payload),
+            4 => {
+                record.extend_from_slice(payload);
+                apply_batch(&record, writes)?;
+                record.clear();
+            }
+            other => bail!("Unknown record type {} at {}", other, pos),
+        }
+    }
+    Ok(())
+}
+
+fn apply_batch(batch: &[u8], writes: &mut Writes<'_>) -> Result<()> {
+    let mut pos = 0;
+    let seq = u64::from_le_bytes(take(batch, &mut pos, 8)?.try_into()?);
+    let count = u32::from_le_bytes(take(batch, &mut pos, 4)?.try_into()?) as u64;
+    for n in 0..count {
+        let kind = take(batch, &mut pos, 1)?[0];
+        let key_len = varint(batch, &mut pos)? as usize;
+        let key = take(batch, &mut pos, key_len)?;
+        let value = match kind {
+            1 => {
+                let len = varint(batch, &mut pos)? as usize;
+                Some(take(batch, &mut pos, len)?)
+            }
+            0 => None,
+            other => bail!("Unknown write type {} in batch {}", other, seq),
+        };
+        writes.write(key, seq + n, value);
+    }
+    Ok(())
+}
+
+/// Writes `writes` as one batch numbered from `seq` to a new log file at
+/// `path`, `None` values deleting their key. LevelDB replays logs newer
+/// than its manifest when it opens, so the batch applies the next time the
+/// database is opened, as long as `seq` follows its last sequence number.
+pub fn write_log(path: &Path, seq: u64, writes: &[(Vec<u8>, Option<Vec<u8>>)]) -> Result<()> {
+    let mut batch = seq.to_le_bytes().to_vec();
+    batch.extend_from_slice(&u32::try_from(writes.len())?.to_le_bytes());
+    for (key, value) in writes {
+        batch.push(value.is_some() as u8);
+        put_varint(&mut batch, key.len() as u64);
+        batch.extend_from_slice(key);
+        if let Some(value) = value {
+            put_varint(&mut batch, value.len() as u64);
+            batch.extend_from_slice(value);
+        }
+    }
+
+    let mut log = Vec::new();
+    let mut rest = batch.as_slice();
+    let mut first = true;
+    loop {
+        let block_left = LOG_BLOCK_SIZE - log.len() % LOG_BLOCK_SIZE;
+        if block_left < LOG_HEADER_SIZE {
+            log.resize(log.len() + block_left, 0);
+            continue;
+        }
+        let len = rest.len().min(block_left - LOG_HEADER_SIZE);
+        let last = len == rest.len();
+        // Whole, first, middle and last fragment
+        let kind = match (first, last) {
+            (true, true) => 1,
+            (true, false) => 2,
+            (false, false) => 3,
+            (false, true) => 4,
+        };
+        let mut typed = vec![kind];
+        typed.extend_from_slice(&rest[..len]);
+        log.extend_from_slice(&checksum(&typed).to_le_bytes());
+        log.extend_from_slice(&(len as u16).to_le_bytes());
+        log.extend_from_slice(&typed);
+        rest = &rest[len..];
+        first = false;
+        if last {
+            break;
+        }
+    }
+    // Never replaces a log LevelDB wrote
+    fs::OpenOptions::new().write(true).create_new(true).open(path)?.write_all(&log)?;
+    Ok(())
+}
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
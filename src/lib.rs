@@ -0,0 +1,70 @@
+pub mod aging;
+pub mod android;
+pub mod app_dirs;
+pub mod archive;
+pub mod auth;
+pub mod busy;
+pub mod chaos;
+pub mod chunk_store;
+pub mod churn;
+pub mod cli;
+pub mod compaction;
+pub mod compression;
+pub mod config;
+pub mod conflicts;
+pub mod connections;
+pub mod correlation;
+pub mod cpu_budget;
+pub mod deletions;
+pub mod delta;
+pub mod devices;
+pub mod discovery;
+pub mod exclusions;
+pub mod file_manager;
+pub mod flow_control;
+pub mod gaming;
+pub mod groups;
+pub mod guest;
+pub mod health;
+pub mod home_assistant;
+pub mod http;
+pub mod index;
+pub mod inspect;
+pub mod interference;
+pub mod journal;
+pub mod level_dat;
+pub mod leveldb;
+pub mod links;
+pub mod manifest;
+pub mod mcworld;
+pub mod merge;
+pub mod metrics;
+pub mod migration;
+pub mod mirror;
+pub mod mqtt;
+pub mod mux;
+pub mod network_change;
+pub mod nbt;
+pub mod network;
+pub mod pairing;
+pub mod platform;
+pub mod players;
+pub mod port_mapping;
+pub mod relay;
+pub mod report;
+pub mod reconnect;
+pub mod rendezvous;
+pub mod shadow_copy;
+pub mod shares;
+pub mod shutdown;
+pub mod smtp;
+pub mod supervisor;
+pub mod snapshots;
+pub mod telemetry;
+pub mod tls;
+pub mod transport;
+pub mod transfer_queue;
+pub mod usage;
+pub mod versions;
+pub mod watcher;
+pub mod wire;
@@ -0,0 +1,85 @@
+use anyhow::{bail, Result};
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::shares;
+
+/// A one-time link to download a world as .mcworld, or to upload one that
+/// is imported as the world.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Link {
+    pub token: String,
+    pub world: String,
+    /// Unix time after which the link no longer works.
+    pub expires_at: u64,
+}
+
+/// Unused links of one kind, kept in a file so the command that creates
+/// them and the running daemon that serves them share them.
+#[derive(Debug, Clone)]
+pub struct Links {
+    file: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Links {
+    pub fn new(file: PathBuf) -> Self {
+        Self { file, lock: Arc::new(Mutex::new(())) }
+    }
+
+    fn read(&self) -> Result<Vec<Link>> {
+        match fs::read(&self.file) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, links: &[Link]) -> Result<()> {
+        let tmp = self.file.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(links)?)?;
+        fs::rename(&tmp, &self.file)?;
+        Ok(())
+    }
+
+    /// Adds a link for `world` that works once within `valid_for`.
+    pub fn create(&self, world: &str, valid_for: Duration, now: SystemTime) -> Result<Link> {
+        let mut components = Path::new(world).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+            bail!("{} is not a world folder name", world);
+        }
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let link = Link { token: shares::random_key()?, world: world.to_string(), expires_at: unix_secs(now + valid_for) };
+        let mut links = self.read()?;
+        links.retain(|l| l.expires_at > unix_secs(now));
+        links.push(link.clone());
+        self.write(&links)?;
+        Ok(link)
+    }
+
+    /// The link with `token`, without using it up. None if it is unknown,
+    /// used or expired.
+    pub fn find(&self, token: &str, now: SystemTime) -> Result<Option<Link>> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(self.read()?.into_iter().find(|l| l.token == token && l.expires_at > unix_secs(now)))
+    }
+
+    /// Uses up the link with `token`. None if it is unknown, used or expired.
+    pub fn redeem(&self, token: &str, now: SystemTime) -> Result<Option<Link>> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut links = self.read()?;
+        let before = links.len();
+        let redeemed = links.iter().position(|l| l.token == token).map(|i| links.remove(i));
+        links.retain(|l| l.expires_at > unix_secs(now));
+        if links.len() != before {
+            self.write(&links)?;
+        }
+        Ok(redeemed.filter(|l| l.expires_at > unix_secs(now)))
+    }
+}
@@ -1,51 +1,82 @@
-mod network;
-mod config;
-mod file_manager;
-
 use anyhow::Result;
-use notify::{Watcher, RecursiveMode, Event, RecommendedWatcher, Config as NotifyConfig};
+use clap::Parser;
+use notify::{Watcher, RecursiveMode, RecommendedWatcher, Config as NotifyConfig};
 use std::path::Path;
-use std::sync::mpsc::channel;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use log::{info, error, warn, debug};
 use std::fs;
 use std::env;
-use network::{SyncServer, SyncClient};
+use std::io::{IsTerminal, Write};
+use mcbd_world_sync::network::{FileRejected, FileVersion, SyncServer, SyncClient};
+use mcbd_world_sync::network_change::{self, NetworkChanges, SyncTicker};
 use std::path::PathBuf;
-use config::Config as AppConfig;
-use file_manager::{FileManager, FileInfo};
+use mcbd_world_sync::conflicts::{self, ConflictPolicy};
+use mcbd_world_sync::connections::Connections;
+use mcbd_world_sync::config::Config as AppConfig;
+use mcbd_world_sync::file_manager::{self, FileIndex, FileManager, FileInfo, Tombstone};
+use mcbd_world_sync::cpu_budget::CpuBudget;
+use mcbd_world_sync::flow_control::WriteWindow;
+use mcbd_world_sync::busy::{self, BusyWorlds};
+use mcbd_world_sync::shadow_copy::{ShadowCopies, ShadowCopy};
+use mcbd_world_sync::gaming::{self, GamingLimits, GamingMode};
+use mcbd_world_sync::rendezvous::{self, Lookup};
+use mcbd_world_sync::config::Device;
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::transfer_queue::{self, PendingTransfer, Priority, TransferQueue};
+use mcbd_world_sync::watcher::{self, WatchEvent};
+use mcbd_world_sync::interference;
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::archive::{self, ArchiveProposals, ArchivedWorlds};
+use mcbd_world_sync::app_dirs::AppDirs;
+use mcbd_world_sync::migration;
+use mcbd_world_sync::mirror::Mirror;
+use mcbd_world_sync::index::{self, Discrepancy, IndexCheck, MismatchAction};
+use mcbd_world_sync::cli::{Cli, Command, DeviceCommand, DiscoverCommand, IdentityCommand, JournalCommand, ShareCommand, SnapshotCommand, TlsCommand};
+use mcbd_world_sync::mcworld;
+use mcbd_world_sync::inspect;
+use mcbd_world_sync::merge;
+use mcbd_world_sync::platform;
+use mcbd_world_sync::players;
+use mcbd_world_sync::relay::Relay;
+use mcbd_world_sync::chaos::Chaos;
+use mcbd_world_sync::churn::Churn;
+use mcbd_world_sync::leveldb;
+use mcbd_world_sync::versions::Versions;
+use mcbd_world_sync::correlation::{self, CorrelationId, LogFormat};
+use mcbd_world_sync::shutdown;
+use mcbd_world_sync::supervisor;
+use mcbd_world_sync::reconnect::{self, Reachability};
+use mcbd_world_sync::manifest::{self, ManifestCache};
+use mcbd_world_sync::telemetry;
+use mcbd_world_sync::links::Links;
+use mcbd_world_sync::http::{self, SyncNow, WorldLinks, WorldSnapshots, HttpState};
+use mcbd_world_sync::journal::Journal;
+use mcbd_world_sync::snapshots::{check_world, FileDiff, Snapshots, Views};
+use mcbd_world_sync::tls;
+use mcbd_world_sync::pairing::{self, PairedDevice};
+use mcbd_world_sync::port_mapping::Mappings;
+use mcbd_world_sync::auth::DeviceKeys;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::aging::{AgingChange, DeviceAging};
+use mcbd_world_sync::android::{Adb, AndroidDevice};
+use mcbd_world_sync::compaction::{self, Compaction};
+use mcbd_world_sync::devices;
+use mcbd_world_sync::discovery::{self, Discovery};
+use mcbd_world_sync::groups::Groups;
+use mcbd_world_sync::guest;
+use mcbd_world_sync::usage::{self, Usage, UsageLog};
+use mcbd_world_sync::deletions::{self, PendingDeletions};
+use mcbd_world_sync::report::{self, Report, ReportSources, ReportTimes};
+use mcbd_world_sync::smtp;
+use mcbd_world_sync::home_assistant::{Publisher, StatusSources};
+use mcbd_world_sync::mqtt::MqttClient;
+use mcbd_world_sync::chunk_store::ChunkStore;
+use mcbd_world_sync::shares::{self, ShareToken};
+use mcbd_world_sync::config::{DeletionConfig, IndexConfig, PerformanceConfig, MqttConfig, ReportConfig, ReportSchedule, WatchMode};
 use std::sync::Arc;
+use std::collections::{BTreeSet, HashSet, VecDeque};
 use tokio::sync::Mutex;
-use std::time::SystemTime;
-
-fn get_username() -> String {
-    // Try different environment variables and methods to get the username
-    if let Ok(username) = env::var("USERNAME") {
-        return username;
-    }
-    if let Ok(username) = env::var("USER") {
-        return username;
-    }
-    if let Ok(username) = env::var("USERPROFILE") {
-        if let Some(name) = Path::new(&username).file_name() {
-            if let Some(name_str) = name.to_str() {
-                return name_str.to_string();
-            }
-        }
-    }
-    // Fallback to a default if nothing else works
-    "unknown".to_string()
-}
-
-fn get_minecraft_paths() -> Vec<String> {
-    let username = get_username();
-    info!("Detected username: {}", username);
-    
-    vec![
-        format!("C:\\Users\\{}\\AppData\\Local\\Packages\\Microsoft.MinecraftUWP_8wekyb3d8bbwe\\LocalState\\games\\com.mojang\\minecraftWorlds", username),
-        format!("C:\\Users\\{}\\AppData\\Local\\Packages\\Microsoft.MinecraftUWP_8wekyb3d8bbwe\\LocalState\\games\\com.mojang\\development_behavior_packs", username)
-    ]
-}
+use tokio_util::sync::CancellationToken;
 
 fn list_worlds(path: &Path) {
     info!("Scanning for Minecraft worlds in: {}", path.display());
@@ -116,40 +147,1690 @@ fn list_worlds(path: &Path) {
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize logger with debug level
-    std::env::set_var("RUST_LOG", "debug");
-    env_logger::init();
+/// Asks a yes/no question on the terminal. Without one, nobody can confirm.
+fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        anyhow::bail!("Run interactively to confirm, or pass --yes");
+    }
+    print!("{} [y/N] ", question);
+    std::io::stdout().flush()?;
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Runs a maintenance command. Configuration changes are saved to
+/// `config.json`, except in headless mode where it comes from the environment.
+async fn run_command(command: Command, config: &mut AppConfig, config_path: &Path, headless: bool) -> Result<()> {
+    let app_dirs = AppDirs::new(&config.paths);
+    app_dirs.ensure()?;
+    match command {
+        Command::Run => unreachable!("the daemon runs without a maintenance command"),
+        Command::SyncNow { world, full: _, device, ignore_limits } => {
+            if let Some(world) = &world {
+                check_world(world)?;
+            }
+            if let Some(device) = &device {
+                if !config.sync.all_devices().iter().any(|d| d.name == *device) && !config.mirrors.iter().any(|m| m.name == *device) {
+                    anyhow::bail!("No device or mirror named {}", device);
+                }
+            }
+            let mut query = Vec::new();
+            if let Some(device) = &device {
+                query.push(format!("device={}", device));
+            }
+            if ignore_limits {
+                query.push("ignore_limits=true".to_string());
+            }
+            let mut path = world.as_ref().map_or("/sync".to_string(), |world| format!("/sync/{}", world));
+            if !query.is_empty() {
+                path = format!("{}?{}", path, query.join("&"));
+            }
+            let worlds = world.unwrap_or_else(|| "every world".to_string());
+            let (status, body) = http::request(&config.http.bind, "POST", &path).await?;
+            if status != 202 {
+                anyhow::bail!("The daemon did not sync {}: {}", worlds, body.trim());
+            }
+            match device {
+                Some(device) => info!("Sending {} to {}", worlds, device),
+                None => info!("Sending {} to every device that syncs it", worlds),
+            }
+            return Ok(());
+        }
+        Command::Status => {
+            let (status, body) = http::request(&config.http.bind, "GET", "/status").await?;
+            if status != 200 {
+                anyhow::bail!("The daemon did not report its status: {}", body.trim());
+            }
+            let status: serde_json::Value = serde_json::from_str(&body)?;
+            println!("{}", serde_json::to_string_pretty(&status)?);
+            return Ok(());
+        }
+        Command::ListWorlds => {
+            let worlds_root = config.paths.worlds_dir();
+            let exclusions = Exclusions::new(&config.watch.exclude).with_ignore(&config.watch.ignore)?;
+            let archived = ArchivedWorlds::load(app_dirs.archived_file())?;
+            let mut worlds: Vec<PathBuf> = fs::read_dir(&worlds_root)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.is_dir() && !exclusions.is_excluded(&worlds_root, path))
+                .collect();
+            worlds.sort();
+            for world in &worlds {
+                let folder = world.file_name().unwrap_or_default().to_string_lossy();
+                let state = if archived.contains(&folder) { "\tarchived" } else { "" };
+                println!("{}\t{}{}", folder, mcworld::display_name(world), state);
+            }
+            return Ok(());
+        }
+        Command::Verify { world } => {
+            let Some(stored) = index::load(&app_dirs.index_file())? else {
+                anyhow::bail!("There is no index yet, the daemon builds it when it first starts");
+            };
+            let worlds_root = config.paths.worlds_dir();
+            let mut files = FileManager::new(worlds_root.clone())
+                .with_hash_workers(config.performance.hash_workers)
+                .with_exclusions(Exclusions::new(&config.watch.exclude).with_ignore(&config.watch.ignore)?);
+            let (prefix, scanned) = match &world {
+                Some(world) => {
+                    check_world(world)?;
+                    let dir = worlds_root.join(world);
+                    (PathBuf::from(world), if dir.is_dir() { files.scan_subtree(&dir)? } else { Vec::new() })
+                }
+                None => (PathBuf::new(), files.scan_directory()?),
+            };
+            let found = index::verify(&stored.files, &scanned, &prefix);
+            for discrepancy in &found {
+                match discrepancy {
+                    Discrepancy::Changed(path) => println!("changed   {}", path.display()),
+                    Discrepancy::Missing(path) => println!("missing   {}", path.display()),
+                    Discrepancy::Unindexed(path) => println!("unindexed {}", path.display()),
+                }
+            }
+            if found.is_empty() {
+                info!("The index matches all {} files", scanned.len());
+            } else {
+                info!("{} of {} files differ from the index, the daemon picks them up when it starts", found.len(), scanned.len());
+            }
+            return Ok(());
+        }
+        Command::Export { world, file } => {
+            check_world(&world)?;
+            let dir = config.paths.worlds_dir().join(&world);
+            if !dir.is_dir() {
+                anyhow::bail!("No world folder {}", world);
+            }
+            let mut out = std::io::BufWriter::new(fs::File::create(&file)?);
+            let size = mcworld::write(&dir, &mut out)?;
+            out.flush()?;
+            info!("Exported {} to {} ({} bytes)", world, file.display(), size);
+            return Ok(());
+        }
+        Command::Import { file, world } => {
+            let world = match world {
+                Some(world) => world,
+                None => file.file_stem().map(|stem| stem.to_string_lossy().to_string()).ok_or_else(|| anyhow::anyhow!("Name the world to import {} as", file.display()))?,
+            };
+            check_world(&world)?;
+            let imported = mcworld::import(&fs::read(&file)?, &config.paths.worlds_dir().join(&world))?;
+            info!("Imported {} files into {}", imported, world);
+            return Ok(());
+        }
+        Command::Device { command: DeviceCommand::Remove { name, final_sync } } => {
+            let removal = devices::remove(config, &app_dirs, &name, final_sync).await?;
+            info!("Removed device {} (sync cursor {})", name, if removal.cursor_removed { "dropped" } else { "not found" });
+            info!("Restart the running daemon so it stops syncing with {}", name);
+        }
+        Command::Device { command: DeviceCommand::Rename { old, new } } => {
+            devices::rename(config, &app_dirs, &old, &new).await?;
+            info!("Renamed device {} to {}", old, new);
+        }
+        Command::Identity { command: IdentityCommand::Export { file: path } } => {
+            let identity = devices::export_identity(config, &app_dirs, &path).await?;
+            info!("Exported identity {} with {} sync cursors to {}", identity.name, identity.cursors.len(), path.display());
+            return Ok(());
+        }
+        Command::Identity { command: IdentityCommand::Import { file: path } } => {
+            let identity = devices::import_identity(config, &app_dirs, &path).await?;
+            info!("This device is now {} with {} sync cursors", identity.name, identity.cursors.len());
+        }
+        Command::Share { command: ShareCommand::Create { world, friend, address, days, read_write: writable } } => {
+            // Without an address the friend can only pull as a guest
+            let friend = Device::from_target(&friend, address.as_deref().unwrap_or(""));
+            let name = friend.name.clone();
+            let token = shares::create(config, &world, friend, days, writable, SystemTime::now())?;
+            info!("Shared {} with {} for {} days ({})", world, name, days, if writable { "read-write" } else { "read-only" });
+            println!("{}", token);
+        }
+        Command::Share { command: ShareCommand::Join { token, host, address } } => {
+            let (token, host) = (ShareToken::parse(&token)?, Device::from_target(&host, &address));
+            let host_name = host.name.clone();
+            shares::join(config, &token, host)?;
+            info!("Joined share of {} from {}", token.world, host_name);
+        }
+        Command::Guest { token, address, keep_updated } => {
+            let (token, host) = (ShareToken::parse(&token)?, Device::from_target("host", &address));
+            // Guests never change the configuration
+            return run_guest(config, token, host, keep_updated).await;
+        }
+        Command::DownloadLink { world, hours } => {
+            if !config.paths.worlds_dir().join(&world).is_dir() {
+                anyhow::bail!("No world folder {}", world);
+            }
+            if !config.http.enabled || !config.http.downloads {
+                warn!("Download links are only served with http.enabled and http.downloads set");
+            }
+            let link = Links::new(app_dirs.downloads_file()).create(&world, Duration::from_secs(hours * 3600), SystemTime::now())?;
+            info!("Created a one-time download of {} valid for {} hours", world, hours);
+            println!("http://{}/download/{}", config.http.bind, link.token);
+            return Ok(());
+        }
+        Command::UploadLink { world, group, hours } => {
+            if config.paths.worlds_dir().join(&world).exists() {
+                anyhow::bail!("There is already a world folder {}", world);
+            }
+            if !config.http.enabled || !config.http.uploads {
+                warn!("Upload links are only served with http.enabled and http.uploads set");
+            }
+            let link = Links::new(app_dirs.uploads_file()).create(&world, Duration::from_secs(hours * 3600), SystemTime::now())?;
+            info!("Created a one-time upload of {} valid for {} hours", world, hours);
+            println!("http://{}/upload/{}", config.http.bind, link.token);
+            // Groups that list their worlds need the new one to sync it
+            let Some(group) = group else {
+                return Ok(());
+            };
+            let group = config.sync.groups.iter_mut().find(|g| g.name == group).ok_or_else(|| anyhow::anyhow!("No group {}", group))?;
+            if group.worlds.is_empty() || group.worlds.contains(&world) {
+                return Ok(());
+            }
+            group.worlds.push(world.clone());
+            info!("Added {} to group {}, restart the running daemon to sync it", world, group.name);
+        }
+        Command::Tls { command: TlsCommand::Fingerprint } => {
+            let identity = tls::Identity::load(&config.tls.clone().unwrap_or_default(), app_dirs.root())?;
+            println!("{}", identity.fingerprint());
+            return Ok(());
+        }
+        Command::Snapshot { command: SnapshotCommand::List { world } } => {
+            for snapshot in Snapshots::new(app_dirs.snapshots()).list(&world)? {
+                println!("{}", snapshot.name);
+            }
+            return Ok(());
+        }
+        Command::Snapshot { command: SnapshotCommand::Restore { world, name } } => {
+            let snapshots = Snapshots::new(app_dirs.snapshots());
+            let snapshot = match name {
+                Some(name) => snapshots.find(&world, &name)?,
+                None => snapshots.list(&world)?.pop().ok_or_else(|| anyhow::anyhow!("No snapshot of {}", world))?,
+            };
+            let before = snapshots.restore(&config.paths.worlds_dir(), &snapshot, SystemTime::now())?;
+            info!("Restored {} from snapshot {}", world, snapshot.name);
+            if let Some(before) = before {
+                info!("Its previous state is snapshot {}, restore that to undo", before.name);
+            }
+            return Ok(());
+        }
+        Command::Snapshot { command: SnapshotCommand::View { world, name } } => {
+            let snapshots = Snapshots::new(app_dirs.snapshots());
+            let snapshot = match name {
+                Some(name) => snapshots.find(&world, &name)?,
+                None => snapshots.list(&world)?.pop().ok_or_else(|| anyhow::anyhow!("No snapshot of {}", world))?,
+            };
+            let path = Views::new(app_dirs.views()).open(&snapshot)?;
+            info!("Snapshot {} of {} is open read-only, close it with: mcbd-world-sync snapshot close \"{}\" {}", snapshot.name, world, world, snapshot.name);
+            println!("{}", path.display());
+            return Ok(());
+        }
+        Command::Snapshot { command: SnapshotCommand::Close { world, name } } => {
+            if !Views::new(app_dirs.views()).close(&world, &name)? {
+                anyhow::bail!("Snapshot {} of {} is not open", name, world);
+            }
+            info!("Closed snapshot {} of {}", name, world);
+            return Ok(());
+        }
+        Command::Stats { days } => {
+            let worlds_root = config.paths.worlds_dir();
+            let exclusions = Exclusions::new(&config.watch.exclude).with_ignore(&config.watch.ignore)?;
+            let archived = ArchivedWorlds::load(app_dirs.archived_file())?;
+            let mut worlds: Vec<PathBuf> = fs::read_dir(&worlds_root)?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.is_dir() && !exclusions.is_excluded(&worlds_root, path))
+                .collect();
+            worlds.sort();
+            let mut total = 0;
+            for world in &worlds {
+                let folder = world.file_name().unwrap_or_default().to_string_lossy();
+                let size = usage::folder_size(world)?;
+                total += size;
+                let state = if archived.contains(&folder) { "\tarchived" } else { "" };
+                println!("{}\t{}\t{}{}", folder, mcworld::display_name(world), usage::format_bytes(size), state);
+            }
+            println!("worlds: {} in {} worlds", usage::format_bytes(total), worlds.len());
+
+            let percent = |part: u64, whole: u64| (part * 100).checked_div(whole).unwrap_or(0);
+            let snapshots = Snapshots::new(app_dirs.snapshots()).usage(&worlds_root)?;
+            println!(
+                "snapshots: {} on disk for {} snapshots, {} as full copies ({}% spared by hard links)",
+                usage::format_bytes(snapshots.stored), snapshots.snapshots, usage::format_bytes(snapshots.size), percent(snapshots.size - snapshots.stored, snapshots.size)
+            );
+            let sent = UsageLog::new(app_dirs.usage_file()).last(days, SystemTime::now())?;
+            println!("sent in the last {} days: {} of files, {} on the wire", days, usage::format_bytes(sent.size), usage::format_bytes(sent.wire));
+            println!("  spared by deltas and chunks peers had: {} ({}%)", usage::format_bytes(sent.spared_by_deltas()), percent(sent.spared_by_deltas(), sent.size));
+            println!("  spared by compression: {} ({}%)", usage::format_bytes(sent.spared_by_compression()), percent(sent.spared_by_compression(), sent.encoded));
+            return Ok(());
+        }
+        Command::Report { send } => {
+            let days = config.report.as_ref().map(|report| report.schedule.days()).unwrap_or(ReportSchedule::default().days());
+            let report = Report::gather(&ReportSources::new(config, &app_dirs)?, days, SystemTime::now()).await?;
+            if !send {
+                println!("{}\n", report.subject());
+                print!("{}", report.body());
+                return Ok(());
+            }
+            let report_config = config.report.as_ref().ok_or_else(|| anyhow::anyhow!("Add a report section to the config to email reports"))?;
+            smtp::send(&report_config.smtp, &report.email(report_config)).await?;
+            info!("Sent the report to {}", report_config.to.join(", "));
+            return Ok(());
+        }
+        Command::Inspect { world, snapshot } => {
+            check_world(&world)?;
+            let dir = match &snapshot {
+                Some(name) => Snapshots::new(app_dirs.snapshots()).find(&world, name)?.path,
+                None => config.paths.worlds_dir().join(&world),
+            };
+            if !dir.is_dir() {
+                anyhow::bail!("No world folder {}", dir.display());
+            }
+            let inspection = inspect::inspect(&dir)?;
+            println!("{}: {} keys, {} bytes", snapshot.as_deref().unwrap_or(&world), inspection.keys, inspection.bytes);
+            let chunks: Vec<String> = inspection.chunks.iter().map(|(dimension, count)| format!("{} {}", dimension, count)).collect();
+            println!("chunks: {}", if chunks.is_empty() { "none".to_string() } else { chunks.join(", ") });
+            println!("players: {}", if inspection.players.is_empty() { "none".to_string() } else { inspection.players.join(", ") });
+            println!("actors: {}, maps: {}", inspection.actors, inspection.maps);
+            for missing in &inspection.missing {
+                println!("missing: {}", missing);
+            }
+            for damaged in &inspection.damaged {
+                println!("damaged: {}: {}", damaged.file.strip_prefix(&dir).unwrap_or(&damaged.file).display(), damaged.error);
+            }
+            return Ok(());
+        }
+        Command::Snapshot { command: SnapshotCommand::Diff { world, a, b } } => {
+            let snapshots = Snapshots::new(app_dirs.snapshots());
+            let (first, second) = (snapshots.find(&world, &a)?, snapshots.find(&world, &b)?);
+            let diffs = snapshots.diff(&first, &second)?;
+            for diff in &diffs {
+                match (diff.before, diff.after) {
+                    (None, Some(size)) => println!("added   {} ({} bytes)", diff.path.display(), size),
+                    (Some(size), None) => println!("removed {} ({} bytes)", diff.path.display(), size),
+                    (before, after) => println!("changed {} ({} -> {} bytes)", diff.path.display(), before.unwrap_or(0), after.unwrap_or(0)),
+                }
+            }
+            let total = |f: fn(&FileDiff) -> Option<u64>| diffs.iter().filter_map(f).sum::<u64>();
+            info!("{} files differ, {} bytes before and {} after", diffs.len(), total(|d| d.before), total(|d| d.after));
+            // File times say little about which copy was played more
+            for difference in players::differences(&players::read(&first.path)?, &players::read(&second.path)?) {
+                println!("player  {}", difference.describe(&a, &b));
+            }
+            return Ok(());
+        }
+        Command::Undo { world } => {
+            let snapshots = Snapshots::new(app_dirs.snapshots());
+            let undone = Journal::new(app_dirs.journal()).undo(&config.paths.worlds_dir(), &snapshots, &world, SystemTime::now())?;
+            info!("Undid {} received changes to {}", undone.changes.len(), world);
+            return Ok(());
+        }
+        Command::Archive { world: Some(world), .. } => {
+            check_world(&world)?;
+            let archived = ArchivedWorlds::load(app_dirs.archived_file())?;
+            let snapshot = archived.archive(&config.paths.worlds_dir(), &world, &Snapshots::new(app_dirs.snapshots()), SystemTime::now())?;
+            info!("Archived {}, its final snapshot is {}", world, snapshot.name);
+            reload_archived(config).await;
+            return Ok(());
+        }
+        Command::Archive { world: None, yes: confirmed, .. } => {
+            if config.archive.propose_after_days == 0 {
+                anyhow::bail!("Set archive.propose_after_days to have worlds proposed");
+            }
+            let Some(stored) = index::load(&app_dirs.index_file())? else {
+                anyhow::bail!("There is no index yet, the daemon builds it when it first starts");
+            };
+            let (worlds_root, now) = (config.paths.worlds_dir(), SystemTime::now());
+            let untouched_for = Duration::from_secs(config.archive.propose_after_days * 86400);
+            let (archived, snapshots) = (ArchivedWorlds::load(app_dirs.archived_file())?, Snapshots::new(app_dirs.snapshots()));
+            let proposals = ArchiveProposals::new(app_dirs.kept_file());
+            // The stored index may still have worlds archived or deleted since it was saved
+            let proposed: Vec<_> = proposals.propose(&archive::last_changes(&stored.files), untouched_for, now)?
+                .into_iter()
+                .filter(|(world, _)| !archived.contains(world) && worlds_root.join(world).is_dir())
+                .collect();
+            if proposed.is_empty() {
+                info!("No world went unchanged for {} days", config.archive.propose_after_days);
+                return Ok(());
+            }
+            let mut count = 0;
+            for (world, last_change) in proposed {
+                let question = format!("Archive {} ({}), unchanged since {}?", world, mcworld::display_name(&worlds_root.join(&world)), conflicts::date(last_change));
+                if !confirmed && !confirm(&question)? {
+                    proposals.keep(&world, last_change)?;
+                    continue;
+                }
+                let snapshot = archived.archive(&worlds_root, &world, &snapshots, now)?;
+                info!("Archived {}, its final snapshot is {}", world, snapshot.name);
+                count += 1;
+            }
+            if count > 0 {
+                reload_archived(config).await;
+            }
+            return Ok(());
+        }
+        Command::Unarchive { world } => {
+            ArchivedWorlds::load(app_dirs.archived_file())?.restore(&world)?;
+            info!("{} is synced again; changes other devices made meanwhile arrive once they send it, such as with sync-now", world);
+            reload_archived(config).await;
+            return Ok(());
+        }
+        Command::Deletions { path, apply, keep } => {
+            let pending = PendingDeletions::new(app_dirs.deletions_file());
+            let path = path.map(PathBuf::from);
+            if apply {
+                let approved = pending.approve(path.as_deref())?;
+                info!("{} deletions are applied at the daemon's next check, within {} seconds", approved, deletions::CHECK_INTERVAL.as_secs());
+            } else if keep {
+                let kept = pending.remove(path.as_deref().unwrap_or(Path::new("")))?;
+                info!("Kept {} files; other devices get them back when they change here or with sync-now", kept);
+            } else {
+                let listed = pending.list()?;
+                if listed.is_empty() {
+                    println!("No deletions are waiting");
+                }
+                for deletion in listed {
+                    let state = if deletion.approved { ", applied at the next check" } else { "" };
+                    println!("{} deleted by {} on {}{}", deletion.path.display(), deletion.device, conflicts::date(deletion.received_at), state);
+                }
+            }
+            return Ok(());
+        }
+        Command::Journal { command: JournalCommand::List { world } } => {
+            for set in Journal::new(app_dirs.journal()).change_sets(&world)? {
+                let started_at = set.started_at.duration_since(UNIX_EPOCH)?.as_secs();
+                let undoes = set.undoes.map(|undone| format!(", undoes {}", undone)).unwrap_or_default();
+                println!("{} {} {} changes{}", set.seq, started_at, set.changes.len(), undoes);
+            }
+            return Ok(());
+        }
+        Command::Journal { command: JournalCommand::Show { world, set } } => {
+            let sets = Journal::new(app_dirs.journal()).change_sets(&world)?;
+            let set = sets.into_iter().find(|s| s.seq == set).ok_or_else(|| anyhow::anyhow!("No change set {} of {}", set, world))?;
+            println!("snapshot {}", set.snapshot.as_deref().unwrap_or("-"));
+            for change in set.changes {
+                let kind = match (&change.before, &change.after) {
+                    (None, _) => "added",
+                    (_, None) => "removed",
+                    _ => "changed",
+                };
+                println!("{} {}", kind, change.path.display());
+            }
+            return Ok(());
+        }
+        Command::Journal { command: JournalCommand::Replay { world, set, target } } => {
+            let journal = Journal::new(app_dirs.journal());
+            let applied = journal.replay(&Snapshots::new(app_dirs.snapshots()), &world, set, &target)?;
+            let from = applied[0].snapshot.as_ref().map_or("an empty folder".to_string(), |name| format!("snapshot {}", name));
+            info!("Rebuilt {} as of change set {} in {} from {} and {} change sets", world, set, target.display(), from, applied.len());
+            return Ok(());
+        }
+        Command::Pair { address, code, port } => {
+            let join = address.zip(code);
+            let local = PairedDevice {
+                name: config.sync.local_name(),
+                port: config.server.port,
+                fingerprint: config.tls.as_ref().map(|tls| tls::Identity::load(tls, app_dirs.root()).map(|i| i.fingerprint())).transpose()?,
+            };
+            let paired = match join {
+                Some((address, code)) => pairing::join(&address, &code, &local).await?,
+                None => {
+                    let port = port.unwrap_or(config.server.port);
+                    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await
+                        .map_err(|e| anyhow::anyhow!("Cannot listen on port {} ({}), stop the daemon or pass --port", port, e))?;
+                    let code = pairing::new_code()?;
+                    info!("Waiting for the other device, run there: mcbd-world-sync pair <this computer>:{} {}", port, code);
+                    println!("{}", code);
+                    tokio::time::timeout(pairing::PAIR_TIMEOUT, pairing::accept(&listener, &code, &local)).await
+                        .map_err(|_| anyhow::anyhow!("Nobody entered the code in time"))??
+                }
+            };
+            pairing::add(config, &paired)?;
+            info!("Paired with {} at {}, restart the running daemon to sync with it", paired.device.name, paired.address);
+            if paired.device.fingerprint.is_some() != config.tls.is_some() {
+                warn!("Only one of the devices uses TLS, they cannot connect until both do");
+            }
+            if config.sync.all_devices().iter().any(|d| d.key.is_none()) {
+                warn!("Devices without a key can no longer connect, pair them too or set their key");
+            }
+        }
+        Command::Discover { command } => {
+            let (add, confirmed) = match command {
+                Some(DiscoverCommand::Add { name, yes }) => (Some(name), yes),
+                None => (None, false),
+            };
+            let local = PairedDevice {
+                name: config.sync.local_name(),
+                port: config.server.port,
+                fingerprint: config.tls.as_ref().map(|tls| tls::Identity::load(tls, app_dirs.root()).map(|i| i.fingerprint())).transpose()?,
+            };
+            info!("Looking for devices on the local network for {} seconds", discovery::SCAN_TIME.as_secs());
+            let peers = discovery::scan(&local, discovery::SCAN_TIME).await?;
+            let configured: Vec<String> = config.sync.all_devices().into_iter().map(|d| d.name).collect();
+            let Some(name) = add else {
+                for peer in &peers {
+                    let known = if configured.contains(&peer.device.name) { " (configured)" } else { "" };
+                    println!("{} {}{}", peer.device.name, peer.address, known);
+                }
+                if peers.is_empty() {
+                    info!("No devices found, check that they run with discovery enabled on the same network");
+                }
+                return Ok(());
+            };
+            let Some(peer) = peers.into_iter().find(|p| p.device.name == name) else {
+                anyhow::bail!("{} is not on the local network", name);
+            };
+            let fingerprint = peer.device.fingerprint.as_deref().map_or(String::new(), |f| format!(" with certificate fingerprint {}", f));
+            if !confirmed && !confirm(&format!("Add {} at {}{}?", name, peer.address, fingerprint))? {
+                anyhow::bail!("Not adding {}", name);
+            }
+            discovery::add(config, &peer)?;
+            info!("Added {} at {}, restart the running daemon to sync with it", name, peer.address);
+            if config.sync.all_devices().iter().any(|d| d.key.is_some()) {
+                warn!("{} has no key and cannot connect, pair with it instead to set one", name);
+            }
+        }
+        Command::Share { command: ShareCommand::Revoke { name } } => {
+            let share = shares::revoke(config, &name)?;
+            info!("Revoked share {} of {}", share.name, share.worlds.join(", "));
+        }
+        Command::Merge { world, base, theirs, yes: confirmed } => {
+            check_world(&world)?;
+            let worlds_root = config.paths.worlds_dir();
+            let snapshots = Snapshots::new(app_dirs.snapshots());
+            let base = snapshots.find(&world, &base)?.path;
+            let theirs = if Path::new(&theirs).is_dir() { PathBuf::from(&theirs) } else { snapshots.find(&world, &theirs)?.path };
+            warn!("Merging chunks is experimental, check the world in Minecraft before syncing it on");
+            let merge = merge::plan(&base, &worlds_root.join(&world), &theirs)?;
+            for (x, z, dimension) in &merge.conflicting_chunks {
+                println!("kept    chunk {} {} in the {} (blocks {} {}), changed in both", x, z, inspect::dimension(*dimension), x * 16, z * 16);
+            }
+            for key in &merge.conflicting_keys {
+                println!("kept    {}, changed in both", key);
+            }
+            if merge.take.is_empty() {
+                info!("The other copy has no changes to take");
+                return Ok(());
+            }
+            let question = format!("Take {} changed records from the other copy into {}? Minecraft must be closed", merge.take.len(), world);
+            if !confirmed && !confirm(&question)? {
+                anyhow::bail!("Not merging");
+            }
+            let before = snapshots.take(&worlds_root, &world, "merge", SystemTime::now())?;
+            merge::apply(&worlds_root.join(&world), &merge)?;
+            info!("Merged {} records into {}, Minecraft applies them when it next opens the world", merge.take.len(), world);
+            info!("Restore snapshot {} to undo", before.name);
+            return Ok(());
+        }
+        Command::Relay { port } => {
+            let port = port.unwrap_or(config.server.port);
+            let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await
+                .map_err(|e| anyhow::anyhow!("Cannot listen on port {} ({}), stop the daemon or pass --port", port, e))?;
+            return Relay::default().with_device_keys(DeviceKeys::new(&config.sync.all_devices())).serve(listener).await;
+        }
+    }
+    if headless {
+        warn!("Configuration comes from the environment in headless mode, update the MCBD_ variables to match");
+        return Ok(());
+    }
+    config.save(config_path)
+}
+
+/// Has a running daemon read the archived worlds again.
+async fn reload_archived(config: &AppConfig) {
+    match http::request(&config.http.bind, "POST", "/archived").await {
+        Ok((202, _)) => info!("The running daemon took the change"),
+        _ => info!("The daemon takes the change when it starts"),
+    }
+}
+
+/// Pulls a shared world once, or every sync interval with `keep_updated`.
+async fn run_guest(config: &AppConfig, token: ShareToken, host: Device, keep_updated: bool) -> Result<()> {
+    let app_dirs = AppDirs::new(&config.paths);
+    let mut files = FileManager::new(config.paths.worlds_dir())
+        .with_hash_workers(config.performance.hash_workers)
+        .with_exclusions(Exclusions::new(&config.watch.exclude).with_ignore(&config.watch.ignore)?)
+        .with_snapshots(Snapshots::new(app_dirs.snapshots()))
+        .with_journal(Journal::new(app_dirs.journal()))
+        .with_staging(config.sync.atomic_apply);
+    let tls = config.tls.as_ref().map(|tls| tls::connector(&tls.trusted)).transpose()?;
+    let connect = |address| SyncClient::new(address).with_tls(tls.clone());
+    loop {
+        let pulled = async {
+            let address = rendezvous::resolve_device(&host, &Lookup { rendezvous: config.sync.rendezvous.clone(), discovered: None }, connect).await?;
+            guest::pull(&connect(address), &token, &mut files).await
+        }.await;
+        match pulled {
+            Ok(_) if !keep_updated => return Ok(()),
+            Err(e) if !keep_updated => return Err(e),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to pull {}: {}", token.world, e),
+        }
+        tokio::time::sleep(Duration::from_secs(config.sync.sync_interval.max(1))).await;
+    }
+}
+
+/// What a peer's transfer worker needs, shared by the workers of all peers.
+#[derive(Clone)]
+struct TransferWorker {
+    queue: Arc<TransferQueue>,
+    metrics: Arc<Metrics>,
+    index: FileIndex,
+    groups: Groups,
+    lookup: Lookup,
+    exclusions: Exclusions,
+    reachability: Reachability,
+    streams: usize,
+    /// Shared by the workers of every peer with `performance.max_transfers`.
+    limit: Option<Arc<tokio::sync::Semaphore>>,
+    gaming: GamingMode,
+    /// Offline devices are tried again right away when the network changed.
+    network: Option<NetworkChanges>,
+    versions: Versions,
+}
+
+impl TransferWorker {
+    /// Tells `device` that the folder of `transfer` was moved to `to`. If it
+    /// does not move its copy, it gets the files below `to` and the deletion
+    /// of the folder instead.
+    async fn send_rename(&self, client: &SyncClient, device: &str, transfer: &PendingTransfer, to: PathBuf, files: Vec<Tombstone>) -> Result<()> {
+        let (from, groups) = (&transfer.path, &self.groups);
+        if client.send_file_renamed(from.clone(), to.clone(), files, groups.tag(device, from), groups.tag(device, &to)).await? {
+            info!("Moved {} to {} on {}", from.display(), to.display(), device);
+            return Ok(());
+        }
+        for entry in self.index.entries().into_iter().filter(|entry| entry.path.starts_with(&to)) {
+            self.queue.push(device.to_string(), entry.path, transfer.change_type.clone()).await;
+        }
+        client.send_file_deleted(from.clone(), transfer.change_type.clone(), self.index.tombstones_under(from), groups.tag(device, from)).await
+    }
+
+    /// Sends the transfers of `stream` queued for `peer`, one at a time.
+    /// While the peer cannot be reached its transfers stay queued.
+    async fn run(self, peer: String, stream: usize, connect: impl Fn(String) -> SyncClient) -> Result<()> {
+        let TransferWorker { queue, metrics, index, groups, exclusions, streams, limit, gaming, versions, .. } = &self;
+        loop {
+            let transfer = queue.pop_for_stream(&peer, stream, *streams).await;
+            if exclusions.is_excluded(Path::new(""), &transfer.path) {
+                debug!("Not sending excluded path {}", transfer.path.display());
+                queue.discard(&transfer).await;
+                continue;
+            }
+            let Some(device) = groups.device(&transfer.peer) else {
+                warn!("Dropping transfer for unknown device {}", transfer.peer);
+                queue.discard(&transfer).await;
+                continue;
+            };
+            let client = match self.reach(device, &connect).await {
+                Ok(client) => client,
+                Err(e) => {
+                    queue.retry(transfer).await;
+                    if self.reachability.set_offline(&device.name, SystemTime::now()) {
+                        warn!("{} is offline ({}), changes for it stay queued until it is back", device.name, e);
+                    }
+                    self.wait_until_back(device, &connect).await;
+                    continue;
+                }
+            };
+
+            // Asked for with --ignore-limits, nothing holds it back
+            let urgent = transfer.priority == Priority::Urgent;
+            let _permit = match limit.as_ref().filter(|_| !urgent) {
+                Some(limit) => Some(limit.clone().acquire_owned().await?),
+                None => None,
+            };
+            let id = CorrelationId::new();
+            let span = telemetry::transfer_span(&id, &device.name, &transfer.path.to_string_lossy());
+            let path = transfer.path.clone();
+            let transfer = correlation::scope(id, async {
+                debug!("Sending {} to {}", transfer.path.display(), device.name);
+                let (content, is_dir) = (index.get_file_content(&transfer.path), index.full_path(&transfer.path).is_dir());
+                let sent = match content {
+                    Ok(content) => {
+                        if !urgent {
+                            gaming.before_sending(content.len() as u64).await;
+                        }
+                        let group = groups.tag(&device.name, &transfer.path);
+                        let version = FileVersion { modified: index.get(&transfer.path).map(|info| info.last_modified), vector: versions.get(&transfer.path) };
+                        client.send_file_version(transfer.path.clone(), content, version, group, transfer.priority).await.map(|sent| metrics.record_sent(&sent))
+                    }
+                    // Folders are created along with the files inside them
+                    Err(_) if is_dir => {
+                        queue.discard(&transfer).await;
+                        return None;
+                    }
+                    Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::NotFound) => {
+                        let tombstones = index.tombstones_under(&transfer.path);
+                        // Moved to a folder the device syncs too
+                        let renamed = index.renamed(&transfer.path)
+                            .filter(|rename| groups.devices_for(&rename.to).iter().any(|synced| synced.name == device.name));
+                        if let Some(rename) = renamed {
+                            self.send_rename(&client, &device.name, &transfer, rename.to, rename.files).await
+                        } else if tombstones.is_empty() {
+                            client.send_file_change(transfer.path.clone(), transfer.change_type.clone()).await
+                        } else {
+                            let group = groups.tag(&device.name, &transfer.path);
+                            client.send_file_deleted(transfer.path.clone(), transfer.change_type.clone(), tombstones, group).await
+                        }
+                    }
+                    Err(e) => Err(e),
+                };
+                match sent {
+                    Ok(()) => {
+                        queue.acknowledged(&transfer).await;
+                        None
+                    }
+                    Err(e) => {
+                        error!("Failed to send change to {}: {}", device.name, e);
+                        if let Some(rejected) = e.downcast_ref::<FileRejected>() {
+                            queue.record_failure(&device.name, &transfer.path, rejected.reason.clone()).await;
+                        }
+                        Some(transfer)
+                    }
+                }
+            }).await;
+
+            match transfer {
+                None => {
+                    telemetry::end_span(span, None);
+                    Metrics::inc(&metrics.transfers_sent);
+                    // The peer swaps in what it staged for the world once it has all of it
+                    let world = transfer_queue::world_of(&path).filter(|_| file_manager::root_of(&path).is_none());
+                    if let Some(world) = world {
+                        if queue.world_delivered(&device.name, world).await {
+                            if let Err(e) = client.send_world_synced(world.to_string()).await {
+                                debug!("Failed to tell {} that {} is synced: {}", device.name, world, e);
+                            }
+                        }
+                    }
+                }
+                Some(failed) => {
+                    telemetry::end_span(span, Some(format!("transfer of {} failed", failed.path.display())));
+                    Metrics::inc(&metrics.transfers_failed);
+                    queue.retry(failed).await;
+                }
+            }
+        }
+    }
+
+    /// Resolves the address of `device` and checks that it answers.
+    async fn reach(&self, device: &Device, connect: &impl Fn(String) -> SyncClient) -> Result<SyncClient> {
+        let address = rendezvous::resolve_device(device, &self.lookup, connect).await?;
+        let client = connect(address).with_key(device.key.clone());
+        client.reach().await?;
+        Ok(client)
+    }
+
+    /// Tries to reach `device` again with growing waits until it answers,
+    /// then lets everything queued for it go at once.
+    async fn wait_until_back(&self, device: &Device, connect: &impl Fn(String) -> SyncClient) {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let network = self.network.clone();
+            let network_changed = async move {
+                match network {
+                    Some(network) => network.changed().await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(reconnect::backoff(attempt, reconnect::jitter())) => {}
+                _ = network_changed => {
+                    debug!("Network changed, trying {} again now", device.name);
+                    attempt = 0;
+                }
+            }
+            match self.reach(device, connect).await {
+                Ok(_) => break,
+                Err(e) => debug!("{} is still offline: {}", device.name, e),
+            }
+        }
+        // The peer's other streams may have noticed first
+        let offline = self.reachability.set_online(&device.name).map(|since| since.elapsed().unwrap_or_default());
+        let resumed = self.queue.resume_peer(&device.name).await;
+        if let Some(offline) = offline {
+            info!("{} is back after {}s offline, sending {} queued changes", device.name, offline.as_secs(), resumed);
+        }
+    }
+}
+
+/// Sends the manifest to every device each tick of `ticker`. Peers that
+/// already acknowledged an earlier manifest (per their persisted cursor) only
+/// get what changed since. Each device only sees the worlds of its group.
+async fn run_manifest_exchange(cache: ManifestCache, index: FileIndex, groups: Groups, lookup: Lookup, connect: impl Fn(String) -> SyncClient, local_name: String, mut ticker: SyncTicker) -> Result<()> {
+    loop {
+        if ticker.tick().await {
+            info!("Network changed, exchanging manifests now");
+        }
+        if !index.hash_policy().is_eager() {
+            // Files left to be hashed on demand are compared now
+            let hashed = hash_pending(&index, |f| groups.sending().any(|g| Groups::shares(g, &f.path))).await?;
+            if hashed > 0 {
+                debug!("Hashed {} files for the manifest", hashed);
+            }
+        }
+        let full = cache.current(&index).await;
+        for group in groups.sending() {
+            let current = Groups::manifest_for(group, &full);
+            for device in group.devices.iter().filter(|d| d.is_reachable()) {
+                // Both sides log the exchange under one ID
+                let exchanged = correlation::scope(CorrelationId::new(), async {
+                    let address = rendezvous::resolve_device(device, &lookup, &connect).await?;
+                    let mut session = connect(address).with_key(device.key.clone()).session().await?;
+                    manifest::exchange(&mut session, &cache, &local_name, &device.name, current.clone()).await
+                }).await;
+                match exchanged {
+                    Ok(sent) => debug!("Manifest {} sent to {} ({:?})", current.version, device.name, sent),
+                    Err(e) => warn!("Manifest exchange with {} failed: {}", device.name, e),
+                }
+            }
+        }
+    }
+}
+
+/// Checks the network interfaces every `every`, and when their addresses
+/// changed drops the kept sessions, which may lead nowhere now, and tells
+/// everything following `network`.
+async fn run_network_changes(network: NetworkChanges, connections: Connections, every: Duration) -> Result<()> {
+    let mut known = tokio::task::spawn_blocking(network_change::local_addresses).await?;
+    loop {
+        tokio::time::sleep(every).await;
+        let current = tokio::task::spawn_blocking(network_change::local_addresses).await?;
+        if current != known {
+            info!("Network changed, reaching devices again");
+            debug!("Addresses were {:?}, now {:?}", known, current);
+            connections.forget().await;
+            network.notify();
+            known = current;
+        }
+    }
+}
+
+/// Checks every `every` which worlds Minecraft has open, holds back their
+/// queued changes until it closes them, and tells the devices.
+async fn run_busy_worlds(busy: BusyWorlds, shadows: ShadowCopies, transfers: TransferWorker, worlds_dir: PathBuf, connect: impl Fn(String) -> SyncClient, local_name: String, every: Duration) -> Result<()> {
+    let TransferWorker { queue, groups, lookup, .. } = &transfers;
+    loop {
+        let (dir, copies) = (worlds_dir.clone(), shadows.clone());
+        let open = tokio::task::spawn_blocking(move || {
+            let open = busy::open_worlds(&dir, gaming::minecraft_running());
+            // Closed worlds are read as they are again
+            copies.retain(&open);
+            open
+        }).await?;
+        if busy.set(open) {
+            let worlds = busy.worlds();
+            if worlds.is_empty() {
+                info!("Minecraft closed the worlds, sending their changes");
+            } else {
+                info!("{} open in Minecraft, holding back their changes until it closes them", worlds.join(", "));
+            }
+            queue.hold_worlds(&worlds).await;
+            for device in groups.all().iter().flat_map(|group| &group.devices).filter(|d| d.is_reachable()) {
+                let told = async {
+                    let address = rendezvous::resolve_device(device, lookup, &connect).await?;
+                    connect(address).with_key(device.key.clone()).send_busy_worlds(local_name.clone(), worlds.clone()).await
+                }.await;
+                if let Err(e) = told {
+                    debug!("Could not tell {} which worlds are open: {}", device.name, e);
+                }
+            }
+        }
+        tokio::time::sleep(every).await;
+    }
+}
+
+/// Swaps in the worlds staged from peers that do not say when they are
+/// done, once nothing arrived for them for a burst gap. Worlds Minecraft has
+/// open wait until it closes them.
+async fn run_staged_worlds(file_manager: Arc<Mutex<FileManager>>, every: Duration) -> Result<()> {
+    loop {
+        tokio::time::sleep(every).await;
+        let mut files = file_manager.lock().await;
+        for world in files.settled_stages(file_manager::RECEIVE_BURST_GAP) {
+            match files.commit_staged(&world) {
+                Ok(true) => info!("Applied the changes received for {}", world),
+                Ok(false) => debug!("{} is open in Minecraft, its received changes wait until it closes", world),
+                Err(e) => warn!("Failed to apply the changes received for {}: {}", world, e),
+            }
+        }
+    }
+}
+
+/// Compacts the index every `compact_interval` and saves it when anything
+/// was dropped. Peers offline for longer than the retention are marked for a
+/// full reconcile first. Unused chunks are pruned on the same schedule.
+async fn run_compaction(file_manager: Arc<Mutex<FileManager>>, cache: ManifestCache, chunks: ChunkStore, peers: Vec<String>, config: IndexConfig, index_file: PathBuf) -> Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.compact_interval.max(1)));
+    loop {
+        interval.tick().await;
+        match chunks.prune(Duration::from_secs(config.chunk_retention_days * 24 * 60 * 60), SystemTime::now()) {
+            Ok(0) => {}
+            Ok(pruned) => info!("Pruned {} unused chunks", pruned),
+            Err(e) => warn!("Failed to prune chunks: {}", e),
+        }
+        for peer in compaction::lapsed_peers(&cache.cursors().await, &peers, &config, SystemTime::now()) {
+            if cache.require_reconcile(&peer).await {
+                warn!("{} has been offline longer than the tombstone retention and could bring deleted files back. It is not synced until a full reconcile is requested (POST /reconcile/{} on the HTTP server)", peer, peer);
+            }
+        }
+        let cursors = cache.cursors().await;
+        let mut guard = file_manager.lock().await;
+        let compacted = compaction::compact(&mut guard, &cursors, &peers, &config, SystemTime::now());
+        if compacted == Compaction::default() {
+            continue;
+        }
+        info!("Index compacted: {} tombstones expired, {} evicted", compacted.expired, compacted.evicted);
+        let (root, generation, files, tombstones) = (guard.base_path().to_path_buf(), guard.generation(), guard.entries(), guard.tombstones());
+        drop(guard);
+        if let Err(e) = index::save(&index_file, &root, generation, files, tombstones) {
+            warn!("Failed to save index: {}", e);
+        }
+    }
+}
+
+/// Proposes archiving the worlds unchanged for `untouched_for` in the log,
+/// once a day and whenever they changed.
+async fn run_archive_proposals(index: FileIndex, proposals: ArchiveProposals, untouched_for: Duration) -> Result<()> {
+    let mut interval = tokio::time::interval(ARCHIVE_PROPOSAL_INTERVAL);
+    let mut proposed_before = BTreeSet::new();
+    loop {
+        interval.tick().await;
+        let proposed: BTreeSet<String> = proposals.propose(&archive::last_changes(&index.entries()), untouched_for, SystemTime::now())?
+            .into_iter()
+            .map(|(world, _)| world)
+            .collect();
+        if !proposed.is_empty() && proposed != proposed_before {
+            let worlds: Vec<&str> = proposed.iter().map(String::as_str).collect();
+            warn!(
+                "{} worlds were not changed for {} days and could be archived: {}. Run `mcbd-world-sync archive --proposed` to choose",
+                worlds.len(), untouched_for.as_secs() / 86400, worlds.join(", ")
+            );
+        }
+        proposed_before = proposed;
+    }
+}
+
+/// Deletes the files peers deleted once the deletion is due, see
+/// `PendingDeletions::due`, unless they changed here since.
+async fn run_pending_deletions(file_manager: Arc<Mutex<FileManager>>, pending: PendingDeletions, config: DeletionConfig) -> Result<()> {
+    let mut interval = tokio::time::interval(deletions::CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let due = pending.due(Duration::from_secs(config.grace_period), config.confirm, SystemTime::now())?;
+        if due.is_empty() {
+            continue;
+        }
+        let mut files = file_manager.lock().await;
+        for deletion in due {
+            match files.receive_deletion(&deletion.path, &deletion.hash) {
+                Ok(true) => info!("Deleted {}, as {} did", deletion.path.display(), deletion.device),
+                Ok(false) if files.get_file_info(&deletion.path).is_some() => info!("Keeping {}, it changed here since {} deleted it", deletion.path.display(), deletion.device),
+                Ok(false) => {}
+                Err(e) => {
+                    warn!("Failed to delete {}: {}", deletion.path.display(), e);
+                    continue;
+                }
+            }
+            pending.remove(&deletion.path)?;
+        }
+    }
+}
+
+/// Publishes the daemon status to the MQTT broker every `interval` seconds.
+/// Sensors of worlds and devices that went away while it was disconnected
+/// stay in Home Assistant, marked unavailable, until removed there.
+async fn run_mqtt(config: Arc<MqttConfig>, sources: StatusSources, name: String) -> Result<()> {
+    let mut publisher = Publisher::new(&config, &name);
+    let mut client = MqttClient::connect(&config.broker, &publisher.connect_options(&config)).await?;
+    info!("Publishing status to MQTT broker {}", config.broker);
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval.max(1)));
+    loop {
+        interval.tick().await;
+        for message in publisher.messages(&sources.gather().await) {
+            client.publish(&message.topic, message.payload.as_bytes(), true).await?;
+        }
+    }
+}
+
+/// Emails the summary report whenever one is due, see `ReportTimes::due`.
+/// A report that fails to send is tried again at the next check.
+async fn run_reports(sources: ReportSources, config: Arc<ReportConfig>) -> Result<()> {
+    let times = ReportTimes::new(sources.app_dirs.report_file());
+    let mut interval = tokio::time::interval(report::CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now();
+        if !times.due(&config.schedule, now)? {
+            continue;
+        }
+        let report = Report::gather(&sources, config.schedule.days(), now).await?;
+        match smtp::send(&config.smtp, &report.email(&config)).await {
+            Ok(()) => {
+                info!("Sent the summary report to {}", config.to.join(", "));
+                times.record(now)?;
+            }
+            Err(e) => warn!("Failed to send the summary report through {}: {}", config.smtp.host, e),
+        }
+    }
+}
+
+/// Adds what was sent to peers to the usage log every `USAGE_INTERVAL`,
+/// for `stats`. What was sent since the last time is lost when the task
+/// restarts.
+async fn run_usage(metrics: Arc<Metrics>, log: UsageLog) -> Result<()> {
+    let mut interval = tokio::time::interval(USAGE_INTERVAL);
+    let mut recorded = Usage::of(&metrics);
+    loop {
+        interval.tick().await;
+        let current = Usage::of(&metrics);
+        let sent = current.since(&recorded);
+        if sent != Usage::default() {
+            log.record(&sent, SystemTime::now())?;
+        }
+        recorded = current;
+    }
+}
+
+/// Warns about devices that stopped syncing. With pausing enabled their queue
+/// is dropped, and every file is queued again once they are back.
+async fn run_device_aging(aging: DeviceAging, index: FileIndex, queue: Arc<TransferQueue>, groups: Groups) -> Result<()> {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        for change in aging.refresh(SystemTime::now()).await {
+            match change {
+                AgingChange::Stale { device, offline } => {
+                    warn!("{} has not synced for {} days", device, offline.as_secs() / (24 * 60 * 60));
+                    if aging.is_paused(&device).await {
+                        let dropped = queue.remove_peer(&device).await;
+                        info!("Stopped queuing changes for {}, dropped {} queued transfers", device, dropped);
+                    }
+                }
+                AgingChange::Returned { device, was_paused } => {
+                    info!("{} is syncing again", device);
+                    if let (true, Some(group)) = (was_paused, groups.of_device(&device)) {
+                        let paths: Vec<PathBuf> = index.entries()
+                            .into_iter()
+                            .map(|f| f.path)
+                            .filter(|p| Groups::shares(group, p))
+                            .collect();
+                        info!("Queuing all {} files for {} to catch up", paths.len(), device);
+                        for path in paths {
+                            queue.push(device.clone(), path, "Reconcile".to_string()).await;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reads the archived worlds again each time it is asked to. Archived
+/// worlds leave the index, without tombstones, so other devices keep them;
+/// restored ones are indexed again, and sent once they change.
+async fn run_archived(requests: Arc<Mutex<tokio::sync::mpsc::UnboundedReceiver<()>>>, archived: ArchivedWorlds, file_manager: Arc<Mutex<FileManager>>) -> Result<()> {
+    let mut requests = requests.lock().await;
+    while requests.recv().await.is_some() {
+        let (archived_now, restored) = archived.reload()?;
+        let mut files = file_manager.lock().await;
+        for world in archived_now {
+            files.remove_entries_under(Path::new(&world));
+            info!("Archived {}, it is not synced anymore", world);
+        }
+        for world in restored {
+            let dir = files.base_path().join(&world);
+            let indexed = if dir.is_dir() { files.scan_subtree(&dir)?.len() } else { 0 };
+            info!("{} is synced again, {} files indexed", world, indexed);
+        }
+    }
+    Ok(())
+}
+
+/// Queues all indexed files of a requested world, or of every world, ahead
+/// of background transfers, for the devices whose group syncs that world
+/// or the one device asked for.
+async fn run_sync_now(requests: Arc<Mutex<tokio::sync::mpsc::UnboundedReceiver<SyncNow>>>, index: FileIndex, queue: Arc<TransferQueue>, groups: Groups, mirrors: Vec<Mirror>, busy: BusyWorlds, shadows: Option<ShadowCopies>) -> Result<()> {
+    let mut requests = requests.lock().await;
+    while let Some(request) = requests.recv().await {
+        let entries = index.entries();
+        let worlds: BTreeSet<String> = match &request.world {
+            Some(world) => BTreeSet::from([world.clone()]),
+            None => entries.iter().filter_map(|f| transfer_queue::world_of(&f.path).map(str::to_string)).collect(),
+        };
+        for world in worlds.iter().filter(|world| busy.is_busy(world)) {
+            match &shadows {
+                Some(shadows) => {
+                    let (copies, dir, name) = (shadows.clone(), index.full_path(Path::new(world)), world.clone());
+                    match tokio::task::spawn_blocking(move || ShadowCopy::create(&dir).map(|copy| copies.insert(name, copy))).await? {
+                        Ok(()) => {
+                            info!("{} is open in Minecraft, sending it from a shadow copy", world);
+                            queue.let_through(world).await;
+                        }
+                        Err(e) => warn!("{} is open in Minecraft and could not be shadow copied, it is sent once closed: {}", world, e),
+                    }
+                }
+                None => info!("{} is open in Minecraft, it is sent once closed", world),
+            }
+        }
+        let priority = if request.ignore_limits { Priority::Urgent } else { Priority::Interactive };
+        let (mut queued, mut reached) = (0, false);
+        for world in &worlds {
+            let paths: Vec<&PathBuf> = entries.iter().map(|f| &f.path).filter(|p| p.starts_with(world)).collect();
+            let devices = groups.devices_for(Path::new(world));
+            let targets: Vec<&String> = devices.iter().map(|device| &device.name)
+                .chain(mirrors.iter().map(|mirror| &mirror.name))
+                .filter(|target| request.device.as_ref().is_none_or(|device| device == *target))
+                .collect();
+            reached |= !targets.is_empty();
+            if request.world.is_some() {
+                info!("Sync now requested for {}: {} files", world, paths.len());
+            }
+            for path in paths {
+                for target in &targets {
+                    queue.push_with_priority(target.to_string(), path.clone(), "SyncNow".to_string(), priority).await;
+                    queued += 1;
+                }
+            }
+        }
+        if request.world.is_none() {
+            info!("Sync now requested for all {} worlds: {} transfers", worlds.len(), queued);
+        }
+        if request.ignore_limits {
+            info!("Sending them outside the gaming mode and transfer limits");
+        }
+        if let Some(device) = request.device.as_ref().filter(|_| !reached) {
+            warn!("{} syncs none of the requested worlds", device);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the changes queued for a local mirror, one at a time. Every
+/// `every` it is also compared with the index, which queues what it missed,
+/// such as while the daemon was not running or files that were received
+/// from other devices. While the mirror cannot be written, such as on a
+/// disconnected drive, its changes stay queued and are retried.
+async fn run_mirror(mirror: Mirror, queue: Arc<TransferQueue>, index: FileIndex, exclusions: Exclusions, every: Duration) -> Result<()> {
+    let mut comparisons = tokio::time::interval(every);
+    comparisons.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        let transfer = tokio::select! {
+            transfer = queue.pop_for(&mirror.name) => transfer,
+            _ = comparisons.tick() => {
+                let (copy, entries) = (mirror.clone(), index.entries());
+                let missed = tokio::task::spawn_blocking(move || copy.differences(&entries)).await??;
+                if !missed.is_empty() {
+                    info!("Mirroring {} changed files to {}", missed.len(), mirror.name);
+                }
+                for path in missed {
+                    queue.push(mirror.name.clone(), path, "Mirror".to_string()).await;
+                }
+                continue;
+            }
+        };
+        if exclusions.is_excluded(Path::new(""), &transfer.path) {
+            queue.discard(&transfer).await;
+            continue;
+        }
+        let (copy, index, path) = (mirror.clone(), index.clone(), transfer.path.clone());
+        match tokio::task::spawn_blocking(move || copy.apply(&index, &path)).await? {
+            Ok(applied) => {
+                if applied {
+                    debug!("Mirrored {} to {}", transfer.path.display(), mirror.name);
+                }
+                queue.acknowledged(&transfer).await;
+            }
+            Err(e) => {
+                warn!("Failed to mirror {} to {}: {}", transfer.path.display(), mirror.name, e);
+                queue.retry(transfer).await;
+            }
+        }
+    }
+}
+
+/// Syncs an Android device every `every`, and queues what it changed for
+/// the other devices and mirrors. While it is not connected, that is logged
+/// once and the sync is retried.
+async fn run_android(device: AndroidDevice, file_manager: Arc<Mutex<FileManager>>, index: FileIndex, queue: Arc<TransferQueue>, groups: Groups, mirrors: Vec<Mirror>, every: Duration) -> Result<()> {
+    let mut interval = tokio::time::interval(every);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut connected = true;
+    loop {
+        interval.tick().await;
+        let (copy, files, index) = (device.clone(), file_manager.clone(), index.clone());
+        let synced = match tokio::task::spawn_blocking(move || copy.sync(&index, &files)).await? {
+            Ok(synced) => synced,
+            Err(e) => {
+                if connected {
+                    warn!("Cannot sync with {}, retrying every {}s: {}", device.name, every.as_secs(), e);
+                }
+                connected = false;
+                continue;
+            }
+        };
+        if !connected {
+            info!("{} is connected again", device.name);
+            connected = true;
+        }
+        let changes = synced.received.len() + synced.removed.len() + synced.pushed + synced.deleted_there;
+        if changes > 0 {
+            info!("Synced {} changes with {}: {} received, {} removed here, {} sent, {} deleted there",
+                changes, device.name, synced.received.len(), synced.removed.len(), synced.pushed, synced.deleted_there);
+        }
+        for path in synced.received.iter().chain(&synced.removed) {
+            for peer in groups.devices_for(path) {
+                queue.push(peer.name.clone(), path.clone(), format!("From {}", device.name)).await;
+            }
+            for mirror in &mirrors {
+                queue.push(mirror.name.clone(), path.clone(), format!("From {}", device.name)).await;
+            }
+        }
+    }
+}
+
+/// Files hashed between two updates of the index.
+const HASH_BATCH: usize = 64;
+
+/// How often worlds are checked for being unchanged long enough to propose
+/// archiving them.
+const ARCHIVE_PROPOSAL_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often the bytes sent to peers are added to the usage log.
+const USAGE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often the worlds directory is checked to still be there, see
+/// `FileIndex::check_available`.
+const AVAILABILITY_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long after a folder was created it is scanned again. Files written
+/// to it before the watcher watched it raise no events.
+const NEW_FOLDER_RESCAN: Duration = Duration::from_secs(1);
+
+/// Hashes the pending entries `wanted` picks, a batch at a time on blocking
+/// threads, so readers of the index never wait for it. Files that cannot be
+/// read are left pending and logged once. Returns how many were hashed.
+async fn hash_pending(index: &FileIndex, wanted: impl Fn(&FileInfo) -> bool) -> Result<usize> {
+    let (mut hashed, mut failed) = (0, HashSet::new());
+    loop {
+        let batch: Vec<FileInfo> = index.pending_hashes().into_iter().filter(|f| wanted(f) && !failed.contains(&f.path)).take(HASH_BATCH).collect();
+        if batch.is_empty() {
+            return Ok(hashed);
+        }
+        let reader = index.clone();
+        let results = tokio::task::spawn_blocking(move || {
+            let paths: Vec<PathBuf> = batch.iter().map(|f| f.path.clone()).collect();
+            let hashes = file_manager::hash_files(&reader, &paths, reader.hash_workers());
+            batch.into_iter().zip(hashes).collect::<Vec<_>>()
+        }).await?;
+        for (file, hash) in results {
+            match hash {
+                Ok(hash) => hashed += index.set_hash(FileInfo { hash, ..file }) as usize,
+                Err(e) => {
+                    warn!("Failed to hash {}: {}", file.path.display(), e);
+                    failed.insert(file.path);
+                }
+            }
+        }
+    }
+}
+
+/// Hashes the files the initial scan only listed, except those the hash
+/// policy leaves until they are needed.
+async fn run_hashing(index: FileIndex) -> Result<()> {
+    let started = std::time::Instant::now();
+    let hashing = index.hash_policy();
+    let hashed = hash_pending(&index, |f| hashing.hashes_eagerly(f.size)).await?;
+    if hashed > 0 {
+        info!("Hashed {} files in {}s", hashed, started.elapsed().as_secs());
+    }
+    Ok(())
+}
+
+/// The runtime the daemon runs on, sized by the `performance` settings.
+fn runtime(config: &PerformanceConfig) -> Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.enable_all().max_blocking_threads(config.blocking_threads.max(1));
+    if let Some(threads) = config.worker_threads {
+        builder.worker_threads(threads.max(1));
+    }
+    Ok(builder.build()?)
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let headless = cli.headless();
+    if let Some(level) = cli.log_level {
+        std::env::set_var("RUST_LOG", level.to_string());
+    }
+    if headless {
+        // Keep container logs readable unless the operator asks for more
+        if env::var("RUST_LOG").is_err() {
+            std::env::set_var("RUST_LOG", "info");
+        }
+        correlation::init_logger(LogFormat::Json);
+    } else {
+        // Initialize logger with debug level unless another one was asked for
+        if cli.log_level.is_none() {
+            std::env::set_var("RUST_LOG", "debug");
+        }
+        correlation::init_logger(LogFormat::Text);
+    }
     
     info!("Starting Minecraft Bedrock World Sync");
-    info!("Note: This program requires administrator privileges to access Minecraft files.");
+
+    let chaos = match &cli.chaos {
+        Some(spec) => {
+            warn!("Chaos mode enabled: {}", spec);
+            Some(Chaos::parse(spec)?)
+        }
+        None => None,
+    };
 
     // Load configuration
-    let config = AppConfig::load()?;
+    let config = if headless { AppConfig::from_env()? } else { AppConfig::load(&cli.config)? };
     info!("Configuration loaded");
+    if cfg!(windows) && !headless && !config.paths.seed {
+        info!("Note: This program requires administrator privileges to access Minecraft files.");
+    }
 
-    // Initialize file manager
-    let file_manager = Arc::new(Mutex::new(FileManager::new(PathBuf::from(&config.paths.minecraft_worlds))));
-    
-    // Start sync server
-    let server = SyncServer::new(config.server.port);
-    let _file_manager_clone = file_manager.clone();
+    runtime(&config.performance)?.block_on(run(config, cli, chaos))
+}
+
+async fn run(mut config: AppConfig, cli: Cli, chaos: Option<Chaos>) -> Result<()> {
+    let headless = cli.headless();
+    match cli.command {
+        None | Some(Command::Run) => {}
+        Some(command) => return run_command(command, &mut config, &cli.config, headless).await,
+    }
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn(shutdown::cancel_on_signal(shutdown.clone()));
+
+    let app_dirs = AppDirs::new(&config.paths);
+    app_dirs.ensure()?;
+    info!("State directory: {}", app_dirs.root().display());
+    if !headless && !config.paths.seed && !config.paths.removable && !config.paths.worlds_dir().is_dir() {
+        // Minecraft keeps its worlds elsewhere depending on the platform and launcher
+        let edition = config.paths.edition;
+        let detected = platform::minecraft_installations().into_iter()
+            .find(|installation| edition.is_none_or(|edition| installation.edition == edition) && installation.worlds.is_dir());
+        if let Some(installation) = detected {
+            info!("{} does not exist, using the detected {} worlds in {}", config.paths.minecraft_worlds, installation.edition, installation.worlds.display());
+            config.paths.minecraft_worlds = installation.worlds.to_string_lossy().into_owned();
+            config.paths.edition = Some(installation.edition);
+        }
+    }
+    if config.paths.seed {
+        // Starts out empty on a new server and fills up as the other devices sync
+        if !config.paths.removable {
+            fs::create_dir_all(config.paths.worlds_dir())?;
+        }
+        info!("Seeding worlds from {}, Minecraft is not needed", config.paths.minecraft_worlds);
+    }
+    if config.paths.removable && !config.paths.worlds_dir().is_dir() {
+        // The stored index would not match a directory that is not there
+        warn!("{} is unavailable, waiting for it", config.paths.minecraft_worlds);
+        while !config.paths.worlds_dir().is_dir() {
+            tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(AVAILABILITY_INTERVAL) => {}
+            }
+        }
+        info!("{} is available", config.paths.minecraft_worlds);
+    }
+
+    // Metrics and health are served before startup finishes so /readyz can report progress
+    let metrics = Arc::new(Metrics::new());
+    let health = Arc::new(Health::new());
+    let _telemetry = match &config.telemetry {
+        Some(telemetry_config) => match telemetry::init(telemetry_config, metrics.clone()) {
+            Ok(telemetry) => Some(telemetry),
+            Err(e) => {
+                warn!("Failed to set up telemetry export: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+    let groups = Groups::new(config.sync.groups())?;
+    let mirrors: Vec<Mirror> = config.mirrors.iter().map(|m| Mirror::new(m.name.clone(), PathBuf::from(&m.path)).with_hard_links(m.hard_links)).collect();
+    for mirror in &mirrors {
+        if config.sync.all_devices().iter().any(|device| device.name == mirror.name) {
+            anyhow::bail!("Mirror {} has the name of a device", mirror.name);
+        }
+        let worlds = config.paths.worlds_dir();
+        if mirror.root.starts_with(&worlds) || worlds.starts_with(&mirror.root) {
+            anyhow::bail!("Mirror {} must be outside the worlds directory and not contain it", mirror.name);
+        }
+        if let Some(name) = config.paths.roots().into_iter().find(|(_, root)| mirror.root.starts_with(root) || root.starts_with(&mirror.root)).map(|(name, _)| name) {
+            anyhow::bail!("Mirror {} must be outside the root {} and not contain it", mirror.name, name);
+        }
+    }
+    let roots = config.paths.roots();
+    for (name, root) in &roots {
+        if name.is_empty() || name.contains(['/', '\\']) {
+            anyhow::bail!("Invalid root name '{}'", name);
+        }
+        let worlds = config.paths.worlds_dir();
+        if root.starts_with(&worlds) || worlds.starts_with(root) {
+            anyhow::bail!("Root {} must be outside the worlds directory and not contain it", name);
+        }
+        if let Some((other, _)) = roots.iter().find(|(other, dir)| other != &name && (root.starts_with(dir) || dir.starts_with(root))) {
+            anyhow::bail!("Roots {} and {} overlap", name, other);
+        }
+    }
+    let manifest_cache = ManifestCache::load(app_dirs.cursors_file());
+    let device_names: Vec<String> = config.sync.all_devices().into_iter().map(|d| d.name).collect();
+    let reachability = Reachability::new();
+    let aging = DeviceAging::new(device_names.clone(), manifest_cache.clone(), config.sync.stale_after_days).with_pause(config.sync.pause_stale_devices).with_reachability(reachability.clone());
+    // Outgoing changes are queued per device and sent by a background worker
+    let transfer_queue = Arc::new(TransferQueue::new(metrics.clone()));
+    let conflicts = ConflictPolicy::parse(&config.sync.conflict_resolution, &config.sync.conflict_overrides)?;
+    let (sync_now_tx, sync_now_rx) = tokio::sync::mpsc::unbounded_channel();
+    let (archived_tx, archived_rx) = tokio::sync::mpsc::unbounded_channel();
+
+    // Initialize file manager, its index is listed by /status while startup runs
+    let worlds_root = config.paths.worlds_dir();
+    let mut exclusion_rules = config.watch.exclude.clone();
+    if app_dirs.root().starts_with(&worlds_root) {
+        warn!("State directory is inside the worlds directory, it will be excluded from sync");
+        exclusion_rules.push(app_dirs.root().display().to_string());
+    }
+    let archived = ArchivedWorlds::load(app_dirs.archived_file())?;
+    let exclusions = Exclusions::new(&exclusion_rules).with_ignore(&config.watch.ignore)?.with_archived(archived.clone());
+    let mut churn = Churn::new(&config.watch.low_value)?;
+    let versions = Versions::open(app_dirs.versions_file(), &config.sync.local_name())?;
+    let gaming = GamingMode::new(config.gaming_mode.enabled.then(|| GamingLimits::from(&config.gaming_mode)));
+    let busy = BusyWorlds::new();
+    let shadows = ShadowCopies::default();
+    if config.gaming_mode.shadow_copies && !cfg!(windows) {
+        warn!("gaming_mode.shadow_copies only works on Windows, worlds open in Minecraft are sent once closed");
+    }
+    let cpu = CpuBudget::new(config.performance.cpu_budget);
+    if cpu.is_limited() {
+        info!("Hashing and compression are paced to use {}% of the CPU on average", config.performance.cpu_budget.unwrap_or(100));
+    }
+    let mut file_manager = FileManager::new(worlds_root.clone())
+        .with_roots(roots.clone())
+        .with_gaming_mode(gaming.clone())
+        .with_cpu_budget(cpu.clone())
+        .with_shadow_copies(shadows.clone())
+        .with_hash_workers(config.performance.hash_workers)
+        .with_hash_policy(config.paths.hashing)
+        .with_exclusions(exclusions.clone())
+        .with_snapshots(Snapshots::new(app_dirs.snapshots()))
+        .with_journal(Journal::new(app_dirs.journal()))
+        .with_staging(config.sync.atomic_apply)
+        .with_conflicts(conflicts, config.sync.local_name());
+    let mut android_devices = Vec::new();
+    for android in &config.android {
+        if config.sync.all_devices().iter().any(|device| device.name == android.name) || mirrors.iter().any(|mirror| mirror.name == android.name) {
+            anyhow::bail!("Android device {} has the name of another device or mirror", android.name);
+        }
+        let adb = Adb::new(android.adb.clone(), android.serial.clone(), android.worlds.clone());
+        android_devices.push(AndroidDevice::new(android.name.clone(), adb, &app_dirs.android()).with_exclusions(exclusions.clone()));
+    }
+
+    let port_mappings = Mappings::default();
+    if config.http.enabled {
+        let bind = config.http.bind.clone();
+        let worlds = config.paths.worlds_dir();
+        let downloads = config.http.downloads.then(|| WorldLinks { links: Links::new(app_dirs.downloads_file()), worlds: worlds.clone() });
+        let uploads = config.http.uploads.then(|| WorldLinks { links: Links::new(app_dirs.uploads_file()), worlds: worlds.clone() });
+        let snapshots = Some(WorldSnapshots { snapshots: Snapshots::new(app_dirs.snapshots()), worlds });
+        let state = HttpState { metrics: metrics.clone(), health: health.clone(), sync_now: sync_now_tx, archived: archived_tx, cursors: manifest_cache.clone(), aging: aging.clone(), queue: transfer_queue.clone(), downloads, uploads, snapshots, port_mappings: port_mappings.clone(), index: Some(file_manager.index()), gaming: gaming.clone(), busy: busy.clone() };
+        supervisor::supervise("HTTP server", move || {
+            let (bind, state) = (bind.clone(), state.clone());
+            async move { http::serve(&bind, state).await }
+        });
+    }
+
+    // Before the staging folder could be taken for a legacy one
+    match file_manager.recover_stages() {
+        Ok(0) => {}
+        Ok(dropped) => warn!("Dropped the changes of {} worlds whose sync was interrupted, they are synced again", dropped),
+        Err(e) => warn!("Failed to clean up worlds staged by the last run: {}", e),
+    }
+
+    // Make sure the stored index belongs to the configured worlds directory
+    let index_file = app_dirs.index_file();
+    let stored_index = index::load(&index_file)?;
+    match index::check(stored_index.as_ref(), &worlds_root) {
+        IndexCheck::Missing => info!("No index found, it will be built by the initial scan"),
+        IndexCheck::Matches => {
+            if let Some(stored) = stored_index {
+                file_manager.load_entries(stored.files);
+                file_manager.load_tombstones(stored.tombstones);
+                file_manager.restore_generation(stored.generation);
+            }
+        }
+        IndexCheck::Mismatch { stored_root } => {
+            if index::resolve_mismatch(&stored_root, &worlds_root)? == MismatchAction::Migrate {
+                if let Some(stored) = stored_index {
+                    file_manager.load_entries(stored.files);
+                    file_manager.load_tombstones(stored.tombstones);
+                    file_manager.restore_generation(stored.generation);
+                }
+            }
+            index::save(&index_file, &worlds_root, file_manager.generation(), file_manager.entries(), file_manager.tombstones())?;
+        }
+    }
+    // Archived while the daemon was not running
+    for world in archived.worlds() {
+        file_manager.remove_entries_under(Path::new(&world));
+    }
+    // Once the stored index is loaded, which may still have their files
+    match migration::migrate_legacy_state(&worlds_root, &app_dirs, &mut file_manager) {
+        Ok(0) => {}
+        Ok(moved) => info!("Moved {} legacy state folders out of the worlds directory", moved),
+        Err(e) => warn!("Failed to migrate legacy state folders: {}", e),
+    }
+    Health::set(&health.index_loaded);
+    let file_index = file_manager.index();
+    let file_manager = Arc::new(Mutex::new(file_manager));
+
+    // Persist the index periodically
+    let shutdown_index_file = index_file.clone();
+    let shutdown_cursors = manifest_cache.clone();
+    let compaction_index_file = index_file.clone();
+    let saved_index = file_index.clone();
+    supervisor::supervise("Index saving", move || {
+        let (index_file, saved_index) = (index_file.clone(), saved_index.clone());
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = index::save(&index_file, saved_index.base_path(), saved_index.generation(), saved_index.entries(), saved_index.tombstones()) {
+                    warn!("Failed to save index: {}", e);
+                }
+            }
+        }
+    });
     
-    tokio::spawn(async move {
-        if let Err(e) = server.start().await {
-            error!("Server error: {}", e);
+    // Start sync server. It comes after the index is loaded and before the
+    // initial scan, so peers reach this device early; until the scan listed
+    // the worlds, requests that need the index are answered with a retry,
+    // and manifests are not sent
+    let chunk_store = ChunkStore::new(app_dirs.chunks());
+    let streams = config.performance.transfer_streams.max(1);
+    let (tls_acceptor, tls_connector, fingerprint) = match &config.tls {
+        Some(tls_config) => {
+            let identity = tls::Identity::load(tls_config, app_dirs.root())?;
+            info!("Sync connections use TLS, this device's certificate fingerprint is {}", identity.fingerprint());
+            (Some(identity.acceptor()?), Some(tls::connector(&tls_config.trusted)?), Some(identity.fingerprint()))
         }
+        None => (None, None, None),
+    };
+    let server = SyncServer::new(config.server.port).with_listeners(config.server.listeners()).with_chaos(chaos.clone()).with_health(health.clone()).with_tombstone_retention(config.index.tombstone_retention_days).with_file_manager(file_manager.clone()).with_groups(groups.clone()).with_chunk_store(chunk_store.clone()).with_codec(config.sync.compression).with_tls(tls_acceptor).with_device_keys(DeviceKeys::new(&config.sync.all_devices())).with_port_mapping(config.server.port_mapping.clone(), port_mappings).with_relay(config.server.relay.clone(), config.sync.local_name()).with_relay_key(config.server.relay_key.clone()).with_device_name(config.sync.local_name()).with_edition(config.paths.edition()).with_reachability(reachability.clone()).with_write_window(WriteWindow::new(config.performance.receive_window.max(1))).with_busy_worlds(busy.clone()).with_warm_up(health.clone()).with_conflict_detection(transfer_queue.clone()).with_versions(versions.clone());
+    let pending_deletions = PendingDeletions::new(app_dirs.deletions_file());
+    let server = if config.deletions.deferred() { server.with_pending_deletions(pending_deletions.clone()) } else { server };
+    let connections = Connections::new().with_streams(streams);
+    let network = config.network_change.enabled.then(NetworkChanges::new);
+    let connect = {
+        let (chaos, codec, name, edition, connections, reachability) = (chaos.clone(), config.sync.compression, config.sync.local_name(), config.paths.edition(), connections.clone(), reachability.clone());
+        move |address| SyncClient::new(address).with_chaos(chaos.clone()).with_codec(codec).with_tls(tls_connector.clone()).with_device_name(name.clone()).with_edition(edition).with_connections(connections.clone()).with_reachability(reachability.clone()).with_cpu_budget(cpu.clone())
+    };
+    
+    let server = Arc::new(server);
+    supervisor::supervise("Sync server", move || {
+        let server = server.clone();
+        async move { server.start().await }
     });
 
-    // Create a channel to receive the events
-    let (tx, rx) = channel();
+    if let Some(rendezvous_config) = config.sync.rendezvous.clone() {
+        tokio::spawn(rendezvous::run_registration(rendezvous_config, config.server.port, connect.clone()));
+    }
+    let discovery = if config.sync.discovery {
+        match Discovery::start(&PairedDevice { name: config.sync.local_name(), port: config.server.port, fingerprint }) {
+            Ok(discovery) => {
+                info!("Announcing this device on the local network");
+                tokio::spawn(discovery::report_new(discovery.discovered(), device_names.clone(), Duration::from_secs(60)));
+                Some(discovery)
+            }
+            Err(e) => {
+                warn!("Failed to start local network discovery: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let lookup = Lookup { rendezvous: config.sync.rendezvous.clone(), discovered: discovery.as_ref().map(Discovery::discovered) };
 
-    // Create a watcher object, delivering debounced events
-    let mut watcher = RecommendedWatcher::new(tx, NotifyConfig::default().with_poll_interval(Duration::from_secs(2)))?;
+    // Each peer has its own worker, so one that is slow or unreachable
+    // does not hold up transfers to the others
+    let transfers = TransferWorker {
+        queue: transfer_queue.clone(),
+        metrics: metrics.clone(),
+        index: file_index.clone(),
+        groups: groups.clone(),
+        lookup: lookup.clone(),
+        exclusions: exclusions.clone(),
+        reachability: reachability.clone(),
+        streams,
+        limit: config.performance.max_transfers.map(|n| Arc::new(tokio::sync::Semaphore::new(n.max(1)))),
+        gaming: gaming.clone(),
+        network: network.clone().filter(|_| config.network_change.retry_peers),
+        versions: versions.clone(),
+    };
+    if config.gaming_mode.enabled {
+        tokio::spawn(gaming.clone().follow(Duration::from_secs(config.gaming_mode.check_interval.max(1)), gaming::minecraft_in_foreground));
+    }
+    for peer in &device_names {
+        for stream in 0..streams {
+            let name = if streams > 1 { format!("Transfers to {} ({}/{})", peer, stream + 1, streams) } else { format!("Transfers to {}", peer) };
+            let (peer, transfers, connect) = (peer.clone(), transfers.clone(), connect.clone());
+            supervisor::supervise(name, move || transfers.clone().run(peer.clone(), stream, connect.clone()));
+        }
+    }
+    if config.gaming_mode.pause_open_worlds {
+        let (busy, shadows, transfers, worlds, connect, name) = (busy.clone(), shadows.clone(), transfers.clone(), worlds_root.clone(), connect.clone(), config.sync.local_name());
+        let every = Duration::from_secs(config.gaming_mode.check_interval.max(1));
+        supervisor::supervise("Busy worlds", move || run_busy_worlds(busy.clone(), shadows.clone(), transfers.clone(), worlds.clone(), connect.clone(), name.clone(), every));
+    }
+    if let Some(network) = &network {
+        let (network, connections) = (network.clone(), connections.clone());
+        let every = Duration::from_secs(config.network_change.check_interval.max(1));
+        supervisor::supervise("Network changes", move || run_network_changes(network.clone(), connections.clone(), every));
+    }
+    if config.sync.atomic_apply {
+        let file_manager = file_manager.clone();
+        supervisor::supervise("Staged worlds", move || run_staged_worlds(file_manager.clone(), Duration::from_secs(5)));
+    }
+    {
+        let (cache, index, groups, name, health) = (manifest_cache.clone(), file_index.clone(), groups.clone(), config.sync.local_name(), health.clone());
+        let every = Duration::from_secs(config.sync.sync_interval.max(1));
+        let network = network.clone().filter(|_| config.network_change.reconcile);
+        supervisor::supervise("Manifest exchange", move || {
+            let (cache, index, groups, lookup, connect, name, health, network) = (cache.clone(), index.clone(), groups.clone(), lookup.clone(), connect.clone(), name.clone(), health.clone(), network.clone());
+            async move {
+                // A manifest sent before the scan would lack worlds the stored index did not know
+                Health::until_set(&health.index_scanned).await;
+                run_manifest_exchange(cache, index, groups, lookup, connect, name, SyncTicker::new(every, network)).await
+            }
+        });
+    }
+    {
+        let (file_manager, peers, index_config) = (file_manager.clone(), device_names.clone(), config.index.clone());
+        supervisor::supervise("Index compaction", move || run_compaction(file_manager.clone(), manifest_cache.clone(), chunk_store.clone(), peers.clone(), index_config.clone(), compaction_index_file.clone()));
+    }
+    if config.archive.propose_after_days > 0 {
+        let (index, proposals, health) = (file_index.clone(), ArchiveProposals::new(app_dirs.kept_file()), health.clone());
+        let untouched_for = Duration::from_secs(config.archive.propose_after_days * 86400);
+        supervisor::supervise("Archive proposals", move || {
+            let (index, proposals, health) = (index.clone(), proposals.clone(), health.clone());
+            async move {
+                Health::until_set(&health.index_scanned).await;
+                run_archive_proposals(index, proposals, untouched_for).await
+            }
+        });
+    }
+    // Also applies what was held back before the deletion settings changed
+    {
+        let (file_manager, pending, deletion_config) = (file_manager.clone(), pending_deletions.clone(), config.deletions.clone());
+        supervisor::supervise("Received deletions", move || run_pending_deletions(file_manager.clone(), pending.clone(), deletion_config.clone()));
+    }
+    if let Some(mqtt_config) = &config.mqtt {
+        let sources = StatusSources { index: file_index.clone(), aging: aging.clone(), queue: transfer_queue.clone(), busy: busy.clone(), gaming: gaming.clone(), metrics: metrics.clone(), worlds_root: worlds_root.clone() };
+        let (mqtt_config, name) = (Arc::new(mqtt_config.clone()), config.sync.local_name());
+        supervisor::supervise("MQTT status", move || run_mqtt(mqtt_config.clone(), sources.clone(), name.clone()));
+    }
+    if let Some(report_config) = &config.report {
+        let (sources, report_config) = (ReportSources::new(&config, &app_dirs)?, Arc::new(report_config.clone()));
+        supervisor::supervise("Summary reports", move || run_reports(sources.clone(), report_config.clone()));
+    }
+    {
+        let (metrics, log) = (metrics.clone(), UsageLog::new(app_dirs.usage_file()));
+        supervisor::supervise("Usage", move || run_usage(metrics.clone(), log.clone()));
+    }
+    {
+        let (aging, index, queue, groups) = (aging.clone(), file_index.clone(), transfer_queue.clone(), groups.clone());
+        supervisor::supervise("Device aging", move || run_device_aging(aging.clone(), index.clone(), queue.clone(), groups.clone()));
+    }
+    {
+        let (requests, archived, file_manager) = (Arc::new(Mutex::new(archived_rx)), archived.clone(), file_manager.clone());
+        supervisor::supervise("Archived worlds", move || run_archived(requests.clone(), archived.clone(), file_manager.clone()));
+    }
+    {
+        let (requests, index, queue, groups, mirrors) = (Arc::new(Mutex::new(sync_now_rx)), file_index.clone(), transfer_queue.clone(), groups.clone(), mirrors.clone());
+        let (busy, shadows) = (busy.clone(), (config.gaming_mode.shadow_copies && cfg!(windows)).then(|| shadows.clone()));
+        supervisor::supervise("Sync now", move || run_sync_now(requests.clone(), index.clone(), queue.clone(), groups.clone(), mirrors.clone(), busy.clone(), shadows.clone()));
+    }
+
+    // Create a bounded channel to receive the events
+    let (tx, mut rx) = watcher::channel(&config.watch, metrics.clone());
+
+    // Create a watcher object, delivering debounced events. Without one, directories are polled
+    let mut watcher = match config.watch.mode {
+        WatchMode::Events => match RecommendedWatcher::new(tx, NotifyConfig::default().with_poll_interval(Duration::from_secs(2))) {
+            Ok(watcher) => Some(watcher),
+            Err(e) => {
+                warn!("File change events are unavailable, polling instead: {}", e);
+                None
+            }
+        },
+        WatchMode::Polling => None,
+    };
+    let poll_interval = Duration::from_secs(config.watch.poll_interval.max(1));
 
-    // Try each possible path
-    for path in get_minecraft_paths() {
+    // The configured directory, or the one detected at startup
+    for path in [config.paths.worlds_dir()] {
         let worlds_path = Path::new(&path);
         info!("Checking path: {}", worlds_path.display());
         
@@ -159,11 +1840,33 @@ async fn main() -> Result<()> {
             // List worlds immediately
             list_worlds(worlds_path);
 
-            // Initial scan of files
+            // Initial scan of files: listed now, hashed in the background
             let mut file_manager_guard = file_manager.lock().await;
-            match file_manager_guard.scan_directory() {
-                Ok(files) => {
-                    info!("Found {} files to sync", files.len());
+            let changed_offline: Vec<PathBuf> = match file_manager_guard.list_directory() {
+                Ok((files, pending)) => {
+                    info!("Found {} files to sync, {} of them new or changed", files, pending);
+                    // Changed while the daemon was not running, no event tells of them
+                    let changed_offline = file_index.pending_hashes().into_iter().map(|file| file.path).collect();
+                    for world in file_index.worlds().into_iter().filter(|world| world.unclean_shutdown) {
+                        warn!("{} was not shut down cleanly, LevelDB left files it could not read in db/lost, which are not synced", world.name);
+                    }
+                    Health::set(&health.index_scanned);
+                    let index = file_index.clone();
+                    supervisor::supervise("Hashing", move || run_hashing(index.clone()));
+                    // Started once the index holds the worlds, which mirrors are compared with
+                    for mirror in &mirrors {
+                        let (mirror, queue, index, exclusions) = (mirror.clone(), transfer_queue.clone(), file_index.clone(), exclusions.clone());
+                        let every = Duration::from_secs(config.sync.sync_interval.max(1));
+                        supervisor::supervise(format!("Mirror to {}", mirror.name), move || run_mirror(mirror.clone(), queue.clone(), index.clone(), exclusions.clone(), every));
+                    }
+                    for device in &android_devices {
+                        let (device, files, index, queue, groups, mirrors) = (device.clone(), file_manager.clone(), file_index.clone(), transfer_queue.clone(), groups.clone(), mirrors.clone());
+                        let every = Duration::from_secs(config.sync.sync_interval.max(1));
+                        supervisor::supervise(format!("Android sync with {}", device.name), move || {
+                            run_android(device.clone(), files.clone(), index.clone(), queue.clone(), groups.clone(), mirrors.clone(), every)
+                        });
+                    }
+                    changed_offline
                 }
                 Err(e) => {
                     if e.to_string().contains("Access is denied") {
@@ -173,56 +1876,248 @@ async fn main() -> Result<()> {
                     }
                     continue;
                 }
-            }
+            };
             drop(file_manager_guard);
+            for relative_path in &changed_offline {
+                if let Err(e) = versions.changed(relative_path) {
+                    warn!("Failed to record the version of {}: {}", relative_path.display(), e);
+                }
+                for device in groups.devices_for(relative_path) {
+                    if !aging.is_paused(&device.name).await {
+                        transfer_queue.push(device.name.clone(), relative_path.clone(), "Startup".to_string()).await;
+                    }
+                }
+                for mirror in &mirrors {
+                    transfer_queue.push(mirror.name.clone(), relative_path.clone(), "Startup".to_string()).await;
+                }
+            }
 
-            info!("Watching directory for changes: {}", worlds_path.display());
-            if let Err(e) = watcher.watch(worlds_path, RecursiveMode::Recursive) {
-                if e.to_string().contains("Access is denied") {
-                    error!("Access denied to watch directory. Please run the program as administrator.");
+            // The other roots are watched along with the worlds, unless they do not exist
+            let mut watched_roots = Vec::new();
+            for (name, root) in &roots {
+                if root.is_dir() {
+                    watched_roots.push(root.clone());
                 } else {
-                    error!("Failed to watch directory: {}", e);
+                    warn!("Root {} does not exist, not syncing it: {}", name, root.display());
                 }
-                continue;
             }
+            let mut watched = match &mut watcher {
+                Some(watcher) => match std::iter::once(worlds_path).chain(watched_roots.iter().map(PathBuf::as_path)).try_for_each(|dir| watcher.watch(dir, RecursiveMode::Recursive)) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        if e.to_string().contains("Access is denied") {
+                            warn!("Access denied to watch directory, polling instead. Run the program as administrator to watch it.");
+                        } else {
+                            warn!("Failed to watch directory, polling instead: {}", e);
+                        }
+                        false
+                    }
+                },
+                None => false,
+            };
+            let start_polling = || {
+                let mut polls = tokio::time::interval_at(tokio::time::Instant::now() + poll_interval, poll_interval);
+                polls.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                polls
+            };
+            let mut polls = (!watched).then(start_polling);
+            for dir in std::iter::once(worlds_path).chain(watched_roots.iter().map(PathBuf::as_path)) {
+                if watched {
+                    info!("Watching directory for changes: {}", dir.display());
+                } else {
+                    info!("Scanning directory for changes every {}s: {}", poll_interval.as_secs(), dir.display());
+                }
+            }
+            Health::set(&health.roots_watched);
+            let mut availability_checks = tokio::time::interval(AVAILABILITY_INTERVAL);
+            availability_checks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut available = true;
+            // Created folders to scan again, oldest first
+            let mut new_folders: VecDeque<(tokio::time::Instant, PathBuf)> = VecDeque::new();
 
-            // Process events
+            // Process events until a shutdown signal arrives
             loop {
-                match rx.recv() {
-                    Ok(Ok(Event { kind, paths, .. })) => {
+                if shutdown.is_cancelled() {
+                    if let Err(e) = index::save(&shutdown_index_file, file_index.base_path(), file_index.generation(), file_index.entries(), file_index.tombstones()) {
+                        warn!("Failed to save index: {}", e);
+                    }
+                    shutdown_cursors.flush().await;
+                    if let Some(discovery) = &discovery {
+                        discovery.stop();
+                    }
+                    info!("Stopped");
+                    return Ok(());
+                }
+                let event = tokio::select! {
+                    _ = shutdown.cancelled() => continue,
+                    _ = availability_checks.tick() => {
+                        if file_index.check_available() == available {
+                            continue;
+                        }
+                        available = !available;
+                        if !available {
+                            // Nothing below it is taken for deleted until it returns
+                            warn!("{} is unavailable, pausing until it returns", worlds_path.display());
+                            Health::clear(&health.roots_watched);
+                            if let Some(watcher) = watcher.as_mut().filter(|_| watched) {
+                                let _ = watcher.unwatch(worlds_path);
+                            }
+                            continue;
+                        }
+                        info!("{} is available again, looking for changes", worlds_path.display());
+                        if let Some(watcher) = watcher.as_mut().filter(|_| watched) {
+                            if let Err(e) = watcher.watch(worlds_path, RecursiveMode::Recursive) {
+                                warn!("Failed to watch directory, polling instead: {}", e);
+                                watched = false;
+                                polls = Some(start_polling());
+                            }
+                        }
+                        Health::set(&health.roots_watched);
+                        Some(WatchEvent::Poll)
+                    }
+                    Some(_) = async { Some(polls.as_mut()?.tick().await) } => Some(WatchEvent::Poll),
+                    Some(path) = async {
+                        tokio::time::sleep_until(new_folders.front()?.0).await;
+                        new_folders.pop_front().map(|(_, path)| path)
+                    } => {
+                        // Moved or deleted since, which its own events tell of
+                        if !path.is_dir() {
+                            continue;
+                        }
+                        Some(WatchEvent::Change { kind: notify::EventKind::Modify(notify::event::ModifyKind::Any), paths: vec![path] })
+                    }
+                    event = rx.recv(), if watched => event,
+                };
+                if !available && event.is_some() {
+                    continue;
+                }
+                match event {
+                    Some(WatchEvent::Change { kind, paths }) => {
                         for path in paths {
+                            // Below the worlds directory or one of the roots
+                            let Some(relative_path) = file_index.relative_path(&path) else {
+                                continue;
+                            };
+                            if exclusions.is_excluded(file_index.dir_of(&relative_path), &path) {
+                                if matches!(kind, notify::EventKind::Create(_)) && leveldb::is_lost_dir(&relative_path) {
+                                    warn!("A world was not shut down cleanly, LevelDB moved files it could not read to {}, which are not synced", path.display());
+                                }
+                                continue;
+                            }
+                            // Indexed as received already
+                            if file_index.is_staged(&relative_path) {
+                                debug!("Skipping change of a file with received changes staged: {}", path.display());
+                                continue;
+                            }
+                            if relative_path.components().count() == 1 && file_index.recently_written(&path) {
+                                // Swapped in with received changes, which only mirrors still need
+                                if path.is_dir() {
+                                    for entry in file_index.entries().into_iter().filter(|entry| entry.path.starts_with(&relative_path)) {
+                                        for mirror in &mirrors {
+                                            transfer_queue.push(mirror.name.clone(), entry.path.clone(), format!("{:?}", kind)).await;
+                                        }
+                                    }
+                                }
+                                continue;
+                            }
                             info!("Change detected: {:?} - {:?}", kind, path);
                             
                             // Update file info
+                            let mut scanned = Vec::new();
+                            let (mut deleted_folder, mut renamed_from) = (false, None);
+                            // A file written to that was indexed before, as opposed to a new one
+                            let mut modified = false;
+                            // Files changed or deleted here, as opposed to touched
+                            let mut changed_here = Vec::new();
                             let mut file_manager_guard = file_manager.lock().await;
                             match fs::metadata(&path) {
+                                Ok(metadata) if metadata.is_dir() => {
+                                    // Files created along with a folder can land before it is watched
+                                    if matches!(kind, notify::EventKind::Create(_)) {
+                                        new_folders.push_back((tokio::time::Instant::now() + NEW_FOLDER_RESCAN, path.clone()));
+                                    }
+                                    match file_manager_guard.scan_subtree(&path) {
+                                        Ok(files) => scanned.extend(files.into_iter().map(|f| f.path)),
+                                        Err(e) => error!("Failed to scan {}: {}", path.display(), e),
+                                    }
+                                    changed_here.clone_from(&scanned);
+                                    if !scanned.is_empty() {
+                                        match file_manager_guard.detect_rename(&relative_path) {
+                                            Ok(from) => renamed_from = from,
+                                            Err(e) => warn!("Failed to look for where {} was moved from: {}", path.display(), e),
+                                        }
+                                    }
+                                }
                                 Ok(metadata) => {
-                                    match path.strip_prefix(worlds_path) {
-                                        Ok(relative_path) => {
-                                            match file_manager_guard.calculate_file_hash(&path) {
-                                                Ok(hash) => {
-                                                    let file_info = FileInfo {
-                                                        path: relative_path.to_path_buf(),
-                                                        last_modified: metadata.modified()?,
-                                                        size: metadata.len(),
-                                                        hash,
-                                                    };
-                                                    file_manager_guard.update_file_info(relative_path.to_path_buf(), file_info);
-                                                }
-                                                Err(e) => {
-                                                    if e.to_string().contains("Access is denied") {
-                                                        error!("Access denied to calculate file hash. Please run the program as administrator.");
-                                                    } else {
-                                                        error!("Failed to calculate file hash: {}", e);
+                                    modified = matches!(kind, notify::EventKind::Modify(_)) && file_manager_guard.get_file_info(&relative_path).is_some();
+                                    if watcher::content_unchanged(file_manager_guard.get_file_info(&relative_path).as_ref(), &metadata, &config.watch) {
+                                        debug!("Skipping attribute-only change: {}", path.display());
+                                        // Also how files received from other devices show up, which mirrors still need
+                                        for mirror in &mirrors {
+                                            transfer_queue.push(mirror.name.clone(), relative_path.clone(), format!("{:?}", kind)).await;
+                                        }
+                                        continue;
+                                    } else if !config.paths.hashing.hashes_eagerly(metadata.len()) {
+                                        // Hashed once it is compared or sent
+                                        let file_info = FileInfo {
+                                            path: relative_path.clone(),
+                                            last_modified: metadata.modified()?,
+                                            size: metadata.len(),
+                                            hash: String::new(),
+                                        };
+                                        file_manager_guard.update_file_info(relative_path.clone(), file_info);
+                                    } else {
+                                        // Paced on the blocking pool, not on the watcher's task
+                                        let (reader, hashed) = (file_index.clone(), relative_path.clone());
+                                        match tokio::task::spawn_blocking(move || reader.hash_file(&hashed)).await? {
+                                            Ok(hash) => {
+                                                let same = file_manager_guard.get_file_info(&relative_path).is_some_and(|old| old.hash == hash);
+                                                let file_info = FileInfo {
+                                                    path: relative_path.clone(),
+                                                    last_modified: metadata.modified()?,
+                                                    size: metadata.len(),
+                                                    hash,
+                                                };
+                                                file_manager_guard.update_file_info(relative_path.clone(), file_info);
+                                                if same {
+                                                    debug!("Skipping change that left the content as it was: {}", path.display());
+                                                    Metrics::inc(&metrics.churn_suppressed);
+                                                    for mirror in &mirrors {
+                                                        transfer_queue.push(mirror.name.clone(), relative_path.clone(), format!("{:?}", kind)).await;
                                                     }
+                                                    continue;
+                                                }
+                                            }
+                                            Err(e) => {
+                                                if e.to_string().contains("Access is denied") {
+                                                    error!("Access denied to calculate file hash. Please run the program as administrator.");
+                                                } else {
+                                                    error!("Failed to calculate file hash: {}", e);
                                                 }
                                             }
                                         }
-                                        Err(e) => error!("Failed to get relative path: {}", e),
                                     }
+                                    changed_here.push(relative_path.clone());
+                                }
+                                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                                    if !file_index.check_available() {
+                                        debug!("Not deleting {}, {} is unavailable", path.display(), worlds_path.display());
+                                        continue;
+                                    }
+                                    let was_file = file_manager_guard.get_file_info(&relative_path).is_some();
+                                    changed_here = if was_file {
+                                        vec![relative_path.clone()]
+                                    } else {
+                                        file_index.entries().into_iter().map(|entry| entry.path).filter(|entry| entry.starts_with(&relative_path)).collect()
+                                    };
+                                    let removed = file_manager_guard.mark_deleted(&relative_path);
+                                    deleted_folder = !was_file && removed > 0;
+                                    debug!("Deleted: {} ({} indexed files)", path.display(), removed);
                                 }
                                 Err(e) => {
-                                    if e.kind() == std::io::ErrorKind::PermissionDenied {
+                                    if interference::is_sharing_violation(&e) {
+                                        debug!("File briefly locked by another process: {}", path.display());
+                                    } else if e.kind() == std::io::ErrorKind::PermissionDenied {
                                         error!("Access denied to file metadata. Please run the program as administrator.");
                                     } else {
                                         error!("Failed to get file metadata: {}", e);
@@ -230,15 +2125,41 @@ async fn main() -> Result<()> {
                                 }
                             }
                             drop(file_manager_guard);
+                            for changed in &changed_here {
+                                if let Err(e) = versions.changed(changed) {
+                                    warn!("Failed to record the version of {}: {}", changed.display(), e);
+                                }
+                            }
 
-                            // Send change to other devices
-                            for device in &config.sync.devices {
-                                let client = SyncClient::new(device.address.clone());
-                                if let Err(e) = client.send_file_change(
-                                    PathBuf::from(path.strip_prefix(worlds_path)?),
-                                    format!("{:?}", kind)
-                                ).await {
-                                    error!("Failed to send change to {}: {}", device.name, e);
+                            // Queue change for the devices whose group syncs this world
+                            let mut changes: Vec<PathBuf> = std::iter::once(&relative_path).chain(&scanned).cloned().collect();
+                            if modified && churn.hold(&relative_path) {
+                                debug!("Holding change of {} until something else in its world changes", path.display());
+                                Metrics::inc(&metrics.low_value_held);
+                                changes.clear();
+                            } else {
+                                changes.extend(churn.release(&relative_path));
+                            }
+                            for device in groups.devices_for(&relative_path) {
+                                if aging.is_paused(&device.name).await {
+                                    continue;
+                                }
+                                // Moved rather than created, the device moves its copy
+                                if let Some(from) = renamed_from.as_ref().filter(|from| groups.devices_for(from).iter().any(|synced| synced.name == device.name)) {
+                                    transfer_queue.push(device.name.clone(), from.clone(), format!("{:?}", kind)).await;
+                                    continue;
+                                }
+                                for queued in &changes {
+                                    if deleted_folder && *queued == relative_path {
+                                        transfer_queue.push_delayed(device.name.clone(), queued.clone(), format!("{:?}", kind), file_manager::RENAME_DELAY).await;
+                                    } else {
+                                        transfer_queue.push(device.name.clone(), queued.clone(), format!("{:?}", kind)).await;
+                                    }
+                                }
+                            }
+                            for mirror in &mirrors {
+                                for queued in std::iter::once(&relative_path).chain(&scanned) {
+                                    transfer_queue.push(mirror.name.clone(), queued.clone(), format!("{:?}", kind)).await;
                                 }
                             }
 
@@ -246,8 +2167,41 @@ async fn main() -> Result<()> {
                             list_worlds(worlds_path);
                         }
                     }
-                    Ok(Err(e)) => error!("Watch error: {:?}", e),
-                    Err(e) => error!("Channel error: {:?}", e),
+                    Some(event @ (WatchEvent::Rescan | WatchEvent::Poll)) => {
+                        let rescan = matches!(event, WatchEvent::Rescan);
+                        let changed = if rescan {
+                            warn!("Missed file changes, scanning {} again", worlds_path.display());
+                            file_manager.lock().await.rescan()
+                        } else {
+                            file_manager.lock().await.poll()
+                        };
+                        match changed {
+                            Ok(changed) => {
+                                if rescan || !changed.is_empty() {
+                                    info!("Found {} changed files", changed.len());
+                                }
+                                for relative_path in &changed {
+                                    if let Err(e) = versions.changed(relative_path) {
+                                        warn!("Failed to record the version of {}: {}", relative_path.display(), e);
+                                    }
+                                    for device in groups.devices_for(relative_path) {
+                                        if !aging.is_paused(&device.name).await {
+                                            transfer_queue.push(device.name.clone(), relative_path.clone(), format!("{:?}", event)).await;
+                                        }
+                                    }
+                                    for mirror in &mirrors {
+                                        transfer_queue.push(mirror.name.clone(), relative_path.clone(), format!("{:?}", event)).await;
+                                    }
+                                }
+                                if !rescan && !changed.is_empty() {
+                                    list_worlds(worlds_path);
+                                }
+                            }
+                            Err(e) => error!("Failed to scan {}: {}", worlds_path.display(), e),
+                        }
+                    }
+                    Some(WatchEvent::Error(e)) => error!("Watch error: {:?}", e),
+                    None => anyhow::bail!("File watcher stopped"),
                 }
             }
         } else {
@@ -0,0 +1,436 @@
+use anyhow::{bail, Result};
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::Mutex;
+use log::{debug, warn};
+use crate::correlation;
+use crate::file_manager::{FileIndex, FileInfo};
+use crate::network::{PeerSession, SyncMessage};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Every file of the worlds directory with its content hash. The version is
+/// derived from the entries, so equal manifests always have equal versions.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub entries: BTreeMap<PathBuf, ManifestEntry>,
+}
+
+/// Changes that turn the manifest with `base_version` into `version`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestDelta {
+    pub base_version: String,
+    pub version: String,
+    pub changed: BTreeMap<PathBuf, ManifestEntry>,
+    pub removed: Vec<PathBuf>,
+}
+
+impl Manifest {
+    pub fn new(entries: BTreeMap<PathBuf, ManifestEntry>) -> Self {
+        let mut hasher = Sha256::new();
+        for (path, entry) in &entries {
+            hasher.update(path.to_string_lossy().as_bytes());
+            hasher.update([0]);
+            hasher.update(entry.hash.as_bytes());
+            hasher.update(entry.size.to_le_bytes());
+        }
+        let hash = hasher.finalize();
+        let version = hash[..8].iter().map(|b| format!("{:02x}", b)).collect();
+        Self { version, entries }
+    }
+
+    /// Files still waiting to be hashed are left out until they are.
+    pub fn from_files(files: &[FileInfo]) -> Self {
+        Self::new(files.iter().filter(|f| !f.hash_pending()).map(|f| (f.path.clone(), ManifestEntry { hash: f.hash.clone(), size: f.size })).collect())
+    }
+
+    /// What changed since `base`.
+    pub fn delta_from(&self, base: &Manifest) -> ManifestDelta {
+        ManifestDelta {
+            base_version: base.version.clone(),
+            version: self.version.clone(),
+            changed: self.entries.iter()
+                .filter(|(path, entry)| base.entries.get(*path) != Some(entry))
+                .map(|(path, entry)| (path.clone(), entry.clone()))
+                .collect(),
+            removed: base.entries.keys().filter(|path| !self.entries.contains_key(*path)).cloned().collect(),
+        }
+    }
+
+    /// Applies a delta made against this manifest. Fails when the delta has
+    /// another base or does not produce the announced version.
+    pub fn apply(&self, delta: &ManifestDelta) -> Result<Manifest> {
+        if delta.base_version != self.version {
+            bail!("Manifest delta is based on {}, have {}", delta.base_version, self.version);
+        }
+        let mut entries = self.entries.clone();
+        for path in &delta.removed {
+            entries.remove(path);
+        }
+        entries.extend(delta.changed.iter().map(|(path, entry)| (path.clone(), entry.clone())));
+        let manifest = Manifest::new(entries);
+        if manifest.version != delta.version {
+            bail!("Manifest delta produced {} instead of {}", manifest.version, delta.version);
+        }
+        Ok(manifest)
+    }
+}
+
+/// How a manifest went out to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestSent {
+    /// The peer already had it; only the version was exchanged.
+    Unchanged,
+    Full,
+    Delta,
+}
+
+/// Where syncing with a peer stands: the last manifest it acknowledged and
+/// the index generation it was built from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncCursor {
+    pub generation: u64,
+    /// Also when the peer was last seen.
+    pub acknowledged_at: SystemTime,
+    pub manifest: Manifest,
+    /// Tombstone retention the peer announced.
+    #[serde(default)]
+    pub peer_retention_days: Option<u64>,
+    /// Set when the peer was offline longer than the tombstone retention. It
+    /// may still have files deleted since, so nothing is exchanged with it
+    /// until a full reconcile is requested.
+    #[serde(default)]
+    pub needs_reconcile: bool,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// Built from the file manager generation in the key.
+    current: Option<(u64, Arc<Manifest>)>,
+    /// Cursor of each peer, the base for the next delta.
+    cursors: HashMap<String, SyncCursor>,
+}
+
+/// Sending side: reuses the local manifest until the files change and
+/// remembers per peer which version it acknowledged, so a reconnect only
+/// sends what changed since then. With a cursor file the cursors survive
+/// restarts.
+#[derive(Clone, Default)]
+pub struct ManifestCache {
+    state: Arc<Mutex<CacheState>>,
+    cursors_file: Option<PathBuf>,
+    writer: CursorWriter,
+}
+
+/// Writes the cursor file on the blocking pool. Saves made while a write is
+/// running replace each other, so only the latest is written next.
+#[derive(Clone, Default)]
+struct CursorWriter {
+    /// Serialized cursors not written yet.
+    pending: Arc<std::sync::Mutex<Option<Vec<u8>>>>,
+    /// Held while writing, so writes land in the order they were saved.
+    writing: Arc<std::sync::Mutex<()>>,
+}
+
+impl CursorWriter {
+    fn write_pending(&self, path: &Path) {
+        let _writing = self.writing.lock().unwrap();
+        let Some(bytes) = self.pending.lock().unwrap().take() else {
+            return;
+        };
+        if let Err(e) = write_cursors(path, &bytes) {
+            warn!("Failed to save sync cursors: {}", e);
+        }
+    }
+}
+
+impl ManifestCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A cache that keeps its cursors in `path`. A missing or unreadable
+    /// file starts without cursors, so every peer gets probed again.
+    pub fn load(path: PathBuf) -> Self {
+        let cursors = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable sync cursors in {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            state: Arc::new(Mutex::new(CacheState { current: None, cursors })),
+            cursors_file: Some(path),
+            writer: CursorWriter::default(),
+        }
+    }
+
+    pub async fn cursor(&self, peer: &str) -> Option<SyncCursor> {
+        self.state.lock().await.cursors.get(peer).cloned()
+    }
+
+    pub async fn cursors(&self) -> HashMap<String, SyncCursor> {
+        self.state.lock().await.cursors.clone()
+    }
+
+    /// Replaces every cursor, e.g. with the ones of an imported identity.
+    pub async fn restore(&self, cursors: HashMap<String, SyncCursor>) {
+        let mut state = self.state.lock().await;
+        state.cursors = cursors;
+        self.save(&state.cursors);
+    }
+
+    /// Moves the cursor of `old` to `new`; returns false if there was none.
+    pub async fn rename(&self, old: &str, new: &str) -> bool {
+        let mut state = self.state.lock().await;
+        let Some(cursor) = state.cursors.remove(old) else {
+            return false;
+        };
+        state.cursors.insert(new.to_string(), cursor);
+        self.save(&state.cursors);
+        true
+    }
+
+    /// When each peer last acknowledged a manifest.
+    pub async fn last_synced(&self) -> HashMap<String, SystemTime> {
+        self.state.lock().await.cursors.iter().map(|(peer, cursor)| (peer.clone(), cursor.acknowledged_at)).collect()
+    }
+
+    /// Queues the cursors to be written, without waiting for the disk.
+    fn save(&self, cursors: &HashMap<String, SyncCursor>) {
+        let Some(path) = self.cursors_file.clone() else {
+            return;
+        };
+        let bytes = match serde_json::to_vec(cursors) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Failed to save sync cursors: {}", e);
+                return;
+            }
+        };
+        *self.writer.pending.lock().unwrap() = Some(bytes);
+        let writer = self.writer.clone();
+        tokio::task::spawn_blocking(move || writer.write_pending(&path));
+    }
+
+    /// Waits until the cursors saved so far are on disk.
+    pub async fn flush(&self) {
+        let Some(path) = self.cursors_file.clone() else {
+            return;
+        };
+        let writer = self.writer.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || writer.write_pending(&path)).await {
+            warn!("Failed to save sync cursors: {}", e);
+        }
+    }
+
+    /// The manifest of the current entries, rebuilt only after they changed.
+    pub async fn current(&self, index: &FileIndex) -> Arc<Manifest> {
+        let mut state = self.state.lock().await;
+        let generation = index.generation();
+        match &state.current {
+            Some((built, manifest)) if *built == generation => manifest.clone(),
+            _ => {
+                let manifest = Arc::new(Manifest::from_files(&index.entries()));
+                state.current = Some((generation, manifest.clone()));
+                manifest
+            }
+        }
+    }
+
+    /// The message to send `peer`: a delta against its last acknowledged
+    /// manifest when there is one, the full manifest otherwise.
+    pub async fn prepare(&self, device: &str, peer: &str, manifest: &Manifest) -> (SyncMessage, ManifestSent) {
+        let state = self.state.lock().await;
+        match state.cursors.get(peer) {
+            Some(cursor) => {
+                let delta = manifest.delta_from(&cursor.manifest);
+                (SyncMessage::ManifestDelta { device: device.to_string(), delta, correlation_id: correlation::current() }, ManifestSent::Delta)
+            }
+            None => (SyncMessage::Manifest { device: device.to_string(), manifest: manifest.clone(), correlation_id: correlation::current() }, ManifestSent::Full),
+        }
+    }
+
+    pub async fn acknowledge(&self, peer: &str, manifest: Arc<Manifest>, peer_retention_days: Option<u64>) {
+        let mut state = self.state.lock().await;
+        let generation = match &state.current {
+            Some((generation, current)) if current.version == manifest.version => *generation,
+            _ => 0,
+        };
+        state.cursors.insert(peer.to_string(), SyncCursor {
+            generation,
+            acknowledged_at: SystemTime::now(),
+            manifest: (*manifest).clone(),
+            peer_retention_days,
+            needs_reconcile: false,
+        });
+        self.save(&state.cursors);
+    }
+
+    /// Stops exchanging with `peer` until [`reconcile`](Self::reconcile) is
+    /// called. Returns false if it was already marked or is unknown.
+    pub async fn require_reconcile(&self, peer: &str) -> bool {
+        let mut state = self.state.lock().await;
+        match state.cursors.get_mut(peer) {
+            Some(cursor) if !cursor.needs_reconcile => {
+                cursor.needs_reconcile = true;
+                self.save(&state.cursors);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub async fn needs_reconcile(&self, peer: &str) -> bool {
+        self.state.lock().await.cursors.get(peer).is_some_and(|cursor| cursor.needs_reconcile)
+    }
+
+    /// Drops the cursor of `peer`, so the next exchange compares whole
+    /// manifests again. Returns false if there was none.
+    pub async fn reconcile(&self, peer: &str) -> bool {
+        let mut state = self.state.lock().await;
+        let known = state.cursors.remove(peer).is_some();
+        if known {
+            self.save(&state.cursors);
+        }
+        known
+    }
+
+    /// Whether to probe with the version before sending anything: when the
+    /// peer is unknown it may still have this manifest, and when it
+    /// acknowledged it already there is nothing else to send.
+    pub async fn should_probe(&self, peer: &str, manifest: &Manifest) -> bool {
+        let state = self.state.lock().await;
+        state.cursors.get(peer).is_none_or(|cursor| cursor.manifest.version == manifest.version)
+    }
+
+    /// Drops what `peer` acknowledged, so the next exchange sends everything.
+    pub async fn forget(&self, peer: &str) {
+        let mut state = self.state.lock().await;
+        if state.cursors.remove(peer).is_some() {
+            self.save(&state.cursors);
+        }
+    }
+}
+
+fn write_cursors(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Sends the manifest to `peer` over `session`. The version (a hash over all
+/// entries) goes first, so an unchanged tree costs one round trip. Otherwise
+/// a delta is sent when possible, and a peer that lost its copy asks for a
+/// resync and gets the full manifest.
+pub async fn exchange(session: &mut PeerSession, cache: &ManifestCache, device: &str, peer: &str, manifest: Arc<Manifest>) -> Result<ManifestSent> {
+    if cache.needs_reconcile(peer).await {
+        bail!("{} was offline longer than the tombstone retention and needs a full reconcile", peer);
+    }
+    if cache.should_probe(peer, &manifest).await {
+        session.send(&SyncMessage::ManifestProbe { device: device.to_string(), version: manifest.version.clone(), correlation_id: correlation::current() }).await?;
+        match session.recv().await {
+            Some(SyncMessage::ManifestAck { version, retention_days, .. }) if version == manifest.version => {
+                cache.acknowledge(peer, manifest, retention_days).await;
+                return Ok(ManifestSent::Unchanged);
+            }
+            Some(SyncMessage::ManifestResync) => cache.forget(peer).await,
+            Some(other) => bail!("Unexpected reply to manifest probe from {}: {:?}", peer, other),
+            None => bail!("{} closed the connection during the manifest exchange", peer),
+        }
+    }
+
+    let (message, mut sent) = cache.prepare(device, peer, &manifest).await;
+    session.send(&message).await?;
+    loop {
+        match session.recv().await {
+            Some(SyncMessage::ManifestAck { version, retention_days, .. }) if version == manifest.version => {
+                cache.acknowledge(peer, manifest, retention_days).await;
+                return Ok(sent);
+            }
+            Some(SyncMessage::ManifestResync) if sent == ManifestSent::Delta => {
+                debug!("{} has no base for a manifest delta, sending it in full", peer);
+                cache.forget(peer).await;
+                session.send(&SyncMessage::Manifest { device: device.to_string(), manifest: (*manifest).clone(), correlation_id: correlation::current() }).await?;
+                sent = ManifestSent::Full;
+            }
+            Some(other) => bail!("Unexpected reply to manifest from {}: {:?}", peer, other),
+            None => bail!("{} closed the connection during the manifest exchange", peer),
+        }
+    }
+}
+
+/// Receiving side: the latest manifest of every device that sent one.
+#[derive(Clone, Default)]
+pub struct ManifestStore {
+    manifests: Arc<Mutex<HashMap<String, Manifest>>>,
+    /// Announced in every ack.
+    retention_days: Option<u64>,
+}
+
+impl ManifestStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_retention_days(mut self, days: u64) -> Self {
+        self.retention_days = Some(days);
+        self
+    }
+
+    fn ack(&self, version: String) -> SyncMessage {
+        SyncMessage::ManifestAck { version, retention_days: self.retention_days, correlation_id: correlation::current() }
+    }
+
+    pub async fn get(&self, device: &str) -> Option<Manifest> {
+        self.manifests.lock().await.get(device).cloned()
+    }
+
+    /// Acknowledges a probe when the stored manifest has `version`.
+    pub async fn probe(&self, device: &str, version: String) -> SyncMessage {
+        match self.manifests.lock().await.get(device) {
+            Some(manifest) if manifest.version == version => self.ack(version),
+            _ => SyncMessage::ManifestResync,
+        }
+    }
+
+    /// Stores a full manifest and returns the reply for its sender.
+    pub async fn replace(&self, device: String, manifest: Manifest) -> SyncMessage {
+        let version = manifest.version.clone();
+        self.manifests.lock().await.insert(device, manifest);
+        self.ack(version)
+    }
+
+    /// Applies a delta to the stored manifest, or asks for a resync when the
+    /// delta does not fit what is stored.
+    pub async fn apply(&self, device: String, delta: &ManifestDelta) -> SyncMessage {
+        let mut manifests = self.manifests.lock().await;
+        let updated = match manifests.get(&device) {
+            Some(base) => base.apply(delta),
+            None => return SyncMessage::ManifestResync,
+        };
+        match updated {
+            Ok(manifest) => {
+                let version = manifest.version.clone();
+                manifests.insert(device, manifest);
+                self.ack(version)
+            }
+            Err(e) => {
+                debug!("Rejecting manifest delta from {}: {}", device, e);
+                SyncMessage::ManifestResync
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use anyhow::{anyhow, bail, Result};
+use std::fs;
+use std::io::{self, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio_util::bytes::Bytes;
+use zip::ZipArchive;
+use crate::exclusions::STAGING_DIR;
+
+/// Largest .mcworld accepted for import.
+pub const MAX_UPLOAD_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Largest unpacked size of an imported world, so a small archive cannot
+/// fill the disk.
+pub const MAX_IMPORT_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| CRC_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8))
+}
+
+/// The name Minecraft shows for a world, from `levelname.txt`, or else its
+/// folder name.
+pub fn display_name(world_dir: &Path) -> String {
+    fs::read_to_string(world_dir.join("levelname.txt"))
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .or_else(|| world_dir.file_name().map(|n| n.to_string_lossy().into_owned()))
+        .unwrap_or_else(|| "world".to_string())
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?.map(|e| e.map(|e| e.path())).collect::<io::Result<_>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Writes `world_dir` as a .mcworld: a zip archive with the world's files at
+/// its root. Entries are stored, since LevelDB tables are compressed already,
+/// so nothing has to be seeked back to and the archive can be streamed.
+/// Returns the archive size.
+pub fn write(world_dir: &Path, out: &mut impl Write) -> Result<u64> {
+    let mut files = Vec::new();
+    collect_files(world_dir, &mut files)?;
+    if files.len() > u16::MAX as usize {
+        bail!("{} has too many files for a .mcworld", world_dir.display());
+    }
+
+    let mut offset = 0u64;
+    let mut central = Vec::new();
+    for path in &files {
+        let name = path.strip_prefix(world_dir)?.to_string_lossy().replace('\\', "/");
+        let data = fs::read(path)?;
+        let (crc, size) = (crc32(&data), u32::try_from(data.len())?);
+        let local_offset = u32::try_from(offset)?;
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        entry_fields(&mut header, crc, size, name.len());
+        header.extend_from_slice(&0u16.to_le_bytes());
+        header.extend_from_slice(name.as_bytes());
+        out.write_all(&header)?;
+        out.write_all(&data)?;
+        offset += (header.len() + data.len()) as u64;
+
+        central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        central.extend_from_slice(&20u16.to_le_bytes());
+        entry_fields(&mut central, crc, size, name.len());
+        // Extra field, comment, disk, internal and external attributes
+        central.extend_from_slice(&[0; 12]);
+        central.extend_from_slice(&local_offset.to_le_bytes());
+        central.extend_from_slice(name.as_bytes());
+    }
+
+    let central_offset = u32::try_from(offset)?;
+    out.write_all(&central)?;
+    let mut end = Vec::with_capacity(22);
+    end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    end.extend_from_slice(&[0; 4]);
+    end.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    end.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    end.extend_from_slice(&u32::try_from(central.len())?.to_le_bytes());
+    end.extend_from_slice(&central_offset.to_le_bytes());
+    end.extend_from_slice(&0u16.to_le_bytes());
+    out.write_all(&end)?;
+    Ok(offset + (central.len() + end.len()) as u64)
+}
+
+/// Version needed through name length, shared by local and central headers.
+fn entry_fields(buf: &mut Vec<u8>, crc: u32, size: u32, name_len: usize) {
+    buf.extend_from_slice(&20u16.to_le_bytes());
+    // UTF-8 names, stored, 1980-01-01 00:00
+    buf.extend_from_slice(&0x0800u16.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes());
+    buf.extend_from_slice(&0u16.to_le_bytes());
+    buf.extend_from_slice(&0x0021u16.to_le_bytes());
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&(name_len as u16).to_le_bytes());
+}
+
+struct ChannelWriter(mpsc::Sender<io::Result<Bytes>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.blocking_send(Ok(Bytes::copy_from_slice(buf))).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Builds the .mcworld on a blocking thread and hands it out in pieces. A
+/// failure ends the stream with an error, so a download is never silently cut.
+pub fn stream(world_dir: PathBuf) -> mpsc::Receiver<io::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(8);
+    tokio::task::spawn_blocking(move || {
+        let mut out = BufWriter::with_capacity(64 * 1024, ChannelWriter(tx.clone()));
+        let written = write(&world_dir, &mut out).and_then(|size| Ok(out.flush().map(|_| size)?));
+        if let Err(e) = written {
+            let _ = tx.blocking_send(Err(io::Error::other(e.to_string())));
+        }
+    });
+    rx
+}
+
+/// Unpacks a .mcworld into the new folder `world_dir`. The archive must hold
+/// a world, with `level.dat` at its root or in a single top folder. It is
+/// unpacked into the excluded staging folder first, so the world only
+/// appears, and is synced, once it is complete. Returns the number of files.
+pub fn import(archive: &[u8], world_dir: &Path) -> Result<usize> {
+    if world_dir.exists() {
+        bail!("{} already exists", world_dir.display());
+    }
+    let (Some(worlds), Some(name)) = (world_dir.parent(), world_dir.file_name()) else {
+        bail!("{} is not a world folder", world_dir.display());
+    };
+    let mut zip = ZipArchive::new(Cursor::new(archive))?;
+
+    let mut files = Vec::new();
+    let mut total = 0u64;
+    for i in 0..zip.len() {
+        let entry = zip.by_index_raw(i)?;
+        let path = entry.enclosed_name().ok_or_else(|| anyhow!("Unsafe path {} in the archive", entry.name()))?;
+        total = total.saturating_add(entry.size());
+        if !entry.is_dir() {
+            files.push((i, path));
+        }
+    }
+    if total > MAX_IMPORT_SIZE {
+        bail!("The world unpacks to {} bytes, more than the {} allowed", total, MAX_IMPORT_SIZE);
+    }
+    let root = if files.iter().any(|(_, p)| p == Path::new("level.dat")) {
+        PathBuf::new()
+    } else {
+        let tops: Vec<&Path> = files.iter().filter_map(|(_, p)| p.components().next().map(|c| Path::new(c.as_os_str()))).collect();
+        match tops.first() {
+            Some(top) if tops.iter().all(|t| t == top) && files.iter().any(|(_, p)| *p == top.join("level.dat")) => top.to_path_buf(),
+            _ => bail!("The archive is not a Minecraft world, it has no level.dat"),
+        }
+    };
+
+    let staging = worlds.join(STAGING_DIR).join(name);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let unpacked = (|| -> Result<()> {
+        for (i, path) in &files {
+            let mut entry = zip.by_index(*i)?;
+            let out = staging.join(path.strip_prefix(&root)?);
+            fs::create_dir_all(out.parent().unwrap_or(&staging))?;
+            let size = entry.size();
+            // The zip reader checks the CRC, this stops entries that lie about their size
+            let written = io::copy(&mut (&mut entry).take(size + 1), &mut fs::File::create(&out)?)?;
+            if written != size {
+                bail!("{} in the archive is larger than it claims", path.display());
+            }
+        }
+        fs::create_dir_all(&staging)?;
+        fs::rename(&staging, world_dir)?;
+        Ok(())
+    })();
+    if let Err(e) = unpacked {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e);
+    }
+    Ok(files.len())
+}
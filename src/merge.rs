@@ -0,0 +1,119 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::inspect;
+use crate::leveldb::{self, Contents};
+
+/// A chunk as x, z and dimension.
+pub type ChunkPos = (i32, i32, i32);
+
+/// The chunk a key is part of, counting the list of actors stored per
+/// chunk by newer versions (`digp`) along with the chunk's own records.
+fn chunk_of(key: &[u8]) -> Option<ChunkPos> {
+    if let Some(pos) = inspect::chunk(key) {
+        return Some(pos);
+    }
+    let rest = key.strip_prefix(b"digp")?;
+    let int = |at: usize| rest.get(at..at + 4).map(|b| i32::from_le_bytes(b.try_into().unwrap()));
+    match rest.len() {
+        8 => Some((int(0)?, int(4)?, 0)),
+        12 => Some((int(0)?, int(4)?, int(8)?)),
+        _ => None,
+    }
+}
+
+/// How to merge another copy of a world into this one, key by key, from
+/// the copy both started from.
+#[derive(Debug, Default)]
+pub struct Merge {
+    /// Keys the other copy changed and this one did not, with the other
+    /// copy's value, `None` where it deleted the key.
+    pub take: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Chunks in which both copies changed the same key. They stay as this
+    /// copy has them, so no chunk ends up half from each.
+    pub conflicting_chunks: BTreeSet<ChunkPos>,
+    /// Keys outside chunks that both copies changed, also kept as they are.
+    pub conflicting_keys: Vec<String>,
+}
+
+/// Reads the database of `world_dir`, refusing one that is damaged since
+/// a key that could not be read would look deleted.
+fn read(world_dir: &Path, keep: &dyn Fn(&[u8]) -> bool) -> Result<Contents> {
+    let contents = leveldb::read_keeping(&world_dir.join("db"), keep)?;
+    if let Some(damaged) = contents.damaged.first() {
+        bail!("{} is damaged: {}", damaged.file.display(), damaged.error);
+    }
+    Ok(contents)
+}
+
+/// Works out which changes of the world in `theirs_dir` can join the one
+/// in `ours_dir`, given `base_dir`, the copy both started from. LevelDB
+/// keeps the sequence number a key was written with through compactions,
+/// so a key whose number differs from the base was changed on that side.
+pub fn plan(base_dir: &Path, ours_dir: &Path, theirs_dir: &Path) -> Result<Merge> {
+    let (base, ours, theirs) = (read(base_dir, &|_| false)?, read(ours_dir, &|_| false)?, read(theirs_dir, &|_| false)?);
+    let seq = |contents: &Contents, key: &[u8]| contents.keys.get(key).map(|entry| entry.seq);
+    let mut merge = Merge::default();
+    let mut changed = Vec::new();
+    let keys: BTreeSet<&Vec<u8>> = base.keys.keys().chain(ours.keys.keys()).chain(theirs.keys.keys()).collect();
+    for key in keys {
+        let original = seq(&base, key);
+        let (ours_changed, theirs_changed) = (seq(&ours, key) != original, seq(&theirs, key) != original);
+        if !theirs_changed {
+            continue;
+        }
+        // Deleted on both sides is no conflict
+        if ours_changed && (ours.keys.contains_key(key.as_slice()) || theirs.keys.contains_key(key.as_slice())) {
+            match chunk_of(key) {
+                Some(pos) => {
+                    merge.conflicting_chunks.insert(pos);
+                }
+                None => merge.conflicting_keys.push(String::from_utf8_lossy(key).into_owned()),
+            }
+            continue;
+        }
+        if !ours_changed {
+            changed.push(key.clone());
+        }
+    }
+    changed.retain(|key| chunk_of(key).is_none_or(|pos| !merge.conflicting_chunks.contains(&pos)));
+
+    let wanted: HashSet<&[u8]> = changed.iter().map(Vec::as_slice).collect();
+    let values = read(theirs_dir, &|key| wanted.contains(key))?;
+    for key in &changed {
+        let value = match values.keys.get(key) {
+            Some(entry) => match &entry.value {
+                Some(value) => Some(value.clone()),
+                None => bail!("{} changed while it was read", String::from_utf8_lossy(key)),
+            },
+            None => None,
+        };
+        merge.take.insert(key.clone(), value);
+    }
+    Ok(merge)
+}
+
+/// Writes the changes `merge` takes into the database of `world_dir` as a
+/// new log, which Minecraft applies when it next opens the world. The world
+/// must not be open meanwhile. Returns the log written.
+pub fn apply(world_dir: &Path, merge: &Merge) -> Result<Option<PathBuf>> {
+    if merge.take.is_empty() {
+        return Ok(None);
+    }
+    let db = world_dir.join("db");
+    let contents = read(world_dir, &|_| false)?;
+    // After every file LevelDB has numbered, so it is replayed last
+    let mut number = 0u64;
+    for entry in fs::read_dir(&db)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let digits = name.rsplit_once('-').map_or(name.as_str(), |(_, n)| n);
+        if let Ok(n) = digits.split('.').next().unwrap_or("").parse::<u64>() {
+            number = number.max(n);
+        }
+    }
+    let path = db.join(format!("{:06}.log", number + 1));
+    let writes: Vec<(Vec<u8>, Option<Vec<u8>>)> = merge.take.iter().map(|(key, value)| (key.clone(), value.clone())).collect();
+    leveldb::write_log(&path, contents.last_seq + 1, &writes)?;
+    Ok(Some(path))
+}
@@ -0,0 +1,73 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::network::Sent;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub transfers_queued: AtomicU64,
+    pub transfers_sent: AtomicU64,
+    pub transfers_failed: AtomicU64,
+    pub duplicates_suppressed: AtomicU64,
+    pub churn_suppressed: AtomicU64,
+    pub low_value_held: AtomicU64,
+    pub watch_events_coalesced: AtomicU64,
+    pub watch_events_dropped: AtomicU64,
+    pub file_bytes_sent: AtomicU64,
+    pub content_bytes_sent: AtomicU64,
+    pub message_bytes_sent: AtomicU64,
+    pub wire_bytes_sent: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Counts the bytes a file delivered to a peer took.
+    pub fn record_sent(&self, sent: &Sent) {
+        Self::add(&self.file_bytes_sent, sent.size);
+        Self::add(&self.content_bytes_sent, sent.carried);
+        Self::add(&self.message_bytes_sent, sent.encoded);
+        Self::add(&self.wire_bytes_sent, sent.wire);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    /// Name, help text and counter for every exported metric.
+    pub fn counters(&self) -> Vec<(&'static str, &'static str, &AtomicU64)> {
+        vec![
+            ("transfers_queued", "Transfers added to the outgoing queue", &self.transfers_queued),
+            ("transfers_sent", "Transfers delivered to a peer", &self.transfers_sent),
+            ("transfers_failed", "Transfer attempts that failed", &self.transfers_failed),
+            ("duplicates_suppressed", "Queued transfers coalesced into an existing entry", &self.duplicates_suppressed),
+            ("churn_suppressed", "Files rewritten with the same content, not queued", &self.churn_suppressed),
+            ("low_value_held", "Changes to low value files held for the next change of their world", &self.low_value_held),
+            ("watch_events_coalesced", "Watcher events merged into a pending event for the same path", &self.watch_events_coalesced),
+            ("watch_events_dropped", "Watcher events dropped for a rescan because too many were pending", &self.watch_events_dropped),
+            ("file_bytes_sent", "Size of the files delivered to peers", &self.file_bytes_sent),
+            ("content_bytes_sent", "Bytes of those files sent, leaving out what deltas and chunks peers had spared", &self.content_bytes_sent),
+            ("message_bytes_sent", "Size of the messages that carried them, before compression", &self.message_bytes_sent),
+            ("wire_bytes_sent", "Size of those messages on the wire, after compression", &self.wire_bytes_sent),
+        ]
+    }
+
+    /// Prometheus text exposition format.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, help, counter) in self.counters() {
+            out.push_str(&format!("# HELP mcbd_{name}_total {help}\n"));
+            out.push_str(&format!("# TYPE mcbd_{name}_total counter\n"));
+            out.push_str(&format!("mcbd_{name}_total {}\n", Self::get(counter)));
+        }
+        out
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use log::{info, warn};
+use crate::app_dirs::AppDirs;
+use crate::exclusions::{STAGING_DIR, TRASH_DIR, SNAPSHOT_DIR, QUARANTINE_DIR};
+use crate::file_manager::FileManager;
+
+/// Relocates internal folders that older versions kept inside the worlds
+/// directory into the app-data directory and drops them from the index.
+/// Returns the number of folders moved.
+pub fn migrate_legacy_state(worlds_root: &Path, dirs: &AppDirs, file_manager: &mut FileManager) -> Result<usize> {
+    let targets = [
+        (STAGING_DIR, dirs.staging()),
+        (TRASH_DIR, dirs.trash()),
+        (SNAPSHOT_DIR, dirs.snapshots()),
+        (QUARANTINE_DIR, dirs.quarantine()),
+    ];
+
+    let mut moved = 0;
+    for (name, target) in targets {
+        let legacy = worlds_root.join(name);
+        if !legacy.is_dir() {
+            continue;
+        }
+
+        let destination = if target.exists() {
+            let stamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            target.join(format!("migrated-{}", stamp))
+        } else {
+            target
+        };
+
+        info!("Moving legacy {} to {}", legacy.display(), destination.display());
+        move_dir(&legacy, &destination)?;
+        file_manager.remove_entries_under(Path::new(name));
+        moved += 1;
+    }
+    Ok(moved)
+}
+
+fn move_dir(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+
+    // Different volume: copy then delete
+    warn!("Cannot rename across volumes, copying {} instead", from.display());
+    copy_dir(from, to)?;
+    fs::remove_dir_all(from)?;
+    Ok(())
+}
+
+pub fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target: PathBuf = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
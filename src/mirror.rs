@@ -0,0 +1,156 @@
+use anyhow::{bail, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use log::info;
+use crate::exclusions::STAGING_DIR;
+use crate::file_manager::{FileIndex, FileInfo};
+
+/// Numbers temporary files, so concurrent copies never share one.
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Another folder on this device, such as one on a second drive, kept an
+/// exact copy of the worlds directory as a continuous backup. Changes are
+/// queued for it like for a device, but it only ever receives: whatever
+/// differs in it is overwritten and whatever is only in it is deleted, so
+/// there is never a conflict to resolve. On the same volume as the worlds
+/// directory, files are hard-linked rather than copied, so the mirror takes
+/// next to no space.
+#[derive(Debug, Clone)]
+pub struct Mirror {
+    pub name: String,
+    pub root: PathBuf,
+    /// Cleared once the mirror turns out to be on another volume, or on a
+    /// filesystem without hard links, or if they are turned off.
+    links: Arc<AtomicBool>,
+}
+
+impl Mirror {
+    pub fn new(name: String, root: PathBuf) -> Self {
+        Self { name, root, links: Arc::new(AtomicBool::new(true)) }
+    }
+
+    /// Copies files even where they could be hard-linked, when `hard_links`
+    /// is false.
+    pub fn with_hard_links(self, hard_links: bool) -> Self {
+        self.links.store(hard_links, Ordering::Relaxed);
+        self
+    }
+
+    /// Makes the mirror's copy of `path` match the worlds directory: links or
+    /// copies a file that differs, creates a folder, and deletes what is gone.
+    /// Copies keep the modification time of the original, which is how an
+    /// unchanged copy is recognised. Returns whether anything changed.
+    pub fn apply(&self, index: &FileIndex, path: &Path) -> Result<bool> {
+        if path.as_os_str().is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            bail!("Refusing to mirror {} outside the worlds directory", path.display());
+        }
+        // Files that cannot be seen are not gone
+        if !index.is_available() {
+            bail!("The worlds directory {} is unavailable", index.base_path().display());
+        }
+        let (source, target) = (index.source_path(path), self.root.join(path));
+        let metadata = match fs::metadata(&source) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return remove(&target),
+            Err(e) => return Err(e.into()),
+        };
+        if metadata.is_dir() {
+            fs::create_dir_all(&target)?;
+            return Ok(false);
+        }
+        let modified = metadata.modified()?;
+        if fs::metadata(&target).is_ok_and(|copy| copy.is_file() && copy.len() == metadata.len() && copy.modified().ok() == Some(modified)) {
+            return Ok(false);
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if target.is_dir() {
+            fs::remove_dir_all(&target)?;
+        }
+        let staging = self.root.join(STAGING_DIR);
+        fs::create_dir_all(&staging)?;
+        let tmp = staging.join(format!("{}-{}.tmp", std::process::id(), TEMP_FILES.fetch_add(1, Ordering::Relaxed)));
+        let copied = self.link(&source, &tmp)
+            .or_else(|_| fs::copy(&source, &tmp).and_then(|_| fs::File::options().write(true).open(&tmp)?.set_modified(modified)))
+            .and_then(|_| fs::rename(&tmp, &target));
+        if copied.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        let _ = fs::remove_dir(&staging);
+        copied?;
+        Ok(true)
+    }
+
+    /// Hard-links `source` to `tmp` while the mirror's volume allows it.
+    /// A linked file is the original, so it changes along with it.
+    fn link(&self, source: &Path, tmp: &Path) -> std::io::Result<()> {
+        if !self.links.load(Ordering::Relaxed) {
+            return Err(std::io::ErrorKind::Unsupported.into());
+        }
+        let linked = fs::hard_link(source, tmp);
+        if let Err(e) = &linked {
+            if matches!(e.kind(), std::io::ErrorKind::CrossesDevices | std::io::ErrorKind::Unsupported | std::io::ErrorKind::PermissionDenied) {
+                info!("Cannot hard-link into {} ({}), copying files instead", self.name, e);
+                self.links.store(false, Ordering::Relaxed);
+            }
+        }
+        linked
+    }
+
+    /// Whether files are hard-linked into the mirror rather than copied.
+    pub fn links(&self) -> bool {
+        self.links.load(Ordering::Relaxed)
+    }
+
+    /// Paths whose copy differs from the indexed `entries`, by size or
+    /// modification time, and files only the mirror has. Applying them
+    /// brings a mirror up to date that missed changes, such as on startup.
+    pub fn differences(&self, entries: &[FileInfo]) -> Result<Vec<PathBuf>> {
+        let mut differ: Vec<PathBuf> = entries.iter()
+            .filter(|entry| !fs::metadata(self.root.join(&entry.path)).is_ok_and(|copy| {
+                copy.is_file() && copy.len() == entry.size && copy.modified().ok() == Some(entry.last_modified)
+            }))
+            .map(|entry| entry.path.clone())
+            .collect();
+        let indexed: HashSet<&Path> = entries.iter().map(|entry| entry.path.as_path()).collect();
+        let mut copies = Vec::new();
+        if self.root.is_dir() {
+            files_below(&self.root, &self.root, &mut copies)?;
+        }
+        differ.extend(copies.into_iter().filter(|path| !indexed.contains(path.as_path())));
+        Ok(differ)
+    }
+}
+
+/// Deletes the mirror's copy of something that is gone.
+fn remove(target: &Path) -> Result<bool> {
+    let removed = match fs::symlink_metadata(target) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(target),
+        Ok(_) => fs::remove_file(target),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => Err(e),
+    };
+    removed?;
+    Ok(true)
+}
+
+/// Files below `dir`, relative to `root`, leaving out the staging folder.
+fn files_below(root: &Path, dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            if entry.file_name() != STAGING_DIR {
+                files_below(root, &path, files)?;
+            }
+        } else {
+            files.push(path.strip_prefix(root)?.to_path_buf());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use anyhow::{anyhow, bail, Result};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Port of brokers given without one.
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// How long connecting to the broker may take, up to its CONNACK.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What the broker publishes for us when the connection drops without a
+/// DISCONNECT, such as when the daemon is stopped or crashes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectOptions {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Seconds the broker waits for a packet before it drops the client.
+    pub keep_alive: u16,
+    pub will: Option<Will>,
+}
+
+/// Publishes to an MQTT 3.1.1 broker. Messages go out at QoS 0, which is
+/// all that status updates, sent again and again, need.
+pub struct MqttClient {
+    stream: TcpStream,
+}
+
+impl MqttClient {
+    /// Connects to `broker`, `host` or `host:port`.
+    pub async fn connect(broker: &str, options: &ConnectOptions) -> Result<Self> {
+        let address = if broker.contains(':') { broker.to_string() } else { format!("{}:{}", broker, DEFAULT_PORT) };
+        tokio::time::timeout(CONNECT_TIMEOUT, async {
+            let mut stream = TcpStream::connect(&address).await?;
+            stream.write_all(&connect_packet(options)).await?;
+            let mut connack = [0u8; 4];
+            stream.read_exact(&mut connack).await?;
+            if connack[..2] != [0x20, 0x02] {
+                bail!("{} did not answer like an MQTT broker", address);
+            }
+            match connack[3] {
+                0 => Ok(Self { stream }),
+                1 => bail!("{} does not speak MQTT 3.1.1", address),
+                4 | 5 => bail!("{} refused the username or password", address),
+                code => bail!("{} refused the connection with code {}", address, code),
+            }
+        }).await.map_err(|_| anyhow!("{} did not answer within {} seconds", address, CONNECT_TIMEOUT.as_secs()))?
+    }
+
+    pub async fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> Result<()> {
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+        put_str(&mut body, topic.as_bytes());
+        body.extend_from_slice(payload);
+        self.stream.write_all(&packet(0x30 | u8::from(retain), &body)).await?;
+        Ok(())
+    }
+}
+
+fn connect_packet(options: &ConnectOptions) -> Vec<u8> {
+    let mut flags = 0x02; // clean session
+    let mut payload = Vec::new();
+    put_str(&mut payload, options.client_id.as_bytes());
+    if let Some(will) = &options.will {
+        // Retained, so whoever subscribes later sees the device offline
+        flags |= 0x04 | 0x20;
+        put_str(&mut payload, will.topic.as_bytes());
+        put_str(&mut payload, will.payload.as_bytes());
+    }
+    if let Some(username) = &options.username {
+        flags |= 0x80;
+        put_str(&mut payload, username.as_bytes());
+        if let Some(password) = &options.password {
+            flags |= 0x40;
+            put_str(&mut payload, password.as_bytes());
+        }
+    }
+    let mut body = Vec::new();
+    put_str(&mut body, b"MQTT");
+    body.extend_from_slice(&[4, flags]);
+    body.extend_from_slice(&options.keep_alive.to_be_bytes());
+    body.extend_from_slice(&payload);
+    packet(0x10, &body)
+}
+
+/// A packet of `kind`, the first byte of the fixed header, around `body`.
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![kind];
+    let mut remaining = body.len();
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if remaining == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &[u8]) {
+    buf.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    buf.extend_from_slice(value);
+}
@@ -0,0 +1,296 @@
+use anyhow::{bail, Result};
+use futures::Sink;
+use log::error;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_util::bytes::{Buf, BufMut, Bytes, BytesMut};
+use crate::chaos::{self, Chaos};
+use crate::compression::{self, Codec};
+use crate::cpu_budget::CpuBudget;
+use crate::transfer_queue::Priority;
+
+/// First frame of a multiplexed connection. Plain connections start with a
+/// JSON message instead, so the server can tell them apart.
+pub const PREAMBLE: &[u8] = b"MCBD-MUX/1";
+
+/// Stream id of the control channel. Bulk messages get ids from 1 upwards.
+pub const CONTROL_STREAM: u32 = 0;
+
+/// Bulk messages are cut into chunks of this size, so a control message
+/// waits for at most one chunk instead of a whole world file.
+pub const BULK_CHUNK: usize = 64 * 1024;
+
+const HEADER_LEN: usize = 5;
+const FLAG_LAST: u8 = 1;
+/// Set on every frame of a bulk message that was compressed before chunking.
+const FLAG_COMPRESSED: u8 = 2;
+/// Like `FLAG_COMPRESSED`, for zstd. Only sent once the peer said it accepts it.
+const FLAG_ZSTD: u8 = 4;
+const BULK_QUEUE: usize = 4;
+
+/// Largest message a peer may send, compressed or not, before the
+/// connection is dropped.
+pub const MAX_MESSAGE: usize = 1024 * 1024 * 1024;
+
+/// Messages a peer may have half sent at once. Writers interleave at most
+/// two bulk streams, so more than this is a broken or hostile peer.
+pub const MAX_STREAMS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Changes, manifests, pings and prompts; always sent first.
+    Control,
+    /// File contents and other large payloads.
+    Bulk,
+}
+
+/// Header (stream id, flags) followed by a piece of a message.
+pub fn encode(stream: u32, last: bool, payload: &[u8]) -> Bytes {
+    encode_frame(stream, if last { FLAG_LAST } else { 0 }, payload)
+}
+
+fn encode_frame(stream: u32, flags: u8, payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + payload.len());
+    frame.put_u32(stream);
+    frame.put_u8(flags);
+    frame.put_slice(payload);
+    frame.freeze()
+}
+
+/// A whole control message in a single frame.
+pub fn encode_control(message: Bytes) -> Bytes {
+    encode(CONTROL_STREAM, true, &message)
+}
+
+/// Joins chunked frames back into messages.
+#[derive(Debug)]
+pub struct Reassembler {
+    partial: HashMap<u32, BytesMut>,
+    max_message: usize,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self { partial: HashMap::new(), max_message: MAX_MESSAGE }
+    }
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Refuses messages larger than `max_message` bytes instead of `MAX_MESSAGE`.
+    pub fn with_max_message(mut self, max_message: usize) -> Self {
+        self.max_message = max_message;
+        self
+    }
+
+    /// Takes one frame; returns a message once its last chunk arrived,
+    /// decompressed if the sender compressed it. Fails on messages over the
+    /// size limit and on more than `MAX_STREAMS` unfinished ones.
+    pub fn push(&mut self, mut frame: BytesMut) -> Result<Option<(Channel, Bytes)>> {
+        if frame.len() < HEADER_LEN {
+            bail!("Multiplexed frame of {} bytes is shorter than its header", frame.len());
+        }
+        let stream = frame.get_u32();
+        let flags = frame.get_u8();
+        let last = flags & FLAG_LAST != 0;
+        let channel = if stream == CONTROL_STREAM { Channel::Control } else { Channel::Bulk };
+
+        let buffered = self.partial.get(&stream).map_or(0, |head| head.len());
+        if buffered + frame.len() > self.max_message {
+            self.partial.remove(&stream);
+            bail!("Multiplexed stream {} is larger than {} bytes", stream, self.max_message);
+        }
+        if !last {
+            if !self.partial.contains_key(&stream) && self.partial.len() >= MAX_STREAMS {
+                bail!("Peer has more than {} unfinished multiplexed streams", MAX_STREAMS);
+            }
+            self.partial.entry(stream).or_default().extend_from_slice(&frame);
+            return Ok(None);
+        }
+        let message = match self.partial.remove(&stream) {
+            Some(mut head) => {
+                head.extend_from_slice(&frame);
+                head.freeze()
+            }
+            None => frame.freeze(),
+        };
+        if flags & FLAG_ZSTD != 0 {
+            return Ok(Some((channel, compression::decompress_zstd(&message)?)));
+        }
+        if flags & FLAG_COMPRESSED != 0 {
+            return Ok(Some((channel, compression::decompress(&message)?)));
+        }
+        Ok(Some((channel, message)))
+    }
+}
+
+/// Queues messages for the writer task of a multiplexed connection.
+#[derive(Clone)]
+pub struct MuxSender {
+    control: mpsc::UnboundedSender<Bytes>,
+    bulk: mpsc::Sender<(Bytes, u8)>,
+    interactive: mpsc::Sender<(Bytes, u8)>,
+    /// Whether the peer accepts zstd; lz4 until it said so.
+    zstd: Arc<AtomicBool>,
+    cpu: CpuBudget,
+}
+
+impl MuxSender {
+    /// Sends on `channel`; bulk messages use the background lane.
+    pub async fn send(&self, channel: Channel, message: Bytes) -> Result<()> {
+        match channel {
+            Channel::Control => {
+                if self.control.send(message).is_err() {
+                    bail!("Multiplexed connection is closed");
+                }
+                Ok(())
+            }
+            Channel::Bulk => self.send_bulk(Priority::Background, message).await,
+        }
+    }
+
+    pub async fn send_bulk(&self, priority: Priority, message: Bytes) -> Result<()> {
+        self.queue_bulk(priority, message, 0).await
+    }
+
+    /// Compresses `message` with the codec agreed on with the peer and marks
+    /// its frames so the peer decompresses it. Returns its compressed size.
+    pub async fn send_compressed(&self, priority: Priority, message: Bytes) -> Result<usize> {
+        let (compressed, flag) = match self.codec() {
+            Codec::Zstd => (self.cpu.run_async(|| compression::compress_zstd(&message)).await?, FLAG_ZSTD),
+            Codec::Lz4 => (self.cpu.run_async(|| compression::compress(&message)).await, FLAG_COMPRESSED),
+        };
+        let len = compressed.len();
+        self.queue_bulk(priority, compressed, flag).await?;
+        Ok(len)
+    }
+
+    /// Paces compression to stay within `cpu`.
+    pub fn with_cpu_budget(mut self, cpu: CpuBudget) -> Self {
+        self.cpu = cpu;
+        self
+    }
+
+    /// Switches compressed messages to `codec`, once the peer accepted it.
+    pub fn set_codec(&self, codec: Codec) {
+        self.zstd.store(codec == Codec::Zstd, Ordering::Relaxed);
+    }
+
+    pub fn codec(&self) -> Codec {
+        if self.zstd.load(Ordering::Relaxed) { Codec::Zstd } else { Codec::Lz4 }
+    }
+
+    async fn queue_bulk(&self, priority: Priority, message: Bytes, flags: u8) -> Result<()> {
+        let lane = match priority {
+            Priority::Background => &self.bulk,
+            Priority::Interactive | Priority::Urgent => &self.interactive,
+        };
+        if lane.send((message, flags)).await.is_err() {
+            bail!("Multiplexed connection is closed");
+        }
+        Ok(())
+    }
+}
+
+/// Spawns the task that owns the write half of a connection. Pending control
+/// messages always go out before the next bulk chunk, and interactive bulk
+/// messages pause a background one until they are sent.
+pub fn spawn_writer<S>(sink: S, chaos: Option<Chaos>) -> MuxSender
+where
+    S: Sink<Bytes> + Unpin + Send + 'static,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let (control_tx, control_rx) = mpsc::unbounded_channel();
+    let (bulk_tx, bulk_rx) = mpsc::channel(BULK_QUEUE);
+    let (interactive_tx, interactive_rx) = mpsc::channel(BULK_QUEUE);
+    tokio::spawn(async move {
+        if let Err(e) = write_loop(sink, control_rx, interactive_rx, bulk_rx, chaos).await {
+            error!("Multiplexed connection writer failed: {}", e);
+        }
+    });
+    MuxSender { control: control_tx, bulk: bulk_tx, interactive: interactive_tx, zstd: Arc::new(AtomicBool::new(false)), cpu: CpuBudget::default() }
+}
+
+/// A bulk message being sent chunk by chunk.
+struct BulkStream {
+    id: u32,
+    remaining: Bytes,
+    /// Compression flags set on every frame.
+    flags: u8,
+}
+
+struct StreamIds(u32);
+
+impl StreamIds {
+    fn next(&mut self, (remaining, flags): (Bytes, u8)) -> BulkStream {
+        let id = self.0;
+        self.0 = self.0.wrapping_add(1).max(CONTROL_STREAM + 1);
+        BulkStream { id, remaining, flags }
+    }
+}
+
+async fn write_loop<S>(
+    mut sink: S,
+    mut control: mpsc::UnboundedReceiver<Bytes>,
+    mut interactive: mpsc::Receiver<(Bytes, u8)>,
+    mut bulk: mpsc::Receiver<(Bytes, u8)>,
+    chaos: Option<Chaos>,
+) -> Result<()>
+where
+    S: Sink<Bytes> + Unpin,
+    S::Error: std::error::Error + Send + Sync + 'static,
+{
+    let mut ids = StreamIds(CONTROL_STREAM + 1);
+    let mut active_interactive: Option<BulkStream> = None;
+    let mut active_background: Option<BulkStream> = None;
+    let (mut control_open, mut interactive_open, mut bulk_open) = (true, true, true);
+
+    loop {
+        while let Ok(message) = control.try_recv() {
+            chaos::send_frame(&mut sink, encode_control(message), chaos.as_ref()).await?;
+        }
+        if active_interactive.is_none() {
+            if let Ok(message) = interactive.try_recv() {
+                active_interactive = Some(ids.next(message));
+            }
+        }
+
+        // An interactive stream preempts the background one, which keeps its
+        // place and resumes once the interactive lane is empty
+        let lane = if active_interactive.is_some() { &mut active_interactive } else { &mut active_background };
+        if let Some(stream) = lane.as_mut() {
+            let chunk = stream.remaining.split_to(stream.remaining.len().min(BULK_CHUNK));
+            let last = stream.remaining.is_empty();
+            let flags = if last { FLAG_LAST } else { 0 } | stream.flags;
+            chaos::send_frame(&mut sink, encode_frame(stream.id, flags, &chunk), chaos.as_ref()).await?;
+            if last {
+                *lane = None;
+            }
+            continue;
+        }
+
+        if !control_open && !interactive_open && !bulk_open {
+            return Ok(());
+        }
+        tokio::select! {
+            biased;
+            message = control.recv(), if control_open => match message {
+                Some(message) => chaos::send_frame(&mut sink, encode_control(message), chaos.as_ref()).await?,
+                None => control_open = false,
+            },
+            message = interactive.recv(), if interactive_open => match message {
+                Some(message) => active_interactive = Some(ids.next(message)),
+                None => interactive_open = false,
+            },
+            message = bulk.recv(), if bulk_open => match message {
+                Some(message) => active_background = Some(ids.next(message)),
+                None => bulk_open = false,
+            },
+        }
+    }
+}
@@ -0,0 +1,221 @@
+use anyhow::{anyhow, bail, Result};
+
+/// Deepest nesting of lists and compounds read, far beyond anything
+/// Minecraft writes.
+const MAX_DEPTH: usize = 64;
+
+/// A value of Bedrock's little-endian NBT.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tag {
+    Byte(i8),
+    Short(i16),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(Vec<(String, Tag)>),
+    IntArray(Vec<i32>),
+    LongArray(Vec<i64>),
+}
+
+impl Tag {
+    /// The field called `name` of a compound.
+    pub fn get(&self, name: &str) -> Option<&Tag> {
+        match self {
+            Tag::Compound(fields) => fields.iter().find(|(field, _)| field == name).map(|(_, tag)| tag),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Tag]> {
+        match self {
+            Tag::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    /// Any whole number, widened.
+    pub fn as_int(&self) -> Option<i64> {
+        match *self {
+            Tag::Byte(n) => Some(n.into()),
+            Tag::Short(n) => Some(n.into()),
+            Tag::Int(n) => Some(n.into()),
+            Tag::Long(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match *self {
+            Tag::Float(n) => Some(n.into()),
+            Tag::Double(n) => Some(n),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Tag::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// The type byte the tag is stored with.
+    pub fn kind(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => 1,
+            Tag::Short(_) => 2,
+            Tag::Int(_) => 3,
+            Tag::Long(_) => 4,
+            Tag::Float(_) => 5,
+            Tag::Double(_) => 6,
+            Tag::ByteArray(_) => 7,
+            Tag::String(_) => 8,
+            Tag::List(_) => 9,
+            Tag::Compound(_) => 10,
+            Tag::IntArray(_) => 11,
+            Tag::LongArray(_) => 12,
+        }
+    }
+
+    fn write_payload(&self, out: &mut Vec<u8>) {
+        match self {
+            Tag::Byte(n) => out.extend_from_slice(&n.to_le_bytes()),
+            Tag::Short(n) => out.extend_from_slice(&n.to_le_bytes()),
+            Tag::Int(n) => out.extend_from_slice(&n.to_le_bytes()),
+            Tag::Long(n) => out.extend_from_slice(&n.to_le_bytes()),
+            Tag::Float(n) => out.extend_from_slice(&n.to_le_bytes()),
+            Tag::Double(n) => out.extend_from_slice(&n.to_le_bytes()),
+            Tag::ByteArray(bytes) => {
+                out.extend_from_slice(&(bytes.len() as i32).to_le_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Tag::String(s) => write_string(out, s),
+            Tag::List(items) => {
+                // An empty list has no items to take the type from
+                out.push(items.first().map_or(0, Tag::kind));
+                out.extend_from_slice(&(items.len() as i32).to_le_bytes());
+                for item in items {
+                    item.write_payload(out);
+                }
+            }
+            Tag::Compound(fields) => {
+                for (name, tag) in fields {
+                    out.push(tag.kind());
+                    write_string(out, name);
+                    tag.write_payload(out);
+                }
+                out.push(0);
+            }
+            Tag::IntArray(items) => {
+                out.extend_from_slice(&(items.len() as i32).to_le_bytes());
+                items.iter().for_each(|n| out.extend_from_slice(&n.to_le_bytes()));
+            }
+            Tag::LongArray(items) => {
+                out.extend_from_slice(&(items.len() as i32).to_le_bytes());
+                items.iter().for_each(|n| out.extend_from_slice(&n.to_le_bytes()));
+            }
+        }
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u16).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.pos.checked_add(len).and_then(|end| self.data.get(self.pos..end)).ok_or_else(|| anyhow!("NBT runs past the end"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into()?)
+    }
+
+    fn length(&mut self) -> Result<usize> {
+        let len = i32::from_le_bytes(self.array()?);
+        usize::try_from(len).map_err(|_| anyhow!("Negative NBT length {}", len))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = u16::from_le_bytes(self.array()?) as usize;
+        Ok(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+
+    fn payload(&mut self, kind: u8, depth: usize) -> Result<Tag> {
+        if depth > MAX_DEPTH {
+            bail!("NBT nested too deep");
+        }
+        Ok(match kind {
+            1 => Tag::Byte(i8::from_le_bytes(self.array()?)),
+            2 => Tag::Short(i16::from_le_bytes(self.array()?)),
+            3 => Tag::Int(i32::from_le_bytes(self.array()?)),
+            4 => Tag::Long(i64::from_le_bytes(self.array()?)),
+            5 => Tag::Float(f32::from_le_bytes(self.array()?)),
+            6 => Tag::Double(f64::from_le_bytes(self.array()?)),
+            7 => {
+                let len = self.length()?;
+                Tag::ByteArray(self.take(len)?.to_vec())
+            }
+            8 => Tag::String(self.string()?),
+            9 => {
+                let kind = self.array::<1>()?[0];
+                let len = self.length()?;
+                // Checked against what is left before allocating
+                if len > self.data.len() - self.pos {
+                    bail!("NBT list of {} items runs past the end", len);
+                }
+                Tag::List((0..len).map(|_| self.payload(kind, depth + 1)).collect::<Result<_>>()?)
+            }
+            10 => {
+                let mut fields = Vec::new();
+                loop {
+                    let kind = self.array::<1>()?[0];
+                    if kind == 0 {
+                        break;
+                    }
+                    let name = self.string()?;
+                    fields.push((name, self.payload(kind, depth + 1)?));
+                }
+                Tag::Compound(fields)
+            }
+            11 => {
+                let len = self.length()?;
+                Tag::IntArray(self.take(len.checked_mul(4).ok_or_else(|| anyhow!("NBT array too long"))?)?.chunks(4).map(|b| i32::from_le_bytes(b.try_into().unwrap())).collect())
+            }
+            12 => {
+                let len = self.length()?;
+                Tag::LongArray(self.take(len.checked_mul(8).ok_or_else(|| anyhow!("NBT array too long"))?)?.chunks(8).map(|b| i64::from_le_bytes(b.try_into().unwrap())).collect())
+            }
+            other => bail!("Unknown NBT tag type {}", other),
+        })
+    }
+}
+
+/// Reads the named root tag at the start of `data`, the way Bedrock stores
+/// player and level data.
+pub fn read(data: &[u8]) -> Result<(String, Tag)> {
+    let mut reader = Reader { data, pos: 0 };
+    let kind = reader.array::<1>()?[0];
+    let name = reader.string()?;
+    Ok((name, reader.payload(kind, 0)?))
+}
+
+/// Writes `tag` as a named root tag, the way `read` reads it.
+pub fn write(name: &str, tag: &Tag) -> Vec<u8> {
+    let mut out = vec![tag.kind()];
+    write_string(&mut out, name);
+    tag.write_payload(&mut out);
+    out
+}
@@ -1,76 +1,1065 @@
 use anyhow::Result;
 use tokio::net::{TcpListener, TcpStream};
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use futures::{SinkExt, StreamExt};
+use futures::future::{self, BoxFuture};
+use futures::{Sink, SinkExt, Stream, StreamExt};
 use serde::{Serialize, Deserialize};
-use std::path::PathBuf;
-use log::{info, error};
-use tokio_util::bytes::Bytes;
+use std::collections::{BTreeMap, VecDeque};
+use std::path::{Component, Path, PathBuf};
+use log::{info, error, debug, warn};
+use tokio_util::bytes::{Bytes, BytesMut};
+use std::net::SocketAddr;
+use crate::rendezvous::RendezvousRegistry;
+use crate::manifest::{Manifest, ManifestDelta, ManifestEntry, ManifestStore};
+use crate::chaos::{self, Chaos};
+use crate::correlation::{self, CorrelationId};
+use crate::busy::BusyWorlds;
+use crate::cpu_budget::CpuBudget;
+use crate::health::Health;
+use crate::file_manager::{self, FileIndex, FileInfo, FileManager, Tombstone};
+use crate::deletions::{PendingDeletion, PendingDeletions};
+use crate::delta::{self, BlockSignature, DeltaOp};
+use crate::chunk_store::{self, Chunk, ChunkStore};
+use crate::flow_control::{Credits, WriteWindow};
+use crate::groups::{GroupTag, Groups};
+use crate::config::{ListenersConfig, PortMappingConfig};
+use crate::auth::{DeviceAuth, DeviceKeys};
+use crate::pairing::PairedDevice;
+use crate::port_mapping::{self, Mappings, Protocol};
+use crate::relay;
+use crate::tls;
+use crate::transport::{self, PeerStream, QuicLink};
+use crate::compression::{self, Codec};
+use crate::mux::{self, Channel, MuxSender, Reassembler};
+use tokio::sync::{mpsc, Mutex, MutexGuard};
+use crate::transfer_queue::{self, Priority, TransferQueue};
+use crate::conflicts::Resolution;
+use crate::wire::{self, Format};
+use crate::connections::Connections;
+use crate::reconnect::Reachability;
+use crate::platform::Edition;
+use crate::versions::{Order, VersionVector, Versions};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SyncMessage {
     FileChange {
         path: PathBuf,
         change_type: String,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
     },
     FileContent {
         path: PathBuf,
+        #[serde(with = "serde_bytes")]
         content: Vec<u8>,
+        /// Sync group the content belongs to; absent for the default group.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<GroupTag>,
+        /// When the sender last changed the file, in milliseconds since the
+        /// Unix epoch, to settle conflicts with.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified: Option<u64>,
+        /// The changes of each device the sender's copy includes, which
+        /// settle conflicts before `modified` does.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<VersionVector>,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    /// `path` was deleted, a file or a whole folder such as a world. Sent to
+    /// peers with `CAPABILITY_DELETES`, others get a `FileChange`.
+    FileDeleted {
+        path: PathBuf,
+        /// The files deleted, with the versions they had.
+        files: Vec<DeletedFile>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<GroupTag>,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    /// The folder `from` was moved to `to`, with the files of `files`. Sent
+    /// to peers with `CAPABILITY_RENAMES` in place of the deletion and every
+    /// file again. Answered with `FileReceived` for `from` once moved, and
+    /// with `FileFailed` when the receiver's copy differs, and the sender
+    /// falls back to sending both.
+    FileRenamed {
+        from: PathBuf,
+        to: PathBuf,
+        files: Vec<DeletedFile>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<GroupTag>,
+        /// Tag of `to`, as `group` is that of `from`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to_group: Option<GroupTag>,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    /// Reply once a `FileContent`, `BlockData` or `ChunkData` was written to
+    /// disk and read back as it was sent, and to a `FileChange` or
+    /// `FileDeleted` from a peer with `CAPABILITY_ACKS` once it was handled.
+    FileReceived {
+        path: PathBuf,
+    },
+    /// Reply instead of `FileReceived` to a peer with `CAPABILITY_RESULTS`
+    /// when the file could not be written or did not read back as sent. The
+    /// connection stays open; other peers see it closed instead.
+    FileFailed {
+        path: PathBuf,
+        reason: String,
+        /// The ID of the message that failed, so both peers log it alike.
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    /// Asks for the checksums of the receiver's copy of a file, so only the
+    /// blocks it lacks are sent. Answered with `BlockSignatures`.
+    BlockRequest {
+        path: PathBuf,
+        block_size: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<GroupTag>,
+    },
+    /// Empty when the receiver has no copy, and the whole file is sent instead.
+    BlockSignatures {
+        path: PathBuf,
+        blocks: Vec<BlockSignature>,
+    },
+    /// Content-defined chunks of a file, answered with a `ChunkRequest` for
+    /// the ones the receiver has not stored yet.
+    ChunkList {
+        path: PathBuf,
+        chunks: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<GroupTag>,
+    },
+    ChunkRequest {
+        path: PathBuf,
+        missing: Vec<String>,
+        /// The receiver stores chunks as they arrive, so missing ones can be
+        /// sent ahead in `ChunkPart`s that survive a dropped connection.
+        #[serde(default)]
+        resumable: bool,
+        /// Parts a peer with `CAPABILITY_CREDITS` may send ahead before it
+        /// waits for them to be stored.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        credits: Option<usize>,
+    },
+    /// Missing chunks of a large file sent ahead of its `ChunkData`. Each
+    /// part is stored before it is acknowledged, so an interrupted transfer
+    /// resumes after the last acknowledged part, even across restarts.
+    ChunkPart {
+        path: PathBuf,
+        chunks: Vec<Chunk>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<GroupTag>,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    ChunkPartStored {
+        path: PathBuf,
+        count: usize,
+        /// Further parts the sender may send ahead, fewer while the
+        /// receiver's writes fall behind.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        credits: Option<usize>,
+    },
+    /// A file as its chunk list, with the chunks the receiver asked for.
+    ChunkData {
+        path: PathBuf,
+        /// Hash of the rebuilt file.
+        hash: String,
+        chunks: Vec<String>,
+        data: Vec<Chunk>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<GroupTag>,
+        /// As in `FileContent`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<VersionVector>,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    /// A guest asking for the files of a shared world, answered with a
+    /// `WorldListing`.
+    WorldRequest {
+        world: String,
+        group: GroupTag,
+    },
+    WorldListing {
+        world: String,
+        entries: BTreeMap<PathBuf, ManifestEntry>,
+    },
+    /// A guest pulling one file, answered with `FilePart`s.
+    FileRequest {
+        path: PathBuf,
+        group: GroupTag,
+    },
+    /// Piece of a pulled file. Files are split so no reply exceeds a frame.
+    FilePart {
+        path: PathBuf,
+        #[serde(with = "serde_bytes")]
+        bytes: Vec<u8>,
+        last: bool,
+    },
+    /// A file rebuilt from blocks of the receiver's copy and new data.
+    BlockData {
+        path: PathBuf,
+        /// Hash of the rebuilt file.
+        hash: String,
+        block_size: u32,
+        ops: Vec<DeltaOp>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<GroupTag>,
+        /// As in `FileContent`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        modified: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<VersionVector>,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    SyncRequest {
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    SyncResponse {
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    RendezvousRegister {
+        id: String,
+        port: u16,
+    },
+    RendezvousLookup {
+        id: String,
+    },
+    RendezvousAddress {
+        id: String,
+        address: Option<String>,
+    },
+    Manifest {
+        device: String,
+        manifest: Manifest,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    ManifestDelta {
+        device: String,
+        delta: ManifestDelta,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    /// Version of the sender's manifest, answered with an ack when the
+    /// receiver holds the same one.
+    ManifestProbe {
+        device: String,
+        version: String,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    ManifestAck {
+        version: String,
+        /// Tombstone retention of the receiver, so both sides keep deletions
+        /// for the longer of their two retentions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retention_days: Option<u64>,
+        #[serde(default)]
+        correlation_id: Option<CorrelationId>,
+    },
+    /// The receiver cannot apply a delta and needs the full manifest.
+    ManifestResync,
+    /// First message on a multiplexed connection, with the codecs the sender
+    /// decompresses, best first, the protocol versions it speaks and its
+    /// optional features. Answered with the receiver's own. Peers that
+    /// predate it ignore it, and both sides keep using lz4.
+    Hello {
+        codecs: Vec<String>,
+        #[serde(default = "legacy_protocol")]
+        protocol: u32,
+        /// Oldest version the sender still speaks.
+        #[serde(default = "legacy_protocol")]
+        min_protocol: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        device: Option<String>,
+        /// Missing from peers that predate it, which have `LEGACY_CAPABILITIES`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capabilities: Option<Vec<String>>,
+        /// Which Minecraft the sender's worlds belong to, if it knows.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        edition: Option<Edition>,
+    },
+    /// Proves which device is connecting. Sent as the very first frame, before
+    /// the multiplexing preamble, to daemons that require authentication.
+    Auth {
+        auth: DeviceAuth,
+    },
+    /// Key exchange of `pair`, which runs on its own listener.
+    PairKey {
+        public_key: Vec<u8>,
+    },
+    /// The joining device's commitment to its `PairConfirm`, sent before
+    /// the accepting device reveals its own.
+    PairCommit {
+        commitment: String,
+    },
+    /// A pairing device introducing itself, proving it knows the code. The
+    /// joining device adds the nonce that opens its commitment.
+    PairConfirm {
+        device: PairedDevice,
+        proof: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        nonce: Option<String>,
+    },
+    /// Opens the control connection of a device accepting connections
+    /// through a relay, and keeps it alive. `auth` proves the name to a
+    /// relay with device keys.
+    RelayListen {
+        device: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auth: Option<DeviceAuth>,
+    },
+    /// Asks a relay for a connection to a listening device.
+    RelayConnect {
+        device: String,
+    },
+    /// Tells a listening device to open a connection for `token`.
+    RelayIncoming {
+        token: String,
+    },
+    /// The connection a listening device opened for `token`.
+    RelayAccept {
+        token: String,
+    },
+    /// The relay joined the connection to the device, everything after
+    /// this comes from it.
+    RelayReady,
+    RelayRefused {
+        reason: String,
+    },
+    /// Sent on an idle session every `HEARTBEAT_INTERVAL` to peers with
+    /// `CAPABILITY_HEARTBEAT`, answered with a `Pong`.
+    Ping,
+    Pong,
+    /// The worlds Minecraft has open on the sender, whose changes it sends
+    /// once the game closes them. Sent to peers with `CAPABILITY_BUSY`
+    /// whenever they change.
+    WorldsBusy {
+        device: String,
+        worlds: Vec<String>,
+    },
+    /// Reply to a request that needs the receiver's index while its initial
+    /// scan still runs, to peers with `CAPABILITY_WARMUP`. Others see the
+    /// connection closed instead, either way nothing empty is answered.
+    WarmingUp {
+        /// Seconds after which to ask again.
+        retry_after: u64,
+    },
+    /// Every change to `world` the sender had queued for the receiver was
+    /// delivered. Sent to peers with `CAPABILITY_STAGING`, which swap in the
+    /// copy of the world they received the changes into.
+    WorldSynced {
+        world: String,
     },
-    SyncRequest,
-    SyncResponse,
 }
 
+impl SyncMessage {
+    /// Which channel of a multiplexed connection carries this message. File
+    /// contents go on bulk so they never delay changes and lookups.
+    pub fn channel(&self) -> Channel {
+        match self {
+            SyncMessage::FileContent { .. } | SyncMessage::BlockData { .. } | SyncMessage::ChunkData { .. } | SyncMessage::ChunkPart { .. } => Channel::Bulk,
+            _ => Channel::Control,
+        }
+    }
+
+    /// Whether the file carried by this message compresses well enough to
+    /// be worth the CPU, judged by its extension and an entropy sample.
+    pub fn worth_compressing(&self) -> bool {
+        match self {
+            SyncMessage::FileContent { path, content, .. } => compression::should_compress(path, content),
+            SyncMessage::ChunkData { path, data, .. } | SyncMessage::ChunkPart { path, chunks: data, .. } => {
+                data.first().is_some_and(|chunk| compression::should_compress(path, &chunk.bytes))
+            }
+            SyncMessage::BlockData { path, ops, .. } => ops.iter().any(|op| match op {
+                DeltaOp::Data { bytes } => compression::should_compress(path, bytes),
+                DeltaOp::Copy { .. } => false,
+            }),
+            _ => false,
+        }
+    }
+
+    /// Bytes of file content the message carries, leaving out what a delta
+    /// copies from the peer's own copy.
+    pub fn file_bytes(&self) -> u64 {
+        match self {
+            SyncMessage::FileContent { content, .. } => content.len() as u64,
+            SyncMessage::ChunkData { data, .. } | SyncMessage::ChunkPart { chunks: data, .. } => data.iter().map(|chunk| chunk.bytes.len() as u64).sum(),
+            SyncMessage::BlockData { ops, .. } => delta::literal_len(ops) as u64,
+            _ => 0,
+        }
+    }
+
+    /// The sync session or transfer this message belongs to, if it carries one.
+    pub fn correlation_id(&self) -> Option<&CorrelationId> {
+        match self {
+            SyncMessage::FileChange { correlation_id, .. }
+            | SyncMessage::FileDeleted { correlation_id, .. }
+            | SyncMessage::FileRenamed { correlation_id, .. }
+            | SyncMessage::FileContent { correlation_id, .. }
+            | SyncMessage::BlockData { correlation_id, .. }
+            | SyncMessage::ChunkData { correlation_id, .. }
+            | SyncMessage::ChunkPart { correlation_id, .. }
+            | SyncMessage::FileFailed { correlation_id, .. }
+            | SyncMessage::Manifest { correlation_id, .. }
+            | SyncMessage::ManifestDelta { correlation_id, .. }
+            | SyncMessage::ManifestProbe { correlation_id, .. }
+            | SyncMessage::ManifestAck { correlation_id, .. }
+            | SyncMessage::SyncRequest { correlation_id }
+            | SyncMessage::SyncResponse { correlation_id } => correlation_id.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Version of the sync protocol, raised whenever a change would be misread
+/// by older peers.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest protocol version still spoken. Version 1 is every peer from
+/// before versions were exchanged.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Sending files as block deltas, `BlockRequest` and `BlockData`.
+pub const CAPABILITY_DELTA: &str = "delta";
+/// Sending files as content-addressed chunks, `ChunkList` and `ChunkData`.
+pub const CAPABILITY_CHUNKS: &str = "chunks";
+/// Reading messages encoded as MessagePack, see `wire::Format`.
+pub const CAPABILITY_MSGPACK: &str = "msgpack";
+/// Answering `Ping`, and closing sessions that stop sending them.
+pub const CAPABILITY_HEARTBEAT: &str = "heartbeat";
+/// Understanding `FileFailed` replies.
+pub const CAPABILITY_RESULTS: &str = "results";
+/// Acknowledging a `FileChange` with `FileReceived`, so the sender knows it
+/// arrived rather than only that it was written to the socket.
+pub const CAPABILITY_ACKS: &str = "acks";
+/// Sending `ChunkPart`s ahead as far as the receiver grants credits.
+pub const CAPABILITY_CREDITS: &str = "credits";
+/// Understanding `WorldsBusy`.
+pub const CAPABILITY_BUSY: &str = "busy";
+/// Understanding `WarmingUp` replies.
+pub const CAPABILITY_WARMUP: &str = "warmup";
+/// Understanding `WorldSynced`.
+pub const CAPABILITY_STAGING: &str = "staging";
+/// Understanding `FileDeleted`.
+pub const CAPABILITY_DELETES: &str = "deletes";
+/// Understanding `FileRenamed`.
+pub const CAPABILITY_RENAMES: &str = "renames";
+
+/// Optional features this build supports, announced in `Hello`.
+pub const CAPABILITIES: &[&str] = &[CAPABILITY_DELTA, CAPABILITY_CHUNKS, CAPABILITY_MSGPACK, CAPABILITY_HEARTBEAT, CAPABILITY_RESULTS, CAPABILITY_ACKS, CAPABILITY_CREDITS, CAPABILITY_BUSY, CAPABILITY_WARMUP, CAPABILITY_STAGING, CAPABILITY_DELETES, CAPABILITY_RENAMES];
+
+/// What peers that do not announce their capabilities support.
+pub const LEGACY_CAPABILITIES: &[&str] = &[CAPABILITY_DELTA, CAPABILITY_CHUNKS];
+
+/// How long a session waits for the peer's `Hello` before taking it for a
+/// peer that predates it.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a session to a peer with `CAPABILITY_HEARTBEAT` is pinged.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A session nothing arrived on for this long is taken for dead and closed,
+/// on both sides. Long enough for a peer busy writing a large file.
+pub const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn legacy_protocol() -> u32 {
+    1
+}
+
+/// This device's `Hello`.
+fn hello(codec: Codec, device: Option<String>, edition: Option<Edition>) -> SyncMessage {
+    SyncMessage::Hello {
+        codecs: codec.accepted(),
+        protocol: PROTOCOL_VERSION,
+        min_protocol: MIN_PROTOCOL_VERSION,
+        device,
+        capabilities: Some(CAPABILITIES.iter().map(|c| c.to_string()).collect()),
+        edition,
+    }
+}
+
+/// Fails if a peer's worlds belong to another edition of Minecraft than
+/// this device's, which are not synced with each other. Devices that do
+/// not know their edition sync with any.
+pub fn check_edition(ours: Option<Edition>, theirs: Option<Edition>, peer: Option<&str>) -> Result<()> {
+    if let (Some(ours), Some(theirs)) = (ours, theirs) {
+        if ours != theirs {
+            anyhow::bail!("{} syncs {} worlds and this device {} worlds, which are not synced with each other", peer.unwrap_or("The peer"), theirs, ours);
+        }
+    }
+    Ok(())
+}
+
+/// How long peers are asked to wait while the initial scan runs.
+pub const WARM_UP_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// A request the peer could not answer yet, as it told in a `WarmingUp`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WarmingUp {
+    pub retry_after: Duration,
+}
+
+impl std::fmt::Display for WarmingUp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer is still scanning its worlds, retry after {}s", self.retry_after.as_secs())
+    }
+}
+
+impl std::error::Error for WarmingUp {}
+
+/// A file the peer received but could not write or verify, as it told in a
+/// `FileFailed`. `SyncClient::send_file_content` fails with it, so callers
+/// can tell it from a connection that failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRejected {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+impl std::fmt::Display for FileRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Peer could not apply {}: {}", self.path.display(), self.reason)
+    }
+}
+
+impl std::error::Error for FileRejected {}
+
+/// Which version of a file is sent, see `SyncClient::send_file_version`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileVersion {
+    /// When the file was last changed here.
+    pub modified: Option<SystemTime>,
+    pub vector: Option<VersionVector>,
+}
+
+impl FileVersion {
+    fn millis(&self) -> Option<u64> {
+        self.modified.and_then(|at| at.duration_since(UNIX_EPOCH).ok()).map(|since| since.as_millis() as u64)
+    }
+}
+
+/// What sending a file to a peer took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sent {
+    /// Size of the file.
+    pub size: u64,
+    /// Bytes of the file that went to the peer, fewer than `size` where it
+    /// went as a delta or the peer had some of its chunks already.
+    pub carried: u64,
+    /// Size of the messages that carried them, as encoded.
+    pub encoded: u64,
+    /// Size of those messages on the wire, after compression.
+    pub wire: u64,
+}
+
+impl Sent {
+    fn add(&mut self, message: &SyncMessage, (encoded, wire): (u64, u64)) {
+        self.carried += message.file_bytes();
+        self.encoded += encoded;
+        self.wire += wire;
+    }
+}
+
+/// A file in a `FileDeleted` or `FileRenamed`, with the hash of the version
+/// deleted or moved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeletedFile {
+    pub path: PathBuf,
+    pub hash: String,
+}
+
+impl From<Tombstone> for DeletedFile {
+    fn from(tombstone: Tombstone) -> Self {
+        Self { path: tombstone.path, hash: tombstone.hash }
+    }
+}
+
+/// What a peer announced about itself in its `Hello`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerInfo {
+    /// Protocol version both sides use, the newer one both speak.
+    pub protocol: u32,
+    pub device: Option<String>,
+    pub capabilities: Vec<String>,
+}
+
+impl PeerInfo {
+    /// A peer that predates `Hello` or did not answer it.
+    pub fn legacy() -> Self {
+        PeerInfo { protocol: legacy_protocol(), device: None, capabilities: LEGACY_CAPABILITIES.iter().map(|c| c.to_string()).collect() }
+    }
+
+    /// Checks the versions a peer speaks against this build's, so peers
+    /// that cannot understand each other stop before misreading anything.
+    pub fn negotiate(protocol: u32, min_protocol: u32, device: Option<String>, capabilities: Option<Vec<String>>) -> Result<Self> {
+        let agreed = protocol.min(PROTOCOL_VERSION);
+        if agreed < min_protocol.max(MIN_PROTOCOL_VERSION) {
+            let peer = device.as_deref().unwrap_or("The peer");
+            anyhow::bail!("{} speaks sync protocol {} to {} and this device {} to {}, update the older one", peer, min_protocol, protocol, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+        }
+        let capabilities = capabilities.unwrap_or_else(|| LEGACY_CAPABILITIES.iter().map(|c| c.to_string()).collect());
+        Ok(PeerInfo { protocol: agreed, device, capabilities })
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Largest piece of a file pulled by a guest or sent ahead as chunks, well
+/// below the frame limit once encoded.
+const FILE_PART: usize = 1024 * 1024;
+
+/// How long to wait before listening on a relay again after losing it.
+const RELAY_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
 pub struct SyncServer {
-    port: u16,
+    listeners: ListenersConfig,
+    rendezvous: RendezvousRegistry,
+    manifests: ManifestStore,
+    chaos: Option<Chaos>,
+    health: Option<Arc<Health>>,
+    files: Option<Arc<Mutex<FileManager>>>,
+    chunks: Option<ChunkStore>,
+    groups: Groups,
+    codec: Codec,
+    tls: Option<TlsAcceptor>,
+    keys: DeviceKeys,
+    port_mapping: Option<(PortMappingConfig, Mappings)>,
+    /// Relay address and the name to listen under there.
+    relay: Option<(String, String)>,
+    /// Key proving that name to the relay.
+    relay_key: Option<String>,
+    /// Name announced to peers.
+    name: Option<String>,
+    edition: Option<Edition>,
+    /// Read side of `files`, taken from it once the server starts.
+    index: OnceLock<FileIndex>,
+    reachability: Option<Reachability>,
+    write_window: WriteWindow,
+    busy: BusyWorlds,
+    /// Set when requests that need the index wait for the initial scan.
+    warm_up: Option<Arc<Health>>,
+    /// What this device still has to send, telling changes that conflict.
+    queue: Option<Arc<TransferQueue>>,
+    /// Where received deletions wait instead of being applied right away.
+    deletions: Option<PendingDeletions>,
+    /// The versions of files, which settle conflicts before the queue does.
+    versions: Option<Versions>,
 }
 
 impl SyncServer {
     pub fn new(port: u16) -> Self {
-        Self { port }
+        Self {
+            listeners: ListenersConfig::tcp(port),
+            rendezvous: RendezvousRegistry::new(),
+            manifests: ManifestStore::new(),
+            chaos: None,
+            health: None,
+            files: None,
+            chunks: None,
+            groups: Groups::default(),
+            codec: Codec::default(),
+            tls: None,
+            keys: DeviceKeys::default(),
+            port_mapping: None,
+            relay: None,
+            relay_key: None,
+            name: None,
+            edition: None,
+            index: OnceLock::new(),
+            reachability: None,
+            write_window: WriteWindow::default(),
+            busy: BusyWorlds::default(),
+            warm_up: None,
+            queue: None,
+            deletions: None,
+            versions: None,
+        }
+    }
+
+    /// Where to record when each device was last heard from.
+    pub fn with_reachability(mut self, reachability: Reachability) -> Self {
+        self.reachability = Some(reachability);
+        self
+    }
+
+    /// Replaces the default TCP listener with the configured transports.
+    pub fn with_listeners(mut self, listeners: ListenersConfig) -> Self {
+        self.listeners = listeners;
+        self
+    }
+
+    pub fn with_chaos(mut self, chaos: Option<Chaos>) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    pub fn with_health(mut self, health: Arc<Health>) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    /// Where received file contents are stored. Without it they are dropped.
+    pub fn with_file_manager(mut self, files: Arc<Mutex<FileManager>>) -> Self {
+        self.files = Some(files);
+        self
+    }
+
+    /// Where chunks of received files are kept, so content this daemon
+    /// already has is not sent again.
+    pub fn with_chunk_store(mut self, chunks: ChunkStore) -> Self {
+        self.chunks = Some(chunks);
+        self
+    }
+
+    /// Sync groups received file contents are checked against. Without any,
+    /// contents for every path are accepted.
+    pub fn with_groups(mut self, groups: Groups) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// Best codec to accept compressed file contents in.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Only accepts TCP connections that complete a TLS handshake.
+    pub fn with_tls(mut self, tls: Option<TlsAcceptor>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Keys of the devices allowed to connect. When any device has one,
+    /// connections that do not authenticate first are closed.
+    pub fn with_device_keys(mut self, keys: DeviceKeys) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// Forwards the listener ports on the router once they are bound,
+    /// keeping the mappings held in `mappings`.
+    pub fn with_port_mapping(mut self, config: Option<PortMappingConfig>, mappings: Mappings) -> Self {
+        self.port_mapping = config.map(|config| (config, mappings));
+        self
+    }
+
+    /// Name this device announces to connecting peers.
+    pub fn with_device_name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Edition of Minecraft the worlds belong to. Peers announcing another
+    /// one are turned away.
+    pub fn with_edition(mut self, edition: Option<Edition>) -> Self {
+        self.edition = edition;
+        self
+    }
+
+    /// Also accepts peers through the relay at `relay`, listening there as
+    /// `device`, for when nobody can connect to this device directly.
+    pub fn with_relay(mut self, relay: Option<String>, device: String) -> Self {
+        self.relay = relay.map(|relay| (relay, device));
+        self
     }
 
+    /// Key the relay has configured for this device, which keeps others
+    /// from listening under its name.
+    pub fn with_relay_key(mut self, key: Option<String>) -> Self {
+        self.relay_key = key;
+        self
+    }
+
+    /// Shares `window` among the peers sending chunks, which lets a slow
+    /// disk set how far ahead they send.
+    pub fn with_write_window(mut self, window: WriteWindow) -> Self {
+        self.write_window = window;
+        self
+    }
+
+    /// Where the worlds peers report open are kept.
+    pub fn with_busy_worlds(mut self, busy: BusyWorlds) -> Self {
+        self.busy = busy;
+        self
+    }
+
+    /// Settles changes to a world that was changed here too, with the
+    /// conflict resolution of the file manager. A received change conflicts
+    /// when `queue` still has changes to the same world for its sender.
+    pub fn with_conflict_detection(mut self, queue: Arc<TransferQueue>) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Compares the version vector peers send with the one `versions` has
+    /// for the file: a change made from this device's version is taken, one
+    /// this device's version was made from is dropped, and only changes made
+    /// without seeing each other are conflicts, whatever the clocks say.
+    /// Files without a version on both sides fall back to the queue.
+    pub fn with_versions(mut self, versions: Versions) -> Self {
+        self.versions = Some(versions);
+        self
+    }
+
+    /// Holds received deletions in `pending` for the daemon to apply after
+    /// the grace period or a confirmation, see `DeletionConfig`.
+    pub fn with_pending_deletions(mut self, pending: PendingDeletions) -> Self {
+        self.deletions = Some(pending);
+        self
+    }
+
+    /// Answers requests that need the index, such as a guest's listing,
+    /// with `WarmingUp` until `health` has the initial scan done, rather
+    /// than from an index that does not hold every world yet.
+    pub fn with_warm_up(mut self, health: Arc<Health>) -> Self {
+        self.warm_up = Some(health);
+        self
+    }
+
+    /// Tombstone retention to announce to peers sending manifests.
+    pub fn with_tombstone_retention(mut self, days: u64) -> Self {
+        self.manifests = self.manifests.with_retention_days(days);
+        self
+    }
+
+    /// Binds every configured transport, then serves them all until one fails.
     pub async fn start(&self) -> Result<()> {
-        let listener = TcpListener::bind(format!("0.0.0.0:{}", self.port)).await?;
-        info!("Sync server listening on port {}", self.port);
+        if let Some(files) = &self.files {
+            let _ = self.index.set(files.lock().await.index());
+        }
+        let mut accept_loops: Vec<BoxFuture<'_, Result<()>>> = Vec::new();
+        if let Some(tcp) = &self.listeners.tcp {
+            let listener = TcpListener::bind(("0.0.0.0", tcp.port)).await?;
+            info!("Sync server listening on port {}{}", tcp.port, if self.tls.is_some() { " with TLS" } else { "" });
+            accept_loops.push(Box::pin(self.accept_tcp(listener)));
+        }
+        if let Some(websocket) = &self.listeners.websocket {
+            let listener = TcpListener::bind(("0.0.0.0", websocket.port)).await?;
+            info!("Sync server listening for WebSocket on port {}", websocket.port);
+            accept_loops.push(Box::pin(self.accept_websocket(listener)));
+        }
+        if let Some(quic) = &self.listeners.quic {
+            let endpoint = quinn::Endpoint::server(transport::quic_server_config(quic, self.tls.as_ref())?, SocketAddr::from(([0, 0, 0, 0], quic.port)))?;
+            info!("Sync server listening for QUIC on port {}", quic.port);
+            accept_loops.push(Box::pin(self.accept_quic(endpoint)));
+        }
+        if let Some((relay, device)) = &self.relay {
+            accept_loops.push(Box::pin(self.accept_relayed(relay, device)));
+        }
+        if accept_loops.is_empty() {
+            anyhow::bail!("No sync listeners configured");
+        }
+        if let Some((config, mappings)) = &self.port_mapping {
+            let ports = [
+                self.listeners.tcp.as_ref().map(|tcp| (Protocol::Tcp, tcp.port)),
+                self.listeners.websocket.as_ref().map(|websocket| (Protocol::Tcp, websocket.port)),
+                self.listeners.quic.as_ref().map(|quic| (Protocol::Udp, quic.port)),
+            ];
+            accept_loops.push(Box::pin(port_mapping::keep_mapped(ports.into_iter().flatten().collect(), config.clone(), mappings.clone())));
+        }
+        if let Some(health) = &self.health {
+            Health::set(&health.listener_bound);
+        }
+
+        future::try_join_all(accept_loops).await?;
+        Ok(())
+    }
 
+    async fn accept_tcp(&self, listener: TcpListener) -> Result<()> {
         loop {
             let (socket, addr) = listener.accept().await?;
             info!("New connection from {}", addr);
-            
+            self.serve_tcp(socket, addr);
+        }
+    }
+
+    fn serve_tcp(&self, socket: TcpStream, addr: SocketAddr) {
+        let server = self.connection_context();
+        let Some(tls) = self.tls.clone() else {
+            tokio::spawn(server.serve(Framed::new(socket, LengthDelimitedCodec::new()), addr));
+            return;
+        };
+        tokio::spawn(async move {
+            match tls.accept(socket).await {
+                Ok(stream) => server.serve(Framed::new(stream, LengthDelimitedCodec::new()), addr).await,
+                Err(e) => error!("TLS handshake with {} failed: {}", addr, e),
+            }
+        });
+    }
+
+    /// Connections through a relay are served like direct TCP ones. The
+    /// control connection to the relay is reopened whenever it is lost.
+    async fn accept_relayed(&self, relay: &str, device: &str) -> Result<()> {
+        loop {
+            let listened = relay::listen(relay, device, self.relay_key.as_deref(), |socket, addr| {
+                info!("New connection through relay {}", addr);
+                self.serve_tcp(socket, addr);
+            }).await;
+            match listened {
+                Ok(()) => warn!("Relay {} closed the connection, reconnecting", relay),
+                Err(e) => warn!("Lost relay {} ({}), reconnecting", relay, e),
+            }
+            tokio::time::sleep(RELAY_RETRY_INTERVAL).await;
+        }
+    }
+
+    async fn accept_websocket(&self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (socket, addr) = listener.accept().await?;
+            info!("New WebSocket connection from {}", addr);
+            let server = self.connection_context();
+            tokio::spawn(async move {
+                match tokio_tungstenite::accept_async(socket).await {
+                    Ok(ws) => server.serve(transport::websocket_frames(ws), addr).await,
+                    Err(e) => error!("WebSocket handshake with {} failed: {}", addr, e),
+                }
+            });
+        }
+    }
+
+    /// Every bidirectional stream of a QUIC connection is handled like a
+    /// separate TCP connection.
+    async fn accept_quic(&self, endpoint: quinn::Endpoint) -> Result<()> {
+        while let Some(incoming) = endpoint.accept().await {
+            let server = self.connection_context();
             tokio::spawn(async move {
-                if let Err(e) = Self::handle_connection(socket).await {
-                    error!("Error handling connection from {}: {}", addr, e);
+                let connection = match incoming.await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        error!("QUIC handshake failed: {}", e);
+                        return;
+                    }
+                };
+                let addr = connection.remote_address();
+                info!("New QUIC connection from {}", addr);
+                while let Ok((send, recv)) = connection.accept_bi().await {
+                    let stream = tokio::io::join(recv, send);
+                    let server = server.clone();
+                    tokio::spawn(async move {
+                        server.serve(Framed::new(stream, LengthDelimitedCodec::new()), addr).await;
+                    });
                 }
             });
         }
+        Ok(())
     }
 
-    async fn handle_connection(socket: TcpStream) -> Result<()> {
-        let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
+    fn connection_context(&self) -> ConnectionContext {
+        ConnectionContext {
+            rendezvous: self.rendezvous.clone(),
+            manifests: self.manifests.clone(),
+            chaos: self.chaos.clone(),
+            files: self.files.clone(),
+            index: self.index.get().cloned(),
+            chunks: self.chunks.clone(),
+            groups: self.groups.clone(),
+            codec: self.codec,
+            keys: self.keys.clone(),
+            name: self.name.clone(),
+            edition: self.edition,
+            reachability: self.reachability.clone(),
+            results: false,
+            acks: false,
+            flow_control: false,
+            credits: Arc::new(Credits::new(self.write_window.clone())),
+            busy: self.busy.clone(),
+            warm_up: self.warm_up.clone(),
+            warmup: false,
+            queue: self.queue.clone(),
+            deletions: self.deletions.clone(),
+            versions: self.versions.clone(),
+            device: None,
+        }
+    }
 
-        while let Some(msg) = framed.next().await {
+    async fn handle_connection<C, E>(mut conn: C, addr: SocketAddr, mut context: ConnectionContext) -> Result<()>
+    where
+        C: Stream<Item = Result<BytesMut, E>> + Sink<Bytes> + Unpin,
+        <C as Sink<Bytes>>::Error: std::error::Error + Send + Sync + 'static,
+        E: std::fmt::Display,
+    {
+        // A connection that opens with the preamble is multiplexed: messages
+        // arrive as chunked frames and replies go out on the control channel
+        let mut reassembler: Option<Reassembler> = None;
+        let mut first = true;
+        let mut authenticated = !context.keys.required();
+        // Who the peer is, once it authenticated or said so in its Hello
+        let mut device: Option<String> = None;
+        let mut heartbeats = false;
+        loop {
+            let msg = if heartbeats {
+                match tokio::time::timeout(HEARTBEAT_TIMEOUT, conn.next()).await {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        warn!("{} stopped sending heartbeats, closing the connection", addr);
+                        break;
+                    }
+                }
+            } else {
+                conn.next().await
+            };
+            let Some(msg) = msg else {
+                break;
+            };
             match msg {
+                Ok(bytes) if !authenticated => match wire::decode(&bytes) {
+                    Ok((SyncMessage::Auth { auth }, _)) => {
+                        context.keys.verify(&auth, SystemTime::now())?;
+                        debug!("{} authenticated as {}", addr, auth.device);
+                        authenticated = true;
+                        device = Some(auth.device);
+                        context.seen(device.as_deref());
+                    }
+                    _ => anyhow::bail!("{} did not authenticate", addr),
+                },
+                Ok(bytes) if first && bytes[..] == *mux::PREAMBLE => {
+                    debug!("Multiplexed connection from {}", addr);
+                    reassembler = Some(Reassembler::new());
+                    first = false;
+                }
                 Ok(bytes) => {
-                    if let Ok(message) = serde_json::from_slice::<SyncMessage>(&bytes) {
-                        match message {
-                            SyncMessage::FileChange { path, change_type } => {
-                                info!("Received file change: {} - {}", path.display(), change_type);
-                                // TODO: Handle file change
-                            }
-                            SyncMessage::FileContent { path, content: _ } => {
-                                info!("Received file content for: {}", path.display());
-                                // TODO: Save file content
-                            }
-                            SyncMessage::SyncRequest => {
-                                info!("Received sync request");
-                                // TODO: Send current state
-                            }
-                            SyncMessage::SyncResponse => {
-                                info!("Received sync response");
-                                // TODO: Handle sync response
-                            }
-                        }
+                    first = false;
+                    let payload = match reassembler.as_mut() {
+                        Some(reassembler) => match reassembler.push(bytes)? {
+                            Some((_, message)) => message,
+                            None => continue,
+                        },
+                        None => bytes.freeze(),
+                    };
+                    let Ok((message, format)) = wire::decode(&payload) else {
+                        continue;
+                    };
+                    if let SyncMessage::Hello { device: announced, capabilities, .. } = &message {
+                        heartbeats = capabilities.iter().flatten().any(|c| c == CAPABILITY_HEARTBEAT);
+                        context.results = capabilities.iter().flatten().any(|c| c == CAPABILITY_RESULTS);
+                        context.acks = capabilities.iter().flatten().any(|c| c == CAPABILITY_ACKS);
+                        context.flow_control = capabilities.iter().flatten().any(|c| c == CAPABILITY_CREDITS);
+                        context.warmup = capabilities.iter().flatten().any(|c| c == CAPABILITY_WARMUP);
+                        device = device.or_else(|| announced.clone());
+                    }
+                    context.seen(device.as_deref());
+                    context.device.clone_from(&device);
+                    if reassembler.is_some() {
+                        let mut control = (&mut conn).with(|frame: Bytes| future::ready(Ok::<_, <C as Sink<Bytes>>::Error>(mux::encode_control(frame))));
+                        Self::handle_message(message, format, &mut control, addr, &context).await?;
+                    } else {
+                        Self::handle_message(message, format, &mut conn, addr, &context).await?;
                     }
                 }
                 Err(e) => {
@@ -82,39 +1071,1222 @@ impl SyncServer {
 
         Ok(())
     }
+
+    /// Handles one message, replying in the format it came in.
+    async fn handle_message<C>(message: SyncMessage, format: Format, framed: &mut C, addr: SocketAddr, context: &ConnectionContext) -> Result<()>
+    where
+        C: Sink<Bytes> + Unpin,
+        C::Error: std::error::Error + Send + Sync + 'static,
+    {
+        // Log lines for this message carry the sender's correlation ID
+        let correlation_id = message.correlation_id().cloned();
+        let handle = async move {
+            match message {
+                SyncMessage::WorldRequest { .. } | SyncMessage::FileRequest { .. } | SyncMessage::SyncRequest { .. } if context.warming_up() => {
+                    if !context.warmup {
+                        anyhow::bail!("Still scanning the worlds, closing the connection from {} to be retried", addr);
+                    }
+                    debug!("Asking {} to retry, the worlds are still being scanned", addr);
+                    let reply = SyncMessage::WarmingUp { retry_after: WARM_UP_RETRY_AFTER.as_secs() };
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::FileChange { path, change_type, .. } => {
+                    info!("Received file change: {} - {}", path.display(), change_type);
+                    // TODO: Handle file change
+                    if context.acks {
+                        chaos::send_frame(framed, format.encode(&SyncMessage::FileReceived { path })?, context.chaos.as_ref()).await?;
+                    }
+                }
+                SyncMessage::FileDeleted { path, files: deleted, group, .. } => {
+                    if let Err(e) = context.groups.authorize(group.as_ref(), &path) {
+                        anyhow::bail!("Refusing deletion of {}: {}", path.display(), e);
+                    }
+                    if let Some(outside) = deleted.iter().find(|file| !file.path.starts_with(&path)) {
+                        anyhow::bail!("Refusing deletion of {}, it is not in {}", outside.path.display(), path.display());
+                    }
+                    let Some(files) = &context.files else {
+                        warn!("Dropping deletion of {}, no worlds directory to delete it from", path.display());
+                        return Ok(());
+                    };
+                    let received = context.receive_deletion(files, &path, deleted).await;
+                    let reply = context.file_reply(path, received)?;
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::FileRenamed { from, to, files: moved, group, to_group, .. } => {
+                    for (path, group) in [(&from, &group), (&to, &to_group)] {
+                        if let Err(e) = context.groups.authorize(group.as_ref(), path) {
+                            anyhow::bail!("Refusing move of {} to {}: {}", from.display(), to.display(), e);
+                        }
+                    }
+                    if let Some(outside) = moved.iter().find(|file| !file.path.starts_with(&from)) {
+                        anyhow::bail!("Refusing move of {}, {} is not in it", from.display(), outside.path.display());
+                    }
+                    let Some(files) = &context.files else {
+                        warn!("Dropping move of {}, no worlds directory to move it in", from.display());
+                        return Ok(());
+                    };
+                    let reply = match context.receive_rename(files, &from, &to, moved).await {
+                        Ok(true) => SyncMessage::FileReceived { path: from },
+                        Ok(false) => SyncMessage::FileFailed { path: from, reason: "Not moved, the copy here differs".to_string(), correlation_id: correlation::current() },
+                        Err(e) => context.file_reply(from, Err(e))?,
+                    };
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::FileContent { path, content, group, modified, version, .. } => {
+                    if let Err(e) = context.groups.authorize(group.as_ref(), &path) {
+                        anyhow::bail!("Refusing {}: {}", path.display(), e);
+                    }
+                    let Some(files) = &context.files else {
+                        warn!("Dropping file content for {}, no worlds directory to store it in", path.display());
+                        return Ok(());
+                    };
+                    let theirs = |_: &FileManager| FileInfo { path: path.clone(), last_modified: modified_at(modified), size: content.len() as u64, hash: file_manager::hash_bytes(&content) };
+                    let received = match context.lock_for_receiving(files, &path, theirs, version.as_ref()).await {
+                        Ok((mut files, resolution)) => {
+                            let received = context.receive_resolved(&mut files, &path, &resolution, &content).await;
+                            context.record_version(&path, &resolution, version.as_ref(), received)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match received {
+                        Ok(true) => {
+                            info!("Received {} ({} bytes)", path.display(), content.len());
+                            context.store_chunks(&content);
+                        }
+                        Ok(false) => debug!("Already have {}", path.display()),
+                        Err(_) => {}
+                    }
+                    let reply = context.file_reply(path, received)?;
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::BlockRequest { path, block_size, group } => {
+                    if let Err(e) = context.groups.authorize(group.as_ref(), &path) {
+                        anyhow::bail!("Refusing block request for {}: {}", path.display(), e);
+                    }
+                    let blocks = match &context.files {
+                        Some(files) => files.lock().await.block_signatures(&path, block_size as usize)?,
+                        None => Vec::new(),
+                    };
+                    let reply = SyncMessage::BlockSignatures { path, blocks };
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::BlockData { path, hash, block_size, ops, group, modified, version, .. } => {
+                    if let Err(e) = context.groups.authorize(group.as_ref(), &path) {
+                        anyhow::bail!("Refusing {}: {}", path.display(), e);
+                    }
+                    let Some(files) = &context.files else {
+                        warn!("Dropping delta for {}, no worlds directory to store it in", path.display());
+                        return Ok(());
+                    };
+                    let theirs = |files: &FileManager| FileInfo {
+                        path: path.clone(),
+                        last_modified: modified_at(modified),
+                        size: delta::rebuilt_len(&ops, block_size as usize, files.get_file_info(&path).map_or(0, |ours| ours.size)),
+                        hash: hash.clone(),
+                    };
+                    let received = match context.lock_for_receiving(files, &path, theirs, version.as_ref()).await {
+                        Ok((mut files, resolution)) => {
+                            let received = match &resolution {
+                                Resolution::Take => files.receive_delta(&path, block_size as usize, &ops, &hash),
+                                Resolution::Keep => Ok(false),
+                                // Diverted or merged, from the same file the delta was made for
+                                _ => match files.get_file_content(&path).and_then(|base| delta::apply(&base, block_size as usize, &ops)) {
+                                    Ok(content) if file_manager::hash_bytes(&content) == hash => context.receive_resolved(&mut files, &path, &resolution, &content).await,
+                                    Ok(_) => Err(anyhow::anyhow!("Delta for {} does not reproduce the sender's file", path.display())),
+                                    Err(e) => Err(e),
+                                },
+                            };
+                            context.record_version(&path, &resolution, version.as_ref(), received)
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match received {
+                        Ok(true) => info!("Received {} as a delta ({} new bytes)", path.display(), delta::literal_len(&ops)),
+                        Ok(false) => debug!("Already have {}", path.display()),
+                        Err(_) => {}
+                    }
+                    let reply = context.file_reply(path, received)?;
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::ChunkList { path, chunks, group } => {
+                    if let Err(e) = context.groups.authorize(group.as_ref(), &path) {
+                        anyhow::bail!("Refusing chunks for {}: {}", path.display(), e);
+                    }
+                    let missing = match &context.chunks {
+                        Some(store) => store.missing(&chunks),
+                        None => ChunkStore::all_unique(&chunks),
+                    };
+                    debug!("Missing {} of {} chunks of {}", missing.len(), chunks.len(), path.display());
+                    let resumable = context.chunks.is_some();
+                    let credits = (resumable && context.flow_control && !missing.is_empty()).then(|| context.credits.open(&path));
+                    let reply = SyncMessage::ChunkRequest { path, missing, resumable, credits };
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::ChunkPart { path, chunks, group, .. } => {
+                    if let Err(e) = context.groups.authorize(group.as_ref(), &path) {
+                        anyhow::bail!("Refusing chunks for {}: {}", path.display(), e);
+                    }
+                    let Some(store) = &context.chunks else {
+                        anyhow::bail!("Received chunks of {} without a chunk store", path.display());
+                    };
+                    let mut stored = 0;
+                    for chunk in &chunks {
+                        // A part sent again after its acknowledgement was lost is not hashed twice
+                        if store.put_received(&chunk.hash, &chunk.bytes).map_err(|e| anyhow::anyhow!("{} of {}", e, path.display()))? {
+                            stored += 1;
+                        }
+                    }
+                    debug!("Stored {} chunks of {}, {} already stored", stored, path.display(), chunks.len() - stored);
+                    let credits = context.flow_control.then(|| context.credits.stored(&path));
+                    let reply = SyncMessage::ChunkPartStored { path, count: chunks.len(), credits };
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::ChunkData { path, hash, chunks, data, group, modified, version, .. } => {
+                    context.credits.close(&path);
+                    if let Err(e) = context.groups.authorize(group.as_ref(), &path) {
+                        anyhow::bail!("Refusing {}: {}", path.display(), e);
+                    }
+                    let Some(files) = &context.files else {
+                        warn!("Dropping chunks of {}, no worlds directory to store them in", path.display());
+                        return Ok(());
+                    };
+                    // Delivered before: nothing to assemble
+                    if files.lock().await.has_content(&path, &hash) {
+                        debug!("Already have {}", path.display());
+                        let received = context.record_version(&path, &Resolution::Take, version.as_ref(), Ok(false));
+                        let reply = context.file_reply(path, received)?;
+                        chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                        return Ok(());
+                    }
+                    let content = chunk_store::assemble(&chunks, &data, context.chunks.as_ref())?;
+                    let received = if file_manager::hash_bytes(&content) != hash {
+                        Err(anyhow::anyhow!("Chunks of {} do not reproduce the sender's file", path.display()))
+                    } else {
+                        let theirs = |_: &FileManager| FileInfo { path: path.clone(), last_modified: modified_at(modified), size: content.len() as u64, hash: hash.clone() };
+                        match context.lock_for_receiving(files, &path, theirs, version.as_ref()).await {
+                            Ok((mut files, resolution)) => {
+                                let received = context.receive_resolved(&mut files, &path, &resolution, &content).await;
+                                context.record_version(&path, &resolution, version.as_ref(), received)
+                            }
+                            Err(e) => Err(e),
+                        }
+                    };
+                    match received {
+                        Ok(true) => {
+                            info!("Received {} ({} bytes, {} in new chunks)", path.display(), content.len(), data.iter().map(|c| c.bytes.len()).sum::<usize>());
+                            context.store_received_chunks(&data);
+                        }
+                        Ok(false) => debug!("Already have {}", path.display()),
+                        Err(_) => {}
+                    }
+                    let reply = context.file_reply(path, received)?;
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::WorldRequest { world, group } => {
+                    if let Err(e) = context.groups.authorize_read(&group, Path::new(&world)) {
+                        anyhow::bail!("Refusing to list {}: {}", world, e);
+                    }
+                    let entries = match &context.index {
+                        Some(index) => index.entries()
+                            .into_iter()
+                            .filter(|f| f.path.starts_with(&world))
+                            // Files not hashed yet are hashed now, or left out if they cannot be read
+                            .filter_map(|f| if f.hash_pending() { index.hashed(&f.path).ok().flatten() } else { Some(f) })
+                            .map(|f| (f.path, ManifestEntry { hash: f.hash, size: f.size }))
+                            .collect(),
+                        None => BTreeMap::new(),
+                    };
+                    info!("Guest of {} is pulling {} ({} files)", group.name, world, entries.len());
+                    let reply = SyncMessage::WorldListing { world, entries };
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::FileRequest { path, group } => {
+                    if let Err(e) = context.groups.authorize_read(&group, &path) {
+                        anyhow::bail!("Refusing to send {}: {}", path.display(), e);
+                    }
+                    let content = match &context.index {
+                        Some(index) => {
+                            if index.get(&path).is_none() {
+                                anyhow::bail!("{} is not an indexed file", path.display());
+                            }
+                            index.get_file_content(&path)?
+                        }
+                        None => anyhow::bail!("No worlds directory to send {} from", path.display()),
+                    };
+                    let mut parts = content.chunks(FILE_PART).peekable();
+                    if parts.peek().is_none() {
+                        let reply = SyncMessage::FilePart { path: path.clone(), bytes: Vec::new(), last: true };
+                        chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                    }
+                    while let Some(part) = parts.next() {
+                        let reply = SyncMessage::FilePart { path: path.clone(), bytes: part.to_vec(), last: parts.peek().is_none() };
+                        chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                    }
+                }
+                SyncMessage::FileReceived { .. }
+                | SyncMessage::FileFailed { .. }
+                | SyncMessage::BlockSignatures { .. }
+                | SyncMessage::ChunkRequest { .. }
+                | SyncMessage::ChunkPartStored { .. }
+                | SyncMessage::WorldListing { .. }
+                | SyncMessage::FilePart { .. }
+                | SyncMessage::WarmingUp { .. } => {
+                    debug!("Ignoring unsolicited file reply");
+                }
+                SyncMessage::SyncRequest { .. } => {
+                    info!("Received sync request");
+                    // TODO: Send current state
+                }
+                SyncMessage::SyncResponse { .. } => {
+                    info!("Received sync response");
+                    // TODO: Handle sync response
+                }
+                SyncMessage::RendezvousRegister { id, port } => {
+                    let observed = SocketAddr::new(addr.ip(), port);
+                    debug!("Rendezvous registration: {} -> {}", id, observed);
+                    // Only a name proven with a key can claim an ID
+                    let owner = if context.keys.required() { context.device.as_deref() } else { None };
+                    if let Err(e) = context.rendezvous.register(id, observed, owner).await {
+                        warn!("Refused rendezvous registration from {}: {}", addr, e);
+                    }
+                }
+                SyncMessage::RendezvousLookup { id } => {
+                    let address = context.rendezvous.lookup(&id).await.map(|a| a.to_string());
+                    debug!("Rendezvous lookup: {} -> {:?}", id, address);
+                    let reply = SyncMessage::RendezvousAddress { id, address };
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::RendezvousAddress { .. } => {
+                    debug!("Ignoring unsolicited rendezvous address");
+                }
+                SyncMessage::Manifest { device, manifest, .. } => {
+                    debug!("Received full manifest {} from {} ({} files)", manifest.version, device, manifest.entries.len());
+                    let reply = context.manifests.replace(device, manifest).await;
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::ManifestProbe { device, version, .. } => {
+                    let reply = context.manifests.probe(&device, version).await;
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::ManifestDelta { device, delta, .. } => {
+                    debug!("Received manifest delta {} -> {} from {} ({} changed, {} removed)", delta.base_version, delta.version, device, delta.changed.len(), delta.removed.len());
+                    let reply = context.manifests.apply(device, &delta).await;
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::ManifestAck { .. } | SyncMessage::ManifestResync => {
+                    debug!("Ignoring unsolicited manifest reply");
+                }
+                SyncMessage::Auth { .. } => {
+                    debug!("Ignoring authentication that was not needed or came late");
+                }
+                SyncMessage::PairKey { .. } | SyncMessage::PairCommit { .. } | SyncMessage::PairConfirm { .. } => {
+                    debug!("Ignoring pairing from {}, the daemon does not pair", addr);
+                }
+                SyncMessage::RelayListen { .. }
+                | SyncMessage::RelayConnect { .. }
+                | SyncMessage::RelayIncoming { .. }
+                | SyncMessage::RelayAccept { .. }
+                | SyncMessage::RelayReady
+                | SyncMessage::RelayRefused { .. } => {
+                    debug!("Ignoring relay message from {}, relays run with `mcbd-world-sync relay`", addr);
+                }
+                SyncMessage::Ping => {
+                    chaos::send_frame(framed, format.encode(&SyncMessage::Pong)?, context.chaos.as_ref()).await?;
+                }
+                SyncMessage::Pong => {}
+                SyncMessage::WorldsBusy { device, worlds } => {
+                    if worlds.is_empty() {
+                        info!("Minecraft closed the worlds open on {}", device);
+                    } else {
+                        info!("{} has {} open in Minecraft, its changes to them come once it closes them", device, worlds.join(", "));
+                    }
+                    context.busy.set_peer(&device, worlds);
+                }
+                SyncMessage::WorldSynced { world } => {
+                    let Some(files) = &context.files else {
+                        return Ok(());
+                    };
+                    match files.lock().await.commit_staged(&world) {
+                        Ok(true) => info!("Applied the changes received for {}", world),
+                        Ok(false) => debug!("Nothing to apply for {}, or it is open in Minecraft", world),
+                        Err(e) => warn!("Failed to apply the changes received for {}: {}", world, e),
+                    }
+                }
+                SyncMessage::Hello { codecs, protocol, min_protocol, device, capabilities, edition } => {
+                    debug!("Peer {} accepts {}", addr, codecs.join(", "));
+                    // Answered either way, so the peer can tell why it is closed
+                    let reply = hello(context.codec, context.name.clone(), context.edition);
+                    chaos::send_frame(framed, format.encode(&reply)?, context.chaos.as_ref()).await?;
+                    check_edition(context.edition, edition, device.as_deref())?;
+                    let peer = PeerInfo::negotiate(protocol, min_protocol, device, capabilities)?;
+                    debug!("Peer {} ({}) uses protocol {}", addr, peer.device.as_deref().unwrap_or("unnamed"), peer.protocol);
+                }
+            }
+            Ok(())
+        };
+
+        match correlation_id {
+            Some(id) => correlation::scope(id, handle).await,
+            None => handle.await,
+        }
+    }
+}
+
+/// When the sender last changed a file it sent, from `modified`. Files
+/// from peers that do not tell count as changed as they arrive.
+fn modified_at(modified: Option<u64>) -> SystemTime {
+    modified.map_or_else(SystemTime::now, |millis| UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+/// What a spawned connection task needs from the server.
+#[derive(Clone)]
+struct ConnectionContext {
+    rendezvous: RendezvousRegistry,
+    manifests: ManifestStore,
+    chaos: Option<Chaos>,
+    files: Option<Arc<Mutex<FileManager>>>,
+    index: Option<FileIndex>,
+    chunks: Option<ChunkStore>,
+    groups: Groups,
+    codec: Codec,
+    keys: DeviceKeys,
+    name: Option<String>,
+    edition: Option<Edition>,
+    reachability: Option<Reachability>,
+    /// The peer announced `CAPABILITY_RESULTS`.
+    results: bool,
+    /// The peer announced `CAPABILITY_ACKS`.
+    acks: bool,
+    /// The peer announced `CAPABILITY_CREDITS`.
+    flow_control: bool,
+    /// What the peer may send ahead, out of `SyncServer::with_write_window`.
+    credits: Arc<Credits>,
+    busy: BusyWorlds,
+    warm_up: Option<Arc<Health>>,
+    /// The peer announced `CAPABILITY_WARMUP`.
+    warmup: bool,
+    queue: Option<Arc<TransferQueue>>,
+    deletions: Option<PendingDeletions>,
+    versions: Option<Versions>,
+    /// Who the peer is, once it authenticated or said so in its Hello.
+    device: Option<String>,
+}
+
+impl ConnectionContext {
+    /// Whether the initial scan still runs, see `SyncServer::with_warm_up`.
+    fn warming_up(&self) -> bool {
+        self.warm_up.as_ref().is_some_and(|health| !Health::is_set(&health.index_scanned))
+    }
+
+    /// Locks `files` to write a change to `path` the peer sent, and tells
+    /// where to write it. When this device changed the same world and has
+    /// not sent those changes to the peer yet, the file manager settles the
+    /// conflict with `theirs`, the version sent: `None` keeps this device's
+    /// version, which the peer gets instead. With `version`, the version
+    /// vector sent, and one for `path` here, the vectors tell whether the
+    /// file itself conflicts. Fails when a copy of the world could not be
+    /// made, so nothing is overwritten and the peer retries.
+    async fn lock_for_receiving<'a>(&self, files: &'a Mutex<FileManager>, path: &Path, theirs: impl FnOnce(&FileManager) -> FileInfo, version: Option<&VersionVector>) -> Result<(MutexGuard<'a, FileManager>, Resolution)> {
+        let order = match (&self.versions, version) {
+            (Some(versions), Some(theirs)) => versions.get(path).map(|ours| ours.order(theirs)),
+            _ => None,
+        };
+        if let Some(order @ (Order::Newer | Order::Same)) = order {
+            if order == Order::Newer {
+                info!("Not taking {} from {}, the version here was made from it", path.display(), self.device.as_deref().unwrap_or("the peer"));
+                if let (Some(queue), Some(peer)) = (&self.queue, &self.device) {
+                    queue.push(peer.clone(), path.to_path_buf(), "Newer".to_string()).await;
+                }
+            }
+            return Ok((files.lock().await, Resolution::Keep));
+        }
+        let conflict = match (&self.queue, &self.device, transfer_queue::world_of(path)) {
+            (Some(queue), Some(peer), Some(world)) if order == Some(Order::Concurrent) || !queue.world_delivered(peer, world).await => {
+                // A newer version includes the changes queued here
+                let same_file = match order {
+                    Some(order) => order == Order::Concurrent,
+                    None => queue.is_queued(peer, path).await,
+                };
+                Some((peer, world, same_file))
+            }
+            _ => None,
+        };
+        if let Some(pending) = &self.deletions {
+            if pending.remove(path)? > 0 {
+                info!("A new version of {} arrived, it is no longer deleted", path.display());
+            }
+        }
+        let mut files = files.lock().await;
+        let theirs = theirs(&files);
+        // Both made the same change, there is nothing to settle
+        if files.has_content(path, &theirs.hash) {
+            return Ok((files, Resolution::Take));
+        }
+        if order.is_none() && files.index().deleted_version(&theirs) {
+            // The peer missed the deletion and gets it again
+            info!("Not restoring {}, it was deleted here after {} last changed it", path.display(), self.device.as_deref().unwrap_or("the peer"));
+            if let (Some(queue), Some(peer)) = (&self.queue, &self.device) {
+                queue.push(peer.clone(), path.to_path_buf(), "Deleted".to_string()).await;
+            }
+            return Ok((files, Resolution::Keep));
+        }
+        let Some((peer, world, same_file)) = conflict else {
+            return Ok((files, Resolution::Take));
+        };
+        let resolution = files.resolve_conflict(path, peer, Some(&theirs), same_file)?;
+        match &resolution {
+            Resolution::Take => {}
+            Resolution::Keep => info!("{} and {} both changed {}, keeping this device's version", peer, self.name.as_deref().unwrap_or("this device"), path.display()),
+            Resolution::Divert(copy) => debug!("{} goes to {}, {} changed it here too", path.display(), copy.display(), world),
+            Resolution::Merge { .. } => debug!("Merging {}, {} changed it too", path.display(), peer),
+        }
+        // Nothing may be queued for a conflict only the versions told of
+        if order == Some(Order::Concurrent) && matches!(resolution, Resolution::Keep | Resolution::Divert(_)) {
+            if let Some(queue) = &self.queue {
+                queue.push(peer.clone(), path.to_path_buf(), "Conflict".to_string()).await;
+            }
+        }
+        Ok((files, resolution))
+    }
+
+    /// Records the version of `path` that receiving `theirs` left here and
+    /// passes `received` on. A change not taken as it was leaves this
+    /// device's version, which then includes theirs and wins over both.
+    fn record_version(&self, path: &Path, resolution: &Resolution, theirs: Option<&VersionVector>, received: Result<bool>) -> Result<bool> {
+        if let (Some(versions), Some(theirs), Ok(_)) = (&self.versions, theirs, &received) {
+            let changed_here = match resolution {
+                Resolution::Take => false,
+                Resolution::Keep => !matches!(versions.get(path).map(|ours| ours.order(theirs)), Some(Order::Newer | Order::Same)),
+                Resolution::Divert(_) | Resolution::Merge { .. } => true,
+            };
+            if let Err(e) = versions.received(path, theirs, changed_here) {
+                warn!("Failed to record the version of {}: {}", path.display(), e);
+            }
+        }
+        received
+    }
+
+    /// Deletes the files of `path` a peer deleted, or holds the deletions
+    /// back with `SyncServer::with_pending_deletions`. Returns whether
+    /// anything was deleted.
+    async fn receive_deletion(&self, files: &Mutex<FileManager>, path: &Path, deleted: Vec<DeletedFile>) -> Result<bool> {
+        let device = self.device.as_deref().unwrap_or("The peer");
+        if let Some(pending) = &self.deletions {
+            let received_at = SystemTime::now();
+            pending.add(deleted.into_iter().map(|file| PendingDeletion { path: file.path, hash: file.hash, device: device.to_string(), received_at, approved: false }).collect())?;
+            info!("{} deleted {}, it is deleted here once the deletion is due", device, path.display());
+            return Ok(false);
+        }
+        let mut files = files.lock().await;
+        let mut applied = false;
+        for file in deleted {
+            if files.receive_deletion(&file.path, &file.hash)? {
+                applied = true;
+            } else if files.get_file_info(&file.path).is_some() {
+                info!("Keeping {}, it changed here since {} deleted it", file.path.display(), device);
+            }
+        }
+        if applied {
+            info!("{} deleted {}", device, path.display());
+        }
+        Ok(applied)
+    }
+
+    /// Moves `from` to `to` as the peer did. Moves held back by the
+    /// deletion grace period are not, nothing is lost by them.
+    async fn receive_rename(&self, files: &Mutex<FileManager>, from: &Path, to: &Path, moved: Vec<DeletedFile>) -> Result<bool> {
+        let device = self.device.as_deref().unwrap_or("The peer");
+        let moved = moved.into_iter().map(|file| (file.path, file.hash)).collect();
+        let renamed = files.lock().await.receive_rename(from, to, &moved)?;
+        if renamed {
+            info!("{} moved {} to {}", device, from.display(), to.display());
+        } else {
+            info!("Not moving {} to {} as {} did, the copy here differs", from.display(), to.display(), device);
+        }
+        Ok(renamed)
+    }
+
+    /// Applies a change as `resolution` settled it. A merge that leaves
+    /// the file different from what the peer sent goes back out to every
+    /// device that syncs it, the peer included.
+    async fn receive_resolved(&self, files: &mut FileManager, path: &Path, resolution: &Resolution, content: &[u8]) -> Result<bool> {
+        let received = files.receive_resolved(path, resolution, content)?;
+        if let (Resolution::Merge { .. }, Some(queue)) = (resolution, &self.queue) {
+            if !files.has_content(path, &file_manager::hash_bytes(content)) {
+                for device in self.groups.devices_for(path) {
+                    queue.push(device.name.clone(), path.to_path_buf(), "Merged".to_string()).await;
+                }
+            }
+        }
+        Ok(received)
+    }
+
+    fn seen(&self, device: Option<&str>) {
+        if let (Some(reachability), Some(device)) = (&self.reachability, device) {
+            reachability.seen(device, SystemTime::now());
+        }
+    }
+
+    /// The reply to a file that was sent. Peers without `CAPABILITY_RESULTS`
+    /// are told about a failure by closing the connection, and retry.
+    fn file_reply(&self, path: PathBuf, received: Result<bool>) -> Result<SyncMessage> {
+        match received {
+            Ok(_) => Ok(SyncMessage::FileReceived { path }),
+            Err(e) if self.results => {
+                warn!("Failed to save {}: {}", path.display(), e);
+                Ok(SyncMessage::FileFailed { path, reason: e.to_string(), correlation_id: correlation::current() })
+            }
+            Err(e) => anyhow::bail!("Failed to save {}: {}", path.display(), e),
+        }
+    }
+
+    /// Keeps the chunks a file was sent in, the others were stored already.
+    /// Failing to does not undo the transfer.
+    fn store_received_chunks(&self, data: &[Chunk]) {
+        if let Some(store) = &self.chunks {
+            for chunk in data {
+                if let Err(e) = store.put_received(&chunk.hash, &chunk.bytes) {
+                    warn!("Failed to store chunks: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Keeps the chunks of a received file for later transfers. Failing to
+    /// does not undo the transfer.
+    fn store_chunks(&self, content: &[u8]) {
+        if let Some(store) = &self.chunks {
+            if let Err(e) = store.insert_file(content) {
+                warn!("Failed to store chunks: {}", e);
+            }
+        }
+    }
+
+    async fn serve<C, E>(self, conn: C, addr: SocketAddr)
+    where
+        C: Stream<Item = Result<BytesMut, E>> + Sink<Bytes> + Unpin,
+        <C as Sink<Bytes>>::Error: std::error::Error + Send + Sync + 'static,
+        E: std::fmt::Display,
+    {
+        if let Err(e) = SyncServer::handle_connection(conn, addr, self).await {
+            error!("Error handling connection from {}: {}", addr, e);
+        }
+    }
 }
 
 pub struct SyncClient {
     server_address: String,
+    chaos: Option<Chaos>,
+    codec: Codec,
+    tls: Option<TlsConnector>,
+    name: Option<String>,
+    edition: Option<Edition>,
+    key: Option<String>,
+    quic: QuicLink,
+    connections: Option<Connections>,
+    reachability: Option<Reachability>,
+    cpu: CpuBudget,
 }
 
 impl SyncClient {
     pub fn new(server_address: String) -> Self {
-        Self { server_address }
+        Self { server_address, chaos: None, codec: Codec::default(), tls: None, name: None, edition: None, key: None, quic: QuicLink::default(), connections: None, reachability: None, cpu: CpuBudget::default() }
+    }
+
+    /// Where sessions record when the peer was last heard from, and that it
+    /// is offline once it stops answering heartbeats.
+    pub fn with_reachability(mut self, reachability: Reachability) -> Self {
+        self.reachability = Some(reachability);
+        self
+    }
+
+    pub fn address(&self) -> &str {
+        &self.server_address
+    }
+
+    /// Sends file contents and changes over the session `connections` keeps
+    /// to the peer, instead of connecting for each.
+    pub fn with_connections(mut self, connections: Connections) -> Self {
+        self.connections = Some(connections);
+        self
+    }
+
+    pub fn with_chaos(mut self, chaos: Option<Chaos>) -> Self {
+        self.chaos = chaos;
+        self
+    }
+
+    /// Codec to compress file contents with, if the peer accepts it.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Compresses file contents within `cpu`.
+    pub fn with_cpu_budget(mut self, cpu: CpuBudget) -> Self {
+        self.cpu = cpu;
+        self
+    }
+
+    /// Connects over TLS, accepting only peers with a trusted certificate.
+    pub fn with_tls(mut self, tls: Option<TlsConnector>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Name this device authenticates as, the one peers have configured for it.
+    pub fn with_device_name(mut self, name: String) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Edition of Minecraft the worlds belong to. Sessions to a peer
+    /// announcing another one fail.
+    pub fn with_edition(mut self, edition: Option<Edition>) -> Self {
+        self.edition = edition;
+        self
+    }
+
+    /// Key shared with the peer. With one, every connection starts by
+    /// proving this device's name with it.
+    pub fn with_key(mut self, key: Option<String>) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Opens a framed connection to the server, through TLS if configured,
+    /// and authenticates if there is a key. For `quic://` addresses it is a
+    /// new stream of the QUIC connection to the server.
+    async fn open(&self) -> Result<Framed<Box<dyn PeerStream>, LengthDelimitedCodec>> {
+        let stream: Box<dyn PeerStream> = if let Some(address) = self.server_address.strip_prefix(transport::QUIC_PREFIX) {
+            // QUIC brings its own TLS
+            Box::new(self.quic.open(address, self.tls.as_ref()).await?)
+        } else {
+            let socket = match relay::parse_address(&self.server_address) {
+                Some((relay, device)) => relay::connect(relay, device).await?,
+                None => TcpStream::connect(&self.server_address).await?,
+            };
+            match &self.tls {
+                Some(tls) => Box::new(tls.connect(tls::server_name(&self.server_address), socket).await?),
+                None => Box::new(socket),
+            }
+        };
+        let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+        if let Some(key) = &self.key {
+            let name = self.name.as_deref().ok_or_else(|| anyhow::anyhow!("No device name to authenticate with"))?;
+            let auth = SyncMessage::Auth { auth: DeviceAuth::new(name, key, SystemTime::now())? };
+            framed.send(Bytes::from(serde_json::to_vec(&auth)?)).await?;
+        }
+        Ok(framed)
     }
 
     pub async fn connect(&self) -> Result<()> {
-        let socket = TcpStream::connect(&self.server_address).await?;
+        let mut framed = self.open().await?;
         info!("Connected to sync server at {}", self.server_address);
-        
-        let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
 
         // Send initial sync request
-        let sync_request = SyncMessage::SyncRequest;
+        let sync_request = SyncMessage::SyncRequest { correlation_id: correlation::current() };
         let bytes = serde_json::to_vec(&sync_request)?;
-        framed.send(Bytes::from(bytes)).await?;
+        chaos::send_frame(&mut framed, Bytes::from(bytes), self.chaos.as_ref()).await?;
 
         Ok(())
     }
 
+    /// Opens a multiplexed connection that keeps control messages flowing
+    /// while large file contents are being sent.
+    pub async fn session(&self) -> Result<PeerSession> {
+        let mut framed = self.open().await?;
+        framed.send(Bytes::from_static(mux::PREAMBLE)).await?;
+        let (sink, mut stream) = framed.split();
+        let sender = mux::spawn_writer(sink, self.chaos.clone()).with_cpu_budget(self.cpu.clone());
+        // Compressed contents stay lz4 until the peer answers with what it accepts
+        let hello = hello(self.codec, self.name.clone(), self.edition);
+        // JSON until the answer tells whether the peer reads MessagePack
+        sender.send(Channel::Control, Format::Json.encode(&hello)?).await?;
+
+        let (replies_tx, replies) = mpsc::unbounded_channel();
+        let (hello_tx, hello_rx) = tokio::sync::oneshot::channel();
+        let (codec, edition, negotiated, reachability, address) = (self.codec, self.edition, sender.clone(), self.reachability.clone(), self.server_address.clone());
+        tokio::spawn(async move {
+            let mut reassembler = Reassembler::new();
+            let mut hello_tx = Some(hello_tx);
+            let mut device: Option<String> = None;
+            // Format to ping in, once the peer's Hello shows it answers
+            let mut heartbeat: Option<Format> = None;
+            let mut last_heard = tokio::time::Instant::now();
+            let mut ticks = tokio::time::interval_at(last_heard + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+            loop {
+                let frame = tokio::select! {
+                    frame = stream.next() => frame,
+                    // The session was dropped
+                    _ = replies_tx.closed() => break,
+                    _ = ticks.tick() => {
+                        let Some(format) = heartbeat else {
+                            continue;
+                        };
+                        if last_heard.elapsed() >= HEARTBEAT_TIMEOUT {
+                            warn!("{} stopped answering heartbeats, closing the connection", address);
+                            if let (Some(reachability), Some(device)) = (&reachability, &device) {
+                                reachability.set_offline(device, SystemTime::now());
+                            }
+                            break;
+                        }
+                        let Ok(ping) = format.encode(&SyncMessage::Ping) else {
+                            break;
+                        };
+                        if negotiated.send(Channel::Control, ping).await.is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                let Some(Ok(frame)) = frame else {
+                    break;
+                };
+                last_heard = tokio::time::Instant::now();
+                if let (Some(reachability), Some(device)) = (&reachability, &device) {
+                    reachability.seen(device, SystemTime::now());
+                }
+                match reassembler.push(frame) {
+                    Ok(Some((_, bytes))) => match wire::decode(&bytes) {
+                        Ok((SyncMessage::Hello { codecs, protocol, min_protocol, device: announced, capabilities, edition: theirs }, _)) => {
+                            negotiated.set_codec(codec.negotiate(&codecs));
+                            let peer = check_edition(edition, theirs, announced.as_deref())
+                                .and_then(|_| PeerInfo::negotiate(protocol, min_protocol, announced, capabilities));
+                            if let Ok(peer) = &peer {
+                                device = peer.device.clone();
+                                if peer.supports(CAPABILITY_HEARTBEAT) {
+                                    heartbeat = Some(if peer.supports(CAPABILITY_MSGPACK) { Format::MessagePack } else { Format::Json });
+                                }
+                                if let (Some(reachability), Some(device)) = (&reachability, &device) {
+                                    reachability.seen(device, SystemTime::now());
+                                }
+                            }
+                            if let Some(hello_tx) = hello_tx.take() {
+                                let _ = hello_tx.send(peer);
+                            }
+                        }
+                        Ok((SyncMessage::Pong, _)) => {}
+                        Ok((message, _)) => {
+                            if replies_tx.send(message).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => error!("Invalid message on multiplexed connection: {}", e),
+                    },
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Multiplexed connection failed: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let peer = match tokio::time::timeout(HELLO_TIMEOUT, hello_rx).await {
+            Ok(Ok(peer)) => peer?,
+            Ok(Err(_)) => anyhow::bail!("{} closed the connection before answering", self.server_address),
+            Err(_) => {
+                debug!("{} did not answer Hello, taking it for an older peer", self.server_address);
+                PeerInfo::legacy()
+            }
+        };
+        let format = if peer.supports(CAPABILITY_MSGPACK) { Format::MessagePack } else { Format::Json };
+        Ok(PeerSession { sender, replies, peer, format })
+    }
+
+    /// Checks that the peer can be reached by opening a session to it, which
+    /// stays open for the next message if the client has `connections`.
+    pub async fn reach(&self) -> Result<()> {
+        match &self.connections {
+            Some(connections) => connections.session(self).await.map(drop),
+            None => self.session().await.map(drop),
+        }
+    }
+
+    /// Sends a change without content, such as a deletion. A peer with
+    /// `CAPABILITY_ACKS` is waited for until it acknowledges the change;
+    /// older peers only show it was sent.
     pub async fn send_file_change(&self, path: PathBuf, change_type: String) -> Result<()> {
-        let socket = TcpStream::connect(&self.server_address).await?;
-        let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
+        let Some(connections) = &self.connections else {
+            return Self::send_change_on(&mut self.session().await?, path, change_type).await;
+        };
+        let mut session = connections.session(self).await?;
+        let sent = Self::send_change_on(&mut session, path, change_type).await;
+        if sent.is_err() {
+            session.close();
+        }
+        sent
+    }
+
+    /// Tells the peer that `path` was deleted, the files of `tombstones`.
+    /// Peers without `CAPABILITY_DELETES` get a `FileChange` of
+    /// `change_type` instead.
+    pub async fn send_file_deleted(&self, path: PathBuf, change_type: String, tombstones: Vec<Tombstone>, group: Option<GroupTag>) -> Result<()> {
+        let Some(connections) = &self.connections else {
+            return Self::send_deleted_on(&mut self.session().await?, path, change_type, tombstones, group).await;
+        };
+        let mut session = connections.session(self).await?;
+        let sent = Self::send_deleted_on(&mut session, path, change_type, tombstones, group).await;
+        if sent.is_err() {
+            session.close();
+        }
+        sent
+    }
+
+    async fn send_deleted_on(session: &mut PeerSession, path: PathBuf, change_type: String, tombstones: Vec<Tombstone>, group: Option<GroupTag>) -> Result<()> {
+        if !session.peer().supports(CAPABILITY_DELETES) {
+            return Self::send_change_on(session, path, change_type).await;
+        }
+        let files = tombstones.into_iter().map(DeletedFile::from).collect();
+        session.send(&SyncMessage::FileDeleted { path: path.clone(), files, group, correlation_id: correlation::current() }).await?;
+        Self::confirmation(session, path).await
+    }
+
+    /// Tells the peer that the folder `from` was moved to `to`, the files of
+    /// `tombstones`. Returns false when it did not move its copy, because it
+    /// differs or the peer lacks `CAPABILITY_RENAMES`, and needs the
+    /// deletion and the files sent instead.
+    pub async fn send_file_renamed(&self, from: PathBuf, to: PathBuf, tombstones: Vec<Tombstone>, group: Option<GroupTag>, to_group: Option<GroupTag>) -> Result<bool> {
+        let Some(connections) = &self.connections else {
+            return Self::send_renamed_on(&mut self.session().await?, from, to, tombstones, group, to_group).await;
+        };
+        let mut session = connections.session(self).await?;
+        let sent = Self::send_renamed_on(&mut session, from, to, tombstones, group, to_group).await;
+        if sent.is_err() {
+            session.close();
+        }
+        sent
+    }
+
+    async fn send_renamed_on(session: &mut PeerSession, from: PathBuf, to: PathBuf, tombstones: Vec<Tombstone>, group: Option<GroupTag>, to_group: Option<GroupTag>) -> Result<bool> {
+        if !session.peer().supports(CAPABILITY_RENAMES) {
+            return Ok(false);
+        }
+        let files = tombstones.into_iter().map(DeletedFile::from).collect();
+        session.send(&SyncMessage::FileRenamed { from: from.clone(), to, files, group, to_group, correlation_id: correlation::current() }).await?;
+        match Self::confirmation(session, from).await {
+            Ok(()) => Ok(true),
+            Err(e) => match e.downcast::<FileRejected>() {
+                Ok(rejected) => {
+                    debug!("{}", rejected);
+                    Ok(false)
+                }
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    async fn send_change_on(session: &mut PeerSession, path: PathBuf, change_type: String) -> Result<()> {
+        let message = SyncMessage::FileChange { path: path.clone(), change_type, correlation_id: correlation::current() };
+        session.send(&message).await?;
+        if !session.peer().supports(CAPABILITY_ACKS) {
+            return Ok(());
+        }
+        Self::confirmation(session, path).await
+    }
+
+    /// Tells the peer which worlds Minecraft has open on `device`, this
+    /// one. Peers without `CAPABILITY_BUSY` are not told.
+    pub async fn send_busy_worlds(&self, device: String, worlds: Vec<String>) -> Result<()> {
+        self.send_if_supported(CAPABILITY_BUSY, SyncMessage::WorldsBusy { device, worlds }).await
+    }
+
+    /// Tells the peer that every change to `world` queued for it was
+    /// delivered. Peers without `CAPABILITY_STAGING` are not told.
+    pub async fn send_world_synced(&self, world: String) -> Result<()> {
+        self.send_if_supported(CAPABILITY_STAGING, SyncMessage::WorldSynced { world }).await
+    }
+
+    async fn send_if_supported(&self, capability: &str, message: SyncMessage) -> Result<()> {
+        match &self.connections {
+            Some(connections) => Self::send_if_supported_on(&*connections.session(self).await?, capability, &message).await,
+            None => Self::send_if_supported_on(&self.session().await?, capability, &message).await,
+        }
+    }
+
+    async fn send_if_supported_on(session: &PeerSession, capability: &str, message: &SyncMessage) -> Result<()> {
+        if session.peer().supports(capability) {
+            session.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a file's content and waits until the peer wrote it to disk.
+    /// Large files the peer already has a copy of are sent as a delta.
+    pub async fn send_file_content(&self, path: PathBuf, content: Vec<u8>, group: Option<GroupTag>, priority: Priority) -> Result<Sent> {
+        self.send_file_version(path, content, FileVersion::default(), group, priority).await
+    }
+
+    /// As `send_file_content`, telling the peer which version of the file
+    /// this is, so a conflicting change of its own is settled by it.
+    pub async fn send_file_version(&self, path: PathBuf, content: Vec<u8>, version: FileVersion, group: Option<GroupTag>, priority: Priority) -> Result<Sent> {
+        let Some(connections) = &self.connections else {
+            return Self::send_content_on(&mut self.session().await?, path, content, version, group, priority).await;
+        };
+        let mut session = connections.session(self).await?;
+        let sent = Self::send_content_on(&mut session, path, content, version, group, priority).await;
+        // Replies of the failed exchange may still arrive on it
+        if sent.is_err() {
+            session.close();
+        }
+        sent
+    }
+
+    async fn send_content_on(session: &mut PeerSession, path: PathBuf, content: Vec<u8>, version: FileVersion, group: Option<GroupTag>, priority: Priority) -> Result<Sent> {
+        let (delta, chunks) = (session.peer().supports(CAPABILITY_DELTA), session.peer().supports(CAPABILITY_CHUNKS));
+        let mut sent = Sent { size: content.len() as u64, ..Sent::default() };
+        let message = if delta && content.len() >= delta::MIN_DELTA_SIZE {
+            Self::delta_or_chunks(session, path.clone(), content, version, group, priority, &mut sent).await?
+        } else if chunks && content.len() >= chunk_store::MIN_CHUNKED_SIZE {
+            Self::chunks(session, path.clone(), content, version, group, priority, &mut sent).await?
+        } else {
+            SyncMessage::FileContent { path: path.clone(), content, group, modified: version.millis(), version: version.vector, correlation_id: correlation::current() }
+        };
+        sent.add(&message, session.send_measured(&message, priority).await?);
+        Self::confirmation(session, path).await?;
+        Ok(sent)
+    }
+
+    /// Waits for the peer to confirm it applied `path`.
+    async fn confirmation(session: &mut PeerSession, path: PathBuf) -> Result<()> {
+        match session.recv().await {
+            Some(SyncMessage::FileReceived { path: received }) if received == path => Ok(()),
+            Some(SyncMessage::FileFailed { path: failed, reason, .. }) if failed == path => Err(FileRejected { path, reason }.into()),
+            Some(other) => anyhow::bail!("Unexpected reply to {}: {:?}", path.display(), other),
+            None => anyhow::bail!("Peer closed the connection before confirming {}", path.display()),
+        }
+    }
+
+    /// Asks the peer for the checksums of its copy and builds a `BlockData`
+    /// from them, or sends chunks if it has no copy.
+    async fn delta_or_chunks(session: &mut PeerSession, path: PathBuf, content: Vec<u8>, version: FileVersion, group: Option<GroupTag>, priority: Priority, sent: &mut Sent) -> Result<SyncMessage> {
+        let request = SyncMessage::BlockRequest { path: path.clone(), block_size: delta::BLOCK_SIZE as u32, group: group.clone() };
+        session.send_with_priority(&request, priority).await?;
+        let blocks = match session.recv().await {
+            Some(SyncMessage::BlockSignatures { path: answered, blocks }) if answered == path => blocks,
+            Some(other) => anyhow::bail!("Unexpected reply to block request: {:?}", other),
+            None => anyhow::bail!("Peer closed the connection before sending checksums for {}", path.display()),
+        };
+        if blocks.is_empty() {
+            return Self::chunks(session, path, content, version, group, priority, sent).await;
+        }
+        let ops = delta::diff(&blocks, delta::BLOCK_SIZE, &content);
+        debug!("Sending {} as a delta: {} of {} bytes", path.display(), delta::literal_len(&ops), content.len());
+        Ok(SyncMessage::BlockData {
+            hash: file_manager::hash_bytes(&content),
+            path,
+            block_size: delta::BLOCK_SIZE as u32,
+            ops,
+            group,
+            modified: version.millis(),
+            version: version.vector,
+            correlation_id: correlation::current(),
+        })
+    }
+
+    /// Tells the peer which chunks make up the file and builds a `ChunkData`
+    /// with the ones it has not stored. Peers that store chunks as they
+    /// arrive get many missing ones ahead in parts, so a retry after a
+    /// dropped connection only sends what was not acknowledged.
+    async fn chunks(session: &mut PeerSession, path: PathBuf, content: Vec<u8>, version: FileVersion, group: Option<GroupTag>, priority: Priority, sent: &mut Sent) -> Result<SyncMessage> {
+        let chunks = chunk_store::chunk_hashes(&content);
+        let list = SyncMessage::ChunkList { path: path.clone(), chunks: chunks.clone(), group: group.clone() };
+        session.send_with_priority(&list, priority).await?;
+        let (missing, resumable, credits) = match session.recv().await {
+            Some(SyncMessage::ChunkRequest { path: answered, missing, resumable, credits }) if answered == path => (missing, resumable, credits),
+            Some(other) => anyhow::bail!("Unexpected reply to chunk list: {:?}", other),
+            None => anyhow::bail!("Peer closed the connection before requesting chunks of {}", path.display()),
+        };
+        debug!("Sending {} of {} chunks of {}", missing.len(), chunks.len(), path.display());
+        let mut data = chunk_store::select(&content, &missing);
+        if resumable && data.iter().map(|c| c.bytes.len()).sum::<usize>() > FILE_PART {
+            Self::chunk_parts(session, &path, std::mem::take(&mut data), &group, priority, credits.unwrap_or(1).max(1), sent).await?;
+        }
+        Ok(SyncMessage::ChunkData {
+            hash: file_manager::hash_bytes(&content),
+            data,
+            path,
+            chunks,
+            group,
+            modified: version.millis(),
+            version: version.vector,
+            correlation_id: correlation::current(),
+        })
+    }
+
+    /// Sends chunks in parts of about `FILE_PART` bytes. As many go ahead
+    /// as the peer granted `credits`, and each stored part may grant more;
+    /// a peer without `CAPABILITY_CREDITS` gets one at a time.
+    async fn chunk_parts(session: &mut PeerSession, path: &Path, data: Vec<Chunk>, group: &Option<GroupTag>, priority: Priority, mut credits: usize, sent: &mut Sent) -> Result<()> {
+        let mut parts = Vec::new();
+        let mut part: Vec<Chunk> = Vec::new();
+        let mut part_size = 0;
+        for chunk in data {
+            if part_size > 0 && part_size + chunk.bytes.len() > FILE_PART {
+                parts.push(std::mem::take(&mut part));
+                part_size = 0;
+            }
+            part_size += chunk.bytes.len();
+            part.push(chunk);
+        }
+        parts.push(part);
+        let mut parts = parts.into_iter().peekable();
+        // Sizes of the parts sent and not stored yet, in order
+        let mut unstored = VecDeque::new();
+        while parts.peek().is_some() || !unstored.is_empty() {
+            while credits > 0 {
+                let Some(chunks) = parts.next() else {
+                    break;
+                };
+                unstored.push_back(chunks.len());
+                let part = SyncMessage::ChunkPart { path: path.to_path_buf(), chunks, group: group.clone(), correlation_id: correlation::current() };
+                sent.add(&part, session.send_measured(&part, priority).await?);
+                credits -= 1;
+            }
+            let count = unstored.pop_front();
+            match session.recv().await {
+                Some(SyncMessage::ChunkPartStored { path: stored, count: stored_count, credits: granted }) if stored == path && Some(stored_count) == count => {
+                    credits += granted.unwrap_or(1);
+                    // With nothing left to store, there is nothing to grant more credits with
+                    if unstored.is_empty() {
+                        credits = credits.max(1);
+                    }
+                }
+                Some(other) => anyhow::bail!("Unexpected reply to chunk part: {:?}", other),
+                None => anyhow::bail!("Peer closed the connection while receiving chunks of {}", path.display()),
+            }
+        }
+        Ok(())
+    }
 
-        let message = SyncMessage::FileChange { path, change_type };
+    pub async fn register_rendezvous(&self, id: String, port: u16) -> Result<()> {
+        let mut framed = self.open().await?;
+
+        let message = SyncMessage::RendezvousRegister { id, port };
         let bytes = serde_json::to_vec(&message)?;
-        framed.send(Bytes::from(bytes)).await?;
+        chaos::send_frame(&mut framed, Bytes::from(bytes), self.chaos.as_ref()).await?;
 
         Ok(())
     }
-} 
\ No newline at end of file
+
+    pub async fn lookup_rendezvous(&self, id: String) -> Result<Option<String>> {
+        let mut framed = self.open().await?;
+
+        let message = SyncMessage::RendezvousLookup { id };
+        let bytes = serde_json::to_vec(&message)?;
+        chaos::send_frame(&mut framed, Bytes::from(bytes), self.chaos.as_ref()).await?;
+
+        match framed.next().await {
+            Some(Ok(bytes)) => match serde_json::from_slice::<SyncMessage>(&bytes)? {
+                SyncMessage::RendezvousAddress { address, .. } => Ok(address),
+                other => anyhow::bail!("Unexpected rendezvous reply: {:?}", other),
+            },
+            Some(Err(e)) => Err(e.into()),
+            None => anyhow::bail!("Relay closed the connection without replying"),
+        }
+    }
+}
+
+/// A multiplexed connection to a peer. Messages are routed to the control or
+/// bulk channel by `SyncMessage::channel`.
+pub struct PeerSession {
+    sender: MuxSender,
+    replies: mpsc::UnboundedReceiver<SyncMessage>,
+    peer: PeerInfo,
+    format: Format,
+}
+
+impl PeerSession {
+    /// What the peer announced about itself.
+    pub fn peer(&self) -> &PeerInfo {
+        &self.peer
+    }
+
+    /// How messages to the peer are encoded.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Whether the connection is gone and nothing more will arrive.
+    pub fn is_closed(&self) -> bool {
+        self.replies.is_closed()
+    }
+
+    /// Stops taking replies, so a session whose exchange failed with
+    /// replies still unread is not used again.
+    pub fn close(&mut self) {
+        self.replies.close();
+    }
+
+    pub async fn send(&self, message: &SyncMessage) -> Result<()> {
+        self.send_with_priority(message, Priority::Background).await
+    }
+
+    /// Like `send`, but an interactive bulk message preempts background ones.
+    pub async fn send_with_priority(&self, message: &SyncMessage, priority: Priority) -> Result<()> {
+        self.send_measured(message, priority).await.map(drop)
+    }
+
+    /// Like `send_with_priority`, returning the size of the message as
+    /// encoded and as it goes on the wire.
+    pub async fn send_measured(&self, message: &SyncMessage, priority: Priority) -> Result<(u64, u64)> {
+        let bytes = self.format.encode(message)?;
+        let encoded = bytes.len() as u64;
+        let wire = match message.channel() {
+            Channel::Control => self.sender.send(Channel::Control, bytes).await.map(|_| encoded)?,
+            Channel::Bulk if message.worth_compressing() => self.sender.send_compressed(priority, bytes).await? as u64,
+            Channel::Bulk => self.sender.send_bulk(priority, bytes).await.map(|_| encoded)?,
+        };
+        Ok((encoded, wire))
+    }
+
+    /// Codec compressed file contents are sent with, lz4 until the peer
+    /// accepted a better one.
+    pub fn codec(&self) -> Codec {
+        self.sender.codec()
+    }
+
+    /// Next message from the peer, or `None` once the connection closed.
+    pub async fn recv(&mut self) -> Option<SyncMessage> {
+        self.replies.recv().await
+    }
+
+    /// Lists the files of a world shared with `group`. A listing with files
+    /// outside the world is refused, so a host cannot reach other worlds.
+    pub async fn list_world(&mut self, world: &str, group: GroupTag) -> Result<BTreeMap<PathBuf, ManifestEntry>> {
+        self.send(&SyncMessage::WorldRequest { world: world.to_string(), group }).await?;
+        match self.recv().await {
+            Some(SyncMessage::WorldListing { world: listed, entries }) if listed == world => {
+                let outside = entries.keys().find(|path| !path.starts_with(world) || path.components().any(|c| !matches!(c, Component::Normal(_))));
+                if let Some(path) = outside {
+                    anyhow::bail!("Host listed {} outside the shared world {}", path.display(), world);
+                }
+                Ok(entries)
+            }
+            Some(SyncMessage::WarmingUp { retry_after }) => Err(WarmingUp { retry_after: Duration::from_secs(retry_after) }.into()),
+            Some(other) => anyhow::bail!("Unexpected reply to world request: {:?}", other),
+            None => anyhow::bail!("Host refused to list {}", world),
+        }
+    }
+
+    /// Downloads one file of a shared world.
+    pub async fn pull_file(&mut self, path: &Path, group: GroupTag) -> Result<Vec<u8>> {
+        self.send(&SyncMessage::FileRequest { path: path.to_path_buf(), group }).await?;
+        let mut content = Vec::new();
+        loop {
+            match self.recv().await {
+                Some(SyncMessage::FilePart { path: part, bytes, last }) if part == path => {
+                    content.extend_from_slice(&bytes);
+                    if last {
+                        return Ok(content);
+                    }
+                }
+                Some(SyncMessage::WarmingUp { retry_after }) => return Err(WarmingUp { retry_after: Duration::from_secs(retry_after) }.into()),
+                Some(other) => anyhow::bail!("Unexpected reply to file request: {:?}", other),
+                None => anyhow::bail!("Host refused to send {}", path.display()),
+            }
+        }
+    }
+}
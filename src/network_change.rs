@@ -0,0 +1,73 @@
+use std::collections::BTreeSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Interval;
+
+/// The addresses of this device's network interfaces other than loopback.
+/// They change when Wi-Fi joins another network, a cable is plugged in or
+/// a VPN comes up.
+pub fn local_addresses() -> BTreeSet<IpAddr> {
+    match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces.into_iter().filter(|i| !i.is_loopback()).map(|i| i.ip()).collect(),
+        Err(_) => BTreeSet::new(),
+    }
+}
+
+/// Told when this device's network changed, so devices that could not be
+/// reached are tried again and manifests exchanged right away, instead of
+/// after the next backoff or sync interval.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkChanges {
+    changed: Arc<Notify>,
+}
+
+impl NetworkChanges {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wakes everything waiting in `changed`.
+    pub fn notify(&self) {
+        self.changed.notify_waiters();
+    }
+
+    /// Waits for the next change.
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+}
+
+/// Ticks every sync interval, and as soon as the network changed when
+/// following `NetworkChanges`. The first tick is right away.
+pub struct SyncTicker {
+    interval: Interval,
+    changes: Option<NetworkChanges>,
+}
+
+impl SyncTicker {
+    pub fn new(every: Duration, changes: Option<NetworkChanges>) -> Self {
+        Self { interval: tokio::time::interval(every), changes }
+    }
+
+    /// Waits for the next tick, returning true if the network changed. The
+    /// interval then starts over.
+    pub async fn tick(&mut self) -> bool {
+        let changes = self.changes.clone();
+        let changed = async move {
+            match changes {
+                Some(changes) => changes.changed().await,
+                None => std::future::pending().await,
+            }
+        };
+        let changed = tokio::select! {
+            _ = self.interval.tick() => false,
+            _ = changed => true,
+        };
+        if changed {
+            self.interval.reset();
+        }
+        changed
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::{anyhow, bail, Result};
+use futures::{SinkExt, StreamExt};
+use rustls::crypto::ring::kx_group::X25519;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use crate::config::{Config, Device};
+use crate::network::SyncMessage;
+use crate::shares;
+
+/// How long `pair` waits for the other device to enter the code.
+pub const PAIR_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long the joining device waits for the other one to prove the code,
+/// which leaves no time to brute-force it from anything sent so far.
+pub const PROOF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Rounds of hashing the code goes through with each key exchange, so every
+/// guess at it costs as much.
+const CODE_ROUNDS: u32 = 100_000;
+
+/// Characters of pairing codes, without ones that are easily misread.
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+/// What a device tells the other about itself while pairing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PairedDevice {
+    pub name: String,
+    /// Port its sync server listens on.
+    pub port: u16,
+    /// Fingerprint of its TLS certificate, if it uses TLS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
+}
+
+/// The other device of a pairing, and the key both sides now share with it.
+#[derive(Debug, Clone)]
+pub struct Paired {
+    pub device: PairedDevice,
+    pub address: String,
+    pub key: String,
+}
+
+/// Eight characters from `CODE_ALPHABET`, shown as `XXXX-XXXX`.
+pub fn new_code() -> Result<String> {
+    let mut bytes = [0u8; 8];
+    rustls::crypto::ring::default_provider().secure_random.fill(&mut bytes).map_err(|_| anyhow!("No secure random source available"))?;
+    let code: String = bytes.iter().map(|b| CODE_ALPHABET[*b as usize % CODE_ALPHABET.len()] as char).collect();
+    Ok(format!("{}-{}", &code[..4], &code[4..]))
+}
+
+/// Codes may be typed in lower case, with or without the dash.
+fn normalize(code: &str) -> String {
+    code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_uppercase()).collect()
+}
+
+/// Everything both sides agreed on. The code is only ever hashed together
+/// with the key exchange, so an eavesdropper learns nothing to guess it
+/// from. Someone in the middle can try codes offline against any proof it
+/// gets, so each proof only goes out once the middle is committed to a guess
+/// of its own, and `CODE_ROUNDS` and `PROOF_TIMEOUT` leave no time to try
+/// them all while the pairing is open.
+struct Transcript {
+    /// The code, stretched with the key exchange.
+    code: Vec<u8>,
+    secret: Vec<u8>,
+    joiner: Vec<u8>,
+    acceptor: Vec<u8>,
+}
+
+impl Transcript {
+    fn new(code: &str, secret: Vec<u8>, joiner: Vec<u8>, acceptor: Vec<u8>) -> Self {
+        let mut stretched = Sha256::new().chain_update(normalize(code)).chain_update(&secret).finalize();
+        for _ in 0..CODE_ROUNDS {
+            stretched = Sha256::digest(stretched);
+        }
+        Self { code: stretched.to_vec(), secret, joiner, acceptor }
+    }
+
+    fn hash(&self, parts: &[&[u8]]) -> String {
+        let mut hasher = Sha256::new();
+        for part in [&self.code, &self.secret, &self.joiner, &self.acceptor].into_iter().map(Vec::as_slice).chain(parts.iter().copied()) {
+            hasher.update(part);
+            hasher.update([0]);
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn proof(&self, role: &str, device: &PairedDevice) -> String {
+        let fingerprint = device.fingerprint.as_deref().unwrap_or("");
+        self.hash(&[role.as_bytes(), device.name.as_bytes(), device.port.to_string().as_bytes(), fingerprint.as_bytes()])
+    }
+
+    fn key(&self) -> String {
+        self.hash(&[b"key"])[..32].to_string()
+    }
+}
+
+/// Binds the joining device to `proof` without revealing it.
+fn commitment(proof: &str, nonce: &str) -> String {
+    format!("{:x}", Sha256::new().chain_update(nonce).chain_update([0]).chain_update(proof).finalize())
+}
+
+async fn send(framed: &mut Framed<TcpStream, LengthDelimitedCodec>, message: &SyncMessage) -> Result<()> {
+    framed.send(Bytes::from(serde_json::to_vec(message)?)).await?;
+    Ok(())
+}
+
+async fn receive(framed: &mut Framed<TcpStream, LengthDelimitedCodec>) -> Result<SyncMessage> {
+    let frame = framed.next().await.ok_or_else(|| anyhow!("The other device hung up, check the pairing code"))??;
+    Ok(serde_json::from_slice(&frame)?)
+}
+
+/// Waits for one device to pair with `code` and introduces `local` to it.
+/// A wrong code ends the pairing, so it cannot be guessed.
+pub async fn accept(listener: &TcpListener, code: &str, local: &PairedDevice) -> Result<Paired> {
+    let (stream, addr) = listener.accept().await?;
+    let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+    let SyncMessage::PairKey { public_key: joiner } = receive(&mut framed).await? else {
+        bail!("{} did not start pairing", addr);
+    };
+    let exchange = X25519.start()?;
+    let acceptor = exchange.pub_key().to_vec();
+    send(&mut framed, &SyncMessage::PairKey { public_key: acceptor.clone() }).await?;
+    let secret = exchange.complete(&joiner)?.secret_bytes().to_vec();
+    let transcript = Transcript::new(code, secret, joiner, acceptor);
+
+    let SyncMessage::PairCommit { commitment: committed } = receive(&mut framed).await? else {
+        bail!("{} did not commit to its proof", addr);
+    };
+    send(&mut framed, &SyncMessage::PairConfirm { device: local.clone(), proof: transcript.proof("accept", local), nonce: None }).await?;
+    let SyncMessage::PairConfirm { device, proof, nonce: Some(nonce) } = receive(&mut framed).await? else {
+        bail!("{} did not confirm pairing", addr);
+    };
+    if commitment(&proof, &nonce) != committed {
+        bail!("{} changed its proof after committing to it", addr);
+    }
+    if proof != transcript.proof("join", &device) {
+        bail!("{} entered the wrong pairing code", addr);
+    }
+    Ok(Paired { address: SocketAddr::new(addr.ip(), device.port).to_string(), key: transcript.key(), device })
+}
+
+/// Pairs with the device showing `code` at `address`, introducing `local`.
+pub async fn join(address: &str, code: &str, local: &PairedDevice) -> Result<Paired> {
+    let mut framed = Framed::new(TcpStream::connect(address).await?, LengthDelimitedCodec::new());
+    let exchange = X25519.start()?;
+    let joiner = exchange.pub_key().to_vec();
+    send(&mut framed, &SyncMessage::PairKey { public_key: joiner.clone() }).await?;
+    let SyncMessage::PairKey { public_key: acceptor } = receive(&mut framed).await? else {
+        bail!("{} does not pair", address);
+    };
+    let secret = exchange.complete(&acceptor)?.secret_bytes().to_vec();
+    let transcript = Transcript::new(code, secret, joiner, acceptor);
+
+    let (proof, nonce) = (transcript.proof("join", local), shares::random_key()?);
+    send(&mut framed, &SyncMessage::PairCommit { commitment: commitment(&proof, &nonce) }).await?;
+    let answer = tokio::time::timeout(PROOF_TIMEOUT, receive(&mut framed)).await.map_err(|_| anyhow!("{} did not prove it knows the pairing code within {:?}", address, PROOF_TIMEOUT))??;
+    let SyncMessage::PairConfirm { device, proof: theirs, .. } = answer else {
+        bail!("{} did not confirm pairing", address);
+    };
+    if theirs != transcript.proof("accept", &device) {
+        bail!("{} could not prove it knows the pairing code", address);
+    }
+    send(&mut framed, &SyncMessage::PairConfirm { device: local.clone(), proof, nonce: Some(nonce) }).await?;
+    // Its sync server is on the same host as the pairing listener
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host);
+    Ok(Paired { address: format!("{}:{}", host, device.port), key: transcript.key(), device })
+}
+
+/// Adds the paired device to `sync.devices`, or updates its address and key
+/// if a device of that name is configured, and trusts its certificate when
+/// this device uses TLS. Saving `config` is up to the caller.
+pub fn add(config: &mut Config, paired: &Paired) -> Result<()> {
+    if paired.device.name == config.sync.local_name() {
+        bail!("The other device is also called {}, rename one of them first", paired.device.name);
+    }
+    let device = Device { key: Some(paired.key.clone()), ..Device::from_target(&paired.device.name, &paired.address) };
+    let existing = config.sync.device_lists_mut().flatten().find(|d| d.name == device.name);
+    match existing {
+        Some(existing) => {
+            existing.address = device.address;
+            existing.key = device.key;
+        }
+        None => config.sync.devices.push(device),
+    }
+    if let (Some(tls), Some(fingerprint)) = (config.tls.as_mut(), &paired.device.fingerprint) {
+        if !tls.trusted.contains(fingerprint) {
+            tls.trusted.push(fingerprint.clone());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The Microsoft Store package of Minecraft for Windows.
+const UWP_PACKAGE: &str = "Microsoft.MinecraftUWP_8wekyb3d8bbwe";
+/// The Microsoft Store package of Minecraft Preview.
+const PREVIEW_PACKAGE: &str = "Microsoft.MinecraftWindowsBeta_8wekyb3d8bbwe";
+/// The Microsoft Store package of Minecraft Education.
+const EDUCATION_PACKAGE: &str = "Microsoft.MinecraftEducationEdition_8wekyb3d8bbwe";
+
+/// Which Minecraft a worlds directory belongs to. Each keeps its own worlds,
+/// in a format the others may not open, so they are only synced with the
+/// same edition on other devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Edition {
+    Release,
+    Preview,
+    Education,
+}
+
+impl fmt::Display for Edition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Edition::Release => "Minecraft",
+            Edition::Preview => "Minecraft Preview",
+            Edition::Education => "Minecraft Education",
+        })
+    }
+}
+
+/// A worlds directory of an installed Minecraft.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Installation {
+    pub edition: Edition,
+    pub worlds: PathBuf,
+}
+
+/// Where Minecraft keeps its worlds on this device, most likely first. Only
+/// some of them exist, depending on the edition and launcher installed.
+pub fn minecraft_worlds_dirs() -> Vec<PathBuf> {
+    minecraft_installations().into_iter().map(|installation| installation.worlds).collect()
+}
+
+/// `minecraft_worlds_dirs` with the edition each belongs to.
+pub fn minecraft_installations() -> Vec<Installation> {
+    installations_for(env::consts::OS, |key| env::var(key).ok().filter(|v| !v.is_empty()))
+}
+
+/// `minecraft_worlds_dirs` on the operating system `os` (as in
+/// `std::env::consts::OS`), with environment variables read from `var`.
+pub fn worlds_dirs_for(os: &str, var: impl Fn(&str) -> Option<String>) -> Vec<PathBuf> {
+    installations_for(os, var).into_iter().map(|installation| installation.worlds).collect()
+}
+
+/// `minecraft_installations` on the operating system `os`, with environment
+/// variables read from `var`. Releases go before Preview and Education.
+pub fn installations_for(os: &str, var: impl Fn(&str) -> Option<String>) -> Vec<Installation> {
+    let mut installations = Vec::new();
+    let mut add = |edition, worlds| installations.push(Installation { edition, worlds });
+    match os {
+        "windows" => {
+            let local = var("LOCALAPPDATA").map(PathBuf::from)
+                .or_else(|| var("USERPROFILE").map(|profile| Path::new(&profile).join("AppData").join("Local")));
+            let roaming = var("APPDATA").map(PathBuf::from);
+            for (edition, package, launcher) in [(Edition::Release, UWP_PACKAGE, "Minecraft Bedrock"), (Edition::Preview, PREVIEW_PACKAGE, "Minecraft Bedrock Preview")] {
+                if let Some(local) = &local {
+                    add(edition, games(&local.join("Packages").join(package).join("LocalState")));
+                }
+                // Newer releases keep the worlds of each Xbox account apart
+                if let Some(roaming) = &roaming {
+                    for account in accounts(&roaming.join(launcher).join("Users")) {
+                        add(edition, account);
+                    }
+                }
+            }
+            if let Some(local) = &local {
+                add(Edition::Education, games(&local.join("Packages").join(EDUCATION_PACKAGE).join("LocalState")));
+            }
+            // Installed from the Education website rather than the Store
+            if let Some(roaming) = &roaming {
+                add(Edition::Education, games(&roaming.join("Minecraft Education Edition")));
+            }
+        }
+        "linux" => {
+            // mcpelauncher, installed natively or as a Flatpak
+            if let Some(home) = var("HOME").map(PathBuf::from) {
+                let data = var("XDG_DATA_HOME").map(PathBuf::from).unwrap_or_else(|| home.join(".local").join("share"));
+                add(Edition::Release, games(&data.join("mcpelauncher")));
+                add(Edition::Release, games(&home.join(".var").join("app").join("io.mrarm.mcpelauncher").join("data").join("mcpelauncher")));
+            }
+        }
+        "macos" => {
+            if let Some(home) = var("HOME") {
+                add(Edition::Release, games(&Path::new(&home).join("Library").join("Application Support").join("mcpelauncher")));
+            }
+        }
+        _ => {}
+    }
+    installations
+}
+
+/// The worlds directories of each account below a launcher's `Users`.
+fn accounts(users: &Path) -> Vec<PathBuf> {
+    let mut accounts: Vec<PathBuf> = fs::read_dir(users).into_iter().flatten().flatten()
+        .map(|entry| games(&entry.path()))
+        .filter(|dir| dir.is_dir())
+        .collect();
+    accounts.sort();
+    accounts
+}
+
+/// The edition a worlds directory belongs to, if its path tells, such as
+/// the worlds directory of a Store package.
+pub fn edition_of(worlds: &Path) -> Option<Edition> {
+    worlds.components().rev().find_map(|component| match component.as_os_str().to_str()? {
+        UWP_PACKAGE | "Minecraft Bedrock" | "mcpelauncher" => Some(Edition::Release),
+        PREVIEW_PACKAGE | "Minecraft Bedrock Preview" => Some(Edition::Preview),
+        EDUCATION_PACKAGE | "Minecraft Education Edition" => Some(Edition::Education),
+        _ => None,
+    })
+}
+
+fn games(data: &Path) -> PathBuf {
+    data.join("games").join("com.mojang").join("minecraftWorlds")
+}
+
+/// Expands `~` and environment variables in a configured path, so one
+/// configuration can name the same place on different devices.
+pub fn expand(path: &str) -> PathBuf {
+    PathBuf::from(shellexpand::full(path).map(|p| p.into_owned()).unwrap_or_else(|_| path.to_string()))
+}
@@ -0,0 +1,132 @@
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use crate::file_manager::hash_bytes;
+use crate::inspect::dimension;
+use crate::leveldb;
+use crate::nbt::{self, Tag};
+
+/// `~local_player` is the player of whoever hosts the world, `player_…`
+/// everyone who joined it.
+pub fn is_player_key(key: &[u8]) -> bool {
+    key == b"~local_player" || key.starts_with(b"player_")
+}
+
+/// What a world holds about one player, as far as its data could be read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Player {
+    pub key: String,
+    /// LevelDB sequence number of the last write of the record.
+    pub seq: u64,
+    pub hash: String,
+    pub position: Option<[f64; 3]>,
+    pub dimension: Option<i32>,
+    pub level: Option<i64>,
+    /// Items in the inventory, counting each one of a stack.
+    pub items: Option<u64>,
+}
+
+impl Player {
+    fn new(key: &[u8], seq: u64, value: &[u8]) -> Self {
+        let root = nbt::read(value).ok().map(|(_, root)| root);
+        let field = |name: &str| root.as_ref().and_then(|root| root.get(name));
+        let position = field("Pos").and_then(Tag::as_list).and_then(|pos| match pos {
+            [x, y, z] => Some([x.as_float()?, y.as_float()?, z.as_float()?]),
+            _ => None,
+        });
+        let items = field("Inventory").and_then(Tag::as_list).map(|slots| {
+            slots.iter()
+                .filter(|slot| slot.get("Name").and_then(Tag::as_str).is_some_and(|name| !name.is_empty()))
+                .filter_map(|slot| slot.get("Count").and_then(Tag::as_int))
+                .map(|count| count.max(0) as u64)
+                .sum()
+        });
+        Player {
+            key: String::from_utf8_lossy(key).into_owned(),
+            seq,
+            hash: hash_bytes(value),
+            position,
+            dimension: field("DimensionId").and_then(Tag::as_int).and_then(|id| i32::try_from(id).ok()),
+            level: field("PlayerLevel").and_then(Tag::as_int),
+            items,
+        }
+    }
+}
+
+impl fmt::Display for Player {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some([x, y, z]) = self.position {
+            let place = self.dimension.map(|id| format!(" in the {}", dimension(id))).unwrap_or_default();
+            parts.push(format!("at {:.0}, {:.0}, {:.0}{}", x, y, z, place));
+        }
+        if let Some(level) = self.level {
+            parts.push(format!("level {}", level));
+        }
+        if let Some(items) = self.items {
+            parts.push(format!("{} items", items));
+        }
+        if parts.is_empty() {
+            return f.write_str("unreadable player data");
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// The players of the world in `world_dir`, read without changing it.
+pub fn read(world_dir: &Path) -> Result<Vec<Player>> {
+    let db = world_dir.join("db");
+    if !db.is_dir() {
+        return Ok(Vec::new());
+    }
+    let contents = leveldb::read_keeping(&db, &is_player_key)?;
+    Ok(contents.keys.iter()
+        .filter_map(|(key, entry)| Some(Player::new(key, entry.seq, entry.value.as_deref()?)))
+        .collect())
+}
+
+/// A player whose data differs between two copies of a world.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    pub key: String,
+    pub a: Option<Player>,
+    pub b: Option<Player>,
+}
+
+impl Difference {
+    /// Whether copy `b` has the newer data, `None` when only one copy has
+    /// the player. LevelDB numbers every write, so of two copies that both
+    /// changed since they last matched, the one that wrote the record later
+    /// has seen more play, which file times do not tell: Minecraft rewrites
+    /// files whenever a world is only opened.
+    pub fn b_is_newer(&self) -> Option<bool> {
+        Some(self.b.as_ref()?.seq > self.a.as_ref()?.seq)
+    }
+
+    /// One line on which copy to keep for this player.
+    pub fn describe(&self, a_name: &str, b_name: &str) -> String {
+        match (&self.a, &self.b, self.b_is_newer()) {
+            (Some(a), Some(b), Some(true)) => format!("{}: newer on {} ({}) than on {} ({})", self.key, b_name, b, a_name, a),
+            (Some(a), Some(b), _) => format!("{}: newer on {} ({}) than on {} ({})", self.key, a_name, a, b_name, b),
+            (Some(a), None, _) => format!("{}: only on {} ({})", self.key, a_name, a),
+            (None, Some(b), _) => format!("{}: only on {} ({})", self.key, b_name, b),
+            (None, None, _) => format!("{}: on neither", self.key),
+        }
+    }
+}
+
+/// The players whose data differs between copies `a` and `b` of a world.
+pub fn differences(a: &[Player], b: &[Player]) -> Vec<Difference> {
+    let mut by_key: BTreeMap<&str, (Option<&Player>, Option<&Player>)> = BTreeMap::new();
+    for player in a {
+        by_key.entry(&player.key).or_default().0 = Some(player);
+    }
+    for player in b {
+        by_key.entry(&player.key).or_default().1 = Some(player);
+    }
+    by_key.into_iter()
+        .filter(|(_, (a, b))| a.map(|p| &p.hash) != b.map(|p| &p.hash))
+        .map(|(key, (a, b))| Difference { key: key.to_string(), a: a.cloned(), b: b.cloned() })
+        .collect()
+}
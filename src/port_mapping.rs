@@ -0,0 +1,212 @@
+use anyhow::{anyhow, bail, Result};
+use igd_next::aio::tokio::search_gateway;
+use igd_next::{PortMappingProtocol, SearchOptions};
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use crate::config::PortMappingConfig;
+
+/// Name mappings are registered under on the router.
+pub const DESCRIPTION: &str = "mcbd-world-sync";
+
+/// Port NAT-PMP gateways listen on.
+pub const NAT_PMP_PORT: u16 = 5351;
+
+/// How long to look for a UPnP router before trying NAT-PMP.
+const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How soon to try again after a port could not be forwarded.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "TCP",
+            Protocol::Udp => "UDP",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Method {
+    Upnp,
+    NatPmp,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Method::Upnp => "UPnP",
+            Method::NatPmp => "NAT-PMP",
+        })
+    }
+}
+
+/// A listener port forwarded on the router.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Mapping {
+    pub method: Method,
+    pub protocol: Protocol,
+    pub port: u16,
+    /// Where peers outside the local network connect to.
+    pub external: SocketAddr,
+}
+
+/// The mappings currently held, shared with the status page.
+#[derive(Debug, Clone, Default)]
+pub struct Mappings {
+    current: Arc<Mutex<Vec<Mapping>>>,
+}
+
+impl Mappings {
+    pub fn current(&self) -> Vec<Mapping> {
+        self.current.lock().unwrap().clone()
+    }
+
+    fn set(&self, mappings: Vec<Mapping>) {
+        *self.current.lock().unwrap() = mappings;
+    }
+}
+
+/// Forwards `port` on the router to this device, with UPnP or else NAT-PMP
+/// as `config` allows.
+pub async fn map(protocol: Protocol, port: u16, config: &PortMappingConfig) -> Result<Mapping> {
+    let mut errors = Vec::new();
+    if config.upnp {
+        match map_upnp(protocol, port, config.lease).await {
+            Ok(external) => return Ok(Mapping { method: Method::Upnp, protocol, port, external }),
+            Err(e) => errors.push(format!("UPnP: {}", e)),
+        }
+    }
+    if config.nat_pmp {
+        match map_nat_pmp(protocol, port, config).await {
+            Ok(external) => return Ok(Mapping { method: Method::NatPmp, protocol, port, external }),
+            Err(e) => errors.push(format!("NAT-PMP: {}", e)),
+        }
+    }
+    if errors.is_empty() {
+        bail!("Both UPnP and NAT-PMP are disabled");
+    }
+    bail!("{}", errors.join(", "))
+}
+
+/// Keeps `ports` forwarded, renewing the mappings halfway through their
+/// lease, and logs where peers outside the network can connect. Only ends
+/// with the server.
+pub async fn keep_mapped(ports: Vec<(Protocol, u16)>, config: PortMappingConfig, mappings: Mappings) -> Result<()> {
+    let renew = Duration::from_secs((config.lease / 2).max(60));
+    loop {
+        let mut held = Vec::new();
+        for (protocol, port) in &ports {
+            match map(*protocol, *port, &config).await {
+                Ok(mapping) => held.push(mapping),
+                Err(e) => warn!("Could not forward {} port {} on the router, forward it by hand for peers outside the network ({})", protocol, port, e),
+            }
+        }
+        let before = mappings.current();
+        for mapping in &held {
+            if before.contains(mapping) {
+                debug!("Renewed {} port {} with {}", mapping.protocol, mapping.port, mapping.method);
+            } else {
+                info!("Forwarded {} port {} with {}, peers outside the network can connect to {}", mapping.protocol, mapping.port, mapping.method, mapping.external);
+            }
+        }
+        let complete = held.len() == ports.len();
+        mappings.set(held);
+        tokio::time::sleep(if complete { renew } else { RETRY_INTERVAL.min(renew) }).await;
+    }
+}
+
+/// The address this device has towards the router, from routing a socket
+/// to the SSDP group without sending anything.
+async fn local_ip() -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect((Ipv4Addr::new(239, 255, 255, 250), 1900)).await?;
+    match socket.local_addr()?.ip() {
+        IpAddr::V4(ip) if !ip.is_unspecified() => Ok(ip),
+        ip => bail!("No local IPv4 address found, got {}", ip),
+    }
+}
+
+async fn map_upnp(protocol: Protocol, port: u16, lease: u64) -> Result<SocketAddr> {
+    let gateway = search_gateway(SearchOptions { timeout: Some(UPNP_SEARCH_TIMEOUT), ..SearchOptions::default() }).await?;
+    let local = SocketAddr::new(IpAddr::V4(local_ip().await?), port);
+    let protocol = match protocol {
+        Protocol::Tcp => PortMappingProtocol::TCP,
+        Protocol::Udp => PortMappingProtocol::UDP,
+    };
+    gateway.add_port(protocol, port, local, u32::try_from(lease).unwrap_or(u32::MAX), DESCRIPTION).await?;
+    Ok(SocketAddr::new(gateway.get_external_ip().await?, port))
+}
+
+/// The configured gateway, or the `.1` address of this device's network.
+async fn nat_pmp_gateway(config: &PortMappingConfig) -> Result<SocketAddr> {
+    if let Some(gateway) = &config.gateway {
+        return gateway.parse::<SocketAddr>()
+            .or_else(|_| gateway.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, NAT_PMP_PORT)))
+            .map_err(|_| anyhow!("Invalid gateway {}, expected an IP address", gateway));
+    }
+    let [a, b, c, _] = local_ip().await?.octets();
+    Ok(SocketAddr::new(IpAddr::V4(Ipv4Addr::new(a, b, c, 1)), NAT_PMP_PORT))
+}
+
+/// Sends a NAT-PMP request, retrying with a doubling timeout as RFC 6886
+/// asks, and returns the successful response.
+async fn nat_pmp_request(gateway: SocketAddr, request: &[u8]) -> Result<Vec<u8>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.connect(gateway).await?;
+    let mut buf = [0u8; 16];
+    let mut wait = Duration::from_millis(250);
+    for _ in 0..4 {
+        socket.send(request).await?;
+        let Ok(received) = tokio::time::timeout(wait, socket.recv(&mut buf)).await else {
+            wait *= 2;
+            continue;
+        };
+        let response = &buf[..received?];
+        if response.len() < 4 || response[0] != 0 || response[1] != request[1] + 128 {
+            bail!("Unexpected NAT-PMP response from {}", gateway);
+        }
+        match u16::from_be_bytes([response[2], response[3]]) {
+            0 => return Ok(response.to_vec()),
+            code => bail!("{} refused with NAT-PMP result code {}", gateway, code),
+        }
+    }
+    bail!("No NAT-PMP response from {}", gateway)
+}
+
+async fn map_nat_pmp(protocol: Protocol, port: u16, config: &PortMappingConfig) -> Result<SocketAddr> {
+    let gateway = nat_pmp_gateway(config).await?;
+    let response = nat_pmp_request(gateway, &[0, 0]).await?;
+    let Some(ip) = response.get(8..12) else {
+        bail!("Short NAT-PMP address response from {}", gateway);
+    };
+    let ip = Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]);
+
+    let opcode = match protocol {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2,
+    };
+    let mut request = vec![0, opcode, 0, 0];
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    request.extend_from_slice(&u32::try_from(config.lease).unwrap_or(u32::MAX).to_be_bytes());
+    let response = nat_pmp_request(gateway, &request).await?;
+    let Some(external) = response.get(10..12) else {
+        bail!("Short NAT-PMP mapping response from {}", gateway);
+    };
+    // The router may hand out another external port than asked for
+    Ok(SocketAddr::new(IpAddr::V4(ip), u16::from_be_bytes([external[0], external[1]])))
+}
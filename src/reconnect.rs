@@ -0,0 +1,98 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use crate::network::HEARTBEAT_TIMEOUT;
+
+/// Wait before the first attempt to reach a device that went offline.
+const FIRST_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between attempts, so a device that comes back is noticed
+/// within minutes.
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Wait before the `attempt`-th try to reach an offline device, doubling up
+/// to `MAX_DELAY`. Up to half of it is cut off by `random`, between 0 and 1,
+/// so devices that lost the same peer do not all retry at the same moment.
+pub fn backoff(attempt: u32, random: f64) -> Duration {
+    let full = FIRST_DELAY.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(MAX_DELAY);
+    full.mul_f64(1.0 - random.clamp(0.0, 1.0) / 2.0)
+}
+
+/// A random number between 0 and 1 for `backoff`.
+pub fn jitter() -> f64 {
+    let mut bytes = [0u8; 8];
+    match rustls::crypto::ring::default_provider().secure_random.fill(&mut bytes) {
+        Ok(()) => (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64,
+        Err(_) => 0.0,
+    }
+}
+
+/// Whether a device is there, as reported by `/status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PeerState {
+    /// Heard from within `HEARTBEAT_TIMEOUT`.
+    Online,
+    /// Not heard from lately, but nothing failed either, e.g. because there
+    /// was nothing to send.
+    Idle,
+    /// Could not be reached, or stopped answering heartbeats.
+    Offline,
+}
+
+/// Devices that could not be reached, with since when, and when each device
+/// was last heard from. Shared by the transfer workers, which stop sending
+/// to offline devices until they are back, the sessions and the server,
+/// which record what they hear, and `/status`.
+#[derive(Debug, Clone, Default)]
+pub struct Reachability {
+    offline: Arc<Mutex<HashMap<String, SystemTime>>>,
+    seen: Arc<Mutex<HashMap<String, SystemTime>>>,
+}
+
+impl Reachability {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if the device was online until now.
+    pub fn set_offline(&self, device: &str, now: SystemTime) -> bool {
+        let mut offline = self.offline.lock().unwrap();
+        if offline.contains_key(device) {
+            return false;
+        }
+        offline.insert(device.to_string(), now);
+        true
+    }
+
+    /// Returns since when the device was offline, if it was.
+    pub fn set_online(&self, device: &str) -> Option<SystemTime> {
+        self.offline.lock().unwrap().remove(device)
+    }
+
+    pub fn offline_since(&self, device: &str) -> Option<SystemTime> {
+        self.offline.lock().unwrap().get(device).copied()
+    }
+
+    /// Records that something arrived from the device, which shows it is
+    /// back if it was offline.
+    pub fn seen(&self, device: &str, now: SystemTime) {
+        self.seen.lock().unwrap().insert(device.to_string(), now);
+        self.offline.lock().unwrap().remove(device);
+    }
+
+    pub fn last_seen(&self, device: &str) -> Option<SystemTime> {
+        self.seen.lock().unwrap().get(device).copied()
+    }
+
+    pub fn state(&self, device: &str, now: SystemTime) -> PeerState {
+        if self.offline_since(device).is_some() {
+            return PeerState::Offline;
+        }
+        match self.last_seen(device).and_then(|seen| now.duration_since(seen).ok()) {
+            Some(ago) if ago < HEARTBEAT_TIMEOUT => PeerState::Online,
+            _ => PeerState::Idle,
+        }
+    }
+}
@@ -0,0 +1,247 @@
+use anyhow::{anyhow, bail, Result};
+use futures::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use crate::auth::{DeviceAuth, DeviceKeys};
+use crate::network::SyncMessage;
+use crate::shares;
+
+/// Device addresses of this form are reached through a relay:
+/// `relay://<relay host>:<port>/<device>`.
+pub const ADDRESS_PREFIX: &str = "relay://";
+
+/// How long the relay waits for a device to answer a connection, and
+/// anyone waits for the relay's first frame.
+pub const ANSWER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a listening device tells the relay it is still there, so
+/// routers do not drop the idle connection.
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Relay frames are small, anything bigger is not the relay protocol.
+const MAX_FRAME: u32 = 64 * 1024;
+
+/// The relay and device of a `relay://` address.
+pub fn parse_address(address: &str) -> Option<(&str, &str)> {
+    address.strip_prefix(ADDRESS_PREFIX)?.split_once('/')
+}
+
+/// Writes one length-prefixed frame, the same framing as sync connections.
+async fn write_frame(stream: &mut TcpStream, message: &SyncMessage) -> Result<()> {
+    let bytes = serde_json::to_vec(message)?;
+    stream.write_u32(bytes.len() as u32).await?;
+    stream.write_all(&bytes).await?;
+    Ok(())
+}
+
+/// Reads exactly one frame, so nothing after it is buffered away from the
+/// stream that gets piped afterwards.
+async fn read_frame(stream: &mut TcpStream) -> Result<SyncMessage> {
+    let len = tokio::time::timeout(ANSWER_TIMEOUT, stream.read_u32()).await.map_err(|_| anyhow!("No answer within {:?}", ANSWER_TIMEOUT))??;
+    if len > MAX_FRAME {
+        bail!("Frame of {} bytes is not a relay frame", len);
+    }
+    let mut bytes = vec![0; len as usize];
+    stream.read_exact(&mut bytes).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// Connects to `device` through the relay at `relay`. The returned stream
+/// carries the connection to the device as is, so TLS and authentication
+/// work end to end and the relay only sees encrypted bytes.
+pub async fn connect(relay: &str, device: &str) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(relay).await?;
+    write_frame(&mut stream, &SyncMessage::RelayConnect { device: device.to_string() }).await?;
+    // The relay waits up to the same timeout for the device
+    match tokio::time::timeout(ANSWER_TIMEOUT * 2, read_frame(&mut stream)).await.map_err(|_| anyhow!("Relay {} did not answer", relay))?? {
+        SyncMessage::RelayReady => Ok(stream),
+        SyncMessage::RelayRefused { reason } => bail!("Relay {} cannot reach {}: {}", relay, device, reason),
+        _ => bail!("Unexpected answer from relay {}", relay),
+    }
+}
+
+/// Listens as `device` on the relay at `relay` until the relay goes away,
+/// passing every connection made through it to `accepted` along with the
+/// relay's address. `key` proves the name to a relay with device keys.
+pub async fn listen(relay: &str, device: &str, key: Option<&str>, mut accepted: impl FnMut(TcpStream, SocketAddr)) -> Result<()> {
+    let stream = TcpStream::connect(relay).await?;
+    let addr = stream.peer_addr()?;
+    let mut control = Framed::new(stream, LengthDelimitedCodec::new());
+    let auth = key.map(|key| DeviceAuth::new(device, key, SystemTime::now())).transpose()?;
+    let listen = Bytes::from(serde_json::to_vec(&SyncMessage::RelayListen { device: device.to_string(), auth })?);
+    control.send(listen.clone()).await?;
+    info!("Accepting connections through relay {} as {}", relay, device);
+    let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = keepalive.tick() => control.send(listen.clone()).await?,
+            frame = control.next() => {
+                let Some(frame) = frame else {
+                    return Ok(());
+                };
+                let token = match serde_json::from_slice(&frame?)? {
+                    SyncMessage::RelayIncoming { token } => token,
+                    SyncMessage::RelayRefused { reason } => bail!("Relay {} refused {}: {}", relay, device, reason),
+                    _ => bail!("Unexpected message from relay {}", relay),
+                };
+                let mut stream = TcpStream::connect(relay).await?;
+                write_frame(&mut stream, &SyncMessage::RelayAccept { token }).await?;
+                accepted(stream, addr);
+            }
+        }
+    }
+}
+
+/// Forwards connections between devices that cannot accept connections
+/// themselves. Devices listen under their name, and every connection to a
+/// name is paired with a fresh connection from that device, then piped
+/// through unchanged.
+#[derive(Debug, Clone, Default)]
+pub struct Relay {
+    /// Where to send the tokens of connections to each listening device.
+    listening: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<String>>>>,
+    /// Connections waiting for the device to answer, by token.
+    waiting: Arc<Mutex<HashMap<String, oneshot::Sender<TcpStream>>>>,
+    keys: DeviceKeys,
+    /// Device that first listened under each name with its key. Only it
+    /// may listen under the name again.
+    owners: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl Relay {
+    /// Keys of the devices allowed to listen. When any device has one,
+    /// listeners have to prove a name before they get it.
+    pub fn with_device_keys(mut self, keys: DeviceKeys) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    pub async fn serve(&self, listener: TcpListener) -> Result<()> {
+        info!("Relaying connections on {}", listener.local_addr()?);
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            let relay = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = relay.handle(stream, addr).await {
+                    debug!("Relay connection from {} ended: {}", addr, e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        match read_frame(&mut stream).await? {
+            SyncMessage::RelayListen { device, auth } => {
+                if let Err(e) = self.claim(&device, auth.as_ref()) {
+                    write_frame(&mut stream, &SyncMessage::RelayRefused { reason: e.to_string() }).await?;
+                    return Err(e);
+                }
+                self.listen(stream, device).await
+            }
+            SyncMessage::RelayConnect { device } => self.connect(stream, addr, device).await,
+            SyncMessage::RelayAccept { token } => {
+                let waiting = self.waiting.lock().unwrap().remove(&token);
+                match waiting {
+                    Some(waiting) => waiting.send(stream).map_err(|_| anyhow!("The connecting side gave up")),
+                    None => bail!("Unknown or expired token"),
+                }
+            }
+            _ => bail!("Not a relay message"),
+        }
+    }
+
+    /// Checks that whoever listens as `device` may have the name, binding it
+    /// to the first device that proves it.
+    fn claim(&self, device: &str, auth: Option<&DeviceAuth>) -> Result<()> {
+        let prover = match auth {
+            Some(auth) => {
+                self.keys.verify(auth, SystemTime::now())?;
+                Some(auth.device.as_str())
+            }
+            None if self.keys.required() => bail!("Listening as {} requires a device key", device),
+            None => None,
+        };
+        let mut owners = self.owners.lock().unwrap();
+        match (owners.get(device), prover) {
+            (Some(owner), Some(prover)) if owner != prover => bail!("{} belongs to {}, not {}", device, owner, prover),
+            (Some(owner), None) => bail!("{} belongs to {}", device, owner),
+            (None, Some(prover)) => {
+                owners.insert(device.to_string(), prover.to_string());
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Holds the control connection of `device`, forwarding it tokens
+    /// until it closes or a newer connection of the device replaces it.
+    async fn listen(&self, stream: TcpStream, device: String) -> Result<()> {
+        let (tokens, mut incoming) = mpsc::unbounded_channel();
+        if self.listening.lock().unwrap().insert(device.clone(), tokens.clone()).is_some() {
+            debug!("{} listens again, replacing its earlier connection", device);
+        }
+        info!("{} is listening through the relay", device);
+        let mut control = Framed::new(stream, LengthDelimitedCodec::new());
+        let result = loop {
+            tokio::select! {
+                token = incoming.recv() => match token {
+                    Some(token) => {
+                        let incoming = Bytes::from(serde_json::to_vec(&SyncMessage::RelayIncoming { token })?);
+                        if let Err(e) = control.send(incoming).await {
+                            break Err(e.into());
+                        }
+                    }
+                    None => break Ok(()),
+                },
+                frame = control.next() => match frame {
+                    // Keepalives repeat the listen message
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => break Err(e.into()),
+                    None => break Ok(()),
+                },
+            }
+        };
+        let mut listening = self.listening.lock().unwrap();
+        if listening.get(&device).is_some_and(|current| current.same_channel(&tokens)) {
+            listening.remove(&device);
+            info!("{} stopped listening through the relay", device);
+        }
+        result
+    }
+
+    /// Asks `device` for a connection and pipes it to `stream` once it arrives.
+    async fn connect(&self, mut stream: TcpStream, addr: SocketAddr, device: String) -> Result<()> {
+        let listener = self.listening.lock().unwrap().get(&device).cloned();
+        let Some(listener) = listener else {
+            let reason = format!("{} is not listening on this relay", device);
+            return write_frame(&mut stream, &SyncMessage::RelayRefused { reason }).await;
+        };
+        let token = shares::random_key()?;
+        let (answer, answered) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(token.clone(), answer);
+        let other = match listener.send(token.clone()) {
+            Ok(()) => tokio::time::timeout(ANSWER_TIMEOUT, answered).await.ok().and_then(Result::ok),
+            Err(_) => None,
+        };
+        let Some(mut other) = other else {
+            self.waiting.lock().unwrap().remove(&token);
+            let reason = format!("{} did not answer", device);
+            return write_frame(&mut stream, &SyncMessage::RelayRefused { reason }).await;
+        };
+        write_frame(&mut stream, &SyncMessage::RelayReady).await?;
+        info!("Relaying {} to {}", addr, device);
+        match tokio::io::copy_bidirectional(&mut stream, &mut other).await {
+            Ok((sent, received)) => debug!("Relayed {} bytes from {} to {} and {} back", sent, addr, device, received),
+            Err(e) => warn!("Relaying {} to {} failed: {}", addr, device, e),
+        }
+        Ok(())
+    }
+}
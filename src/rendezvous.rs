@@ -0,0 +1,110 @@
+use anyhow::{Result, anyhow, bail};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use log::{info, warn, debug};
+use crate::config::{Device, RendezvousConfig};
+use crate::discovery::Discovered;
+use crate::network::SyncClient;
+
+/// Registrations older than this are treated as gone, so a device that went
+/// offline does not keep handing out its last known address.
+pub const REGISTRATION_TTL: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Clone, Default)]
+pub struct RendezvousRegistry {
+    entries: Arc<Mutex<HashMap<String, (SocketAddr, Instant)>>>,
+    /// Device that first registered each ID with its key. Only it may
+    /// register the ID again, also after its registration expired.
+    owners: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl RendezvousRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `id` at `addr`. `device` is who the connection authenticated
+    /// as, or `None` on a relay that requires no keys.
+    pub async fn register(&self, id: String, addr: SocketAddr, device: Option<&str>) -> Result<()> {
+        let mut owners = self.owners.lock().await;
+        match (owners.get(&id), device) {
+            (Some(owner), Some(device)) if owner != device => bail!("Rendezvous ID {} belongs to {}, not {}", id, owner, device),
+            (Some(owner), None) => bail!("Rendezvous ID {} belongs to {}", id, owner),
+            (None, Some(device)) => {
+                owners.insert(id.clone(), device.to_string());
+            }
+            _ => {}
+        }
+        self.entries.lock().await.insert(id, (addr, Instant::now()));
+        Ok(())
+    }
+
+    pub async fn lookup(&self, id: &str) -> Option<SocketAddr> {
+        let mut entries = self.entries.lock().await;
+        entries.retain(|_, (_, seen)| seen.elapsed() < REGISTRATION_TTL);
+        entries.get(id).map(|(addr, _)| *addr)
+    }
+}
+
+/// Where devices are looked up besides their configured address.
+#[derive(Debug, Clone, Default)]
+pub struct Lookup {
+    pub rendezvous: Option<RendezvousConfig>,
+    /// Peers announced on the local network, when discovery is on.
+    pub discovered: Option<Discovered>,
+}
+
+/// Resolves the address to connect to for a device. Where the device last
+/// announced itself on the local network wins, as it follows IP changes.
+/// Otherwise a configured address is used as-is (hostnames are resolved by
+/// the connect call, so dynamic-DNS names pick up IP changes), or else the
+/// rendezvous ID is looked up on the relay, connecting to it with `connect`.
+pub async fn resolve_device(device: &Device, lookup: &Lookup, connect: impl Fn(String) -> SyncClient) -> Result<String> {
+    if let Some(address) = lookup.discovered.as_ref().and_then(|d| d.address(&device.name)) {
+        debug!("Found {} on the local network at {}", device.name, address);
+        return Ok(address);
+    }
+    if !device.address.is_empty() {
+        return Ok(device.address.clone());
+    }
+
+    let id = device.rendezvous.as_ref()
+        .ok_or_else(|| anyhow!("Device {} has neither an address nor a rendezvous ID", device.name))?;
+    let relay = lookup.rendezvous.as_ref()
+        .ok_or_else(|| anyhow!("Device {} uses rendezvous ID {} but no relay is configured", device.name, id))?;
+
+    let address = connect(relay.relay.clone())
+        .lookup_rendezvous(id.clone())
+        .await?
+        .ok_or_else(|| anyhow!("Rendezvous ID {} is not registered on {}", id, relay.relay))?;
+    debug!("Resolved {} via rendezvous {} to {}", device.name, id, address);
+    Ok(address)
+}
+
+/// How often to register, from `register_interval` seconds kept between one
+/// second and a third of `REGISTRATION_TTL`, so a lost registration or two
+/// does not expire the entry.
+pub fn registration_interval(config: &RendezvousConfig) -> Duration {
+    Duration::from_secs(config.register_interval).clamp(Duration::from_secs(1), REGISTRATION_TTL / 3)
+}
+
+/// Periodically announces this device on the relay under its rendezvous ID.
+pub async fn run_registration(config: RendezvousConfig, port: u16, connect: impl Fn(String) -> SyncClient) {
+    let client = connect(config.relay.clone());
+    let every = registration_interval(&config);
+    if every.as_secs() != config.register_interval {
+        warn!("register_interval of {} seconds is out of range, registering every {} seconds", config.register_interval, every.as_secs());
+    }
+    let mut interval = tokio::time::interval(every);
+    info!("Registering as {} on relay {}", config.id, config.relay);
+
+    loop {
+        interval.tick().await;
+        if let Err(e) = client.register_rendezvous(config.id.clone(), port).await {
+            warn!("Failed to register with relay {}: {}", config.relay, e);
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+use crate::app_dirs::AppDirs;
+use crate::config::{Config, ReportConfig, ReportSchedule};
+use crate::conflicts;
+use crate::deletions::PendingDeletions;
+use crate::exclusions::Exclusions;
+use crate::manifest::ManifestCache;
+use crate::mcworld;
+use crate::smtp::Email;
+use crate::snapshots::{SnapshotUsage, Snapshots};
+use crate::usage::{self, Usage, UsageLog};
+
+/// How often the daemon checks whether a report is due.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// What a report is gathered from, taken from the config so the daemon can
+/// gather one whenever it is due.
+#[derive(Debug, Clone)]
+pub struct ReportSources {
+    pub device: String,
+    pub devices: Vec<String>,
+    pub stale_after_days: u64,
+    pub worlds_root: PathBuf,
+    pub exclusions: Exclusions,
+    pub app_dirs: AppDirs,
+}
+
+impl ReportSources {
+    pub fn new(config: &Config, app_dirs: &AppDirs) -> Result<Self> {
+        Ok(Self {
+            device: config.sync.local_name(),
+            devices: config.sync.all_devices().into_iter().map(|device| device.name).collect(),
+            stale_after_days: config.sync.stale_after_days,
+            worlds_root: config.paths.worlds_dir(),
+            exclusions: Exclusions::new(&config.watch.exclude).with_ignore(&config.watch.ignore)?,
+            app_dirs: app_dirs.clone(),
+        })
+    }
+}
+
+/// How a configured device has been syncing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSync {
+    pub name: String,
+    pub last_synced: Option<SystemTime>,
+    pub stale: bool,
+}
+
+/// A world folder and the space it takes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorldSize {
+    pub folder: String,
+    pub name: String,
+    pub size: u64,
+}
+
+/// Summary of the last `days` days, for the `report` command and the
+/// emails of the `report` config section.
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub device: String,
+    pub days: u64,
+    pub at: SystemTime,
+    pub sent: Usage,
+    pub devices: Vec<DeviceSync>,
+    /// World folders kept as conflict copies, see `conflicts::copy_name`.
+    pub conflicts: Vec<String>,
+    /// Deletions received and held back, see `PendingDeletions`.
+    pub pending_deletions: usize,
+    pub worlds: Vec<WorldSize>,
+    pub snapshots: SnapshotUsage,
+}
+
+impl Report {
+    pub async fn gather(sources: &ReportSources, days: u64, now: SystemTime) -> Result<Self> {
+        let app_dirs = &sources.app_dirs;
+        let last_synced = ManifestCache::load(app_dirs.cursors_file()).last_synced().await;
+        let stale_after = Duration::from_secs(sources.stale_after_days * 24 * 60 * 60);
+        let devices = sources.devices.iter()
+            .map(|name| {
+                let last_synced = last_synced.get(name).copied();
+                let stale = last_synced.is_some_and(|at| now.duration_since(at).is_ok_and(|offline| offline > stale_after));
+                DeviceSync { name: name.clone(), last_synced, stale }
+            })
+            .collect();
+
+        let mut conflicts = Vec::new();
+        let mut worlds = Vec::new();
+        for entry in fs::read_dir(&sources.worlds_root)? {
+            let path = entry?.path();
+            if !path.is_dir() || sources.exclusions.is_excluded(&sources.worlds_root, &path) {
+                continue;
+            }
+            let folder = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            if conflicts::is_copy(&folder) {
+                conflicts.push(folder.clone());
+            }
+            worlds.push(WorldSize { name: mcworld::display_name(&path), size: usage::folder_size(&path)?, folder });
+        }
+        conflicts.sort();
+        worlds.sort_by(|a, b| a.folder.cmp(&b.folder));
+
+        Ok(Self {
+            device: sources.device.clone(),
+            days,
+            at: now,
+            sent: UsageLog::new(app_dirs.usage_file()).last(days, now)?,
+            devices,
+            conflicts,
+            pending_deletions: PendingDeletions::new(app_dirs.deletions_file()).list()?.len(),
+            worlds,
+            snapshots: Snapshots::new(app_dirs.snapshots()).usage(&sources.worlds_root)?,
+        })
+    }
+
+    pub fn stale_devices(&self) -> usize {
+        self.devices.iter().filter(|device| device.stale).count()
+    }
+
+    /// Names what needs attention, so it shows in the inbox.
+    pub fn subject(&self) -> String {
+        let mut attention = Vec::new();
+        if !self.conflicts.is_empty() {
+            attention.push(plural(self.conflicts.len(), "conflict copy", "conflict copies"));
+        }
+        if self.stale_devices() > 0 {
+            attention.push(plural(self.stale_devices(), "stale device", "stale devices"));
+        }
+        if self.sent.failed > 0 {
+            attention.push(plural(self.sent.failed as usize, "failed transfer", "failed transfers"));
+        }
+        let state = if attention.is_empty() { "all well".to_string() } else { attention.join(", ") };
+        format!("World sync on {}, {}: {}", self.device, conflicts::date(self.at), state)
+    }
+
+    pub fn body(&self) -> String {
+        let period = if self.days == 1 { "today".to_string() } else { format!("the last {} days", self.days) };
+        let mut lines = vec![
+            format!("Summary of {} on {}, up to {}.", period, self.device, conflicts::date(self.at)),
+            String::new(),
+            "Sync".to_string(),
+            format!("  {} sent to peers, {} of files in {} on the wire", plural(self.sent.files as usize, "file", "files"), usage::format_bytes(self.sent.size), usage::format_bytes(self.sent.wire)),
+            format!("  {}", plural(self.sent.failed as usize, "failed attempt", "failed attempts")),
+            String::new(),
+            "Devices".to_string(),
+        ];
+        if self.devices.is_empty() {
+            lines.push("  none configured".to_string());
+        }
+        for device in &self.devices {
+            let state = match device.last_synced {
+                Some(at) if device.stale => format!("last synced {}, stale", conflicts::date(at)),
+                Some(at) => format!("last synced {}", conflicts::date(at)),
+                None => "never synced".to_string(),
+            };
+            lines.push(format!("  {}: {}", device.name, state));
+        }
+
+        lines.extend([String::new(), "Conflicts pending".to_string()]);
+        if self.conflicts.is_empty() {
+            lines.push("  none".to_string());
+        }
+        lines.extend(self.conflicts.iter().map(|copy| format!("  {}", copy)));
+        if self.pending_deletions > 0 {
+            lines.push(format!("  {} received and not applied, see `mcbd-world-sync deletions`", plural(self.pending_deletions, "deletion", "deletions")));
+        }
+
+        lines.extend([String::new(), "Storage".to_string()]);
+        lines.extend(self.worlds.iter().map(|world| format!("  {} ({}): {}", world.name, world.folder, usage::format_bytes(world.size))));
+        let total: u64 = self.worlds.iter().map(|world| world.size).sum();
+        lines.push(format!("  worlds: {} in {}", usage::format_bytes(total), plural(self.worlds.len(), "world", "worlds")));
+        lines.push(format!("  snapshots: {} on disk for {}", usage::format_bytes(self.snapshots.stored), plural(self.snapshots.snapshots, "snapshot", "snapshots")));
+        lines.join("\n") + "\n"
+    }
+
+    pub fn email(&self, config: &ReportConfig) -> Email {
+        Email { from: config.from.clone(), to: config.to.clone(), subject: self.subject(), body: self.body() }
+    }
+}
+
+fn plural(count: usize, one: &str, many: &str) -> String {
+    format!("{} {}", count, if count == 1 { one } else { many })
+}
+
+/// When the last report was emailed, kept in `report.json` in the state
+/// directory so restarts keep to the schedule.
+#[derive(Debug, Clone)]
+pub struct ReportTimes {
+    file: PathBuf,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Sent {
+    last_sent: SystemTime,
+}
+
+impl ReportTimes {
+    pub fn new(file: PathBuf) -> Self {
+        Self { file }
+    }
+
+    pub fn last_sent(&self) -> Result<Option<SystemTime>> {
+        match fs::read(&self.file) {
+            Ok(data) => Ok(Some(serde_json::from_slice::<Sent>(&data)?.last_sent)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn record(&self, at: SystemTime) -> Result<()> {
+        let tmp = self.file.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&Sent { last_sent: at })?)?;
+        fs::rename(&tmp, &self.file)?;
+        Ok(())
+    }
+
+    /// Whether a report of `schedule` is due at `now`. The first check only
+    /// starts the schedule, so the first report covers a full period.
+    pub fn due(&self, schedule: &ReportSchedule, now: SystemTime) -> Result<bool> {
+        let Some(last) = self.last_sent()? else {
+            self.record(now)?;
+            return Ok(false);
+        };
+        let period = Duration::from_secs(schedule.days() * 24 * 60 * 60);
+        Ok(now.duration_since(last).is_ok_and(|since| since >= period))
+    }
+}
@@ -0,0 +1,117 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Component, Path, PathBuf, Prefix};
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use log::{debug, warn};
+
+/// A Volume Shadow Copy of the volume a world is on: every file as it was
+/// at one instant, which the game's later writes do not change. Reading a
+/// world that is played from it gives a crash-consistent copy instead of
+/// files caught mid-write. Only Windows has them, and only administrators
+/// may take them. Deleted once dropped.
+#[derive(Debug)]
+pub struct ShadowCopy {
+    id: String,
+    /// The volume, such as `C:\`.
+    volume: PathBuf,
+    /// Where the copy of the volume is read, such as
+    /// `\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3`.
+    device: PathBuf,
+}
+
+impl ShadowCopy {
+    /// Takes a shadow copy of the volume `path` is on, which takes a few
+    /// seconds.
+    pub fn create(path: &Path) -> Result<Self> {
+        if !cfg!(windows) {
+            bail!("Shadow copies are only available on Windows");
+        }
+        let volume = volume_of(path).ok_or_else(|| anyhow!("{} is not on a drive with a letter", path.display()))?;
+        let script = format!(
+            "$created = (Get-WmiObject -List Win32_ShadowCopy).Create('{}', 'ClientAccessible'); \
+             if ($created.ReturnValue -ne 0) {{ throw \"Win32_ShadowCopy.Create returned $($created.ReturnValue), is the daemon running as administrator?\" }}; \
+             $copy = Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq $created.ShadowID }}; $copy.ID; $copy.DeviceObject",
+            volume.display()
+        );
+        let output = powershell(&script)?;
+        let mut lines = output.lines().map(str::trim).filter(|line| !line.is_empty());
+        match (lines.next(), lines.next()) {
+            (Some(id), Some(device)) => {
+                debug!("Took shadow copy {} of {}", id, volume.display());
+                Ok(Self { id: id.to_string(), volume, device: PathBuf::from(device) })
+            }
+            _ => bail!("Windows did not report the shadow copy of {}", volume.display()),
+        }
+    }
+
+    /// Where `path`, a full path on the copied volume, is in the copy.
+    pub fn path_of(&self, path: &Path) -> Option<PathBuf> {
+        if volume_of(path)? != self.volume {
+            return None;
+        }
+        let rest: PathBuf = path.components().skip_while(|c| matches!(c, Component::Prefix(_) | Component::RootDir)).collect();
+        Some(self.device.join(rest))
+    }
+}
+
+impl Drop for ShadowCopy {
+    fn drop(&mut self) {
+        let script = format!("Get-WmiObject Win32_ShadowCopy | Where-Object {{ $_.ID -eq '{}' }} | ForEach-Object {{ $_.Delete() }}", self.id);
+        match powershell(&script) {
+            Ok(_) => debug!("Deleted shadow copy {}", self.id),
+            Err(e) => warn!("Failed to delete shadow copy {} of {}: {}", self.id, self.volume.display(), e),
+        }
+    }
+}
+
+/// The drive `path` is on, such as `C:\`.
+fn volume_of(path: &Path) -> Option<PathBuf> {
+    match path.components().next()? {
+        Component::Prefix(prefix) => match prefix.kind() {
+            Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => Some(PathBuf::from(format!("{}:\\", letter.to_ascii_uppercase() as char))),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn powershell(script: &str) -> Result<String> {
+    let output = Command::new("powershell").args(["-NoProfile", "-NonInteractive", "-Command", script]).output()?;
+    if !output.status.success() {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// The shadow copies worlds are read from, by world folder, until the game
+/// closes them.
+#[derive(Debug, Clone, Default)]
+pub struct ShadowCopies {
+    copies: Arc<Mutex<BTreeMap<String, Arc<ShadowCopy>>>>,
+}
+
+impl ShadowCopies {
+    /// Reads `world` from `copy` from now on, instead of an older copy.
+    pub fn insert(&self, world: String, copy: ShadowCopy) {
+        // Deleted once the lock is released, deleting takes a moment
+        let older = self.copies.lock().unwrap().insert(world, Arc::new(copy));
+        drop(older);
+    }
+
+    pub fn get(&self, world: &str) -> Option<Arc<ShadowCopy>> {
+        self.copies.lock().unwrap().get(world).cloned()
+    }
+
+    /// Drops the copies of the worlds that are not `open` anymore, which
+    /// are read as they are again.
+    pub fn retain(&self, open: &BTreeSet<String>) {
+        let closed = {
+            let mut copies = self.copies.lock().unwrap();
+            let (kept, closed): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut *copies).into_iter().partition(|(world, _)| open.contains(world));
+            *copies = kept;
+            closed
+        };
+        drop(closed);
+    }
+}
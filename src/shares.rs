@@ -0,0 +1,123 @@
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::path::{Component, Path};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::config::{Config, Device, Direction, GroupConfig};
+use crate::groups::Groups;
+
+const TOKEN_PREFIX: &str = "mcbd-share";
+
+/// Everything a friend needs to join a share, passed along as one string.
+/// A share is a group of one device and one world that expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShareToken {
+    pub group: String,
+    pub world: String,
+    /// Whether the friend may send changes back.
+    pub writable: bool,
+    /// Unix time the share ends.
+    pub expires_at: u64,
+    pub key: String,
+}
+
+impl ShareToken {
+    /// Parses `mcbd-share/<group>/<r|rw>/<expires_at>/<key>/<world>`.
+    pub fn parse(token: &str) -> Result<Self> {
+        let invalid = || anyhow!("Not a share token: {}", token);
+        let mut parts = token.trim().splitn(6, '/');
+        if parts.next() != Some(TOKEN_PREFIX) {
+            return Err(invalid());
+        }
+        let (Some(group), Some(mode), Some(expires_at), Some(key), Some(world)) = (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) else {
+            return Err(invalid());
+        };
+        let writable = match mode {
+            "rw" => true,
+            "r" => false,
+            _ => return Err(invalid()),
+        };
+        check_world(world)?;
+        Ok(Self {
+            group: group.to_string(),
+            world: world.to_string(),
+            writable,
+            expires_at: expires_at.parse().map_err(|_| invalid())?,
+            key: key.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for ShareToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = if self.writable { "rw" } else { "r" };
+        write!(f, "{}/{}/{}/{}/{}/{}", TOKEN_PREFIX, self.group, mode, self.expires_at, self.key, self.world)
+    }
+}
+
+fn check_world(world: &str) -> Result<()> {
+    let mut components = Path::new(world).components();
+    if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+        bail!("{} is not a world folder name", world);
+    }
+    Ok(())
+}
+
+/// 128 random bits as hex, for keys and link tokens.
+pub fn random_key() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    rustls::crypto::ring::default_provider().secure_random.fill(&mut bytes).map_err(|_| anyhow!("No secure random source available"))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Adds `group` after checking it against the configured ones.
+fn add_group(config: &mut Config, group: GroupConfig) -> Result<()> {
+    let mut groups = config.sync.groups();
+    groups.push(group.clone());
+    Groups::new(groups)?;
+    config.sync.groups.push(group);
+    Ok(())
+}
+
+/// Shares one world with `friend` until `days` from `now`. Read-only shares
+/// refuse anything the friend sends. Saving `config` is up to the caller.
+pub fn create(config: &mut Config, world: &str, friend: Device, days: u64, writable: bool, now: SystemTime) -> Result<ShareToken> {
+    check_world(world)?;
+    let expires_at = (now + Duration::from_secs(days * 24 * 60 * 60)).duration_since(UNIX_EPOCH)?.as_secs();
+    let token = ShareToken {
+        group: format!("share-{}", friend.name),
+        world: world.to_string(),
+        writable,
+        expires_at,
+        key: random_key()?,
+    };
+    add_group(config, GroupConfig {
+        name: token.group.clone(),
+        devices: vec![friend],
+        worlds: vec![token.world.clone()],
+        key: Some(token.key.clone()),
+        direction: if writable { Direction::Both } else { Direction::SendOnly },
+        expires_at: Some(expires_at),
+    })?;
+    Ok(token)
+}
+
+/// The friend's side of a share: receives the world from `host`, and sends
+/// changes back if the share is writable. Saving `config` is up to the caller.
+pub fn join(config: &mut Config, token: &ShareToken, host: Device) -> Result<()> {
+    add_group(config, GroupConfig {
+        name: token.group.clone(),
+        devices: vec![host],
+        worlds: vec![token.world.clone()],
+        key: Some(token.key.clone()),
+        direction: if token.writable { Direction::Both } else { Direction::ReceiveOnly },
+        expires_at: Some(token.expires_at),
+    })
+}
+
+/// Ends a share, by group name or the name of the device it was shared with.
+pub fn revoke(config: &mut Config, name: &str) -> Result<GroupConfig> {
+    let position = config.sync.groups.iter()
+        .position(|g| g.expires_at.is_some() && (g.name == name || g.devices.iter().any(|d| d.name == name)))
+        .ok_or_else(|| anyhow!("No share named {}", name))?;
+    Ok(config.sync.groups.remove(position))
+}
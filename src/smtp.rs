@@ -0,0 +1,157 @@
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use rustls::pki_types::ServerName;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use crate::config::{SmtpConfig, SmtpSecurity};
+use crate::tls;
+
+/// How long sending one email may take, from connecting to the last reply.
+const SEND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// An email with a plain text body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub from: String,
+    pub to: Vec<String>,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    /// The message as sent after `DATA`, with headers, CRLF line endings and
+    /// lines starting with a dot doubled, ending with the lone dot.
+    pub fn encode(&self, at: SystemTime) -> String {
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            self.from, self.to.join(", "), self.subject, rfc2822(at)
+        );
+        for line in self.body.lines() {
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push_str(".\r\n");
+        message
+    }
+}
+
+/// Sends `email` through the server of `config`.
+pub async fn send(config: &SmtpConfig, email: &Email) -> Result<()> {
+    if email.to.is_empty() {
+        bail!("The email has no recipients");
+    }
+    tokio::time::timeout(SEND_TIMEOUT, deliver(config, email)).await
+        .map_err(|_| anyhow!("{}:{} did not finish within {} seconds", config.host, config.port, SEND_TIMEOUT.as_secs()))?
+}
+
+async fn deliver(config: &SmtpConfig, email: &Email) -> Result<()> {
+    let stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    let name = ServerName::try_from(config.host.clone())?;
+    match config.security {
+        SmtpSecurity::Tls => {
+            let stream = tls::system_connector()?.connect(name, stream).await?;
+            let mut session = Session::new(stream);
+            session.expect(2).await?;
+            session.hello().await?;
+            session.transaction(config, email).await
+        }
+        SmtpSecurity::Starttls => {
+            let mut session = Session::new(stream);
+            session.expect(2).await?;
+            session.hello().await?;
+            session.command("STARTTLS", 2).await?;
+            let stream = tls::system_connector()?.connect(name, session.into_inner()).await?;
+            let mut session = Session::new(stream);
+            session.hello().await?;
+            session.transaction(config, email).await
+        }
+        SmtpSecurity::None => {
+            let mut session = Session::new(stream);
+            session.expect(2).await?;
+            session.hello().await?;
+            session.transaction(config, email).await
+        }
+    }
+}
+
+struct Session<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    fn new(stream: S) -> Self {
+        Self { stream: BufReader::new(stream) }
+    }
+
+    fn into_inner(self) -> S {
+        self.stream.into_inner()
+    }
+
+    async fn hello(&mut self) -> Result<()> {
+        self.command("EHLO mcbd-world-sync", 2).await?;
+        Ok(())
+    }
+
+    async fn transaction(&mut self, config: &SmtpConfig, email: &Email) -> Result<()> {
+        if let Some(username) = &config.username {
+            let credentials = format!("\0{}\0{}", username, config.password.as_deref().unwrap_or(""));
+            self.command(&format!("AUTH PLAIN {}", STANDARD.encode(credentials)), 2).await?;
+        }
+        self.command(&format!("MAIL FROM:<{}>", email.from), 2).await?;
+        for to in &email.to {
+            self.command(&format!("RCPT TO:<{}>", to), 2).await?;
+        }
+        self.command("DATA", 3).await?;
+        self.stream.get_mut().write_all(email.encode(SystemTime::now()).as_bytes()).await?;
+        self.expect(2).await?;
+        self.command("QUIT", 2).await?;
+        Ok(())
+    }
+
+    /// Sends `line` and reads the reply, which must be of the `class`,
+    /// such as 2 for 250.
+    async fn command(&mut self, line: &str, class: u16) -> Result<String> {
+        self.stream.get_mut().write_all(format!("{}\r\n", line).as_bytes()).await?;
+        let verb = line.split(' ').next().unwrap_or(line);
+        self.expect(class).await.map_err(|e| anyhow!("{} failed: {}", verb, e))
+    }
+
+    /// Reads a reply, every line of a multiline one, and checks its class.
+    async fn expect(&mut self, class: u16) -> Result<String> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                bail!("The mail server closed the connection");
+            }
+            let line = line.trim_end();
+            let code: u16 = line.get(..3).and_then(|code| code.parse().ok()).ok_or_else(|| anyhow!("Unexpected reply: {}", line))?;
+            text.push_str(line.get(4..).unwrap_or(""));
+            if line.as_bytes().get(3) != Some(&b'-') {
+                if code / 100 != class {
+                    bail!("{}", line);
+                }
+                return Ok(text);
+            }
+            text.push('\n');
+        }
+    }
+}
+
+/// `at` as the `Date` header wants it, in UTC.
+fn rfc2822(at: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let date = crate::conflicts::date(at);
+    let (year, month, day) = (&date[..4], date[5..7].parse::<usize>().unwrap_or(1), &date[8..]);
+    format!(
+        "{}, {} {} {} {:02}:{:02}:{:02} +0000",
+        DAYS[(secs / 86400 % 7) as usize], day, MONTHS[month - 1], year, secs / 3600 % 24, secs / 60 % 60, secs % 60
+    )
+}
@@ -0,0 +1,379 @@
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::exclusions::STAGING_DIR;
+
+/// Automatic snapshots kept per world; the oldest are removed first.
+pub const MAX_SNAPSHOTS: usize = 10;
+
+/// A copy of a world taken before something overwrote it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub world: String,
+    /// Folder name, `<unix seconds>-<reason>`.
+    pub name: String,
+    pub taken_at: SystemTime,
+    pub reason: String,
+    pub path: PathBuf,
+}
+
+/// A file that differs between two snapshots, with its size in each. A
+/// file missing from one of them has no size there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDiff {
+    pub path: PathBuf,
+    pub before: Option<u64>,
+    pub after: Option<u64>,
+}
+
+/// Disk space taken by snapshots.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SnapshotUsage {
+    pub snapshots: usize,
+    /// Size of all the snapshots as if each were a full copy.
+    pub size: u64,
+    /// What they take on disk, counting each table once where it is
+    /// hard-linked between snapshots and with its world.
+    pub stored: u64,
+}
+
+/// Copies of worlds in the state directory, one folder per world. LevelDB
+/// tables, which make up most of a world and are never written again once
+/// complete, are hard-linked where the filesystem allows, so a snapshot mostly
+/// costs the small files Minecraft keeps rewriting. Received files replace
+/// the old ones instead of being written into them, see
+/// `FileManager::save_file_content`, so they never change a snapshot.
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    dir: PathBuf,
+}
+
+impl Snapshots {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Duplicates `worlds_root/world`, dropping the oldest snapshots of the
+    /// world beyond `MAX_SNAPSHOTS`.
+    pub fn take(&self, worlds_root: &Path, world: &str, reason: &str, now: SystemTime) -> Result<Snapshot> {
+        let snapshot = self.duplicate(worlds_root, world, reason, now)?;
+        self.prune(world)?;
+        Ok(snapshot)
+    }
+
+    fn duplicate(&self, worlds_root: &Path, world: &str, reason: &str, now: SystemTime) -> Result<Snapshot> {
+        check_world(world)?;
+        if reason.is_empty() || !reason.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') {
+            bail!("Invalid snapshot reason {}", reason);
+        }
+        let world_dir = worlds_root.join(world);
+        if !world_dir.is_dir() {
+            bail!("No world folder {}", world);
+        }
+        let secs = now.duration_since(UNIX_EPOCH)?.as_secs();
+        let base = format!("{}-{}", secs, reason);
+        let mut name = base.clone();
+        let mut n = 1;
+        while self.dir.join(world).join(&name).exists() {
+            n += 1;
+            name = format!("{}-{}", base, n);
+        }
+        let path = self.dir.join(world).join(&name);
+        let staging = self.dir.join(world).join(format!(".{}", name));
+        let copied = copy_world(&world_dir, &staging).and_then(|_| Ok(fs::rename(&staging, &path)?));
+        if let Err(e) = copied {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+        Ok(Snapshot { world: world.to_string(), name, taken_at: UNIX_EPOCH + Duration::from_secs(secs), reason: reason.to_string(), path })
+    }
+
+    fn prune(&self, world: &str) -> Result<()> {
+        let snapshots = self.list(world)?;
+        for old in snapshots.iter().take(snapshots.len().saturating_sub(MAX_SNAPSHOTS)) {
+            fs::remove_dir_all(&old.path)?;
+        }
+        Ok(())
+    }
+
+    /// Snapshots of `world`, oldest first.
+    pub fn list(&self, world: &str) -> Result<Vec<Snapshot>> {
+        check_world(world)?;
+        let Ok(entries) = fs::read_dir(self.dir.join(world)) else {
+            return Ok(Vec::new());
+        };
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some((secs, reason)) = name.split_once('-') else {
+                continue;
+            };
+            let Ok(secs) = secs.parse::<u64>() else {
+                continue;
+            };
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+            snapshots.push(Snapshot {
+                world: world.to_string(),
+                taken_at: UNIX_EPOCH + Duration::from_secs(secs),
+                reason: reason.to_string(),
+                path: entry.path(),
+                name,
+            });
+        }
+        snapshots.sort_by(|a, b| a.taken_at.cmp(&b.taken_at).then_with(|| a.name.cmp(&b.name)));
+        Ok(snapshots)
+    }
+
+    /// Space the snapshots of every world take. LevelDB never writes a
+    /// table again under the same number, so a table of the same name and
+    /// size is taken for a hard link to the same file, which holds where
+    /// `copy_world` could link it.
+    pub fn usage(&self, worlds_root: &Path) -> Result<SnapshotUsage> {
+        let mut usage = SnapshotUsage::default();
+        let Ok(worlds) = fs::read_dir(&self.dir) else {
+            return Ok(usage);
+        };
+        for world in worlds {
+            let world = world?.file_name().to_string_lossy().into_owned();
+            let mut tables = HashSet::new();
+            for snapshot in self.list(&world)? {
+                usage.snapshots += 1;
+                let mut files = Vec::new();
+                collect_files(&snapshot.path, Path::new(""), &mut files)?;
+                for file in files {
+                    let size = fs::metadata(snapshot.path.join(&file))?.len();
+                    usage.size += size;
+                    if file.extension().is_some_and(|e| e == "ldb") {
+                        let live = fs::metadata(worlds_root.join(&world).join(&file)).is_ok_and(|live| live.len() == size);
+                        if live || !tables.insert((file, size)) {
+                            continue;
+                        }
+                    }
+                    usage.stored += size;
+                }
+            }
+        }
+        Ok(usage)
+    }
+
+    pub fn find(&self, world: &str, name: &str) -> Result<Snapshot> {
+        self.list(world)?.into_iter().find(|s| s.name == name).ok_or_else(|| anyhow!("No snapshot {} of {}", name, world))
+    }
+
+    /// Files added, removed or changed from snapshot `a` to `b`, by path.
+    pub fn diff(&self, a: &Snapshot, b: &Snapshot) -> Result<Vec<FileDiff>> {
+        let (mut before, mut after) = (Vec::new(), Vec::new());
+        collect_files(&a.path, Path::new(""), &mut before)?;
+        collect_files(&b.path, Path::new(""), &mut after)?;
+        let mut paths: Vec<PathBuf> = before.into_iter().chain(after).collect::<HashSet<_>>().into_iter().collect();
+        paths.sort();
+
+        let mut diffs = Vec::new();
+        for path in paths {
+            let (old, new) = (a.path.join(&path), b.path.join(&path));
+            let size = |file: &Path| fs::metadata(file).ok().map(|m| m.len());
+            let (before, after) = (size(&old), size(&new));
+            // Sizes settle most files; tables hard-linked into both are read twice at worst
+            if before.is_some() && before == after && fs::read(&old)? == fs::read(&new)? {
+                continue;
+            }
+            diffs.push(FileDiff { path, before, after });
+        }
+        Ok(diffs)
+    }
+
+    /// Puts the world back the way `snapshot` has it, after taking a snapshot
+    /// of its current state so the restore can be undone the same way. Only
+    /// files that differ are replaced or deleted, so a running daemon syncs
+    /// the restore as ordinary changes. Returns the new snapshot, if the world
+    /// still existed.
+    pub fn restore(&self, worlds_root: &Path, snapshot: &Snapshot, now: SystemTime) -> Result<Option<Snapshot>> {
+        let world_dir = worlds_root.join(&snapshot.world);
+        let mut paths = Vec::new();
+        collect_files(&snapshot.path, Path::new(""), &mut paths)?;
+        if world_dir.is_dir() {
+            collect_files(&world_dir, Path::new(""), &mut paths)?;
+        }
+        let paths: Vec<PathBuf> = paths.into_iter().collect::<HashSet<_>>().into_iter().collect();
+        self.revert(worlds_root, snapshot, &paths, now)
+    }
+
+    /// Like `restore`, but only for the given files of the world, relative
+    /// to it: each is replaced where it differs from `snapshot`, or deleted
+    /// where the snapshot does not have it.
+    pub fn revert(&self, worlds_root: &Path, snapshot: &Snapshot, paths: &[PathBuf], now: SystemTime) -> Result<Option<Snapshot>> {
+        if let Some(path) = paths.iter().find(|p| !p.components().all(|c| matches!(c, Component::Normal(_)))) {
+            bail!("Refusing to restore {} outside the world", path.display());
+        }
+        let world_dir = worlds_root.join(&snapshot.world);
+        let before = if world_dir.is_dir() {
+            // Pruned only afterwards, in case `snapshot` is the oldest
+            Some(self.duplicate(worlds_root, &snapshot.world, "undo", now)?)
+        } else {
+            None
+        };
+
+        let staging = worlds_root.join(STAGING_DIR);
+        for relative in paths {
+            let (target, source) = (world_dir.join(relative), snapshot.path.join(relative));
+            if !source.is_file() {
+                if target.is_file() {
+                    fs::remove_file(&target)?;
+                }
+                continue;
+            }
+            if target.is_file() && fs::read(&target)? == fs::read(&source)? {
+                continue;
+            }
+            fs::create_dir_all(target.parent().unwrap_or(&world_dir))?;
+            fs::create_dir_all(&staging)?;
+            let tmp = staging.join(format!("restore-{}", snapshot.name));
+            fs::copy(&source, &tmp)?;
+            fs::rename(&tmp, &target)?;
+        }
+        let _ = fs::remove_dir(&staging);
+        self.prune(&snapshot.world)?;
+        Ok(before)
+    }
+}
+
+/// Read-only copies of snapshots, one folder per world, to open in other
+/// tools such as Amulet without restoring them. Unlike snapshots they copy
+/// every file, since the tables a snapshot hard-links are shared with the
+/// world and must stay writable.
+#[derive(Debug, Clone)]
+pub struct Views {
+    dir: PathBuf,
+}
+
+impl Views {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Where the view of snapshot `name` of `world` is, open or not.
+    pub fn path(&self, world: &str, name: &str) -> Result<PathBuf> {
+        check_world(world)?;
+        let mut components = Path::new(name).components();
+        if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+            bail!("{} is not a snapshot name", name);
+        }
+        Ok(self.dir.join(world).join(name))
+    }
+
+    /// Copies `snapshot` into its view with every file read-only, or returns
+    /// the view if it is open already.
+    pub fn open(&self, snapshot: &Snapshot) -> Result<PathBuf> {
+        let path = self.path(&snapshot.world, &snapshot.name)?;
+        if path.is_dir() {
+            return Ok(path);
+        }
+        let staging = path.with_file_name(format!(".{}", snapshot.name));
+        if staging.exists() {
+            remove_view(&staging)?;
+        }
+        let copied = copy_read_only(&snapshot.path, &staging).and_then(|_| Ok(fs::rename(&staging, &path)?));
+        if let Err(e) = copied {
+            let _ = remove_view(&staging);
+            return Err(e);
+        }
+        Ok(path)
+    }
+
+    /// Removes the view of snapshot `name` of `world`, returning whether it
+    /// was open.
+    pub fn close(&self, world: &str, name: &str) -> Result<bool> {
+        let path = self.path(world, name)?;
+        if !path.is_dir() {
+            return Ok(false);
+        }
+        remove_view(&path)?;
+        Ok(true)
+    }
+}
+
+fn copy_read_only(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_read_only(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+            let mut permissions = fs::metadata(&target)?.permissions();
+            permissions.set_readonly(true);
+            fs::set_permissions(&target, permissions)?;
+        }
+    }
+    Ok(())
+}
+
+/// Windows refuses to delete read-only files, elsewhere the folders being
+/// writable is enough.
+fn remove_view(dir: &Path) -> Result<()> {
+    #[cfg(windows)]
+    make_writable(dir)?;
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn make_writable(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            make_writable(&entry.path())?;
+        } else {
+            let mut permissions = entry.metadata()?.permissions();
+            permissions.set_readonly(false);
+            fs::set_permissions(entry.path(), permissions)?;
+        }
+    }
+    Ok(())
+}
+
+pub fn check_world(world: &str) -> Result<()> {
+    let mut components = Path::new(world).components();
+    if !matches!((components.next(), components.next()), (Some(Component::Normal(_)), None)) {
+        bail!("{} is not a world folder name", world);
+    }
+    Ok(())
+}
+
+fn collect_files(dir: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = relative.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            collect_files(&entry.path(), &path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Copies a world folder, hard-linking its LevelDB tables where possible.
+pub fn copy_world(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_world(&entry.path(), &target)?;
+        } else if entry.path().extension().is_some_and(|e| e == "ldb") {
+            if fs::hard_link(entry.path(), &target).is_err() {
+                fs::copy(entry.path(), &target)?;
+            }
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use anyhow::Result;
+use log::{error, info, warn};
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+
+/// Longest wait before a failed task is started again.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+/// A task that ran this long before failing starts over with the shortest
+/// delay, as its failure is not part of a crash loop.
+const HEALTHY_RUN: Duration = Duration::from_secs(5 * 60);
+
+/// Wait before starting a task again after its `failures`-th failure in a row.
+pub fn restart_delay(failures: u32) -> Duration {
+    Duration::from_secs(1 << failures.saturating_sub(1).min(6)).min(MAX_RESTART_DELAY)
+}
+
+/// Runs the task `start` creates under `name`, and creates and runs it again
+/// whenever it fails or panics, so one part of the daemon, such as the worker
+/// of one unreachable peer, cannot take the others down. A task that returns
+/// `Ok` is done and not restarted.
+pub fn supervise<F, T>(name: impl Into<String>, start: F) -> JoinHandle<()>
+where
+    F: Fn() -> T + Send + 'static,
+    T: Future<Output = Result<()>> + Send + 'static,
+{
+    let name = name.into();
+    tokio::spawn(async move {
+        let mut failures = 0;
+        loop {
+            let started = Instant::now();
+            // Its own task, so a panic ends only this run
+            match tokio::spawn(start()).await {
+                Ok(Ok(())) => {
+                    info!("{} finished", name);
+                    return;
+                }
+                Ok(Err(e)) => error!("{} failed: {}", name, e),
+                Err(e) => error!("{} crashed: {}", name, e),
+            }
+            if started.elapsed() >= HEALTHY_RUN {
+                failures = 0;
+            }
+            failures += 1;
+            let delay = restart_delay(failures);
+            warn!("Restarting {} in {}s", name, delay.as_secs());
+            tokio::time::sleep(delay).await;
+        }
+    })
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use opentelemetry::{global, KeyValue};
+use opentelemetry::trace::{Span, Status, Tracer};
+use opentelemetry_otlp::{MetricExporter, Protocol, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use std::sync::Arc;
+use std::time::Duration;
+use log::{info, warn};
+use crate::config::TelemetryConfig;
+use crate::correlation::CorrelationId;
+use crate::metrics::Metrics;
+
+const INSTRUMENTATION_NAME: &str = "mcbd-world-sync";
+
+/// Keeps the exporters alive; dropping it flushes pending spans and metrics.
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Drop for Telemetry {
+    fn drop(&mut self) {
+        if let Err(e) = self.tracer_provider.shutdown() {
+            warn!("Failed to flush spans: {}", e);
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            warn!("Failed to flush metrics: {}", e);
+        }
+    }
+}
+
+/// Sets up OTLP/HTTP export of transfer spans and the daemon counters.
+pub fn init(config: &TelemetryConfig, metrics: Arc<Metrics>) -> Result<Telemetry> {
+    let endpoint = config.otlp_endpoint.trim_end_matches('/').to_string();
+    let resource = Resource::builder().with_service_name(config.service_name.clone()).build();
+    let interval = Duration::from_secs(config.export_interval.max(1));
+
+    // The blocking HTTP client owns a runtime of its own and must not be built
+    // on a tokio worker thread
+    let (span_exporter, metric_exporter) = std::thread::spawn(move || -> Result<_> {
+        let spans = SpanExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpJson)
+            .with_endpoint(format!("{}/v1/traces", endpoint))
+            .build()?;
+        let metrics = MetricExporter::builder()
+            .with_http()
+            .with_protocol(Protocol::HttpJson)
+            .with_endpoint(format!("{}/v1/metrics", endpoint))
+            .build()?;
+        Ok((spans, metrics))
+    }).join().map_err(|_| anyhow::anyhow!("OTLP exporter setup panicked"))??;
+
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_batch_exporter(span_exporter)
+        .with_resource(resource.clone())
+        .build();
+    global::set_tracer_provider(tracer_provider.clone());
+
+    let reader = PeriodicReader::builder(metric_exporter).with_interval(interval).build();
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(reader)
+        .with_resource(resource)
+        .build();
+    global::set_meter_provider(meter_provider.clone());
+
+    let meter = global::meter(INSTRUMENTATION_NAME);
+    for (name, help, _) in metrics.counters() {
+        let metrics = metrics.clone();
+        meter.u64_observable_counter(format!("mcbd.{}", name))
+            .with_description(help)
+            .with_callback(move |observer| {
+                if let Some((_, _, counter)) = metrics.counters().into_iter().find(|(n, _, _)| *n == name) {
+                    observer.observe(Metrics::get(counter), &[]);
+                }
+            })
+            .build();
+    }
+
+    info!("Exporting telemetry to {}", config.otlp_endpoint);
+    Ok(Telemetry { tracer_provider, meter_provider })
+}
+
+/// Span for one transfer. A no-op unless OTLP export is configured.
+pub fn transfer_span(id: &CorrelationId, peer: &str, path: &str) -> global::BoxedSpan {
+    let mut span = global::tracer(INSTRUMENTATION_NAME).start("transfer");
+    span.set_attribute(KeyValue::new("correlation_id", id.to_string()));
+    span.set_attribute(KeyValue::new("peer", peer.to_string()));
+    span.set_attribute(KeyValue::new("path", path.to_string()));
+    span
+}
+
+pub fn end_span(mut span: global::BoxedSpan, error: Option<String>) {
+    if let Some(error) = error {
+        span.set_status(Status::error(error));
+    }
+    span.end();
+}
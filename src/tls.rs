@@ -0,0 +1,149 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use crate::config::TlsConfig;
+
+/// Certificate and key this device proves itself with.
+pub struct Identity {
+    pub certs: Vec<CertificateDer<'static>>,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl Identity {
+    /// The configured PEM files, or a self-signed certificate kept in `dir`,
+    /// generated the first time so its fingerprint stays the same.
+    pub fn load(config: &TlsConfig, dir: &Path) -> Result<Self> {
+        let (cert, key) = match (&config.cert, &config.key) {
+            (Some(cert), Some(key)) => (Path::new(cert).to_path_buf(), Path::new(key).to_path_buf()),
+            (None, None) => {
+                let (cert, key) = (dir.join("tls-cert.pem"), dir.join("tls-key.pem"));
+                if !cert.exists() || !key.exists() {
+                    let generated = rcgen::generate_simple_self_signed(vec!["mcbd-world-sync".to_string()])?;
+                    fs::create_dir_all(dir)?;
+                    fs::write(&key, generated.signing_key.serialize_pem())?;
+                    fs::write(&cert, generated.cert.pem())?;
+                    info!("Generated a TLS certificate in {}", cert.display());
+                }
+                (cert, key)
+            }
+            _ => return Err(anyhow!("TLS needs both cert and key, or neither")),
+        };
+        let certs = CertificateDer::pem_file_iter(&cert)?.collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(anyhow!("No certificate in {}", cert.display()));
+        }
+        Ok(Self { certs, key: PrivateKeyDer::from_pem_file(&key)? })
+    }
+
+    /// Fingerprint peers list in `tls.trusted` to accept this device.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.certs[0])
+    }
+
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let config = rustls::ServerConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(self.certs.clone(), self.key.clone_key())?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// SHA-256 of a certificate, as lowercase hex.
+pub fn fingerprint(cert: &CertificateDer) -> String {
+    format!("{:x}", Sha256::digest(cert.as_ref()))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+/// Connects to peers whose certificate has one of the `trusted` fingerprints.
+/// Peers use self-signed certificates, so there is no authority to check
+/// them against.
+pub fn connector(trusted: &[String]) -> Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(client_config(Some(trusted))?)))
+}
+
+/// Client side of `connector`. Without `trusted` any certificate is
+/// accepted, for QUIC, which always encrypts, to peers that do not use TLS.
+pub fn client_config(trusted: Option<&[String]>) -> Result<rustls::ClientConfig> {
+    let provider = provider();
+    let verifier = PinnedVerifier {
+        trusted: trusted.map(|trusted| trusted.iter().map(|f| f.replace(':', "").to_lowercase()).collect()),
+        provider: provider.clone(),
+    };
+    Ok(rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth())
+}
+
+/// Connects to servers with a certificate from an authority this system
+/// trusts, such as mail servers.
+pub fn system_connector() -> Result<TlsConnector> {
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(rustls_native_certs::load_native_certs().certs);
+    if added == 0 {
+        return Err(anyhow!("No trusted certificate authorities found on this system"));
+    }
+    let config = rustls::ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Name to send in the TLS handshake for a `host:port` address. Peers are
+/// recognised by fingerprint, so it only has to be well-formed.
+pub fn server_name(address: &str) -> ServerName<'static> {
+    let host = address.rsplit_once(':').map_or(address, |(host, _)| host).trim_matches(['[', ']']);
+    ServerName::try_from(host.to_string()).unwrap_or_else(|_| ServerName::try_from("mcbd-world-sync").unwrap())
+}
+
+#[derive(Debug)]
+struct PinnedVerifier {
+    /// `None` trusts every certificate.
+    trusted: Option<Vec<String>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(end_entity);
+        if self.trusted.as_ref().is_none_or(|trusted| trusted.contains(&fingerprint)) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!("peer certificate {} is not in tls.trusted", fingerprint)))
+        }
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,349 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+use log::debug;
+use serde::Serialize;
+use crate::metrics::Metrics;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Which of `streams` transfer streams to a peer sends `path`. A path always
+/// takes the same stream, so an older copy of a file can never overtake a
+/// newer one.
+pub fn stream_of(path: &Path, streams: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    (hasher.finish() % streams.max(1) as u64) as usize
+}
+
+/// Files of a world that are small but describe the rest of it, such as its
+/// settings and name. When a save changes hundreds of files at once, these
+/// go before the database files within each lane.
+pub const METADATA_FILES: &[&str] = &["level.dat", "level.dat_old", "levelname.txt", "world_icon.jpeg", "world_behavior_packs.json", "world_resource_packs.json"];
+
+pub fn is_metadata(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| METADATA_FILES.iter().any(|m| name == *m))
+}
+
+/// The world folder `path` is in, its first component.
+pub fn world_of(path: &Path) -> Option<&str> {
+    path.components().next().and_then(|c| c.as_os_str().to_str())
+}
+
+/// Scheduling lane of a transfer. Interactive transfers (the user asked to
+/// sync a world now) are popped before any background transfer and preempt
+/// background bulk streams on a multiplexed connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    #[default]
+    Background,
+    Interactive,
+    /// Interactive, asked to go outside the gaming mode limits and
+    /// `performance.max_transfers` too, and popped before other interactive
+    /// transfers.
+    Urgent,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingTransfer {
+    pub peer: String,
+    pub path: PathBuf,
+    pub change_type: String,
+    pub priority: Priority,
+    pub attempts: u32,
+    not_before: Instant,
+}
+
+/// A file a peer received but could not apply, as it reported.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FailedFile {
+    pub path: PathBuf,
+    pub reason: String,
+}
+
+#[derive(Default)]
+struct QueueState {
+    order: VecDeque<(String, PathBuf)>,
+    pending: HashMap<(String, PathBuf), PendingTransfer>,
+    /// Popped but not yet acknowledged by the peer.
+    in_flight: HashSet<(String, PathBuf)>,
+    /// Why a peer could not apply a file, until it applies it.
+    failed: BTreeMap<(String, PathBuf), String>,
+    /// Worlds whose files are not popped until they are no longer held.
+    held: BTreeSet<String>,
+    /// Held worlds whose interactive transfers go all the same.
+    passing: BTreeSet<String>,
+}
+
+impl QueueState {
+    fn is_held(&self, transfer: &PendingTransfer) -> bool {
+        world_of(&transfer.path).is_some_and(|world| {
+            self.held.contains(world) && !(transfer.priority >= Priority::Interactive && self.passing.contains(world))
+        })
+    }
+}
+
+/// Outgoing transfers keyed by (peer, path). A path that is queued again
+/// before it was sent is coalesced into the existing entry, so an autosave
+/// storm results in a single transfer that reads the latest content.
+pub struct TransferQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    metrics: Arc<Metrics>,
+}
+
+impl TransferQueue {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+            metrics,
+        }
+    }
+
+    pub async fn push(&self, peer: String, path: PathBuf, change_type: String) {
+        self.push_with_priority(peer, path, change_type, Priority::Background).await
+    }
+
+    pub async fn push_with_priority(&self, peer: String, path: PathBuf, change_type: String, priority: Priority) {
+        self.push_after(peer, path, change_type, priority, Duration::ZERO).await
+    }
+
+    /// As `push`, but not sent before `delay` passed unless the path was
+    /// queued already. Used for deleted folders, which might turn out moved.
+    pub async fn push_delayed(&self, peer: String, path: PathBuf, change_type: String, delay: Duration) {
+        self.push_after(peer, path, change_type, Priority::Background, delay).await
+    }
+
+    async fn push_after(&self, peer: String, path: PathBuf, change_type: String, priority: Priority, delay: Duration) {
+        let mut state = self.state.lock().await;
+        let key = (peer.clone(), path.clone());
+        match state.pending.get_mut(&key) {
+            Some(existing) => {
+                // Keep the entry's position and backoff, only the latest change matters
+                existing.change_type = change_type;
+                if priority > existing.priority {
+                    // An explicit request skips the remaining backoff
+                    existing.priority = priority;
+                    existing.not_before = Instant::now();
+                    self.notify.notify_waiters();
+                }
+                Metrics::inc(&self.metrics.duplicates_suppressed);
+                debug!("Coalesced duplicate transfer of {} to {}", path.display(), peer);
+            }
+            None => {
+                state.pending.insert(key.clone(), PendingTransfer {
+                    peer,
+                    path,
+                    change_type,
+                    priority,
+                    attempts: 0,
+                    not_before: Instant::now() + delay,
+                });
+                state.order.push_back(key);
+                Metrics::inc(&self.metrics.transfers_queued);
+                self.notify.notify_waiters();
+            }
+        }
+    }
+
+    /// Waits for the next transfer whose backoff has elapsed and removes it
+    /// from the queue. It counts as in flight until it is `acknowledged`,
+    /// `discard`ed or put back with `retry`. Interactive transfers go first, each lane in FIFO order
+    /// except that `METADATA_FILES` go before the other files.
+    pub async fn pop(&self) -> PendingTransfer {
+        self.pop_matching(|_, _| true).await
+    }
+
+    /// Like `pop`, but only for transfers to `peer`, so each peer can be
+    /// served by its own worker.
+    pub async fn pop_for(&self, peer: &str) -> PendingTransfer {
+        self.pop_for_stream(peer, 0, 1).await
+    }
+
+    /// Like `pop_for`, but only for the paths `stream_of` assigns to `stream`
+    /// of `streams`.
+    pub async fn pop_for_stream(&self, peer: &str, stream: usize, streams: usize) -> PendingTransfer {
+        self.pop_matching(|queued_for, path| queued_for == peer && stream_of(path, streams) == stream).await
+    }
+
+    async fn pop_matching(&self, wanted: impl Fn(&str, &Path) -> bool) -> PendingTransfer {
+        loop {
+            // Registered before looking, so a push in between still wakes us
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            let wait = {
+                let mut state = self.state.lock().await;
+                let now = Instant::now();
+                let ready = state.order.iter()
+                    .enumerate()
+                    .filter(|(_, (peer, path))| wanted(peer, path))
+                    .filter_map(|(index, key)| state.pending.get(key).filter(|t| t.not_before <= now && !state.is_held(t)).map(|t| (index, (t.priority, is_metadata(&t.path)))))
+                    .max_by_key(|(index, rank)| (*rank, Reverse(*index)))
+                    .map(|(index, _)| index);
+                if let Some(index) = ready {
+                    let key = state.order.remove(index).expect("index in range");
+                    let transfer = state.pending.remove(&key).expect("queued key is pending");
+                    state.in_flight.insert(key);
+                    return transfer;
+                }
+                state.pending.values().filter(|t| wanted(&t.peer, &t.path) && !state.is_held(t)).map(|t| t.not_before.saturating_duration_since(now)).min()
+            };
+
+            match wait {
+                Some(delay) => {
+                    let _ = tokio::time::timeout(delay, notified).await;
+                }
+                None => notified.await,
+            }
+        }
+    }
+
+    /// Puts a failed transfer back with exponential backoff. If the same path was
+    /// queued again in the meantime, that newer entry inherits the backoff instead.
+    pub async fn retry(&self, mut transfer: PendingTransfer) {
+        transfer.attempts += 1;
+        let backoff = Duration::from_secs(1u64 << transfer.attempts.min(6)).min(MAX_BACKOFF);
+        transfer.not_before = Instant::now() + backoff;
+
+        let mut state = self.state.lock().await;
+        let key = (transfer.peer.clone(), transfer.path.clone());
+        state.in_flight.remove(&key);
+        match state.pending.get_mut(&key) {
+            Some(newer) => {
+                newer.attempts = transfer.attempts;
+                if newer.priority <= transfer.priority {
+                    newer.not_before = transfer.not_before;
+                }
+                Metrics::inc(&self.metrics.duplicates_suppressed);
+            }
+            None => {
+                state.pending.insert(key.clone(), transfer);
+                state.order.push_back(key);
+            }
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Ends the backoff of everything queued for `peer`, e.g. once it is
+    /// reachable again; returns how many transfers that were.
+    pub async fn resume_peer(&self, peer: &str) -> usize {
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        let mut resumed = 0;
+        for transfer in state.pending.values_mut().filter(|t| t.peer == peer) {
+            transfer.not_before = transfer.not_before.min(now);
+            resumed += 1;
+        }
+        self.notify.notify_waiters();
+        resumed
+    }
+
+    /// Holds back the files of `worlds`, such as those open in Minecraft,
+    /// and lets those of every other world go again. Held files stay
+    /// queued and are coalesced as usual.
+    pub async fn hold_worlds(&self, worlds: &[String]) {
+        let mut state = self.state.lock().await;
+        let QueueState { held, passing, .. } = &mut *state;
+        *held = worlds.iter().cloned().collect();
+        passing.retain(|world| held.contains(world));
+        self.notify.notify_waiters();
+    }
+
+    /// Lets the interactive transfers of `world` go while it is held, such
+    /// as when they are read from a shadow copy, until it is released.
+    pub async fn let_through(&self, world: &str) {
+        self.state.lock().await.passing.insert(world.to_string());
+        self.notify.notify_waiters();
+    }
+
+    /// Drops everything queued for `peer`; returns how many transfers that were.
+    pub async fn remove_peer(&self, peer: &str) -> usize {
+        let mut state = self.state.lock().await;
+        let before = state.pending.len();
+        state.pending.retain(|(queued_for, _), _| queued_for != peer);
+        state.order.retain(|(queued_for, _)| queued_for != peer);
+        state.in_flight.retain(|(queued_for, _)| queued_for != peer);
+        state.failed.retain(|(failed_for, _), _| failed_for != peer);
+        before - state.pending.len()
+    }
+
+    /// Remembers that `peer` could not apply `path`, for its status. The
+    /// transfer itself is retried like any other.
+    pub async fn record_failure(&self, peer: &str, path: &Path, reason: String) {
+        self.state.lock().await.failed.insert((peer.to_string(), path.to_path_buf()), reason);
+    }
+
+    /// Marks a transfer as delivered once its peer confirmed it, forgetting
+    /// any earlier failure to apply it.
+    pub async fn acknowledged(&self, transfer: &PendingTransfer) {
+        let mut state = self.state.lock().await;
+        let key = (transfer.peer.clone(), transfer.path.clone());
+        state.in_flight.remove(&key);
+        state.failed.remove(&key);
+    }
+
+    /// The files `peer` could not apply, by path.
+    pub async fn failures_for(&self, peer: &str) -> Vec<FailedFile> {
+        self.state.lock().await.failed.iter()
+            .filter(|((failed_for, _), _)| failed_for == peer)
+            .map(|((_, path), reason)| FailedFile { path: path.clone(), reason: reason.clone() })
+            .collect()
+    }
+
+    /// Forgets a popped transfer that is not going to be sent.
+    pub async fn discard(&self, transfer: &PendingTransfer) {
+        self.state.lock().await.in_flight.remove(&(transfer.peer.clone(), transfer.path.clone()));
+    }
+
+    /// Changes for `peer` it has not acknowledged yet, queued or in flight.
+    pub async fn len_for(&self, peer: &str) -> usize {
+        let state = self.state.lock().await;
+        let in_flight = state.in_flight.iter().filter(|key| !state.pending.contains_key(*key));
+        state.pending.keys().chain(in_flight).filter(|(queued_for, _)| queued_for == peer).count()
+    }
+
+    /// Whether every change to `world` queued for `peer` was delivered, with
+    /// none left queued or in flight.
+    pub async fn world_delivered(&self, peer: &str, world: &str) -> bool {
+        let state = self.state.lock().await;
+        !state.pending.keys().chain(&state.in_flight).any(|(queued_for, path)| queued_for == peer && world_of(path) == Some(world))
+    }
+
+    /// Changes queued or in flight per world, counting each file once
+    /// however many peers it is queued for.
+    pub async fn len_by_world(&self) -> BTreeMap<String, usize> {
+        let state = self.state.lock().await;
+        let paths: BTreeSet<&PathBuf> = state.pending.keys().chain(&state.in_flight).map(|(_, path)| path).collect();
+        let mut worlds = BTreeMap::new();
+        for world in paths.into_iter().filter_map(|path| world_of(path)) {
+            *worlds.entry(world.to_string()).or_insert(0) += 1;
+        }
+        worlds
+    }
+
+    /// Whether a change to `path` is queued or in flight for `peer`.
+    pub async fn is_queued(&self, peer: &str, path: &Path) -> bool {
+        let state = self.state.lock().await;
+        let key = (peer.to_string(), path.to_path_buf());
+        state.pending.contains_key(&key) || state.in_flight.contains(&key)
+    }
+
+    /// Changes for `peer` that were sent but not acknowledged yet.
+    pub async fn in_flight_for(&self, peer: &str) -> usize {
+        self.state.lock().await.in_flight.iter().filter(|(queued_for, _)| queued_for == peer).count()
+    }
+
+    pub async fn len(&self) -> usize {
+        self.state.lock().await.pending.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+}
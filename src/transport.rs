@@ -0,0 +1,125 @@
+use anyhow::{anyhow, Result};
+use futures::{future, Sink, SinkExt, Stream, TryStreamExt};
+use log::warn;
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::Mutex;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::bytes::{Bytes, BytesMut};
+use crate::config::QuicConfig;
+use crate::tls;
+
+/// ALPN protocol id peers must offer when connecting over QUIC.
+pub const QUIC_ALPN: &[u8] = b"mcbd-sync";
+
+/// Device addresses of this form are reached over QUIC: `quic://<host>:<port>`.
+pub const QUIC_PREFIX: &str = "quic://";
+
+/// How often an idle QUIC connection is pinged, well within the idle
+/// timeout, so it stays open between syncs.
+const QUIC_KEEP_ALIVE: Duration = Duration::from_secs(10);
+
+/// TLS setup for the QUIC listener. With `tls` the device presents its TLS
+/// certificate, so peers pin it like on TCP. Otherwise the configured PEM
+/// files are used, or a self-signed certificate generated for this run.
+pub fn quic_server_config(config: &QuicConfig, tls: Option<&TlsAcceptor>) -> Result<quinn::ServerConfig> {
+    if let Some(tls) = tls {
+        let mut tls = (**tls.config()).clone();
+        tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+        return Ok(quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?)));
+    }
+    let (certs, key) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => {
+            let certs = CertificateDer::pem_file_iter(cert)?.collect::<Result<Vec<_>, _>>()?;
+            (certs, PrivateKeyDer::from_pem_file(key)?)
+        }
+        (None, None) => {
+            warn!("No QUIC certificate configured, using a self-signed one");
+            let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()])?;
+            let key = PrivatePkcs8KeyDer::from(generated.signing_key.serialize_der());
+            (vec![generated.cert.der().clone()], key.into())
+        }
+        _ => return Err(anyhow!("QUIC listener needs both cert and key, or neither")),
+    };
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ServerConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls)?)))
+}
+
+/// Client side of QUIC, checking the peer's certificate against the
+/// trusted fingerprints of `tls`. Without it any certificate is accepted,
+/// as a plain TCP connection would not check anything either.
+pub fn quic_client_config(tls: Option<&TlsConnector>) -> Result<quinn::ClientConfig> {
+    let mut config = match tls {
+        Some(tls) => (**tls.config()).clone(),
+        None => tls::client_config(None)?,
+    };
+    config.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+    let mut client = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(config)?));
+    let mut transport = quinn::TransportConfig::default();
+    transport.keep_alive_interval(Some(QUIC_KEEP_ALIVE));
+    client.transport_config(Arc::new(transport));
+    Ok(client)
+}
+
+/// A QUIC connection to one peer, opened on first use and then shared:
+/// every connection the sync protocol makes becomes a stream of it, so
+/// transfers run side by side and a lost packet only holds up its own
+/// stream. Clones share the connection.
+#[derive(Debug, Clone, Default)]
+pub struct QuicLink {
+    connection: Arc<Mutex<Option<(quinn::Endpoint, quinn::Connection)>>>,
+}
+
+impl QuicLink {
+    /// A new stream to `address`, connecting again if the connection was lost.
+    pub async fn open(&self, address: &str, tls: Option<&TlsConnector>) -> Result<impl PeerStream> {
+        let mut connection = self.connection.lock().await;
+        if let Some((_, existing)) = connection.as_ref().filter(|(_, c)| c.close_reason().is_none()) {
+            if let Ok((send, recv)) = existing.open_bi().await {
+                return Ok(tokio::io::join(recv, send));
+            }
+        }
+        let remote = tokio::net::lookup_host(address).await?.next().ok_or_else(|| anyhow!("{} has no address", address))?;
+        let local = match remote {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        let mut endpoint = quinn::Endpoint::client(local)?;
+        endpoint.set_default_client_config(quic_client_config(tls)?);
+        let opened = endpoint.connect(remote, &tls::server_name(address).to_str())?.await?;
+        let (send, recv) = opened.open_bi().await?;
+        *connection = Some((endpoint, opened));
+        Ok(tokio::io::join(recv, send))
+    }
+}
+
+/// A connection to a peer, plain or through TLS.
+pub trait PeerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> PeerStream for S {}
+
+/// Presents a WebSocket as the same frame stream and sink the TCP transport
+/// uses: every binary message carries one frame, other messages are skipped.
+pub fn websocket_frames<S>(ws: WebSocketStream<S>) -> impl Stream<Item = Result<BytesMut, WsError>> + Sink<Bytes, Error = WsError> + Unpin
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    ws.with(|frame: Bytes| future::ready(Ok::<_, WsError>(Message::Binary(frame))))
+        .try_filter_map(|message| future::ready(Ok(match message {
+            Message::Binary(data) => Some(BytesMut::from(&data[..])),
+            _ => None,
+        })))
+}
@@ -0,0 +1,138 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use crate::conflicts;
+use crate::metrics::Metrics;
+
+/// Days of usage kept, enough for the last month with room to spare.
+pub const KEEP_DAYS: u64 = 90;
+
+/// Bytes sent to peers, see `network::Sent`, and the transfers that
+/// carried them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub size: u64,
+    pub carried: u64,
+    pub encoded: u64,
+    pub wire: u64,
+    /// Transfers delivered to a peer.
+    #[serde(default)]
+    pub files: u64,
+    /// Transfer attempts that failed.
+    #[serde(default)]
+    pub failed: u64,
+}
+
+impl Usage {
+    /// What the counters of `metrics` say was sent since the process started.
+    pub fn of(metrics: &Metrics) -> Self {
+        Self {
+            size: Metrics::get(&metrics.file_bytes_sent),
+            carried: Metrics::get(&metrics.content_bytes_sent),
+            encoded: Metrics::get(&metrics.message_bytes_sent),
+            wire: Metrics::get(&metrics.wire_bytes_sent),
+            files: Metrics::get(&metrics.transfers_sent),
+            failed: Metrics::get(&metrics.transfers_failed),
+        }
+    }
+
+    /// What was sent after `earlier`, taken from the same counters.
+    pub fn since(&self, earlier: &Usage) -> Self {
+        Self {
+            size: self.size - earlier.size,
+            carried: self.carried - earlier.carried,
+            encoded: self.encoded - earlier.encoded,
+            wire: self.wire - earlier.wire,
+            files: self.files - earlier.files,
+            failed: self.failed - earlier.failed,
+        }
+    }
+
+    fn add(&mut self, other: &Usage) {
+        self.size += other.size;
+        self.carried += other.carried;
+        self.encoded += other.encoded;
+        self.wire += other.wire;
+        self.files += other.files;
+        self.failed += other.failed;
+    }
+
+    /// Bytes of files that did not have to be sent, thanks to deltas and
+    /// chunks the peers had stored already.
+    pub fn spared_by_deltas(&self) -> u64 {
+        self.size.saturating_sub(self.carried)
+    }
+
+    /// Bytes compression took off the messages.
+    pub fn spared_by_compression(&self) -> u64 {
+        self.encoded.saturating_sub(self.wire)
+    }
+}
+
+/// Bytes sent per UTC day, kept in `usage.json` in the state directory.
+#[derive(Debug, Clone)]
+pub struct UsageLog {
+    file: PathBuf,
+}
+
+impl UsageLog {
+    pub fn new(file: PathBuf) -> Self {
+        Self { file }
+    }
+
+    /// Adds `usage` to the day of `now`, dropping days older than
+    /// `KEEP_DAYS`.
+    pub fn record(&self, usage: &Usage, now: SystemTime) -> Result<()> {
+        let mut days = self.days()?;
+        days.entry(conflicts::date(now)).or_default().add(usage);
+        let oldest = conflicts::date(now - Duration::from_secs(KEEP_DAYS * 86400));
+        days.retain(|day, _| *day >= oldest);
+        let tmp = self.file.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&days)?)?;
+        fs::rename(&tmp, &self.file)?;
+        Ok(())
+    }
+
+    /// The bytes sent over the last `days` days up to `now`, today included.
+    pub fn last(&self, days: u64, now: SystemTime) -> Result<Usage> {
+        let first = conflicts::date(now - Duration::from_secs(days.saturating_sub(1) * 86400));
+        let mut total = Usage::default();
+        for (_, usage) in self.days()?.range(first..) {
+            total.add(usage);
+        }
+        Ok(total)
+    }
+
+    fn days(&self) -> Result<BTreeMap<String, Usage>> {
+        match fs::read(&self.file) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Size of the files in `dir` and below.
+pub fn folder_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        size += if entry.file_type()?.is_dir() { folder_size(&entry.path())? } else { entry.metadata()?.len() };
+    }
+    Ok(size)
+}
+
+/// `bytes` in the largest unit that keeps it at 1 or more, as in `3.2 GiB`.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["bytes", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 { format!("{} bytes", bytes) } else { format!("{:.1} {}", value, UNITS[unit]) }
+}
@@ -0,0 +1,172 @@
+use anyhow::Result;
+use serde::{Serialize, Deserialize};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use log::warn;
+
+/// Records beyond one per file after which the journal is rewritten.
+const COMPACT_SLACK: usize = 1000;
+
+/// How many changes each device made to a file, as far as this device has
+/// seen them. Unlike modification times, these tell which of two versions
+/// came from the other however the clocks of the devices drift.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct VersionVector(pub BTreeMap<String, u64>);
+
+/// How one version relates to another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Order {
+    Same,
+    /// The other version was made from this one, or from a later one.
+    Older,
+    /// This version was made from the other one, or from a later one.
+    Newer,
+    /// Both were changed without seeing the other's change: a true conflict.
+    Concurrent,
+}
+
+impl VersionVector {
+    /// Counts a change made by `device`.
+    pub fn bump(&mut self, device: &str) {
+        *self.0.entry(device.to_string()).or_default() += 1;
+    }
+
+    /// Every change seen in either version.
+    pub fn merge(&mut self, other: &VersionVector) {
+        for (device, count) in &other.0 {
+            let ours = self.0.entry(device.clone()).or_default();
+            *ours = (*ours).max(*count);
+        }
+    }
+
+    /// How this version relates to `other`.
+    pub fn order(&self, other: &VersionVector) -> Order {
+        let mut order = Ordering::Equal;
+        for device in self.0.keys().chain(other.0.keys()) {
+            let (ours, theirs) = (self.0.get(device).copied().unwrap_or(0), other.0.get(device).copied().unwrap_or(0));
+            match (order, ours.cmp(&theirs)) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, differs) => order = differs,
+                (seen, differs) if seen != differs => return Order::Concurrent,
+                _ => {}
+            }
+        }
+        match order {
+            Ordering::Equal => Order::Same,
+            Ordering::Less => Order::Older,
+            Ordering::Greater => Order::Newer,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    path: PathBuf,
+    version: VersionVector,
+}
+
+/// The version of every file changed here or received, kept as a journal
+/// in `versions.jsonl` in the state directory: one record per change,
+/// replayed on start and rewritten once outdated records pile up.
+#[derive(Debug, Clone)]
+pub struct Versions {
+    device: String,
+    file: PathBuf,
+    journal: Arc<Mutex<Journal>>,
+}
+
+#[derive(Debug, Default)]
+struct Journal {
+    versions: BTreeMap<PathBuf, VersionVector>,
+    /// Records in the file, outdated ones included.
+    records: usize,
+}
+
+impl Versions {
+    /// Replays the journal in `file` of this device, `device`.
+    pub fn open(file: PathBuf, device: &str) -> Result<Self> {
+        let mut journal = Journal::default();
+        match fs::read_to_string(&file) {
+            Ok(records) => for line in records.lines().filter(|line| !line.trim().is_empty()) {
+                match serde_json::from_str::<Record>(line) {
+                    Ok(record) => {
+                        journal.versions.insert(record.path, record.version);
+                        journal.records += 1;
+                    }
+                    // Cut short by a crash while it was written
+                    Err(e) => warn!("Skipping a broken record of {}: {}", file.display(), e),
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let versions = Self { device: device.to_string(), file, journal: Arc::new(Mutex::new(journal)) };
+        versions.compact_if_outdated(&mut versions.lock())?;
+        Ok(versions)
+    }
+
+    pub fn get(&self, path: &Path) -> Option<VersionVector> {
+        self.lock().versions.get(path).cloned()
+    }
+
+    /// Records a change to `path` made here, or its deletion.
+    pub fn changed(&self, path: &Path) -> Result<VersionVector> {
+        self.update(path, |version, device| version.bump(device))
+    }
+
+    /// Records that `path` now includes the changes of `theirs`, received
+    /// from a peer. With `changed_here` the version kept is counted as a
+    /// change of this device, so it wins over both versions it came from.
+    pub fn received(&self, path: &Path, theirs: &VersionVector, changed_here: bool) -> Result<VersionVector> {
+        self.update(path, |version, device| {
+            version.merge(theirs);
+            if changed_here {
+                version.bump(device);
+            }
+        })
+    }
+
+    /// Records in the journal file, outdated ones included.
+    pub fn records(&self) -> usize {
+        self.lock().records
+    }
+
+    fn update(&self, path: &Path, change: impl FnOnce(&mut VersionVector, &str)) -> Result<VersionVector> {
+        let mut journal = self.lock();
+        let version = journal.versions.entry(path.to_path_buf()).or_default();
+        change(version, &self.device);
+        let record = Record { path: path.to_path_buf(), version: version.clone() };
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.file)?;
+        writeln!(file, "{}", serde_json::to_string(&record)?)?;
+        journal.records += 1;
+        self.compact_if_outdated(&mut journal)?;
+        Ok(record.version)
+    }
+
+    /// Rewrites the journal with the last record of each file once more
+    /// than `COMPACT_SLACK` records are outdated.
+    fn compact_if_outdated(&self, journal: &mut Journal) -> Result<()> {
+        if journal.records <= journal.versions.len() + COMPACT_SLACK {
+            return Ok(());
+        }
+        let mut records = String::new();
+        for (path, version) in &journal.versions {
+            records.push_str(&serde_json::to_string(&Record { path: path.clone(), version: version.clone() })?);
+            records.push('\n');
+        }
+        let tmp = self.file.with_extension("jsonl.tmp");
+        fs::write(&tmp, records)?;
+        fs::rename(&tmp, &self.file)?;
+        journal.records = journal.versions.len();
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Journal> {
+        self.journal.lock().unwrap()
+    }
+}
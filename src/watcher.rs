@@ -0,0 +1,212 @@
+use log::warn;
+use notify::{Event, EventHandler, EventKind};
+use notify::event::{ModifyKind, AccessKind};
+use std::collections::{HashMap, VecDeque};
+use std::fs::Metadata;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Notify};
+use tokio::sync::mpsc::error::TrySendError;
+use crate::config::WatchConfig;
+use crate::file_manager::FileInfo;
+use crate::metrics::Metrics;
+
+/// Paths kept while coalescing. Beyond them events are dropped and the
+/// worlds directory is scanned again instead.
+pub const MAX_COALESCED: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventClass {
+    /// File created, written, renamed or removed.
+    Content,
+    /// Attribute, permission or timestamp change only (e.g. antivirus scans).
+    MetadataOnly,
+    /// Reads and opens, which never change anything.
+    Access,
+}
+
+pub fn classify(kind: &EventKind) -> EventClass {
+    match kind {
+        EventKind::Access(AccessKind::Close(notify::event::AccessMode::Write)) => EventClass::Content,
+        EventKind::Access(_) => EventClass::Access,
+        EventKind::Modify(ModifyKind::Metadata(_)) => EventClass::MetadataOnly,
+        _ => EventClass::Content,
+    }
+}
+
+/// Whether an event is worth hashing and syncing under the given watch settings.
+pub fn should_process(kind: &EventKind, config: &WatchConfig) -> bool {
+    match classify(kind) {
+        EventClass::Content => true,
+        EventClass::MetadataOnly => config.process_metadata_changes,
+        EventClass::Access => false,
+    }
+}
+
+/// Platforms that report every write as a generic modification (Windows) can't
+/// tell attribute changes apart from the event kind, so compare with the
+/// cached size and modification time instead.
+pub fn content_unchanged(cached: Option<&FileInfo>, metadata: &Metadata, config: &WatchConfig) -> bool {
+    if config.process_metadata_changes {
+        return false;
+    }
+    match (cached, metadata.modified()) {
+        (Some(cached), Ok(modified)) => cached.size == metadata.len() && cached.last_modified == modified,
+        _ => false,
+    }
+}
+
+/// A change picked up by the watcher, as handed to the sync engine.
+#[derive(Debug)]
+pub enum WatchEvent {
+    Change { kind: EventKind, paths: Vec<PathBuf> },
+    Error(notify::Error),
+    /// Events were lost, by the platform or because too many were pending;
+    /// the whole directory has to be scanned again.
+    Rescan,
+    /// Time for the next scan of a directory that is polled instead of
+    /// watched. Never sent through `channel`.
+    Poll,
+}
+
+/// Events that did not fit into the channel, one per path with its latest
+/// kind, oldest first.
+#[derive(Default)]
+struct Overflow {
+    kinds: HashMap<PathBuf, EventKind>,
+    order: VecDeque<PathBuf>,
+    rescan: bool,
+}
+
+impl Overflow {
+    fn is_empty(&self) -> bool {
+        self.order.is_empty() && !self.rescan
+    }
+
+    fn add(&mut self, event: WatchEvent, metrics: &Metrics) {
+        let paths = match event {
+            WatchEvent::Change { kind, paths } if !self.rescan => paths.into_iter().map(|path| (path, kind)).collect(),
+            WatchEvent::Change { paths, .. } => {
+                for _ in paths {
+                    Metrics::inc(&metrics.watch_events_dropped);
+                }
+                return;
+            }
+            WatchEvent::Error(e) => {
+                warn!("Watch error while events are pending: {:?}", e);
+                Vec::new()
+            }
+            WatchEvent::Rescan | WatchEvent::Poll => {
+                self.set_rescan();
+                return;
+            }
+        };
+        for (path, kind) in paths {
+            if let Some(pending) = self.kinds.get_mut(&path) {
+                *pending = kind;
+                Metrics::inc(&metrics.watch_events_coalesced);
+            } else if self.order.len() < MAX_COALESCED {
+                self.kinds.insert(path.clone(), kind);
+                self.order.push_back(path);
+            } else {
+                warn!("Too many file changes pending, rescanning once they are processed");
+                Metrics::inc(&metrics.watch_events_dropped);
+                self.set_rescan();
+                return;
+            }
+        }
+    }
+
+    /// A rescan covers every pending path, so they are let go.
+    fn set_rescan(&mut self) {
+        self.rescan = true;
+        self.kinds = HashMap::new();
+        self.order = VecDeque::new();
+    }
+
+    fn take(&mut self) -> Option<WatchEvent> {
+        if std::mem::take(&mut self.rescan) {
+            return Some(WatchEvent::Rescan);
+        }
+        let path = self.order.pop_front()?;
+        let kind = self.kinds.remove(&path)?;
+        Some(WatchEvent::Change { kind, paths: vec![path] })
+    }
+}
+
+/// A bounded channel from the watcher to the sync engine. Events the engine
+/// has no use for are filtered out before they are queued. When the engine
+/// falls behind, further events are merged per path, and past
+/// `MAX_COALESCED` paths replaced by one rescan, so a flood of changes
+/// cannot use up memory and the watcher thread never blocks.
+pub fn channel(config: &WatchConfig, metrics: Arc<Metrics>) -> (EventSender, EventReceiver) {
+    let (events_tx, events) = mpsc::channel(config.event_queue.max(1));
+    let overflow = Arc::new(Mutex::new(Overflow::default()));
+    let notify = Arc::new(Notify::new());
+    let sender = EventSender { events: events_tx, overflow: overflow.clone(), notify: notify.clone(), config: config.clone(), metrics };
+    (sender, EventReceiver { events, overflow, notify })
+}
+
+/// The watcher's end of `channel`, called on the watcher's thread.
+pub struct EventSender {
+    events: mpsc::Sender<WatchEvent>,
+    overflow: Arc<Mutex<Overflow>>,
+    notify: Arc<Notify>,
+    config: WatchConfig,
+    metrics: Arc<Metrics>,
+}
+
+impl EventSender {
+    pub fn send(&self, event: WatchEvent) {
+        let mut overflow = self.overflow.lock().unwrap();
+        // While events wait in the overflow, newer ones join them so no change overtakes an older one
+        let event = if overflow.is_empty() {
+            match self.events.try_send(event) {
+                Ok(()) | Err(TrySendError::Closed(_)) => return,
+                Err(TrySendError::Full(event)) => event,
+            }
+        } else {
+            event
+        };
+        overflow.add(event, &self.metrics);
+        drop(overflow);
+        self.notify.notify_one();
+    }
+}
+
+impl EventHandler for EventSender {
+    fn handle_event(&mut self, event: notify::Result<Event>) {
+        match event {
+            Ok(event) if event.need_rescan() => self.send(WatchEvent::Rescan),
+            Ok(Event { kind, .. }) if !should_process(&kind, &self.config) => {}
+            Ok(Event { kind, paths, .. }) => self.send(WatchEvent::Change { kind, paths }),
+            Err(e) => self.send(WatchEvent::Error(e)),
+        }
+    }
+}
+
+/// The sync engine's end of `channel`.
+pub struct EventReceiver {
+    events: mpsc::Receiver<WatchEvent>,
+    overflow: Arc<Mutex<Overflow>>,
+    notify: Arc<Notify>,
+}
+
+impl EventReceiver {
+    /// The next event, or `None` once the watcher is gone.
+    pub async fn recv(&mut self) -> Option<WatchEvent> {
+        loop {
+            // Everything in the channel is older than what overflowed
+            if let Ok(event) = self.events.try_recv() {
+                return Some(event);
+            }
+            if let Some(event) = self.overflow.lock().unwrap().take() {
+                return Some(event);
+            }
+            tokio::select! {
+                event = self.events.recv() => return event,
+                _ = self.notify.notified() => {}
+            }
+        }
+    }
+}
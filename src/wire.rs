@@ -0,0 +1,45 @@
+use anyhow::Result;
+use tokio_util::bytes::Bytes;
+use crate::network::SyncMessage;
+
+/// How a `SyncMessage` is encoded in a frame. Every peer reads JSON, so it
+/// is used until the peer announced that it reads MessagePack too.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    #[default]
+    Json,
+    /// File contents as raw bytes instead of a JSON array of numbers, several
+    /// times smaller and faster to encode for large world files.
+    MessagePack,
+}
+
+impl Format {
+    pub fn encode(&self, message: &SyncMessage) -> Result<Bytes> {
+        Ok(Bytes::from(match self {
+            Format::Json => serde_json::to_vec(message)?,
+            // Named fields, so optional ones can be left out as in JSON
+            Format::MessagePack => rmp_serde::to_vec_named(message)?,
+        }))
+    }
+
+    /// The format of an encoded message. JSON messages start with `{`, or `"`
+    /// for those without fields; to MessagePack both are small integers,
+    /// which no message encodes to.
+    pub fn of(bytes: &[u8]) -> Format {
+        match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{' | b'"') => Format::Json,
+            _ => Format::MessagePack,
+        }
+    }
+}
+
+/// Decodes a message in either format, returning the format it came in so
+/// replies can use the same.
+pub fn decode(bytes: &[u8]) -> Result<(SyncMessage, Format)> {
+    let format = Format::of(bytes);
+    let message = match format {
+        Format::Json => serde_json::from_slice(bytes)?,
+        Format::MessagePack => rmp_serde::from_slice(bytes)?,
+    };
+    Ok((message, format))
+}
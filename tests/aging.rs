@@ -0,0 +1,61 @@
+//! Devices that stopped syncing: stale warnings, paused queues and status.
+
+use mcbd_world_sync::aging::{AgingChange, DeviceAging};
+use mcbd_world_sync::manifest::{Manifest, ManifestCache};
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::transfer_queue::TransferQueue;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[tokio::test]
+async fn devices_turn_stale_and_come_back() {
+    let cache = ManifestCache::new();
+    cache.acknowledge("laptop", Arc::new(Manifest::new(BTreeMap::new())), None).await;
+    let devices = vec!["laptop".to_string(), "new-pc".to_string()];
+    let aging = DeviceAging::new(devices, cache.clone(), 7).with_pause(true);
+    let now = SystemTime::now();
+
+    assert!(aging.refresh(now).await.is_empty());
+    assert!(!aging.is_paused("laptop").await);
+
+    // A device that never synced does not age
+    let changes = aging.refresh(now + 8 * DAY).await;
+    assert!(matches!(&changes[..], [AgingChange::Stale { device, .. }] if device == "laptop"), "{:?}", changes);
+    assert!(aging.is_paused("laptop").await);
+    assert!(!aging.is_paused("new-pc").await);
+    assert!(aging.refresh(now + 9 * DAY).await.is_empty());
+
+    cache.acknowledge("laptop", Arc::new(Manifest::new(BTreeMap::new())), None).await;
+    let changes = aging.refresh(SystemTime::now()).await;
+    assert_eq!(changes, vec![AgingChange::Returned { device: "laptop".to_string(), was_paused: true }]);
+    assert!(!aging.is_paused("laptop").await);
+}
+
+#[tokio::test]
+async fn status_reports_queued_transfers_per_device() {
+    let cache = ManifestCache::new();
+    cache.acknowledge("laptop", Arc::new(Manifest::new(BTreeMap::new())), None).await;
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    for (peer, path) in [("laptop", "w/level.dat"), ("laptop", "w/db/1.ldb"), ("nas", "w/level.dat")] {
+        queue.push(peer.to_string(), PathBuf::from(path), "Modify".to_string()).await;
+    }
+    // Stale devices are still reported without pausing
+    let aging = DeviceAging::new(vec!["laptop".to_string(), "nas".to_string()], cache, 7);
+    aging.refresh(SystemTime::now() + 8 * DAY).await;
+    assert!(!aging.is_paused("laptop").await);
+
+    let status = aging.status(&queue).await;
+    assert_eq!(status[0].name, "laptop");
+    assert!(status[0].stale && !status[0].paused);
+    assert!(status[0].last_synced.is_some());
+    assert_eq!(status[0].queued, 2);
+    assert_eq!((status[1].last_synced, status[1].stale, status[1].queued), (None, false, 1));
+
+    assert_eq!(queue.remove_peer("laptop").await, 2);
+    assert_eq!(queue.len().await, 1);
+    assert_eq!(queue.pop().await.peer, "nas");
+}
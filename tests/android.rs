@@ -0,0 +1,129 @@
+//! Android devices synced over ADB, against a stand-in `adb` that works on a
+//! local folder.
+
+#![cfg(unix)]
+
+use mcbd_world_sync::android::{Adb, AndroidDevice};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+/// Runs `shell` commands locally and copies for `push` and `pull`, keeping
+/// modification times as adb does.
+const FAKE_ADB: &str = r#"#!/bin/sh
+[ "$1" = "-s" ] && shift 2
+command=$1
+shift
+case $command in
+    shell) sh -c "$*" ;;
+    pull) cp -p "$1" "$2" ;;
+    push) mkdir -p "$(dirname "$2")" && cp -p "$1" "$2" ;;
+    connect) echo "connected to $1" ;;
+    *) exit 1 ;;
+esac
+"#;
+
+struct Setup {
+    _dir: tempfile::TempDir,
+    worlds: PathBuf,
+    tablet: PathBuf,
+    files: Mutex<FileManager>,
+    device: AndroidDevice,
+}
+
+fn setup() -> Setup {
+    let dir = tempfile::TempDir::new().unwrap();
+    let adb = dir.path().join("adb");
+    fs::write(&adb, FAKE_ADB).unwrap();
+    fs::set_permissions(&adb, fs::Permissions::from_mode(0o755)).unwrap();
+    let (worlds, tablet) = (dir.path().join("worlds"), dir.path().join("tablet"));
+    fs::create_dir_all(&worlds).unwrap();
+    fs::create_dir_all(&tablet).unwrap();
+    let files = FileManager::new(worlds.clone()).with_exclusions(Exclusions::new(&[]));
+    let adb = Adb::new(adb.to_string_lossy().into_owned(), Some("emulator-5554".to_string()), tablet.to_string_lossy().into_owned());
+    let device = AndroidDevice::new("tablet".to_string(), adb, &dir.path().join("state"));
+    Setup { worlds, tablet, files: Mutex::new(files), device, _dir: dir }
+}
+
+impl Setup {
+    fn sync(&self) -> mcbd_world_sync::android::AndroidSync {
+        let index = {
+            let mut files = self.files.blocking_lock();
+            files.rescan().unwrap();
+            files.index()
+        };
+        self.device.sync(&index, &self.files).unwrap()
+    }
+}
+
+/// Writes `content` dated `age` ago, since only whole seconds are compared.
+fn write(path: &Path, content: &str, age: u64) {
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+    fs::File::options().write(true).open(path).unwrap().set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+}
+
+#[test]
+fn changes_on_either_side_reach_the_other() {
+    let setup = setup();
+    write(&setup.worlds.join("Alpha/level.dat"), "alpha", 100);
+    write(&setup.tablet.join("Beta/level.dat"), "beta", 100);
+    write(&setup.worlds.join("Both/level.dat"), "same", 100);
+    write(&setup.tablet.join("Both/level.dat"), "same", 50);
+
+    let synced = setup.sync();
+    assert_eq!(synced.received, vec![PathBuf::from("Beta/level.dat")]);
+    assert_eq!(synced.pushed, 1);
+    assert_eq!(fs::read(setup.tablet.join("Alpha/level.dat")).unwrap(), b"alpha");
+    assert_eq!(fs::read(setup.worlds.join("Beta/level.dat")).unwrap(), b"beta");
+    // Nothing changed since
+    assert_eq!(setup.sync(), Default::default());
+
+    write(&setup.tablet.join("Alpha/level.dat"), "alpha, played on the tablet", 10);
+    fs::remove_file(setup.worlds.join("Beta/level.dat")).unwrap();
+    let synced = setup.sync();
+    assert_eq!(synced.received, vec![PathBuf::from("Alpha/level.dat")]);
+    assert_eq!(synced.deleted_there, 1);
+    assert_eq!(fs::read(setup.worlds.join("Alpha/level.dat")).unwrap(), b"alpha, played on the tablet");
+    assert!(!setup.tablet.join("Beta/level.dat").exists());
+
+    fs::remove_file(setup.tablet.join("Both/level.dat")).unwrap();
+    assert_eq!(setup.sync().removed, vec![PathBuf::from("Both/level.dat")]);
+    assert!(!setup.worlds.join("Both/level.dat").exists());
+}
+
+#[test]
+fn the_newer_copy_wins_when_both_changed() {
+    let setup = setup();
+    write(&setup.worlds.join("Alpha/level.dat"), "alpha", 100);
+    write(&setup.worlds.join("Alpha/db/1.ldb"), "chunks", 100);
+    setup.sync();
+
+    write(&setup.worlds.join("Alpha/level.dat"), "newer here", 10);
+    write(&setup.tablet.join("Alpha/level.dat"), "older there", 20);
+    write(&setup.worlds.join("Alpha/db/1.ldb"), "older here", 20);
+    write(&setup.tablet.join("Alpha/db/1.ldb"), "newer there", 10);
+    let synced = setup.sync();
+    assert_eq!((synced.received.len(), synced.pushed), (1, 1));
+    assert_eq!(fs::read(setup.tablet.join("Alpha/level.dat")).unwrap(), b"newer here");
+    assert_eq!(fs::read(setup.worlds.join("Alpha/db/1.ldb")).unwrap(), b"newer there");
+}
+
+#[test]
+fn a_missing_or_emptied_device_directory_deletes_nothing() {
+    let setup = setup();
+    write(&setup.worlds.join("Alpha/level.dat"), "alpha", 100);
+    setup.sync();
+
+    fs::remove_file(setup.tablet.join("Alpha/level.dat")).unwrap();
+    fs::remove_dir(setup.tablet.join("Alpha")).unwrap();
+    let index = setup.files.blocking_lock().index();
+    assert!(setup.device.sync(&index, &setup.files).is_err());
+    fs::remove_dir(&setup.tablet).unwrap();
+    assert!(setup.device.sync(&index, &setup.files).is_err());
+    assert!(setup.worlds.join("Alpha/level.dat").exists());
+}
@@ -0,0 +1,84 @@
+//! Archived worlds: snapshotted one last time, then left out of the index
+//! and of syncing until they are restored.
+
+use mcbd_world_sync::archive::{self, ArchiveProposals, ArchivedWorlds, ARCHIVE_REASON};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::{FileInfo, FileManager};
+use mcbd_world_sync::snapshots::Snapshots;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn worlds(dir: &Path) {
+    for world in ["Old", "Played"] {
+        fs::create_dir_all(dir.join("worlds").join(world).join("db")).unwrap();
+        fs::write(dir.join("worlds").join(world).join("level.dat"), world).unwrap();
+    }
+}
+
+#[test]
+fn archived_worlds_get_a_final_snapshot_and_are_remembered() {
+    let dir = tempfile::TempDir::new().unwrap();
+    worlds(dir.path());
+    let (file, snapshots) = (dir.path().join("archived.json"), Snapshots::new(dir.path().join("snapshots")));
+    let daemon = ArchivedWorlds::load(file.clone()).unwrap();
+
+    let cli = ArchivedWorlds::load(file.clone()).unwrap();
+    let snapshot = cli.archive(&dir.path().join("worlds"), "Old", &snapshots, SystemTime::now()).unwrap();
+    assert_eq!(snapshot.reason, ARCHIVE_REASON);
+    assert_eq!(fs::read(snapshot.path.join("level.dat")).unwrap(), b"Old");
+    assert!(cli.archive(&dir.path().join("worlds"), "Old", &snapshots, SystemTime::now()).is_err());
+    assert!(cli.archive(&dir.path().join("worlds"), "Missing", &snapshots, SystemTime::now()).is_err());
+    assert_eq!(ArchivedWorlds::load(file.clone()).unwrap().worlds(), vec!["Old".to_string()]);
+
+    // The daemon learns what changed when it reads the file again
+    assert!(!daemon.contains("Old"));
+    assert_eq!(daemon.reload().unwrap(), (vec!["Old".to_string()], Vec::new()));
+    assert!(daemon.contains_path(Path::new("Old/db/000005.ldb")));
+    cli.restore("Old").unwrap();
+    assert!(cli.restore("Old").is_err());
+    assert_eq!(daemon.reload().unwrap(), (Vec::new(), vec!["Old".to_string()]));
+}
+
+#[test]
+fn archived_worlds_are_neither_indexed_nor_written() {
+    let dir = tempfile::TempDir::new().unwrap();
+    worlds(dir.path());
+    let archived = ArchivedWorlds::new();
+    archived.archive(&dir.path().join("worlds"), "Old", &Snapshots::new(dir.path().join("snapshots")), SystemTime::now()).unwrap();
+    let exclusions = Exclusions::new(&[]).with_archived(archived.clone());
+    let mut files = FileManager::new(dir.path().join("worlds")).with_exclusions(exclusions);
+    files.scan_directory().unwrap();
+    assert!(files.entries().iter().all(|f| f.path.starts_with("Played")), "{:?}", files.entries());
+
+    // Taken as delivered, so the sender does not retry it
+    assert!(!files.receive_file(Path::new("Old/level.dat"), b"changed elsewhere").unwrap());
+    files.remove_received(Path::new("Old/level.dat")).unwrap();
+    assert_eq!(fs::read(dir.path().join("worlds/Old/level.dat")).unwrap(), b"Old");
+
+    archived.restore("Old").unwrap();
+    files.scan_subtree(&dir.path().join("worlds/Old")).unwrap();
+    assert!(files.get_file_info(Path::new("Old/level.dat")).is_some());
+}
+
+fn file(path: &str, days: u64) -> FileInfo {
+    FileInfo { path: PathBuf::from(path), last_modified: UNIX_EPOCH + Duration::from_secs(days * 86400), size: 1, hash: "h".to_string() }
+}
+
+#[test]
+fn worlds_untouched_long_enough_are_proposed_until_kept() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let entries = [file("Old/level.dat", 10), file("Old/db/000005.ldb", 20), file("Played/level.dat", 95), file("@packs/manifest.json", 1)];
+    let changes = archive::last_changes(&entries);
+    assert_eq!(changes.keys().collect::<Vec<_>>(), ["Old", "Played"]);
+    assert_eq!(changes["Old"], UNIX_EPOCH + Duration::from_secs(20 * 86400));
+
+    let (proposals, now, month) = (ArchiveProposals::new(dir.path().join("kept.json")), UNIX_EPOCH + Duration::from_secs(100 * 86400), Duration::from_secs(30 * 86400));
+    assert_eq!(proposals.propose(&changes, month, now).unwrap(), vec![("Old".to_string(), changes["Old"])]);
+    proposals.keep("Old", changes["Old"]).unwrap();
+    assert!(proposals.propose(&changes, month, now).unwrap().is_empty());
+
+    // Played again since it was kept, then left alone
+    let changes = archive::last_changes(&[file("Old/level.dat", 40)]);
+    assert_eq!(proposals.propose(&changes, month, now).unwrap().len(), 1);
+}
@@ -0,0 +1,69 @@
+//! Devices proving their name with a pre-shared key before a daemon that
+//! has keys configured accepts anything from them.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::auth::{DeviceAuth, DeviceKeys, AUTH_WINDOW};
+use mcbd_world_sync::config::Device;
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::{SyncClient, SyncServer};
+use mcbd_world_sync::transfer_queue::Priority;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+fn keys() -> DeviceKeys {
+    DeviceKeys::new(&[
+        Device { key: Some("laptop-secret".to_string()), ..Device::from_target("laptop", "127.0.0.1:1") },
+        Device::from_target("tablet", "127.0.0.1:2"),
+    ])
+}
+
+#[test]
+fn proofs_need_the_key_a_fresh_time_and_a_new_nonce() {
+    let keys = keys();
+    let now = SystemTime::now();
+    let auth = DeviceAuth::new("laptop", "laptop-secret", now).unwrap();
+    assert!(keys.verify(&auth, now).is_ok());
+    // The same proof twice is a replay
+    assert!(keys.verify(&auth, now).is_err());
+
+    assert!(keys.verify(&DeviceAuth::new("laptop", "guessed", now).unwrap(), now).is_err());
+    let stale = DeviceAuth::new("laptop", "laptop-secret", now - AUTH_WINDOW - Duration::from_secs(1)).unwrap();
+    assert!(keys.verify(&stale, now).is_err());
+    // A device without a key cannot authenticate at all
+    assert!(keys.verify(&DeviceAuth::new("tablet", "", now).unwrap(), now).is_err());
+
+    let renamed = DeviceAuth { device: "tablet".to_string(), ..DeviceAuth::new("laptop", "laptop-secret", now).unwrap() };
+    assert!(keys.verify(&renamed, now).is_err());
+    assert!(!DeviceKeys::new(&[Device::from_target("tablet", "127.0.0.1:2")]).required());
+}
+
+#[tokio::test]
+async fn only_authenticated_devices_can_send_content() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let files = Arc::new(Mutex::new(FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]))));
+    let server = SyncServer::new(port).with_health(health.clone()).with_file_manager(files).with_device_keys(keys());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let address = format!("127.0.0.1:{}", port);
+
+    let path = PathBuf::from("World/level.dat");
+    let anonymous = SyncClient::new(address.clone());
+    assert!(anonymous.send_file_content(path.clone(), b"anonymous".to_vec(), None, Priority::Background).await.is_err());
+    let wrong = SyncClient::new(address.clone()).with_device_name("laptop".to_string()).with_key(Some("guessed".to_string()));
+    assert!(wrong.send_file_content(path.clone(), b"wrong".to_vec(), None, Priority::Background).await.is_err());
+    assert!(!dir.path().join(&path).exists());
+
+    let laptop = SyncClient::new(address).with_device_name("laptop".to_string()).with_key(Some("laptop-secret".to_string()));
+    laptop.send_file_content(path.clone(), b"level".to_vec(), None, Priority::Background).await.unwrap();
+    assert_eq!(std::fs::read(dir.path().join(&path)).unwrap(), b"level");
+}
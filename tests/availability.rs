@@ -0,0 +1,80 @@
+//! A worlds directory on a network share or external drive: while it is
+//! gone nothing is taken for deleted, and syncing resumes once it returns.
+
+mod common;
+
+use common::daemon::{assert_converged, free_port, wait_until, TestDaemon, CONVERGE_TIMEOUT};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+#[test]
+fn a_missing_or_emptied_root_is_unavailable_not_deleted() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let root = dir.path().join("share");
+    fs::create_dir_all(root.join("w")).unwrap();
+    fs::write(root.join("w/level.dat"), "level").unwrap();
+    let mut files = FileManager::new(root.clone()).with_exclusions(Exclusions::new(&[]));
+    files.scan_directory().unwrap();
+    let index = files.index();
+    assert!(index.check_available());
+
+    // Unmounted: the mount point is left empty
+    fs::rename(root.join("w"), dir.path().join("away")).unwrap();
+    assert!(files.poll().is_err());
+    assert!(files.rescan().is_err());
+    assert!(!index.is_available());
+    assert!(files.get_file_info(Path::new("w/level.dat")).is_some());
+    assert!(files.tombstones().is_empty());
+    assert!(files.receive_file(Path::new("w/new.txt"), b"new").is_err());
+    assert!(files.remove_received(Path::new("w/level.dat")).is_err());
+
+    // Gone entirely, as a disconnected drive
+    fs::remove_dir(&root).unwrap();
+    assert!(!index.check_available());
+    assert!(files.receive_file(Path::new("w/new.txt"), b"new").is_err());
+    assert!(!root.exists());
+
+    fs::create_dir(&root).unwrap();
+    fs::rename(dir.path().join("away"), root.join("w")).unwrap();
+    assert!(files.poll().unwrap().is_empty());
+    assert!(index.is_available());
+    assert!(files.receive_file(Path::new("w/new.txt"), b"new").unwrap());
+}
+
+#[test]
+fn an_empty_root_without_indexed_files_is_available() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    assert!(files.index().check_available());
+}
+
+#[test]
+fn syncing_resumes_when_the_root_returns() {
+    let port_a = free_port();
+    let port_b = std::iter::repeat_with(free_port).find(|p| *p != port_a).unwrap();
+    let mut a = TestDaemon::new("a", port_a, port_b, "newest").with_http();
+    let mut b = TestDaemon::new("b", port_b, port_a, "newest");
+    a.start();
+    b.start();
+    fs::create_dir_all(a.worlds.join("Alpha")).unwrap();
+    fs::write(a.worlds.join("Alpha/level.dat"), "alpha").unwrap();
+    assert_converged(&a, &b);
+
+    let away = a.dir.path().join("away");
+    fs::rename(&a.worlds, &away).unwrap();
+    assert!(wait_until(CONVERGE_TIMEOUT, || a.log().contains("is unavailable, pausing")), "{}", a.log());
+    assert_eq!(a.http_get("/readyz").unwrap().0, 503);
+    // What b sends meanwhile is not written where the worlds used to be
+    fs::write(b.worlds.join("Alpha/from_b.txt"), "from b").unwrap();
+    std::thread::sleep(Duration::from_secs(3));
+    assert!(!a.worlds.exists());
+
+    fs::rename(&away, &a.worlds).unwrap();
+    assert!(wait_until(CONVERGE_TIMEOUT, || a.log().contains("is available again")), "{}", a.log());
+    fs::write(a.worlds.join("Alpha/level.dat"), "alpha, changed").unwrap();
+    assert_converged(&a, &b);
+    assert_eq!(fs::read(b.worlds.join("Alpha/level.dat")).unwrap(), b"alpha, changed");
+}
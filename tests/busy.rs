@@ -0,0 +1,113 @@
+//! Worlds open in Minecraft: their changes are held back until the game
+//! closes them, unless sent from a shadow copy, and devices tell each other
+//! which of theirs are open.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::busy::{self, BusyWorlds};
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::network::{SyncClient, SyncServer};
+use mcbd_world_sync::shadow_copy::ShadowCopy;
+use mcbd_world_sync::transfer_queue::{Priority, TransferQueue};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[test]
+fn worlds_are_open_while_their_lock_is_held_or_the_game_runs() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for world in ["a", "b"] {
+        fs::create_dir_all(dir.path().join(world).join("db")).unwrap();
+        fs::write(dir.path().join(world).join("db/LOCK"), b"").unwrap();
+    }
+    fs::create_dir_all(dir.path().join("not a world")).unwrap();
+    assert!(busy::open_worlds(dir.path(), false).is_empty());
+
+    let lock = File::open(dir.path().join("a/db/LOCK")).unwrap();
+    lock.lock().unwrap();
+    assert!(busy::lock_held(&dir.path().join("a")));
+    assert!(!busy::lock_held(&dir.path().join("b")));
+    assert_eq!(busy::open_worlds(dir.path(), false), BTreeSet::from(["a".to_string()]));
+    assert_eq!(busy::open_worlds(dir.path(), true), BTreeSet::from(["a".to_string(), "b".to_string()]));
+
+    drop(lock);
+    assert!(busy::open_worlds(dir.path(), false).is_empty());
+}
+
+#[tokio::test]
+async fn changes_of_held_worlds_wait_until_they_are_released() {
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    queue.hold_worlds(&["a".to_string()]).await;
+    queue.push("peer".to_string(), PathBuf::from("a/db/000005.ldb"), "Modify".to_string()).await;
+    queue.push("peer".to_string(), PathBuf::from("b/level.dat"), "Modify".to_string()).await;
+
+    assert_eq!(queue.pop().await.path, PathBuf::from("b/level.dat"));
+    assert!(tokio::time::timeout(Duration::from_millis(200), queue.pop()).await.is_err());
+    assert_eq!(queue.len_for("peer").await, 2);
+
+    queue.hold_worlds(&[]).await;
+    assert_eq!(queue.pop().await.path, PathBuf::from("a/db/000005.ldb"));
+}
+
+#[tokio::test]
+async fn peers_learn_which_worlds_are_open() {
+    let port = free_port();
+    let (health, busy) = (Arc::new(Health::new()), BusyWorlds::new());
+    let server = SyncServer::new(port).with_health(health.clone()).with_busy_worlds(busy.clone());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let client = SyncClient::new(format!("127.0.0.1:{}", port));
+    client.send_busy_worlds("laptop".to_string(), vec!["Survival".to_string()]).await.unwrap();
+    for _ in 0..50 {
+        if !busy.peers().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(busy.peers().get("laptop"), Some(&vec!["Survival".to_string()]));
+    // Nothing of this device is held back for it
+    assert!(busy.worlds().is_empty());
+
+    client.send_busy_worlds("laptop".to_string(), Vec::new()).await.unwrap();
+    for _ in 0..50 {
+        if busy.peers().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(busy.peers().is_empty());
+}
+
+#[tokio::test]
+async fn worlds_let_through_only_send_what_was_asked_for() {
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    queue.hold_worlds(&["a".to_string()]).await;
+    queue.push("peer".to_string(), PathBuf::from("a/db/000007.log"), "Modify".to_string()).await;
+    queue.push_with_priority("peer".to_string(), PathBuf::from("a/level.dat"), "SyncNow".to_string(), Priority::Interactive).await;
+    assert!(tokio::time::timeout(Duration::from_millis(200), queue.pop()).await.is_err());
+
+    queue.let_through("a").await;
+    assert_eq!(queue.pop().await.path, PathBuf::from("a/level.dat"));
+    // Changes made after the copy was taken wait for the world to close
+    assert!(tokio::time::timeout(Duration::from_millis(200), queue.pop()).await.is_err());
+
+    // Held again later, the world is not let through anymore
+    queue.hold_worlds(&[]).await;
+    queue.hold_worlds(&["a".to_string()]).await;
+    queue.push_with_priority("peer".to_string(), PathBuf::from("a/level.dat"), "SyncNow".to_string(), Priority::Interactive).await;
+    assert!(tokio::time::timeout(Duration::from_millis(200), queue.pop()).await.is_err());
+}
+
+#[cfg(not(windows))]
+#[test]
+fn shadow_copies_are_only_taken_on_windows() {
+    let error = ShadowCopy::create(Path::new("/")).unwrap_err();
+    assert!(error.to_string().contains("Windows"), "{}", error);
+}
@@ -0,0 +1,65 @@
+use futures::channel::mpsc;
+use futures::StreamExt;
+use mcbd_world_sync::chaos::{send_frame, Chaos};
+use std::time::Duration;
+use tokio_util::bytes::Bytes;
+
+async fn deliver(chaos: &Chaos, frames: usize) -> (Vec<Bytes>, usize) {
+    let (mut tx, rx) = mpsc::unbounded();
+    let mut killed = 0;
+    for i in 0..frames {
+        let frame = Bytes::from(format!("frame-{:04}", i));
+        if send_frame(&mut tx, frame, Some(chaos)).await.is_err() {
+            killed += 1;
+        }
+    }
+    drop(tx);
+    (rx.collect().await, killed)
+}
+
+#[tokio::test]
+async fn disabled_chaos_passes_frames_through() {
+    let (mut tx, rx) = mpsc::unbounded();
+    send_frame(&mut tx, Bytes::from_static(b"hello"), None).await.unwrap();
+    drop(tx);
+    assert_eq!(rx.collect::<Vec<_>>().await, vec![Bytes::from_static(b"hello")]);
+}
+
+#[tokio::test]
+async fn each_fault_kind_is_applied() {
+    let (received, _) = deliver(&Chaos::parse("drop=1").unwrap(), 10).await;
+    assert!(received.is_empty());
+
+    let (received, _) = deliver(&Chaos::parse("duplicate=1").unwrap(), 10).await;
+    assert_eq!(received.len(), 20);
+
+    let (received, _) = deliver(&Chaos::parse("truncate=1").unwrap(), 10).await;
+    assert!(received.iter().all(|f| f.len() < "frame-0000".len()));
+
+    let (received, killed) = deliver(&Chaos::parse("kill=1").unwrap(), 10).await;
+    assert!(received.is_empty());
+    assert_eq!(killed, 10);
+}
+
+#[tokio::test]
+async fn same_seed_gives_same_faults() {
+    let spec = "drop=0.3,duplicate=0.3,truncate=0.2,seed=99";
+    let first = deliver(&Chaos::parse(spec).unwrap(), 200).await;
+    let second = deliver(&Chaos::parse(spec).unwrap(), 200).await;
+    assert_eq!(first, second);
+}
+
+#[tokio::test]
+async fn delay_is_bounded() {
+    let chaos = Chaos::parse("delay=20").unwrap();
+    let start = std::time::Instant::now();
+    let (received, _) = deliver(&chaos, 5).await;
+    assert_eq!(received.len(), 5);
+    assert!(start.elapsed() < Duration::from_secs(2));
+}
+
+#[test]
+fn rejects_unknown_settings() {
+    assert!(Chaos::parse("explode=1").is_err());
+    assert!(Chaos::parse("drop").is_err());
+}
@@ -0,0 +1,215 @@
+//! Content-defined chunks and the store that keeps them, so content a peer
+//! already has is not sent again.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::chunk_store::{self, Chunk, ChunkStore};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::{self, FileManager};
+use mcbd_world_sync::flow_control::WriteWindow;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::{SyncClient, SyncMessage, SyncServer};
+use mcbd_world_sync::transfer_queue::Priority;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as u8
+    }).collect()
+}
+
+#[test]
+fn an_insertion_only_changes_nearby_chunks() {
+    let data = noise(512 * 1024, 1);
+    let mut edited = b"a few new bytes".to_vec();
+    edited.extend_from_slice(&data);
+
+    let before = chunk_store::chunk_hashes(&data);
+    let after = chunk_store::chunk_hashes(&edited);
+    let changed = after.iter().filter(|c| !before.contains(c)).count();
+    assert!(before.len() > 20, "{} chunks", before.len());
+    assert!(changed <= 2, "{} of {} chunks changed", changed, after.len());
+    assert_eq!(chunk_store::boundaries(&data).last().unwrap().end, data.len());
+}
+
+#[test]
+fn stored_chunks_rebuild_files_and_age_out() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let store = ChunkStore::new(dir.path().to_path_buf());
+    let data = noise(100 * 1024, 2);
+    let chunks = chunk_store::chunk_hashes(&data);
+    assert_eq!(store.missing(&chunks).len(), chunks.len());
+
+    store.insert_file(&data).unwrap();
+    assert!(store.missing(&chunks).is_empty());
+    assert_eq!(chunk_store::assemble(&chunks, &[], Some(&store)).unwrap(), data);
+    assert!(store.get("../../etc/passwd").is_err());
+
+    assert_eq!(store.prune(Duration::from_secs(3600), SystemTime::now()).unwrap(), 0);
+    let removed = store.prune(Duration::from_secs(3600), SystemTime::now() + Duration::from_secs(7200)).unwrap();
+    assert_eq!(removed, store.missing(&chunks).len());
+    assert!(chunk_store::assemble(&chunks, &[], Some(&store)).is_err());
+}
+
+#[test]
+fn received_chunks_are_checked_against_their_hash_once() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let store = ChunkStore::new(dir.path().to_path_buf());
+    let data = noise(100 * 1024, 5);
+    let Chunk { hash, bytes } = chunk_store::select(&data, &chunk_store::chunk_hashes(&data)).remove(0);
+
+    assert!(store.put_received(&hash, &bytes).unwrap());
+    assert!(!store.put_received(&hash, &bytes).unwrap());
+    let forged = chunk_store::chunk_hashes(&noise(100 * 1024, 6)).remove(0);
+    assert!(store.put_received(&forged, &bytes).is_err());
+    assert!(!store.contains(&forged));
+}
+
+/// Starts a receiving daemon's server and returns a client for it.
+async fn receiver(dir: &Path) -> SyncClient {
+    receiver_with_window(dir, WriteWindow::default()).await
+}
+
+async fn receiver_with_window(dir: &Path, window: WriteWindow) -> SyncClient {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let files = Arc::new(Mutex::new(FileManager::new(dir.join("worlds")).with_exclusions(Exclusions::new(&[]))));
+    let server = SyncServer::new(port)
+        .with_health(health.clone())
+        .with_file_manager(files)
+        .with_chunk_store(ChunkStore::new(dir.join("chunks")))
+        .with_write_window(window);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    SyncClient::new(format!("127.0.0.1:{}", port))
+}
+
+#[tokio::test]
+async fn peers_only_request_chunks_they_lack() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    let client = receiver(dir.path()).await;
+
+    let original = noise(300 * 1024, 3);
+    client.send_file_content(PathBuf::from("World/db/000011.ldb"), original.clone(), None, Priority::Background).await.unwrap();
+
+    // A copy of the world with a small edit shares almost every chunk
+    let mut copy = original.clone();
+    copy.splice(1000..1000, b"edited".iter().copied());
+    let chunks = chunk_store::chunk_hashes(&copy);
+    let mut session = client.session().await.unwrap();
+    session.send(&SyncMessage::ChunkList { path: PathBuf::from("Copy/db/000011.ldb"), chunks: chunks.clone(), group: None }).await.unwrap();
+    let Some(SyncMessage::ChunkRequest { missing, .. }) = session.recv().await else { panic!("no chunk request") };
+    assert!(missing.len() <= 2, "{} of {} chunks requested", missing.len(), chunks.len());
+
+    client.send_file_content(PathBuf::from("Copy/db/000011.ldb"), copy.clone(), None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(worlds.join("Copy/db/000011.ldb")).unwrap(), copy);
+}
+
+#[tokio::test]
+async fn interrupted_transfers_resume_after_the_stored_parts() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = PathBuf::from("World/db/000012.ldb");
+    let content = noise(3 * 1024 * 1024, 4);
+    let chunks = chunk_store::chunk_hashes(&content);
+
+    // The connection drops after the first part was acknowledged
+    let client = receiver(dir.path()).await;
+    let mut session = client.session().await.unwrap();
+    session.send(&SyncMessage::ChunkList { path: path.clone(), chunks: chunks.clone(), group: None }).await.unwrap();
+    let Some(SyncMessage::ChunkRequest { missing, resumable: true, .. }) = session.recv().await else { panic!("no resumable chunk request") };
+    let first: Vec<Chunk> = chunk_store::select(&content, &missing[..missing.len() / 3]);
+    let count = first.len();
+    session.send(&SyncMessage::ChunkPart { path: path.clone(), chunks: first, group: None, correlation_id: None }).await.unwrap();
+    let Some(SyncMessage::ChunkPartStored { count: stored, .. }) = session.recv().await else { panic!("part not stored") };
+    assert_eq!(stored, count);
+    drop(session);
+
+    // After a restart the receiver only asks for the rest
+    let client = receiver(dir.path()).await;
+    let mut session = client.session().await.unwrap();
+    session.send(&SyncMessage::ChunkList { path: path.clone(), chunks: chunks.clone(), group: None }).await.unwrap();
+    let Some(SyncMessage::ChunkRequest { missing: rest, .. }) = session.recv().await else { panic!("no chunk request") };
+    assert_eq!(rest.len(), missing.len() - count);
+
+    client.send_file_content(path.clone(), content.clone(), None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(dir.path().join("worlds").join(&path)).unwrap(), content);
+}
+
+#[tokio::test]
+async fn chunks_delivered_twice_are_applied_once() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = PathBuf::from("World/db/000013.ldb");
+    let content = noise(300 * 1024, 7);
+    let chunks = chunk_store::chunk_hashes(&content);
+    let data = ChunkStore::all_unique(&chunks);
+    let message = || SyncMessage::ChunkData {
+        path: path.clone(),
+        hash: file_manager::hash_bytes(&content),
+        chunks: chunks.clone(),
+        data: chunk_store::select(&content, &data),
+        group: None,
+        modified: None,
+        version: None,
+        correlation_id: None,
+    };
+
+    let client = receiver(dir.path()).await;
+    let mut session = client.session().await.unwrap();
+    session.send(&message()).await.unwrap();
+    assert!(matches!(session.recv().await, Some(SyncMessage::FileReceived { .. })));
+    let written = fs::metadata(dir.path().join("worlds").join(&path)).unwrap().modified().unwrap();
+
+    // As after a reconnect that lost the first acknowledgement
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    session.send(&message()).await.unwrap();
+    assert!(matches!(session.recv().await, Some(SyncMessage::FileReceived { .. })));
+    assert_eq!(fs::metadata(dir.path().join("worlds").join(&path)).unwrap().modified().unwrap(), written);
+}
+
+#[tokio::test]
+async fn senders_only_send_ahead_as_far_as_the_receiver_grants() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = PathBuf::from("World/db/000014.ldb");
+    let content = noise(3 * 1024 * 1024, 8);
+    let chunks = chunk_store::chunk_hashes(&content);
+    let window = WriteWindow::new(2);
+    let client = receiver_with_window(dir.path(), window.clone()).await;
+
+    let mut session = client.session().await.unwrap();
+    session.send(&SyncMessage::ChunkList { path: path.clone(), chunks: chunks.clone(), group: None }).await.unwrap();
+    let Some(SyncMessage::ChunkRequest { missing, credits: Some(2), .. }) = session.recv().await else { panic!("no credits granted") };
+    assert_eq!(window.depth(), 2);
+
+    // Another transfer still gets one part while the queue is full
+    let mut other = client.session().await.unwrap();
+    other.send(&SyncMessage::ChunkList { path: PathBuf::from("Other/db/000014.ldb"), chunks: chunks.clone(), group: None }).await.unwrap();
+    let Some(SyncMessage::ChunkRequest { credits: Some(1), .. }) = other.recv().await else { panic!("no credit granted") };
+    assert_eq!(window.depth(), 3);
+
+    // A stored part is replaced only once the queue has room
+    let part = chunk_store::select(&content, &missing[..1]);
+    session.send(&SyncMessage::ChunkPart { path: path.clone(), chunks: part, group: None, correlation_id: None }).await.unwrap();
+    let Some(SyncMessage::ChunkPartStored { credits: Some(0), .. }) = session.recv().await else { panic!("credit granted over the limit") };
+    assert_eq!(window.depth(), 2);
+    drop((session, other));
+
+    // Whole files still go through, sent ahead as far as they are granted
+    let dir = tempfile::TempDir::new().unwrap();
+    let window = WriteWindow::new(2);
+    let client = receiver_with_window(dir.path(), window.clone()).await;
+    client.send_file_content(path.clone(), content.clone(), None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(dir.path().join("worlds").join(&path)).unwrap(), content);
+    assert_eq!(window.depth(), 0);
+}
@@ -0,0 +1,137 @@
+//! The command line: argument parsing and the commands that work on the
+//! worlds directory or talk to a running daemon.
+
+mod common;
+
+use clap::Parser;
+use common::daemon::{free_port, wait_until, TestDaemon, CONVERGE_TIMEOUT};
+use mcbd_world_sync::cli::{Cli, Command, ShareCommand};
+use mcbd_world_sync::file_manager::FileInfo;
+use mcbd_world_sync::index::{self, Discrepancy};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Output;
+use std::time::SystemTime;
+
+fn run(daemon: &TestDaemon, args: &[&str]) -> Output {
+    std::process::Command::new(env!("CARGO_BIN_EXE_mcbd-world-sync"))
+        .args(args)
+        .current_dir(daemon.dir.path())
+        .env_remove("MCBD_HEADLESS")
+        .output()
+        .unwrap()
+}
+
+fn file(path: &str, size: u64, hash: &str) -> FileInfo {
+    FileInfo { path: PathBuf::from(path), last_modified: SystemTime::UNIX_EPOCH, size, hash: hash.to_string() }
+}
+
+#[test]
+fn commands_and_flags_are_parsed() {
+    let cli = Cli::try_parse_from(["mcbd-world-sync"]).unwrap();
+    assert!(cli.command.is_none());
+    assert_eq!(cli.config, Path::new("config.json"));
+
+    let cli = Cli::try_parse_from(["mcbd-world-sync", "sync-now", "Alpha", "--config", "other.json", "--log-level", "warn"]).unwrap();
+    assert!(matches!(cli.command, Some(Command::SyncNow { world: Some(world), full: false, .. }) if world == "Alpha"));
+    assert_eq!(cli.config, Path::new("other.json"));
+    assert_eq!(cli.log_level, Some(log::LevelFilter::Warn));
+
+    let cli = Cli::try_parse_from(["mcbd-world-sync", "archive", "--proposed", "--yes"]).unwrap();
+    assert!(matches!(cli.command, Some(Command::Archive { world: None, proposed: true, yes: true })));
+    assert!(Cli::try_parse_from(["mcbd-world-sync", "archive"]).is_err());
+    assert!(Cli::try_parse_from(["mcbd-world-sync", "archive", "Alpha", "--yes"]).is_err());
+
+    let cli = Cli::try_parse_from(["mcbd-world-sync", "deletions", "Alpha", "--apply"]).unwrap();
+    assert!(matches!(cli.command, Some(Command::Deletions { path: Some(_), apply: true, keep: false })));
+    assert!(Cli::try_parse_from(["mcbd-world-sync", "deletions"]).is_ok());
+    assert!(Cli::try_parse_from(["mcbd-world-sync", "deletions", "Alpha"]).is_err());
+    assert!(Cli::try_parse_from(["mcbd-world-sync", "deletions", "--apply", "--keep"]).is_err());
+
+    let cli = Cli::try_parse_from(["mcbd-world-sync", "report", "--send"]).unwrap();
+    assert!(matches!(cli.command, Some(Command::Report { send: true })));
+
+    let cli = Cli::try_parse_from(["mcbd-world-sync", "pair", "10.0.0.2:25565", "123456"]).unwrap();
+    assert!(matches!(cli.command, Some(Command::Pair { address: Some(_), code: Some(_), port: None })));
+    assert!(Cli::try_parse_from(["mcbd-world-sync", "pair", "10.0.0.2:25565"]).is_err());
+    assert!(Cli::try_parse_from(["mcbd-world-sync", "pair", "10.0.0.2:25565", "123456", "--port", "1"]).is_err());
+
+    let cli = Cli::try_parse_from(["mcbd-world-sync", "share", "create", "Alpha", "friend", "--days", "3"]).unwrap();
+    assert!(matches!(
+        cli.command,
+        Some(Command::Share { command: ShareCommand::Create { days: 3, read_write: false, address: None, .. } })
+    ));
+    assert!(Cli::try_parse_from(["mcbd-world-sync", "sync-now"]).is_err());
+    assert!(Cli::try_parse_from(["mcbd-world-sync", "frobnicate"]).is_err());
+}
+
+#[test]
+fn verify_reports_files_that_differ_from_the_index() {
+    let stored = vec![file("Alpha/level.dat", 5, "a"), file("Alpha/db/1.ldb", 6, ""), file("Alpha/gone", 1, "g"), file("Beta/level.dat", 4, "b")];
+    let scanned = vec![file("Alpha/level.dat", 5, "changed"), file("Alpha/db/1.ldb", 6, "c"), file("Alpha/new", 1, "n"), file("Beta/level.dat", 2, "b")];
+
+    // The pending entry only counts as changed if its size differs
+    assert_eq!(index::verify(&stored, &scanned, Path::new("Alpha")), vec![
+        Discrepancy::Missing(PathBuf::from("Alpha/gone")),
+        Discrepancy::Changed(PathBuf::from("Alpha/level.dat")),
+        Discrepancy::Unindexed(PathBuf::from("Alpha/new")),
+    ]);
+    assert_eq!(index::verify(&stored, &scanned, Path::new("")).len(), 4);
+}
+
+#[test]
+fn worlds_are_listed_exported_and_imported() {
+    let daemon = TestDaemon::new("local", free_port(), free_port(), "newest");
+    fs::create_dir_all(daemon.worlds.join("Alpha/db")).unwrap();
+    fs::write(daemon.worlds.join("Alpha/level.dat"), "alpha").unwrap();
+    fs::write(daemon.worlds.join("Alpha/levelname.txt"), "My Alpha").unwrap();
+    fs::write(daemon.worlds.join("Alpha/db/1.ldb"), "chunks").unwrap();
+
+    let output = run(&daemon, &["list-worlds"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Alpha\tMy Alpha\n");
+
+    let archive = daemon.dir.path().join("alpha.mcworld");
+    assert!(run(&daemon, &["export", "Alpha", archive.to_str().unwrap()]).status.success());
+    assert!(run(&daemon, &["import", archive.to_str().unwrap(), "Copy"]).status.success());
+    assert_eq!(fs::read(daemon.worlds.join("Copy/db/1.ldb")).unwrap(), b"chunks");
+    // Named after the file unless given, and never over an existing world
+    assert!(run(&daemon, &["import", archive.to_str().unwrap()]).status.success());
+    assert!(daemon.worlds.join("alpha/levelname.txt").exists());
+    assert!(!run(&daemon, &["import", archive.to_str().unwrap(), "Copy"]).status.success());
+    assert!(!run(&daemon, &["export", "Missing", archive.to_str().unwrap()]).status.success());
+}
+
+#[cfg(unix)]
+#[test]
+fn status_and_sync_now_talk_to_the_running_daemon() {
+    let mut daemon = TestDaemon::new("local", free_port(), free_port(), "newest").with_http();
+    fs::create_dir_all(daemon.worlds.join("Alpha")).unwrap();
+    fs::write(daemon.worlds.join("Alpha/level.dat"), "alpha").unwrap();
+
+    // Nothing to talk to yet
+    assert!(!run(&daemon, &["status"]).status.success());
+
+    daemon.start();
+    let output = run(&daemon, &["status"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let status: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert!(status["devices"].is_array());
+
+    let output = run(&daemon, &["sync-now", "Alpha"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let output = run(&daemon, &["sync-now", "--full", "--device", "peer", "--ignore-limits"]);
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(wait_until(CONVERGE_TIMEOUT, || daemon.log().contains("Sync now requested for all 1 worlds")), "{}", daemon.log());
+    assert!(!run(&daemon, &["sync-now", "--full", "--device", "nobody"]).status.success());
+    assert!(!run(&daemon, &["sync-now"]).status.success());
+
+    // The index the daemon saves on exit matches the disk
+    assert!(wait_until(CONVERGE_TIMEOUT, || daemon.log().contains("Hashed 1 files")), "{}", daemon.log());
+    daemon.terminate();
+    let output = run(&daemon, &["verify"]);
+    assert!(output.status.success() && output.stdout.is_empty(), "{}", String::from_utf8_lossy(&output.stdout));
+    fs::write(daemon.worlds.join("Alpha/level.dat"), "alpha, changed").unwrap();
+    let output = run(&daemon, &["verify", "Alpha"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "changed   Alpha/level.dat\n");
+}
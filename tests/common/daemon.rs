@@ -0,0 +1,224 @@
+//! Runs real daemon processes against temp directories for end-to-end tests.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use mcbd_world_sync::exclusions::{Exclusions, DEFAULT_IGNORE};
+use tempfile::TempDir;
+
+pub const CONVERGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct TestDaemon {
+    pub name: String,
+    pub dir: TempDir,
+    pub worlds: PathBuf,
+    pub port: u16,
+    pub args: Vec<String>,
+    pub http_port: Option<u16>,
+    child: Option<Child>,
+}
+
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+impl TestDaemon {
+    /// Prepares a daemon directory with its own config, worlds and state
+    /// directories, peered with `peer_port`. Call `start` to launch it.
+    pub fn new(name: &str, port: u16, peer_port: u16, conflict_resolution: &str) -> Self {
+        let dir = TempDir::new().unwrap();
+        let worlds = dir.path().join("minecraftWorlds");
+        fs::create_dir_all(&worlds).unwrap();
+
+        let config = serde_json::json!({
+            "server": { "port": port, "host": "127.0.0.1" },
+            "sync": {
+                "name": name,
+                "devices": [{ "name": "peer", "address": format!("127.0.0.1:{}", peer_port) }],
+                "conflict_resolution": conflict_resolution,
+                "sync_interval": 1
+            },
+            "paths": {
+                "minecraft_worlds": worlds,
+                "state_dir": dir.path().join("state")
+            }
+        });
+        fs::write(dir.path().join("config.json"), serde_json::to_vec_pretty(&config).unwrap()).unwrap();
+
+        Self { name: name.to_string(), dir, worlds, port, args: Vec::new(), http_port: None, child: None }
+    }
+
+    /// Names the peer `peer`, the name it announces itself with.
+    pub fn with_peer_name(self, peer: &str) -> Self {
+        let path = self.dir.path().join("config.json");
+        let mut config: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        config["sync"]["devices"][0]["name"] = serde_json::json!(peer);
+        fs::write(&path, serde_json::to_vec_pretty(&config).unwrap()).unwrap();
+        self
+    }
+
+    /// Runs the daemon with the hidden transport fault injection flag.
+    pub fn with_chaos(mut self, spec: &str) -> Self {
+        self.args = vec!["--chaos".to_string(), spec.to_string()];
+        self
+    }
+
+    /// Enables the local HTTP server on a free port.
+    pub fn with_http(mut self) -> Self {
+        let path = self.dir.path().join("config.json");
+        let mut config: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        let port = free_port();
+        config["http"] = serde_json::json!({ "enabled": true, "bind": format!("127.0.0.1:{}", port) });
+        fs::write(&path, serde_json::to_vec_pretty(&config).unwrap()).unwrap();
+        self.http_port = Some(port);
+        self
+    }
+
+    /// Scans the worlds directory every second instead of watching it.
+    pub fn with_polling(self) -> Self {
+        let path = self.dir.path().join("config.json");
+        let mut config: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        config["watch"] = serde_json::json!({ "mode": "polling", "poll_interval": 1 });
+        fs::write(&path, serde_json::to_vec_pretty(&config).unwrap()).unwrap();
+        self
+    }
+
+    /// Runs as a seed whose worlds directory does not exist yet.
+    pub fn with_seed(self) -> Self {
+        let path = self.dir.path().join("config.json");
+        let mut config: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        config["paths"]["seed"] = serde_json::json!(true);
+        fs::write(&path, serde_json::to_vec_pretty(&config).unwrap()).unwrap();
+        fs::remove_dir(&self.worlds).unwrap();
+        self
+    }
+
+    /// Mirrors the worlds directory to `backup` in the daemon directory.
+    pub fn with_mirror(self) -> Self {
+        let path = self.dir.path().join("config.json");
+        let mut config: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        config["mirrors"] = serde_json::json!([{ "name": "backup", "path": self.dir.path().join("backup") }]);
+        fs::write(&path, serde_json::to_vec_pretty(&config).unwrap()).unwrap();
+        self
+    }
+
+    /// Status code and body of a GET against the daemon's HTTP server.
+    pub fn http_get(&self, path: &str) -> Option<(u16, String)> {
+        let mut stream = TcpStream::connect(("127.0.0.1", self.http_port?)).ok()?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path).ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        let status = response.split(' ').nth(1)?.parse().ok()?;
+        let body = response.split_once("\r\n\r\n")?.1.to_string();
+        Some((status, body))
+    }
+
+    pub fn start(&mut self) {
+        let log = fs::File::create(self.dir.path().join("daemon.log")).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_mcbd-world-sync"))
+            .args(&self.args)
+            .current_dir(self.dir.path())
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(log)
+            .spawn()
+            .unwrap();
+        self.child = Some(child);
+        assert!(
+            wait_until(Duration::from_secs(10), || TcpStream::connect(("127.0.0.1", self.port)).is_ok()),
+            "{} did not start listening:\n{}", self.name, self.log()
+        );
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    /// Stops the daemon the way a service manager does, so it saves its
+    /// index before exiting. Killed if it does not exit within 10 seconds.
+    #[cfg(unix)]
+    pub fn terminate(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = Command::new("kill").args(["-TERM", &child.id().to_string()]).status();
+            wait_until(Duration::from_secs(10), || child.try_wait().is_ok_and(|status| status.is_some()));
+        }
+        self.stop();
+    }
+
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.path().join("daemon.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for TestDaemon {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Two daemons peered with each other, both running.
+pub fn spawn_pair(conflict_resolution: &str) -> (TestDaemon, TestDaemon) {
+    let port_a = free_port();
+    let port_b = std::iter::repeat_with(free_port).find(|p| *p != port_a).unwrap();
+    let mut a = TestDaemon::new("a", port_a, port_b, conflict_resolution).with_peer_name("b");
+    let mut b = TestDaemon::new("b", port_b, port_a, conflict_resolution).with_peer_name("a");
+    a.start();
+    b.start();
+    // Give the watchers a moment to register before tests mutate files
+    thread::sleep(Duration::from_millis(500));
+    (a, b)
+}
+
+pub fn wait_until(timeout: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    condition()
+}
+
+/// Every file under `root` with its bytes, for byte-identical comparisons.
+/// Files ignored by default, such as LevelDB's `LOCK`, are never synced and
+/// left out.
+pub fn tree_contents(root: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+    fn walk(root: &Path, dir: &Path, ignored: &Exclusions, out: &mut BTreeMap<PathBuf, Vec<u8>>) {
+        for entry in fs::read_dir(dir).unwrap().flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                walk(root, &path, ignored, out);
+            } else if ignored.is_excluded(root, &path) {
+                continue;
+            } else if let Ok(content) = fs::read(&path) {
+                out.insert(path.strip_prefix(root).unwrap().to_path_buf(), content);
+            }
+        }
+    }
+    let ignored = Exclusions::default().with_ignore(&DEFAULT_IGNORE.iter().map(|p| p.to_string()).collect::<Vec<_>>()).unwrap();
+    let mut out = BTreeMap::new();
+    walk(root, root, &ignored, &mut out);
+    out
+}
+
+pub fn assert_converged(a: &TestDaemon, b: &TestDaemon) {
+    let converged = wait_until(CONVERGE_TIMEOUT, || tree_contents(&a.worlds) == tree_contents(&b.worlds));
+    if !converged {
+        let (left, right) = (tree_contents(&a.worlds), tree_contents(&b.worlds));
+        let only_a: Vec<_> = left.keys().filter(|k| right.get(*k) != left.get(*k)).collect();
+        let only_b: Vec<_> = right.keys().filter(|k| left.get(*k) != right.get(*k)).collect();
+        panic!(
+            "trees did not converge\ndiffering on a: {:?}\ndiffering on b: {:?}\n--- a log ---\n{}\n--- b log ---\n{}",
+            only_a, only_b, a.log(), b.log()
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Deterministic generator for fake Bedrock worlds. The same seed always
+//! produces byte-identical trees, so tests and benchmarks can run without
+//! real game data.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Small xorshift generator; good enough for filler bytes and fully reproducible.
+#[derive(Debug, Clone)]
+pub struct FixtureRng(u64);
+
+impl FixtureRng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+
+    /// Bytes that compress roughly like leveldb blocks: runs of repeated
+    /// values mixed with noise.
+    pub fn block_bytes(&mut self, len: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(len);
+        while out.len() < len {
+            let run = 1 + self.below(32) as usize;
+            let value = self.next_u64() as u8;
+            if self.below(3) == 0 {
+                out.extend(std::iter::repeat_n(value, run));
+            } else {
+                out.extend(self.bytes(run));
+            }
+        }
+        out.truncate(len);
+        out
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WorldSpec {
+    pub level_name: String,
+    pub ldb_files: usize,
+    pub ldb_size: usize,
+    pub with_packs: bool,
+}
+
+impl WorldSpec {
+    pub fn new(level_name: &str) -> Self {
+        Self {
+            level_name: level_name.to_string(),
+            ldb_files: 4,
+            ldb_size: 64 * 1024,
+            with_packs: false,
+        }
+    }
+
+    pub fn ldb(mut self, files: usize, size: usize) -> Self {
+        self.ldb_files = files;
+        self.ldb_size = size;
+        self
+    }
+
+    pub fn with_packs(mut self) -> Self {
+        self.with_packs = true;
+        self
+    }
+}
+
+/// Describes a set of worlds to generate under one `minecraftWorlds` directory.
+#[derive(Debug, Clone)]
+pub struct FixtureBuilder {
+    seed: u64,
+    worlds: Vec<WorldSpec>,
+}
+
+impl FixtureBuilder {
+    pub fn new(seed: u64) -> Self {
+        Self { seed, worlds: Vec::new() }
+    }
+
+    pub fn world(mut self, spec: WorldSpec) -> Self {
+        self.worlds.push(spec);
+        self
+    }
+
+    /// Writes the worlds into `root` and returns their folder paths.
+    pub fn build(&self, root: &Path) -> Vec<PathBuf> {
+        let mut rng = FixtureRng::new(self.seed);
+        self.worlds.iter().map(|spec| write_world(root, spec, &mut rng)).collect()
+    }
+}
+
+fn world_id(rng: &mut FixtureRng) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+-";
+    let mut id: String = (0..11).map(|_| ALPHABET[rng.below(64) as usize] as char).collect();
+    id.push('=');
+    id
+}
+
+fn write_world(root: &Path, spec: &WorldSpec, rng: &mut FixtureRng) -> PathBuf {
+    let world = root.join(world_id(rng));
+    let db = world.join("db");
+    fs::create_dir_all(&db).unwrap();
+
+    let level_dat = level_dat(&spec.level_name, rng.next_u64() as i64);
+    fs::write(world.join("level.dat"), &level_dat).unwrap();
+    fs::write(world.join("level.dat_old"), &level_dat).unwrap();
+    fs::write(world.join("levelname.txt"), &spec.level_name).unwrap();
+    fs::write(world.join("world_icon.jpeg"), rng.bytes(2048)).unwrap();
+
+    fs::write(db.join("CURRENT"), "MANIFEST-000002\n").unwrap();
+    fs::write(db.join("MANIFEST-000002"), rng.bytes(256)).unwrap();
+    fs::write(db.join("LOCK"), b"").unwrap();
+    fs::write(db.join("LOG"), "leveldb log\n").unwrap();
+    fs::write(db.join("000003.log"), rng.block_bytes(spec.ldb_size / 4)).unwrap();
+    for i in 0..spec.ldb_files {
+        fs::write(db.join(format!("{:06}.ldb", 5 + i)), rng.block_bytes(spec.ldb_size)).unwrap();
+    }
+
+    if spec.with_packs {
+        let pack = world.join("behavior_packs").join("fixture_pack");
+        fs::create_dir_all(&pack).unwrap();
+        fs::write(pack.join("manifest.json"), r#"{"format_version":2,"header":{"name":"fixture"}}"#).unwrap();
+        fs::write(world.join("world_behavior_packs.json"), "[]").unwrap();
+        fs::create_dir_all(world.join("resource_packs")).unwrap();
+        fs::write(world.join("world_resource_packs.json"), "[]").unwrap();
+    }
+    world
+}
+
+/// level.dat as Bedrock writes it: an 8-byte header (storage version and
+/// payload length, little endian) followed by a little-endian NBT compound.
+pub fn level_dat(level_name: &str, seed: i64) -> Vec<u8> {
+    let mut nbt = Vec::new();
+    nbt.push(10u8);
+    put_name(&mut nbt, "");
+    put_string_tag(&mut nbt, "LevelName", level_name);
+    put_tag_header(&mut nbt, 4, "RandomSeed");
+    nbt.extend(seed.to_le_bytes());
+    put_tag_header(&mut nbt, 4, "LastPlayed");
+    nbt.extend(1_700_000_000i64.to_le_bytes());
+    for (name, value) in [("SpawnX", 0i32), ("SpawnY", 64), ("SpawnZ", 0)] {
+        put_tag_header(&mut nbt, 3, name);
+        nbt.extend(value.to_le_bytes());
+    }
+    nbt.push(0);
+
+    let mut out = Vec::with_capacity(nbt.len() + 8);
+    out.extend(10u32.to_le_bytes());
+    out.extend((nbt.len() as u32).to_le_bytes());
+    out.extend(nbt);
+    out
+}
+
+fn put_name(out: &mut Vec<u8>, name: &str) {
+    out.extend((name.len() as u16).to_le_bytes());
+    out.extend(name.as_bytes());
+}
+
+fn put_tag_header(out: &mut Vec<u8>, tag: u8, name: &str) {
+    out.push(tag);
+    put_name(out, name);
+}
+
+fn put_string_tag(out: &mut Vec<u8>, name: &str, value: &str) {
+    put_tag_header(out, 8, name);
+    put_name(out, value);
+}
+
+/// Edits that mimic what the game does to a world between syncs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mutation {
+    /// Append to the write-ahead log, as every autosave does.
+    AppendLog,
+    /// Overwrite a slice in the middle of an existing table file.
+    RewriteLdbRange,
+    /// Compaction output: a brand-new table file.
+    NewLdb,
+    /// Compaction input removed.
+    DeleteLdb,
+    /// Game settings or playtime updated.
+    TouchLevelDat,
+}
+
+/// Applies a mutation to a generated world, deterministically for a given rng state.
+pub fn mutate(world: &Path, mutation: Mutation, rng: &mut FixtureRng) {
+    let db = world.join("db");
+    let mut tables: Vec<PathBuf> = fs::read_dir(&db)
+        .unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.extension().is_some_and(|e| e == "ldb"))
+        .collect();
+    tables.sort();
+
+    match mutation {
+        Mutation::AppendLog => {
+            let log = db.join("000003.log");
+            let mut content = fs::read(&log).unwrap_or_default();
+            content.extend(rng.block_bytes(4096));
+            fs::write(log, content).unwrap();
+        }
+        Mutation::RewriteLdbRange => {
+            if let Some(table) = tables.first() {
+                let mut content = fs::read(table).unwrap();
+                let len = content.len().min(4096);
+                let start = rng.below((content.len() - len + 1) as u64) as usize;
+                content[start..start + len].copy_from_slice(&rng.bytes(len));
+                fs::write(table, content).unwrap();
+            }
+        }
+        Mutation::NewLdb => {
+            let next = tables.len() + 5 + rng.below(1000) as usize;
+            fs::write(db.join(format!("{:06}.ldb", next)), rng.block_bytes(16 * 1024)).unwrap();
+        }
+        Mutation::DeleteLdb => {
+            if let Some(table) = tables.last() {
+                fs::remove_file(table).unwrap();
+            }
+        }
+        Mutation::TouchLevelDat => {
+            let name = fs::read_to_string(world.join("levelname.txt")).unwrap_or_default();
+            fs::write(world.join("level.dat"), level_dat(&name, rng.next_u64() as i64)).unwrap();
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! The local HTTP server started in-process, and plain HTTP/1.1 requests to it.
+
+use super::daemon::free_port;
+use mcbd_world_sync::aging::DeviceAging;
+use mcbd_world_sync::busy::BusyWorlds;
+use mcbd_world_sync::gaming::GamingMode;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::http::{self, HttpState};
+use mcbd_world_sync::manifest::ManifestCache;
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::port_mapping::Mappings;
+use mcbd_world_sync::transfer_queue::TransferQueue;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// State with every optional route off, keeping its cursors under `dir`.
+pub fn state(dir: &Path, metrics: Arc<Metrics>) -> HttpState {
+    let cursors = ManifestCache::load(dir.join("cursors.json"));
+    HttpState {
+        metrics: metrics.clone(),
+        health: Arc::new(Health::new()),
+        sync_now: tokio::sync::mpsc::unbounded_channel().0,
+        archived: tokio::sync::mpsc::unbounded_channel().0,
+        cursors: cursors.clone(),
+        aging: DeviceAging::new(Vec::new(), cursors, 30),
+        queue: Arc::new(TransferQueue::new(metrics)),
+        downloads: None,
+        uploads: None,
+        snapshots: None,
+        port_mappings: Mappings::default(),
+        index: None,
+        gaming: GamingMode::default(),
+        busy: BusyWorlds::default(),
+    }
+}
+
+/// Serves the HTTP routes with `state` and returns the port.
+pub async fn serve(state: HttpState) -> u16 {
+    let port = free_port();
+    tokio::spawn(async move { http::serve(&format!("127.0.0.1:{}", port), state).await.unwrap() });
+    while TcpStream::connect(("127.0.0.1", port)).await.is_err() {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    port
+}
+
+/// Returns the status and body of the response.
+pub async fn request(port: u16, method: &str, path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let head = format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", method, path, body.len());
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(body).await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    let head_end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8_lossy(&response[..head_end]).to_string();
+    (head.split(' ').nth(1).unwrap().parse().unwrap(), response[head_end + 4..].to_vec())
+}
+
+pub async fn get(port: u16, path: &str) -> (u16, Vec<u8>) {
+    request(port, "GET", path, &[]).await
+}
@@ -0,0 +1,100 @@
+//! LevelDB files written the way Minecraft writes them, for reading back.
+
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use mcbd_world_sync::leveldb::checksum;
+use std::io::Write;
+
+pub fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// A block without key sharing and a single restart point.
+pub fn block(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (key, value) in entries {
+        varint(&mut out, 0);
+        varint(&mut out, key.len() as u64);
+        varint(&mut out, value.len() as u64);
+        out.extend_from_slice(key);
+        out.extend_from_slice(value);
+    }
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out
+}
+
+/// Appends `contents` as a block compressed with `compression` and returns
+/// its handle.
+pub fn append_block(file: &mut Vec<u8>, contents: &[u8], compression: u8) -> Vec<u8> {
+    let stored = match compression {
+        4 => {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(contents).unwrap();
+            encoder.finish().unwrap()
+        }
+        _ => contents.to_vec(),
+    };
+    let mut handle = Vec::new();
+    varint(&mut handle, file.len() as u64);
+    varint(&mut handle, stored.len() as u64);
+    let start = file.len();
+    file.extend_from_slice(&stored);
+    file.push(compression);
+    let crc = checksum(&file[start..]);
+    file.extend_from_slice(&crc.to_le_bytes());
+    handle
+}
+
+pub fn internal_key(key: &[u8], seq: u64, written: bool) -> Vec<u8> {
+    let mut internal = key.to_vec();
+    internal.extend_from_slice(&((seq << 8) | written as u64).to_le_bytes());
+    internal
+}
+
+pub fn table(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+    let mut file = Vec::new();
+    let data = append_block(&mut file, &block(entries), 4);
+    let meta = append_block(&mut file, &block(&[]), 0);
+    let index = append_block(&mut file, &block(&[(entries.last().unwrap().0.clone(), data)]), 0);
+    let mut footer = [meta, index].concat();
+    footer.resize(40, 0);
+    footer.extend_from_slice(&0xdb47_7524_8b80_fb57u64.to_le_bytes());
+    file.extend_from_slice(&footer);
+    file
+}
+
+/// A log holding one batch, written as a single record.
+pub fn log(seq: u64, writes: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+    let mut batch = seq.to_le_bytes().to_vec();
+    batch.extend_from_slice(&(writes.len() as u32).to_le_bytes());
+    for (key, value) in writes {
+        batch.push(value.is_some() as u8);
+        varint(&mut batch, key.len() as u64);
+        batch.extend_from_slice(key);
+        if let Some(value) = value {
+            varint(&mut batch, value.len() as u64);
+            batch.extend_from_slice(value);
+        }
+    }
+    let mut typed = vec![1];
+    typed.extend_from_slice(&batch);
+    let mut record = checksum(&typed).to_le_bytes().to_vec();
+    record.extend_from_slice(&(batch.len() as u16).to_le_bytes());
+    record.extend_from_slice(&typed);
+    record
+}
+
+pub fn chunk_key(x: i32, z: i32, dimension: Option<i32>, tag: u8, sub_chunk: Option<u8>) -> Vec<u8> {
+    let mut key = [x.to_le_bytes(), z.to_le_bytes()].concat();
+    if let Some(dimension) = dimension {
+        key.extend_from_slice(&dimension.to_le_bytes());
+    }
+    key.push(tag);
+    key.extend(sub_chunk);
+    key
+}
@@ -0,0 +1,7 @@
+#![allow(dead_code)]
+
+pub mod daemon;
+pub mod fixtures;
+pub mod http;
+pub mod leveldb;
+pub mod receiver;
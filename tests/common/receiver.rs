@@ -0,0 +1,39 @@
+//! Sync servers started in-process for tests that send files to them.
+
+use super::daemon::free_port;
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::SyncServer;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Writes a small world named `name` under `worlds`.
+pub fn world(worlds: &Path, name: &str) {
+    fs::create_dir_all(worlds.join(name).join("db")).unwrap();
+    fs::write(worlds.join(name).join("level.dat"), name).unwrap();
+    fs::write(worlds.join(name).join("db/000005.ldb"), [5u8; 64]).unwrap();
+}
+
+/// A file manager over `worlds`, with nothing excluded and already scanned.
+pub fn files(worlds: PathBuf) -> FileManager {
+    let mut files = FileManager::new(worlds).with_exclusions(Exclusions::new(&[]));
+    files.scan_directory().unwrap();
+    files
+}
+
+/// Starts a server receiving into `files`, set up further by `setup`, and
+/// returns its address once it is listening.
+pub async fn start_receiver(files: Arc<Mutex<FileManager>>, setup: impl FnOnce(SyncServer) -> SyncServer) -> String {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = setup(SyncServer::new(port).with_health(health.clone()).with_file_manager(files));
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    format!("127.0.0.1:{}", port)
+}
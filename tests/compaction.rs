@@ -0,0 +1,125 @@
+//! Tombstones for deleted files and how compaction expires them.
+
+use mcbd_world_sync::compaction::{self, Compaction};
+use mcbd_world_sync::config::IndexConfig;
+use mcbd_world_sync::file_manager::{FileInfo, FileManager, Tombstone};
+use mcbd_world_sync::index;
+use mcbd_world_sync::manifest::{Manifest, ManifestCache, SyncCursor};
+use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn tombstone(path: &str, age: Duration, now: SystemTime) -> Tombstone {
+    Tombstone { path: PathBuf::from(path), hash: "h".to_string(), deleted_at: now - age }
+}
+
+fn cursor(acknowledged_at: SystemTime) -> SyncCursor {
+    SyncCursor { generation: 1, acknowledged_at, manifest: Manifest::new(BTreeMap::new()), peer_retention_days: None, needs_reconcile: false }
+}
+
+fn config(max_tombstones: usize) -> IndexConfig {
+    IndexConfig { compact_interval: 60, tombstone_retention_days: 30, max_tombstones, ..IndexConfig::default() }
+}
+
+#[test]
+fn deleted_files_leave_tombstones_until_they_come_back() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut file_manager = FileManager::new(dir.path().to_path_buf());
+    for path in ["w/level.dat", "w/db/1.ldb", "other/level.dat"] {
+        let path = PathBuf::from(path);
+        file_manager.update_file_info(path.clone(), FileInfo { path, last_modified: SystemTime::now(), size: 1, hash: "x".to_string() });
+    }
+
+    assert_eq!(file_manager.mark_deleted(&PathBuf::from("w")), 2);
+    assert_eq!(file_manager.entries().len(), 1);
+    assert_eq!(file_manager.tombstones().len(), 2);
+
+    let path = PathBuf::from("w/level.dat");
+    file_manager.update_file_info(path.clone(), FileInfo { path, last_modified: SystemTime::now(), size: 1, hash: "y".to_string() });
+    let remaining: Vec<_> = file_manager.tombstones().into_iter().map(|t| t.path).collect();
+    assert_eq!(remaining, vec![PathBuf::from("w/db/1.ldb")]);
+
+    // Tombstones are saved with the index
+    let index_file = dir.path().join("index.json");
+    index::save(&index_file, dir.path(), file_manager.generation(), file_manager.entries(), file_manager.tombstones()).unwrap();
+    assert_eq!(index::load(&index_file).unwrap().unwrap().tombstones.len(), 1);
+}
+
+#[test]
+fn expired_tombstones_wait_for_every_peer() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let now = SystemTime::now();
+    let mut file_manager = FileManager::new(dir.path().to_path_buf());
+    file_manager.load_tombstones(vec![
+        tombstone("w/old.ldb", 40 * DAY, now),
+        tombstone("w/recent.ldb", DAY, now),
+    ]);
+    let peers = vec!["laptop".to_string(), "nas".to_string()];
+
+    // The NAS was never seen and may still have the file
+    let mut cursors = HashMap::from([("laptop".to_string(), cursor(now))]);
+    assert_eq!(compaction::compact(&mut file_manager, &cursors, &peers, &config(100), now), Compaction::default());
+
+    cursors.insert("nas".to_string(), cursor(now));
+    assert_eq!(compaction::compact(&mut file_manager, &cursors, &peers, &config(100), now), Compaction { expired: 1, evicted: 0 });
+    let remaining: Vec<_> = file_manager.tombstones().into_iter().map(|t| t.path).collect();
+    assert_eq!(remaining, vec![PathBuf::from("w/recent.ldb")]);
+}
+
+#[test]
+fn oldest_tombstones_are_evicted_over_the_limit() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let now = SystemTime::now();
+    let mut file_manager = FileManager::new(dir.path().to_path_buf());
+    file_manager.load_tombstones((1..=5).map(|days| tombstone(&format!("w/{days}.ldb"), days * DAY, now)).collect());
+
+    let compacted = compaction::compact(&mut file_manager, &HashMap::new(), &["nas".to_string()], &config(3), now);
+    assert_eq!(compacted, Compaction { expired: 0, evicted: 2 });
+    let mut remaining: Vec<_> = file_manager.tombstones().into_iter().map(|t| t.path).collect();
+    remaining.sort();
+    assert_eq!(remaining, vec![PathBuf::from("w/1.ldb"), PathBuf::from("w/2.ldb"), PathBuf::from("w/3.ldb")]);
+}
+
+#[test]
+fn retention_is_the_longer_of_both_sides() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let now = SystemTime::now();
+    let mut file_manager = FileManager::new(dir.path().to_path_buf());
+    file_manager.load_tombstones(vec![tombstone("w/old.ldb", 40 * DAY, now)]);
+    let cursors = HashMap::from([("nas".to_string(), SyncCursor { peer_retention_days: Some(60), ..cursor(now) })]);
+
+    let compacted = compaction::compact(&mut file_manager, &cursors, &["nas".to_string()], &config(100), now);
+    assert_eq!(compacted, Compaction::default());
+    assert_eq!(compaction::negotiated_retention(cursors.get("nas"), &config(100)), 60 * DAY);
+}
+
+#[tokio::test]
+async fn peers_offline_past_the_retention_need_a_reconcile() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let now = SystemTime::now();
+    let mut file_manager = FileManager::new(dir.path().to_path_buf());
+    file_manager.load_tombstones(vec![tombstone("w/old.ldb", 40 * DAY, now)]);
+    let peers = vec!["laptop".to_string(), "nas".to_string()];
+    let cursors = HashMap::from([
+        ("laptop".to_string(), cursor(now)),
+        ("nas".to_string(), cursor(now - 50 * DAY)),
+    ]);
+
+    assert_eq!(compaction::lapsed_peers(&cursors, &peers, &config(100), now), vec!["nas".to_string()]);
+    // The deletion is not held back for the lapsed peer
+    assert_eq!(compaction::compact(&mut file_manager, &cursors, &peers, &config(100), now), Compaction { expired: 1, evicted: 0 });
+
+    let cache = ManifestCache::new();
+    cache.acknowledge("nas", Arc::new(Manifest::new(BTreeMap::new())), None).await;
+    assert!(cache.require_reconcile("nas").await);
+    assert!(!cache.require_reconcile("nas").await);
+    assert!(cache.needs_reconcile("nas").await);
+    assert!(compaction::lapsed_peers(&cache.cursors().await, &peers, &config(100), now + 50 * DAY).is_empty());
+
+    assert!(cache.reconcile("nas").await);
+    assert!(!cache.needs_reconcile("nas").await);
+    assert!(!cache.reconcile("nas").await);
+}
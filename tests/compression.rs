@@ -0,0 +1,130 @@
+//! Per-file compression decisions, compressed bulk frames and the codec
+//! peers agree on.
+
+mod common;
+
+use common::fixtures::FixtureRng;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use common::daemon::free_port;
+use mcbd_world_sync::compression::{self, entropy, should_compress, Codec};
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::mux::{self, Channel, Reassembler};
+use mcbd_world_sync::network::{SyncClient, SyncMessage, SyncServer};
+use mcbd_world_sync::transfer_queue::Priority;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::bytes::{Bytes, BytesMut};
+
+fn manifest_json() -> Vec<u8> {
+    r#"{"format_version": 2, "header": {"name": "pack", "uuid": "00000000-0000-0000-0000-000000000000"}}"#.repeat(40).into_bytes()
+}
+
+#[test]
+fn decision_follows_extension_and_entropy() {
+    let mut rng = FixtureRng::new(7);
+    assert!(should_compress(Path::new("world/behavior_packs/manifest.json"), &manifest_json()));
+    // Already compressed tables are skipped by extension, whatever they contain
+    assert!(!should_compress(Path::new("world/db/000012.LDB"), &manifest_json()));
+    // Random-looking content is skipped by its entropy sample
+    assert!(!should_compress(Path::new("world/db/000013.log"), &rng.bytes(16 * 1024)));
+    assert!(!should_compress(Path::new("world/levelname.txt"), b"My World"));
+
+    assert_eq!(entropy(&[0; 1024]), 0.0);
+    assert!(entropy(&rng.bytes(4096)) > 7.9);
+}
+
+#[test]
+fn file_content_messages_use_the_decision() {
+    let message = |path: &str, content: Vec<u8>| SyncMessage::FileContent { path: PathBuf::from(path), content, group: None, modified: None, version: None, correlation_id: None };
+    assert!(message("world/world_resource_packs.json", manifest_json()).worth_compressing());
+    assert!(!message("world/db/000005.ldb", manifest_json()).worth_compressing());
+    assert!(!SyncMessage::SyncRequest { correlation_id: None }.worth_compressing());
+}
+
+#[tokio::test]
+async fn compressed_bulk_is_flagged_and_restored() {
+    let (tx, rx) = mpsc::unbounded();
+    let sender = mux::spawn_writer(tx, None);
+    let payload = Bytes::from(manifest_json().repeat(200));
+    let compressed = sender.send_compressed(Priority::Background, payload.clone()).await.unwrap();
+    drop(sender);
+
+    let frames: Vec<Bytes> = rx.collect().await;
+    let sent: usize = frames.iter().map(|f| f.len()).sum();
+    assert!(sent < payload.len() / 4, "sent {} bytes for {}", sent, payload.len());
+    assert!(compressed < sent, "compressed to {} bytes, {} with frame headers", compressed, sent);
+
+    let mut reassembler = Reassembler::new();
+    let messages: Vec<_> = frames
+        .into_iter()
+        .filter_map(|frame| reassembler.push(BytesMut::from(&frame[..])).unwrap())
+        .collect();
+    assert_eq!(messages, vec![(Channel::Bulk, payload)]);
+}
+
+#[tokio::test]
+async fn zstd_bulk_is_flagged_and_restored() {
+    let (tx, rx) = mpsc::unbounded();
+    let sender = mux::spawn_writer(tx, None);
+    sender.set_codec(Codec::Zstd);
+    let payload = Bytes::from(manifest_json().repeat(200));
+    let compressed = sender.send_compressed(Priority::Background, payload.clone()).await.unwrap();
+    drop(sender);
+
+    let frames: Vec<Bytes> = rx.collect().await;
+    let sent: usize = frames.iter().map(|f| f.len()).sum();
+    assert!(sent < payload.len() / 4, "sent {} bytes for {}", sent, payload.len());
+    assert!(compressed < sent, "compressed to {} bytes, {} with frame headers", compressed, sent);
+
+    let mut reassembler = Reassembler::new();
+    let messages: Vec<_> = frames
+        .into_iter()
+        .filter_map(|frame| reassembler.push(BytesMut::from(&frame[..])).unwrap())
+        .collect();
+    assert_eq!(messages, vec![(Channel::Bulk, payload)]);
+}
+
+#[test]
+fn oversized_lz4_headers_are_refused() {
+    let mut payload = compression::compress(&manifest_json()).to_vec();
+    assert_eq!(compression::decompress(&payload).unwrap(), manifest_json());
+    // A few bytes that claim to unpack to 4 GiB
+    payload[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+    let error = compression::decompress(&payload).unwrap_err();
+    assert!(error.to_string().contains("claims to unpack"), "{}", error);
+}
+
+#[test]
+fn zstd_is_only_used_when_both_sides_want_it() {
+    assert_eq!(Codec::Zstd.negotiate(&Codec::Zstd.accepted()), Codec::Zstd);
+    assert_eq!(Codec::Zstd.negotiate(&Codec::Lz4.accepted()), Codec::Lz4);
+    assert_eq!(Codec::Lz4.negotiate(&Codec::Zstd.accepted()), Codec::Lz4);
+    // A peer that never answers is never sent anything but lz4
+    assert_eq!(Codec::Zstd.negotiate(&[]), Codec::Lz4);
+}
+
+async fn negotiated(server_codec: Codec) -> Codec {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(port).with_health(health.clone()).with_codec(server_codec);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let session = SyncClient::new(format!("127.0.0.1:{}", port)).session().await.unwrap();
+    for _ in 0..50 {
+        if session.codec() == Codec::Zstd {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    session.codec()
+}
+
+#[tokio::test]
+async fn sessions_negotiate_the_codec_when_they_open() {
+    assert_eq!(negotiated(Codec::Zstd).await, Codec::Zstd);
+    assert_eq!(negotiated(Codec::Lz4).await, Codec::Lz4);
+}
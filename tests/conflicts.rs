@@ -0,0 +1,180 @@
+//! Changes that arrive for a world changed here too, and how each conflict
+//! resolution settles them.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::conflicts::{self, ConflictPolicy, ConflictResolution, Resolution};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::{FileInfo, FileManager};
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::network::{FileVersion, SyncClient, SyncServer};
+use mcbd_world_sync::transfer_queue::{Priority, TransferQueue};
+use std::fs;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+#[test]
+fn copies_are_named_after_the_device_and_day() {
+    let at = UNIX_EPOCH + Duration::from_secs(1_717_243_200);
+    assert_eq!(conflicts::copy_name("MyWorld", "LAPTOP", at), "MyWorld (conflict from LAPTOP 2024-06-01)");
+    assert_eq!(conflicts::date(UNIX_EPOCH), "1970-01-01");
+    assert_eq!(conflicts::date(UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29");
+
+    assert_eq!("keep_both".parse::<ConflictResolution>().unwrap(), ConflictResolution::KeepBoth);
+    assert_eq!("newest".parse::<ConflictResolution>().unwrap(), ConflictResolution::Newest);
+    assert_eq!("prefer:laptop".parse::<ConflictResolution>().unwrap(), ConflictResolution::PreferDevice("laptop".to_string()));
+    assert!("oldest".parse::<ConflictResolution>().is_err());
+    assert!("prefer:".parse::<ConflictResolution>().is_err());
+}
+
+fn version(size: u64, modified: u64, hash: &str) -> FileInfo {
+    FileInfo { path: PathBuf::from("World/level.dat"), last_modified: UNIX_EPOCH + Duration::from_secs(modified), size, hash: hash.to_string() }
+}
+
+#[test]
+fn strategies_pick_the_same_version_on_both_devices() {
+    let (older_larger, newer_smaller) = (version(200, 100, "a"), version(100, 200, "b"));
+    let wins = |resolution: &ConflictResolution, ours: &FileInfo, theirs: &FileInfo| {
+        let here = resolution.theirs_wins(Some(ours), Some(theirs), "laptop", "desktop");
+        // The laptop decides the other way round, and must agree
+        assert_ne!(here, resolution.theirs_wins(Some(theirs), Some(ours), "desktop", "laptop"));
+        here
+    };
+    assert!(wins(&ConflictResolution::Newest, &older_larger, &newer_smaller));
+    assert!(!wins(&ConflictResolution::Largest, &older_larger, &newer_smaller));
+    assert!(wins(&ConflictResolution::PreferDevice("laptop".to_string()), &newer_smaller, &older_larger));
+    assert!(!wins(&ConflictResolution::PreferDevice("desktop".to_string()), &older_larger, &newer_smaller));
+    // Ties are broken by size, then hash
+    assert!(wins(&ConflictResolution::Newest, &version(1, 5, "a"), &version(1, 5, "b")));
+    // A deletion loses
+    assert!(!ConflictResolution::Newest.theirs_wins(Some(&older_larger), None, "laptop", "desktop"));
+    assert!(ConflictResolution::Largest.theirs_wins(None, Some(&older_larger), "laptop", "desktop"));
+
+    let overrides = BTreeMap::from([("Survival".to_string(), "manual".to_string())]);
+    let policy = ConflictPolicy::parse("largest", &overrides).unwrap();
+    assert_eq!(policy.for_world("Survival"), &ConflictResolution::Manual);
+    assert_eq!(policy.for_world("Creative"), &ConflictResolution::Largest);
+    let overrides = BTreeMap::from([("Survival".to_string(), "oldest".to_string())]);
+    assert!(ConflictPolicy::parse("newest", &overrides).unwrap_err().to_string().contains("Survival"));
+}
+
+#[test]
+fn manual_resolution_diverts_changes_into_a_copy() {
+    let dir = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join("Survival/db")).unwrap();
+    fs::write(dir.path().join("Survival/level.dat"), b"edited here").unwrap();
+    let policy = ConflictPolicy::new(ConflictResolution::Newest).with_world("Survival".to_string(), ConflictResolution::Manual);
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[])).with_conflicts(policy, "desktop".to_string());
+    files.scan_directory().unwrap();
+
+    let theirs = version(20, 0, "x");
+    let Resolution::Divert(copy) = files.resolve_conflict(Path::new("Survival/level.dat"), "laptop", Some(&theirs), true).unwrap() else {
+        panic!("not diverted");
+    };
+    let name = conflicts::copy_name("Survival", "laptop", SystemTime::now());
+    assert_eq!(copy, Path::new(&name).join("level.dat"));
+    files.receive_file(&copy, b"edited on the laptop").unwrap();
+    // The rest of the burst goes into the same copy
+    assert_eq!(
+        files.resolve_conflict(Path::new("Survival/db/CURRENT"), "laptop", Some(&theirs), false).unwrap(),
+        Resolution::Divert(Path::new(&name).join("db/CURRENT"))
+    );
+    assert_eq!(fs::read(dir.path().join("Survival/level.dat")).unwrap(), b"edited here");
+    assert_eq!(fs::read(dir.path().join(&name).join("level.dat")).unwrap(), b"edited on the laptop");
+}
+
+#[test]
+fn keep_both_copies_the_version_that_loses_on_either_device() {
+    let dir = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join("Survival/db")).unwrap();
+    fs::write(dir.path().join("Survival/levelname.txt"), b"edited here").unwrap();
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]))
+        .with_conflicts(ConflictPolicy::new(ConflictResolution::KeepBoth), "desktop".to_string());
+    files.scan_directory().unwrap();
+
+    // The laptop's version is older: it goes to a copy named after the laptop, as the laptop copies it too
+    let path = Path::new("Survival/levelname.txt");
+    let older = FileInfo { path: path.to_path_buf(), last_modified: UNIX_EPOCH, size: 20, hash: "x".to_string() };
+    let name = conflicts::copy_name("Survival", "laptop", SystemTime::now());
+    assert_eq!(files.resolve_conflict(path, "laptop", Some(&older), true).unwrap(), Resolution::Divert(Path::new(&name).join("levelname.txt")));
+    assert!(dir.path().join(&name).is_dir());
+    // Copies are not copied again
+    files.scan_directory().unwrap();
+    let copied = Path::new(&name).join("levelname.txt");
+    assert_eq!(files.resolve_conflict(&copied, "laptop", Some(&older), true).unwrap(), Resolution::Keep);
+    let newer = FileInfo { last_modified: SystemTime::now() + Duration::from_secs(60), ..older };
+    assert_eq!(files.resolve_conflict(&copied, "laptop", Some(&newer), true).unwrap(), Resolution::Take);
+    let worlds = fs::read_dir(dir.path()).unwrap().count();
+    assert_eq!(worlds, 2);
+}
+
+#[tokio::test]
+async fn the_winning_version_of_a_file_changed_on_both_devices_is_kept() {
+    let dir = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join("Skyblock/db")).unwrap();
+    fs::write(dir.path().join("Skyblock/level.dat"), b"edited here, later").unwrap();
+    fs::write(dir.path().join("Skyblock/levelname.txt"), b"Skyblock").unwrap();
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    files.scan_directory().unwrap();
+    let queue = Arc::new(TransferQueue::new(Arc::new(Metrics::new())));
+    queue.push("laptop".to_string(), PathBuf::from("Skyblock/level.dat"), "Modify".to_string()).await;
+
+    let (port, health) = (free_port(), Arc::new(Health::new()));
+    let server = SyncServer::new(port).with_health(health.clone()).with_file_manager(Arc::new(Mutex::new(files))).with_conflict_detection(queue);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let client = SyncClient::new(format!("127.0.0.1:{}", port)).with_device_name("laptop".to_string());
+    let earlier = FileVersion { modified: Some(SystemTime::now() - Duration::from_secs(3600)), vector: None };
+    client.send_file_version(PathBuf::from("Skyblock/level.dat"), b"edited on the laptop".to_vec(), earlier.clone(), None, Priority::Background).await.unwrap();
+    // Files this device did not change too are taken
+    client.send_file_version(PathBuf::from("Skyblock/levelname.txt"), b"Renamed".to_vec(), earlier, None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(dir.path().join("Skyblock/level.dat")).unwrap(), b"edited here, later");
+    assert_eq!(fs::read(dir.path().join("Skyblock/levelname.txt")).unwrap(), b"Renamed");
+}
+
+#[tokio::test]
+async fn the_overwritten_version_is_kept_as_a_copy() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for world in ["Skyblock", "Farm"] {
+        fs::create_dir_all(dir.path().join(world).join("db")).unwrap();
+        fs::write(dir.path().join(world).join("level.dat"), b"edited here").unwrap();
+        fs::write(dir.path().join(world).join("levelname.txt"), world).unwrap();
+    }
+    let policy = ConflictPolicy::new(ConflictResolution::KeepBoth);
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[])).with_conflicts(policy, "DESKTOP".to_string());
+    files.scan_directory().unwrap();
+    // The edit to Skyblock has not reached the laptop yet
+    let queue = Arc::new(TransferQueue::new(Arc::new(Metrics::new())));
+    queue.push("laptop".to_string(), PathBuf::from("Skyblock/level.dat"), "Modify".to_string()).await;
+
+    let (port, health) = (free_port(), Arc::new(Health::new()));
+    let server = SyncServer::new(port).with_health(health.clone()).with_device_name("DESKTOP".to_string())
+        .with_file_manager(Arc::new(Mutex::new(files))).with_conflict_detection(queue);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let client = SyncClient::new(format!("127.0.0.1:{}", port)).with_device_name("laptop".to_string());
+    client.send_file_content(PathBuf::from("Skyblock/level.dat"), b"edited on the laptop".to_vec(), None, Priority::Background).await.unwrap();
+    client.send_file_content(PathBuf::from("Skyblock/db/CURRENT"), b"MANIFEST-000002".to_vec(), None, Priority::Background).await.unwrap();
+    client.send_file_content(PathBuf::from("Farm/level.dat"), b"edited on the laptop".to_vec(), None, Priority::Background).await.unwrap();
+
+    let copy = dir.path().join(conflicts::copy_name("Skyblock", "DESKTOP", SystemTime::now()));
+    assert_eq!(fs::read(copy.join("level.dat")).unwrap(), b"edited here");
+    assert!(fs::read_to_string(copy.join("levelname.txt")).unwrap().starts_with("Skyblock (conflict from DESKTOP "));
+    assert!(!copy.join("db/CURRENT").exists());
+    assert_eq!(fs::read(dir.path().join("Skyblock/level.dat")).unwrap(), b"edited on the laptop");
+    // One copy per burst, and none of worlds whose changes were all delivered
+    let worlds: Vec<_> = fs::read_dir(dir.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+    assert_eq!(worlds.len(), 3, "{:?}", worlds);
+    assert_eq!(fs::read(dir.path().join("Farm/level.dat")).unwrap(), b"edited on the laptop");
+}
@@ -0,0 +1,117 @@
+//! Messages to a peer sharing one long-lived connection.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::connections::Connections;
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::{SyncClient, SyncServer};
+use mcbd_world_sync::transfer_queue::Priority;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+/// Forwards connections to `target`, keeping them so a test can count and
+/// cut them.
+async fn forwarder(target: String) -> (String, Arc<Mutex<Vec<JoinHandle<()>>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let connections = Arc::new(Mutex::new(Vec::new()));
+    let accepted = connections.clone();
+    tokio::spawn(async move {
+        while let Ok((mut incoming, _)) = listener.accept().await {
+            let target = target.clone();
+            accepted.lock().await.push(tokio::spawn(async move {
+                let mut outgoing = TcpStream::connect(target).await.unwrap();
+                let _ = tokio::io::copy_bidirectional(&mut incoming, &mut outgoing).await;
+            }));
+        }
+    });
+    (address, connections)
+}
+
+#[tokio::test]
+async fn changes_to_a_peer_share_one_connection_until_it_drops() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let files = Arc::new(Mutex::new(FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]))));
+    let server = SyncServer::new(port).with_health(health.clone()).with_file_manager(files);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let (address, forwarded) = forwarder(format!("127.0.0.1:{}", port)).await;
+
+    let connections = Connections::new();
+    let connect = || SyncClient::new(address.clone()).with_connections(connections.clone());
+    for n in 0..5u8 {
+        let path = PathBuf::from(format!("World/db/00000{}.ldb", n));
+        connect().send_file_content(path, vec![n; 100 * 1024], None, Priority::Background).await.unwrap();
+        connect().send_file_change(PathBuf::from("World/db/LOCK"), "Remove".to_string()).await.unwrap();
+    }
+    assert_eq!(fs::read(dir.path().join("World/db/000004.ldb")).unwrap(), vec![4; 100 * 1024]);
+    assert_eq!(forwarded.lock().await.len(), 1);
+
+    // Once the connection is lost, the next message opens a new one
+    forwarded.lock().await[0].abort();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    connect().send_file_content(PathBuf::from("World/level.dat"), b"level".to_vec(), None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(dir.path().join("World/level.dat")).unwrap(), b"level");
+    assert_eq!(forwarded.lock().await.len(), 2);
+}
+
+#[tokio::test]
+async fn each_transfer_stream_has_its_own_connection() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let files = Arc::new(Mutex::new(FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]))));
+    let server = SyncServer::new(port).with_health(health.clone()).with_file_manager(files);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let (address, forwarded) = forwarder(format!("127.0.0.1:{}", port)).await;
+
+    let connections = Connections::new().with_streams(2);
+    let client = SyncClient::new(address.clone()).with_connections(connections.clone());
+    let first = connections.session(&client).await.unwrap();
+    let second = connections.session(&client).await.unwrap();
+    drop((first, second));
+    assert_eq!(forwarded.lock().await.len(), 2);
+
+    // Both stay open for the streams that follow
+    for n in 0..4u8 {
+        client.send_file_content(PathBuf::from(format!("World/db/00000{}.ldb", n)), vec![n; 1024], None, Priority::Background).await.unwrap();
+    }
+    assert_eq!(forwarded.lock().await.len(), 2);
+}
+
+#[tokio::test]
+async fn forgotten_sessions_are_not_reused() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let files = Arc::new(Mutex::new(FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]))));
+    let server = SyncServer::new(port).with_health(health.clone()).with_file_manager(files);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let (address, forwarded) = forwarder(format!("127.0.0.1:{}", port)).await;
+
+    let connections = Connections::new();
+    let client = SyncClient::new(address.clone()).with_connections(connections.clone());
+    client.send_file_content(PathBuf::from("World/level.dat"), b"old".to_vec(), None, Priority::Background).await.unwrap();
+    connections.forget().await;
+    client.send_file_content(PathBuf::from("World/level.dat"), b"new".to_vec(), None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(dir.path().join("World/level.dat")).unwrap(), b"new");
+    assert_eq!(forwarded.lock().await.len(), 2);
+}
@@ -0,0 +1,73 @@
+//! The CPU budget that paces hashing and compression.
+
+use mcbd_world_sync::cpu_budget::CpuBudget;
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use std::fs;
+use std::time::{Duration, Instant};
+
+fn between(wait: Duration, min: u64, max: u64) -> bool {
+    wait >= Duration::from_millis(min) && wait <= Duration::from_millis(max)
+}
+
+#[test]
+fn work_is_followed_by_a_wait_that_keeps_the_average_within_the_budget() {
+    // A quarter of one core: 100ms of work takes 400ms
+    let budget = CpuBudget::for_cores(Some(25), 1);
+    assert!(budget.is_limited());
+    let wait = budget.charge(Duration::from_millis(100));
+    assert!(between(wait, 290, 300), "{:?}", wait);
+    // The next piece waits for the first one's turn to end
+    let wait = budget.charge(Duration::from_millis(100));
+    assert!(between(wait, 690, 700), "{:?}", wait);
+
+    // Half of four cores is two cores' worth, more than one thread uses
+    let budget = CpuBudget::for_cores(Some(50), 4);
+    assert_eq!(budget.charge(Duration::from_millis(100)), Duration::ZERO);
+
+    let unlimited = CpuBudget::for_cores(None, 1);
+    assert!(!unlimited.is_limited());
+    assert_eq!(unlimited.charge(Duration::from_secs(1)), Duration::ZERO);
+    assert!(!CpuBudget::for_cores(Some(100), 1).is_limited());
+}
+
+#[test]
+fn hashing_is_paced_by_the_budget() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    fs::create_dir_all(worlds.join("w/db")).unwrap();
+    fs::write(worlds.join("w/db/000005.ldb"), vec![7u8; 4 * 1024 * 1024]).unwrap();
+    let scan = |budget: CpuBudget| {
+        let mut files = FileManager::new(worlds.clone()).with_exclusions(Exclusions::new(&[])).with_cpu_budget(budget);
+        let start = Instant::now();
+        files.scan_directory().unwrap();
+        start.elapsed()
+    };
+
+    // A tenth of one core: the hashing takes about ten times as long
+    let unlimited = scan(CpuBudget::default());
+    let paced = scan(CpuBudget::for_cores(Some(10), 1));
+    assert!(paced >= unlimited * 4, "{:?} paced, {:?} unlimited", paced, unlimited);
+}
+
+#[test]
+fn booked_work_does_not_wait_but_the_next_piece_waits_for_it() {
+    let budget = CpuBudget::for_cores(Some(25), 1);
+    let start = Instant::now();
+    budget.run_booked(|| std::thread::sleep(Duration::from_millis(100)));
+    assert!(start.elapsed() < Duration::from_millis(250), "{:?}", start.elapsed());
+    let wait = budget.charge(Duration::ZERO);
+    assert!(between(wait, 200, 400), "{:?}", wait);
+}
+
+#[test]
+fn received_files_are_checked_without_waiting_for_the_budget() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    fs::create_dir_all(&worlds).unwrap();
+    // A hundredth of one core would keep a paced check waiting for seconds
+    let mut files = FileManager::new(worlds.clone()).with_exclusions(Exclusions::new(&[])).with_cpu_budget(CpuBudget::for_cores(Some(1), 1));
+    let start = Instant::now();
+    assert!(files.receive_file(std::path::Path::new("w/db/000005.ldb"), &vec![7u8; 4 * 1024 * 1024]).unwrap());
+    assert!(start.elapsed() < Duration::from_secs(3), "{:?}", start.elapsed());
+}
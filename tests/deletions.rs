@@ -0,0 +1,94 @@
+//! Files deleted on one device deleted on its peers, unless they changed
+//! there since, right away or once the deletion is due.
+
+mod common;
+
+use common::receiver::{files, start_receiver, world};
+use mcbd_world_sync::deletions::{PendingDeletion, PendingDeletions};
+use mcbd_world_sync::file_manager::{self, FileInfo};
+use mcbd_world_sync::network::SyncClient;
+use mcbd_world_sync::transfer_queue::Priority;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+#[test]
+fn only_the_version_deleted_is_deleted() {
+    let dir = tempfile::TempDir::new().unwrap();
+    world(dir.path(), "Survival");
+    let mut files = files(dir.path().to_path_buf());
+    let level = Path::new("Survival/level.dat");
+
+    // Changed here since the peer deleted it
+    assert!(!files.receive_deletion(level, &file_manager::hash_bytes(b"older")).unwrap());
+    assert!(dir.path().join(level).is_file());
+
+    let ldb = Path::new("Survival/db/000005.ldb");
+    assert!(files.receive_deletion(ldb, &file_manager::hash_bytes(&[5u8; 64])).unwrap());
+    assert!(!dir.path().join("Survival/db").exists());
+    assert!(files.get_file_info(ldb).is_none());
+    // The world goes with its last file
+    assert!(files.receive_deletion(level, &file_manager::hash_bytes(b"Survival")).unwrap());
+    assert!(!dir.path().join("Survival").exists());
+    assert!(!files.receive_deletion(level, "").unwrap());
+
+    // A peer that missed the deletion sending the file back does not restore it
+    let index = files.index();
+    let stale = FileInfo { path: level.to_path_buf(), last_modified: SystemTime::now() - Duration::from_secs(60), size: 8, hash: file_manager::hash_bytes(b"Survival") };
+    assert!(index.deleted_version(&stale));
+    assert!(!index.deleted_version(&FileInfo { last_modified: SystemTime::now() + Duration::from_secs(60), ..stale.clone() }));
+    assert!(!index.deleted_version(&FileInfo { hash: file_manager::hash_bytes(b"new"), ..stale }));
+}
+
+#[test]
+fn pending_deletions_wait_for_their_grace_period_or_a_confirmation() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let pending = PendingDeletions::new(dir.path().join("deletions.json"));
+    let now = SystemTime::now();
+    let deletion = |path: &str, received_at| PendingDeletion { path: PathBuf::from(path), hash: String::new(), device: "laptop".to_string(), received_at, approved: false };
+    pending.add(vec![deletion("Old/level.dat", now - Duration::from_secs(600)), deletion("New/level.dat", now)]).unwrap();
+
+    let due = |grace, confirm| pending.due(Duration::from_secs(grace), confirm, now).unwrap().into_iter().map(|d| d.path).collect::<Vec<_>>();
+    assert_eq!(due(300, false), [PathBuf::from("Old/level.dat")]);
+    assert_eq!(due(0, false).len(), 2);
+    assert!(due(0, true).is_empty());
+
+    assert_eq!(pending.approve(Some(Path::new("New"))).unwrap(), 1);
+    assert_eq!(due(300, true), [PathBuf::from("New/level.dat")]);
+    // Received again, it waits for a new confirmation
+    pending.add(vec![deletion("New/level.dat", now)]).unwrap();
+    assert!(due(300, true).is_empty());
+
+    assert_eq!(pending.remove(Path::new("Old")).unwrap(), 1);
+    assert_eq!(pending.list().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn deletions_sent_to_a_peer_are_applied_or_held_back() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let (sender, receiver) = (dir.path().join("sender"), dir.path().join("receiver"));
+    world(&sender, "Survival");
+    world(&receiver, "Survival");
+    let mut sent = files(sender.clone());
+    fs::remove_dir_all(sender.join("Survival")).unwrap();
+    sent.mark_deleted(Path::new("Survival"));
+    let tombstones = sent.index().tombstones_under(Path::new("Survival"));
+    assert_eq!(tombstones.len(), 2);
+
+    let address = start_receiver(Arc::new(Mutex::new(files(receiver.clone()))), |server| server).await;
+    SyncClient::new(address).send_file_deleted(PathBuf::from("Survival"), "Remove(Folder)".to_string(), tombstones.clone(), None).await.unwrap();
+    assert!(!receiver.join("Survival").exists());
+
+    // Held back, and cancelled by a new version of the file
+    world(&receiver, "Survival");
+    let pending = PendingDeletions::new(dir.path().join("deletions.json"));
+    let address = start_receiver(Arc::new(Mutex::new(files(receiver.clone()))), |server| server.with_pending_deletions(pending.clone())).await;
+    let client = SyncClient::new(address);
+    client.send_file_deleted(PathBuf::from("Survival"), "Remove(Folder)".to_string(), tombstones, None).await.unwrap();
+    assert!(receiver.join("Survival/level.dat").is_file());
+    assert_eq!(pending.list().unwrap().len(), 2);
+    client.send_file_content(PathBuf::from("Survival/level.dat"), b"Replayed".to_vec(), None, Priority::Interactive).await.unwrap();
+    assert_eq!(pending.list().unwrap().iter().map(|d| d.path.clone()).collect::<Vec<_>>(), [PathBuf::from("Survival/db/000005.ldb")]);
+}
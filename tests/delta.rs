@@ -0,0 +1,56 @@
+//! Rolling-checksum deltas between two versions of a file.
+
+use mcbd_world_sync::delta::{self, DeltaOp};
+
+/// Deterministic bytes that do not repeat within a block.
+fn noise(len: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(2654435761).max(1);
+    (0..len).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state as u8
+    }).collect()
+}
+
+fn round_trip(old: &[u8], new: &[u8]) -> Vec<DeltaOp> {
+    let block_size = 4096;
+    let ops = delta::diff(&delta::signatures(old, block_size), block_size, new);
+    assert_eq!(delta::apply(old, block_size, &ops).unwrap(), new);
+    ops
+}
+
+#[test]
+fn only_changed_blocks_are_sent() {
+    let old = noise(100_000, 1);
+    let mut new = old.clone();
+    new[50_000..50_010].copy_from_slice(&[0xAA; 10]);
+
+    let ops = round_trip(&old, &new);
+    assert!(delta::literal_len(&ops) <= 4096, "sent {} bytes", delta::literal_len(&ops));
+}
+
+#[test]
+fn shifted_data_is_found_at_any_offset() {
+    let old = noise(64 * 1024, 2);
+    let mut new = b"inserted at the front".to_vec();
+    new.extend_from_slice(&old);
+    new.extend_from_slice(b"and appended");
+
+    let ops = round_trip(&old, &new);
+    assert_eq!(delta::literal_len(&ops), "inserted at the front".len() + "and appended".len());
+}
+
+#[test]
+fn unrelated_and_empty_files_are_sent_whole() {
+    let new = noise(10_000, 3);
+    assert_eq!(delta::literal_len(&round_trip(&noise(10_000, 4), &new)), new.len());
+    assert_eq!(delta::literal_len(&round_trip(&[], &new)), new.len());
+    assert!(round_trip(&new, &[]).is_empty());
+}
+
+#[test]
+fn copies_past_the_base_are_rejected() {
+    assert!(delta::apply(b"short", 4096, &[DeltaOp::Copy { block: 1 }]).is_err());
+    assert!(delta::check_block_size(1).is_err());
+}
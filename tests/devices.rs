@@ -0,0 +1,119 @@
+//! Removing and renaming devices, and moving a device identity to a new install.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::app_dirs::AppDirs;
+use mcbd_world_sync::config::Config;
+use mcbd_world_sync::devices;
+use mcbd_world_sync::file_manager::FileInfo;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::index;
+use mcbd_world_sync::manifest::{Manifest, ManifestCache, ManifestSent};
+use mcbd_world_sync::network::SyncServer;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+fn config(state_dir: &std::path::Path, laptop_port: u16) -> Config {
+    serde_json::from_value(serde_json::json!({
+        "server": { "port": 0, "host": "127.0.0.1" },
+        "sync": {
+            "name": "desktop",
+            "devices": [
+                { "name": "laptop", "address": format!("127.0.0.1:{}", laptop_port) },
+                { "name": "nas", "address": "127.0.0.1:1" }
+            ],
+            "conflict_resolution": "newest",
+            "sync_interval": 60
+        },
+        "paths": { "minecraft_worlds": "worlds", "state_dir": state_dir.display().to_string() }
+    })).unwrap()
+}
+
+#[tokio::test]
+async fn removing_a_device_drops_its_cursor() {
+    let state = tempfile::TempDir::new().unwrap();
+    let app_dirs = AppDirs::at(state.path().to_path_buf());
+    let mut config = config(state.path(), free_port());
+    let cache = ManifestCache::load(app_dirs.cursors_file());
+    cache.acknowledge("nas", Arc::new(Manifest::new(BTreeMap::new())), None).await;
+    cache.acknowledge("laptop", Arc::new(Manifest::new(BTreeMap::new())), None).await;
+    cache.flush().await;
+
+    let removal = devices::remove(&mut config, &app_dirs, "nas", false).await.unwrap();
+    assert!(removal.cursor_removed);
+    assert!(removal.final_sync.is_none());
+    let names: Vec<_> = config.sync.devices.iter().map(|d| d.name.as_str()).collect();
+    assert_eq!(names, vec!["laptop"]);
+
+    let reloaded = ManifestCache::load(app_dirs.cursors_file());
+    assert!(reloaded.cursor("nas").await.is_none());
+    assert!(reloaded.cursor("laptop").await.is_some());
+
+    assert!(devices::remove(&mut config, &app_dirs, "nas", false).await.is_err());
+}
+
+#[tokio::test]
+async fn final_sync_pushes_the_manifest_before_removal() {
+    let state = tempfile::TempDir::new().unwrap();
+    let app_dirs = AppDirs::at(state.path().to_path_buf());
+    let path = PathBuf::from("w/level.dat");
+    let files = vec![FileInfo { path: path.clone(), last_modified: SystemTime::now(), size: 1, hash: "a".to_string() }];
+    index::save(&app_dirs.index_file(), state.path(), 1, files, Vec::new()).unwrap();
+
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(port).with_health(health.clone());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let mut config = config(state.path(), port);
+    let removal = devices::remove(&mut config, &app_dirs, "laptop", true).await.unwrap();
+    assert_eq!(removal.final_sync, Some(ManifestSent::Full));
+    assert!(removal.cursor_removed);
+
+    // An unreachable device is kept
+    assert!(devices::remove(&mut config, &app_dirs, "nas", true).await.is_err());
+    assert_eq!(config.sync.devices.len(), 1);
+}
+
+#[tokio::test]
+async fn renaming_a_device_keeps_its_cursor() {
+    let state = tempfile::TempDir::new().unwrap();
+    let app_dirs = AppDirs::at(state.path().to_path_buf());
+    let mut config = config(state.path(), free_port());
+    let cache = ManifestCache::load(app_dirs.cursors_file());
+    cache.acknowledge("nas", Arc::new(Manifest::new(BTreeMap::new())), None).await;
+    cache.flush().await;
+
+    assert!(devices::rename(&mut config, &app_dirs, "nas", "laptop").await.is_err());
+    devices::rename(&mut config, &app_dirs, "nas", "attic-nas").await.unwrap();
+    assert!(config.sync.devices.iter().any(|d| d.name == "attic-nas"));
+    let cache = ManifestCache::load(app_dirs.cursors_file());
+    assert!(cache.cursor("nas").await.is_none());
+    assert!(cache.cursor("attic-nas").await.is_some());
+}
+
+#[tokio::test]
+async fn a_reinstall_adopts_the_exported_identity() {
+    let old = tempfile::TempDir::new().unwrap();
+    let old_dirs = AppDirs::at(old.path().to_path_buf());
+    let cache = ManifestCache::load(old_dirs.cursors_file());
+    cache.acknowledge("laptop", Arc::new(Manifest::new(BTreeMap::new())), None).await;
+    cache.flush().await;
+    let export = old.path().join("identity.json");
+    let exported = devices::export_identity(&config(old.path(), 1), &old_dirs, &export).await.unwrap();
+    assert_eq!(exported.name, "desktop");
+
+    let new = tempfile::TempDir::new().unwrap();
+    let new_dirs = AppDirs::at(new.path().to_path_buf());
+    let mut config = config(new.path(), 1);
+    config.sync.name = None;
+    devices::import_identity(&mut config, &new_dirs, &export).await.unwrap();
+    assert_eq!(config.sync.local_name(), "desktop");
+    assert!(ManifestCache::load(new_dirs.cursors_file()).cursor("laptop").await.is_some());
+}
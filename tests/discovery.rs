@@ -0,0 +1,69 @@
+//! Peers found on the local network: where they are connected to, and
+//! adding them to the configuration.
+
+use mcbd_world_sync::config::{Config, Device, TlsConfig};
+use mcbd_world_sync::discovery::{self, Discovered, Peer};
+use mcbd_world_sync::network::SyncClient;
+use mcbd_world_sync::pairing::PairedDevice;
+use mcbd_world_sync::rendezvous::{self, Lookup};
+
+fn config(name: &str) -> Config {
+    serde_json::from_value(serde_json::json!({
+        "server": { "port": 0, "host": "127.0.0.1" },
+        "sync": { "name": name, "devices": [{ "name": "laptop", "address": "10.0.0.9:8080" }], "conflict_resolution": "newest", "sync_interval": 60 },
+        "paths": { "minecraft_worlds": "worlds" }
+    })).unwrap()
+}
+
+fn peer(name: &str, address: &str, fingerprint: Option<&str>) -> Peer {
+    Peer {
+        device: PairedDevice { name: name.to_string(), port: 8080, fingerprint: fingerprint.map(str::to_string) },
+        address: address.to_string(),
+    }
+}
+
+#[test]
+fn announcements_replace_earlier_ones() {
+    let discovered = Discovered::default();
+    assert!(discovered.insert(peer("laptop", "192.168.1.20:8080", None)));
+    assert!(!discovered.insert(peer("laptop", "192.168.1.20:8080", None)));
+    assert!(discovered.insert(peer("laptop", "192.168.1.31:8080", None)));
+    assert_eq!(discovered.address("laptop").as_deref(), Some("192.168.1.31:8080"));
+
+    assert!(discovered.remove("laptop").is_some());
+    assert_eq!(discovered.address("laptop"), None);
+    assert!(discovered.peers().is_empty());
+}
+
+#[tokio::test]
+async fn devices_are_reached_where_they_announce_themselves() {
+    let discovered = Discovered::default();
+    let lookup = Lookup { rendezvous: None, discovered: Some(discovered.clone()) };
+    let no_relay = |_: String| -> SyncClient { panic!("no relay is needed") };
+    let laptop = Device::from_target("laptop", "10.0.0.9:8080");
+    assert_eq!(rendezvous::resolve_device(&laptop, &lookup, no_relay).await.unwrap(), "10.0.0.9:8080");
+
+    discovered.insert(peer("laptop", "192.168.1.20:8080", None));
+    assert_eq!(rendezvous::resolve_device(&laptop, &lookup, no_relay).await.unwrap(), "192.168.1.20:8080");
+    // Also before asking the relay
+    let roaming = Device::from_target("laptop", "@laptop-id");
+    assert_eq!(rendezvous::resolve_device(&roaming, &lookup, no_relay).await.unwrap(), "192.168.1.20:8080");
+
+    let without = Lookup { rendezvous: None, discovered: None };
+    assert!(rendezvous::resolve_device(&roaming, &without, no_relay).await.is_err());
+}
+
+#[test]
+fn adding_a_peer_configures_and_trusts_it() {
+    let mut desktop = Config { tls: Some(TlsConfig::default()), ..config("desktop") };
+    discovery::add(&mut desktop, &peer("tablet", "192.168.1.40:8080", Some("ab12"))).unwrap();
+    discovery::add(&mut desktop, &peer("laptop", "192.168.1.20:8080", None)).unwrap();
+    let devices: Vec<(String, String)> = desktop.sync.devices.iter().map(|d| (d.name.clone(), d.address.clone())).collect();
+    assert_eq!(devices, [
+        ("laptop".to_string(), "192.168.1.20:8080".to_string()),
+        ("tablet".to_string(), "192.168.1.40:8080".to_string()),
+    ]);
+    assert_eq!(desktop.tls.unwrap().trusted, ["ab12"]);
+
+    assert!(discovery::add(&mut config("desktop"), &peer("desktop", "192.168.1.10:8080", None)).is_err());
+}
@@ -0,0 +1,88 @@
+//! One-time .mcworld download links served by the local HTTP server.
+
+mod common;
+
+use common::http;
+use mcbd_world_sync::http::{HttpState, WorldLinks};
+use mcbd_world_sync::links::Links;
+use mcbd_world_sync::mcworld;
+use mcbd_world_sync::metrics::Metrics;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+fn world(root: &Path) {
+    fs::create_dir_all(root.join("Skyblock/db")).unwrap();
+    fs::write(root.join("Skyblock/a.txt"), b"123456789").unwrap();
+    fs::write(root.join("Skyblock/levelname.txt"), b"Sky Block\n").unwrap();
+    fs::write(root.join("Skyblock/db/000005.ldb"), vec![7u8; 5000]).unwrap();
+}
+
+fn u16_at(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(data[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+#[test]
+fn mcworld_is_a_zip_of_the_world_folder() {
+    let dir = tempfile::TempDir::new().unwrap();
+    world(dir.path());
+    let world_dir = dir.path().join("Skyblock");
+    assert_eq!(mcworld::display_name(&world_dir), "Sky Block");
+
+    let mut archive = Vec::new();
+    let size = mcworld::write(&world_dir, &mut archive).unwrap();
+    assert_eq!(size, archive.len() as u64);
+
+    // The first entry is a.txt, stored with the standard CRC-32 of its bytes
+    assert_eq!(u32_at(&archive, 0), 0x0403_4b50);
+    assert_eq!(u32_at(&archive, 14), 0xCBF4_3926);
+    assert_eq!(&archive[30..35], b"a.txt");
+    assert_eq!(&archive[35..44], b"123456789");
+
+    let end = &archive[archive.len() - 22..];
+    assert_eq!(u32_at(end, 0), 0x0605_4b50);
+    assert_eq!(u16_at(end, 10), 3);
+    let central = u32_at(end, 16) as usize;
+    assert_eq!(u32_at(&archive, central), 0x0201_4b50);
+    assert!(archive.windows(14).any(|w| w == b"db/000005.ldb\x07"));
+}
+
+#[test]
+fn links_are_used_up_and_expire() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let links = Links::new(dir.path().join("downloads.json"));
+    let now = SystemTime::now();
+
+    let link = links.create("Skyblock", Duration::from_secs(3600), now).unwrap();
+    assert_eq!(links.redeem(&link.token, now).unwrap().unwrap().world, "Skyblock");
+    assert!(links.redeem(&link.token, now).unwrap().is_none());
+
+    let link = links.create("Skyblock", Duration::from_secs(60), now).unwrap();
+    assert!(links.redeem(&link.token, now + Duration::from_secs(120)).unwrap().is_none());
+    assert!(links.create("../etc", Duration::from_secs(60), now).is_err());
+}
+
+#[tokio::test]
+async fn download_link_serves_the_world_once() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    world(&worlds);
+    let links = Links::new(dir.path().join("downloads.json"));
+    let link = links.create("Skyblock", Duration::from_secs(3600), SystemTime::now()).unwrap();
+    let port = http::serve(HttpState { downloads: Some(WorldLinks { links, worlds }), ..http::state(dir.path(), Arc::new(Metrics::new())) }).await;
+
+    let (status, body) = http::get(port, &format!("/download/{}", link.token)).await;
+    assert_eq!(status, 200);
+    // Streamed with chunked encoding, so look for the archive's parts
+    assert!(body.windows(4).any(|w| w == 0x0403_4b50u32.to_le_bytes()));
+    assert!(body.windows(4).any(|w| w == 0x0605_4b50u32.to_le_bytes()));
+    assert!(body.windows(9).any(|w| w == b"123456789"));
+
+    assert_eq!(http::get(port, &format!("/download/{}", link.token)).await.0, 404);
+    assert_eq!(http::get(port, "/download/unknown").await.0, 404);
+}
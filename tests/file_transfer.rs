@@ -0,0 +1,191 @@
+//! File contents sent to a peer and written under its worlds directory,
+//! whole or as a delta against the peer's copy.
+
+mod common;
+
+use common::receiver::{files, start_receiver};
+use futures::{SinkExt, StreamExt};
+use mcbd_world_sync::correlation::CorrelationId;
+use mcbd_world_sync::delta;
+use mcbd_world_sync::file_manager;
+use mcbd_world_sync::mux::{self, Reassembler};
+use mcbd_world_sync::network::{FileRejected, SyncClient, SyncMessage, CAPABILITIES};
+use mcbd_world_sync::transfer_queue::Priority;
+use mcbd_world_sync::wire::{self, Format};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+#[tokio::test]
+async fn received_content_is_written_and_indexed() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let files = Arc::new(Mutex::new(files(dir.path().to_path_buf())));
+    let address = start_receiver(files.clone(), |server| server).await;
+    let path = PathBuf::from("World/db/000005.ldb");
+    let content = vec![7u8; 200 * 1024];
+
+    let sent = SyncClient::new(address.clone()).send_file_content(path.clone(), content.clone(), None, Priority::Background).await.unwrap();
+    assert_eq!(sent.size, content.len() as u64);
+    // Its chunks repeat, so most of them were sent once
+    assert!(sent.carried < sent.size / 2, "{:?}", sent);
+    assert_eq!(fs::read(dir.path().join(&path)).unwrap(), content);
+    let indexed = files.lock().await.get_file_info(&path).unwrap();
+    assert_eq!(indexed.size, content.len() as u64);
+
+    // The same content again is confirmed without rewriting the file
+    let generation = files.lock().await.generation();
+    SyncClient::new(address).send_file_content(path, content, None, Priority::Interactive).await.unwrap();
+    assert_eq!(files.lock().await.generation(), generation);
+}
+
+#[tokio::test]
+async fn paths_outside_the_worlds_directory_are_refused() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    fs::create_dir_all(&worlds).unwrap();
+    let address = start_receiver(Arc::new(Mutex::new(files(worlds))), |server| server).await;
+
+    for path in ["../escaped.txt", "/tmp/absolute.txt", ".mcbd-staging/x"] {
+        let sent = SyncClient::new(address.clone()).send_file_content(PathBuf::from(path), b"x".to_vec(), None, Priority::Background).await;
+        assert!(sent.is_err(), "{} was accepted", path);
+    }
+    assert!(!dir.path().join("escaped.txt").exists());
+}
+
+#[tokio::test]
+async fn files_that_cannot_be_written_are_reported_back() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let address = start_receiver(Arc::new(Mutex::new(files(dir.path().to_path_buf()))), |server| server).await;
+    let path = PathBuf::from("World/level.dat");
+    // A folder where the file goes
+    fs::create_dir_all(dir.path().join(&path).join("in_the_way")).unwrap();
+
+    let client = SyncClient::new(address);
+    let sent = client.send_file_content(path.clone(), b"level".to_vec(), None, Priority::Background).await;
+    let rejected = sent.unwrap_err().downcast::<FileRejected>().unwrap();
+    assert_eq!(rejected.path, path);
+    assert!(!rejected.reason.is_empty());
+
+    fs::remove_dir_all(dir.path().join(&path)).unwrap();
+    client.send_file_content(path.clone(), b"level".to_vec(), None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(dir.path().join(&path)).unwrap(), b"level");
+}
+
+#[tokio::test]
+async fn failure_replies_carry_the_senders_correlation_id() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let files = Arc::new(Mutex::new(files(dir.path().to_path_buf())));
+    let address = start_receiver(files, |server| server).await;
+    let path = PathBuf::from("World/level.dat");
+    fs::create_dir_all(dir.path().join(&path).join("in_the_way")).unwrap();
+
+    let id = CorrelationId::new();
+    let mut session = SyncClient::new(address).session().await.unwrap();
+    let content = SyncMessage::FileContent { path: path.clone(), content: b"level".to_vec(), group: None, modified: None, version: None, correlation_id: Some(id.clone()) };
+    session.send(&content).await.unwrap();
+    let Some(failed @ SyncMessage::FileFailed { .. }) = session.recv().await else { panic!("no failure reply") };
+    assert_eq!(failed.correlation_id(), Some(&id));
+}
+
+#[tokio::test]
+async fn changes_are_only_sent_once_the_peer_acknowledges_them() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let address = start_receiver(Arc::new(Mutex::new(files(dir.path().to_path_buf()))), |server| server).await;
+    SyncClient::new(address).send_file_change(PathBuf::from("World/db/000005.ldb"), "Remove".to_string()).await.unwrap();
+
+    // A peer that reads the change but goes away before acknowledging it
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let peer = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
+        framed.next().await.unwrap().unwrap();
+        let hello = SyncMessage::Hello {
+            codecs: vec!["lz4".to_string()],
+            protocol: 2,
+            min_protocol: 1,
+            device: Some("desktop".to_string()),
+            capabilities: Some(CAPABILITIES.iter().map(|c| c.to_string()).collect()),
+            edition: None,
+        };
+        framed.send(mux::encode_control(Format::Json.encode(&hello).unwrap())).await.unwrap();
+        let mut reassembler = Reassembler::new();
+        let mut received = Vec::new();
+        while let Some(Ok(frame)) = framed.next().await {
+            if let Some((_, bytes)) = reassembler.push(frame).unwrap() {
+                received.push(wire::decode(&bytes).unwrap().0);
+            }
+            if received.iter().any(|message| matches!(message, SyncMessage::FileChange { .. })) {
+                break;
+            }
+        }
+    });
+    let sent = SyncClient::new(address).send_file_change(PathBuf::from("World/db/000005.ldb"), "Remove".to_string()).await;
+    assert!(sent.unwrap_err().to_string().contains("before confirming"));
+    peer.await.unwrap();
+}
+
+#[tokio::test]
+async fn large_files_are_updated_with_a_delta() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let files = Arc::new(Mutex::new(files(dir.path().to_path_buf())));
+    let address = start_receiver(files.clone(), |server| server).await;
+    let path = PathBuf::from("World/db/000009.ldb");
+    let mut content: Vec<u8> = (0..2 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect();
+
+    let sent = SyncClient::new(address.clone()).send_file_content(path.clone(), content.clone(), None, Priority::Background).await.unwrap();
+    assert_eq!(sent.carried, sent.size);
+    content[1_000_000..1_000_100].fill(0);
+    content.extend_from_slice(b"appended block");
+    let sent = SyncClient::new(address).send_file_content(path.clone(), content.clone(), None, Priority::Background).await.unwrap();
+    assert_eq!(sent.size, content.len() as u64);
+    assert!(sent.carried < 64 * 1024, "{:?}", sent);
+
+    assert_eq!(fs::read(dir.path().join(&path)).unwrap(), content);
+    assert_eq!(files.lock().await.get_file_info(&path).unwrap().size, content.len() as u64);
+}
+
+#[tokio::test]
+async fn deltas_against_a_changed_copy_are_refused() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let files = Arc::new(Mutex::new(files(dir.path().to_path_buf())));
+    let path = PathBuf::from("World/db/000010.ldb");
+    let old = vec![1u8; 64 * 1024];
+    let mut guard = files.lock().await;
+    guard.receive_file(&path, &old).unwrap();
+
+    let mut new = old.clone();
+    new[0] = 2;
+    let ops = delta::diff(&guard.block_signatures(&path, delta::BLOCK_SIZE).unwrap(), delta::BLOCK_SIZE, &new);
+    fs::write(dir.path().join(&path), vec![3u8; 64 * 1024]).unwrap();
+    assert!(guard.receive_delta(&path, delta::BLOCK_SIZE, &ops, &file_manager::hash_bytes(&new)).is_err());
+    assert!(guard.block_signatures(Path::new("../outside"), delta::BLOCK_SIZE).is_err());
+}
+
+#[tokio::test]
+async fn changes_delivered_twice_are_applied_once() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let files = Arc::new(Mutex::new(files(dir.path().to_path_buf())));
+    let path = PathBuf::from("World/db/000011.ldb");
+    let old = vec![1u8; 64 * 1024];
+    let mut guard = files.lock().await;
+    guard.receive_file(&path, &old).unwrap();
+
+    // The second delta no longer fits the copy, which already is its result
+    let mut new = old.clone();
+    new[0] = 2;
+    let ops = delta::diff(&guard.block_signatures(&path, delta::BLOCK_SIZE).unwrap(), delta::BLOCK_SIZE, &new);
+    let hash = file_manager::hash_bytes(&new);
+    assert!(guard.receive_delta(&path, delta::BLOCK_SIZE, &ops, &hash).unwrap());
+    assert!(!guard.receive_delta(&path, delta::BLOCK_SIZE, &ops, &hash).unwrap());
+    assert!(!guard.receive_file(&path, &new).unwrap());
+
+    // A copy already on disk but not indexed yet is indexed rather than rewritten
+    let copy = PathBuf::from("World/level.dat");
+    fs::write(dir.path().join(&copy), b"level").unwrap();
+    assert!(!guard.receive_file(&copy, b"level").unwrap());
+    assert_eq!(guard.get_file_info(&copy).unwrap().hash, file_manager::hash_bytes(b"level"));
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use common::fixtures::{mutate, FixtureBuilder, FixtureRng, Mutation, WorldSpec};
+use mcbd_world_sync::file_manager::{FileInfo, FileManager};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+fn hashes(root: &Path) -> BTreeMap<PathBuf, String> {
+    FileManager::new(root.to_path_buf())
+        .scan_directory()
+        .unwrap()
+        .into_iter()
+        .map(|FileInfo { path, hash, .. }| (path, hash))
+        .collect()
+}
+
+fn builder() -> FixtureBuilder {
+    FixtureBuilder::new(42)
+        .world(WorldSpec::new("Survival").ldb(3, 32 * 1024))
+        .world(WorldSpec::new("Creative").with_packs())
+}
+
+#[test]
+fn same_seed_builds_identical_trees() {
+    let (a, b) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    builder().build(a.path());
+    builder().build(b.path());
+
+    let hashes_a = hashes(a.path());
+    assert!(hashes_a.keys().any(|p| p.ends_with("level.dat")));
+    assert!(hashes_a.keys().any(|p| p.ends_with("manifest.json")));
+    assert_eq!(hashes_a, hashes(b.path()));
+}
+
+#[test]
+fn mutations_are_deterministic_and_visible() {
+    let (a, b) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let world_a = builder().build(a.path()).remove(0);
+    let world_b = builder().build(b.path()).remove(0);
+    let before = hashes(a.path());
+
+    for (world, mut rng) in [(world_a, FixtureRng::new(7)), (world_b, FixtureRng::new(7))] {
+        for mutation in [Mutation::AppendLog, Mutation::RewriteLdbRange, Mutation::NewLdb, Mutation::DeleteLdb, Mutation::TouchLevelDat] {
+            mutate(&world, mutation, &mut rng);
+        }
+    }
+
+    let after = hashes(a.path());
+    assert_ne!(before, after);
+    assert_eq!(after, hashes(b.path()));
+}
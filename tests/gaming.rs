@@ -0,0 +1,95 @@
+//! Gaming mode: hashing and sending slow down while Minecraft is played on
+//! this device, and go back to full speed afterwards.
+
+use mcbd_world_sync::config::GamingModeConfig;
+use mcbd_world_sync::gaming::{self, GamingLimits, GamingMode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn limits() -> GamingLimits {
+    GamingLimits { hash_workers: 1, hash_rate: Some(1024 * 1024), send_rate: Some(1024 * 1024) }
+}
+
+#[tokio::test]
+async fn limits_only_apply_while_minecraft_is_in_the_foreground() {
+    let gaming = GamingMode::new(Some(limits()));
+    assert_eq!(gaming.hash_workers(4), 4);
+    let start = Instant::now();
+    for _ in 0..4 {
+        gaming.before_sending(512 * 1024).await;
+        gaming.before_hashing(512 * 1024);
+    }
+    assert!(start.elapsed() < Duration::from_millis(200));
+
+    assert!(gaming.set_active(true));
+    assert!(!gaming.set_active(true));
+    assert_eq!(gaming.hash_workers(4), 1);
+    // The first bytes go at once, the next ones wait for their turn
+    let start = Instant::now();
+    gaming.before_sending(256 * 1024).await;
+    gaming.before_sending(256 * 1024).await;
+    assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+    let start = Instant::now();
+    gaming.before_hashing(256 * 1024);
+    gaming.before_hashing(256 * 1024);
+    assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+
+    assert!(gaming.set_active(false));
+    let start = Instant::now();
+    gaming.before_sending(4 * 1024 * 1024).await;
+    gaming.before_sending(4 * 1024 * 1024).await;
+    assert!(start.elapsed() < Duration::from_millis(200));
+
+    // Turned off, it is never active
+    let disabled = GamingMode::new(None);
+    assert!(!disabled.set_active(true));
+    assert!(!disabled.is_active());
+}
+
+#[test]
+fn hashing_booked_on_async_tasks_is_waited_for_by_the_next_hashing() {
+    let gaming = GamingMode::new(Some(limits()));
+    gaming.set_active(true);
+    let start = Instant::now();
+    gaming.book_hashing(256 * 1024);
+    gaming.book_hashing(256 * 1024);
+    assert!(start.elapsed() < Duration::from_millis(100), "{:?}", start.elapsed());
+    // Half a second's worth booked ahead of it
+    gaming.before_hashing(0);
+    assert!(start.elapsed() >= Duration::from_millis(400), "{:?}", start.elapsed());
+}
+
+#[tokio::test]
+async fn gaming_mode_follows_the_foreground() {
+    let gaming = GamingMode::new(Some(GamingLimits::from(&GamingModeConfig::default())));
+    let playing = Arc::new(AtomicBool::new(false));
+    let probe = playing.clone();
+    tokio::spawn(gaming.clone().follow(Duration::from_millis(10), move || probe.load(Ordering::Relaxed)));
+
+    let wait_for = |active: bool| {
+        let gaming = gaming.clone();
+        async move {
+            for _ in 0..200 {
+                if gaming.is_active() == active {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("gaming mode did not turn {}", if active { "on" } else { "off" });
+        }
+    };
+    playing.store(true, Ordering::Relaxed);
+    wait_for(true).await;
+    playing.store(false, Ordering::Relaxed);
+    wait_for(false).await;
+}
+
+#[test]
+fn minecraft_is_recognised_by_its_program() {
+    assert!(gaming::is_game(r"C:\Program Files\WindowsApps\Microsoft.MinecraftUWP_1.21.0.0_x64__8wekyb3d8bbwe\Minecraft.Windows.exe"));
+    assert!(gaming::is_game("minecraft.windows.exe"));
+    assert!(gaming::is_game("/usr/bin/mcpelauncher-client"));
+    assert!(!gaming::is_game("/usr/bin/mcpelauncher-ui-qt"));
+    assert!(!gaming::is_game(r"C:\Windows\explorer.exe"));
+}
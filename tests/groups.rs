@@ -0,0 +1,108 @@
+//! Several sync groups on one daemon, each limited to its own worlds and key.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::config::{Device, Direction, GroupConfig, SyncConfig};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::groups::{GroupTag, Groups};
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::{SyncClient, SyncServer};
+use mcbd_world_sync::transfer_queue::Priority;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+fn device(name: &str) -> Device {
+    Device { name: name.to_string(), address: "127.0.0.1:1".to_string(), rendezvous: None, key: None }
+}
+
+fn group(name: &str, devices: &[&str], worlds: &[&str], key: Option<&str>) -> GroupConfig {
+    GroupConfig {
+        name: name.to_string(),
+        devices: devices.iter().map(|d| device(d)).collect(),
+        worlds: worlds.iter().map(|w| w.to_string()).collect(),
+        key: key.map(str::to_string),
+        direction: Direction::Both,
+        expires_at: None,
+    }
+}
+
+fn friends_and_family() -> Groups {
+    Groups::new(vec![
+        group("friends", &["alex", "sam"], &["Skyblock"], Some("friends-secret")),
+        group("family", &["mum"], &["Farm", "Castle"], Some("family-secret")),
+    ]).unwrap()
+}
+
+#[test]
+fn changes_only_go_to_groups_sharing_the_world() {
+    let groups = friends_and_family();
+    let names = |path: &str| groups.devices_for(Path::new(path)).iter().map(|d| d.name.clone()).collect::<Vec<_>>();
+    assert_eq!(names("Skyblock/db/000005.ldb"), ["alex", "sam"]);
+    assert_eq!(names("Farm/level.dat"), ["mum"]);
+    assert!(names("Private/level.dat").is_empty());
+}
+
+#[test]
+fn devices_belong_to_one_group() {
+    let duplicate = Groups::new(vec![
+        group("friends", &["alex"], &[], None),
+        group("family", &["alex"], &[], None),
+    ]);
+    assert!(duplicate.is_err());
+
+    let mut config: SyncConfig = serde_json::from_value(serde_json::json!({ "devices": [], "conflict_resolution": "newest", "sync_interval": 60 })).unwrap();
+    config.devices.push(device("laptop"));
+    config.groups.push(group("friends", &["alex"], &["Skyblock"], None));
+    let names: Vec<String> = config.groups().into_iter().map(|g| g.name).collect();
+    assert_eq!(names, ["default", "friends"]);
+    assert_eq!(config.all_devices().len(), 2);
+}
+
+#[test]
+fn content_needs_the_group_key_and_one_of_its_worlds() {
+    let groups = friends_and_family();
+    let path = Path::new("Skyblock/level.dat");
+    let tag = groups.tag("alex", path).unwrap();
+    assert!(groups.authorize(Some(&tag), path).is_ok());
+
+    // The proof is bound to the path and the key
+    assert!(groups.authorize(Some(&tag), Path::new("Skyblock/other.dat")).is_err());
+    let forged = GroupTag { name: "friends".to_string(), proof: Some("0".repeat(64)) };
+    assert!(groups.authorize(Some(&forged), path).is_err());
+
+    // A member of one group cannot write into another group's worlds
+    let farm = Path::new("Farm/level.dat");
+    assert!(groups.authorize(groups.tag("alex", farm).as_ref(), farm).is_err());
+    // Without a default group, untagged content has nowhere to go
+    assert!(groups.authorize(None, path).is_err());
+}
+
+#[tokio::test]
+async fn receiver_refuses_content_from_another_group() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let files = Arc::new(Mutex::new(FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]))));
+    let server = SyncServer::new(port).with_health(health.clone()).with_file_manager(files).with_groups(friends_and_family());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let address = format!("127.0.0.1:{}", port);
+    // The sending hub knows the same groups
+    let sender = friends_and_family();
+
+    let path = PathBuf::from("Skyblock/level.dat");
+    let tag = sender.tag("sam", &path);
+    SyncClient::new(address.clone()).send_file_content(path.clone(), b"sky".to_vec(), tag, Priority::Background).await.unwrap();
+    assert!(dir.path().join(&path).exists());
+
+    let path = PathBuf::from("Farm/level.dat");
+    let tag = sender.tag("sam", &path);
+    assert!(SyncClient::new(address).send_file_content(path.clone(), b"farm".to_vec(), tag, Priority::Background).await.is_err());
+    assert!(!dir.path().join(&path).exists());
+}
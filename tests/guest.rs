@@ -0,0 +1,191 @@
+//! Pulling a shared world with a token, without joining the host's groups.
+
+mod common;
+
+use common::daemon::free_port;
+use futures::{SinkExt, StreamExt};
+use mcbd_world_sync::config::{Config, Device};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::{self, FileManager};
+use mcbd_world_sync::groups::{GroupTag, Groups};
+use mcbd_world_sync::guest::{self, Pulled};
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::manifest::ManifestEntry;
+use mcbd_world_sync::mux::{self, Reassembler};
+use mcbd_world_sync::network::{SyncClient, SyncMessage, SyncServer, WarmingUp, CAPABILITIES, WARM_UP_RETRY_AFTER};
+use mcbd_world_sync::shares::{self, ShareToken};
+use mcbd_world_sync::wire::{self, Format};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio::sync::Mutex;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+fn write(root: &Path, path: &str, content: &[u8]) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+}
+
+/// A host sharing "Adventure" with a guest-only friend, and the token. It
+/// answers with the worlds once its initial scan is `scanned`.
+async fn start_host(worlds: &Path, scanned: bool) -> (SyncClient, Arc<Mutex<FileManager>>, ShareToken, Arc<Health>) {
+    let mut config: Config = serde_json::from_value(serde_json::json!({
+        "server": { "port": 0, "host": "127.0.0.1" },
+        "sync": { "devices": [{ "name": "laptop", "address": "127.0.0.1:1" }], "conflict_resolution": "newest", "sync_interval": 60 },
+        "paths": { "minecraft_worlds": "worlds" }
+    })).unwrap();
+    let token = shares::create(&mut config, "Adventure", Device::from_target("robin", ""), 7, false, SystemTime::now()).unwrap();
+    let groups = Groups::new(config.sync.groups()).unwrap();
+    assert!(groups.devices_for(Path::new("Adventure/level.dat")).iter().all(|d| d.name != "robin"));
+
+    let mut files = FileManager::new(worlds.to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    files.scan_directory().unwrap();
+    let files = Arc::new(Mutex::new(files));
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    if scanned {
+        Health::set(&health.index_scanned);
+    }
+    let server = SyncServer::new(port).with_health(health.clone()).with_warm_up(health.clone()).with_file_manager(files.clone()).with_groups(groups);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    (SyncClient::new(format!("127.0.0.1:{}", port)), files, token, health)
+}
+
+#[tokio::test]
+async fn guest_pulls_and_updates_the_shared_world() {
+    let host_dir = tempfile::TempDir::new().unwrap();
+    let guest_dir = tempfile::TempDir::new().unwrap();
+    let big: Vec<u8> = (0..3 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+    write(host_dir.path(), "Adventure/level.dat", b"level");
+    write(host_dir.path(), "Adventure/db/000005.ldb", &big);
+    write(host_dir.path(), "Adventure/db/000006.ldb", b"old");
+    write(host_dir.path(), "Private/level.dat", b"secret");
+    let (client, host_files, token, _) = start_host(host_dir.path(), true).await;
+    let mut guest_files = FileManager::new(guest_dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+
+    let pulled = guest::pull(&client, &token, &mut guest_files).await.unwrap();
+    assert_eq!(pulled, Pulled { fetched: 3, unchanged: 0, removed: 0 });
+    assert_eq!(fs::read(guest_dir.path().join("Adventure/db/000005.ldb")).unwrap(), big);
+    assert!(!guest_dir.path().join("Private").exists());
+
+    // The host compacts the world: one file changes, one goes away
+    write(host_dir.path(), "Adventure/level.dat", b"level 2");
+    fs::remove_file(host_dir.path().join("Adventure/db/000006.ldb")).unwrap();
+    {
+        let mut host = host_files.lock().await;
+        host.mark_deleted(Path::new("Adventure/db/000006.ldb"));
+        host.scan_directory().unwrap();
+    }
+    let mut guest_files = FileManager::new(guest_dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    let pulled = guest::pull(&client, &token, &mut guest_files).await.unwrap();
+    assert_eq!(pulled, Pulled { fetched: 1, unchanged: 1, removed: 1 });
+    assert_eq!(fs::read(guest_dir.path().join("Adventure/level.dat")).unwrap(), b"level 2");
+    assert!(!guest_dir.path().join("Adventure/db/000006.ldb").exists());
+}
+
+#[tokio::test]
+async fn other_worlds_and_wrong_keys_are_refused() {
+    let host_dir = tempfile::TempDir::new().unwrap();
+    let guest_dir = tempfile::TempDir::new().unwrap();
+    write(host_dir.path(), "Adventure/level.dat", b"level");
+    write(host_dir.path(), "Private/level.dat", b"secret");
+    let (client, _, token, _) = start_host(host_dir.path(), true).await;
+    let mut guest_files = FileManager::new(guest_dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+
+    let other_world = ShareToken { world: "Private".to_string(), ..token.clone() };
+    assert!(guest::pull(&client, &other_world, &mut guest_files).await.is_err());
+    let wrong_key = ShareToken { key: "0".repeat(32), ..token.clone() };
+    assert!(guest::pull(&client, &wrong_key, &mut guest_files).await.is_err());
+
+    let mut session = client.session().await.unwrap();
+    let tag = GroupTag::new(&token.group, Some(&token.key), Path::new("Adventure/../Private/level.dat"));
+    assert!(session.pull_file(Path::new("Adventure/../Private/level.dat"), tag).await.is_err());
+    assert!(!guest_dir.path().join("Private").exists());
+}
+
+#[tokio::test]
+async fn guests_wait_until_the_host_scanned_its_worlds() {
+    let host_dir = tempfile::TempDir::new().unwrap();
+    let guest_dir = tempfile::TempDir::new().unwrap();
+    write(host_dir.path(), "Adventure/level.dat", b"level");
+    write(guest_dir.path(), "Adventure/level.dat", b"older");
+    let (client, _, token, health) = start_host(host_dir.path(), false).await;
+
+    let mut session = client.session().await.unwrap();
+    let tag = GroupTag::new(&token.group, Some(&token.key), Path::new("Adventure"));
+    let error = session.list_world("Adventure", tag).await.unwrap_err();
+    assert_eq!(error.downcast_ref::<WarmingUp>(), Some(&WarmingUp { retry_after: WARM_UP_RETRY_AFTER }));
+
+    // The pull asks again rather than taking the world for empty
+    let scanned = health.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        Health::set(&scanned.index_scanned);
+    });
+    let mut guest_files = FileManager::new(guest_dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    let pulled = guest::pull(&client, &token, &mut guest_files).await.unwrap();
+    assert_eq!(pulled, Pulled { fetched: 1, unchanged: 0, removed: 0 });
+    assert_eq!(fs::read(guest_dir.path().join("Adventure/level.dat")).unwrap(), b"level");
+}
+
+/// A host that lists `listed` for any world and sends `served` for any file.
+async fn start_lying_host(listed: &[(&str, &[u8])], served: &'static [u8]) -> SyncClient {
+    let entries: BTreeMap<PathBuf, ManifestEntry> = listed.iter()
+        .map(|(path, content)| (PathBuf::from(path), ManifestEntry { hash: file_manager::hash_bytes(content), size: content.len() as u64 }))
+        .collect();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
+        framed.next().await.unwrap().unwrap();
+        let hello = SyncMessage::Hello {
+            codecs: vec!["lz4".to_string()],
+            protocol: 2,
+            min_protocol: 1,
+            device: Some("host".to_string()),
+            capabilities: Some(CAPABILITIES.iter().map(|c| c.to_string()).collect()),
+            edition: None,
+        };
+        framed.send(mux::encode_control(Format::Json.encode(&hello).unwrap())).await.unwrap();
+        let mut reassembler = Reassembler::new();
+        while let Some(Ok(frame)) = framed.next().await {
+            let Some((_, bytes)) = reassembler.push(frame).unwrap() else { continue };
+            let reply = match wire::decode(&bytes).unwrap().0 {
+                SyncMessage::WorldRequest { world, .. } => SyncMessage::WorldListing { world, entries: entries.clone() },
+                SyncMessage::FileRequest { path, .. } => SyncMessage::FilePart { path, bytes: served.to_vec(), last: true },
+                _ => continue,
+            };
+            framed.send(mux::encode_control(Format::Json.encode(&reply).unwrap())).await.unwrap();
+        }
+    });
+    SyncClient::new(address)
+}
+
+#[tokio::test]
+async fn hosts_cannot_write_outside_the_shared_world() {
+    let host_dir = tempfile::TempDir::new().unwrap();
+    let (_, _, token, _) = start_host(host_dir.path(), true).await;
+    let guest_dir = tempfile::TempDir::new().unwrap();
+    write(guest_dir.path(), "OtherWorld/level.dat", b"mine");
+
+    let client = start_lying_host(&[("Adventure/level.dat", b"evil"), ("OtherWorld/level.dat", b"evil")], b"evil").await;
+    let mut guest_files = FileManager::new(guest_dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    let error = guest::pull(&client, &token, &mut guest_files).await.unwrap_err();
+    assert!(error.to_string().contains("outside the shared world"), "{}", error);
+    assert_eq!(fs::read(guest_dir.path().join("OtherWorld/level.dat")).unwrap(), b"mine");
+    assert!(!guest_dir.path().join("Adventure").exists());
+
+    // Content that does not match the listing is not written either
+    let client = start_lying_host(&[("Adventure/level.dat", b"level")], b"evil").await;
+    let error = guest::pull(&client, &token, &mut guest_files).await.unwrap_err();
+    assert!(error.to_string().contains("does not match"), "{}", error);
+    assert!(!guest_dir.path().join("Adventure/level.dat").exists());
+}
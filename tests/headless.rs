@@ -0,0 +1,56 @@
+//! Container mode: configuration from the environment, JSON logs on stdout
+//! and a clean exit on SIGTERM.
+#![cfg(unix)]
+
+mod common;
+
+use common::daemon::{free_port, wait_until};
+use std::io::Read;
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use tempfile::TempDir;
+
+#[test]
+fn headless_daemon_runs_from_env_and_stops_on_sigterm() {
+    let dir = TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    let state = dir.path().join("state");
+    std::fs::create_dir_all(&worlds).unwrap();
+    let port = free_port();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_mcbd-world-sync"))
+        .arg("--headless")
+        .current_dir(dir.path())
+        .env("MCBD_PORT", port.to_string())
+        .env("MCBD_WORLDS", &worlds)
+        .env("MCBD_STATE_DIR", &state)
+        .env("MCBD_DEVICES", format!("peer=127.0.0.1:{}", free_port()))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    assert!(wait_until(Duration::from_secs(10), || TcpStream::connect(("127.0.0.1", port)).is_ok()));
+    // Let the watcher register before asking the daemon to stop
+    std::thread::sleep(Duration::from_millis(500));
+
+    let status = Command::new("kill").args(["-TERM", &child.id().to_string()]).status().unwrap();
+    assert!(status.success());
+    let exited = wait_until(Duration::from_secs(10), || child.try_wait().unwrap().is_some());
+    if !exited {
+        child.kill().unwrap();
+    }
+    assert!(exited, "daemon ignored SIGTERM");
+    assert!(child.wait().unwrap().success());
+
+    let mut stdout = String::new();
+    child.stdout.take().unwrap().read_to_string(&mut stdout).unwrap();
+    assert!(!stdout.is_empty());
+    for line in stdout.lines() {
+        let entry: serde_json::Value = serde_json::from_str(line).unwrap_or_else(|e| panic!("{}: {}", e, line));
+        assert!(entry["level"].is_string() && entry["msg"].is_string(), "{}", line);
+    }
+    assert!(state.join("index.json").exists());
+}
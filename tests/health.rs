@@ -0,0 +1,61 @@
+//! Liveness and readiness endpoints of the local HTTP server.
+
+mod common;
+
+use common::daemon::{free_port, wait_until, TestDaemon, CONVERGE_TIMEOUT};
+use std::fs;
+use std::time::Duration;
+
+#[test]
+fn healthz_and_readyz_report_a_started_daemon() {
+    let port = free_port();
+    let mut daemon = TestDaemon::new("a", port, free_port(), "newest").with_http();
+    daemon.start();
+
+    let live = wait_until(Duration::from_secs(10), || {
+        daemon.http_get("/healthz").is_some_and(|(status, _)| status == 200)
+    });
+    assert!(live, "healthz did not answer:\n{}", daemon.log());
+    let ready = wait_until(Duration::from_secs(10), || {
+        daemon.http_get("/readyz").is_some_and(|(status, _)| status == 200)
+    });
+    assert!(ready, "daemon never became ready: {:?}\n{}", daemon.http_get("/readyz"), daemon.log());
+}
+
+
+#[test]
+fn status_lists_configured_devices() {
+    let port = free_port();
+    let mut daemon = TestDaemon::new("a", port, free_port(), "newest").with_http();
+    daemon.start();
+
+    let status = wait_until(Duration::from_secs(10), || {
+        daemon.http_get("/status").is_some_and(|(status, body)| status == 200 && body.contains("\"name\":\"peer\""))
+    });
+    assert!(status, "status did not answer: {:?}\n{}", daemon.http_get("/status"), daemon.log());
+}
+
+#[test]
+fn status_lists_files_a_device_could_not_apply() {
+    let port_a = free_port();
+    let port_b = std::iter::repeat_with(free_port).find(|p| *p != port_a).unwrap();
+    let mut a = TestDaemon::new("a", port_a, port_b, "newest").with_http();
+    let mut b = TestDaemon::new("b", port_b, port_a, "newest");
+    a.start();
+    b.start();
+    // b has a folder where the file goes
+    fs::create_dir_all(b.worlds.join("Alpha/level.dat/in_the_way")).unwrap();
+    fs::create_dir_all(a.worlds.join("Alpha")).unwrap();
+    fs::write(a.worlds.join("Alpha/level.dat"), "alpha").unwrap();
+
+    let failed = || {
+        let (_, body) = a.http_get("/status").unwrap_or_default();
+        let status: serde_json::Value = serde_json::from_str(&body).unwrap_or_default();
+        status["devices"][0]["failed"].as_array().cloned().unwrap_or_default()
+    };
+    assert!(wait_until(CONVERGE_TIMEOUT, || failed().iter().any(|f| f["path"] == "Alpha/level.dat")), "{}", a.log());
+
+    fs::remove_dir_all(b.worlds.join("Alpha/level.dat")).unwrap();
+    assert!(wait_until(CONVERGE_TIMEOUT, || failed().is_empty()), "{}", a.log());
+    assert_eq!(fs::read(b.worlds.join("Alpha/level.dat")).unwrap(), b"alpha");
+}
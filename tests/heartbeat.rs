@@ -0,0 +1,117 @@
+//! Heartbeats on sessions: when each device was last heard from, and
+//! sessions closed once the other side goes silent.
+
+mod common;
+
+use common::daemon::free_port;
+use futures::{SinkExt, StreamExt};
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::mux::{self, Reassembler};
+use mcbd_world_sync::network::{SyncClient, SyncMessage, SyncServer, CAPABILITIES, HEARTBEAT_TIMEOUT};
+use mcbd_world_sync::reconnect::{PeerState, Reachability};
+use mcbd_world_sync::wire::{self, Format};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+fn hello(device: &str) -> Bytes {
+    let hello = SyncMessage::Hello {
+        codecs: vec!["lz4".to_string()],
+        protocol: 2,
+        min_protocol: 1,
+        device: Some(device.to_string()),
+        capabilities: Some(CAPABILITIES.iter().map(|c| c.to_string()).collect()),
+        edition: None,
+    };
+    mux::encode_control(Format::Json.encode(&hello).unwrap())
+}
+
+/// Next whole message on a multiplexed connection, `None` once it closed.
+async fn next_message(framed: &mut Framed<TcpStream, LengthDelimitedCodec>, reassembler: &mut Reassembler) -> Option<SyncMessage> {
+    while let Some(Ok(frame)) = framed.next().await {
+        if let Some((_, bytes)) = reassembler.push(frame).unwrap() {
+            return Some(wire::decode(&bytes).unwrap().0);
+        }
+    }
+    None
+}
+
+#[tokio::test]
+async fn both_sides_record_when_they_last_heard_from_each_other() {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let heard_by_server = Reachability::new();
+    let server = SyncServer::new(port).with_health(health.clone()).with_device_name("desktop".to_string()).with_reachability(heard_by_server.clone());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let heard_by_client = Reachability::new();
+    heard_by_client.set_offline("desktop", SystemTime::now());
+    let client = SyncClient::new(format!("127.0.0.1:{}", port)).with_device_name("laptop".to_string()).with_reachability(heard_by_client.clone());
+    let _session = client.session().await.unwrap();
+
+    // Hearing from a device shows it is back
+    assert_eq!(heard_by_client.state("desktop", SystemTime::now()), PeerState::Online);
+    assert!(heard_by_client.offline_since("desktop").is_none());
+    assert!(heard_by_server.last_seen("laptop").is_some());
+    assert_eq!(heard_by_server.state("laptop", SystemTime::now() + HEARTBEAT_TIMEOUT), PeerState::Idle);
+}
+
+#[tokio::test]
+async fn sessions_to_a_silent_peer_are_closed() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    // Answers the Hello, then reads everything and never answers again
+    let peer = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
+        framed.next().await.unwrap().unwrap();
+        framed.next().await.unwrap().unwrap();
+        framed.send(hello("desktop")).await.unwrap();
+        let mut pings = 0;
+        let mut reassembler = Reassembler::new();
+        while let Some(message) = next_message(&mut framed, &mut reassembler).await {
+            assert!(matches!(message, SyncMessage::Ping), "{:?}", message);
+            pings += 1;
+        }
+        pings
+    });
+
+    let reachability = Reachability::new();
+    let mut session = SyncClient::new(address).with_reachability(reachability.clone()).session().await.unwrap();
+    tokio::time::pause();
+    assert!(session.recv().await.is_none());
+    assert!(session.is_closed());
+    assert_eq!(reachability.state("desktop", SystemTime::now()), PeerState::Offline);
+    drop(session);
+    let pings = peer.await.unwrap();
+    assert!(pings >= 3, "{}", pings);
+}
+
+#[tokio::test]
+async fn the_server_answers_pings_and_drops_silent_peers() {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(port).with_health(health.clone());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let socket = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
+    let mut reassembler = Reassembler::new();
+    framed.send(Bytes::from_static(mux::PREAMBLE)).await.unwrap();
+    framed.send(hello("laptop")).await.unwrap();
+    assert!(matches!(next_message(&mut framed, &mut reassembler).await, Some(SyncMessage::Hello { .. })));
+    framed.send(mux::encode_control(Format::MessagePack.encode(&SyncMessage::Ping).unwrap())).await.unwrap();
+    assert!(matches!(next_message(&mut framed, &mut reassembler).await, Some(SyncMessage::Pong)));
+
+    // Without further pings the server gives up on the connection
+    tokio::time::pause();
+    assert!(next_message(&mut framed, &mut reassembler).await.is_none());
+}
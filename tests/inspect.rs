@@ -0,0 +1,70 @@
+//! Reading what a world's LevelDB database holds without opening it for
+//! writing, from tables and logs written here the way Minecraft does.
+
+mod common;
+
+use common::leveldb::{chunk_key, internal_key, log, table};
+use mcbd_world_sync::inspect;
+use std::fs;
+use std::path::Path;
+
+fn world(root: &Path) -> std::path::PathBuf {
+    let world = root.join("Skyblock");
+    fs::create_dir_all(world.join("db")).unwrap();
+    fs::write(world.join("level.dat"), b"level").unwrap();
+    fs::write(world.join("db/CURRENT"), b"MANIFEST-000001\n").unwrap();
+    world
+}
+
+#[test]
+fn counts_chunks_players_and_keys_across_tables_and_logs() {
+    let root = tempfile::TempDir::new().unwrap();
+    let world = world(root.path());
+    let mut entries = vec![
+        (internal_key(&chunk_key(0, 0, None, 44, None), 1, true), vec![40]),
+        (internal_key(&chunk_key(0, 0, None, 47, Some(0)), 2, true), vec![0; 100]),
+        (internal_key(&chunk_key(0, 1, None, 47, Some(3)), 3, true), vec![0; 50]),
+        (internal_key(&chunk_key(5, -2, Some(1), 44, None), 4, true), vec![40]),
+        (internal_key(b"Overworld", 5, true), vec![1; 10]),
+        (internal_key(b"~local_player", 6, true), vec![1; 20]),
+        (internal_key(b"player_server_1234", 7, true), vec![1; 20]),
+    ];
+    entries.sort();
+    fs::write(world.join("db/000005.ldb"), table(&entries)).unwrap();
+    // The log is newer: it removes one chunk and adds an actor, a map and a player
+    fs::write(world.join("db/000006.log"), log(10, &[
+        (&chunk_key(0, 1, None, 47, Some(3)), None),
+        (b"actorprefix\x00\x00\x00\x01\x00\x00\x00\x02", Some(b"actor")),
+        (b"map_-12", Some(b"map")),
+        (b"player_server_5678", Some(b"player")),
+    ])).unwrap();
+
+    let inspection = inspect::inspect(&world).unwrap();
+    assert_eq!(inspection.keys, 9);
+    assert_eq!(inspection.bytes, 1 + 100 + 1 + 10 + 20 + 20 + 5 + 3 + 6);
+    assert_eq!(inspection.chunks.into_iter().collect::<Vec<_>>(), [("nether".to_string(), 1), ("overworld".to_string(), 1)]);
+    assert_eq!(inspection.players, ["player_server_1234", "player_server_5678", "~local_player"]);
+    assert_eq!((inspection.actors, inspection.maps), (1, 1));
+    assert!(inspection.damaged.is_empty() && inspection.missing.is_empty());
+}
+
+#[test]
+fn damaged_files_are_reported_with_what_could_be_read() {
+    let root = tempfile::TempDir::new().unwrap();
+    let world = world(root.path());
+    fs::remove_file(world.join("db/CURRENT")).unwrap();
+    let mut table = table(&[(internal_key(b"~local_player", 1, true), vec![1; 20])]);
+    table[3] ^= 0xff;
+    fs::write(world.join("db/000005.ldb"), table).unwrap();
+    let mut log = log(2, &[(b"map_1", Some(b"map"))]);
+    // A record Minecraft was still writing is not damage
+    log.extend_from_slice(&[0, 0, 0, 0, 200, 0, 1, 1, 2]);
+    fs::write(world.join("db/000006.log"), log).unwrap();
+
+    let inspection = inspect::inspect(&world).unwrap();
+    assert_eq!((inspection.keys, inspection.maps), (1, 1));
+    assert_eq!(inspection.missing, ["db/CURRENT"]);
+    assert_eq!(inspection.damaged.len(), 1);
+    assert!(inspection.damaged[0].file.ends_with("000005.ldb"));
+    assert!(inspection.damaged[0].error.contains("Checksum"));
+}
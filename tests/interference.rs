@@ -0,0 +1,58 @@
+//! Retrying file operations that a scanner briefly blocks after our writes.
+
+use mcbd_world_sync::interference::WriteTracker;
+use std::io;
+use std::path::Path;
+
+/// An operation that is denied `denials` times before it succeeds,
+/// counting its attempts in `attempts`.
+fn denied(denials: u32, attempts: &mut u32) -> impl FnMut() -> io::Result<()> + '_ {
+    move || {
+        *attempts += 1;
+        if *attempts <= denials {
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn files_we_just_wrote_are_retried_while_access_is_denied() {
+    let writes = WriteTracker::new();
+    let path = Path::new("World/db/000005.ldb");
+    writes.record_write(path);
+    let mut attempts = 0;
+    writes.retry(path, denied(2, &mut attempts)).unwrap();
+    assert_eq!(attempts, 3);
+}
+
+#[test]
+fn files_we_did_not_write_are_not_retried() {
+    let writes = WriteTracker::new();
+    writes.record_write(Path::new("World/level.dat"));
+    let mut attempts = 0;
+    let error = writes.retry(Path::new("World/db/000005.ldb"), denied(2, &mut attempts)).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(attempts, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn async_tasks_retry_without_holding_up_the_runtime() {
+    let writes = WriteTracker::new();
+    let path = Path::new("World/db/000005.ldb");
+    writes.record_write(path);
+    let mut attempts = 0;
+    writes.retry(path, denied(2, &mut attempts)).unwrap();
+    assert_eq!(attempts, 3);
+}
+
+#[tokio::test]
+async fn a_single_threaded_runtime_is_not_put_to_sleep() {
+    let writes = WriteTracker::new();
+    let path = Path::new("World/db/000005.ldb");
+    writes.record_write(path);
+    let mut attempts = 0;
+    writes.retry(path, denied(2, &mut attempts)).unwrap_err();
+    assert_eq!(attempts, 1);
+}
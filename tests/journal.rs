@@ -0,0 +1,106 @@
+//! The journal of change sets received for a world: undoing the last one
+//! and rebuilding the world as it was after any of them.
+
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::journal::Journal;
+use mcbd_world_sync::snapshots::Snapshots;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+fn read(path: impl AsRef<Path>) -> String {
+    String::from_utf8(fs::read(path).unwrap()).unwrap()
+}
+
+#[test]
+fn undo_reverts_only_the_files_of_the_last_burst() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(worlds.path().join("Skyblock/db")).unwrap();
+    fs::write(worlds.path().join("Skyblock/level.dat"), b"old level").unwrap();
+    fs::write(worlds.path().join("Skyblock/db/000005.ldb"), b"old table").unwrap();
+    let (snapshots, journal) = (Snapshots::new(state.path().join("snapshots")), Journal::new(state.path().join("journal")));
+    let mut files = FileManager::new(worlds.path().to_path_buf()).with_snapshots(snapshots.clone()).with_journal(journal.clone());
+    files.scan_directory().unwrap();
+
+    files.receive_file(Path::new("Skyblock/level.dat"), b"new level").unwrap();
+    files.receive_file(Path::new("Skyblock/level.dat"), b"newer level").unwrap();
+    files.receive_file(Path::new("Skyblock/db/000006.ldb"), b"another table").unwrap();
+    files.remove_received(Path::new("Skyblock/db/000005.ldb")).unwrap();
+    // A local edit after the burst is not part of it
+    fs::write(worlds.path().join("Skyblock/world_icon.jpeg"), b"icon").unwrap();
+
+    let last = journal.last("Skyblock").unwrap().unwrap();
+    assert_eq!(last.changes.len(), 3);
+    assert_eq!(last.changes[0].path, Path::new("level.dat"));
+    assert!(last.changes[0].before.is_some() && last.changes[1].before.is_none() && last.changes[2].after.is_none());
+
+    journal.undo(worlds.path(), &snapshots, "Skyblock", SystemTime::now()).unwrap();
+    assert_eq!(read(worlds.path().join("Skyblock/level.dat")), "old level");
+    assert_eq!(read(worlds.path().join("Skyblock/db/000005.ldb")), "old table");
+    assert!(!worlds.path().join("Skyblock/db/000006.ldb").exists());
+    assert_eq!(read(worlds.path().join("Skyblock/world_icon.jpeg")), "icon");
+    // The state before the undo is kept as a snapshot, and there is nothing more to undo
+    assert!(snapshots.list("Skyblock").unwrap().iter().any(|s| s.reason == "undo"));
+    assert!(journal.undo(worlds.path(), &snapshots, "Skyblock", SystemTime::now()).is_err());
+
+    // Changes received after an undo start a new change set
+    files.receive_file(Path::new("Skyblock/level.dat"), b"again").unwrap();
+    assert_eq!(journal.last("Skyblock").unwrap().unwrap().changes.len(), 1);
+}
+
+#[test]
+fn undoing_a_new_world_removes_what_arrived() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    let (snapshots, journal) = (Snapshots::new(state.path().join("snapshots")), Journal::new(state.path().join("journal")));
+    let mut files = FileManager::new(worlds.path().to_path_buf()).with_snapshots(snapshots.clone()).with_journal(journal.clone());
+
+    files.receive_file(Path::new("Farm/level.dat"), b"farm").unwrap();
+    files.receive_file(Path::new("Farm/db/CURRENT"), b"MANIFEST-000001").unwrap();
+    assert_eq!(journal.last("Farm").unwrap().unwrap().snapshot, None);
+
+    journal.undo(worlds.path(), &snapshots, "Farm", SystemTime::now()).unwrap();
+    assert!(!worlds.path().join("Farm/level.dat").exists());
+    assert!(!worlds.path().join("Farm/db/CURRENT").exists());
+    assert!(journal.undo(worlds.path(), &snapshots, "../Farm", SystemTime::now()).is_err());
+}
+
+#[test]
+fn replay_rebuilds_the_world_after_any_change_set() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(worlds.path().join("Skyblock/db")).unwrap();
+    fs::write(worlds.path().join("Skyblock/level.dat"), b"old level").unwrap();
+    fs::write(worlds.path().join("Skyblock/db/000005.ldb"), b"old table").unwrap();
+    let (snapshots, journal) = (Snapshots::new(state.path().join("snapshots")), Journal::new(state.path().join("journal")));
+    let mut files = FileManager::new(worlds.path().to_path_buf()).with_snapshots(snapshots.clone()).with_journal(journal.clone());
+    files.scan_directory().unwrap();
+
+    files.receive_file(Path::new("Skyblock/level.dat"), b"new level").unwrap();
+    files.receive_file(Path::new("Skyblock/db/000006.ldb"), b"another table").unwrap();
+    journal.undo(worlds.path(), &snapshots, "Skyblock", SystemTime::now()).unwrap();
+    files.receive_file(Path::new("Skyblock/level.dat"), b"third level").unwrap();
+
+    let sets = journal.change_sets("Skyblock").unwrap();
+    assert_eq!(sets.iter().map(|s| (s.seq, s.undoes)).collect::<Vec<_>>(), [(1, None), (2, Some(1)), (3, None)]);
+
+    let replays = tempfile::TempDir::new().unwrap();
+    let first = replays.path().join("first");
+    journal.replay(&snapshots, "Skyblock", 1, &first).unwrap();
+    assert_eq!(read(first.join("level.dat")), "new level");
+    assert_eq!(read(first.join("db/000005.ldb")), "old table");
+    assert_eq!(read(first.join("db/000006.ldb")), "another table");
+
+    let undone = replays.path().join("undone");
+    journal.replay(&snapshots, "Skyblock", 2, &undone).unwrap();
+    assert_eq!(read(undone.join("level.dat")), "old level");
+    assert!(!undone.join("db/000006.ldb").exists());
+
+    let third = replays.path().join("third");
+    journal.replay(&snapshots, "Skyblock", 3, &third).unwrap();
+    assert_eq!(read(third.join("level.dat")), "third level");
+
+    assert!(journal.replay(&snapshots, "Skyblock", 3, &third).is_err());
+    assert!(journal.replay(&snapshots, "Skyblock", 4, &replays.path().join("fourth")).is_err());
+}
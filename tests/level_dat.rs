@@ -0,0 +1,110 @@
+//! `level.dat` changed on two devices, merged tag by tag from the version
+//! both started from.
+
+use mcbd_world_sync::conflicts::{ConflictPolicy, ConflictResolution, Resolution};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::{self, FileManager};
+use mcbd_world_sync::journal::{Change, Journal};
+use mcbd_world_sync::level_dat::{self, LevelDat};
+use mcbd_world_sync::nbt::{self, Tag};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// A `level.dat` with a game rule, a spawn point and the time it was last
+/// played.
+fn level(keep_inventory: i8, spawn_x: i32, last_played: i64) -> Vec<u8> {
+    LevelDat {
+        storage_version: 10,
+        name: String::new(),
+        root: Tag::Compound(vec![
+            ("LevelName".to_string(), Tag::String("Survival".to_string())),
+            ("keepinventory".to_string(), Tag::Byte(keep_inventory)),
+            ("SpawnX".to_string(), Tag::Int(spawn_x)),
+            ("LastPlayed".to_string(), Tag::Long(last_played)),
+            ("abilities".to_string(), Tag::Compound(vec![("flySpeed".to_string(), Tag::Float(0.05))])),
+            ("lastOpenedWithVersion".to_string(), Tag::List(vec![Tag::Int(1), Tag::Int(21)])),
+        ]),
+    }.write()
+}
+
+fn fields(data: &[u8]) -> Tag {
+    LevelDat::read(data).unwrap().root
+}
+
+#[test]
+fn nbt_reads_back_what_it_writes() {
+    let tag = Tag::Compound(vec![
+        ("bytes".to_string(), Tag::ByteArray(vec![1, 2])),
+        ("ints".to_string(), Tag::IntArray(vec![-1, 7])),
+        ("longs".to_string(), Tag::LongArray(vec![i64::MAX])),
+        ("empty".to_string(), Tag::List(Vec::new())),
+        ("nested".to_string(), Tag::Compound(vec![("d".to_string(), Tag::Double(0.5)), ("s".to_string(), Tag::Short(-3))])),
+    ]);
+    assert_eq!(nbt::read(&nbt::write("root", &tag)).unwrap(), ("root".to_string(), tag));
+
+    let data = level(0, 4, 100);
+    assert_eq!(LevelDat::read(&data).unwrap().write(), data);
+    assert!(LevelDat::read(&data[..data.len() - 1]).is_err());
+}
+
+#[test]
+fn changes_to_different_tags_are_all_kept() {
+    let base = level(0, 0, 100);
+    // A game rule changed on one device, the spawn moved on the other
+    let (ours, theirs) = (level(1, 0, 200), level(0, 64, 300));
+    let merged = level_dat::merge(&base, &ours, &theirs, true).unwrap();
+    assert_eq!(merged.conflicts, ["LastPlayed"]);
+    assert_eq!(fields(&merged.content), fields(&level(1, 64, 300)));
+    // The other device merges the other way round and comes to the same file
+    assert_eq!(level_dat::merge(&base, &theirs, &ours, false).unwrap().content, merged.content);
+
+    let merged = level_dat::merge(&base, &ours, &level(2, 64, 300), false).unwrap();
+    assert_eq!(merged.conflicts, ["keepinventory", "LastPlayed"]);
+    assert_eq!(fields(&merged.content), fields(&level(1, 64, 200)));
+}
+
+#[test]
+fn added_and_removed_tags_are_merged() {
+    let base = level(0, 0, 100);
+    let Tag::Compound(mut added) = fields(&base) else { unreachable!() };
+    added.push(("newRule".to_string(), Tag::Byte(1)));
+    let ours = LevelDat { storage_version: 10, name: String::new(), root: Tag::Compound(added) }.write();
+    let Tag::Compound(mut removed) = fields(&base) else { unreachable!() };
+    removed.retain(|(name, _)| name != "abilities");
+    let theirs = LevelDat { storage_version: 10, name: String::new(), root: Tag::Compound(removed) }.write();
+
+    let merged = fields(&level_dat::merge(&base, &ours, &theirs, false).unwrap().content);
+    assert_eq!(merged.get("newRule"), Some(&Tag::Byte(1)));
+    assert_eq!(merged.get("abilities"), None);
+}
+
+#[test]
+fn received_level_dat_is_merged_from_the_last_version_received() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let (worlds, journal) = (dir.path().join("worlds"), Journal::new(dir.path().join("journal")));
+    fs::create_dir_all(worlds.join("Survival/db")).unwrap();
+    let base = level(0, 0, 100);
+    let set = journal.begin("Survival", None, None, SystemTime::now()).unwrap();
+    let change = Change { path: PathBuf::from("level.dat"), before: None, after: Some(file_manager::hash_bytes(&base)) };
+    journal.record("Survival", set, change, Some(&base)).unwrap();
+    fs::write(worlds.join("Survival/level.dat"), level(1, 0, 200)).unwrap();
+
+    let policy = ConflictPolicy::new(ConflictResolution::Newest);
+    let mut files = FileManager::new(worlds.clone()).with_exclusions(Exclusions::new(&[])).with_journal(journal).with_conflicts(policy, "desktop".to_string());
+    files.scan_directory().unwrap();
+    let path = Path::new("Survival/level.dat");
+    let theirs = level(0, 64, 300);
+    let info = file_manager::FileInfo { path: path.to_path_buf(), last_modified: SystemTime::now() + Duration::from_secs(60), size: theirs.len() as u64, hash: file_manager::hash_bytes(&theirs) };
+    let resolution = files.resolve_conflict(path, "laptop", Some(&info), true).unwrap();
+    assert_eq!(resolution, Resolution::Merge { theirs_wins: true });
+    assert!(files.receive_resolved(path, &resolution, &theirs).unwrap());
+    assert_eq!(fields(&fs::read(worlds.join("Survival/level.dat")).unwrap()), fields(&level(1, 64, 300)));
+
+    // Without a base, the newer version is taken whole
+    fs::create_dir_all(worlds.join("Creative/db")).unwrap();
+    fs::write(worlds.join("Creative/level.dat"), level(1, 0, 200)).unwrap();
+    files.scan_directory().unwrap();
+    assert!(files.receive_merged(Path::new("Creative/level.dat"), &theirs, true).unwrap());
+    assert_eq!(fs::read(worlds.join("Creative/level.dat")).unwrap(), theirs);
+}
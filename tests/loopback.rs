@@ -0,0 +1,126 @@
+//! End-to-end tests: two daemon processes on localhost syncing temp directories.
+
+mod common;
+
+use common::daemon::{assert_converged, free_port, spawn_pair, tree_contents, TestDaemon};
+use common::fixtures::{mutate, FixtureBuilder, FixtureRng, Mutation, WorldSpec};
+use std::fs;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+/// Values of `sync.conflict_resolution` after which both devices have the
+/// same worlds; `manual` leaves the choice to the user.
+const STRATEGIES: &[&str] = &["newest", "largest", "prefer:a", "keep_both"];
+
+fn small_world(seed: u64) -> FixtureBuilder {
+    FixtureBuilder::new(seed).world(WorldSpec::new("Loopback").ldb(2, 8 * 1024))
+}
+
+#[test]
+fn daemons_start_and_accept_connections() {
+    let (a, b) = spawn_pair("newest");
+    assert!(TcpStream::connect(("127.0.0.1", a.port)).is_ok());
+    assert!(TcpStream::connect(("127.0.0.1", b.port)).is_ok());
+}
+
+#[test]
+fn created_and_modified_files_converge() {
+    let (a, b) = spawn_pair("newest");
+    let world = small_world(1).build(&a.worlds).remove(0);
+    assert_converged(&a, &b);
+
+    let mut rng = FixtureRng::new(11);
+    for mutation in [Mutation::AppendLog, Mutation::RewriteLdbRange, Mutation::NewLdb, Mutation::TouchLevelDat] {
+        mutate(&world, mutation, &mut rng);
+    }
+    assert_converged(&a, &b);
+}
+
+#[test]
+fn changes_in_a_polled_directory_converge() {
+    let port_a = free_port();
+    let port_b = std::iter::repeat_with(free_port).find(|p| *p != port_a).unwrap();
+    let mut a = TestDaemon::new("a", port_a, port_b, "newest").with_polling();
+    let mut b = TestDaemon::new("b", port_b, port_a, "newest");
+    a.start();
+    b.start();
+    let world = small_world(6).build(&a.worlds).remove(0);
+    assert_converged(&a, &b);
+    assert!(a.log().contains("Scanning directory for changes every 1s"), "{}", a.log());
+
+    let mut rng = FixtureRng::new(12);
+    for mutation in [Mutation::AppendLog, Mutation::NewLdb, Mutation::TouchLevelDat] {
+        mutate(&world, mutation, &mut rng);
+    }
+    assert_converged(&a, &b);
+}
+
+#[test]
+fn a_seed_without_minecraft_receives_and_serves_worlds() {
+    let port_a = free_port();
+    let port_b = std::iter::repeat_with(free_port).find(|p| *p != port_a).unwrap();
+    let mut a = TestDaemon::new("a", port_a, port_b, "newest");
+    let mut seed = TestDaemon::new("seed", port_b, port_a, "newest").with_seed();
+    a.start();
+    seed.start();
+    assert!(seed.worlds.is_dir());
+    let world = small_world(7).build(&a.worlds).remove(0);
+    assert_converged(&a, &seed);
+
+    // Changes made on the seed reach the device too
+    let name = world.file_name().unwrap();
+    mutate(&seed.worlds.join(name), Mutation::NewLdb, &mut FixtureRng::new(13));
+    assert_converged(&a, &seed);
+}
+
+#[test]
+fn deletes_converge() {
+    let (a, b) = spawn_pair("newest");
+    // Deleting the last world would look like an unmounted worlds directory
+    let worlds = small_world(2).world(WorldSpec::new("Kept").ldb(1, 4 * 1024)).build(&a.worlds);
+    let world = &worlds[0];
+    assert_converged(&a, &b);
+
+    mutate(world, Mutation::DeleteLdb, &mut FixtureRng::new(3));
+    assert_converged(&a, &b);
+
+    fs::remove_dir_all(world).unwrap();
+    assert_converged(&a, &b);
+    let name = world.file_name().unwrap();
+    assert!(tree_contents(&b.worlds).keys().all(|path| !path.starts_with(name)));
+    assert!(!b.worlds.join(name).exists(), "{}", b.log());
+}
+
+#[test]
+fn renames_converge() {
+    let (a, b) = spawn_pair("newest");
+    let world = small_world(4).build(&a.worlds).remove(0);
+    assert_converged(&a, &b);
+
+    fs::rename(&world, a.worlds.join("Renamed World")).unwrap();
+    assert_converged(&a, &b);
+    // Moved on B too, rather than deleted and sent again
+    assert!(a.log().contains("to Renamed World on b"), "{}", a.log());
+}
+
+#[test]
+fn conflicts_converge_under_each_strategy() {
+    for strategy in STRATEGIES {
+        let (mut a, mut b) = spawn_pair(strategy);
+        let world = small_world(5).build(&a.worlds).remove(0);
+        assert_converged(&a, &b);
+        let name = world.file_name().unwrap().to_owned();
+
+        // Both sides edit the same file while disconnected
+        a.stop();
+        b.stop();
+        fs::write(a.worlds.join(&name).join("levelname.txt"), "Edited on A").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        fs::write(b.worlds.join(&name).join("levelname.txt"), "Edited on B").unwrap();
+        a.start();
+        b.start();
+
+        assert_converged(&a, &b);
+    }
+}
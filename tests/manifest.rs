@@ -0,0 +1,190 @@
+//! Manifest deltas against what each peer acknowledged last.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::correlation::{self, CorrelationId};
+use mcbd_world_sync::file_manager::{FileInfo, FileManager};
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::manifest::{self, Manifest, ManifestCache, ManifestEntry, ManifestSent};
+use mcbd_world_sync::network::{SyncClient, SyncMessage, SyncServer};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+fn manifest(files: &[(&str, &str)]) -> Manifest {
+    Manifest::new(files.iter().map(|(path, hash)| (PathBuf::from(path), ManifestEntry { hash: hash.to_string(), size: 1 })).collect())
+}
+
+async fn start_server() -> String {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(port).with_health(health.clone());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    format!("127.0.0.1:{}", port)
+}
+
+#[test]
+fn delta_carries_only_changes_and_applies_to_its_base() {
+    let base = manifest(&[("w/level.dat", "a"), ("w/db/1.ldb", "b"), ("w/db/2.ldb", "c")]);
+    let next = manifest(&[("w/level.dat", "a2"), ("w/db/1.ldb", "b"), ("w/db/3.ldb", "d")]);
+
+    let delta = next.delta_from(&base);
+    let changed: Vec<_> = delta.changed.keys().cloned().collect();
+    assert_eq!(changed, vec![PathBuf::from("w/db/3.ldb"), PathBuf::from("w/level.dat")]);
+    assert_eq!(delta.removed, vec![PathBuf::from("w/db/2.ldb")]);
+    assert_eq!(base.apply(&delta).unwrap(), next);
+    assert!(next.apply(&delta).is_err());
+
+    // Versions depend only on content
+    assert_eq!(manifest(&[]).version, Manifest::new(BTreeMap::new()).version);
+    assert_ne!(base.version, next.version);
+}
+
+#[tokio::test]
+async fn current_manifest_is_rebuilt_only_after_changes() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let file_manager = Mutex::new(FileManager::new(dir.path().to_path_buf()));
+    let index = file_manager.lock().await.index();
+    let cache = ManifestCache::new();
+
+    let first = cache.current(&index).await;
+    assert!(Arc::ptr_eq(&first, &cache.current(&index).await));
+
+    let path = PathBuf::from("w/level.dat");
+    let mut guard = file_manager.lock().await;
+    guard.update_file_info(path.clone(), FileInfo { path, last_modified: SystemTime::now(), size: 3, hash: "x".to_string() });
+    // Built while the manager is still busy, as during a long scan
+    let second = tokio::time::timeout(Duration::from_secs(5), cache.current(&index)).await.unwrap();
+    assert!(!Arc::ptr_eq(&first, &second));
+    assert_eq!(second.entries.len(), 1);
+    drop(guard);
+}
+
+#[tokio::test]
+async fn reconnects_send_deltas_and_fall_back_to_full() {
+    let address = start_server().await;
+    let cache = ManifestCache::new();
+    let v1 = Arc::new(manifest(&[("w/level.dat", "a"), ("w/db/1.ldb", "b")]));
+    let v2 = Arc::new(manifest(&[("w/level.dat", "a2"), ("w/db/1.ldb", "b")]));
+
+    let mut session = SyncClient::new(address.clone()).session().await.unwrap();
+    assert_eq!(manifest::exchange(&mut session, &cache, "laptop", "hub", v1.clone()).await.unwrap(), ManifestSent::Full);
+
+    let mut session = SyncClient::new(address.clone()).session().await.unwrap();
+    assert_eq!(manifest::exchange(&mut session, &cache, "laptop", "hub", v2.clone()).await.unwrap(), ManifestSent::Delta);
+
+    // Nothing changed since the last exchange
+    let mut session = SyncClient::new(address.clone()).session().await.unwrap();
+    assert_eq!(manifest::exchange(&mut session, &cache, "laptop", "hub", v2.clone()).await.unwrap(), ManifestSent::Unchanged);
+
+    // A restarted sender lost its cache, but the hub still has the same manifest
+    let mut session = SyncClient::new(address).session().await.unwrap();
+    assert_eq!(manifest::exchange(&mut session, &ManifestCache::new(), "laptop", "hub", v2.clone()).await.unwrap(), ManifestSent::Unchanged);
+
+    // A restarted hub has nothing and asks for everything
+    let restarted = start_server().await;
+    let mut session = SyncClient::new(restarted).session().await.unwrap();
+    assert_eq!(manifest::exchange(&mut session, &cache, "laptop", "hub", v2).await.unwrap(), ManifestSent::Full);
+}
+
+#[tokio::test]
+async fn cursors_survive_a_restart_of_the_sender() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let cursors = dir.path().join("cursors.json");
+    let address = start_server().await;
+    let v1 = Arc::new(manifest(&[("w/level.dat", "a"), ("w/db/1.ldb", "b")]));
+    let v2 = Arc::new(manifest(&[("w/level.dat", "a2"), ("w/db/1.ldb", "b")]));
+
+    let cache = ManifestCache::load(cursors.clone());
+    let mut session = SyncClient::new(address.clone()).session().await.unwrap();
+    assert_eq!(manifest::exchange(&mut session, &cache, "laptop", "hub", v1.clone()).await.unwrap(), ManifestSent::Full);
+    cache.flush().await;
+    drop(cache);
+
+    let restarted = ManifestCache::load(cursors.clone());
+    assert_eq!(restarted.cursor("hub").await.unwrap().manifest.version, v1.version);
+    let mut session = SyncClient::new(address).session().await.unwrap();
+    assert_eq!(manifest::exchange(&mut session, &restarted, "laptop", "hub", v2.clone()).await.unwrap(), ManifestSent::Delta);
+    restarted.flush().await;
+    assert_eq!(ManifestCache::load(cursors).cursor("hub").await.unwrap().manifest.version, v2.version);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cursors_are_written_in_the_background_latest_last() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let cursors = dir.path().join("cursors.json");
+    let address = start_server().await;
+    let cache = ManifestCache::load(cursors.clone());
+    let versions: Vec<_> = (0..20).map(|i| Arc::new(manifest(&[("w/level.dat", &i.to_string())]))).collect();
+    for version in &versions {
+        cache.acknowledge("hub", version.clone(), None).await;
+        cache.acknowledge("tablet", version.clone(), None).await;
+    }
+    cache.flush().await;
+    let restarted = ManifestCache::load(cursors);
+    assert_eq!(restarted.cursors().await.len(), 2);
+    assert_eq!(restarted.cursor("hub").await.unwrap().manifest.version, versions[19].version);
+
+    // This hub never saw the written cursor, so it gets everything once and deltas from then on
+    let mut session = SyncClient::new(address.clone()).session().await.unwrap();
+    assert_eq!(manifest::exchange(&mut session, &restarted, "laptop", "hub", versions[0].clone()).await.unwrap(), ManifestSent::Full);
+    let changed = Arc::new(manifest(&[("w/level.dat", "changed"), ("w/db/1.ldb", "b")]));
+    let mut session = SyncClient::new(address).session().await.unwrap();
+    assert_eq!(manifest::exchange(&mut session, &restarted, "laptop", "hub", changed).await.unwrap(), ManifestSent::Delta);
+}
+
+#[test]
+fn generation_ignores_identical_updates() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut file_manager = FileManager::new(dir.path().to_path_buf());
+    let path = PathBuf::from("w/level.dat");
+    let info = FileInfo { path: path.clone(), last_modified: SystemTime::now(), size: 3, hash: "x".to_string() };
+
+    file_manager.update_file_info(path.clone(), info.clone());
+    let generation = file_manager.generation();
+    file_manager.update_file_info(path, info);
+    assert_eq!(file_manager.generation(), generation);
+}
+
+#[tokio::test]
+async fn acks_announce_the_receivers_retention() {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(port).with_health(health.clone()).with_tombstone_retention(90);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let cache = ManifestCache::new();
+    let v1 = Arc::new(manifest(&[("w/level.dat", "a")]));
+
+    let mut session = SyncClient::new(format!("127.0.0.1:{}", port)).session().await.unwrap();
+    manifest::exchange(&mut session, &cache, "laptop", "hub", v1.clone()).await.unwrap();
+    assert_eq!(cache.cursor("hub").await.unwrap().peer_retention_days, Some(90));
+
+    // Nothing is exchanged with a peer marked for a reconcile
+    cache.require_reconcile("hub").await;
+    let mut session = SyncClient::new(format!("127.0.0.1:{}", port)).session().await.unwrap();
+    assert!(manifest::exchange(&mut session, &cache, "laptop", "hub", v1).await.is_err());
+}
+
+#[tokio::test]
+async fn manifest_replies_carry_the_senders_correlation_id() {
+    let address = start_server().await;
+    let v1 = Arc::new(manifest(&[("w/level.dat", "a")]));
+    let id = CorrelationId::new();
+    let mut session = SyncClient::new(address).session().await.unwrap();
+    correlation::scope(id.clone(), manifest::exchange(&mut session, &ManifestCache::new(), "laptop", "hub", v1.clone())).await.unwrap();
+
+    // Once the hub has the manifest, probing it is acknowledged under the probe's ID
+    let probe = SyncMessage::ManifestProbe { device: "laptop".to_string(), version: v1.version.clone(), correlation_id: Some(id.clone()) };
+    session.send(&probe).await.unwrap();
+    let Some(ack @ SyncMessage::ManifestAck { .. }) = session.recv().await else { panic!("no ack") };
+    assert_eq!(ack.correlation_id(), Some(&id));
+}
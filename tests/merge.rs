@@ -0,0 +1,92 @@
+//! Merging two copies of a world that changed different chunks, record by
+//! record, from the copy both started from.
+
+mod common;
+
+use common::leveldb::{chunk_key, log};
+use mcbd_world_sync::leveldb;
+use mcbd_world_sync::merge;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn sub_chunk(x: i32, z: i32, y: u8) -> Vec<u8> {
+    chunk_key(x, z, None, 47, Some(y))
+}
+
+/// A copy of the world in `root`: the shared history, then its own changes.
+fn copy(root: &Path, name: &str, changes: &[(&[u8], Option<&[u8]>)]) -> PathBuf {
+    let world = root.join(name);
+    fs::create_dir_all(world.join("db")).unwrap();
+    fs::write(world.join("db/000003.log"), log(1, &[
+        (&sub_chunk(0, 0, 0), Some(b"base")),
+        (&sub_chunk(0, 0, 1), Some(b"base")),
+        (&sub_chunk(5, 5, 0), Some(b"base")),
+        (&sub_chunk(7, 7, 0), Some(b"base")),
+        (&sub_chunk(7, 7, 1), Some(b"base")),
+        (b"~local_player", Some(b"base")),
+    ])).unwrap();
+    fs::write(world.join("db/MANIFEST-000004"), b"").unwrap();
+    if !changes.is_empty() {
+        fs::write(world.join("db/000005.log"), log(10, changes)).unwrap();
+    }
+    world
+}
+
+#[test]
+fn changes_to_different_sub_chunks_are_merged() {
+    let root = tempfile::TempDir::new().unwrap();
+    let base = copy(root.path(), "base", &[]);
+    let ours = copy(root.path(), "ours", &[
+        (&sub_chunk(0, 0, 0), Some(b"ours")),
+        (&sub_chunk(7, 7, 0), Some(b"ours")),
+        (b"~local_player", Some(b"ours")),
+    ]);
+    let theirs = copy(root.path(), "theirs", &[
+        // Same chunk as ours, another sub-chunk
+        (&sub_chunk(0, 0, 1), Some(b"theirs")),
+        (&sub_chunk(5, 5, 0), None),
+        // Both changed this sub-chunk, so nothing of the chunk is taken
+        (&sub_chunk(7, 7, 0), Some(b"theirs")),
+        (&sub_chunk(7, 7, 1), Some(b"theirs")),
+        (b"~local_player", Some(b"theirs")),
+        (b"map_1", Some(b"theirs")),
+    ]);
+
+    let plan = merge::plan(&base, &ours, &theirs).unwrap();
+    assert_eq!(plan.conflicting_chunks.iter().collect::<Vec<_>>(), [&(7, 7, 0)]);
+    assert_eq!(plan.conflicting_keys, ["~local_player"]);
+    let taken: Vec<(Vec<u8>, Option<Vec<u8>>)> = plan.take.clone().into_iter().collect();
+    assert_eq!(taken, [
+        (sub_chunk(0, 0, 1), Some(b"theirs".to_vec())),
+        (sub_chunk(5, 5, 0), None),
+        (b"map_1".to_vec(), Some(b"theirs".to_vec())),
+    ]);
+
+    let written = merge::apply(&ours, &plan).unwrap().unwrap();
+    assert!(written.ends_with("000006.log"));
+    let merged = leveldb::read_keeping(&ours.join("db"), &|_| true).unwrap();
+    let value = |key: &[u8]| merged.keys.get(key).and_then(|entry| entry.value.clone());
+    assert_eq!(value(&sub_chunk(0, 0, 0)).as_deref(), Some(&b"ours"[..]));
+    assert_eq!(value(&sub_chunk(0, 0, 1)).as_deref(), Some(&b"theirs"[..]));
+    assert_eq!(value(&sub_chunk(5, 5, 0)), None);
+    assert_eq!(value(&sub_chunk(7, 7, 1)).as_deref(), Some(&b"base"[..]));
+    assert_eq!(value(b"~local_player").as_deref(), Some(&b"ours"[..]));
+    assert_eq!(value(b"map_1").as_deref(), Some(&b"theirs"[..]));
+    assert!(merged.damaged.is_empty());
+}
+
+#[test]
+fn large_merges_span_log_blocks() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let path = dir.path().join("000001.log");
+    let writes: Vec<(Vec<u8>, Option<Vec<u8>>)> = (0..40u8).map(|n| (vec![n], Some(vec![n; 5000]))).collect();
+    leveldb::write_log(&path, 100, &writes).unwrap();
+    assert!(fs::metadata(&path).unwrap().len() > 6 * 32 * 1024);
+
+    let read = leveldb::read_keeping(dir.path(), &|_| true).unwrap();
+    assert!(read.damaged.is_empty());
+    assert_eq!(read.keys.len(), 40);
+    assert_eq!(read.keys[&vec![39]].seq, 139);
+    assert_eq!(read.keys[&vec![39]].value.as_deref(), Some(&[39; 5000][..]));
+    assert!(leveldb::write_log(&path, 200, &writes).is_err());
+}
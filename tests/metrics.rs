@@ -0,0 +1,57 @@
+//! Transfer counters exported on `/metrics` in the Prometheus text format.
+
+mod common;
+
+use common::http;
+use common::receiver::{files, start_receiver};
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::network::SyncClient;
+use mcbd_world_sync::transfer_queue::{Priority, TransferQueue};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+#[tokio::test]
+async fn recorded_transfers_are_exported_as_counters() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let metrics = Arc::new(Metrics::new());
+    let queue = TransferQueue::new(metrics.clone());
+    queue.push("laptop".to_string(), PathBuf::from("World/level.dat"), "Modify".to_string()).await;
+    queue.push("laptop".to_string(), PathBuf::from("World/level.dat"), "Modify".to_string()).await;
+
+    // Recorded the way the sender loop does once a transfer is delivered
+    let address = start_receiver(Arc::new(Mutex::new(files(dir.path().to_path_buf()))), |server| server).await;
+    let content = vec![7u8; 100 * 1024];
+    let sent = SyncClient::new(address).send_file_content(PathBuf::from("World/level.dat"), content.clone(), None, Priority::Background).await.unwrap();
+    metrics.record_sent(&sent);
+    Metrics::inc(&metrics.transfers_sent);
+
+    let port = http::serve(http::state(dir.path(), metrics.clone())).await;
+    let (status, body) = http::get(port, "/metrics").await;
+    assert_eq!(status, 200);
+    let body = String::from_utf8(body).unwrap();
+
+    // Every counter has its help and type, then one sample
+    let mut samples = BTreeMap::new();
+    let mut lines = body.lines();
+    while let Some(help) = lines.next() {
+        let name = help.strip_prefix("# HELP ").unwrap().split(' ').next().unwrap();
+        assert!(name.starts_with("mcbd_") && name.ends_with("_total"), "{}", name);
+        assert_eq!(lines.next().unwrap(), format!("# TYPE {} counter", name));
+        let (sample, value) = lines.next().unwrap().split_once(' ').unwrap();
+        assert_eq!(sample, name);
+        samples.insert(name.to_string(), value.parse::<u64>().unwrap());
+    }
+    assert_eq!(samples.len(), metrics.counters().len());
+
+    assert_eq!(samples["mcbd_transfers_queued_total"], 1);
+    assert_eq!(samples["mcbd_duplicates_suppressed_total"], 1);
+    assert_eq!(samples["mcbd_transfers_sent_total"], 1);
+    assert_eq!(samples["mcbd_transfers_failed_total"], 0);
+    assert_eq!(samples["mcbd_file_bytes_sent_total"], content.len() as u64);
+    assert_eq!(samples["mcbd_content_bytes_sent_total"], sent.carried);
+    assert_eq!(samples["mcbd_message_bytes_sent_total"], sent.encoded);
+    assert_eq!(samples["mcbd_wire_bytes_sent_total"], sent.wire);
+    assert!(sent.wire > 0);
+}
@@ -0,0 +1,101 @@
+//! Local mirrors: a second folder kept an exact copy of the worlds
+//! directory, fed by the same queue as the devices.
+
+mod common;
+
+use common::daemon::{assert_converged, free_port, tree_contents, wait_until, TestDaemon, CONVERGE_TIMEOUT};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::mirror::Mirror;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[test]
+fn mirrors_copy_changes_and_delete_what_is_gone() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    fs::create_dir_all(worlds.join("w/db")).unwrap();
+    fs::write(worlds.join("w/level.dat"), "level").unwrap();
+    fs::write(worlds.join("w/db/1.ldb"), "chunks").unwrap();
+    let mut files = FileManager::new(worlds.clone()).with_exclusions(Exclusions::new(&[]));
+    files.scan_directory().unwrap();
+    let index = files.index();
+    let mirror = Mirror::new("backup".to_string(), dir.path().join("backup")).with_hard_links(false);
+
+    // A new mirror misses everything, and only what is in the mirror is extra
+    let mut missed = mirror.differences(&files.entries()).unwrap();
+    missed.sort();
+    assert_eq!(missed, ["w/db/1.ldb", "w/level.dat"].map(PathBuf::from));
+    for path in &missed {
+        assert!(mirror.apply(&index, path).unwrap());
+    }
+    assert_eq!(tree_contents(&mirror.root), tree_contents(&worlds));
+    assert!(mirror.differences(&files.entries()).unwrap().is_empty());
+    // Unchanged copies are left alone
+    assert!(!mirror.apply(&index, Path::new("w/level.dat")).unwrap());
+
+    // Whatever differs in the mirror is overwritten or deleted
+    fs::write(mirror.root.join("w/level.dat"), "edited in the mirror").unwrap();
+    fs::write(mirror.root.join("w/stray.txt"), "stray").unwrap();
+    let mut missed = mirror.differences(&files.entries()).unwrap();
+    missed.sort();
+    assert_eq!(missed, ["w/level.dat", "w/stray.txt"].map(PathBuf::from));
+    for path in &missed {
+        mirror.apply(&index, path).unwrap();
+    }
+    assert_eq!(tree_contents(&mirror.root), tree_contents(&worlds));
+
+    fs::remove_dir_all(worlds.join("w")).unwrap();
+    assert!(mirror.apply(&index, Path::new("w")).unwrap());
+    assert!(!mirror.root.join("w").exists());
+    assert!(mirror.apply(&index, Path::new("../outside")).is_err());
+}
+
+#[test]
+fn mirrors_on_the_same_volume_link_files() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    fs::create_dir_all(worlds.join("w")).unwrap();
+    fs::write(worlds.join("w/level.dat"), "level").unwrap();
+    let files = FileManager::new(worlds.clone()).with_exclusions(Exclusions::new(&[]));
+    let index = files.index();
+    let mirror = Mirror::new("backup".to_string(), dir.path().join("backup"));
+    assert!(mirror.apply(&index, Path::new("w/level.dat")).unwrap());
+    assert!(mirror.links());
+
+    // Written in place, the original and the link are the same file
+    fs::OpenOptions::new().append(true).open(worlds.join("w/level.dat")).unwrap().write_all(b", appended").unwrap();
+    assert_eq!(fs::read(mirror.root.join("w/level.dat")).unwrap(), b"level, appended");
+    assert!(!mirror.apply(&index, Path::new("w/level.dat")).unwrap());
+
+    // Replaced, as received files are, the link is made again
+    fs::write(dir.path().join("new"), "replaced").unwrap();
+    fs::rename(dir.path().join("new"), worlds.join("w/level.dat")).unwrap();
+    assert!(mirror.apply(&index, Path::new("w/level.dat")).unwrap());
+    assert_eq!(fs::read(mirror.root.join("w/level.dat")).unwrap(), b"replaced");
+    fs::OpenOptions::new().append(true).open(worlds.join("w/level.dat")).unwrap().write_all(b"!").unwrap();
+    assert_eq!(fs::read(mirror.root.join("w/level.dat")).unwrap(), b"replaced!");
+}
+
+#[test]
+fn the_daemon_mirrors_local_and_received_changes() {
+    let port_a = free_port();
+    let port_b = std::iter::repeat_with(free_port).find(|p| *p != port_a).unwrap();
+    let mut a = TestDaemon::new("a", port_a, port_b, "newest").with_mirror();
+    let mut b = TestDaemon::new("b", port_b, port_a, "newest");
+    let backup = a.dir.path().join("backup");
+    // Left there before the daemon started, caught up with on startup
+    fs::create_dir_all(backup.join("Old")).unwrap();
+    fs::write(backup.join("Old/level.dat"), "old").unwrap();
+    a.start();
+    b.start();
+    assert!(wait_until(CONVERGE_TIMEOUT, || !backup.join("Old").join("level.dat").exists()), "{}", a.log());
+
+    fs::create_dir_all(a.worlds.join("Alpha")).unwrap();
+    fs::write(a.worlds.join("Alpha/level.dat"), "alpha").unwrap();
+    fs::create_dir_all(b.worlds.join("Beta")).unwrap();
+    fs::write(b.worlds.join("Beta/level.dat"), "beta").unwrap();
+    assert_converged(&a, &b);
+    assert!(wait_until(CONVERGE_TIMEOUT, || tree_contents(&backup) == tree_contents(&a.worlds)), "{}", a.log());
+}
@@ -0,0 +1,174 @@
+//! Status published to an MQTT broker, with Home Assistant discovery.
+
+use mcbd_world_sync::aging::DeviceStatus;
+use mcbd_world_sync::config::MqttConfig;
+use mcbd_world_sync::home_assistant::{DaemonStatus, Message, Publisher, WorldStatus};
+use mcbd_world_sync::mqtt::{ConnectOptions, MqttClient, Will};
+use mcbd_world_sync::reconnect::PeerState;
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+fn config() -> MqttConfig {
+    MqttConfig {
+        broker: "localhost".to_string(),
+        username: None,
+        password: None,
+        topic: "mcbd-world-sync".to_string(),
+        discovery_prefix: "homeassistant".to_string(),
+        interval: 30,
+    }
+}
+
+fn world(folder: &str, queued: usize) -> WorldStatus {
+    WorldStatus { folder: folder.to_string(), name: format!("{} name", folder), files: 3, size: 3000, queued, open: false, hash_pending: 0 }
+}
+
+fn device(name: &str, state: PeerState) -> DeviceStatus {
+    DeviceStatus {
+        name: name.to_string(),
+        last_synced: Some(1_728_000_000),
+        stale: false,
+        paused: false,
+        needs_reconcile: false,
+        queued: 0,
+        in_flight: 0,
+        offline_since: None,
+        last_seen: None,
+        state,
+        failed: Vec::new(),
+    }
+}
+
+fn status(worlds: Vec<WorldStatus>) -> DaemonStatus {
+    DaemonStatus { gaming_mode: false, transfers_sent: 10, transfers_failed: 1, devices: vec![device("Desktop PC", PeerState::Offline)], worlds }
+}
+
+fn payload(messages: &[Message], topic: &str) -> Value {
+    let message = messages.iter().find(|message| message.topic == topic).unwrap_or_else(|| panic!("no message on {}", topic));
+    serde_json::from_str(&message.payload).unwrap()
+}
+
+#[test]
+fn status_is_published_with_discovery_payloads() {
+    let mut publisher = Publisher::new(&config(), "LAPTOP");
+    let messages = publisher.messages(&status(vec![world("Ab+c=", 2), world("Other", 0)]));
+
+    assert_eq!(messages[0].topic, "mcbd-world-sync/LAPTOP/availability");
+    assert_eq!(messages[0].payload, "online");
+    let state = payload(&messages, "mcbd-world-sync/LAPTOP/state");
+    assert_eq!((state["queued"].as_u64(), state["size"].as_u64(), state["devices_online"].as_u64()), (Some(2), Some(6000), Some(0)));
+
+    let world_state = payload(&messages, "mcbd-world-sync/LAPTOP/world/Ab_c_");
+    assert_eq!(world_state["state"], "syncing");
+    assert_eq!(world_state["name"], "Ab+c= name");
+    let world_sensor = payload(&messages, "homeassistant/sensor/mcbd_world_sync_LAPTOP/world_Ab_c_/config");
+    assert_eq!(world_sensor["state_topic"], "mcbd-world-sync/LAPTOP/world/Ab_c_");
+    assert_eq!(world_sensor["availability_topic"], "mcbd-world-sync/LAPTOP/availability");
+    assert_eq!(world_sensor["device"]["identifiers"][0], "mcbd_world_sync_LAPTOP");
+    assert_eq!(payload(&messages, "mcbd-world-sync/LAPTOP/world/Other")["state"], "synced");
+
+    let peer = payload(&messages, "mcbd-world-sync/LAPTOP/device/Desktop_PC");
+    assert_eq!(peer["state"], "offline");
+    assert_eq!(peer["last_synced"], "2024-10-04T00:00:00+00:00");
+    let connected = payload(&messages, "homeassistant/binary_sensor/mcbd_world_sync_LAPTOP/device_Desktop_PC_connected/config");
+    assert_eq!(connected["device_class"], "connectivity");
+    assert_eq!(connected["name"], "Desktop PC connected");
+    let gaming = payload(&messages, "homeassistant/binary_sensor/mcbd_world_sync_LAPTOP/gaming_mode/config");
+    assert_eq!(gaming["value_template"], "{{ value_json.gaming_mode }}");
+}
+
+#[test]
+fn sensors_of_removed_worlds_are_cleared() {
+    let mut publisher = Publisher::new(&config(), "LAPTOP");
+    publisher.messages(&status(vec![world("Gone", 0), world("Kept", 0)]));
+    let messages = publisher.messages(&status(vec![world("Kept", 0)]));
+
+    let cleared: Vec<&str> = messages.iter().filter(|message| message.payload.is_empty()).map(|message| message.topic.as_str()).collect();
+    assert_eq!(cleared, vec!["homeassistant/sensor/mcbd_world_sync_LAPTOP/world_Gone/config", "mcbd-world-sync/LAPTOP/world/Gone"]);
+    // Cleared once, not on every update
+    assert!(publisher.messages(&status(vec![world("Kept", 0)])).iter().all(|message| !message.payload.is_empty()));
+}
+
+#[test]
+fn open_worlds_show_as_open_before_anything_else() {
+    let open = WorldStatus { open: true, hash_pending: 2, ..world("World", 5) };
+    assert_eq!(open.state(), "open");
+    assert_eq!(WorldStatus { hash_pending: 2, ..world("World", 5) }.state(), "scanning");
+}
+
+async fn read_packet(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let kind = stream.read_u8().await.unwrap();
+    let (mut length, mut shift) = (0usize, 0);
+    loop {
+        let byte = stream.read_u8().await.unwrap();
+        length |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0u8; length];
+    stream.read_exact(&mut body).await.unwrap();
+    (kind, body)
+}
+
+fn read_str(body: &[u8], at: &mut usize) -> String {
+    let length = u16::from_be_bytes([body[*at], body[*at + 1]]) as usize;
+    let value = String::from_utf8(body[*at + 2..*at + 2 + length].to_vec()).unwrap();
+    *at += 2 + length;
+    value
+}
+
+#[tokio::test]
+async fn client_connects_with_a_will_and_publishes_retained() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let broker = listener.local_addr().unwrap().to_string();
+    let server = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (kind, connect) = read_packet(&mut stream).await;
+        assert_eq!(kind, 0x10);
+        stream.write_all(&[0x20, 0x02, 0x00, 0x00]).await.unwrap();
+        let (kind, publish) = read_packet(&mut stream).await;
+        (connect, kind, publish)
+    });
+
+    let options = ConnectOptions {
+        client_id: "sync".to_string(),
+        username: Some("user".to_string()),
+        password: Some("pass".to_string()),
+        keep_alive: 90,
+        will: Some(Will { topic: "base/availability".to_string(), payload: "offline".to_string() }),
+    };
+    let mut client = MqttClient::connect(&broker, &options).await.unwrap();
+    let payload = "x".repeat(300);
+    client.publish("base/state", payload.as_bytes(), true).await.unwrap();
+
+    let (connect, kind, publish) = server.await.unwrap();
+    let mut at = 0;
+    assert_eq!(read_str(&connect, &mut at), "MQTT");
+    // Level 4, username, password, retained will and clean session
+    assert_eq!(&connect[at..at + 4], &[4, 0x80 | 0x40 | 0x20 | 0x04 | 0x02, 0, 90]);
+    at += 4;
+    let fields: Vec<String> = (0..5).map(|_| read_str(&connect, &mut at)).collect();
+    assert_eq!(fields, vec!["sync", "base/availability", "offline", "user", "pass"]);
+
+    assert_eq!(kind, 0x31);
+    let mut at = 0;
+    assert_eq!(read_str(&publish, &mut at), "base/state");
+    assert_eq!(&publish[at..], payload.as_bytes());
+}
+
+#[tokio::test]
+async fn refused_credentials_fail_the_connection() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let broker = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        read_packet(&mut stream).await;
+        stream.write_all(&[0x20, 0x02, 0x00, 0x05]).await.unwrap();
+    });
+    let options = ConnectOptions { client_id: "sync".to_string(), username: None, password: None, keep_alive: 60, will: None };
+    let error = MqttClient::connect(&broker, &options).await.err().unwrap().to_string();
+    assert!(error.contains("refused the username or password"), "{}", error);
+}
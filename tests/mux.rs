@@ -0,0 +1,128 @@
+//! Control and bulk channels sharing one connection.
+
+mod common;
+
+use common::daemon::free_port;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::mux::{self, Channel, Reassembler, BULK_CHUNK, CONTROL_STREAM, MAX_STREAMS};
+use mcbd_world_sync::platform::Edition;
+use mcbd_world_sync::network::{PeerInfo, SyncClient, SyncMessage, SyncServer, CAPABILITY_DELTA, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::bytes::{Buf, Bytes, BytesMut};
+
+#[tokio::test]
+async fn queued_control_messages_go_before_bulk_chunks() {
+    let (tx, rx) = mpsc::unbounded();
+    let sender = mux::spawn_writer(tx, None);
+    let bulk: Bytes = (0..BULK_CHUNK * 3 + 10).map(|i| i as u8).collect::<Vec<_>>().into();
+    sender.send(Channel::Bulk, bulk.clone()).await.unwrap();
+    sender.send(Channel::Control, Bytes::from_static(b"ping")).await.unwrap();
+    drop(sender);
+
+    let frames: Vec<Bytes> = rx.collect().await;
+    assert_eq!(frames.len(), 5);
+    assert_eq!(frames[0].clone().get_u32(), CONTROL_STREAM);
+
+    let mut reassembler = Reassembler::new();
+    let messages: Vec<_> = frames
+        .into_iter()
+        .filter_map(|frame| reassembler.push(BytesMut::from(&frame[..])).unwrap())
+        .collect();
+    assert_eq!(messages, vec![(Channel::Control, Bytes::from_static(b"ping")), (Channel::Bulk, bulk)]);
+}
+
+#[test]
+fn oversized_and_too_many_streams_are_refused() {
+    let mut reassembler = Reassembler::new().with_max_message(100);
+    assert!(reassembler.push(BytesMut::from(&mux::encode(1, false, &[0; 60])[..])).unwrap().is_none());
+    let error = reassembler.push(BytesMut::from(&mux::encode(1, false, &[0; 60])[..])).unwrap_err();
+    assert!(error.to_string().contains("larger than 100 bytes"), "{}", error);
+    assert!(reassembler.push(BytesMut::from(&mux::encode(2, true, &[0; 101])[..])).is_err());
+    assert_eq!(reassembler.push(BytesMut::from(&mux::encode(2, true, &[0; 100])[..])).unwrap().unwrap().1.len(), 100);
+
+    let mut reassembler = Reassembler::new();
+    for stream in 1..=MAX_STREAMS as u32 {
+        reassembler.push(BytesMut::from(&mux::encode(stream, false, b"head")[..])).unwrap();
+    }
+    let error = reassembler.push(BytesMut::from(&mux::encode(MAX_STREAMS as u32 + 1, false, b"head")[..])).unwrap_err();
+    assert!(error.to_string().contains("unfinished"), "{}", error);
+    // Streams already open still finish
+    assert_eq!(reassembler.push(BytesMut::from(&mux::encode(1, true, b" tail")[..])).unwrap(), Some((Channel::Bulk, Bytes::from_static(b"head tail"))));
+}
+
+#[tokio::test]
+async fn session_answers_lookups_while_file_content_is_in_flight() {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(port).with_health(health.clone());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let mut session = SyncClient::new(format!("127.0.0.1:{}", port)).session().await.unwrap();
+    let content = SyncMessage::FileContent { path: PathBuf::from("db/000005.ldb"), content: vec![7; 4 * 1024 * 1024], group: None, modified: None, version: None, correlation_id: None };
+    assert_eq!(content.channel(), Channel::Bulk);
+    session.send(&content).await.unwrap();
+    session.send(&SyncMessage::RendezvousRegister { id: "hub".to_string(), port: 9000 }).await.unwrap();
+    session.send(&SyncMessage::RendezvousLookup { id: "hub".to_string() }).await.unwrap();
+
+    let reply = tokio::time::timeout(Duration::from_secs(10), session.recv()).await.unwrap();
+    match reply {
+        Some(SyncMessage::RendezvousAddress { address, .. }) => assert_eq!(address.as_deref(), Some("127.0.0.1:9000")),
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn session_learns_the_peer_from_its_hello() {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(port).with_health(health.clone()).with_device_name("desktop".to_string());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let session = SyncClient::new(format!("127.0.0.1:{}", port)).session().await.unwrap();
+    assert_eq!(session.peer().protocol, PROTOCOL_VERSION);
+    assert_eq!(session.peer().device.as_deref(), Some("desktop"));
+    assert!(session.peer().supports(CAPABILITY_DELTA));
+}
+
+#[tokio::test]
+async fn sessions_only_join_devices_of_the_same_edition() {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(port).with_health(health.clone()).with_device_name("desktop".to_string()).with_edition(Some(Edition::Preview));
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let address = format!("127.0.0.1:{}", port);
+    let Err(error) = SyncClient::new(address.clone()).with_edition(Some(Edition::Release)).session().await else { panic!("joined a Minecraft Preview device") };
+    assert!(error.to_string().contains("desktop syncs Minecraft Preview worlds"), "{}", error);
+    SyncClient::new(address.clone()).with_edition(Some(Edition::Preview)).session().await.unwrap();
+    // A device that does not know its edition, such as a seed, syncs with any
+    SyncClient::new(address).session().await.unwrap();
+}
+
+#[test]
+fn peers_without_a_common_protocol_are_refused() {
+    // A Hello from before versions were exchanged is version 1 with every feature of its time
+    let legacy: SyncMessage = serde_json::from_str(r#"{"Hello":{"codecs":["lz4"]}}"#).unwrap();
+    let SyncMessage::Hello { protocol, min_protocol, device, capabilities, .. } = legacy else { panic!("not a Hello") };
+    let peer = PeerInfo::negotiate(protocol, min_protocol, device, capabilities).unwrap();
+    assert_eq!(peer, PeerInfo::legacy());
+    assert!(peer.supports(CAPABILITY_DELTA));
+
+    // A newer peer that still speaks this version is met at it
+    assert_eq!(PeerInfo::negotiate(PROTOCOL_VERSION + 3, MIN_PROTOCOL_VERSION, None, Some(Vec::new())).unwrap().protocol, PROTOCOL_VERSION);
+    let error = PeerInfo::negotiate(PROTOCOL_VERSION + 3, PROTOCOL_VERSION + 1, Some("laptop".to_string()), None).unwrap_err();
+    assert!(error.to_string().contains("laptop speaks sync protocol"), "{}", error);
+}
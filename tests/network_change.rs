@@ -0,0 +1,48 @@
+//! Devices are tried again and manifests exchanged as soon as the network
+//! changed.
+
+use mcbd_world_sync::network_change::{self, NetworkChanges, SyncTicker};
+use std::time::Duration;
+
+#[test]
+fn loopback_does_not_count_as_a_network() {
+    assert!(network_change::local_addresses().iter().all(|address| !address.is_loopback()));
+}
+
+#[tokio::test]
+async fn everything_waiting_hears_of_a_change() {
+    let network = NetworkChanges::new();
+    let waiting: Vec<_> = (0..2).map(|_| {
+        let network = network.clone();
+        tokio::spawn(async move { network.changed().await })
+    }).collect();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    network.notify();
+    for waiter in waiting {
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn the_sync_interval_is_cut_short_by_a_change() {
+    let network = NetworkChanges::new();
+    let mut ticker = SyncTicker::new(Duration::from_secs(3600), Some(network.clone()));
+    assert!(!ticker.tick().await);
+
+    let notifier = network.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        notifier.notify();
+    });
+    assert!(tokio::time::timeout(Duration::from_secs(1), ticker.tick()).await.unwrap());
+
+    // Without following changes only the interval ticks
+    let mut ticker = SyncTicker::new(Duration::from_secs(3600), None);
+    assert!(!ticker.tick().await);
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        network.notify();
+    });
+    assert!(tokio::time::timeout(Duration::from_millis(200), ticker.tick()).await.is_err());
+}
@@ -0,0 +1,98 @@
+//! Pairing two devices with a one-time code instead of editing their
+//! configuration by hand.
+
+mod common;
+
+use common::daemon::free_port;
+use futures::{SinkExt, StreamExt};
+use mcbd_world_sync::config::{Config, TlsConfig};
+use mcbd_world_sync::network::SyncMessage;
+use mcbd_world_sync::pairing::{self, PairedDevice};
+use rustls::crypto::ring::kx_group::X25519;
+use tokio::net::TcpListener;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+fn config(name: &str) -> Config {
+    serde_json::from_value(serde_json::json!({
+        "server": { "port": 0, "host": "127.0.0.1" },
+        "sync": { "name": name, "devices": [], "conflict_resolution": "newest", "sync_interval": 60 },
+        "paths": { "minecraft_worlds": "worlds" }
+    })).unwrap()
+}
+
+fn device(name: &str, port: u16, fingerprint: Option<&str>) -> PairedDevice {
+    PairedDevice { name: name.to_string(), port, fingerprint: fingerprint.map(str::to_string) }
+}
+
+#[test]
+fn codes_are_short_and_readable() {
+    let code = pairing::new_code().unwrap();
+    assert_eq!(code.len(), 9);
+    assert_eq!(&code[4..5], "-");
+    assert!(code.chars().all(|c| c == '-' || (c.is_ascii_alphanumeric() && !"01IO".contains(c) && !c.is_ascii_lowercase())));
+    assert_ne!(code, pairing::new_code().unwrap());
+}
+
+#[tokio::test]
+async fn both_devices_learn_each_other_and_share_a_key() {
+    let port = free_port();
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    let code = pairing::new_code().unwrap();
+    let accepting = {
+        let code = code.clone();
+        tokio::spawn(async move { pairing::accept(&listener, &code, &device("desktop", 7070, Some("ab12"))).await })
+    };
+    // Typed in lower case and without the dash
+    let typed = code.replace('-', "").to_lowercase();
+    let joined = pairing::join(&format!("127.0.0.1:{}", port), &typed, &device("laptop", 8080, None)).await.unwrap();
+    let accepted = accepting.await.unwrap().unwrap();
+
+    assert_eq!(joined.device, device("desktop", 7070, Some("ab12")));
+    assert_eq!(joined.address, "127.0.0.1:7070");
+    assert_eq!(accepted.device, device("laptop", 8080, None));
+    assert_eq!(accepted.address, "127.0.0.1:8080");
+    assert_eq!(joined.key, accepted.key);
+
+    let mut laptop = config("laptop");
+    laptop.tls = Some(TlsConfig::default());
+    pairing::add(&mut laptop, &joined).unwrap();
+    assert_eq!(laptop.sync.devices[0].name, "desktop");
+    assert_eq!(laptop.sync.devices[0].key, Some(joined.key.clone()));
+    assert_eq!(laptop.tls.as_ref().unwrap().trusted, ["ab12"]);
+    // Pairing again updates the device instead of adding it twice
+    pairing::add(&mut laptop, &joined).unwrap();
+    assert_eq!(laptop.sync.devices.len(), 1);
+    assert!(pairing::add(&mut config("desktop"), &joined).is_err());
+}
+
+#[tokio::test]
+async fn a_wrong_code_pairs_nobody() {
+    let port = free_port();
+    let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+    let accepting = tokio::spawn(async move { pairing::accept(&listener, "ABCD-EFGH", &device("desktop", 7070, None)).await });
+    assert!(pairing::join(&format!("127.0.0.1:{}", port), "ABCD-EFGJ", &device("laptop", 8080, None)).await.is_err());
+    assert!(accepting.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn joiners_reveal_nothing_to_a_device_that_does_not_prove_the_code() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let joining = tokio::spawn(async move { pairing::join(&address, "ABCD-EFGH", &device("laptop", 8080, None)).await });
+
+    // Someone in the middle completes the key exchange
+    let mut framed = Framed::new(listener.accept().await.unwrap().0, LengthDelimitedCodec::new());
+    let frame = framed.next().await.unwrap().unwrap();
+    assert!(matches!(serde_json::from_slice(&frame).unwrap(), SyncMessage::PairKey { .. }));
+    let exchange = X25519.start().unwrap();
+    let key = SyncMessage::PairKey { public_key: exchange.pub_key().to_vec() };
+    framed.send(Bytes::from(serde_json::to_vec(&key).unwrap())).await.unwrap();
+
+    // It only gets a commitment, nothing to try codes against, and no time
+    let frame = framed.next().await.unwrap().unwrap();
+    assert!(matches!(serde_json::from_slice(&frame).unwrap(), SyncMessage::PairCommit { .. }));
+    tokio::time::pause();
+    let error = joining.await.unwrap().unwrap_err();
+    assert!(error.to_string().contains("did not prove"), "{}", error);
+}
@@ -0,0 +1,68 @@
+//! Tuning for weak and fast devices: parallel hashing and several transfer
+//! streams per peer.
+
+use mcbd_world_sync::config::{Config, PerformanceConfig};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::transfer_queue::{stream_of, TransferQueue};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[test]
+fn performance_settings_default_to_the_previous_behavior() {
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "server": { "port": 0, "host": "127.0.0.1" },
+        "sync": { "devices": [], "conflict_resolution": "newest", "sync_interval": 60 },
+        "paths": { "minecraft_worlds": "worlds" },
+        "performance": { "worker_threads": 2, "hash_workers": 4 }
+    })).unwrap();
+    assert_eq!(config.performance.worker_threads, Some(2));
+    assert_eq!(config.performance.hash_workers, 4);
+    assert_eq!(config.performance.transfer_streams, 1);
+    assert_eq!(config.performance.blocking_threads, PerformanceConfig::default().blocking_threads);
+    assert_eq!(config.performance.max_transfers, None);
+}
+
+#[test]
+fn files_hashed_in_parallel_match_a_single_worker() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for world in ["a", "b", "c"] {
+        fs::create_dir_all(dir.path().join(world).join("db")).unwrap();
+        for n in 0..10 {
+            fs::write(dir.path().join(world).join(format!("db/{}.ldb", n)), format!("{} {}", world, n).repeat(n + 1)).unwrap();
+        }
+    }
+    let scan = |workers| {
+        let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[])).with_hash_workers(workers);
+        let mut scanned: Vec<(PathBuf, String)> = files.scan_directory().unwrap().into_iter().map(|file| (file.path, file.hash)).collect();
+        scanned.sort();
+        scanned
+    };
+    let single = scan(1);
+    assert_eq!(single.len(), 30);
+    assert_eq!(scan(4), single);
+    assert_eq!(scan(64), single);
+}
+
+#[tokio::test]
+async fn each_path_is_sent_by_one_stream() {
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    let paths: Vec<PathBuf> = (0..20).map(|n| PathBuf::from(format!("w/db/{}.ldb", n))).collect();
+    for path in &paths {
+        queue.push("laptop".to_string(), path.clone(), "Modify".to_string()).await;
+    }
+    assert!(paths.iter().any(|path| stream_of(path, 2) == 0) && paths.iter().any(|path| stream_of(path, 2) == 1));
+
+    for stream in 0..2 {
+        let expected = paths.iter().filter(|path| stream_of(path, 2) == stream).count();
+        for _ in 0..expected {
+            let transfer = queue.pop_for_stream("laptop", stream, 2).await;
+            assert_eq!(stream_of(&transfer.path, 2), stream);
+        }
+        assert!(tokio::time::timeout(Duration::from_millis(50), queue.pop_for_stream("laptop", stream, 2)).await.is_err());
+    }
+    assert_eq!(queue.len().await, 0);
+}
@@ -0,0 +1,84 @@
+//! Where Minecraft keeps its worlds on Windows, Linux and macOS.
+
+use mcbd_world_sync::platform::{self, edition_of, installations_for, worlds_dirs_for, Edition, Installation};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn worlds(data: &Path) -> PathBuf {
+    data.join("games/com.mojang/minecraftWorlds")
+}
+
+#[test]
+fn windows_has_the_store_package_and_one_folder_per_account() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let roaming = dir.path().join("Roaming");
+    fs::create_dir_all(worlds(&roaming.join("Minecraft Bedrock/Users/2535"))).unwrap();
+    fs::create_dir_all(roaming.join("Minecraft Bedrock/Users/Shared/games")).unwrap();
+    let env = |key: &str| match key {
+        "LOCALAPPDATA" => Some("C:/Users/steve/AppData/Local".to_string()),
+        "APPDATA" => Some(roaming.to_string_lossy().into_owned()),
+        _ => None,
+    };
+    assert_eq!(worlds_dirs_for("windows", env)[..2], [
+        worlds(Path::new("C:/Users/steve/AppData/Local/Packages/Microsoft.MinecraftUWP_8wekyb3d8bbwe/LocalState")),
+        worlds(&roaming.join("Minecraft Bedrock/Users/2535")),
+    ]);
+
+    let env = |key: &str| (key == "USERPROFILE").then(|| "C:/Users/alex".to_string());
+    assert_eq!(worlds_dirs_for("windows", env)[0], worlds(Path::new("C:/Users/alex/AppData/Local/Packages/Microsoft.MinecraftUWP_8wekyb3d8bbwe/LocalState")));
+}
+
+#[test]
+fn preview_and_education_are_found_after_the_release() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let roaming = dir.path().join("Roaming");
+    fs::create_dir_all(worlds(&roaming.join("Minecraft Bedrock Preview/Users/2535"))).unwrap();
+    let env = |key: &str| match key {
+        "LOCALAPPDATA" => Some("C:/Users/steve/AppData/Local".to_string()),
+        "APPDATA" => Some(roaming.to_string_lossy().into_owned()),
+        _ => None,
+    };
+    let packages = Path::new("C:/Users/steve/AppData/Local/Packages");
+    assert_eq!(installations_for("windows", env), vec![
+        Installation { edition: Edition::Release, worlds: worlds(&packages.join("Microsoft.MinecraftUWP_8wekyb3d8bbwe/LocalState")) },
+        Installation { edition: Edition::Preview, worlds: worlds(&packages.join("Microsoft.MinecraftWindowsBeta_8wekyb3d8bbwe/LocalState")) },
+        Installation { edition: Edition::Preview, worlds: worlds(&roaming.join("Minecraft Bedrock Preview/Users/2535")) },
+        Installation { edition: Edition::Education, worlds: worlds(&packages.join("Microsoft.MinecraftEducationEdition_8wekyb3d8bbwe/LocalState")) },
+        Installation { edition: Edition::Education, worlds: worlds(&roaming.join("Minecraft Education Edition")) },
+    ]);
+    assert!(installations_for("linux", |key| (key == "HOME").then(|| "/home/steve".to_string())).iter().all(|i| i.edition == Edition::Release));
+
+    // Configured paths tell their edition the same way
+    for installation in installations_for("windows", env) {
+        assert_eq!(edition_of(&installation.worlds), Some(installation.edition));
+    }
+    assert_eq!(edition_of(Path::new("/data/worlds")), None);
+}
+
+#[test]
+fn linux_and_macos_use_the_launcher_data_folder() {
+    let home = |key: &str| (key == "HOME").then(|| "/home/steve".to_string());
+    assert_eq!(worlds_dirs_for("linux", home), vec![
+        worlds(Path::new("/home/steve/.local/share/mcpelauncher")),
+        worlds(Path::new("/home/steve/.var/app/io.mrarm.mcpelauncher/data/mcpelauncher")),
+    ]);
+    let xdg = |key: &str| match key {
+        "HOME" => Some("/home/steve".to_string()),
+        "XDG_DATA_HOME" => Some("/data".to_string()),
+        _ => None,
+    };
+    assert_eq!(worlds_dirs_for("linux", xdg)[0], worlds(Path::new("/data/mcpelauncher")));
+    assert_eq!(worlds_dirs_for("macos", home), vec![worlds(Path::new("/home/steve/Library/Application Support/mcpelauncher"))]);
+
+    assert!(worlds_dirs_for("linux", |_| None).is_empty());
+    assert!(worlds_dirs_for("freebsd", home).is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn configured_paths_expand_the_home_directory() {
+    let home = std::env::var("HOME").unwrap();
+    assert_eq!(platform::expand("~/worlds"), Path::new(&home).join("worlds"));
+    assert_eq!(platform::expand("$HOME/worlds"), Path::new(&home).join("worlds"));
+    assert_eq!(platform::expand("/srv/worlds"), Path::new("/srv/worlds"));
+}
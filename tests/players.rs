@@ -0,0 +1,96 @@
+//! Player data read from two copies of a world, to tell which copy was
+//! played more when both changed.
+
+mod common;
+
+use common::leveldb::log;
+use mcbd_world_sync::nbt::{self, Tag};
+use mcbd_world_sync::players;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn name(out: &mut Vec<u8>, name: &str) {
+    out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn item(out: &mut Vec<u8>, item: &str, count: i8) {
+    out.push(8);
+    name(out, "Name");
+    name(out, item);
+    out.push(1);
+    name(out, "Count");
+    out.push(count as u8);
+    out.push(0);
+}
+
+/// Player data the way Bedrock stores it, cut down to the fields read.
+fn player(x: f32, y: f32, z: f32, level: i32, items: &[(&str, i8)]) -> Vec<u8> {
+    let mut out = vec![10];
+    name(&mut out, "");
+    out.push(9);
+    name(&mut out, "Pos");
+    out.push(5);
+    out.extend_from_slice(&3i32.to_le_bytes());
+    for n in [x, y, z] {
+        out.extend_from_slice(&n.to_le_bytes());
+    }
+    out.push(3);
+    name(&mut out, "DimensionId");
+    out.extend_from_slice(&1i32.to_le_bytes());
+    out.push(3);
+    name(&mut out, "PlayerLevel");
+    out.extend_from_slice(&level.to_le_bytes());
+    out.push(9);
+    name(&mut out, "Inventory");
+    out.push(10);
+    out.extend_from_slice(&(items.len() as i32).to_le_bytes());
+    for (name, count) in items {
+        item(&mut out, name, *count);
+    }
+    out.push(0);
+    out
+}
+
+fn world(root: &Path, copy: &str, seq: u64, writes: &[(&[u8], Option<&[u8]>)]) -> PathBuf {
+    let world = root.join(copy);
+    fs::create_dir_all(world.join("db")).unwrap();
+    fs::write(world.join("db/000003.log"), log(seq, writes)).unwrap();
+    world
+}
+
+#[test]
+fn player_data_is_read_from_nbt() {
+    let (_, root) = nbt::read(&player(10.5, 64.0, -3.2, 7, &[("minecraft:dirt", 32), ("", 0), ("minecraft:torch", 5)])).unwrap();
+    assert_eq!(root.get("PlayerLevel"), Some(&Tag::Int(7)));
+
+    let dir = tempfile::TempDir::new().unwrap();
+    let data = player(10.5, 64.0, -3.2, 7, &[("minecraft:dirt", 32), ("", 0), ("minecraft:torch", 5)]);
+    let copy = world(dir.path(), "World", 5, &[(b"~local_player", Some(&data)), (b"Overworld", Some(b"other"))]);
+    let read = players::read(&copy).unwrap();
+    assert_eq!(read.len(), 1);
+    assert_eq!((read[0].key.as_str(), read[0].seq, read[0].level, read[0].items), ("~local_player", 5, Some(7), Some(37)));
+    assert_eq!(read[0].to_string(), "at 10, 64, -3 in the nether, level 7, 37 items");
+
+    let unreadable = world(dir.path(), "Broken", 5, &[(b"~local_player", Some(b"not nbt"))]);
+    assert_eq!(players::read(&unreadable).unwrap()[0].to_string(), "unreadable player data");
+}
+
+#[test]
+fn the_copy_that_wrote_a_player_later_is_newer() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let (explored, idle) = (player(900.0, 70.0, 40.0, 12, &[("minecraft:diamond", 3)]), player(0.0, 64.0, 0.0, 11, &[]));
+    let shared = player(5.0, 64.0, 5.0, 2, &[]);
+    // The desktop played on for many more writes, the laptop only opened the world
+    let desktop = world(dir.path(), "desktop", 400, &[(b"~local_player", Some(&explored)), (b"player_friend", Some(&shared))]);
+    let laptop = world(dir.path(), "laptop", 120, &[(b"~local_player", Some(&idle)), (b"player_friend", Some(&shared)), (b"player_guest", Some(&shared))]);
+
+    let differences = players::differences(&players::read(&laptop).unwrap(), &players::read(&desktop).unwrap());
+    let keys: Vec<&str> = differences.iter().map(|d| d.key.as_str()).collect();
+    assert_eq!(keys, ["player_guest", "~local_player"]);
+    assert_eq!(differences[0].b_is_newer(), None);
+    assert_eq!(differences[1].b_is_newer(), Some(true));
+    assert_eq!(differences[1].describe("laptop", "desktop"),
+        "~local_player: newer on desktop (at 900, 70, 40 in the nether, level 12, 3 items) than on laptop (at 0, 64, 0 in the nether, level 11, 0 items)");
+    assert!(differences[0].describe("laptop", "desktop").starts_with("player_guest: only on laptop"));
+}
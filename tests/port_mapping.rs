@@ -0,0 +1,65 @@
+//! Forwarding listener ports on the router with NAT-PMP, against a fake
+//! gateway on the loopback interface.
+
+use mcbd_world_sync::config::PortMappingConfig;
+use mcbd_world_sync::port_mapping::{self, Mapping, Mappings, Method, Protocol};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// Answers NAT-PMP requests like a router with external address
+/// 203.0.113.7 that hands out `external_port`, or fails with `result`.
+async fn fake_gateway(external_port: u16, result: u16) -> String {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut buf = [0u8; 12];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+            let mut response = vec![0, buf[1] + 128];
+            response.extend_from_slice(&result.to_be_bytes());
+            response.extend_from_slice(&1000u32.to_be_bytes());
+            match buf[1] {
+                0 => response.extend_from_slice(&[203, 0, 113, 7]),
+                _ => {
+                    assert_eq!(len, 12);
+                    response.extend_from_slice(&buf[4..6]);
+                    response.extend_from_slice(&external_port.to_be_bytes());
+                    response.extend_from_slice(&buf[8..12]);
+                }
+            }
+            socket.send_to(&response, peer).await.unwrap();
+        }
+    });
+    address
+}
+
+fn nat_pmp_only(gateway: String) -> PortMappingConfig {
+    PortMappingConfig { upnp: false, gateway: Some(gateway), ..PortMappingConfig::default() }
+}
+
+#[tokio::test]
+async fn nat_pmp_reports_the_external_address() {
+    let config = nat_pmp_only(fake_gateway(40123, 0).await);
+    let mapping = port_mapping::map(Protocol::Tcp, 8080, &config).await.unwrap();
+    assert_eq!(mapping, Mapping { method: Method::NatPmp, protocol: Protocol::Tcp, port: 8080, external: "203.0.113.7:40123".parse().unwrap() });
+
+    let refused = nat_pmp_only(fake_gateway(40123, 2).await);
+    assert!(port_mapping::map(Protocol::Udp, 8443, &refused).await.is_err());
+    let disabled = PortMappingConfig { upnp: false, nat_pmp: false, ..PortMappingConfig::default() };
+    assert!(port_mapping::map(Protocol::Tcp, 8080, &disabled).await.is_err());
+}
+
+#[tokio::test]
+async fn every_listener_port_stays_mapped() {
+    let config = nat_pmp_only(fake_gateway(8080, 0).await);
+    let mappings = Mappings::default();
+    tokio::spawn(port_mapping::keep_mapped(vec![(Protocol::Tcp, 8080), (Protocol::Udp, 8443)], config, mappings.clone()));
+    for _ in 0..100 {
+        if !mappings.current().is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let current: Vec<(Protocol, String)> = mappings.current().into_iter().map(|m| (m.protocol, m.external.to_string())).collect();
+    assert_eq!(current, [(Protocol::Tcp, "203.0.113.7:8080".to_string()), (Protocol::Udp, "203.0.113.7:8080".to_string())]);
+}
@@ -0,0 +1,80 @@
+//! Interactive transfers overtaking background ones, in the queue and on the
+//! wire, and world metadata overtaking the rest of a save.
+
+use futures::channel::mpsc;
+use futures::StreamExt;
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::mux::{self, Channel, Reassembler, BULK_CHUNK};
+use mcbd_world_sync::transfer_queue::{is_metadata, Priority, TransferQueue};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_util::bytes::{Bytes, BytesMut};
+
+#[tokio::test]
+async fn interactive_transfers_are_popped_first() {
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    for path in ["a/db/1.ldb", "b/db/2.ldb", "c/level.dat"] {
+        queue.push("peer".to_string(), PathBuf::from(path), "Modify".to_string()).await;
+    }
+    queue.push_with_priority("peer".to_string(), PathBuf::from("world/level.dat"), "SyncNow".to_string(), Priority::Interactive).await;
+    // Raising an already queued path moves it to the interactive lane
+    queue.push_with_priority("peer".to_string(), PathBuf::from("c/level.dat"), "SyncNow".to_string(), Priority::Interactive).await;
+
+    let mut order = Vec::new();
+    for _ in 0..4 {
+        order.push(queue.pop().await.path);
+    }
+    let expected: Vec<PathBuf> = ["c/level.dat", "world/level.dat", "a/db/1.ldb", "b/db/2.ldb"].iter().map(PathBuf::from).collect();
+    assert_eq!(order, expected);
+}
+
+#[tokio::test]
+async fn transfers_asked_to_ignore_limits_go_before_interactive_ones() {
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    queue.push_with_priority("peer".to_string(), PathBuf::from("a/level.dat"), "SyncNow".to_string(), Priority::Interactive).await;
+    queue.push_with_priority("laptop".to_string(), PathBuf::from("a/level.dat"), "SyncNow".to_string(), Priority::Urgent).await;
+    queue.push("laptop".to_string(), PathBuf::from("a/db/1.ldb"), "Modify".to_string()).await;
+
+    let first = queue.pop().await;
+    assert_eq!((first.peer.as_str(), first.priority), ("laptop", Priority::Urgent));
+    assert_eq!(queue.pop().await.priority, Priority::Interactive);
+    assert_eq!(queue.pop().await.priority, Priority::Background);
+}
+
+#[tokio::test]
+async fn world_metadata_goes_before_the_rest_of_a_save() {
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    for path in ["w/db/1.ldb", "w/db/MANIFEST-000002", "w/level.dat", "w/db/2.ldb", "w/levelname.txt"] {
+        queue.push("peer".to_string(), PathBuf::from(path), "Modify".to_string()).await;
+    }
+    queue.push_with_priority("peer".to_string(), PathBuf::from("v/db/3.ldb"), "SyncNow".to_string(), Priority::Interactive).await;
+    assert!(is_metadata(&PathBuf::from("w/level.dat")) && !is_metadata(&PathBuf::from("w/db/level.ldb")));
+
+    let mut order = Vec::new();
+    for _ in 0..6 {
+        order.push(queue.pop().await.path);
+    }
+    let expected: Vec<PathBuf> = ["v/db/3.ldb", "w/level.dat", "w/levelname.txt", "w/db/1.ldb", "w/db/MANIFEST-000002", "w/db/2.ldb"].iter().map(PathBuf::from).collect();
+    assert_eq!(order, expected);
+}
+
+#[tokio::test]
+async fn interactive_bulk_pauses_a_background_stream() {
+    let (tx, mut rx) = mpsc::channel(0);
+    let sender = mux::spawn_writer(tx, None);
+    let background = Bytes::from(vec![1u8; BULK_CHUNK * 8]);
+    let interactive = Bytes::from(vec![2u8; BULK_CHUNK / 2]);
+
+    sender.send_bulk(Priority::Background, background.clone()).await.unwrap();
+    let mut frames = vec![rx.next().await.unwrap()];
+    sender.send_bulk(Priority::Interactive, interactive.clone()).await.unwrap();
+    drop(sender);
+    frames.extend(rx.collect::<Vec<Bytes>>().await);
+
+    let mut reassembler = Reassembler::new();
+    let messages: Vec<_> = frames
+        .into_iter()
+        .filter_map(|frame| reassembler.push(BytesMut::from(&frame[..])).unwrap())
+        .collect();
+    assert_eq!(messages, vec![(Channel::Bulk, interactive), (Channel::Bulk, background)]);
+}
@@ -0,0 +1,81 @@
+//! Devices that went offline: retry waits, status and queued changes sent
+//! once they are back.
+
+use mcbd_world_sync::aging::DeviceAging;
+use mcbd_world_sync::manifest::ManifestCache;
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::reconnect::{backoff, jitter, Reachability};
+use mcbd_world_sync::transfer_queue::TransferQueue;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[test]
+fn waits_grow_up_to_five_minutes_with_jitter() {
+    assert_eq!(backoff(1, 0.0), Duration::from_secs(1));
+    assert_eq!(backoff(4, 0.0), Duration::from_secs(8));
+    assert_eq!(backoff(30, 0.0), Duration::from_secs(300));
+    assert_eq!(backoff(30, 1.0), Duration::from_secs(150));
+    for _ in 0..100 {
+        let random = jitter();
+        assert!((0.0..1.0).contains(&random), "{}", random);
+        assert!((Duration::from_secs(2)..=Duration::from_secs(4)).contains(&backoff(3, random)));
+    }
+}
+
+#[tokio::test]
+async fn offline_devices_show_in_status_until_they_are_back() {
+    let reachability = Reachability::new();
+    let aging = DeviceAging::new(vec!["laptop".to_string()], ManifestCache::new(), 7).with_reachability(reachability.clone());
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    let lost = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+    assert!(reachability.set_offline("laptop", lost));
+    // Only the first failure marks the device offline
+    assert!(!reachability.set_offline("laptop", SystemTime::now()));
+    assert_eq!(aging.status(&queue).await[0].offline_since, Some(1_700_000_000));
+
+    assert_eq!(reachability.set_online("laptop"), Some(lost));
+    assert_eq!(aging.status(&queue).await[0].offline_since, None);
+    assert_eq!(reachability.set_online("laptop"), None);
+}
+
+#[tokio::test]
+async fn queued_changes_go_right_away_once_a_device_is_back() {
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    queue.push("laptop".to_string(), PathBuf::from("w/level.dat"), "Modify".to_string()).await;
+    queue.push("laptop".to_string(), PathBuf::from("w/db/1.ldb"), "Modify".to_string()).await;
+    queue.push("nas".to_string(), PathBuf::from("w/level.dat"), "Modify".to_string()).await;
+
+    // A failed send backs off, as would everything sent while offline
+    let failed = queue.pop_for("laptop").await;
+    queue.retry(failed).await;
+    let second = queue.pop_for("laptop").await;
+    queue.retry(second).await;
+    assert!(tokio::time::timeout(Duration::from_millis(200), queue.pop_for("laptop")).await.is_err());
+
+    assert_eq!(queue.resume_peer("laptop").await, 2);
+    let resumed = tokio::time::timeout(Duration::from_millis(200), queue.pop_for("laptop")).await.unwrap();
+    assert_eq!(resumed.peer, "laptop");
+    assert_eq!(queue.len_for("nas").await, 1);
+}
+
+#[tokio::test]
+async fn changes_count_as_queued_until_acknowledged() {
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    queue.push("laptop".to_string(), PathBuf::from("w/level.dat"), "Modify".to_string()).await;
+    queue.push("laptop".to_string(), PathBuf::from("w/db/1.ldb"), "Modify".to_string()).await;
+
+    let sent = queue.pop_for("laptop").await;
+    assert_eq!((queue.len_for("laptop").await, queue.in_flight_for("laptop").await), (2, 1));
+    // Changed again while on its way: still one change to acknowledge
+    queue.push("laptop".to_string(), sent.path.clone(), "Modify".to_string()).await;
+    assert_eq!(queue.len_for("laptop").await, 2);
+    queue.acknowledged(&sent).await;
+    assert_eq!((queue.len_for("laptop").await, queue.in_flight_for("laptop").await), (2, 0));
+
+    // Unacknowledged changes go back in the queue
+    let unanswered = queue.pop_for("laptop").await;
+    queue.retry(unanswered).await;
+    assert_eq!((queue.len_for("laptop").await, queue.in_flight_for("laptop").await), (2, 0));
+}
@@ -0,0 +1,101 @@
+//! Devices that cannot accept connections reached through a relay, with
+//! TLS still ending at the devices themselves.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::auth::DeviceKeys;
+use mcbd_world_sync::config::{Device, TlsConfig};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::{SyncClient, SyncServer};
+use mcbd_world_sync::relay::{self, Relay};
+use mcbd_world_sync::tls::{self, Identity};
+use mcbd_world_sync::transfer_queue::Priority;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+async fn start_relay() -> String {
+    start_relay_with(Relay::default()).await
+}
+
+async fn start_relay_with(relay: Relay) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move { relay.serve(listener).await.unwrap() });
+    address
+}
+
+#[tokio::test]
+async fn files_reach_a_device_listening_on_the_relay() {
+    let relay = start_relay().await;
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    let identity = Identity::load(&TlsConfig::default(), state.path()).unwrap();
+    let files = Arc::new(Mutex::new(FileManager::new(worlds.path().to_path_buf()).with_exclusions(Exclusions::new(&[]))));
+    // The receiver's own listener is never connected to
+    let server = SyncServer::new(free_port())
+        .with_health(Arc::new(Health::new()))
+        .with_file_manager(files)
+        .with_tls(Some(identity.acceptor().unwrap()))
+        .with_relay(Some(relay.clone()), "desktop".to_string());
+    tokio::spawn(async move { server.start().await.unwrap() });
+
+    let client = SyncClient::new(format!("relay://{}/desktop", relay)).with_tls(Some(tls::connector(&[identity.fingerprint()]).unwrap()));
+    let path = PathBuf::from("World/level.dat");
+    let mut tries = 0;
+    while let Err(e) = client.send_file_content(path.clone(), vec![5u8; 300 * 1024], None, Priority::Background).await {
+        tries += 1;
+        assert!(tries < 100, "desktop never became reachable: {}", e);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(fs::read(worlds.path().join(&path)).unwrap(), vec![5u8; 300 * 1024]);
+}
+
+#[tokio::test]
+async fn connecting_to_a_device_that_does_not_listen_is_refused() {
+    let relay = start_relay().await;
+    let error = relay::connect(&relay, "laptop").await.unwrap_err();
+    assert!(error.to_string().contains("laptop is not listening"), "{}", error);
+
+    assert_eq!(relay::parse_address("relay://vps.example.com:8090/laptop"), Some(("vps.example.com:8090", "laptop")));
+    assert_eq!(relay::parse_address("vps.example.com:8090"), None);
+}
+
+#[tokio::test]
+async fn others_cannot_take_over_the_name_of_a_listening_device() {
+    let keys = DeviceKeys::new(&[
+        Device { key: Some("desktop-secret".to_string()), ..Device::from_target("desktop", "") },
+        Device { key: Some("laptop-secret".to_string()), ..Device::from_target("laptop", "") },
+    ]);
+    let relay = start_relay_with(Relay::default().with_device_keys(keys)).await;
+    let worlds = tempfile::TempDir::new().unwrap();
+    let files = Arc::new(Mutex::new(FileManager::new(worlds.path().to_path_buf()).with_exclusions(Exclusions::new(&[]))));
+    let server = SyncServer::new(free_port())
+        .with_health(Arc::new(Health::new()))
+        .with_file_manager(files)
+        .with_relay(Some(relay.clone()), "desktop".to_string())
+        .with_relay_key(Some("desktop-secret".to_string()));
+    tokio::spawn(async move { server.start().await.unwrap() });
+
+    let client = SyncClient::new(format!("relay://{}/desktop", relay));
+    let mut tries = 0;
+    while let Err(e) = client.send_file_content(PathBuf::from("World/level.dat"), b"first".to_vec(), None, Priority::Background).await {
+        tries += 1;
+        assert!(tries < 100, "desktop never became reachable: {}", e);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    let error = relay::listen(&relay, "desktop", None, |_, _| panic!("an unauthenticated listener got a connection")).await.unwrap_err();
+    assert!(error.to_string().contains("refused"), "{}", error);
+    // Another device's key does not prove the name either
+    let error = relay::listen(&relay, "desktop", Some("laptop-secret"), |_, _| panic!("a wrong key got a connection")).await.unwrap_err();
+    assert!(error.to_string().contains("refused"), "{}", error);
+
+    client.send_file_content(PathBuf::from("World/level.dat"), b"second".to_vec(), None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(worlds.path().join("World/level.dat")).unwrap(), b"second");
+}
@@ -0,0 +1,104 @@
+//! Folders moved on one device moved on its peers, instead of deleted and
+//! sent again file by file.
+
+mod common;
+
+use common::receiver::{files, start_receiver, world};
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::network::SyncClient;
+use mcbd_world_sync::transfer_queue::TransferQueue;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+#[test]
+fn moved_folders_are_found_by_their_files() {
+    let dir = tempfile::TempDir::new().unwrap();
+    world(dir.path(), "Survival");
+    world(dir.path(), "Creative");
+    let mut files = files(dir.path().to_path_buf());
+
+    // The old folder reported gone first
+    fs::rename(dir.path().join("Survival"), dir.path().join("Moved")).unwrap();
+    files.mark_deleted(Path::new("Survival"));
+    files.scan_subtree(&dir.path().join("Moved")).unwrap();
+    assert_eq!(files.detect_rename(Path::new("Moved")).unwrap(), Some(PathBuf::from("Survival")));
+    let rename = files.index().renamed(Path::new("Survival")).unwrap();
+    assert_eq!(rename.to, PathBuf::from("Moved"));
+    assert_eq!(rename.files.len(), 2);
+
+    // The new folder reported first, the old one is still indexed
+    fs::create_dir(dir.path().join("Sub")).unwrap();
+    fs::rename(dir.path().join("Creative"), dir.path().join("Sub/Creative")).unwrap();
+    files.scan_subtree(&dir.path().join("Sub")).unwrap();
+    assert_eq!(files.detect_rename(Path::new("Sub/Creative")).unwrap(), Some(PathBuf::from("Creative")));
+    assert!(files.get_file_info(Path::new("Creative/level.dat")).is_none());
+
+    // A copy made later is new, as is a folder whose files differ
+    fs::create_dir_all(dir.path().join("Copy")).unwrap();
+    for file in ["level.dat", "db/000005.ldb"] {
+        fs::create_dir_all(dir.path().join("Copy").join(file).parent().unwrap()).unwrap();
+        fs::copy(dir.path().join("Moved").join(file), dir.path().join("Copy").join(file)).unwrap();
+    }
+    files.scan_subtree(&dir.path().join("Copy")).unwrap();
+    assert_eq!(files.detect_rename(Path::new("Copy")).unwrap(), None);
+    world(dir.path(), "Edited");
+    fs::write(dir.path().join("Edited/level.dat"), "Survival").unwrap();
+    fs::write(dir.path().join("Edited/db/000005.ldb"), [6u8; 64]).unwrap();
+    files.scan_subtree(&dir.path().join("Edited")).unwrap();
+    assert_eq!(files.detect_rename(Path::new("Edited")).unwrap(), None);
+
+    // Until the old folder shows up again
+    world(dir.path(), "Survival");
+    files.scan_subtree(&dir.path().join("Survival")).unwrap();
+    assert!(files.index().renamed(Path::new("Survival")).is_none());
+}
+
+#[tokio::test]
+async fn moves_sent_to_a_peer_are_applied_unless_its_copy_differs() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let (sender, receiver) = (dir.path().join("sender"), dir.path().join("receiver"));
+    world(&sender, "Survival");
+    world(&receiver, "Survival");
+    let mut sent = files(sender.clone());
+    fs::rename(sender.join("Survival"), sender.join("Moved")).unwrap();
+    sent.mark_deleted(Path::new("Survival"));
+    sent.scan_subtree(&sender.join("Moved")).unwrap();
+    sent.detect_rename(Path::new("Moved")).unwrap();
+    let rename = sent.index().renamed(Path::new("Survival")).unwrap();
+
+    let received = files(receiver.clone());
+    let index = received.index();
+    let address = start_receiver(Arc::new(Mutex::new(received)), |server| server).await;
+    let client = SyncClient::new(address);
+    assert!(client.send_file_renamed(PathBuf::from("Survival"), rename.to.clone(), rename.files.clone(), None, None).await.unwrap());
+    assert!(!receiver.join("Survival").exists());
+    assert_eq!(fs::read(receiver.join("Moved/level.dat")).unwrap(), b"Survival");
+    assert!(index.get(Path::new("Moved/db/000005.ldb")).is_some());
+    assert!(index.get(Path::new("Survival/level.dat")).is_none());
+
+    // Changed there since, so it needs the files instead
+    world(&receiver, "Survival");
+    fs::write(receiver.join("Survival/level.dat"), "Edited").unwrap();
+    let address = start_receiver(Arc::new(Mutex::new(files(receiver.clone()))), |server| server).await;
+    let client = SyncClient::new(address);
+    assert!(!client.send_file_renamed(PathBuf::from("Survival"), PathBuf::from("Elsewhere"), rename.files, None, None).await.unwrap());
+    assert!(receiver.join("Survival/level.dat").is_file());
+    assert!(!receiver.join("Elsewhere").exists());
+}
+
+#[tokio::test]
+async fn deleted_folders_wait_in_the_queue_in_case_they_were_moved() {
+    let queue = TransferQueue::new(Arc::new(Metrics::new()));
+    let started = Instant::now();
+    queue.push_delayed("peer".to_string(), PathBuf::from("Survival"), "Remove(Folder)".to_string(), Duration::from_millis(300)).await;
+    queue.push("peer".to_string(), PathBuf::from("Creative/level.dat"), "Modify".to_string()).await;
+    assert_eq!(queue.pop().await.path, PathBuf::from("Creative/level.dat"));
+    // Queued again once found moved, it keeps waiting
+    queue.push("peer".to_string(), PathBuf::from("Survival"), "Create(Folder)".to_string()).await;
+    let moved = queue.pop().await;
+    assert_eq!((moved.path, moved.change_type.as_str()), (PathBuf::from("Survival"), "Create(Folder)"));
+    assert!(started.elapsed() >= Duration::from_millis(300));
+}
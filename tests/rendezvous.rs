@@ -0,0 +1,98 @@
+//! Finding devices without a fixed address: by a dynamic-DNS name, or by
+//! the rendezvous ID they register on a relay.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::auth::DeviceKeys;
+use mcbd_world_sync::config::{Device, RendezvousConfig};
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::{SyncClient, SyncServer};
+use mcbd_world_sync::rendezvous::{self, Lookup, RendezvousRegistry, REGISTRATION_TTL};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn relay_config(relay: &str, interval: u64) -> RendezvousConfig {
+    RendezvousConfig { relay: relay.to_string(), id: "desktop-id".to_string(), register_interval: interval }
+}
+
+async fn start_relay(keys: DeviceKeys) -> String {
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(port).with_health(health.clone()).with_device_keys(keys);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    format!("127.0.0.1:{}", port)
+}
+
+#[test]
+fn registrations_repeat_well_within_their_lifetime() {
+    assert_eq!(rendezvous::registration_interval(&relay_config("relay:8080", 300)), Duration::from_secs(300));
+    assert_eq!(rendezvous::registration_interval(&relay_config("relay:8080", 0)), Duration::from_secs(1));
+    assert_eq!(rendezvous::registration_interval(&relay_config("relay:8080", 24 * 3600)), REGISTRATION_TTL / 3);
+}
+
+#[tokio::test]
+async fn ids_belong_to_the_device_that_registered_them_with_its_key() {
+    let registry = RendezvousRegistry::new();
+    let (home, elsewhere): (SocketAddr, SocketAddr) = ("10.0.0.9:8080".parse().unwrap(), "10.6.6.6:8080".parse().unwrap());
+    registry.register("laptop-id".to_string(), home, Some("laptop")).await.unwrap();
+    let error = registry.register("laptop-id".to_string(), elsewhere, Some("tablet")).await.unwrap_err();
+    assert!(error.to_string().contains("belongs to laptop"), "{}", error);
+    assert!(registry.register("laptop-id".to_string(), elsewhere, None).await.is_err());
+    assert_eq!(registry.lookup("laptop-id").await, Some(home));
+    registry.register("laptop-id".to_string(), elsewhere, Some("laptop")).await.unwrap();
+    assert_eq!(registry.lookup("laptop-id").await, Some(elsewhere));
+
+    // Without keys, IDs are not tied to anyone
+    registry.register("open-id".to_string(), home, None).await.unwrap();
+    registry.register("open-id".to_string(), elsewhere, None).await.unwrap();
+    assert_eq!(registry.lookup("open-id").await, Some(elsewhere));
+}
+
+#[tokio::test]
+async fn dynamic_dns_names_are_connected_to_as_they_are() {
+    let address = start_relay(DeviceKeys::default()).await;
+    let port = address.rsplit(':').next().unwrap();
+    let home = Device::from_target("laptop", &format!("localhost:{}", port));
+    let no_relay = |_: String| -> SyncClient { panic!("no relay is needed") };
+    // The name is resolved on every connect, so it follows IP changes
+    let resolved = rendezvous::resolve_device(&home, &Lookup::default(), no_relay).await.unwrap();
+    assert_eq!(resolved, format!("localhost:{}", port));
+    SyncClient::new(resolved).session().await.unwrap();
+}
+
+#[tokio::test]
+async fn rendezvous_ids_are_resolved_on_the_relay() {
+    let keys = DeviceKeys::new(&[
+        Device { key: Some("laptop-secret".to_string()), ..Device::from_target("laptop", "@laptop-id") },
+        Device { key: Some("desktop-secret".to_string()), ..Device::from_target("desktop", "@desktop-id") },
+    ]);
+    let relay = start_relay(keys).await;
+    let as_device = |name: &str, key: &str| {
+        let (name, key) = (name.to_string(), key.to_string());
+        move |address: String| SyncClient::new(address).with_device_name(name.clone()).with_key(Some(key.clone()))
+    };
+    as_device("laptop", "laptop-secret")(relay.clone()).register_rendezvous("laptop-id".to_string(), 9000).await.unwrap();
+
+    let lookup = Lookup { rendezvous: Some(relay_config(&relay, 300)), discovered: None };
+    let laptop = Device::from_target("laptop", "@laptop-id");
+    let mut resolved = rendezvous::resolve_device(&laptop, &lookup, as_device("desktop", "desktop-secret")).await;
+    for _ in 0..50 {
+        if resolved.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        resolved = rendezvous::resolve_device(&laptop, &lookup, as_device("desktop", "desktop-secret")).await;
+    }
+    assert_eq!(resolved.unwrap(), "127.0.0.1:9000");
+
+    let unknown = Device::from_target("tablet", "@tablet-id");
+    let error = rendezvous::resolve_device(&unknown, &lookup, as_device("desktop", "desktop-secret")).await.unwrap_err();
+    assert!(error.to_string().contains("is not registered"), "{}", error);
+    let no_relay = Lookup::default();
+    assert!(rendezvous::resolve_device(&laptop, &no_relay, as_device("desktop", "desktop-secret")).await.is_err());
+}
@@ -0,0 +1,186 @@
+//! The summary report: what it gathers, when it is due and how it is
+//! emailed.
+
+use mcbd_world_sync::app_dirs::AppDirs;
+use mcbd_world_sync::config::{ReportConfig, ReportSchedule, SmtpConfig, SmtpSecurity};
+use mcbd_world_sync::deletions::{PendingDeletion, PendingDeletions};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::report::{DeviceSync, Report, ReportSources, ReportTimes};
+use mcbd_world_sync::smtp::{self, Email};
+use mcbd_world_sync::usage::{Usage, UsageLog};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+const DAY: Duration = Duration::from_secs(86400);
+
+#[tokio::test]
+async fn report_gathers_conflicts_deletions_and_storage() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    for world in ["World", "World (conflict from DESKTOP 2026-10-01)"] {
+        fs::create_dir_all(worlds.join(world).join("db")).unwrap();
+        fs::write(worlds.join(world).join("levelname.txt"), "Castle").unwrap();
+        fs::write(worlds.join(world).join("db/000005.ldb"), [5u8; 1000]).unwrap();
+    }
+    let app_dirs = AppDirs::at(dir.path().join("state"));
+    app_dirs.ensure().unwrap();
+    let now = SystemTime::now();
+    let usage = Usage { size: 5000, wire: 2000, files: 3, failed: 1, ..Usage::default() };
+    UsageLog::new(app_dirs.usage_file()).record(&usage, now - DAY * 2).unwrap();
+    UsageLog::new(app_dirs.usage_file()).record(&usage, now - DAY * 20).unwrap();
+    let deletion = PendingDeletion { path: PathBuf::from("World/db/000005.ldb"), hash: String::new(), device: "DESKTOP".to_string(), received_at: now, approved: false };
+    PendingDeletions::new(app_dirs.deletions_file()).add(vec![deletion]).unwrap();
+
+    let sources = ReportSources {
+        device: "LAPTOP".to_string(),
+        devices: vec!["DESKTOP".to_string()],
+        stale_after_days: 7,
+        worlds_root: worlds,
+        exclusions: Exclusions::new(&[]),
+        app_dirs,
+    };
+    let report = Report::gather(&sources, 7, now).await.unwrap();
+    assert_eq!(report.sent, usage);
+    assert_eq!(report.conflicts, vec!["World (conflict from DESKTOP 2026-10-01)".to_string()]);
+    assert_eq!(report.pending_deletions, 1);
+    assert_eq!(report.worlds.len(), 2);
+    assert_eq!(report.devices, vec![DeviceSync { name: "DESKTOP".to_string(), last_synced: None, stale: false }]);
+
+    let body = report.body();
+    assert!(body.contains("3 files sent to peers"), "{}", body);
+    assert!(body.contains("DESKTOP: never synced"), "{}", body);
+    assert!(body.contains("1 deletion received and not applied"), "{}", body);
+    assert!(body.contains("Castle (World): 1006 bytes"), "{}", body);
+    assert!(body.contains("worlds: 2.0 KiB in 2 worlds"), "{}", body);
+    assert!(report.subject().ends_with("1 conflict copy, 1 failed transfer"), "{}", report.subject());
+}
+
+#[test]
+fn stale_devices_are_named_in_the_subject() {
+    let report = Report {
+        device: "LAPTOP".to_string(),
+        days: 1,
+        at: UNIX_EPOCH + DAY * 20000,
+        sent: Usage::default(),
+        devices: vec![
+            DeviceSync { name: "DESKTOP".to_string(), last_synced: Some(UNIX_EPOCH + DAY * 19999), stale: false },
+            DeviceSync { name: "PHONE".to_string(), last_synced: Some(UNIX_EPOCH + DAY * 19900), stale: true },
+        ],
+        conflicts: Vec::new(),
+        pending_deletions: 0,
+        worlds: Vec::new(),
+        snapshots: Default::default(),
+    };
+    assert_eq!(report.subject(), "World sync on LAPTOP, 2024-10-04: 1 stale device");
+    let body = report.body();
+    assert!(body.starts_with("Summary of today on LAPTOP, up to 2024-10-04."), "{}", body);
+    assert!(body.contains("DESKTOP: last synced 2024-10-03\n"), "{}", body);
+    assert!(body.contains("PHONE: last synced 2024-06-26, stale"), "{}", body);
+
+    let healthy = Report { devices: Vec::new(), ..report };
+    assert!(healthy.subject().ends_with(": all well"));
+}
+
+#[test]
+fn reports_are_due_once_a_period_passed() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let times = ReportTimes::new(dir.path().join("report.json"));
+    let start = UNIX_EPOCH + DAY * 20000;
+    // The first check starts the schedule
+    assert!(!times.due(&ReportSchedule::Weekly, start).unwrap());
+    assert_eq!(times.last_sent().unwrap(), Some(start));
+    assert!(!times.due(&ReportSchedule::Weekly, start + DAY * 6).unwrap());
+    assert!(times.due(&ReportSchedule::Daily, start + DAY).unwrap());
+    assert!(times.due(&ReportSchedule::Weekly, start + DAY * 7).unwrap());
+    times.record(start + DAY * 7).unwrap();
+    assert!(!times.due(&ReportSchedule::Weekly, start + DAY * 8).unwrap());
+}
+
+/// Accepts one session the way a mail server would, returning the commands
+/// and the message it received.
+async fn fake_mail_server(listener: TcpListener) -> (Vec<String>, String) {
+    let (stream, _) = listener.accept().await.unwrap();
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    write.write_all(b"220 mail.example.com ready\r\n").await.unwrap();
+    let (mut commands, mut message) = (Vec::new(), String::new());
+    while let Some(line) = lines.next_line().await.unwrap() {
+        let reply: &[u8] = match line.split(' ').next().unwrap() {
+            "EHLO" => b"250-mail.example.com\r\n250 AUTH PLAIN\r\n",
+            "AUTH" => b"235 2.7.0 Accepted\r\n",
+            "DATA" => b"354 End data with <CR><LF>.<CR><LF>\r\n",
+            "QUIT" => b"221 Bye\r\n",
+            _ => b"250 OK\r\n",
+        };
+        commands.push(line.clone());
+        write.write_all(reply).await.unwrap();
+        if line == "DATA" {
+            while let Some(line) = lines.next_line().await.unwrap() {
+                if line == "." {
+                    break;
+                }
+                message.push_str(&line);
+                message.push('\n');
+            }
+            write.write_all(b"250 Queued\r\n").await.unwrap();
+        }
+        if line == "QUIT" {
+            break;
+        }
+    }
+    (commands, message)
+}
+
+#[tokio::test]
+async fn report_is_emailed_through_the_configured_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = tokio::spawn(fake_mail_server(listener));
+    let config = ReportConfig {
+        schedule: ReportSchedule::Weekly,
+        from: "sync@example.com".to_string(),
+        to: vec!["me@example.com".to_string(), "you@example.com".to_string()],
+        smtp: SmtpConfig { host: "127.0.0.1".to_string(), port, security: SmtpSecurity::None, username: Some("me".to_string()), password: Some("secret".to_string()) },
+    };
+    let email = Email { from: config.from.clone(), to: config.to.clone(), subject: "Weekly".to_string(), body: "Sync\n.hidden line\n".to_string() };
+    smtp::send(&config.smtp, &email).await.unwrap();
+
+    let (commands, message) = server.await.unwrap();
+    assert_eq!(commands, vec![
+        "EHLO mcbd-world-sync",
+        // base64 of "\0me\0secret"
+        "AUTH PLAIN AG1lAHNlY3JldA==",
+        "MAIL FROM:<sync@example.com>",
+        "RCPT TO:<me@example.com>",
+        "RCPT TO:<you@example.com>",
+        "DATA",
+        "QUIT",
+    ]);
+    assert!(message.contains("Subject: Weekly\n"), "{}", message);
+    assert!(message.contains("To: me@example.com, you@example.com\n"), "{}", message);
+    // Lines starting with a dot are doubled so they do not end the message
+    assert!(message.ends_with("\nSync\n..hidden line\n"), "{}", message);
+}
+
+#[tokio::test]
+async fn rejected_commands_fail_the_send() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        write.write_all(b"220 ready\r\n").await.unwrap();
+        lines.next_line().await.unwrap();
+        write.write_all(b"250 hello\r\n").await.unwrap();
+        lines.next_line().await.unwrap();
+        write.write_all(b"550 5.7.1 Sender rejected\r\n").await.unwrap();
+    });
+    let smtp = SmtpConfig { host: "127.0.0.1".to_string(), port, security: SmtpSecurity::None, username: None, password: None };
+    let email = Email { from: "sync@example.com".to_string(), to: vec!["me@example.com".to_string()], subject: "Daily".to_string(), body: String::new() };
+    let error = smtp::send(&smtp, &email).await.unwrap_err().to_string();
+    assert!(error.contains("MAIL failed: 550 5.7.1 Sender rejected"), "{}", error);
+}
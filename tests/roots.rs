@@ -0,0 +1,57 @@
+//! Roots synced along with the worlds, such as `development_behavior_packs`,
+//! indexed below `@<name>` and written into the root of that name.
+
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn roots(dir: &Path) -> BTreeMap<String, PathBuf> {
+    BTreeMap::from([("packs".to_string(), dir.join("development_behavior_packs"))])
+}
+
+#[test]
+fn roots_are_indexed_and_received_next_to_the_worlds() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    let packs = dir.path().join("development_behavior_packs");
+    fs::create_dir_all(worlds.join("w")).unwrap();
+    fs::create_dir_all(packs.join("pack")).unwrap();
+    fs::write(worlds.join("w/level.dat"), "level").unwrap();
+    fs::write(packs.join("pack/manifest.json"), "{}").unwrap();
+    let mut files = FileManager::new(worlds.clone()).with_exclusions(Exclusions::new(&[])).with_roots(roots(dir.path()));
+    files.scan_directory().unwrap();
+
+    let mut paths: Vec<PathBuf> = files.entries().into_iter().map(|entry| entry.path).collect();
+    paths.sort();
+    assert_eq!(paths, ["@packs/pack/manifest.json", "w/level.dat"].map(PathBuf::from));
+    let index = files.index();
+    assert_eq!(index.full_path(Path::new("@packs/pack/manifest.json")), packs.join("pack/manifest.json"));
+    assert_eq!(index.relative_path(&packs.join("pack/manifest.json")), Some(PathBuf::from("@packs/pack/manifest.json")));
+    assert_eq!(index.relative_path(&dir.path().join("elsewhere")), None);
+
+    // A root's files go into the root of the same name, not the worlds
+    assert!(files.receive_file(Path::new("@packs/pack/scripts/main.js"), b"main").unwrap());
+    assert_eq!(fs::read(packs.join("pack/scripts/main.js")).unwrap(), b"main");
+    assert!(!worlds.join("@packs").exists());
+    assert!(files.receive_file(Path::new("@other/file"), b"unknown root").is_err());
+    assert!(files.receive_file(Path::new("@packs"), b"the root itself").is_err());
+}
+
+#[test]
+fn a_missing_root_is_not_taken_for_deleted() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    let packs = dir.path().join("development_behavior_packs");
+    fs::create_dir_all(worlds.join("w")).unwrap();
+    fs::create_dir_all(packs.join("pack")).unwrap();
+    fs::write(worlds.join("w/level.dat"), "level").unwrap();
+    fs::write(packs.join("pack/manifest.json"), "{}").unwrap();
+    let mut files = FileManager::new(worlds.clone()).with_exclusions(Exclusions::new(&[])).with_roots(roots(dir.path()));
+    files.scan_directory().unwrap();
+
+    fs::rename(&packs, dir.path().join("unplugged")).unwrap();
+    files.scan_directory().unwrap();
+    assert!(files.get_file_info(Path::new("@packs/pack/manifest.json")).is_some());
+}
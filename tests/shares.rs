@@ -0,0 +1,79 @@
+//! Sharing a single world with a friend outside every sync group.
+
+use mcbd_world_sync::config::{Config, Device, Direction};
+use mcbd_world_sync::groups::Groups;
+use mcbd_world_sync::shares::{self, ShareToken};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+fn config(devices: serde_json::Value) -> Config {
+    serde_json::from_value(serde_json::json!({
+        "server": { "port": 0, "host": "127.0.0.1" },
+        "sync": { "devices": devices, "conflict_resolution": "newest", "sync_interval": 60 },
+        "paths": { "minecraft_worlds": "worlds" }
+    })).unwrap()
+}
+
+fn friend() -> Device {
+    Device::from_target("robin", "robin.example.com:8080")
+}
+
+#[test]
+fn token_round_trips() {
+    let mut host = config(serde_json::json!([{ "name": "laptop", "address": "127.0.0.1:1" }]));
+    let token = shares::create(&mut host, "Adventure Map", friend(), 3, false, SystemTime::now()).unwrap();
+    assert_eq!(ShareToken::parse(&token.to_string()).unwrap(), token);
+    assert_eq!(token.key.len(), 32);
+
+    assert!(ShareToken::parse("mcbd-share/share-x/rw/12/key/../escape").is_err());
+    assert!(ShareToken::parse("something else").is_err());
+}
+
+#[test]
+fn friend_only_sees_the_shared_world() {
+    let mut host = config(serde_json::json!([{ "name": "laptop", "address": "127.0.0.1:1" }]));
+    let token = shares::create(&mut host, "Adventure", friend(), 7, false, SystemTime::now()).unwrap();
+    let groups = Groups::new(host.sync.groups()).unwrap();
+
+    let names = |path: &str| groups.devices_for(Path::new(path)).iter().map(|d| d.name.clone()).collect::<Vec<_>>();
+    assert_eq!(names("Adventure/level.dat"), ["laptop", "robin"]);
+    assert_eq!(names("Survival/level.dat"), ["laptop"]);
+
+    // Read-only: whatever the friend sends back is refused, even with the key
+    let path = Path::new("Adventure/level.dat");
+    assert!(groups.authorize(groups.tag("robin", path).as_ref(), path).is_err());
+
+    // The friend receives the world but sends nothing to the host
+    let mut guest = config(serde_json::json!([]));
+    shares::join(&mut guest, &token, Device::from_target("host", "host.example.com:8080")).unwrap();
+    let joined = Groups::new(guest.sync.groups()).unwrap();
+    assert_eq!(joined.all()[0].direction, Direction::ReceiveOnly);
+    assert!(joined.devices_for(path).is_empty());
+    assert!(joined.authorize(groups.tag("robin", path).as_ref(), path).is_ok());
+}
+
+#[test]
+fn writable_shares_accept_changes_until_they_expire() {
+    let mut host = config(serde_json::json!([]));
+    shares::create(&mut host, "Adventure", friend(), 1, true, SystemTime::now()).unwrap();
+    let path = Path::new("Adventure/level.dat");
+    let groups = Groups::new(host.sync.groups()).unwrap();
+    assert!(groups.authorize(groups.tag("robin", path).as_ref(), path).is_ok());
+
+    let mut expired = config(serde_json::json!([]));
+    shares::create(&mut expired, "Adventure", friend(), 1, true, SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60)).unwrap();
+    let groups = Groups::new(expired.sync.groups()).unwrap();
+    assert!(groups.authorize(groups.tag("robin", path).as_ref(), path).is_err());
+    assert!(groups.devices_for(path).is_empty());
+}
+
+#[test]
+fn revoking_removes_the_share() {
+    let mut host = config(serde_json::json!([]));
+    shares::create(&mut host, "Adventure", friend(), 7, false, SystemTime::now()).unwrap();
+    assert!(shares::create(&mut host, "Other", friend(), 7, false, SystemTime::now()).is_err());
+
+    assert_eq!(shares::revoke(&mut host, "robin").unwrap().name, "share-robin");
+    assert!(host.sync.groups.is_empty());
+    assert!(shares::revoke(&mut host, "robin").is_err());
+}
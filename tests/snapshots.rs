@@ -0,0 +1,135 @@
+//! Automatic snapshots of worlds before received changes overwrite them, and
+//! restoring from or looking into them.
+
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::snapshots::{FileDiff, Snapshots, Views, MAX_SNAPSHOTS};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+fn world(root: &Path) {
+    fs::create_dir_all(root.join("Skyblock/db")).unwrap();
+    fs::write(root.join("Skyblock/level.dat"), b"old level").unwrap();
+    fs::write(root.join("Skyblock/db/000005.ldb"), b"old table").unwrap();
+}
+
+fn read(path: impl AsRef<Path>) -> String {
+    String::from_utf8(fs::read(path).unwrap()).unwrap()
+}
+
+#[test]
+fn received_changes_are_snapshotted_once_per_burst() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    world(worlds.path());
+    let snapshots = Snapshots::new(state.path().to_path_buf());
+    let mut files = FileManager::new(worlds.path().to_path_buf()).with_snapshots(snapshots.clone());
+    files.scan_directory().unwrap();
+
+    files.receive_file(Path::new("Skyblock/level.dat"), b"new level").unwrap();
+    files.receive_file(Path::new("Skyblock/db/000005.ldb"), b"new table").unwrap();
+    files.receive_file(Path::new("Skyblock/db/000006.ldb"), b"another table").unwrap();
+    files.remove_received(Path::new("Skyblock/level.dat")).unwrap();
+
+    let taken = snapshots.list("Skyblock").unwrap();
+    assert_eq!(taken.len(), 1);
+    assert_eq!(taken[0].reason, "received");
+    assert_eq!(read(taken[0].path.join("level.dat")), "old level");
+    // The table is hard-linked, and received files replace it rather than write into it
+    assert_eq!(read(taken[0].path.join("db/000005.ldb")), "old table");
+    assert!(!taken[0].path.join("db/000006.ldb").exists());
+    assert_eq!(read(worlds.path().join("Skyblock/db/000005.ldb")), "new table");
+    // Nothing is left in the staging folder
+    assert!(!worlds.path().join(".mcbd-staging").exists());
+
+    // A world that only now arrives has nothing to snapshot
+    files.receive_file(Path::new("Farm/level.dat"), b"farm").unwrap();
+    assert!(snapshots.list("Farm").unwrap().is_empty());
+}
+
+#[test]
+fn restoring_puts_the_world_back_and_can_be_undone() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    world(worlds.path());
+    let snapshots = Snapshots::new(state.path().to_path_buf());
+    let taken = snapshots.take(worlds.path(), "Skyblock", "received", SystemTime::now()).unwrap();
+
+    fs::write(worlds.path().join("Skyblock/level.dat"), b"new level").unwrap();
+    fs::write(worlds.path().join("Skyblock/db/000006.ldb"), b"another table").unwrap();
+
+    let before = snapshots.restore(worlds.path(), &taken, SystemTime::now()).unwrap().unwrap();
+    assert_eq!(before.reason, "undo");
+    assert_eq!(read(worlds.path().join("Skyblock/level.dat")), "old level");
+    assert_eq!(read(worlds.path().join("Skyblock/db/000005.ldb")), "old table");
+    assert!(!worlds.path().join("Skyblock/db/000006.ldb").exists());
+
+    snapshots.restore(worlds.path(), &before, SystemTime::now()).unwrap();
+    assert_eq!(read(worlds.path().join("Skyblock/level.dat")), "new level");
+    assert_eq!(read(worlds.path().join("Skyblock/db/000006.ldb")), "another table");
+}
+
+#[test]
+fn only_the_newest_snapshots_are_kept() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    world(worlds.path());
+    let snapshots = Snapshots::new(state.path().to_path_buf());
+    for i in 0..MAX_SNAPSHOTS as u64 + 3 {
+        snapshots.take(worlds.path(), "Skyblock", "received", UNIX_EPOCH + Duration::from_secs(1000 + i)).unwrap();
+    }
+    let kept = snapshots.list("Skyblock").unwrap();
+    assert_eq!(kept.len(), MAX_SNAPSHOTS);
+    assert_eq!(kept[0].name, "1003-received");
+
+    assert!(snapshots.take(worlds.path(), "../Skyblock", "received", SystemTime::now()).is_err());
+    assert!(snapshots.take(worlds.path(), "Skyblock", "no/slashes", SystemTime::now()).is_err());
+}
+
+#[test]
+fn diff_lists_what_changed_between_two_snapshots() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    world(worlds.path());
+    fs::write(worlds.path().join("Skyblock/levelname.txt"), b"Skyblock").unwrap();
+    let snapshots = Snapshots::new(state.path().to_path_buf());
+    let a = snapshots.take(worlds.path(), "Skyblock", "received", UNIX_EPOCH + Duration::from_secs(1000)).unwrap();
+
+    fs::write(worlds.path().join("Skyblock/level.dat"), b"new level, longer").unwrap();
+    fs::write(worlds.path().join("Skyblock/db/000006.ldb"), b"another table").unwrap();
+    fs::remove_file(worlds.path().join("Skyblock/levelname.txt")).unwrap();
+    let b = snapshots.take(worlds.path(), "Skyblock", "received", UNIX_EPOCH + Duration::from_secs(2000)).unwrap();
+
+    let diff = |path: &str, before, after| FileDiff { path: PathBuf::from(path), before, after };
+    // The unchanged table is hard-linked into both and left out
+    assert_eq!(snapshots.diff(&a, &b).unwrap(), [
+        diff("db/000006.ldb", None, Some(13)),
+        diff("level.dat", Some(9), Some(17)),
+        diff("levelname.txt", Some(8), None),
+    ]);
+    assert_eq!(snapshots.find("Skyblock", &b.name).unwrap(), b);
+    assert!(snapshots.find("Skyblock", "1500-received").is_err());
+}
+
+#[test]
+fn views_are_read_only_copies_that_leave_the_world_writable() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    world(worlds.path());
+    let snapshots = Snapshots::new(state.path().join("snapshots"));
+    let views = Views::new(state.path().join("views"));
+    let taken = snapshots.take(worlds.path(), "Skyblock", "received", SystemTime::now()).unwrap();
+
+    let view = views.open(&taken).unwrap();
+    assert_eq!(views.open(&taken).unwrap(), view);
+    assert_eq!(read(view.join("db/000005.ldb")), "old table");
+    assert!(fs::metadata(view.join("db/000005.ldb")).unwrap().permissions().readonly());
+    // The table is hard-linked between the world and the snapshot, never into the view
+    assert!(!fs::metadata(worlds.path().join("Skyblock/db/000005.ldb")).unwrap().permissions().readonly());
+    assert!(!fs::metadata(taken.path.join("db/000005.ldb")).unwrap().permissions().readonly());
+
+    assert!(views.close("Skyblock", &taken.name).unwrap());
+    assert!(!view.exists());
+    assert!(!views.close("Skyblock", &taken.name).unwrap());
+    assert!(views.close("Skyblock", "../snapshots").is_err());
+}
@@ -0,0 +1,104 @@
+//! Received changes are staged and swapped into their world in one go,
+//! once all of them arrived.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::{SyncClient, SyncServer};
+use mcbd_world_sync::transfer_queue::Priority;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+fn world(dir: &Path) -> FileManager {
+    fs::create_dir_all(dir.join("Skyblock/db")).unwrap();
+    fs::write(dir.join("Skyblock/level.dat"), b"old level").unwrap();
+    fs::write(dir.join("Skyblock/db/000005.ldb"), b"table").unwrap();
+    let mut files = FileManager::new(dir.to_path_buf()).with_exclusions(Exclusions::new(&[])).with_staging(true);
+    files.scan_directory().unwrap();
+    files
+}
+
+#[test]
+fn received_changes_show_in_the_world_once_committed() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut files = world(dir.path());
+
+    files.receive_file(Path::new("Skyblock/level.dat"), b"new level").unwrap();
+    files.remove_received(Path::new("Skyblock/db/000005.ldb")).unwrap();
+    assert_eq!(fs::read(dir.path().join("Skyblock/level.dat")).unwrap(), b"old level");
+    assert!(dir.path().join("Skyblock/db/000005.ldb").is_file());
+    // The index and what is sent on already have them
+    assert_eq!(files.get_file_content(Path::new("Skyblock/level.dat")).unwrap(), b"new level");
+    assert!(files.get_file_info(Path::new("Skyblock/db/000005.ldb")).is_none());
+    // The world as it still is on disk is not taken for a change
+    assert!(files.rescan().unwrap().is_empty());
+    assert!(files.poll().unwrap().is_empty());
+
+    // Changed here meanwhile
+    fs::write(dir.path().join("Skyblock/levelname.txt"), b"Skyblock").unwrap();
+
+    assert!(files.commit_staged("Skyblock").unwrap());
+    assert_eq!(fs::read(dir.path().join("Skyblock/level.dat")).unwrap(), b"new level");
+    assert!(!dir.path().join("Skyblock/db/000005.ldb").exists());
+    assert_eq!(fs::read(dir.path().join("Skyblock/levelname.txt")).unwrap(), b"Skyblock");
+    assert!(!dir.path().join(".mcbd-staging").exists());
+    assert!(!files.commit_staged("Skyblock").unwrap());
+    assert_eq!(files.rescan().unwrap(), vec![PathBuf::from("Skyblock/levelname.txt")]);
+}
+
+#[test]
+fn new_worlds_appear_whole() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut files = world(dir.path());
+
+    files.receive_file(Path::new("Farm/level.dat"), b"farm").unwrap();
+    files.receive_file(Path::new("Farm/db/CURRENT"), b"MANIFEST-000001").unwrap();
+    assert!(!dir.path().join("Farm").exists());
+
+    assert!(files.commit_staged("Farm").unwrap());
+    assert_eq!(fs::read(dir.path().join("Farm/db/CURRENT")).unwrap(), b"MANIFEST-000001");
+}
+
+#[test]
+fn interrupted_syncs_leave_the_world_as_it_was() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let mut files = world(dir.path());
+    files.receive_file(Path::new("Skyblock/level.dat"), b"new level").unwrap();
+    drop(files);
+
+    let restarted = FileManager::new(dir.path().to_path_buf()).with_staging(true);
+    assert_eq!(restarted.recover_stages().unwrap(), 1);
+    assert_eq!(fs::read(dir.path().join("Skyblock/level.dat")).unwrap(), b"old level");
+    assert!(!dir.path().join(".mcbd-staging").exists());
+}
+
+#[tokio::test]
+async fn senders_say_when_a_world_is_complete() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let files = Arc::new(Mutex::new(world(dir.path())));
+    let (port, health) = (free_port(), Arc::new(Health::new()));
+    let server = SyncServer::new(port).with_health(health.clone()).with_file_manager(files.clone());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let client = SyncClient::new(format!("127.0.0.1:{}", port));
+    client.send_file_content(PathBuf::from("Skyblock/level.dat"), b"new level".to_vec(), None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(dir.path().join("Skyblock/level.dat")).unwrap(), b"old level");
+
+    client.send_world_synced("Skyblock".to_string()).await.unwrap();
+    for _ in 0..50 {
+        if fs::read(dir.path().join("Skyblock/level.dat")).unwrap() == b"new level" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(fs::read(dir.path().join("Skyblock/level.dat")).unwrap(), b"new level");
+}
@@ -0,0 +1,175 @@
+//! Startup: the stored index and legacy state folders, then the scan with
+//! worlds listed right away and files hashed afterwards or only when they
+//! are needed.
+
+use mcbd_world_sync::app_dirs::AppDirs;
+use mcbd_world_sync::config::{Config, HashPolicy, HYBRID_LIMIT};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::{self, FileInfo, FileManager, WorldSummary};
+use mcbd_world_sync::index::{self, IndexCheck, MismatchAction};
+use mcbd_world_sync::manifest::Manifest;
+use mcbd_world_sync::migration;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+fn worlds_dir() -> tempfile::TempDir {
+    let dir = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join("Alpha/db")).unwrap();
+    fs::create_dir_all(dir.path().join("Beta")).unwrap();
+    for (name, content) in [("Alpha/level.dat", "alpha"), ("Alpha/db/1.ldb", "chunks"), ("Beta/level.dat", "beta")] {
+        fs::write(dir.path().join(name), content).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn worlds_are_listed_before_their_files_are_hashed() {
+    let dir = worlds_dir();
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    assert_eq!(files.list_directory().unwrap(), (3, 3));
+
+    let index = files.index();
+    assert_eq!(index.worlds(), vec![
+        WorldSummary { name: "Alpha".to_string(), files: 2, size: 11, hash_pending: 2, unclean_shutdown: false },
+        WorldSummary { name: "Beta".to_string(), files: 1, size: 4, hash_pending: 1, unclean_shutdown: false },
+    ]);
+    // Nothing is offered to other devices before it is hashed
+    assert!(Manifest::from_files(&files.entries()).entries.is_empty());
+
+    let pending = index.pending_hashes();
+    let paths: Vec<PathBuf> = pending.iter().map(|f| f.path.clone()).collect();
+    for (file, hash) in pending.into_iter().zip(file_manager::hash_files(&index, &paths, 2)) {
+        assert!(index.set_hash(FileInfo { hash: hash.unwrap(), ..file }));
+    }
+    assert!(index.pending_hashes().is_empty());
+    assert_eq!(index.worlds()[0].hash_pending, 0);
+    let hash = &files.get_file_info(Path::new("Alpha/level.dat")).unwrap().hash;
+    assert_eq!(hash, &files.calculate_file_hash(Path::new("Alpha/level.dat")).unwrap());
+    assert_eq!(Manifest::from_files(&files.entries()).entries.len(), 3);
+}
+
+#[test]
+fn unchanged_files_keep_their_stored_hash() {
+    let dir = worlds_dir();
+    let mut scanned = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    scanned.scan_directory().unwrap();
+
+    // As after a restart with the stored index loaded
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    files.load_entries(scanned.entries());
+    fs::write(dir.path().join("Beta/level.dat"), "beta, changed").unwrap();
+    assert_eq!(files.list_directory().unwrap(), (3, 1));
+    assert_eq!(files.index().pending_hashes()[0].path, Path::new("Beta/level.dat"));
+
+    // A hash computed before the file changed again is not stored
+    let stale = files.index().pending_hashes().remove(0);
+    fs::write(dir.path().join("Beta/level.dat"), "beta, changed again").unwrap();
+    files.list_directory().unwrap();
+    assert!(!files.index().set_hash(FileInfo { hash: "stale".to_string(), ..stale }));
+}
+
+#[test]
+fn lazily_hashed_files_are_hashed_when_compared_or_sent() {
+    let dir = worlds_dir();
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[])).with_hash_policy(HashPolicy::Lazy);
+    files.list_directory().unwrap();
+    let index = files.index();
+    assert_eq!(index.hash_policy(), HashPolicy::Lazy);
+
+    // Sending a file hashes what was read
+    assert_eq!(index.get_file_content(Path::new("Alpha/level.dat")).unwrap(), b"alpha");
+    let sent = index.get(Path::new("Alpha/level.dat")).unwrap();
+    assert_eq!(sent.hash, files.calculate_file_hash(Path::new("Alpha/level.dat")).unwrap());
+
+    // Comparing with a received copy hashes the local one
+    assert!(!files.receive_file(Path::new("Beta/level.dat"), b"beta").unwrap());
+    assert!(!index.get(Path::new("Beta/level.dat")).unwrap().hash_pending());
+    // Or when a peer asks for the listing of its world
+    let generation = index.generation();
+    assert_eq!(index.hashed(Path::new("Alpha/db/1.ldb")).unwrap().unwrap().size, 6);
+    assert!(index.pending_hashes().is_empty());
+    assert!(index.generation() > generation);
+}
+
+#[test]
+fn hybrid_hashing_leaves_only_large_files_for_later() {
+    assert!(HashPolicy::Eager.hashes_eagerly(u64::MAX));
+    assert!(!HashPolicy::Lazy.hashes_eagerly(0));
+    assert!(HashPolicy::Hybrid.hashes_eagerly(HYBRID_LIMIT));
+    assert!(!HashPolicy::Hybrid.hashes_eagerly(HYBRID_LIMIT + 1));
+
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "server": { "port": 0, "host": "127.0.0.1" },
+        "sync": { "devices": [], "conflict_resolution": "newest", "sync_interval": 60 },
+        "paths": { "minecraft_worlds": "worlds", "hashing": "hybrid" }
+    })).unwrap();
+    assert_eq!(config.paths.hashing, HashPolicy::Hybrid);
+}
+
+#[test]
+fn stored_indexes_are_checked_against_the_worlds_directory() {
+    let dir = worlds_dir();
+    let state = tempfile::TempDir::new().unwrap();
+    let index_file = state.path().join("index.json");
+    assert_eq!(index::check(index::load(&index_file).unwrap().as_ref(), dir.path()), IndexCheck::Missing);
+
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    index::save(&index_file, dir.path(), 3, files.scan_directory().unwrap(), Vec::new()).unwrap();
+    let stored = index::load(&index_file).unwrap().unwrap();
+    assert_eq!(stored.root_fingerprint, index::root_fingerprint(dir.path()));
+    assert_eq!(index::check(Some(&stored), dir.path()), IndexCheck::Matches);
+
+    // The same worlds copied elsewhere are another root
+    let moved = worlds_dir();
+    assert_ne!(index::root_fingerprint(moved.path()), stored.root_fingerprint);
+    assert_eq!(index::check(Some(&stored), moved.path()), IndexCheck::Mismatch { stored_root: dir.path().to_path_buf() });
+}
+
+#[test]
+fn mismatched_indexes_are_migrated_or_rebuilt_as_answered() {
+    let root = Path::new("/new/worlds");
+    assert_eq!(index::ask_mismatch(root, Cursor::new("maybe\nm\n")).unwrap(), MismatchAction::Migrate);
+    assert_eq!(index::ask_mismatch(root, Cursor::new("Rebuild\n")).unwrap(), MismatchAction::Rebuild);
+    assert!(index::ask_mismatch(root, Cursor::new("q\n")).is_err());
+    // Nobody left to answer
+    assert!(index::ask_mismatch(root, Cursor::new("later\n")).is_err());
+
+    // A migrated index is saved for the new root and matches it from then on
+    let (old, new) = (worlds_dir(), worlds_dir());
+    let state = tempfile::TempDir::new().unwrap();
+    let index_file = state.path().join("index.json");
+    let mut files = FileManager::new(old.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    index::save(&index_file, old.path(), 5, files.scan_directory().unwrap(), Vec::new()).unwrap();
+    let stored = index::load(&index_file).unwrap().unwrap();
+    assert!(matches!(index::check(Some(&stored), new.path()), IndexCheck::Mismatch { .. }));
+    index::save(&index_file, new.path(), stored.generation, stored.files, stored.tombstones).unwrap();
+    let migrated = index::load(&index_file).unwrap().unwrap();
+    assert_eq!((migrated.generation, migrated.files.len()), (5, 3));
+    assert_eq!(index::check(Some(&migrated), new.path()), IndexCheck::Matches);
+}
+
+#[test]
+fn legacy_state_folders_leave_the_stored_index() {
+    let dir = worlds_dir();
+    let state = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join(".mcbd-trash/Alpha")).unwrap();
+    fs::write(dir.path().join(".mcbd-trash/Alpha/level.dat"), "deleted").unwrap();
+    // Indexed by a version that did not exclude it yet
+    let mut old = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    let mut entries = old.scan_directory().unwrap();
+    entries.push(FileInfo { path: PathBuf::from(".mcbd-trash/Alpha/level.dat"), last_modified: SystemTime::now(), size: 7, hash: "deleted".to_string() });
+    let index_file = state.path().join("index.json");
+    index::save(&index_file, dir.path(), 1, entries, Vec::new()).unwrap();
+
+    // As on startup: the stored index is loaded before the folders are moved out
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    files.load_entries(index::load(&index_file).unwrap().unwrap().files);
+    let app_dirs = AppDirs::at(state.path().to_path_buf());
+    assert_eq!(migration::migrate_legacy_state(dir.path(), &app_dirs, &mut files).unwrap(), 1);
+    assert!(!dir.path().join(".mcbd-trash").exists());
+    assert_eq!(fs::read_to_string(app_dirs.trash().join("Alpha/level.dat")).unwrap(), "deleted");
+    assert!(files.get_file_info(Path::new(".mcbd-trash/Alpha/level.dat")).is_none());
+    assert_eq!(files.entries().len(), 3);
+}
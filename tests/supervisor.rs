@@ -0,0 +1,55 @@
+//! Daemon tasks started again after they fail, and transfers to each peer
+//! handled apart from the others.
+
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::supervisor::{self, restart_delay};
+use mcbd_world_sync::transfer_queue::TransferQueue;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+#[tokio::test]
+async fn failed_and_panicked_tasks_are_restarted_until_they_finish() {
+    let runs = Arc::new(AtomicU32::new(0));
+    let counted = runs.clone();
+    let supervised = supervisor::supervise("flaky", move || {
+        let runs = counted.clone();
+        async move {
+            match runs.fetch_add(1, Ordering::SeqCst) {
+                0 => panic!("first run crashes"),
+                1 => anyhow::bail!("second run fails"),
+                _ => Ok(()),
+            }
+        }
+    });
+    tokio::time::timeout(Duration::from_secs(10), supervised).await.unwrap().unwrap();
+    assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+    assert_eq!(restart_delay(1), Duration::from_secs(1));
+    assert_eq!(restart_delay(3), Duration::from_secs(4));
+    assert_eq!(restart_delay(40), Duration::from_secs(60));
+}
+
+#[tokio::test]
+async fn a_peers_worker_only_takes_its_own_transfers() {
+    let queue = Arc::new(TransferQueue::new(Arc::new(Metrics::new())));
+    queue.push("laptop".to_string(), PathBuf::from("w/db/1.ldb"), "Modify".to_string()).await;
+    queue.push("nas".to_string(), PathBuf::from("w/level.dat"), "Modify".to_string()).await;
+
+    // The laptop's transfer stays queued while the NAS is served
+    let nas = queue.pop_for("nas").await;
+    assert_eq!(nas.path, PathBuf::from("w/level.dat"));
+    assert_eq!(queue.len_for("laptop").await, 1);
+
+    // A worker waiting for its peer wakes up when something is queued for it
+    let waiting = tokio::spawn({
+        let queue = queue.clone();
+        async move { queue.pop_for("nas").await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    queue.push("nas".to_string(), PathBuf::from("w/db/2.ldb"), "Modify".to_string()).await;
+    let woken = tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap();
+    assert_eq!(woken.path, PathBuf::from("w/db/2.ldb"));
+    assert_eq!(queue.pop_for("laptop").await.path, PathBuf::from("w/db/1.ldb"));
+}
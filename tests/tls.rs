@@ -0,0 +1,67 @@
+//! Sync connections encrypted with TLS, accepted only from peers whose
+//! certificate fingerprint is trusted.
+
+mod common;
+
+use common::receiver::{files, start_receiver};
+use mcbd_world_sync::config::TlsConfig;
+use mcbd_world_sync::network::SyncClient;
+use mcbd_world_sync::tls::{self, Identity};
+use mcbd_world_sync::transfer_queue::Priority;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Starts a TLS-only receiver and returns its address and fingerprint.
+async fn start_tls_receiver(worlds: PathBuf, state: &Path) -> (String, String) {
+    let identity = Identity::load(&TlsConfig::default(), state).unwrap();
+    let acceptor = identity.acceptor().unwrap();
+    let address = start_receiver(Arc::new(Mutex::new(files(worlds))), |server| server.with_tls(Some(acceptor))).await;
+    (address, identity.fingerprint())
+}
+
+#[test]
+fn generated_certificates_are_kept() {
+    let state = tempfile::TempDir::new().unwrap();
+    let first = Identity::load(&TlsConfig::default(), state.path()).unwrap();
+    let again = Identity::load(&TlsConfig::default(), state.path()).unwrap();
+    assert_eq!(first.fingerprint(), again.fingerprint());
+    assert_eq!(first.fingerprint().len(), 64);
+
+    let half = TlsConfig { cert: Some(state.path().join("tls-cert.pem").display().to_string()), ..TlsConfig::default() };
+    assert!(Identity::load(&half, state.path()).is_err());
+}
+
+#[tokio::test]
+async fn trusted_peers_sync_over_tls() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    let (address, fingerprint) = start_tls_receiver(worlds.path().to_path_buf(), state.path()).await;
+
+    // Fingerprints may be written with colons and in upper case
+    let pretty = fingerprint.to_uppercase().as_bytes().chunks(2).map(|b| String::from_utf8_lossy(b).into_owned()).collect::<Vec<_>>().join(":");
+    let client = SyncClient::new(address).with_tls(Some(tls::connector(&[pretty]).unwrap()));
+    let path = PathBuf::from("World/level.dat");
+    client.send_file_content(path.clone(), vec![3u8; 100 * 1024], None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(worlds.path().join(&path)).unwrap(), vec![3u8; 100 * 1024]);
+}
+
+#[tokio::test]
+async fn untrusted_and_plain_connections_are_refused() {
+    let worlds = tempfile::TempDir::new().unwrap();
+    let state = tempfile::TempDir::new().unwrap();
+    let (address, _) = start_tls_receiver(worlds.path().to_path_buf(), state.path()).await;
+    let path = PathBuf::from("World/level.dat");
+
+    let other = tempfile::TempDir::new().unwrap();
+    let stranger = Identity::load(&TlsConfig::default(), other.path()).unwrap().fingerprint();
+    let untrusted = SyncClient::new(address.clone()).with_tls(Some(tls::connector(&[stranger]).unwrap()));
+    assert!(untrusted.send_file_content(path.clone(), b"level".to_vec(), None, Priority::Background).await.is_err());
+
+    let plain = SyncClient::new(address);
+    let sent = tokio::time::timeout(Duration::from_secs(5), plain.send_file_content(path.clone(), b"level".to_vec(), None, Priority::Background)).await;
+    assert!(!matches!(sent, Ok(Ok(_))));
+    assert!(!worlds.path().join(&path).exists());
+}
@@ -0,0 +1,172 @@
+//! The sync server accepting the same protocol over TCP, WebSocket and QUIC.
+
+mod common;
+
+use common::daemon::free_port;
+use futures::{SinkExt, StreamExt};
+use mcbd_world_sync::config::{ListenersConfig, PortConfig, QuicConfig, TlsConfig};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::{SyncClient, SyncMessage, SyncServer};
+use mcbd_world_sync::tls::{self, Identity};
+use mcbd_world_sync::transfer_queue::Priority;
+use mcbd_world_sync::transport::QUIC_ALPN;
+use quinn::crypto::rustls::QuicClientConfig;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::bytes::Bytes;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+fn register(id: &str) -> Bytes {
+    Bytes::from(serde_json::to_vec(&SyncMessage::RendezvousRegister { id: id.to_string(), port: 9000 }).unwrap())
+}
+
+fn lookup(id: &str) -> Bytes {
+    Bytes::from(serde_json::to_vec(&SyncMessage::RendezvousLookup { id: id.to_string() }).unwrap())
+}
+
+fn looked_up_address(reply: &[u8]) -> Option<String> {
+    match serde_json::from_slice(reply).unwrap() {
+        SyncMessage::RendezvousAddress { address, .. } => address,
+        other => panic!("unexpected reply {:?}", other),
+    }
+}
+
+/// Starts a server with every transport enabled and a certificate the
+/// QUIC client can trust. Returns the ports and the certificate.
+async fn start_server(dir: &TempDir) -> (ListenersConfig, rcgen::CertifiedKey<rcgen::KeyPair>) {
+    let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let (cert, key) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+    std::fs::write(&cert, generated.cert.pem()).unwrap();
+    std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+    let listeners = ListenersConfig {
+        tcp: Some(PortConfig { port: free_port() }),
+        quic: Some(QuicConfig {
+            port: free_port(),
+            cert: Some(cert.display().to_string()),
+            key: Some(key.display().to_string()),
+        }),
+        websocket: Some(PortConfig { port: free_port() }),
+    };
+    let health = Arc::new(Health::new());
+    let server = SyncServer::new(0).with_listeners(listeners.clone()).with_health(health.clone());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    for _ in 0..100 {
+        if health.pending() == ["index_loaded", "index_scanned", "roots_watched"] {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    (listeners, generated)
+}
+
+#[tokio::test]
+async fn all_transports_serve_the_sync_protocol() {
+    let dir = TempDir::new().unwrap();
+    let (listeners, generated) = start_server(&dir).await;
+
+    // TCP
+    let socket = TcpStream::connect(("127.0.0.1", listeners.tcp.unwrap().port)).await.unwrap();
+    let mut tcp = Framed::new(socket, LengthDelimitedCodec::new());
+    tcp.send(register("over-tcp")).await.unwrap();
+    tcp.send(lookup("over-tcp")).await.unwrap();
+    let reply = tcp.next().await.unwrap().unwrap();
+    assert_eq!(looked_up_address(&reply).as_deref(), Some("127.0.0.1:9000"));
+
+    // WebSocket, one frame per binary message
+    let url = format!("ws://127.0.0.1:{}", listeners.websocket.unwrap().port);
+    let (mut ws, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+    ws.send(Message::Binary(register("over-ws"))).await.unwrap();
+    ws.send(Message::Binary(lookup("over-ws"))).await.unwrap();
+    let reply = match ws.next().await.unwrap().unwrap() {
+        Message::Binary(data) => data,
+        other => panic!("unexpected message {:?}", other),
+    };
+    assert_eq!(looked_up_address(&reply).as_deref(), Some("127.0.0.1:9000"));
+
+    // QUIC, length-delimited frames on a bidirectional stream
+    let mut roots = rustls::RootCertStore::empty();
+    roots.add(generated.cert.der().clone()).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut tls = rustls::ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+    let mut endpoint = quinn::Endpoint::client(SocketAddr::from(([127, 0, 0, 1], 0))).unwrap();
+    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls).unwrap())));
+    let server_addr = SocketAddr::from(([127, 0, 0, 1], listeners.quic.unwrap().port));
+    let connection = endpoint.connect(server_addr, "localhost").unwrap().await.unwrap();
+    let (send, recv) = connection.open_bi().await.unwrap();
+    let mut quic = Framed::new(tokio::io::join(recv, send), LengthDelimitedCodec::new());
+    quic.send(register("over-quic")).await.unwrap();
+    quic.send(lookup("over-quic")).await.unwrap();
+    let reply = quic.next().await.unwrap().unwrap();
+    assert_eq!(looked_up_address(&reply).as_deref(), Some("127.0.0.1:9000"));
+
+    // Registrations are shared between transports
+    tcp.send(lookup("over-quic")).await.unwrap();
+    let reply = tcp.next().await.unwrap().unwrap();
+    assert_eq!(looked_up_address(&reply).as_deref(), Some("127.0.0.1:9000"));
+}
+
+/// Starts a QUIC-only receiver writing into `worlds`, with TLS when there
+/// is an identity, and returns its `quic://` address.
+async fn start_quic_receiver(worlds: PathBuf, identity: Option<&Identity>) -> String {
+    let port = free_port();
+    let listeners = ListenersConfig { quic: Some(QuicConfig { port, cert: None, key: None }), ..ListenersConfig::default() };
+    let health = Arc::new(Health::new());
+    let files = Arc::new(tokio::sync::Mutex::new(FileManager::new(worlds).with_exclusions(Exclusions::new(&[]))));
+    let server = SyncServer::new(0)
+        .with_listeners(listeners)
+        .with_health(health.clone())
+        .with_file_manager(files)
+        .with_tls(identity.map(|identity| identity.acceptor().unwrap()));
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    format!("quic://127.0.0.1:{}", port)
+}
+
+#[tokio::test]
+async fn clients_send_files_side_by_side_over_one_quic_connection() {
+    let worlds = TempDir::new().unwrap();
+    let client = SyncClient::new(start_quic_receiver(worlds.path().to_path_buf(), None).await);
+    let paths: Vec<PathBuf> = (0..4).map(|n| PathBuf::from(format!("World/db/00000{}.ldb", n))).collect();
+    let sends = paths.iter().map(|path| client.send_file_content(path.clone(), vec![path.to_string_lossy().len() as u8; 512 * 1024], None, Priority::Background));
+    for sent in futures::future::join_all(sends).await {
+        sent.unwrap();
+    }
+    for path in &paths {
+        assert_eq!(fs::read(worlds.path().join(path)).unwrap().len(), 512 * 1024);
+    }
+}
+
+#[tokio::test]
+async fn quic_peers_pin_the_tls_certificate() {
+    let worlds = TempDir::new().unwrap();
+    let state = TempDir::new().unwrap();
+    let identity = Identity::load(&TlsConfig::default(), state.path()).unwrap();
+    let address = start_quic_receiver(worlds.path().to_path_buf(), Some(&identity)).await;
+    let path = PathBuf::from("World/level.dat");
+
+    let other = TempDir::new().unwrap();
+    let stranger = Identity::load(&TlsConfig::default(), other.path()).unwrap().fingerprint();
+    let untrusted = SyncClient::new(address.clone()).with_tls(Some(tls::connector(&[stranger]).unwrap()));
+    assert!(untrusted.send_file_content(path.clone(), b"level".to_vec(), None, Priority::Background).await.is_err());
+    assert!(!worlds.path().join(&path).exists());
+
+    let trusted = SyncClient::new(address).with_tls(Some(tls::connector(&[identity.fingerprint()]).unwrap()));
+    trusted.send_file_content(path.clone(), b"level".to_vec(), None, Priority::Background).await.unwrap();
+    assert_eq!(fs::read(worlds.path().join(&path)).unwrap(), b"level");
+}
@@ -0,0 +1,76 @@
+//! One-time upload links of the local HTTP server, whose .mcworld is
+//! imported as a new world.
+
+mod common;
+
+use common::daemon::tree_contents;
+use common::http;
+use mcbd_world_sync::http::{HttpState, WorldLinks};
+use mcbd_world_sync::links::Links;
+use mcbd_world_sync::mcworld;
+use mcbd_world_sync::metrics::Metrics;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// A complete world, as exported by Minecraft.
+fn world(root: &Path) {
+    fs::create_dir_all(root.join("Skyblock/db")).unwrap();
+    fs::write(root.join("Skyblock/level.dat"), b"level").unwrap();
+    fs::write(root.join("Skyblock/levelname.txt"), b"Sky Block\n").unwrap();
+    fs::write(root.join("Skyblock/db/CURRENT"), b"MANIFEST-000002").unwrap();
+    fs::write(root.join("Skyblock/db/000005.ldb"), vec![7u8; 5000]).unwrap();
+}
+
+/// A deflated zip like the ones Minecraft exports.
+fn zipped(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    for (name, bytes) in files {
+        zip.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+        zip.write_all(bytes).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+#[test]
+fn imports_only_complete_worlds_inside_their_folder() {
+    let dir = tempfile::TempDir::new().unwrap();
+
+    // Exports that wrap the world in a folder are unpacked from inside it
+    let archive = zipped(&[("Robin's Build/level.dat", b"level"), ("Robin's Build/db/CURRENT", b"MANIFEST-1")]);
+    assert_eq!(mcworld::import(&archive, &dir.path().join("Build")).unwrap(), 2);
+    assert_eq!(fs::read(dir.path().join("Build/db/CURRENT")).unwrap(), b"MANIFEST-1");
+    assert!(mcworld::import(&archive, &dir.path().join("Build")).is_err());
+
+    let escaping = zipped(&[("level.dat", b"level"), ("../evil.txt", b"evil")]);
+    assert!(mcworld::import(&escaping, &dir.path().join("Escape")).is_err());
+    assert!(!dir.path().join("evil.txt").exists());
+    assert!(mcworld::import(&zipped(&[("notes.txt", b"hi")]), &dir.path().join("Notes")).is_err());
+    assert!(mcworld::import(b"not a zip", &dir.path().join("Garbage")).is_err());
+    assert!(!dir.path().join("Escape").exists() && !dir.path().join("Notes").exists());
+}
+
+#[tokio::test]
+async fn upload_link_imports_one_world() {
+    let dir = tempfile::TempDir::new().unwrap();
+    world(&dir.path().join("source"));
+    let mut archive = Vec::new();
+    mcworld::write(&dir.path().join("source/Skyblock"), &mut archive).unwrap();
+
+    let worlds = dir.path().join("worlds");
+    let links = Links::new(dir.path().join("uploads.json"));
+    let link = links.create("From Robin", Duration::from_secs(3600), SystemTime::now()).unwrap();
+    let port = http::serve(HttpState { uploads: Some(WorldLinks { links, worlds: worlds.clone() }), ..http::state(dir.path(), Arc::new(Metrics::new())) }).await;
+    let url = format!("/upload/{}", link.token);
+
+    assert_eq!(http::get(port, &url).await.0, 200);
+    // A broken upload does not use up the link
+    assert_eq!(http::request(port, "POST", &url, b"not a world").await.0, 400);
+    assert_eq!(http::request(port, "POST", &url, &archive).await.0, 201);
+    assert_eq!(tree_contents(&worlds.join("From Robin")), tree_contents(&dir.path().join("source/Skyblock")));
+
+    assert_eq!(http::request(port, "POST", &url, &archive).await.0, 404);
+    assert_eq!(http::get(port, &url).await.0, 404);
+}
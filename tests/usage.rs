@@ -0,0 +1,58 @@
+//! What the `stats` command reports: bytes sent per day and the space
+//! snapshots take.
+
+use mcbd_world_sync::snapshots::Snapshots;
+use mcbd_world_sync::usage::{self, Usage, UsageLog};
+use std::fs;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY: Duration = Duration::from_secs(86400);
+
+#[test]
+fn usage_is_summed_over_the_last_days() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let log = UsageLog::new(dir.path().join("usage.json"));
+    let now = UNIX_EPOCH + DAY * 1000;
+    let day = Usage { size: 1000, carried: 100, encoded: 120, wire: 60, files: 4, failed: 1 };
+    log.record(&day, now - DAY * 200).unwrap();
+    log.record(&day, now - DAY * 40).unwrap();
+    log.record(&day, now - DAY * 29).unwrap();
+    log.record(&day, now).unwrap();
+    log.record(&day, now).unwrap();
+
+    let month = log.last(30, now).unwrap();
+    assert_eq!(month, Usage { size: 3000, carried: 300, encoded: 360, wire: 180, files: 12, failed: 3 });
+    assert_eq!((month.spared_by_deltas(), month.spared_by_compression()), (2700, 180));
+    assert_eq!(log.last(1, now).unwrap().size, 2000);
+    // Days beyond the kept ones are dropped as new ones are recorded
+    assert_eq!(log.last(1000, now).unwrap().size, 4000);
+
+    let later = Usage { size: 1500, carried: 150, encoded: 170, wire: 80, files: 6, failed: 1 };
+    assert_eq!(later.since(&day), Usage { size: 500, carried: 50, encoded: 50, wire: 20, files: 2, failed: 0 });
+}
+
+#[test]
+fn sizes_are_shown_in_readable_units() {
+    assert_eq!(usage::format_bytes(512), "512 bytes");
+    assert_eq!(usage::format_bytes(1536), "1.5 KiB");
+    assert_eq!(usage::format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+}
+
+#[test]
+fn hard_linked_tables_are_counted_once() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    fs::create_dir_all(worlds.join("World/db")).unwrap();
+    fs::write(worlds.join("World/level.dat"), [0u8; 100]).unwrap();
+    fs::write(worlds.join("World/db/000005.ldb"), [1u8; 1000]).unwrap();
+    let snapshots = Snapshots::new(dir.path().join("snapshots"));
+    snapshots.take(&worlds, "World", "test", SystemTime::now()).unwrap();
+    snapshots.take(&worlds, "World", "again", SystemTime::now() + Duration::from_secs(1)).unwrap();
+    assert_eq!(usage::folder_size(&worlds).unwrap(), 1100);
+
+    let taken = snapshots.usage(&worlds).unwrap();
+    assert_eq!((taken.snapshots, taken.size, taken.stored), (2, 2200, 200));
+    // Once the world compacted the table away, the snapshots hold the only copy
+    fs::remove_file(worlds.join("World/db/000005.ldb")).unwrap();
+    assert_eq!(snapshots.usage(&worlds).unwrap().stored, 1200);
+}
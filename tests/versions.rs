@@ -0,0 +1,118 @@
+//! Version vectors: which of two versions of a file is newer, or whether
+//! both were changed, told without trusting the clocks of either device.
+
+mod common;
+
+use common::daemon::free_port;
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::network::{FileVersion, SyncClient, SyncServer};
+use mcbd_world_sync::transfer_queue::{Priority, TransferQueue};
+use mcbd_world_sync::versions::{Order, VersionVector, Versions};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
+
+fn vector(counts: &[(&str, u64)]) -> VersionVector {
+    VersionVector(counts.iter().map(|(device, count)| (device.to_string(), *count)).collect::<BTreeMap<_, _>>())
+}
+
+#[test]
+fn vectors_tell_newer_versions_from_concurrent_ones() {
+    let base = vector(&[("desktop", 2), ("laptop", 1)]);
+    let mut edited = base.clone();
+    edited.bump("laptop");
+    assert_eq!(edited, vector(&[("desktop", 2), ("laptop", 2)]));
+    assert_eq!(base.order(&edited), Order::Older);
+    assert_eq!(edited.order(&base), Order::Newer);
+    assert_eq!(base.order(&base.clone()), Order::Same);
+    // Devices a version has not seen count as no changes
+    assert_eq!(VersionVector::default().order(&base), Order::Older);
+
+    let mut elsewhere = base.clone();
+    elsewhere.bump("desktop");
+    assert_eq!(edited.order(&elsewhere), Order::Concurrent);
+    edited.merge(&elsewhere);
+    assert_eq!(edited, vector(&[("desktop", 3), ("laptop", 2)]));
+    assert_eq!(edited.order(&elsewhere), Order::Newer);
+}
+
+#[test]
+fn the_journal_is_replayed_and_compacted() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let file = dir.path().join("versions.jsonl");
+    let versions = Versions::open(file.clone(), "desktop").unwrap();
+    let path = Path::new("World/level.dat");
+    versions.changed(path).unwrap();
+    versions.received(path, &vector(&[("laptop", 4)]), false).unwrap();
+    assert_eq!(versions.received(path, &vector(&[("laptop", 3)]), true).unwrap(), vector(&[("desktop", 2), ("laptop", 4)]));
+    // A record cut short by a crash is skipped
+    fs::write(&file, fs::read_to_string(&file).unwrap() + "{\"path\":\"World/db/CURR").unwrap();
+
+    let replayed = Versions::open(file.clone(), "desktop").unwrap();
+    assert_eq!(replayed.get(path), Some(vector(&[("desktop", 2), ("laptop", 4)])));
+    assert_eq!(replayed.records(), 3);
+    for _ in 0..1000 {
+        replayed.changed(Path::new("World/db/CURRENT")).unwrap();
+    }
+    // Only the last record of each file is kept
+    assert_eq!(replayed.records(), 2);
+    assert_eq!(fs::read_to_string(&file).unwrap().lines().count(), 2);
+    assert_eq!(Versions::open(file, "desktop").unwrap().get(Path::new("World/db/CURRENT")), Some(vector(&[("desktop", 1000)])));
+}
+
+#[tokio::test]
+async fn versions_settle_conflicts_whatever_the_clocks_say() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    fs::create_dir_all(worlds.join("Skyblock/db")).unwrap();
+    for file in ["level.dat", "levelname.txt", "db/CURRENT"] {
+        fs::write(worlds.join("Skyblock").join(file), "edited here").unwrap();
+    }
+    let mut files = FileManager::new(worlds.clone()).with_exclusions(Exclusions::new(&[]));
+    files.scan_directory().unwrap();
+    let versions = Versions::open(dir.path().join("versions.jsonl"), "desktop").unwrap();
+    // level.dat was edited here after the laptop's version reached it
+    versions.received(Path::new("Skyblock/level.dat"), &vector(&[("laptop", 1)]), false).unwrap();
+    versions.changed(Path::new("Skyblock/level.dat")).unwrap();
+    // The laptop edited levelname.txt after this device's edit reached it
+    versions.changed(Path::new("Skyblock/levelname.txt")).unwrap();
+    // Both edited CURRENT
+    versions.changed(Path::new("Skyblock/db/CURRENT")).unwrap();
+    // Changes to the world are still queued for the laptop
+    let queue = Arc::new(TransferQueue::new(Arc::new(Metrics::new())));
+    queue.push("laptop".to_string(), PathBuf::from("Skyblock/levelname.txt"), "Modify".to_string()).await;
+
+    let (port, health) = (free_port(), Arc::new(Health::new()));
+    let server = SyncServer::new(port).with_health(health.clone()).with_device_name("desktop".to_string())
+        .with_file_manager(Arc::new(Mutex::new(files))).with_conflict_detection(queue.clone()).with_versions(versions.clone());
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // The laptop's clock runs a day ahead, or a day behind
+    let client = SyncClient::new(format!("127.0.0.1:{}", port)).with_device_name("laptop".to_string());
+    let day = Duration::from_secs(86400);
+    let (ahead, behind) = (Some(SystemTime::now() + day), Some(SystemTime::now() - day));
+    let send = |path: &str, content: &str, modified, counts: &[(&str, u64)]| {
+        let version = FileVersion { modified, vector: Some(vector(counts)) };
+        client.send_file_version(PathBuf::from(path), content.as_bytes().to_vec(), version, None, Priority::Background)
+    };
+    send("Skyblock/level.dat", "old laptop version", ahead, &[("laptop", 1)]).await.unwrap();
+    send("Skyblock/levelname.txt", "newer laptop version", behind, &[("desktop", 1), ("laptop", 1)]).await.unwrap();
+    send("Skyblock/db/CURRENT", "laptop version", ahead, &[("laptop", 1)]).await.unwrap();
+
+    assert_eq!(fs::read_to_string(worlds.join("Skyblock/level.dat")).unwrap(), "edited here");
+    assert_eq!(fs::read_to_string(worlds.join("Skyblock/levelname.txt")).unwrap(), "newer laptop version");
+    // A true conflict, which the newest version wins
+    assert_eq!(fs::read_to_string(worlds.join("Skyblock/db/CURRENT")).unwrap(), "laptop version");
+    assert!(queue.is_queued("laptop", Path::new("Skyblock/level.dat")).await);
+    assert_eq!(versions.get(Path::new("Skyblock/levelname.txt")), Some(vector(&[("desktop", 1), ("laptop", 1)])));
+    assert_eq!(versions.get(Path::new("Skyblock/db/CURRENT")), Some(vector(&[("desktop", 1), ("laptop", 1)])));
+}
@@ -0,0 +1,185 @@
+//! Watcher events handed to the sync engine through a bounded channel.
+
+use mcbd_world_sync::churn::Churn;
+use mcbd_world_sync::config::{HashPolicy, WatchConfig};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::leveldb;
+use mcbd_world_sync::metrics::Metrics;
+use mcbd_world_sync::watcher::{self, WatchEvent, MAX_COALESCED};
+use notify::event::{AccessKind, CreateKind, DataChange, ModifyKind};
+use notify::{Event, EventHandler, EventKind};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+fn modified(path: &str) -> notify::Result<Event> {
+    Ok(Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any))).add_path(PathBuf::from(path)))
+}
+
+fn changed_paths(event: Option<WatchEvent>) -> Vec<PathBuf> {
+    match event {
+        Some(WatchEvent::Change { paths, .. }) => paths,
+        other => panic!("expected a change, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn events_beyond_the_bound_are_coalesced_per_path() {
+    let metrics = Arc::new(Metrics::new());
+    let config = WatchConfig { event_queue: 2, ..WatchConfig::default() };
+    let (mut sender, mut receiver) = watcher::channel(&config, metrics.clone());
+
+    sender.handle_event(Ok(Event::new(EventKind::Access(AccessKind::Any)).add_path(PathBuf::from("w/level.dat"))));
+    for path in ["w/level.dat", "w/db/1.ldb", "w/db/2.ldb", "w/db/3.ldb", "w/db/2.ldb", "w/db/2.ldb"] {
+        sender.handle_event(modified(path));
+    }
+    sender.handle_event(Ok(Event::new(EventKind::Create(CreateKind::File)).add_path(PathBuf::from("w/db/3.ldb"))));
+    assert_eq!(Metrics::get(&metrics.watch_events_coalesced), 3);
+
+    // Reads are filtered out, and the overflow follows what was queued first
+    let order: Vec<PathBuf> = [receiver.recv().await, receiver.recv().await, receiver.recv().await, receiver.recv().await]
+        .into_iter()
+        .flat_map(changed_paths)
+        .collect();
+    assert_eq!(order, ["w/level.dat", "w/db/1.ldb", "w/db/2.ldb", "w/db/3.ldb"].map(PathBuf::from));
+
+    // With the engine caught up, events go through the channel again
+    sender.handle_event(modified("w/db/4.ldb"));
+    assert_eq!(changed_paths(receiver.recv().await), [PathBuf::from("w/db/4.ldb")]);
+}
+
+#[tokio::test]
+async fn a_flood_of_events_turns_into_one_rescan() {
+    let metrics = Arc::new(Metrics::new());
+    let config = WatchConfig { event_queue: 1, ..WatchConfig::default() };
+    let (mut sender, mut receiver) = watcher::channel(&config, metrics.clone());
+
+    for n in 0..MAX_COALESCED + 2 {
+        sender.handle_event(modified(&format!("w/db/{}.ldb", n)));
+    }
+    sender.handle_event(modified("w/level.dat"));
+    assert_eq!(Metrics::get(&metrics.watch_events_dropped), 2);
+
+    assert_eq!(changed_paths(receiver.recv().await), [PathBuf::from("w/db/0.ldb")]);
+    assert!(matches!(receiver.recv().await, Some(WatchEvent::Rescan)));
+    sender.handle_event(modified("w/level.dat"));
+    assert_eq!(changed_paths(receiver.recv().await), [PathBuf::from("w/level.dat")]);
+}
+
+#[test]
+fn rescan_finds_files_changed_and_removed_while_unwatched() {
+    let dir = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join("w/db")).unwrap();
+    for name in ["w/level.dat", "w/db/1.ldb", "w/db/2.ldb"] {
+        fs::write(dir.path().join(name), name).unwrap();
+    }
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    files.scan_directory().unwrap();
+
+    fs::write(dir.path().join("w/level.dat"), "changed").unwrap();
+    fs::remove_file(dir.path().join("w/db/1.ldb")).unwrap();
+    fs::write(dir.path().join("w/db/3.ldb"), "new").unwrap();
+    let mut changed = files.rescan().unwrap();
+    changed.sort();
+    assert_eq!(changed, ["w/db/1.ldb", "w/db/3.ldb", "w/level.dat"].map(PathBuf::from));
+    assert!(files.get_file_info(Path::new("w/db/1.ldb")).is_none());
+    assert!(files.tombstones().iter().any(|t| t.path == Path::new("w/db/1.ldb")));
+    assert!(files.rescan().unwrap().is_empty());
+}
+
+#[test]
+fn polling_reads_only_files_whose_size_or_time_changed() {
+    let dir = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join("w/db")).unwrap();
+    for name in ["w/level.dat", "w/db/1.ldb", "w/db/2.ldb"] {
+        fs::write(dir.path().join(name), name).unwrap();
+    }
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]));
+    files.scan_directory().unwrap();
+    assert!(files.poll().unwrap().is_empty());
+
+    fs::write(dir.path().join("w/level.dat"), "changed").unwrap();
+    fs::remove_file(dir.path().join("w/db/1.ldb")).unwrap();
+    fs::write(dir.path().join("w/db/3.ldb"), "new").unwrap();
+    let mut changed = files.poll().unwrap();
+    changed.sort();
+    assert_eq!(changed, ["w/db/1.ldb", "w/db/3.ldb", "w/level.dat"].map(PathBuf::from));
+    assert!(files.tombstones().iter().any(|t| t.path == Path::new("w/db/1.ldb")));
+    let hash = &files.get_file_info(Path::new("w/level.dat")).unwrap().hash;
+    assert_eq!(hash, &files.calculate_file_hash(Path::new("w/level.dat")).unwrap());
+    assert!(files.poll().unwrap().is_empty());
+}
+
+#[test]
+fn polling_leaves_lazily_hashed_files_pending() {
+    let dir = tempfile::TempDir::new().unwrap();
+    fs::create_dir_all(dir.path().join("w")).unwrap();
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[])).with_hash_policy(HashPolicy::Lazy);
+    fs::write(dir.path().join("w/level.dat"), "level").unwrap();
+    assert_eq!(files.poll().unwrap(), [PathBuf::from("w/level.dat")]);
+    assert!(files.get_file_info(Path::new("w/level.dat")).unwrap().hash_pending());
+    assert!(files.poll().unwrap().is_empty());
+}
+
+#[test]
+fn files_minecraft_touches_while_a_world_is_open_are_ignored() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let worlds = dir.path().join("worlds");
+    fs::create_dir_all(worlds.join("w/db")).unwrap();
+    fs::create_dir_all(worlds.join("w/cache")).unwrap();
+    for file in ["w/level.dat", "w/db/CURRENT", "w/db/000005.ldb", "w/db/LOCK", "w/db/LOG", "w/db/LOG.old", "w/db/000006.dbtmp", "w/cache/icon.png", "w/save.tmp"] {
+        fs::write(worlds.join(file), file).unwrap();
+    }
+    let ignore: Vec<String> = WatchConfig::default().ignore.into_iter().chain(["cache".to_string()]).collect();
+    let exclusions = Exclusions::new(&[]).with_ignore(&ignore).unwrap();
+    let mut files = FileManager::new(worlds.clone()).with_exclusions(exclusions.clone());
+    files.scan_directory().unwrap();
+
+    let mut indexed: Vec<PathBuf> = files.entries().into_iter().map(|entry| entry.path).collect();
+    indexed.sort();
+    assert_eq!(indexed, ["w/db/000005.ldb", "w/db/CURRENT", "w/level.dat"].map(PathBuf::from));
+    // As the watcher checks its events
+    assert!(exclusions.is_excluded(&worlds, &worlds.join("w/db/LOCK")));
+    assert!(exclusions.is_excluded(&worlds, &worlds.join("w/cache/new.png")));
+    // A `*` does not reach into other directories
+    assert!(!exclusions.is_excluded(&worlds, &worlds.join("w/sub/db/LOCK")));
+    assert!(!exclusions.is_excluded(&worlds, &worlds.join("w/db/LOCKED")));
+    assert!(Exclusions::new(&[]).with_ignore(&["w/[".to_string()]).is_err());
+}
+
+#[test]
+fn recovery_leftovers_are_not_synced_but_reported() {
+    let dir = tempfile::TempDir::new().unwrap();
+    for world in ["Clean", "Crashed"] {
+        fs::create_dir_all(dir.path().join(world).join("db")).unwrap();
+        fs::write(dir.path().join(world).join("db/000005.ldb"), world).unwrap();
+    }
+    fs::create_dir_all(dir.path().join("Crashed/db/lost")).unwrap();
+    fs::write(dir.path().join("Crashed/db/lost/000003.log"), "unreadable").unwrap();
+    let exclusions = Exclusions::new(&[]).with_ignore(&WatchConfig::default().ignore).unwrap();
+    let mut files = FileManager::new(dir.path().to_path_buf()).with_exclusions(exclusions.clone());
+    files.scan_directory().unwrap();
+
+    assert!(files.get_file_info(Path::new("Crashed/db/lost/000003.log")).is_none());
+    assert!(exclusions.is_excluded(dir.path(), &dir.path().join("Crashed/db/lost")));
+    assert!(leveldb::is_lost_dir(Path::new("Crashed/db/lost")));
+    let unclean: Vec<(String, bool)> = files.index().worlds().into_iter().map(|world| (world.name, world.unclean_shutdown)).collect();
+    assert_eq!(unclean, [("Clean".to_string(), false), ("Crashed".to_string(), true)]);
+}
+
+#[test]
+fn changes_to_low_value_files_wait_for_a_real_change_of_their_world() {
+    let mut churn = Churn::new(&WatchConfig::default().low_value).unwrap();
+    assert!(!churn.hold(Path::new("w/level.dat")));
+    assert!(churn.hold(Path::new("w/level.dat_old")));
+    assert!(churn.hold(Path::new("w/world_icon.jpeg")));
+    assert!(churn.hold(Path::new("v/level.dat_old")));
+    assert_eq!(churn.held(), 3);
+
+    assert_eq!(churn.release(Path::new("w/db/000005.ldb")), ["w/level.dat_old", "w/world_icon.jpeg"].map(PathBuf::from));
+    assert!(churn.release(Path::new("w/db/000006.ldb")).is_empty());
+    assert_eq!(churn.held(), 1);
+    assert!(!churn.is_low_value(Path::new("w/db/level.dat_old/000005.ldb")));
+    assert!(Churn::new(&["w/[".to_string()]).is_err());
+}
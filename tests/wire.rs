@@ -0,0 +1,72 @@
+//! Messages encoded as MessagePack between peers that both read it, and
+//! as JSON for everyone else.
+
+mod common;
+
+use common::daemon::free_port;
+use common::fixtures::FixtureRng;
+use futures::{SinkExt, StreamExt};
+use mcbd_world_sync::exclusions::Exclusions;
+use mcbd_world_sync::file_manager::FileManager;
+use mcbd_world_sync::health::Health;
+use mcbd_world_sync::network::{SyncClient, SyncMessage, SyncServer};
+use mcbd_world_sync::wire::{self, Format};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+fn content(path: &str, content: Vec<u8>) -> SyncMessage {
+    SyncMessage::FileContent { path: PathBuf::from(path), content, group: None, modified: None, version: None, correlation_id: None }
+}
+
+#[test]
+fn message_pack_carries_file_contents_as_raw_bytes() {
+    let message = content("World/db/000005.ldb", FixtureRng::new(3).bytes(64 * 1024));
+    let (json, packed) = (Format::Json.encode(&message).unwrap(), Format::MessagePack.encode(&message).unwrap());
+    assert!(packed.len() < 64 * 1024 + 200, "{} bytes", packed.len());
+    assert!(json.len() > packed.len() * 3, "{} bytes as JSON, {} packed", json.len(), packed.len());
+
+    for (bytes, format) in [(json, Format::Json), (packed, Format::MessagePack)] {
+        let (decoded, detected) = wire::decode(&bytes).unwrap();
+        assert_eq!(detected, format);
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", message));
+    }
+    for format in [Format::Json, Format::MessagePack] {
+        let (decoded, _) = wire::decode(&format.encode(&SyncMessage::ManifestResync).unwrap()).unwrap();
+        assert!(matches!(decoded, SyncMessage::ManifestResync));
+    }
+
+    // Contents from peers that predate the format are arrays of numbers
+    let (old, _) = wire::decode(br#"{"FileContent":{"path":"w/level.dat","content":[1,2,3],"correlation_id":null}}"#).unwrap();
+    assert_eq!(format!("{:?}", old), format!("{:?}", content("w/level.dat", vec![1, 2, 3])));
+}
+
+#[tokio::test]
+async fn sessions_use_message_pack_and_older_peers_get_json() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let port = free_port();
+    let health = Arc::new(Health::new());
+    let files = Arc::new(Mutex::new(FileManager::new(dir.path().to_path_buf()).with_exclusions(Exclusions::new(&[]))));
+    let server = SyncServer::new(port).with_health(health.clone()).with_file_manager(files);
+    tokio::spawn(async move { server.start().await.unwrap() });
+    while health.pending().contains(&"listener_bound") {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let mut session = SyncClient::new(format!("127.0.0.1:{}", port)).session().await.unwrap();
+    assert_eq!(session.format(), Format::MessagePack);
+    session.send(&content("World/level.dat", vec![9; 1000])).await.unwrap();
+    assert!(matches!(session.recv().await, Some(SyncMessage::FileReceived { .. })));
+    assert_eq!(fs::read(dir.path().join("World/level.dat")).unwrap(), vec![9; 1000]);
+
+    // A peer from before sends JSON without a session and is answered in it
+    let mut framed = Framed::new(TcpStream::connect(("127.0.0.1", port)).await.unwrap(), LengthDelimitedCodec::new());
+    framed.send(Format::Json.encode(&content("World/old.txt", b"old".to_vec())).unwrap()).await.unwrap();
+    let reply = framed.next().await.unwrap().unwrap();
+    assert_eq!(Format::of(&reply), Format::Json);
+    assert_eq!(fs::read(dir.path().join("World/old.txt")).unwrap(), b"old");
+}